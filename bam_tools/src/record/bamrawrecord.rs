@@ -258,15 +258,16 @@ fn get_seq_base(val: u32) -> char {
     }
 }
 
-/// Decodes sequence bytes into string
+/// Decodes sequence bytes into string. Without l_seq zero bottom 4 bits of
+/// the last byte can't be told from '=' base, they are taken as padding.
+/// Nonzero padding is decoded as a base.
 pub fn decode_seq(bytes: &[u8], res: &mut String) {
     res.clear();
-    for byte in bytes {
+    for (i, byte) in bytes.iter().enumerate() {
         let first = (byte >> 4) as u32;
         let second = (byte & 0xf) as u32;
         res.push(get_seq_base(first));
-        // WARNING TODO: Assumption that the last half will contain value or zero (it is not mandated.)
-        if second != 0 {
+        if second != 0 || i + 1 < bytes.len() {
             res.push(get_seq_base(second));
         }
     }
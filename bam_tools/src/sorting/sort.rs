@@ -95,6 +95,8 @@ pub enum SortBy {
     CoordinatesAndStrand,
 }
 
+/// Where sorted runs are kept until they are merged.
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum TempFilesMode {
     RegularFiles,
     LZ4CompressedFiles,
@@ -107,6 +109,7 @@ fn do_index_sort<W: Write, IndexW: std::io::Write>(
     bam_reader: &mut Reader,
     writer: &mut W,
     index_writer: &mut IndexW,
+    prefix_block_size: bool,
 ) -> std::io::Result<()> {
     let temp_me = Instant::now();
     let mut records = bam_reader.records();
@@ -117,7 +120,7 @@ fn do_index_sort<W: Write, IndexW: std::io::Write>(
         let wrapper = BAMRawRecord(Cow::Borrowed(rec));
         let key = create_key_tuple("", &wrapper, &SortBy::CoordinatesAndStrand);
         all_keys.push((key, i..i));
-        writer.write_all(&rec[..])?;
+        write_sorted_rec(writer, rec, prefix_block_size)?;
         i += 1;
     }
 
@@ -136,6 +139,65 @@ fn do_index_sort<W: Write, IndexW: std::io::Write>(
 /// Memory limit won't be strictly obeyed, but it probably won't be overflowed significantly.
 #[allow(clippy::too_many_arguments)]
 pub fn sort_bam<R: Read + Send + 'static, W: Write, IndexW: Write>(
+    mem_limit: usize,
+    reader: R,
+    sorted_sink: &mut W,
+    tmp_dir: &TempDir,
+    out_compr_level: usize,
+    reader_thread_num: usize,
+    temp_files_mode: TempFilesMode,
+    index_file_to_create: Option<IndexW>,
+    sort_by: SortBy,
+    bam_file_size: Option<u64>,
+) -> std::io::Result<()> {
+    sort_bam_to_sink(
+        mem_limit,
+        reader,
+        sorted_sink,
+        tmp_dir,
+        out_compr_level,
+        reader_thread_num,
+        temp_files_mode,
+        index_file_to_create,
+        sort_by,
+        bam_file_size,
+        false,
+    )
+}
+
+/// Same as `sort_bam()`, but records are written into `sorted_sink` prefixed
+/// with their block_size, as in BAM, so the sink can tell records apart
+/// however writes are split.
+#[allow(clippy::too_many_arguments)]
+pub fn sort_bam_block_size_prefixed<R: Read + Send + 'static, W: Write, IndexW: Write>(
+    mem_limit: usize,
+    reader: R,
+    sorted_sink: &mut W,
+    tmp_dir: &TempDir,
+    out_compr_level: usize,
+    reader_thread_num: usize,
+    temp_files_mode: TempFilesMode,
+    index_file_to_create: Option<IndexW>,
+    sort_by: SortBy,
+    bam_file_size: Option<u64>,
+) -> std::io::Result<()> {
+    sort_bam_to_sink(
+        mem_limit,
+        reader,
+        sorted_sink,
+        tmp_dir,
+        out_compr_level,
+        reader_thread_num,
+        temp_files_mode,
+        index_file_to_create,
+        sort_by,
+        bam_file_size,
+        true,
+    )
+}
+
+#[allow(clippy::too_many_arguments)]
+fn sort_bam_to_sink<R: Read + Send + 'static, W: Write, IndexW: Write>(
     mem_limit: usize,
     reader: R,
     sorted_sink: &mut W,
@@ -146,6 +208,7 @@ pub fn sort_bam<R: Read + Send + 'static, W: Write, IndexW: Write>(
     index_file_to_create: Option<IndexW>,
     sort_by: SortBy,
     bam_file_size: Option<u64>,
+    prefix_block_size: bool,
 ) -> std::io::Result<()> {
     let reader_thread_num = max(min(num_cpus::get(), reader_thread_num), 1);
 
@@ -153,7 +216,7 @@ pub fn sort_bam<R: Read + Send + 'static, W: Write, IndexW: Write>(
     parallel_reader.read_header().unwrap();
 
     if let Some(mut index_file) = index_file_to_create {
-        do_index_sort(&mut parallel_reader, sorted_sink, &mut index_file)?;
+        do_index_sort(&mut parallel_reader, sorted_sink, &mut index_file, prefix_block_size)?;
         return Ok(());
     }
 
@@ -172,11 +235,19 @@ pub fn sort_bam<R: Read + Send + 'static, W: Write, IndexW: Write>(
         sort_by,
         sorted_sink,
         &temp_files_mode,
+        prefix_block_size,
     )?;
 
     Ok(())
 }
 
+fn write_sorted_rec<W: Write>(writer: &mut W, rec: &[u8], prefix_block_size: bool) -> std::io::Result<()> {
+    if prefix_block_size {
+        writer.write_u32::<LittleEndian>(rec.len() as u32)?;
+    }
+    writer.write_all(rec)
+}
+
 static INDEX_SORT_KEY_SIZE: usize = std::mem::size_of::<i32>()
     + std::mem::size_of::<i32>()
     + std::mem::size_of::<u32>()
@@ -593,6 +664,7 @@ fn merge_sorted_chunks_and_write<W: Write>(
     sort_by: SortBy,
     writer: &mut W,
     temp_files_are_compressed: &TempFilesMode,
+    prefix_block_size: bool,
 ) -> std::io::Result<()> {
     let num_chunks = tmp_medium.len();
     let input_buf_mem_limit = min(16 * MEGA_BYTE_SIZE, mem_limit / 4 / num_chunks);
@@ -640,7 +712,7 @@ fn merge_sorted_chunks_and_write<W: Write>(
 
     while let Some(rec) = merger.get_next_rec(temp_buf) {
         prev = now.elapsed();
-        writer.write_all(&rec[..])?;
+        write_sorted_rec(writer, &rec, prefix_block_size)?;
         unsafe {
             IO_WAIT += now.elapsed() - prev;
         }
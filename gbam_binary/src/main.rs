@@ -2,12 +2,11 @@
 use bam_tools::{record::fields::Fields, MEGA_BYTE_SIZE};
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use gbam_tools::{
-    bam::bam_to_gbam::bam_sort_to_gbam,
-    bam::gbam_to_bam::gbam_to_bam,
+    bam::convert::{bam_to_gbam, gbam_to_bam, temp_files_mode, Bam2GbamOptions, Gbam2BamOptions},
     query::depth::main_depth,
     query::flagstat::collect_stats,
     reader::{parse_tmplt::ParsingTemplate, reader::Reader, record::GbamRecord},
-    {bam_to_gbam, Codecs},
+    Codecs,
 };
 use itertools::zip_eq;
 use std::fs::OpenOptions;
@@ -26,6 +25,10 @@ use std::{
 use structopt::StructOpt;
 
 use gbam_tools::query::cigar::base_coverage;
+use gbam_tools::analytics::{self, DepthOptions};
+use gbam_tools::reader::region::Region;
+use gbam_tools::sam_export::SamWriter;
+use gbam_tools::fastq_export::{fastq_export_interleaved, FastqOptions, FASTQ_FIELDS};
 
 use rayon::prelude::*;
 
@@ -43,6 +46,9 @@ struct Cli {
     /// Convert to bam
     #[structopt(long)]
     convert_to_bam: bool,
+    /// With --convert-to-bam, write BAI (or CSI for long references) index of the output BAM in the same pass. Input must be sorted by coordinate.
+    #[structopt(long)]
+    write_index: bool,
     /// Perform the test
     #[structopt(short, long)]
     test: bool,
@@ -82,6 +88,15 @@ struct Cli {
     /// View file in binary format. Can be piped to samtools view. `gbam_binary -v test_data/1gb.gbam | samtools view`
     #[structopt(short, long)]
     view: bool,
+    /// View file as SAM text with header, like `samtools view -h --no-PG`.
+    #[structopt(long)]
+    view_sam: bool,
+    /// Print primary reads as interleaved FASTQ, like `samtools fastq`.
+    #[structopt(long)]
+    fastq: bool,
+    /// Print depth of every base in region given with -q, like `samtools depth -a -r`. Example: chr1:1000-2000. Reads with map quality lower than --mapq are skipped.
+    #[structopt(long)]
+    region_depth: bool,
     /// View file in binary format for piping to samtools markdup. `gbam_binary -v little.gbam > /tmp/testpipe.bam & samtools markdup -u /tmp/testpipe.bam /tmp/testoutpipe.bam`. It disables reading of two heavy fields to potentially speedup the process.
     #[structopt(long)]
     markdup_view: bool,
@@ -102,6 +117,9 @@ struct Cli {
     /// Use codec map from JSON file if specified.
     #[structopt(long)]
     codec_map_required: bool,
+    /// Compare input GBAM file with this one column by column, and report which fields differ.
+    #[structopt(long, parse(from_os_str))]
+    diff_with: Option<PathBuf>,
 }
 
 /// Limited wrapper of `gbam_tools` converts BAM file to GBAM
@@ -128,6 +146,12 @@ fn main() {
         let mut template = ParsingTemplate::new();
         template.set_all();
         view_file(args, template);
+    } else if args.view_sam {
+        view_sam(args);
+    } else if args.fastq {
+        view_fastq(args);
+    } else if args.region_depth {
+        region_depth(args);
     } else if args.markdup_view {
         let mut template = ParsingTemplate::new();
         template.set_all_except(&[Fields::RawQual, Fields::RawSequence]);
@@ -136,57 +160,47 @@ fn main() {
         patch_dups(args);
     } else if args.calc_uncompressed_size {
         test_file_uncompressed_size_fetch(args);
+    } else if args.diff_with.is_some() {
+        diff_files(args);
     }
 }
 
 fn convert(args: Cli, full_command: String) {
-    let in_path = args
-        .in_path
-        .as_path()
-        .to_str()
-        .expect("Couldn't parse input path");
     let out_path = args
         .out_path
         .as_ref()
-        .expect("Output path is mandatory for this operation.")
-        .as_path()
-        .to_str()
-        .unwrap();
+        .expect("Output path is mandatory for this operation.");
+    let mut options = Bam2GbamOptions::default()
+        .with_codec(Codecs::Brotli)
+        .with_threads(8)
+        .with_command(full_command)
+        .with_codec_map_required(args.codec_map_required);
     if args.sort {
-        bam_sort_to_gbam(
-            in_path,
-            out_path,
-            Codecs::Brotli,
-            args.sort_temp_mode,
-            args.temp_dir,
-            full_command,
-            args.index_sort,
-            args.codec_map_required
-        );
-    } else {
-        bam_to_gbam(
-            in_path, out_path,
-            Codecs::Brotli,
-            full_command,
-            args.codec_map_required
-        );
+        let temp_mode = temp_files_mode(args.sort_temp_mode.as_deref().unwrap_or("file")).unwrap();
+        options = options
+            .with_sorting(temp_mode, args.temp_dir.clone())
+            .with_sort_index(args.index_sort);
+    }
+    if let Err(e) = bam_to_gbam(&args.in_path, out_path, &options) {
+        eprintln!("Conversion failed: {}", e);
+        std::process::exit(1);
     }
 }
 
 fn convert_to_bam(args: Cli) {
-    let in_path = args
-        .in_path
-        .as_path()
-        .to_str()
-        .expect("Couldn't parse input path.");
     let out_path = args
         .out_path
         .as_ref()
-        .expect("Output path is mandatory for this operation.")
-        .as_path()
-        .to_str()
-        .unwrap();
-    gbam_to_bam(in_path, out_path);
+        .expect("Output path is mandatory for this operation.");
+    let options = Gbam2BamOptions::default().with_index(args.write_index);
+    match gbam_to_bam(&args.in_path, out_path, &options) {
+        Ok(Some(index_path)) => eprintln!("Index written to {}", index_path.display()),
+        Ok(None) => {}
+        Err(e) => {
+            eprintln!("BAM export failed: {}", e);
+            std::process::exit(1);
+        }
+    }
 }
 
 fn flagstat(args: Cli) {
@@ -212,7 +226,7 @@ fn test(args: Cli) {
 
     let mut u = 0;
     #[allow(unused_variables)]
-    while let Some(rec) = records.next_rec() {
+    while let Some(rec) = records.next_rec().unwrap() {
         u += base_coverage(&rec.cigar.as_ref().unwrap().0[..]);
     }
     println!("Record count {u}");
@@ -244,7 +258,7 @@ fn test_parallel_cigar_fetch(args: Cli) {
             let mut collector = Vec::with_capacity(records_range.len());
 
             for rec_num in records_range {
-                reader.fill_record(rec_num, &mut rec);
+                reader.fill_record(rec_num, &mut rec).unwrap();
                 collector.push(base_coverage(&rec.cigar.as_ref().unwrap().0[..]));
             }
         });
@@ -344,6 +358,99 @@ fn view_header(args: Cli) {
     println!("{header}");
 }
 
+fn view_sam(args: Cli) {
+    let file = File::open(args.in_path.as_path().to_str().unwrap()).unwrap();
+    let mut template = ParsingTemplate::new();
+    template.set_all();
+    let mut reader =
+        Reader::new_with_index(file, template, args.index_file.and_then(read_index)).unwrap();
+
+    let st = std::io::stdout();
+    let stdout = BufWriter::with_capacity(64 * 1024, st.lock());
+    let mut writer = SamWriter::new(stdout, reader.file_meta.clone());
+    if writer.write_header().is_err() {
+        return;
+    }
+    let mut records = reader.records();
+    while let Some(rec) = records.next_rec().unwrap() {
+        // Stop quietly when piped into head and the like.
+        if writer.write_record(rec).is_err() {
+            break;
+        }
+    }
+}
+
+fn view_fastq(args: Cli) {
+    let file = File::open(args.in_path.as_path().to_str().unwrap()).unwrap();
+    let mut reader = Reader::new(file, ParsingTemplate::new_with(&FASTQ_FIELDS)).unwrap();
+    let st = std::io::stdout();
+    let stdout = BufWriter::with_capacity(64 * 1024, st.lock());
+    match fastq_export_interleaved(&mut reader, stdout, &FastqOptions::default()) {
+        Ok(report) => eprintln!("{}", report),
+        Err(e) => {
+            eprintln!("FASTQ export failed: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn diff_files(args: Cli) {
+    let open = |path: &std::path::Path| {
+        let mut template = ParsingTemplate::new();
+        template.set_all();
+        Reader::new(File::open(path).unwrap(), template).unwrap()
+    };
+    let mut a = open(args.in_path.as_path());
+    let mut b = open(args.diff_with.as_ref().unwrap().as_path());
+    let fields: Vec<Fields> = Fields::iterator()
+        .copied()
+        .filter(bam_tools::record::fields::is_data_field)
+        .collect();
+    let report = gbam_tools::diff(&mut a, &mut b, &fields).unwrap();
+    println!("{}", report);
+    if !report.is_equal() {
+        std::process::exit(1);
+    }
+}
+
+fn region_depth(args: Cli) {
+    let file = File::open(args.in_path.as_path().to_str().unwrap()).unwrap();
+    let mut template = ParsingTemplate::new();
+    template.set_all();
+    let mut reader =
+        Reader::new_with_index(file, template, args.index_file.and_then(read_index)).unwrap();
+
+    // 1-based inclusive, like samtools regions.
+    let query = args.query.expect("Region has to be given with -q.");
+    let (ref_name, range) = query
+        .rsplit_once(':')
+        .expect("Region format is <ref name>:<start>-<end>.");
+    let (start, end) = range
+        .split_once('-')
+        .expect("Region format is <ref name>:<start>-<end>.");
+    let (start, end): (i32, i32) = (start.parse().unwrap(), end.parse().unwrap());
+    let ref_id = reader
+        .file_meta
+        .get_ref_seqs()
+        .iter()
+        .position(|(name, _)| name == ref_name)
+        .expect("Unknown reference name.");
+    let region = Region::new(ref_id as i32, start - 1, end);
+    let options = DepthOptions {
+        min_mapq: args.mapq.unwrap_or(0) as u8,
+        ..Default::default()
+    };
+    let depths = analytics::depth(&mut reader, &region, &options).unwrap();
+
+    let st = std::io::stdout();
+    let mut stdout = BufWriter::with_capacity(64 * 1024, st.lock());
+    for (pos, depth) in (start..).zip(depths) {
+        if writeln!(stdout, "{}\t{}\t{}", ref_name, pos, depth).is_err() {
+            break;
+        }
+    }
+}
+
 fn parse_tag(tags: &[u8], target_tag: &str) -> Option<String> {
     // Convert the target tag to bytes
     let target_bytes = target_tag.as_bytes();
@@ -393,8 +500,8 @@ fn view_file(args: Cli, template: ParsingTemplate) {
 
     let mut records = reader.records();
     let mut buf = Vec::new();
-    while let Some(rec) = records.next_rec() {
-        rec.convert_to_bytes(&mut buf);
+    while let Some(rec) = records.next_rec().unwrap() {
+        rec.convert_to_bam_bytes(&mut buf);
         if stdout.write_all(&buf).is_err() {
             break;
         }
@@ -0,0 +1,170 @@
+//! Regression benchmarks of the whole pipeline on synthetic records of
+//! `gbam_tools::bench_support`: ingest, cost of flushing each column, full
+//! file read, region fetch and export to BAM. Inputs are the same on every
+//! run, so results of two commits compare. Run with `--features bench`.
+use std::path::Path;
+
+use bam_tools::record::bamrawrecord::BAMRawRecord;
+use bam_tools::record::fields::DATA_FIELDS_NUM;
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use gbam_tools::bam::convert::{gbam_to_bam, Gbam2BamOptions};
+use gbam_tools::bench_support::{ReadLengths, SyntheticConfig, TagProfile};
+use gbam_tools::reader::parse_tmplt::ParsingTemplate;
+use gbam_tools::reader::reader::Reader;
+use gbam_tools::reader::region::Region;
+use gbam_tools::writer::{WriteTemplate, Writer};
+use gbam_tools::Fields;
+use tempdir::TempDir;
+
+const RECORDS_NUM: usize = 200_000;
+
+fn configs() -> Vec<(&'static str, SyntheticConfig)> {
+    let config = SyntheticConfig::default().with_records(RECORDS_NUM);
+    vec![
+        ("sorted_short_tags", config.clone()),
+        (
+            "unsorted_long_tags",
+            config.clone().with_sorted(false).with_tags(TagProfile::Long),
+        ),
+        (
+            "sorted_long_reads",
+            config
+                .with_records(RECORDS_NUM / 100)
+                .with_read_lengths(ReadLengths::Uniform(1_000..=20_000)),
+        ),
+    ]
+}
+
+fn write(path: &Path, config: &SyntheticConfig, records: &[BAMRawRecord], template: Option<WriteTemplate>) {
+    let mut writer = Writer::create(path, config.writer_settings()).unwrap();
+    if let Some(template) = template {
+        writer.set_write_template(template);
+    }
+    writer.push_records(records, false).unwrap();
+    writer.finish_with_summary(false).unwrap();
+}
+
+fn open(path: &Path) -> Reader {
+    let mut template = ParsingTemplate::new();
+    template.set_all();
+    Reader::from_path(path, template).unwrap()
+}
+
+fn bench_ingest(c: &mut Criterion) {
+    let dir = TempDir::new("gbam_bench").unwrap();
+    let path = dir.path().join("ingest.gbam");
+    let mut group = c.benchmark_group("ingest");
+    group.sample_size(10);
+    for (name, config) in configs() {
+        let records = config.generate();
+        let bytes: usize = records.iter().map(|rec| rec.len()).sum();
+        group.throughput(Throughput::Bytes(bytes as u64));
+        group.bench_function(name, |b| b.iter(|| write(&path, &config, &records, None)));
+    }
+    group.finish();
+}
+
+/// Writes of single columns, RefID is always written and measured alone
+/// as the baseline.
+fn bench_column_flush(c: &mut Criterion) {
+    let dir = TempDir::new("gbam_bench").unwrap();
+    let path = dir.path().join("column.gbam");
+    let config = SyntheticConfig::default()
+        .with_records(RECORDS_NUM)
+        .with_tags(TagProfile::Long);
+    let records = config.generate();
+    let mut group = c.benchmark_group("column_flush");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(RECORDS_NUM as u64));
+    for field in Fields::iterator().take(DATA_FIELDS_NUM) {
+        group.bench_function(field.to_string(), |b| {
+            b.iter(|| write(&path, &config, &records, Some(WriteTemplate::new_with(&[*field]))))
+        });
+    }
+    group.finish();
+}
+
+fn bench_read(c: &mut Criterion) {
+    let dir = TempDir::new("gbam_bench").unwrap();
+    let mut group = c.benchmark_group("read");
+    group.sample_size(10);
+    for (name, config) in configs() {
+        let path = dir.path().join(format!("{}.gbam", name));
+        write(&path, &config, &config.generate(), None);
+        group.throughput(Throughput::Elements(config.records as u64));
+        group.bench_function(name, |b| {
+            b.iter_batched(
+                || open(&path),
+                |mut reader| {
+                    let mut records = reader.records();
+                    let mut bases = 0;
+                    while let Some(rec) = records.next_rec().unwrap() {
+                        bases += rec.seq.as_ref().unwrap().len();
+                    }
+                    bases
+                },
+                BatchSize::PerIteration,
+            )
+        });
+    }
+    group.finish();
+}
+
+/// Fetches of 100 kb regions spread over the references.
+fn bench_region_fetch(c: &mut Criterion) {
+    const REGIONS_NUM: i32 = 50;
+    let dir = TempDir::new("gbam_bench").unwrap();
+    let path = dir.path().join("regions.gbam");
+    let config = SyntheticConfig::default().with_records(RECORDS_NUM);
+    write(&path, &config, &config.generate(), None);
+    let regions: Vec<Region> = (0..REGIONS_NUM)
+        .map(|i| {
+            let ref_id = i % config.ref_seqs.len() as i32;
+            let start = (i / 3) * 2_900_000;
+            Region::new(ref_id, start, start + 100_000)
+        })
+        .collect();
+    let mut reader = open(&path);
+    let mut group = c.benchmark_group("region_fetch");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(REGIONS_NUM as u64));
+    group.bench_function("100kb", |b| {
+        b.iter(|| {
+            let mut fetched = 0;
+            for region in &regions {
+                let mut records = reader.fetch(region).unwrap();
+                while records.next_rec().unwrap().is_some() {
+                    fetched += 1;
+                }
+            }
+            fetched
+        })
+    });
+    group.finish();
+}
+
+fn bench_bam_export(c: &mut Criterion) {
+    let dir = TempDir::new("gbam_bench").unwrap();
+    let mut group = c.benchmark_group("bam_export");
+    group.sample_size(10);
+    for (name, config) in configs() {
+        let src = dir.path().join(format!("{}.gbam", name));
+        let dst = dir.path().join(format!("{}.bam", name));
+        write(&src, &config, &config.generate(), None);
+        group.throughput(Throughput::Elements(config.records as u64));
+        group.bench_function(name, |b| {
+            b.iter(|| gbam_to_bam(&src, &dst, &Gbam2BamOptions::default()).unwrap())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    bench_ingest,
+    bench_column_flush,
+    bench_read,
+    bench_region_fetch,
+    bench_bam_export
+);
+criterion_main!(benches);
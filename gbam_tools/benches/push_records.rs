@@ -0,0 +1,176 @@
+//! Compares pushing records one by one with pushing them in batches, and
+//! measures ingest of small files, where every column ends with a partially
+//! filled block, with and without a shared buffer pool.
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::io::Cursor;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use bam_tools::record::bamrawrecord::BAMRawRecord;
+use bam_tools::record::fields::FIELDS_NUM;
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use gbam_tools::buffer_pool::BufferPool;
+use gbam_tools::writer::Writer;
+use gbam_tools::{Codecs, Fields};
+
+const RECORDS_NUM: usize = 200_000;
+
+/// Allocations of at least a block size, 8 MB.
+static LARGE_ALLOCS: AtomicU64 = AtomicU64::new(0);
+
+struct CountingAlloc;
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if layout.size() >= 8 << 20 {
+            LARGE_ALLOCS.fetch_add(1, Ordering::Relaxed);
+        }
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        if new_size >= 8 << 20 {
+            LARGE_ALLOCS.fetch_add(1, Ordering::Relaxed);
+        }
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAlloc = CountingAlloc;
+
+/// Builds a mapped 100bp read without tags, in BAM layout without block_size.
+fn record(i: usize) -> BAMRawRecord<'static> {
+    let name = format!("read{}", i);
+    let l_seq = 100;
+    let mut bytes = Vec::new();
+    bytes.extend_from_slice(&0i32.to_le_bytes());
+    bytes.extend_from_slice(&(i as i32).to_le_bytes());
+    bytes.push(name.len() as u8 + 1);
+    bytes.push(60);
+    bytes.extend_from_slice(&4680u16.to_le_bytes());
+    bytes.extend_from_slice(&1u16.to_le_bytes());
+    bytes.extend_from_slice(&0u16.to_le_bytes());
+    bytes.extend_from_slice(&(l_seq as u32).to_le_bytes());
+    bytes.extend_from_slice(&(-1i32).to_le_bytes());
+    bytes.extend_from_slice(&(-1i32).to_le_bytes());
+    bytes.extend_from_slice(&0i32.to_le_bytes());
+    bytes.extend_from_slice(name.as_bytes());
+    bytes.push(0);
+    bytes.extend_from_slice(&((l_seq as u32) << 4).to_le_bytes());
+    bytes.extend((0..l_seq / 2).map(|j| [0x12, 0x48][(i + j) % 2]));
+    bytes.extend((0..l_seq).map(|j| 20 + (j % 20) as u8));
+    BAMRawRecord::from(bytes)
+}
+
+fn writer() -> Writer<Cursor<Vec<u8>>> {
+    let ref_seqs = vec![(String::from("chr1"), 1_000_000_000)];
+    let mut sam_header = Vec::new();
+    sam_header.extend_from_slice(&0u32.to_le_bytes());
+    sam_header.extend_from_slice(&1u32.to_le_bytes());
+    sam_header.extend_from_slice(&5u32.to_le_bytes());
+    sam_header.extend_from_slice(b"chr1\0");
+    sam_header.extend_from_slice(&1_000_000_000u32.to_le_bytes());
+    Writer::new(
+        Cursor::new(Vec::with_capacity(64 << 20)),
+        vec![Codecs::Lz4; FIELDS_NUM],
+        4,
+        vec![Fields::RefID, Fields::Pos],
+        ref_seqs,
+        sam_header,
+        String::from("bench"),
+        false,
+        false,
+    )
+    .unwrap()
+}
+
+fn bench_push(c: &mut Criterion) {
+    let records: Vec<BAMRawRecord> = (0..RECORDS_NUM).map(record).collect();
+    let mut group = c.benchmark_group("push");
+    group.throughput(Throughput::Elements(RECORDS_NUM as u64));
+    group.sample_size(10);
+
+    group.bench_function("push_record", |b| {
+        b.iter_batched(
+            writer,
+            |mut writer| {
+                for rec in &records {
+                    writer.push_record(rec, false).unwrap();
+                }
+                writer.finish_with_summary(false).unwrap()
+            },
+            BatchSize::PerIteration,
+        )
+    });
+
+    for batch_size in [64, 1024] {
+        group.bench_function(format!("push_records/{}", batch_size), |b| {
+            b.iter_batched(
+                writer,
+                |mut writer| {
+                    for batch in records.chunks(batch_size) {
+                        writer.push_records(batch, false).unwrap();
+                    }
+                    writer.finish_with_summary(false).unwrap()
+                },
+                BatchSize::PerIteration,
+            )
+        });
+    }
+    group.finish();
+}
+
+fn bench_small_files(c: &mut Criterion) {
+    const FILES_NUM: usize = 20;
+    let records: Vec<BAMRawRecord> = (0..1_000).map(record).collect();
+    let mut group = c.benchmark_group("ingest");
+    group.throughput(Throughput::Elements((FILES_NUM * records.len()) as u64));
+    group.sample_size(10);
+
+    group.bench_function("small_files", |b| {
+        b.iter(|| {
+            for _ in 0..FILES_NUM {
+                let mut writer = writer();
+                writer.push_records(&records, false).unwrap();
+                writer.finish_with_summary(false).unwrap();
+            }
+        })
+    });
+    group.finish();
+}
+
+fn bench_buffer_pool(c: &mut Criterion) {
+    const FILES_NUM: usize = 20;
+    let records: Vec<BAMRawRecord> = (0..1_000).map(record).collect();
+    let ingest = |pool: Option<&BufferPool>| {
+        for _ in 0..FILES_NUM {
+            let mut writer = writer();
+            if let Some(pool) = pool {
+                writer.set_buffer_pool(pool.clone());
+            }
+            writer.push_records(&records, false).unwrap();
+            writer.finish_with_summary(false).unwrap();
+        }
+    };
+    let mut group = c.benchmark_group("buffer_pool");
+    group.throughput(Throughput::Elements((FILES_NUM * records.len()) as u64));
+    group.sample_size(10);
+
+    for (name, shared) in [("own_pools", false), ("shared_pool", true)] {
+        let pool = BufferPool::new(64);
+        let before = LARGE_ALLOCS.load(Ordering::Relaxed);
+        ingest(shared.then_some(&pool));
+        let allocs = LARGE_ALLOCS.load(Ordering::Relaxed) - before;
+        // Output cursor of each writer counts too.
+        eprintln!("{}: {} allocations of 8 MB or more per file", name, allocs / FILES_NUM as u64);
+        group.bench_function(name, |b| b.iter(|| ingest(shared.then_some(&pool))));
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_push, bench_small_files, bench_buffer_pool);
+criterion_main!(benches);
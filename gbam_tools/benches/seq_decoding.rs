@@ -0,0 +1,71 @@
+//! Compares decoding of packed sequences base by base, as
+//! `bam_tools::record::bamrawrecord::decode_seq()` does, with the table
+//! decoder of `gbam_tools::utils::seq`. Run with `--features simd` to measure
+//! the SIMD one. Encoding is measured against `put_sequence()`.
+use bam_tools::record::bamrawrecord::{decode_seq as decode_per_base, put_sequence};
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use gbam_tools::utils::seq::{decode_seq, encode_bases};
+
+/// 150bp reads, as in a sequence column block.
+const READ_LEN: usize = 150;
+const READS_NUM: usize = 10_000;
+
+fn reads() -> Vec<String> {
+    (0..READS_NUM)
+        .map(|i| (0..READ_LEN).map(|j| b"ACGTN"[(i * 7 + j * 13) % 5] as char).collect())
+        .collect()
+}
+
+fn bench_decode(c: &mut Criterion) {
+    let packed: Vec<Vec<u8>> = reads()
+        .iter()
+        .map(|read| {
+            let mut packed = vec![0; READ_LEN.div_ceil(2)];
+            encode_bases(read.as_bytes(), &mut packed);
+            packed
+        })
+        .collect();
+    let mut group = c.benchmark_group("decode_seq");
+    group.throughput(Throughput::Elements((READ_LEN * READS_NUM) as u64));
+    let mut res = String::new();
+    group.bench_function("per_base", |b| {
+        b.iter(|| {
+            for read in &packed {
+                decode_per_base(read, &mut res);
+            }
+        })
+    });
+    group.bench_function("table", |b| {
+        b.iter(|| {
+            for read in &packed {
+                decode_seq(read, &mut res);
+            }
+        })
+    });
+    group.finish();
+}
+
+fn bench_encode(c: &mut Criterion) {
+    let reads = reads();
+    let mut group = c.benchmark_group("encode_seq");
+    group.throughput(Throughput::Elements((READ_LEN * READS_NUM) as u64));
+    let mut packed = vec![0; READ_LEN.div_ceil(2)];
+    group.bench_function("per_base", |b| {
+        b.iter(|| {
+            for read in &reads {
+                put_sequence(&mut packed, READ_LEN, read).unwrap();
+            }
+        })
+    });
+    group.bench_function("table", |b| {
+        b.iter(|| {
+            for read in &reads {
+                encode_bases(read.as_bytes(), &mut packed);
+            }
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_decode, bench_encode);
+criterion_main!(benches);
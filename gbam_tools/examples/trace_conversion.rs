@@ -0,0 +1,26 @@
+//! Converts a BAM file into GBAM with spans of block flush, compression,
+//! writing and fetch printed to stderr, followed by a summary of each.
+//!
+//!     cargo run --release --features tracing --example trace_conversion -- in.bam out.gbam
+use gbam_tools::bam::convert::{bam_to_gbam, Bam2GbamOptions};
+use gbam_tools::reader::parse_tmplt::ParsingTemplate;
+use gbam_tools::reader::reader::Reader;
+use gbam_tools::trace::LogSubscriber;
+use gbam_tools::Codecs;
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() != 3 {
+        eprintln!("Usage: {} <in.bam> <out.gbam>", args[0]);
+        std::process::exit(1);
+    }
+    tracing::subscriber::set_global_default(LogSubscriber::stderr()).unwrap();
+    let options = Bam2GbamOptions::default().with_codec(Codecs::Lz4).with_command(args.join(" "));
+    bam_to_gbam(args[1].as_ref(), args[2].as_ref(), &options).unwrap();
+
+    let mut template = ParsingTemplate::new();
+    template.set_all();
+    let mut reader = Reader::from_path(&args[2], template).unwrap();
+    let mut records = reader.records();
+    while records.next_rec().unwrap().is_some() {}
+}
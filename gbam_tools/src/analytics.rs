@@ -0,0 +1,1458 @@
+//! Summaries computed from field data while writing, stored in file meta,
+//! and computed from columns of written files.
+use std::collections::HashSet;
+use std::fmt;
+use std::io;
+
+use bam_tools::record::fields::Fields;
+use byteorder::{LittleEndian, ReadBytesExt};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::reader::parse_tmplt::ParsingTemplate;
+use crate::reader::reader::Reader;
+use crate::reader::record::GbamRecord;
+use crate::stats::stat_ref_id;
+use crate::query::cigar::{base_coverage, Op};
+use crate::reader::region::Region;
+use crate::meta::SortOrder;
+
+/// Receives bytes of a field for every pushed record. Registered with
+/// `Writer::register_collector`, its summary is stored in the "analytics" map
+/// of file meta under `name()`.
+pub trait RecordObserver: Send {
+    fn name(&self) -> &str;
+    /// Called with the field bytes of each record, in push order.
+    fn observe(&mut self, field_bytes: &[u8]);
+    fn summary(&self) -> Value;
+}
+
+/// Insert sizes from TemplateLength field. Only positive template lengths
+/// are counted, so each pair contributes once, through its leftmost mate.
+pub struct InsertSizeHistogram {
+    // Index is insert size.
+    counts: Vec<u64>,
+    above_max: u64,
+}
+
+/// Summary of `InsertSizeHistogram`. Insert sizes above `max_insert_size`
+/// are only counted in `above_max`, they are excluded from the statistics.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct InsertSizeStats {
+    pub count: u64,
+    pub above_max: u64,
+    pub max_insert_size: u32,
+    pub mean: f64,
+    pub median: f64,
+    pub sd: f64,
+    /// Median absolute deviation.
+    pub mad: f64,
+    /// (insert size, count) pairs with nonzero count.
+    pub histogram: Vec<(u32, u64)>,
+}
+
+impl InsertSizeHistogram {
+    pub const NAME: &'static str = "insert_size";
+
+    pub fn new(max_insert_size: u32) -> Self {
+        Self {
+            counts: vec![0; max_insert_size as usize + 1],
+            above_max: 0,
+        }
+    }
+
+    pub fn stats(&self) -> InsertSizeStats {
+        let histogram: Vec<(u32, u64)> = self
+            .counts
+            .iter()
+            .enumerate()
+            .filter(|(_, &n)| n > 0)
+            .map(|(size, &n)| (size as u32, n))
+            .collect();
+        let count: u64 = histogram.iter().map(|(_, n)| n).sum();
+        let mut stats = InsertSizeStats {
+            count,
+            above_max: self.above_max,
+            max_insert_size: self.counts.len() as u32 - 1,
+            mean: 0.0,
+            median: 0.0,
+            sd: 0.0,
+            mad: 0.0,
+            histogram,
+        };
+        if count == 0 {
+            return stats;
+        }
+        let hist: Vec<(f64, u64)> = stats
+            .histogram
+            .iter()
+            .map(|&(size, n)| (size as f64, n))
+            .collect();
+        stats.mean = hist.iter().map(|&(v, n)| v * n as f64).sum::<f64>() / count as f64;
+        let var = hist
+            .iter()
+            .map(|&(v, n)| (v - stats.mean).powi(2) * n as f64)
+            .sum::<f64>()
+            / count as f64;
+        stats.sd = var.sqrt();
+        stats.median = weighted_median(&hist, count);
+        let mut deviations: Vec<(f64, u64)> = hist
+            .iter()
+            .map(|&(v, n)| ((v - stats.median).abs(), n))
+            .collect();
+        deviations.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+        stats.mad = weighted_median(&deviations, count);
+        stats
+    }
+}
+
+/// Median of values given as sorted (value, count) pairs, averaging the two
+/// middle values for even counts.
+fn weighted_median(sorted: &[(f64, u64)], count: u64) -> f64 {
+    let nth = |idx: u64| {
+        let mut seen = 0;
+        for &(v, n) in sorted {
+            seen += n;
+            if idx < seen {
+                return v;
+            }
+        }
+        unreachable!()
+    };
+    (nth((count - 1) / 2) + nth(count / 2)) / 2.0
+}
+
+impl RecordObserver for InsertSizeHistogram {
+    fn name(&self) -> &str {
+        Self::NAME
+    }
+
+    fn observe(&mut self, mut field_bytes: &[u8]) {
+        let tlen = field_bytes.read_i32::<LittleEndian>().unwrap();
+        if tlen <= 0 {
+            return;
+        }
+        match self.counts.get_mut(tlen as usize) {
+            Some(n) => *n += 1,
+            None => self.above_max += 1,
+        }
+    }
+
+    fn summary(&self) -> Value {
+        serde_json::to_value(self.stats()).unwrap()
+    }
+}
+
+/// Record filters of [`depth`].
+#[derive(Clone, Copy, Debug)]
+pub struct DepthOptions {
+    /// Records must have all of these flags set.
+    pub required_flags: u16,
+    /// Records with any of these flags set are skipped.
+    pub excluded_flags: u16,
+    pub min_mapq: u8,
+}
+
+impl Default for DepthOptions {
+    /// Same as `samtools depth`: unmapped, secondary, QC failed and
+    /// duplicate records are skipped.
+    fn default() -> Self {
+        Self {
+            required_flags: 0,
+            excluded_flags: 0x4 | 0x100 | 0x200 | 0x400,
+            min_mapq: 0,
+        }
+    }
+}
+
+/// Fields decoded by [`depth`].
+const DEPTH_FIELDS: [Fields; 5] = [
+    Fields::RefID,
+    Fields::Pos,
+    Fields::Flags,
+    Fields::Mapq,
+    Fields::RawCigar,
+];
+
+/// Per-base coverage of `region`, element `i` being the depth at
+/// `region.start + i`. Only M, = and X operations add coverage, deletions and
+/// reference skips don't, like in `samtools depth` without `-J`. The file
+/// has to be coordinate sorted, and opened with RefID, Pos, Flags, Mapq and
+/// RawCigar fields. Other fields are not decoded.
+pub fn depth(reader: &mut Reader, region: &Region, options: &DepthOptions) -> io::Result<Vec<u32>> {
+    if !reader.parsing_template.check_if_active(&DEPTH_FIELDS) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "RefID, Pos, Flags, Mapq and RawCigar fields have to be enabled in parsing template to compute depth.",
+        ));
+    }
+    let start = region.start as i64;
+    let mut depths = vec![0u32; (region.end as i64 - start).max(0) as usize];
+    let template = std::mem::replace(
+        &mut reader.parsing_template,
+        ParsingTemplate::new_with(&DEPTH_FIELDS),
+    );
+    let res = add_coverage(reader, region, options, &mut depths);
+    reader.parsing_template = template;
+    res.map(|_| depths)
+}
+
+fn add_coverage(
+    reader: &mut Reader,
+    region: &Region,
+    options: &DepthOptions,
+    depths: &mut [u32],
+) -> io::Result<()> {
+    let (start, end) = (region.start as i64, region.end as i64);
+    let mut records = reader.fetch(region)?;
+    while let Some(rec) = records.next_rec()? {
+        let flag = rec.flag.unwrap();
+        if flag & options.required_flags != options.required_flags
+            || flag & options.excluded_flags != 0
+            || rec.mapq.unwrap() < options.min_mapq
+        {
+            continue;
+        }
+        let mut ref_pos = rec.pos.unwrap() as i64;
+        for op in rec.cigar.as_ref().unwrap().ops() {
+            let len = op.length() as i64;
+            match op.op_type() {
+                'M' | '=' | 'X' => {
+                    for pos in ref_pos.max(start)..(ref_pos + len).min(end) {
+                        depths[(pos - start) as usize] += 1;
+                    }
+                    ref_pos += len;
+                }
+                'D' | 'N' => ref_pos += len,
+                _ => {}
+            }
+            if ref_pos >= end {
+                break;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Record filters and options of [`pileup`].
+#[derive(Clone, Copy, Debug)]
+pub struct PileupOptions {
+    /// Records must have all of these flags set.
+    pub required_flags: u16,
+    /// Records with any of these flags set are skipped.
+    pub excluded_flags: u16,
+    pub min_mapq: u8,
+    /// Bases of lower quality are left out. Deletions and reference skips
+    /// are kept.
+    pub min_base_quality: u8,
+    /// Paired records not mapped in proper pair are skipped.
+    pub skip_anomalous_pairs: bool,
+    /// Where mates overlap, only one of them keeps its base, see [`pileup`].
+    pub dedup_mate_overlaps: bool,
+}
+
+impl Default for PileupOptions {
+    /// Same as `samtools mpileup -B`: unmapped, secondary, QC failed,
+    /// duplicate and anomalous pair records are skipped, bases need quality
+    /// 13, and overlapping mates are counted once.
+    fn default() -> Self {
+        Self {
+            required_flags: 0,
+            excluded_flags: 0x4 | 0x100 | 0x200 | 0x400,
+            min_mapq: 0,
+            min_base_quality: 13,
+            skip_anomalous_pairs: true,
+            dedup_mate_overlaps: true,
+        }
+    }
+}
+
+/// Read covering a position of [`pileup`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PileupRead {
+    /// Record number, as taken by `Reader::fill_record()`.
+    pub rec_num: usize,
+    /// Read base and its quality, 0 in deletions and reference skips.
+    pub base: u8,
+    pub qual: u8,
+    pub is_reverse: bool,
+    pub is_del: bool,
+    pub is_refskip: bool,
+    /// Length of insertion (positive) or deletion (negative) following the
+    /// position, 0 if there is none.
+    pub indel: i32,
+    /// Bases of the insertion following the position.
+    pub inserted: Vec<u8>,
+}
+
+/// Fields decoded by [`pileup`]. Sequences, qualities and names are decoded
+/// only for records passing the filters.
+const PILEUP_FIELDS: [Fields; 8] = [
+    Fields::RefID,
+    Fields::Pos,
+    Fields::Flags,
+    Fields::Mapq,
+    Fields::RawCigar,
+    Fields::RawSequence,
+    Fields::RawQual,
+    Fields::ReadName,
+];
+
+/// Calls `visitor` with every position of `region` covered by reads, in
+/// order, and reads at it, in record order. Reads are kept only while they
+/// cover positions being visited, so memory depends on depth, not on region
+/// size. Positions are 0-based. The file has to be coordinate sorted, and
+/// opened with RefID, Pos, Flags, Mapq, RawCigar, RawSequence, RawQual and
+/// ReadName fields.
+///
+/// Overlapping mates are resolved like in `samtools mpileup`: if bases agree
+/// the first mate gets the sum of qualities (at most 200), otherwise the one
+/// of higher quality (the first one on ties) keeps its base with 80% of its
+/// quality. The other one gets quality 0. Base qualities are not adjusted
+/// by BAQ and depth is not capped, like with `samtools mpileup -B -d 0`.
+pub fn pileup<F: FnMut(i32, &[PileupRead])>(
+    reader: &mut Reader,
+    region: &Region,
+    options: &PileupOptions,
+    mut visitor: F,
+) -> io::Result<()> {
+    if !reader.parsing_template.check_if_active(&PILEUP_FIELDS) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "RefID, Pos, Flags, Mapq, RawCigar, RawSequence, RawQual and ReadName fields have to be enabled \
+             in parsing template to compute pileup.",
+        ));
+    }
+    let template = std::mem::replace(
+        &mut reader.parsing_template,
+        ParsingTemplate::new_with(&DEPTH_FIELDS),
+    );
+    let res = add_pileup(reader, region, options, &mut visitor);
+    reader.parsing_template = template;
+    res
+}
+
+fn add_pileup<F: FnMut(i32, &[PileupRead])>(
+    reader: &mut Reader,
+    region: &Region,
+    options: &PileupOptions,
+    visitor: &mut F,
+) -> io::Result<()> {
+    let mut window = PileupWindow {
+        reads: Vec::new(),
+        column: Vec::new(),
+        mates: Vec::new(),
+        next_pos: region.start as i64,
+        end: region.end as i64,
+    };
+    let mut records = reader.fetch(region)?;
+    while let Some(rec) = records.next_rec()? {
+        let flag = rec.flag.unwrap();
+        if flag & options.required_flags != options.required_flags
+            || flag & options.excluded_flags != 0
+            || rec.mapq.unwrap() < options.min_mapq
+            || (options.skip_anomalous_pairs && flag & BAM_FPAIRED != 0 && flag & BAM_FPROPER_PAIR == 0)
+        {
+            continue;
+        }
+        let pos = rec.pos.unwrap() as i64;
+        window.visit_until(pos, options, visitor);
+        let paired = options.dedup_mate_overlaps && flag & BAM_FPAIRED != 0;
+        let rec_num = records.rec_num();
+        let rec = if paired {
+            records.fill_fields(&[Fields::RawSequence, Fields::RawQual, Fields::ReadName])
+        } else {
+            records.fill_fields(&[Fields::RawSequence, Fields::RawQual])
+        };
+        window.add(rec_num, flag, paired, rec?);
+    }
+    let last_end = window.reads.iter().map(|read| read.end).max().unwrap_or(0);
+    window.visit_until(last_end, options, visitor);
+    Ok(())
+}
+
+/// Reads covering positions from `next_pos` on.
+struct PileupWindow {
+    reads: Vec<WindowRead>,
+    column: Vec<PileupRead>,
+    // Record numbers of earlier mates of reads in `column`.
+    mates: Vec<Option<usize>>,
+    next_pos: i64,
+    end: i64,
+}
+
+/// Read of [`PileupWindow`], with position of its CIGAR operation covering
+/// the last visited position.
+struct WindowRead {
+    rec_num: usize,
+    is_reverse: bool,
+    // Empty unless needed to find mates.
+    name: Vec<u8>,
+    mate: Option<usize>,
+    ops: Vec<Op>,
+    seq: Vec<u8>,
+    qual: Vec<u8>,
+    end: i64,
+    op: usize,
+    op_ref_start: i64,
+    op_read_start: usize,
+}
+
+impl PileupWindow {
+    fn add(&mut self, rec_num: usize, flag: u16, paired: bool, rec: &GbamRecord) {
+        let ops = rec.cigar.as_ref().unwrap().0.clone();
+        let pos = rec.pos.unwrap() as i64;
+        let end = pos + base_coverage(&ops) as i64;
+        if end == pos {
+            return;
+        }
+        let name = if paired { rec.read_name.clone().unwrap() } else { Vec::new() };
+        let mate = if paired {
+            self.reads.iter().find(|read| read.name == name).map(|read| read.rec_num)
+        } else {
+            None
+        };
+        self.reads.push(WindowRead {
+            rec_num,
+            is_reverse: flag & BAM_FREVERSE != 0,
+            name,
+            mate,
+            ops,
+            seq: rec.seq.as_ref().map_or_else(Vec::new, |seq| seq.as_bytes().to_vec()),
+            qual: rec.qual.clone().unwrap_or_default(),
+            end,
+            op: 0,
+            op_ref_start: pos,
+            op_read_start: 0,
+        });
+    }
+
+    /// Visits positions before `limit` which are covered by reads.
+    fn visit_until<F: FnMut(i32, &[PileupRead])>(
+        &mut self,
+        limit: i64,
+        options: &PileupOptions,
+        visitor: &mut F,
+    ) {
+        let limit = limit.min(self.end);
+        while self.next_pos < limit {
+            let pos = self.next_pos;
+            self.reads.retain(|read| read.end > pos);
+            if self.reads.is_empty() {
+                self.next_pos = limit;
+                break;
+            }
+            self.column.clear();
+            self.mates.clear();
+            for read in &mut self.reads {
+                self.column.push(read.at(pos));
+                self.mates.push(read.mate);
+            }
+            if options.dedup_mate_overlaps {
+                self.merge_mate_overlaps();
+            }
+            let min_base_quality = options.min_base_quality;
+            self.column
+                .retain(|read| read.is_del || read.is_refskip || read.qual >= min_base_quality);
+            if !self.column.is_empty() {
+                visitor(pos as i32, &self.column);
+            }
+            self.next_pos += 1;
+        }
+    }
+
+    fn merge_mate_overlaps(&mut self) {
+        for i in 0..self.column.len() {
+            let mate = match self.mates[i] {
+                Some(mate) => mate,
+                None => continue,
+            };
+            // Reads are in record order.
+            let j = match self.column[..i].binary_search_by_key(&mate, |read| read.rec_num) {
+                Ok(j) => j,
+                Err(_) => continue,
+            };
+            let (first, second) = (&self.column[j], &self.column[i]);
+            if first.is_del || first.is_refskip || second.is_del || second.is_refskip {
+                continue;
+            }
+            let (first_qual, second_qual) = (first.qual as u32, second.qual as u32);
+            if first.base == second.base {
+                self.column[j].qual = (first_qual + second_qual).min(200) as u8;
+                self.column[i].qual = 0;
+            } else if first_qual >= second_qual {
+                self.column[j].qual = (first_qual * 4 / 5) as u8;
+                self.column[i].qual = 0;
+            } else {
+                self.column[i].qual = (second_qual * 4 / 5) as u8;
+                self.column[j].qual = 0;
+            }
+        }
+    }
+}
+
+impl WindowRead {
+    /// The read at `pos`, which is not before positions visited already.
+    fn at(&mut self, pos: i64) -> PileupRead {
+        loop {
+            let op = &self.ops[self.op];
+            let len = op.length() as i64;
+            if op.is_consuming_reference() {
+                if pos < self.op_ref_start + len {
+                    break;
+                }
+                self.op_ref_start += len;
+            }
+            if op.consumes_read() {
+                self.op_read_start += len as usize;
+            }
+            self.op += 1;
+        }
+        let op = &self.ops[self.op];
+        let op_type = op.op_type();
+        let offset = (pos - self.op_ref_start) as usize;
+        let mut read = PileupRead {
+            rec_num: self.rec_num,
+            base: 0,
+            qual: 0,
+            is_reverse: self.is_reverse,
+            is_del: op_type == 'D',
+            is_refskip: op_type == 'N',
+            indel: 0,
+            inserted: Vec::new(),
+        };
+        if op.consumes_read() {
+            let qpos = self.op_read_start + offset;
+            read.base = self.seq.get(qpos).copied().unwrap_or(b'N');
+            read.qual = self.qual.get(qpos).copied().unwrap_or(0xff);
+        }
+        if offset + 1 == op.length() as usize {
+            // Padding is skipped, like in samtools.
+            let next = self.ops[self.op + 1..].iter().find(|next| next.op_type() != 'P');
+            match next.map(|next| (next.op_type(), next.length())) {
+                Some(('I', len)) => {
+                    read.indel = len as i32;
+                    let op_read_len = if op.consumes_read() { op.length() as usize } else { 0 };
+                    let start = self.op_read_start + op_read_len;
+                    read.inserted = self.seq.get(start..start + len as usize).unwrap_or_default().to_vec();
+                }
+                Some(('D', len)) => read.indel = -(len as i32),
+                _ => {}
+            }
+        }
+        read
+    }
+}
+
+/// Record counts of a reference, a line of `samtools idxstats`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RefStats {
+    pub name: String,
+    pub length: u32,
+    pub mapped: u64,
+    /// Unmapped reads placed at the position of their mate.
+    pub unmapped: u64,
+}
+
+/// Result of [`idxstats`]. Displayed as `samtools idxstats` output, with
+/// unplaced reads on the trailing "*" line.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct IdxStats {
+    /// In header order.
+    pub refs: Vec<RefStats>,
+    /// Reads with RefID -1.
+    pub unplaced: u64,
+}
+
+impl fmt::Display for IdxStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for rec in &self.refs {
+            writeln!(f, "{}\t{}\t{}\t{}", rec.name, rec.length, rec.mapped, rec.unmapped)?;
+        }
+        writeln!(f, "*\t0\t0\t{}", self.unplaced)
+    }
+}
+
+const BAM_FUNMAP: u16 = 0x4;
+
+/// Records per reference, like `samtools idxstats`. Only RefID and Flags
+/// columns are read, they have to be enabled in parsing template. RefID
+/// blocks whose stats show a single reference are counted from their size,
+/// without decoding. Flags are decoded only if `split_unmapped` is set,
+/// otherwise all placed records count as mapped. The file doesn't have to
+/// be sorted, but sorted ones have few blocks spanning references.
+pub fn idxstats(reader: &mut Reader, split_unmapped: bool) -> io::Result<IdxStats> {
+    let fields: &[Fields] = if split_unmapped {
+        &[Fields::RefID, Fields::Flags]
+    } else {
+        &[Fields::RefID]
+    };
+    if !reader.parsing_template.check_if_active(fields) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "RefID and Flags fields have to be enabled in parsing template to compute idxstats.",
+        ));
+    }
+    let mut stats = IdxStats {
+        refs: reader
+            .file_meta
+            .get_ref_seqs()
+            .iter()
+            .map(|(name, length)| RefStats {
+                name: name.clone(),
+                length: *length,
+                mapped: 0,
+                unmapped: 0,
+            })
+            .collect(),
+        unplaced: 0,
+    };
+    let blocks: Vec<_> = reader
+        .file_meta
+        .view_blocks(&Fields::RefID)
+        .iter()
+        .map(|block| {
+            let single_ref = block.stats.as_ref().filter(|stat| stat.min_value == stat.max_value);
+            (block.numitems as usize, single_ref.map(|stat| stat_ref_id(stat.min_value)))
+        })
+        .collect();
+    let mut rec = GbamRecord::default();
+    let mut first_rec = 0;
+    for (numitems, single_ref) in blocks {
+        let recs = first_rec..first_rec + numitems;
+        first_rec += numitems;
+        match single_ref {
+            Some(-1) => stats.unplaced += numitems as u64,
+            Some(ref_id) if !split_unmapped => ref_stats(&mut stats, ref_id)?.mapped += numitems as u64,
+            _ => {
+                for rec_num in recs {
+                    let ref_id = match single_ref {
+                        Some(ref_id) => ref_id,
+                        None => {
+                            reader.get_column(&Fields::RefID)?.fill_record_field(rec_num, &mut rec)?;
+                            rec.refid.unwrap()
+                        }
+                    };
+                    if ref_id == -1 {
+                        stats.unplaced += 1;
+                        continue;
+                    }
+                    let unmapped = split_unmapped && {
+                        reader.get_column(&Fields::Flags)?.fill_record_field(rec_num, &mut rec)?;
+                        rec.flag.unwrap() & BAM_FUNMAP != 0
+                    };
+                    let counts = ref_stats(&mut stats, ref_id)?;
+                    if unmapped {
+                        counts.unmapped += 1;
+                    } else {
+                        counts.mapped += 1;
+                    }
+                }
+            }
+        }
+    }
+    Ok(stats)
+}
+
+fn ref_stats(stats: &mut IdxStats, ref_id: i32) -> io::Result<&mut RefStats> {
+    let refs_num = stats.refs.len();
+    if ref_id < 0 || ref_id as usize >= refs_num {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("RefID {} is not among {} references of the header", ref_id, refs_num),
+        ));
+    }
+    Ok(&mut stats.refs[ref_id as usize])
+}
+
+/// Bases and qualities at a sequencing cycle. Reverse strand reads are
+/// reversed and complemented back, as they were sequenced.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CycleStats {
+    /// Counts of A, C, G, T and other bases.
+    pub bases: [u64; 5],
+    /// Number of bases by quality, missing qualities are not counted.
+    pub qualities: Vec<u64>,
+}
+
+impl CycleStats {
+    /// Percentage of G and C among A, C, G and T bases, like in GCC lines
+    /// of `samtools stats`.
+    pub fn gc_percent(&self) -> f64 {
+        let acgt: u64 = self.bases[..4].iter().sum();
+        if acgt == 0 {
+            return 0.0;
+        }
+        (self.bases[1] + self.bases[2]) as f64 * 100.0 / acgt as f64
+    }
+}
+
+/// Result of [`stats`], headline numbers of `samtools stats`. Secondary
+/// and supplementary records are only counted in `non_primary_alignments`
+/// and `supplementary_alignments`. Displayed as "SN" lines of
+/// `samtools stats` output.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SamStats {
+    pub raw_total_sequences: u64,
+    pub is_sorted: bool,
+    pub first_fragments: u64,
+    pub last_fragments: u64,
+    pub reads_mapped: u64,
+    /// Paired, with both mates mapped.
+    pub reads_mapped_and_paired: u64,
+    pub reads_unmapped: u64,
+    pub reads_properly_paired: u64,
+    pub reads_paired: u64,
+    pub reads_duplicated: u64,
+    /// Mapped with MAPQ 0.
+    pub reads_mq0: u64,
+    pub reads_qc_failed: u64,
+    pub non_primary_alignments: u64,
+    pub supplementary_alignments: u64,
+    /// Lengths of sequences, clipping is ignored.
+    pub total_length: u64,
+    pub total_first_fragment_length: u64,
+    pub total_last_fragment_length: u64,
+    pub bases_mapped: u64,
+    /// Bases of M, I, = and X operations.
+    pub bases_mapped_cigar: u64,
+    pub bases_duplicated: u64,
+    /// Sum of NM tags of mapped reads.
+    pub mismatches: u64,
+    pub max_length: u64,
+    pub max_first_fragment_length: u64,
+    pub max_last_fragment_length: u64,
+    /// Mean of base qualities, 0 unless RawSequence was read.
+    pub average_quality: f64,
+    /// Of positive TLEN of mapped pairs on one reference. Unlike
+    /// `samtools stats`, the most extreme insert sizes are not trimmed.
+    pub insert_size_average: f64,
+    pub insert_size_sd: f64,
+    /// Empty unless RawSequence was read.
+    pub cycles: Vec<CycleStats>,
+}
+
+impl SamStats {
+    /// Mismatches per base mapped by cigar.
+    pub fn error_rate(&self) -> f64 {
+        ratio(self.mismatches, self.bases_mapped_cigar)
+    }
+
+    pub fn average_length(&self) -> f64 {
+        ratio(self.total_length, self.raw_total_sequences)
+    }
+
+    pub fn average_first_fragment_length(&self) -> f64 {
+        ratio(self.total_first_fragment_length, self.first_fragments)
+    }
+
+    pub fn average_last_fragment_length(&self) -> f64 {
+        ratio(self.total_last_fragment_length, self.last_fragments)
+    }
+
+    pub fn properly_paired_percent(&self) -> f64 {
+        ratio(self.reads_properly_paired, self.raw_total_sequences) * 100.0
+    }
+}
+
+fn ratio(num: u64, denom: u64) -> f64 {
+    if denom == 0 {
+        return 0.0;
+    }
+    num as f64 / denom as f64
+}
+
+/// Formats like `%e` of C, e.g. "7.894737e-02".
+fn c_exp(value: f64) -> String {
+    let formatted = format!("{:.6e}", value);
+    let (mantissa, exp) = formatted.split_once('e').unwrap();
+    let exp: i32 = exp.parse().unwrap();
+    let sign = if exp < 0 { '-' } else { '+' };
+    format!("{}e{}{:02}", mantissa, sign, exp.abs())
+}
+
+impl fmt::Display for SamStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let lines = [
+            (
+                "raw total sequences",
+                self.raw_total_sequences.to_string(),
+                "excluding supplementary and secondary reads",
+            ),
+            ("filtered sequences", String::from("0"), ""),
+            ("sequences", self.raw_total_sequences.to_string(), ""),
+            ("is sorted", u8::from(self.is_sorted).to_string(), ""),
+            ("1st fragments", self.first_fragments.to_string(), ""),
+            ("last fragments", self.last_fragments.to_string(), ""),
+            ("reads mapped", self.reads_mapped.to_string(), ""),
+            (
+                "reads mapped and paired",
+                self.reads_mapped_and_paired.to_string(),
+                "paired-end technology bit set + both mates mapped",
+            ),
+            ("reads unmapped", self.reads_unmapped.to_string(), ""),
+            ("reads properly paired", self.reads_properly_paired.to_string(), "proper-pair bit set"),
+            ("reads paired", self.reads_paired.to_string(), "paired-end technology bit set"),
+            ("reads duplicated", self.reads_duplicated.to_string(), "PCR or optical duplicate bit set"),
+            ("reads MQ0", self.reads_mq0.to_string(), "mapped and MQ=0"),
+            ("reads QC failed", self.reads_qc_failed.to_string(), ""),
+            ("non-primary alignments", self.non_primary_alignments.to_string(), ""),
+            ("supplementary alignments", self.supplementary_alignments.to_string(), ""),
+            ("total length", self.total_length.to_string(), "ignores clipping"),
+            ("total first fragment length", self.total_first_fragment_length.to_string(), "ignores clipping"),
+            ("total last fragment length", self.total_last_fragment_length.to_string(), "ignores clipping"),
+            ("bases mapped", self.bases_mapped.to_string(), "ignores clipping"),
+            ("bases mapped (cigar)", self.bases_mapped_cigar.to_string(), "more accurate"),
+            ("bases trimmed", String::from("0"), ""),
+            ("bases duplicated", self.bases_duplicated.to_string(), ""),
+            ("mismatches", self.mismatches.to_string(), "from NM fields"),
+            ("error rate", c_exp(self.error_rate()), "mismatches / bases mapped (cigar)"),
+            ("average length", format!("{:.0}", self.average_length()), ""),
+            ("average first fragment length", format!("{:.0}", self.average_first_fragment_length()), ""),
+            ("average last fragment length", format!("{:.0}", self.average_last_fragment_length()), ""),
+            ("maximum length", self.max_length.to_string(), ""),
+            ("maximum first fragment length", self.max_first_fragment_length.to_string(), ""),
+            ("maximum last fragment length", self.max_last_fragment_length.to_string(), ""),
+            ("average quality", format!("{:.1}", self.average_quality), ""),
+            ("insert size average", format!("{:.1}", self.insert_size_average), ""),
+            ("insert size standard deviation", format!("{:.1}", self.insert_size_sd), ""),
+            ("percentage of properly paired reads (%)", format!("{:.1}", self.properly_paired_percent()), ""),
+        ];
+        for (name, value, comment) in &lines {
+            write!(f, "SN\t{}:\t{}", name, value)?;
+            if !comment.is_empty() {
+                write!(f, "\t# {}", comment)?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+/// Fields read by [`stats`], RawSequence is optional.
+pub const STATS_FIELDS: [Fields; 8] = [
+    Fields::RefID,
+    Fields::Flags,
+    Fields::Mapq,
+    Fields::NextRefID,
+    Fields::TemplateLength,
+    Fields::RawCigar,
+    Fields::RawTags,
+    Fields::RawQual,
+];
+
+const BAM_FPAIRED: u16 = 0x1;
+const BAM_FPROPER_PAIR: u16 = 0x2;
+const BAM_FMUNMAP: u16 = 0x8;
+const BAM_FREVERSE: u16 = 0x10;
+const BAM_FREAD2: u16 = 0x80;
+const BAM_FSECONDARY: u16 = 0x100;
+const BAM_FQCFAIL: u16 = 0x200;
+const BAM_FDUP: u16 = 0x400;
+const BAM_FSUPPLEMENTARY: u16 = 0x800;
+
+/// Headline numbers of `samtools stats`, computed column by column: read
+/// lengths come from SequenceLength, the index of RawQual, so sequences and
+/// qualities are not decompressed for them, and tags are decoded only for
+/// mapped reads. Fields of `STATS_FIELDS` have to be enabled in parsing
+/// template. Per-cycle stats and average quality are computed if
+/// RawSequence is enabled too.
+pub fn stats(reader: &mut Reader) -> io::Result<SamStats> {
+    if !reader.parsing_template.check_if_active(&STATS_FIELDS) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "RefID, Flags, Mapq, NextRefID, TemplateLength, RawCigar, RawTags and RawQual fields \
+             have to be enabled in parsing template to compute stats.",
+        ));
+    }
+    let num_records = reader.num_records();
+    if reader.file_meta.count_items(&Fields::SequenceLength) != num_records as u64 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "File was written without RawQual column, read lengths are unknown.",
+        ));
+    }
+    let per_cycle = reader.parsing_template.check_if_active(&[Fields::RawSequence, Fields::RawQual]);
+    // Offsets in SequenceLength restart at first records of RawQual blocks.
+    let qual_starts: HashSet<u64> = reader.file_meta.block_starts(&Fields::RawQual).into_iter().collect();
+    let mut stats = SamStats {
+        is_sorted: reader.file_meta.get_sort_order() == SortOrder::Coordinate,
+        ..Default::default()
+    };
+    let (mut quality_sum, mut quality_bases) = (0u64, 0u64);
+    let (mut pairs, mut insert_sum, mut insert_sq_sum) = (0u64, 0f64, 0f64);
+    let mut rec = GbamRecord::default();
+    let mut prev_end = 0;
+    for rec_num in 0..num_records {
+        let end = reader
+            .get_column(&Fields::SequenceLength)?
+            .raw_item(rec_num)?
+            .read_u32::<LittleEndian>()? as u64;
+        let len = if qual_starts.contains(&(rec_num as u64)) { end } else { end - prev_end };
+        prev_end = end;
+
+        reader.get_column(&Fields::Flags)?.fill_record_field(rec_num, &mut rec)?;
+        let flag = rec.flag.unwrap();
+        if flag & BAM_FQCFAIL != 0 {
+            stats.reads_qc_failed += 1;
+        }
+        if flag & BAM_FSECONDARY != 0 {
+            stats.non_primary_alignments += 1;
+            continue;
+        }
+        if flag & BAM_FSUPPLEMENTARY != 0 {
+            stats.supplementary_alignments += 1;
+            continue;
+        }
+        stats.raw_total_sequences += 1;
+        stats.total_length += len;
+        stats.max_length = stats.max_length.max(len);
+        // Unpaired reads are first fragments.
+        if flag & BAM_FREAD2 != 0 {
+            stats.last_fragments += 1;
+            stats.total_last_fragment_length += len;
+            stats.max_last_fragment_length = stats.max_last_fragment_length.max(len);
+        } else {
+            stats.first_fragments += 1;
+            stats.total_first_fragment_length += len;
+            stats.max_first_fragment_length = stats.max_first_fragment_length.max(len);
+        }
+        let paired = flag & BAM_FPAIRED != 0;
+        if paired {
+            stats.reads_paired += 1;
+        }
+        if flag & BAM_FDUP != 0 {
+            stats.reads_duplicated += 1;
+            stats.bases_duplicated += len;
+        }
+        if flag & BAM_FUNMAP != 0 {
+            stats.reads_unmapped += 1;
+        } else {
+            add_mapped(reader, rec_num, len, &mut rec, &mut stats)?;
+            if paired && flag & BAM_FMUNMAP == 0 {
+                stats.reads_mapped_and_paired += 1;
+                if flag & BAM_FPROPER_PAIR != 0 {
+                    stats.reads_properly_paired += 1;
+                }
+                for field in [Fields::RefID, Fields::NextRefID, Fields::TemplateLength] {
+                    reader.get_column(&field)?.fill_record_field(rec_num, &mut rec)?;
+                }
+                let tlen = rec.tlen.unwrap();
+                if rec.refid == rec.next_ref_id && tlen > 0 {
+                    pairs += 1;
+                    insert_sum += tlen as f64;
+                    insert_sq_sum += (tlen as f64).powi(2);
+                }
+            }
+        }
+        if per_cycle {
+            reader.get_column(&Fields::RawSequence)?.fill_record_field(rec_num, &mut rec)?;
+            reader.get_column(&Fields::RawQual)?.fill_record_field(rec_num, &mut rec)?;
+            let (sum, bases) = add_cycles(&mut stats.cycles, &rec, flag & BAM_FREVERSE != 0);
+            quality_sum += sum;
+            quality_bases += bases;
+        }
+    }
+    stats.average_quality = ratio(quality_sum, quality_bases);
+    if pairs > 0 {
+        stats.insert_size_average = insert_sum / pairs as f64;
+        let var = insert_sq_sum / pairs as f64 - stats.insert_size_average.powi(2);
+        stats.insert_size_sd = var.max(0.0).sqrt();
+    }
+    Ok(stats)
+}
+
+fn add_mapped(
+    reader: &mut Reader,
+    rec_num: usize,
+    len: u64,
+    rec: &mut GbamRecord,
+    stats: &mut SamStats,
+) -> io::Result<()> {
+    stats.reads_mapped += 1;
+    stats.bases_mapped += len;
+    for field in [Fields::Mapq, Fields::RawCigar, Fields::RawTags] {
+        reader.get_column(&field)?.fill_record_field(rec_num, rec)?;
+    }
+    if rec.mapq == Some(0) {
+        stats.reads_mq0 += 1;
+    }
+    for op in rec.cigar.as_ref().unwrap().ops() {
+        if matches!(op.op_type(), 'M' | 'I' | '=' | 'X') {
+            stats.bases_mapped_cigar += u64::from(op.length());
+        }
+    }
+    if let Some(nm) = rec.get_tag(b"NM")?.and_then(|value| value.as_int()) {
+        stats.mismatches += nm.max(0) as u64;
+    }
+    Ok(())
+}
+
+// Adds bases and qualities of `rec` in sequencing order, returns sum and
+// number of its qualities.
+fn add_cycles(cycles: &mut Vec<CycleStats>, rec: &GbamRecord, reverse: bool) -> (u64, u64) {
+    let seq = rec.seq.as_deref().unwrap_or_default().as_bytes();
+    let qual = rec.qual.as_deref().unwrap_or_default();
+    if cycles.len() < seq.len() {
+        cycles.resize(seq.len(), CycleStats::default());
+    }
+    let (mut sum, mut count) = (0, 0);
+    for (i, &base) in seq.iter().enumerate() {
+        let (cycle, base) = if reverse {
+            (seq.len() - 1 - i, complement(base))
+        } else {
+            (i, base)
+        };
+        let stats = &mut cycles[cycle];
+        let base_idx = match base {
+            b'A' => 0,
+            b'C' => 1,
+            b'G' => 2,
+            b'T' => 3,
+            _ => 4,
+        };
+        stats.bases[base_idx] += 1;
+        // Missing qualities are stored as 0xff.
+        if let Some(&q) = qual.get(i).filter(|&&q| q != 0xff) {
+            let q = q as usize;
+            if stats.qualities.len() <= q {
+                stats.qualities.resize(q + 1, 0);
+            }
+            stats.qualities[q] += 1;
+            sum += q as u64;
+            count += 1;
+        }
+    }
+    (sum, count)
+}
+
+fn complement(base: u8) -> u8 {
+    match base {
+        b'A' => b'T',
+        b'C' => b'G',
+        b'G' => b'C',
+        b'T' => b'A',
+        other => other,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bam::convert::{bam_to_gbam, Bam2GbamOptions};
+    use crate::test_utils::{new_test_writer, open_test_file, write_test_file, TestRecord};
+    use crate::text_import::{sam_to_gbam, TextImportOptions};
+    use bam_tools::record::fields::Fields;
+    use std::fs::File;
+    use std::io::BufReader;
+    use std::path::{Path, PathBuf};
+    use tempdir::TempDir;
+
+    fn brute_force_median(sorted: &[f64]) -> f64 {
+        let n = sorted.len();
+        (sorted[(n - 1) / 2] + sorted[n / 2]) / 2.0
+    }
+
+    #[test]
+    fn test_insert_size_histogram() {
+        let dir = TempDir::new("gbam_analytics").unwrap();
+        let path = dir.path().join("pairs.gbam");
+        let mut writer = new_test_writer(&path, "");
+        writer.register_collector(Fields::TemplateLength, Box::new(InsertSizeHistogram::new(1000)));
+
+        let mut sizes = Vec::new();
+        for i in 0..5_001i32 {
+            let tlen = 150 + (i * 37) % 300 + if i % 10 == 0 { 400 } else { 0 };
+            for (pos, tlen) in [(i, tlen), (i + tlen, -tlen)] {
+                let mut rec = TestRecord::new(0, pos, &format!("pair{}", i));
+                rec.tlen = tlen;
+                writer.push_record(&rec.to_raw(), false).unwrap();
+            }
+            sizes.push(tlen as f64);
+        }
+        for tlen in [0, 5_000] {
+            let mut rec = TestRecord::new(0, 0, "odd");
+            rec.tlen = tlen;
+            writer.push_record(&rec.to_raw(), false).unwrap();
+        }
+        writer.finish_with_summary(false).unwrap();
+
+        let reader = open_test_file(&path);
+        let summary = reader.analytics(InsertSizeHistogram::NAME).unwrap();
+        let stats: InsertSizeStats = serde_json::from_value(summary.clone()).unwrap();
+
+        sizes.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let n = sizes.len() as f64;
+        let mean = sizes.iter().sum::<f64>() / n;
+        let sd = (sizes.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / n).sqrt();
+        let median = brute_force_median(&sizes);
+        let mut deviations: Vec<f64> = sizes.iter().map(|v| (v - median).abs()).collect();
+        deviations.sort_by(|a, b| a.partial_cmp(b).unwrap());
+
+        assert_eq!(stats.count, sizes.len() as u64);
+        assert_eq!(stats.above_max, 1);
+        assert!((stats.mean - mean).abs() < 1e-9);
+        assert!((stats.sd - sd).abs() < 1e-9);
+        assert_eq!(stats.median, median);
+        assert_eq!(stats.mad, brute_force_median(&deviations));
+        let mut histogram: Vec<(u32, u64)> = Vec::new();
+        for &v in &sizes {
+            match histogram.last_mut() {
+                Some((size, n)) if *size == v as u32 => *n += 1,
+                _ => histogram.push((v as u32, 1)),
+            }
+        }
+        assert_eq!(stats.histogram, histogram);
+    }
+
+    #[test]
+    fn test_no_analytics() {
+        let dir = TempDir::new("gbam_analytics").unwrap();
+        let path = dir.path().join("plain.gbam");
+        write_test_file(&path, "", &[TestRecord::default()]);
+        assert!(open_test_file(&path).analytics(InsertSizeHistogram::NAME).is_none());
+    }
+
+    #[test]
+    fn test_depth() {
+        let dir = TempDir::new("gbam_analytics").unwrap();
+        let path = dir.path().join("sorted.gbam");
+        let with_cigar = |pos: i32, cigar: &[u32]| {
+            let mut rec = TestRecord::new(0, pos, "read");
+            rec.cigar = cigar.to_vec();
+            rec
+        };
+        let mut dup = with_cigar(20, &[4 << 4]);
+        dup.flag = 0x400;
+        let mut low_mapq = with_cigar(20, &[4 << 4]);
+        low_mapq.mapq = 5;
+        let records = [
+            // Starts before the region.
+            with_cigar(5, &[10 << 4]),
+            // 3M2D3M
+            with_cigar(12, &[3 << 4, 2 << 4 | 2, 3 << 4]),
+            // 2S2M5N2M1I2M2H
+            with_cigar(14, &[2 << 4 | 4, 2 << 4, 5 << 4 | 3, 2 << 4, 1 << 4 | 1, 2 << 4, 2 << 4 | 5]),
+            dup,
+            low_mapq,
+            // Ends after the region.
+            with_cigar(25, &[10 << 4]),
+            TestRecord::new(1, 10, "other_ref"),
+        ];
+        write_test_file(&path, "@HD\tVN:1.6\tSO:coordinate\n", &records);
+
+        let mut reader = open_test_file(&path);
+        let region = Region::new(0, 10, 30);
+        let mut expected = vec![
+            1, 1, 2, 2, 3, 1, 0, 1, 1, 1, 1, 2, 2, 2, 1, 1, 1, 1, 1, 1,
+        ];
+        assert_eq!(depth(&mut reader, &region, &DepthOptions::default()).unwrap(), expected);
+        // Template is restored.
+        assert!(reader.parsing_template.check_if_active(&[Fields::RawSequence]));
+
+        let options = DepthOptions {
+            min_mapq: 10,
+            ..Default::default()
+        };
+        for pos in 20..24 {
+            expected[pos - 10] -= 1;
+        }
+        assert_eq!(depth(&mut reader, &region, &options).unwrap(), expected);
+
+        let options = DepthOptions {
+            required_flags: 0x400,
+            excluded_flags: 0,
+            min_mapq: 0,
+        };
+        let depths = depth(&mut reader, &Region::new(0, 18, 26), &options).unwrap();
+        assert_eq!(depths, vec![0, 0, 1, 1, 1, 1, 0, 0]);
+
+        let unsorted = dir.path().join("unsorted.gbam");
+        write_test_file(&unsorted, "", &records);
+        let mut reader = open_test_file(&unsorted);
+        assert!(depth(&mut reader, &region, &DepthOptions::default()).is_err());
+    }
+
+    #[test]
+    fn test_pileup() {
+        let dir = TempDir::new("gbam_analytics").unwrap();
+        let path = dir.path().join("sorted.gbam");
+        let read = |pos: i32, name: &str, cigar: &[u32], seq: &str, qual: &[u8]| TestRecord {
+            cigar: cigar.to_vec(),
+            seq: seq.to_string(),
+            qual: qual.to_vec(),
+            ..TestRecord::new(0, pos, name)
+        };
+        let mut records = vec![
+            read(100, "a", &[10 << 4], "ACGTACGTAC", &[30; 10]),
+            // 2M3N3M
+            read(101, "b", &[2 << 4, 3 << 4 | 3, 3 << 4], "CGGTA", &[30; 5]),
+            // 2M2D4M
+            read(102, "c", &[2 << 4, 2 << 4 | 2, 4 << 4], "GTCGTA", &[30; 6]),
+            // 1M2I3M
+            read(103, "d", &[1 << 4, 2 << 4 | 1, 3 << 4], "TGGACG", &[30; 6]),
+            read(104, "pair", &[4 << 4], "ACGT", &[30; 4]),
+            read(105, "dup", &[3 << 4], "CGT", &[30; 3]),
+            read(105, "anomalous", &[3 << 4], "CGT", &[30; 3]),
+            read(106, "pair", &[4 << 4], "GAAC", &[20; 4]),
+            read(106, "low_qual", &[2 << 4], "GG", &[10, 30]),
+        ];
+        records[1].flag = 0x10;
+        records[4].flag = 0x1 | 0x2 | 0x40;
+        records[5].flag = 0x400;
+        records[6].flag = 0x1;
+        records[7].flag = 0x1 | 0x2 | 0x10 | 0x80;
+        write_test_file(&path, "@HD\tVN:1.6\tSO:coordinate\n", &records);
+
+        let mut reader = open_test_file(&path);
+        let mut columns = Vec::new();
+        let region = Region::new(0, 90, 120);
+        pileup(&mut reader, &region, &PileupOptions::default(), |pos, reads| {
+            columns.push((pos, reads.to_vec()))
+        })
+        .unwrap();
+        // Template is restored.
+        assert!(reader.parsing_template.check_if_active(&[Fields::RawTags]));
+        let positions: Vec<i32> = columns.iter().map(|(pos, _)| *pos).collect();
+        assert_eq!(positions, (100..110).collect::<Vec<_>>());
+
+        // Bases as printed by `samtools mpileup -B`, with reference skips as
+        // '>' or '<' and deletions as '*'.
+        let bases = |reads: &[PileupRead]| -> String {
+            reads
+                .iter()
+                .map(|read| match (read.is_del, read.is_refskip, read.is_reverse) {
+                    (true, _, _) => '*',
+                    (_, true, false) => '>',
+                    (_, true, true) => '<',
+                    (_, _, false) => read.base as char,
+                    (_, _, true) => read.base.to_ascii_lowercase() as char,
+                })
+                .collect()
+        };
+        let expected = [
+            "A", "Cc", "GgG", "T<TT", "A<*AA", "C<*CC", "GgCGG", "TtGTG", "AaTa", "CAc",
+        ];
+        for ((_, reads), expected) in columns.iter().zip(expected.iter()) {
+            assert_eq!(&bases(reads), expected);
+        }
+        let rec_nums = |pos: i32| -> Vec<usize> {
+            columns[(pos - 100) as usize].1.iter().map(|read| read.rec_num).collect()
+        };
+        assert_eq!(rec_nums(106), vec![0, 1, 2, 3, 4]);
+        assert_eq!(rec_nums(107), vec![0, 1, 2, 4, 8]);
+        assert_eq!(rec_nums(108), vec![0, 1, 2, 7]);
+
+        // Indels following the position.
+        let at_103 = &columns[3].1;
+        assert_eq!(at_103[2].indel, -2);
+        assert_eq!((at_103[3].indel, at_103[3].inserted.as_slice()), (2, &b"GG"[..]));
+        assert!(at_103.iter().take(2).all(|read| read.indel == 0));
+        // Agreeing mates, the first one gets both qualities. Disagreeing
+        // ones, the better one keeps 80% of its quality.
+        assert_eq!(columns[6].1[4].qual, 50);
+        assert_eq!(columns[7].1[3].qual, 24);
+
+        let options = PileupOptions {
+            dedup_mate_overlaps: false,
+            skip_anomalous_pairs: false,
+            min_base_quality: 0,
+            ..Default::default()
+        };
+        let mut columns = Vec::new();
+        pileup(&mut reader, &Region::new(0, 105, 107), &options, |pos, reads| {
+            columns.push((pos, bases(reads)))
+        })
+        .unwrap();
+        assert_eq!(columns, vec![(105, String::from("C<*CCC")), (106, String::from("GgCGGGgG"))]);
+
+        let unsorted = dir.path().join("unsorted.gbam");
+        write_test_file(&unsorted, "", &records);
+        let mut reader = open_test_file(&unsorted);
+        assert!(pileup(&mut reader, &region, &PileupOptions::default(), |_, _| ()).is_err());
+    }
+
+    #[test]
+    fn test_idxstats() {
+        let dir = TempDir::new("gbam_analytics").unwrap();
+        let path = dir.path().join("idxstats.gbam");
+        let mut records: Vec<_> = (0..6).map(|i| TestRecord::new(0, i, "chr1_read")).collect();
+        // Placed at the position of its mate.
+        records[5].flag = 0x4;
+        records.extend((0..3).map(|i| TestRecord::new(1, 10 + i, "chr2_read")));
+        records.extend((0..5).map(|_| TestRecord {
+            flag: 0x4,
+            ..TestRecord::new(-1, -1, "unplaced")
+        }));
+        let mut writer = new_test_writer(&path, "@HD\tVN:1.6\tSO:coordinate\n");
+        // RefID blocks: [0, 0, 0, 0], [0, 0, 1, 1], [1, -1, -1, -1], [-1, -1].
+        writer.set_rows_per_block(4);
+        writer.push_records(&records.iter().map(TestRecord::to_raw).collect::<Vec<_>>(), false).unwrap();
+        writer.finish_with_summary(false).unwrap();
+
+        let mut reader = open_test_file(&path);
+        let stats = idxstats(&mut reader, true).unwrap();
+        assert_eq!(
+            stats.to_string(),
+            "chr1\t1000000\t5\t1\nchr2\t1000000\t3\t0\nchr3\t1000000\t0\t0\n*\t0\t0\t5\n"
+        );
+        // Blocks of a single reference are not decoded, of unplaced reads
+        // neither their flags.
+        assert_eq!(reader.blocks_decompressed(&Fields::RefID), 2);
+        assert_eq!(reader.blocks_decompressed(&Fields::Flags), 3);
+
+        let mut reader = Reader::from_path(&path, ParsingTemplate::new_with(&[Fields::RefID])).unwrap();
+        assert!(idxstats(&mut reader, true).is_err());
+        let stats = idxstats(&mut reader, false).unwrap();
+        let counts: Vec<_> = stats.refs.iter().map(|rec| (rec.mapped, rec.unmapped)).collect();
+        assert_eq!(counts, vec![(6, 0), (3, 0), (0, 0)]);
+        assert_eq!(stats.unplaced, 5);
+        assert_eq!(reader.blocks_decompressed(&Fields::RefID), 2);
+        assert_eq!(reader.blocks_decompressed(&Fields::Flags), 0);
+    }
+
+    /// `SamStats` of SAM or BAM file at `path` without per-cycle stats,
+    /// counted the way `samtools stats` does from records decoded by htslib.
+    fn htslib_stats(path: &Path) -> SamStats {
+        use rust_htslib::bam::{self, record::Aux, record::Cigar, Read};
+        let mut reader = bam::Reader::from_path(path).unwrap();
+        let header = String::from_utf8_lossy(reader.header().as_bytes()).into_owned();
+        let mut stats = SamStats {
+            is_sorted: header.lines().next().is_some_and(|hd| hd.contains("SO:coordinate")),
+            ..Default::default()
+        };
+        let (mut quality_sum, mut quality_bases) = (0u64, 0u64);
+        let mut inserts = Vec::new();
+        for rec in reader.records() {
+            let rec = rec.unwrap();
+            let len = rec.seq_len() as u64;
+            stats.reads_qc_failed += u64::from(rec.is_quality_check_failed());
+            if rec.is_secondary() {
+                stats.non_primary_alignments += 1;
+                continue;
+            }
+            if rec.is_supplementary() {
+                stats.supplementary_alignments += 1;
+                continue;
+            }
+            stats.raw_total_sequences += 1;
+            stats.total_length += len;
+            stats.max_length = stats.max_length.max(len);
+            if rec.is_last_in_template() {
+                stats.last_fragments += 1;
+                stats.total_last_fragment_length += len;
+                stats.max_last_fragment_length = stats.max_last_fragment_length.max(len);
+            } else {
+                stats.first_fragments += 1;
+                stats.total_first_fragment_length += len;
+                stats.max_first_fragment_length = stats.max_first_fragment_length.max(len);
+            }
+            stats.reads_paired += u64::from(rec.is_paired());
+            if rec.is_duplicate() {
+                stats.reads_duplicated += 1;
+                stats.bases_duplicated += len;
+            }
+            if rec.is_unmapped() {
+                stats.reads_unmapped += 1;
+            } else {
+                stats.reads_mapped += 1;
+                stats.bases_mapped += len;
+                stats.reads_mq0 += u64::from(rec.mapq() == 0);
+                for op in rec.cigar().iter() {
+                    if let Cigar::Match(n) | Cigar::Ins(n) | Cigar::Equal(n) | Cigar::Diff(n) = op {
+                        stats.bases_mapped_cigar += u64::from(*n);
+                    }
+                }
+                stats.mismatches += match rec.aux(b"NM") {
+                    Ok(Aux::U8(nm)) => u64::from(nm),
+                    Ok(Aux::U16(nm)) => u64::from(nm),
+                    Ok(Aux::U32(nm)) => u64::from(nm),
+                    Ok(Aux::I8(nm)) => nm.max(0) as u64,
+                    Ok(Aux::I16(nm)) => nm.max(0) as u64,
+                    Ok(Aux::I32(nm)) => nm.max(0) as u64,
+                    _ => 0,
+                };
+                if rec.is_paired() && !rec.is_mate_unmapped() {
+                    stats.reads_mapped_and_paired += 1;
+                    stats.reads_properly_paired += u64::from(rec.is_proper_pair());
+                    if rec.tid() == rec.mtid() && rec.insert_size() > 0 {
+                        inserts.push(rec.insert_size() as f64);
+                    }
+                }
+            }
+            for &q in rec.qual().iter().filter(|&&q| q != 0xff) {
+                quality_sum += u64::from(q);
+                quality_bases += 1;
+            }
+        }
+        stats.average_quality = ratio(quality_sum, quality_bases);
+        if !inserts.is_empty() {
+            let n = inserts.len() as f64;
+            stats.insert_size_average = inserts.iter().sum::<f64>() / n;
+            let var = inserts.iter().map(|x| x * x).sum::<f64>() / n - stats.insert_size_average.powi(2);
+            stats.insert_size_sd = var.max(0.0).sqrt();
+        }
+        stats
+    }
+
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("../test_data").join(name)
+    }
+
+    #[test]
+    fn test_stats() {
+        let dir = TempDir::new("gbam_analytics").unwrap();
+        let path = dir.path().join("stats.gbam");
+        // A proper pair, one of its reads reverse and clipped, duplicate
+        // with an insertion and MAPQ 0, QC failed, secondary, supplementary
+        // and unmapped reads.
+        let sam_path = fixture("stats.sam");
+        let input = BufReader::new(File::open(&sam_path).unwrap());
+        sam_to_gbam(input, &path, &TextImportOptions::default()).unwrap();
+
+        let mut reader = open_test_file(&path);
+        let full = stats(&mut reader).unwrap();
+        let text = full.to_string();
+        let expected = htslib_stats(&sam_path);
+        assert_eq!(expected.raw_total_sequences, 5);
+        assert_eq!(text, expected.to_string());
+        // Reverse read is counted from its last base, complemented.
+        assert_eq!(full.cycles.len(), 10);
+        assert_eq!(full.cycles[0].bases, [2, 2, 0, 1, 0]);
+        assert_eq!(full.cycles[9].bases, [1, 2, 0, 1, 0]);
+        assert_eq!(full.cycles[0].gc_percent(), 40.0);
+        assert_eq!((full.cycles[0].qualities[30], full.cycles[0].qualities[20]), (4, 1));
+
+        // Read lengths come from the index of RawQual.
+        let mut reader = Reader::from_path(&path, ParsingTemplate::new_with(&STATS_FIELDS)).unwrap();
+        let partial = stats(&mut reader).unwrap();
+        assert!(partial.cycles.is_empty());
+        assert_eq!(
+            SamStats {
+                average_quality: full.average_quality,
+                cycles: full.cycles.clone(),
+                ..partial
+            },
+            full
+        );
+        assert_eq!(reader.blocks_decompressed(&Fields::RawSequence), 0);
+        assert_eq!(reader.blocks_decompressed(&Fields::RawQual), 0);
+
+        let mut reader = Reader::from_path(&path, ParsingTemplate::new_with(&[Fields::RefID])).unwrap();
+        assert!(stats(&mut reader).is_err());
+    }
+
+    #[test]
+    fn test_stats_of_bam() {
+        let dir = TempDir::new("gbam_analytics").unwrap();
+        let path = dir.path().join("little.gbam");
+        let bam_path = fixture("little.bam");
+        bam_to_gbam(&bam_path, &path, &Bam2GbamOptions::default()).unwrap();
+
+        let mut reader = open_test_file(&path);
+        let bam_stats = stats(&mut reader).unwrap();
+        assert_eq!(bam_stats.raw_total_sequences, reader.num_records() as u64);
+        assert!(bam_stats.mismatches > 0 && bam_stats.reads_mq0 > 0);
+        assert_eq!(bam_stats.to_string(), htslib_stats(&bam_path).to_string());
+    }
+}
@@ -0,0 +1,131 @@
+//! BGZF writer keeping track of virtual offsets of written data, to index
+//! BAM files while they are written.
+use std::io::{self, Write};
+
+use byteorder::{LittleEndian, WriteBytesExt};
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+
+/// Uncompressed bytes per block, as written by htslib.
+pub const BLOCK_DATA_SIZE: usize = 0xff00;
+
+// Gzip header with BC extra subfield, BSIZE follows.
+const BLOCK_HEADER: [u8; 16] = [
+    0x1f, 0x8b, 0x08, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0x06, 0x00, b'B', b'C', 0x02, 0x00,
+];
+
+/// Empty block closing BGZF files.
+pub const EOF_BLOCK: [u8; 28] = [
+    0x1f, 0x8b, 0x08, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0x06, 0x00, 0x42, 0x43, 0x02, 0x00,
+    0x1b, 0x00, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+];
+
+/// Compresses written data into BGZF blocks of `BLOCK_DATA_SIZE` bytes.
+pub struct BgzfWriter<W: Write> {
+    inner: W,
+    buf: Vec<u8>,
+    // Compressed bytes written so far, the offset of the next block.
+    position: u64,
+    compression: Compression,
+}
+
+impl<W: Write> BgzfWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            buf: Vec::with_capacity(BLOCK_DATA_SIZE),
+            position: 0,
+            compression: Compression::default(),
+        }
+    }
+
+    /// Virtual offset of the next byte written: offset of its block in the
+    /// file shifted by 16 bits, ored with its offset in block data. Full
+    /// blocks are written right away, so the offset of data ending a block
+    /// points to the start of the next one, as htslib reports it.
+    pub fn virtual_position(&self) -> u64 {
+        (self.position << 16) | self.buf.len() as u64
+    }
+
+    /// Writes buffered data as a block, even if it is not full. Does nothing
+    /// if there is no data.
+    pub fn flush_block(&mut self) -> io::Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+        let mut encoder = DeflateEncoder::new(Vec::with_capacity(self.buf.len()), self.compression);
+        encoder.write_all(&self.buf)?;
+        let compressed = encoder.finish()?;
+        // Header, BSIZE, compressed data, CRC32 and ISIZE.
+        let block_size = BLOCK_HEADER.len() + 2 + compressed.len() + 8;
+        if block_size > 1 << 16 {
+            return Err(io::Error::other(format!(
+                "Compressed BGZF block takes {} bytes, more than 64 KB",
+                block_size
+            )));
+        }
+        self.inner.write_all(&BLOCK_HEADER)?;
+        self.inner.write_u16::<LittleEndian>((block_size - 1) as u16)?;
+        self.inner.write_all(&compressed)?;
+        self.inner.write_u32::<LittleEndian>(crc32fast::hash(&self.buf))?;
+        self.inner.write_u32::<LittleEndian>(self.buf.len() as u32)?;
+        self.position += block_size as u64;
+        self.buf.clear();
+        Ok(())
+    }
+
+    /// Writes the remaining data and EOF block, and returns the inner writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.flush_block()?;
+        self.inner.write_all(&EOF_BLOCK)?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for BgzfWriter<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let len = data.len().min(BLOCK_DATA_SIZE - self.buf.len());
+        self.buf.extend_from_slice(&data[..len]);
+        if self.buf.len() == BLOCK_DATA_SIZE {
+            self.flush_block()?;
+        }
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.flush_block()?;
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::MultiGzDecoder;
+    use std::io::Read;
+
+    #[test]
+    fn test_virtual_positions() {
+        let mut writer = BgzfWriter::new(Vec::new());
+        writer.write_all(&[7; 100]).unwrap();
+        assert_eq!(writer.virtual_position(), 100);
+        writer.write_all(&[1; BLOCK_DATA_SIZE - 100]).unwrap();
+        // A full block is written, next data starts in the following one.
+        let second_block = writer.virtual_position() >> 16;
+        assert!(second_block > 0);
+        assert_eq!(writer.virtual_position() & 0xffff, 0);
+        writer.write_all(&[2; 10]).unwrap();
+        assert_eq!(writer.virtual_position(), (second_block << 16) | 10);
+
+        let bytes = writer.finish().unwrap();
+        assert!(bytes.ends_with(&EOF_BLOCK));
+        // BSIZE of the first block.
+        assert_eq!(u64::from(u16::from_le_bytes([bytes[16], bytes[17]])) + 1, second_block);
+        let mut data = Vec::new();
+        MultiGzDecoder::new(&bytes[..]).read_to_end(&mut data).unwrap();
+        assert_eq!(data.len(), BLOCK_DATA_SIZE + 10);
+        assert!(data[..100].iter().all(|&b| b == 7));
+        assert!(data[BLOCK_DATA_SIZE..].iter().all(|&b| b == 2));
+    }
+}
@@ -0,0 +1,481 @@
+//! Conversion between BAM and GBAM files. Settings are passed in option
+//! structs built from `Default` with `with_*()` methods, so new options
+//! don't change signatures of `bam_to_gbam()` and `gbam_to_bam()`.
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+
+use bam_tools::parse_reference_sequences;
+use bam_tools::record::bamrawrecord::BAMRawRecord;
+use bam_tools::record::fields::{Fields, FIELDS_NUM};
+use bam_tools::sorting::sort::{self, SortBy, TempFilesMode};
+use tempdir::TempDir;
+
+use super::gbam_to_bam::{gbam_to_bam_with_index, write_bam};
+use crate::error::with_path;
+use crate::reader::parse_tmplt::ParsingTemplate;
+use crate::reader::reader::Reader;
+use crate::writer::{WriteTemplate, WriterSettings};
+use crate::{Codecs, Writer, MAX_BLOCK_SIZE_LIMIT, MEGA_BYTE_SIZE, MIN_BLOCK_SIZE_LIMIT};
+
+/// Memory taken by sorted runs before they are spilled.
+const MEM_LIMIT: usize = 2000 * MEGA_BYTE_SIZE;
+
+/// Options of `bam_to_gbam()`.
+#[derive(Clone, Debug)]
+pub struct Bam2GbamOptions {
+    codec: Codecs,
+    threads: usize,
+    sorted_input: bool,
+    sort: bool,
+    sort_temp_mode: TempFilesMode,
+    temp_dir: Option<PathBuf>,
+    sort_index: bool,
+    rows_per_block: Option<u32>,
+    block_size_limits: HashMap<Fields, usize>,
+    content_digest: bool,
+    memory_budget: Option<usize>,
+    write_template: WriteTemplate,
+    codec_map_required: bool,
+    command: String,
+}
+
+impl Default for Bam2GbamOptions {
+    /// Lz4 for every field, 4 threads, records kept in input order, blocks
+    /// cut by size only, every field written.
+    fn default() -> Self {
+        Self {
+            codec: Codecs::Lz4,
+            threads: 4,
+            sorted_input: false,
+            sort: false,
+            sort_temp_mode: TempFilesMode::RegularFiles,
+            temp_dir: None,
+            sort_index: false,
+            rows_per_block: None,
+            block_size_limits: HashMap::new(),
+            content_digest: false,
+            memory_budget: None,
+            write_template: WriteTemplate::all(),
+            codec_map_required: false,
+            command: String::new(),
+        }
+    }
+}
+
+impl Bam2GbamOptions {
+    /// Codec of every field.
+    pub fn with_codec(mut self, codec: Codecs) -> Self {
+        self.codec = codec;
+        self
+    }
+
+    /// Threads decompressing BAM and compressing GBAM blocks, each.
+    pub fn with_threads(mut self, threads: usize) -> Self {
+        self.threads = threads.max(1);
+        self
+    }
+
+    /// Marks the output coordinate sorted, for input known to be sorted.
+    pub fn with_sorted_input(mut self, sorted: bool) -> Self {
+        self.sorted_input = sorted;
+        self
+    }
+
+    /// Sorts records by coordinate while converting, keeping sorted runs as
+    /// `temp_mode` tells, in `temp_dir` or the system temporary directory.
+    pub fn with_sorting(mut self, temp_mode: TempFilesMode, temp_dir: Option<PathBuf>) -> Self {
+        self.sort = true;
+        self.sort_temp_mode = temp_mode;
+        self.temp_dir = temp_dir;
+        self
+    }
+
+    /// Writes index of the sort into `.gbai` file next to the output.
+    /// Applies only with `with_sorting()`.
+    pub fn with_sort_index(mut self, enabled: bool) -> Self {
+        self.sort_index = enabled;
+        self
+    }
+
+    /// Records per block, see `Writer::set_rows_per_block()`.
+    pub fn with_block_size(mut self, rows: u32) -> Self {
+        self.rows_per_block = Some(rows);
+        self
+    }
+
+    /// Bytes at which blocks of fields are cut, for fields which don't keep
+    /// the default, see `Writer::set_block_size_limit()`.
+    pub fn with_block_size_limits(mut self, limits: HashMap<Fields, usize>) -> Self {
+        self.block_size_limits = limits;
+        self
+    }
+
+    /// Records content digest and provenance, see
+    /// `Writer::set_content_digest()`.
+    pub fn with_digests(mut self, enabled: bool) -> Self {
+        self.content_digest = enabled;
+        self
+    }
+
+    /// Bytes buffered by the writer, see `Writer::set_memory_budget()`.
+    pub fn with_memory_budget(mut self, bytes: usize) -> Self {
+        self.memory_budget = Some(bytes);
+        self
+    }
+
+    /// Fields written, see `Writer::set_write_template()`.
+    pub fn with_write_template(mut self, template: WriteTemplate) -> Self {
+        self.write_template = template;
+        self
+    }
+
+    pub fn with_codec_map_required(mut self, required: bool) -> Self {
+        self.codec_map_required = required;
+        self
+    }
+
+    /// Command recorded in file info.
+    pub fn with_command(mut self, command: String) -> Self {
+        self.command = command;
+        self
+    }
+}
+
+/// Options of `gbam_to_bam()`.
+#[derive(Clone, Debug)]
+pub struct Gbam2BamOptions {
+    threads: usize,
+    index: bool,
+    verify_digest: bool,
+}
+
+impl Default for Gbam2BamOptions {
+    /// 4 threads, no index, no digest check.
+    fn default() -> Self {
+        Self {
+            threads: 4,
+            index: false,
+            verify_digest: false,
+        }
+    }
+}
+
+impl Gbam2BamOptions {
+    /// Threads compressing BAM. Ignored when writing index, see `with_index()`.
+    pub fn with_threads(mut self, threads: usize) -> Self {
+        self.threads = threads.max(1);
+        self
+    }
+
+    /// Writes BAI or CSI index alongside, see `gbam_to_bam_with_index()`.
+    /// Records have to be sorted by coordinate.
+    pub fn with_index(mut self, enabled: bool) -> Self {
+        self.index = enabled;
+        self
+    }
+
+    /// Checks content digest of the input before converting, failing on
+    /// mismatch or when there is none, see `Reader::verify_file_digest()`.
+    pub fn with_digests(mut self, enabled: bool) -> Self {
+        self.verify_digest = enabled;
+        self
+    }
+}
+
+/// Mode of sort temporary files by name: `file`, `lz4_file`, `ram` or
+/// `lz4_ram`.
+pub fn temp_files_mode(name: &str) -> io::Result<TempFilesMode> {
+    match name {
+        "file" => Ok(TempFilesMode::RegularFiles),
+        "lz4_file" => Ok(TempFilesMode::LZ4CompressedFiles),
+        "ram" => Ok(TempFilesMode::InMemoryBlocks),
+        "lz4_ram" => Ok(TempFilesMode::InMemoryBlocksLZ4),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Unknown sort temporary files mode {}", name),
+        )),
+    }
+}
+
+/// Converts BAM file at `src` into GBAM file at `dst`. Errors carry the
+/// path they occurred at, see `GbamError::AtPath`. On errors output is left
+/// unfinished.
+pub fn bam_to_gbam(src: &Path, dst: &Path, options: &Bam2GbamOptions) -> io::Result<()> {
+    for (field, &bytes) in &options.block_size_limits {
+        if !(MIN_BLOCK_SIZE_LIMIT..=MAX_BLOCK_SIZE_LIMIT).contains(&bytes) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Block size limit {} of field {} is not in {}..={}",
+                    bytes, field, MIN_BLOCK_SIZE_LIMIT, MAX_BLOCK_SIZE_LIMIT
+                ),
+            ));
+        }
+    }
+    // Sorting reads the input by itself, header is taken from its own reader.
+    let header_threads = if options.sort { 1 } else { options.threads };
+    let (mut bam_reader, sam_header, ref_seqs) = open_bam(src, header_threads, !options.sort)?;
+
+    let settings = WriterSettings {
+        codecs: vec![options.codec; FIELDS_NUM],
+        thread_num: options.threads,
+        collect_stats_for: vec![Fields::RefID],
+        ref_seqs,
+        sam_header,
+        full_command: options.command.clone(),
+        is_sorted: options.sorted_input || options.sort,
+        codec_map_required: options.codec_map_required,
+        ..Default::default()
+    };
+    let mut writer = Writer::create(dst, settings)?;
+    writer.set_write_template(options.write_template.clone());
+    for (&field, &bytes) in &options.block_size_limits {
+        writer.set_block_size_limit(field, bytes)?;
+    }
+    if let Some(rows) = options.rows_per_block {
+        writer.set_rows_per_block(rows);
+    }
+    writer.set_content_digest(options.content_digest);
+    if let Some(bytes) = options.memory_budget {
+        writer.set_memory_budget(bytes);
+    }
+
+    if options.sort {
+        drop(bam_reader);
+        let file = File::open(src).map_err(|err| with_path(err, src))?;
+        let file_size = file.metadata()?.len();
+        let index_file = if options.sort_index {
+            let mut index_path = dst.as_os_str().to_owned();
+            index_path.push(".gbai");
+            let index_path = PathBuf::from(index_path);
+            let file = File::create(&index_path).map_err(|err| with_path(err, &index_path))?;
+            Some(BufWriter::with_capacity(33_554_432, file))
+        } else {
+            None
+        };
+        let temp_dir = options.temp_dir.clone().unwrap_or_else(std::env::temp_dir);
+        let dir = TempDir::new_in(&temp_dir, "BAM sort temporary directory.")
+            .map_err(|err| with_path(err, &temp_dir))?;
+        sort::sort_bam_block_size_prefixed(
+            MEM_LIMIT,
+            BufReader::new(file),
+            &mut writer,
+            &dir,
+            0,
+            options.threads,
+            options.sort_temp_mode,
+            index_file,
+            SortBy::CoordinatesAndStrand,
+            Some(file_size),
+        )?;
+        // Sorted records are pushed through `Write`, which needs no codec map.
+        writer.finish_with_summary(false)?;
+    } else {
+        let mut records = bam_reader.records();
+        while let Some(rec) = records.next_rec() {
+            let rec = BAMRawRecord(Cow::Borrowed(rec?));
+            writer.push_record(&rec, options.codec_map_required)?;
+        }
+        writer.finish_with_summary(options.codec_map_required)?;
+    }
+    Ok(())
+}
+
+/// Converts GBAM file at `src` into BAM file at `dst`. Returns path of the
+/// index, if it was requested with `Gbam2BamOptions::with_index()`.
+pub fn gbam_to_bam(src: &Path, dst: &Path, options: &Gbam2BamOptions) -> io::Result<Option<PathBuf>> {
+    if options.verify_digest {
+        Reader::from_path(src, ParsingTemplate::new())?.verify_file_digest()?;
+    }
+    if options.index {
+        return gbam_to_bam_with_index(src, dst).map(Some);
+    }
+    let mut template = ParsingTemplate::new();
+    template.set_all();
+    let mut reader = Reader::from_path(src, template)?;
+    write_bam(&mut reader, dst, options.threads)?;
+    Ok(None)
+}
+
+/// BAM reader past the header, bytes of the header including references,
+/// and parsed references.
+type OpenedBam = (bam_tools::Reader, Vec<u8>, Vec<(String, u32)>);
+
+/// Opens BAM file at `path` and consumes its header.
+fn open_bam(path: &Path, thread_num: usize, show_progress: bool) -> io::Result<OpenedBam> {
+    let file = File::open(path).map_err(|err| with_path(err, path))?;
+    let file_size = file.metadata()?.len();
+    let track_progress = Some(file_size).filter(|_| show_progress);
+    let mut reader = bam_tools::Reader::new(BufReader::new(file), thread_num, track_progress);
+    let (sam_header, ref_seqs_offset) = reader.read_header()?;
+    let ref_seqs = parse_reference_sequences(&sam_header[ref_seqs_offset..])?;
+    Ok((reader, sam_header, ref_seqs))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::meta::SortOrder;
+    use crate::test_utils::{open_test_file, write_test_file, TestRecord};
+    use byteorder::{ByteOrder, LittleEndian, ReadBytesExt};
+    use flate2::read::MultiGzDecoder;
+    use std::io::Read;
+
+    /// Records of BAM file at `path`, decompressed, with header skipped.
+    fn bam_records(path: &Path) -> Vec<u8> {
+        let mut data = Vec::new();
+        MultiGzDecoder::new(File::open(path).unwrap()).read_to_end(&mut data).unwrap();
+        let mut rest = &data[4..];
+        let l_text = rest.read_u32::<LittleEndian>().unwrap() as usize;
+        rest = &rest[l_text..];
+        for _ in 0..rest.read_u32::<LittleEndian>().unwrap() {
+            let l_name = rest.read_u32::<LittleEndian>().unwrap() as usize;
+            rest = &rest[l_name + 4..];
+        }
+        rest.to_vec()
+    }
+
+    #[test]
+    fn test_convert_with_options() {
+        let bam_path = Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/../test_data/little.bam"));
+        let dir = TempDir::new("gbam_convert").unwrap();
+        let gbam_path = dir.path().join("little.gbam");
+        let options = Bam2GbamOptions::default()
+            .with_codec(Codecs::Gzip)
+            .with_threads(2)
+            .with_sorted_input(true)
+            .with_block_size(50_000)
+            .with_digests(true)
+            .with_command(String::from("test"));
+        bam_to_gbam(bam_path, &gbam_path, &options).unwrap();
+
+        let reader = open_test_file(&gbam_path);
+        let meta = &reader.file_meta;
+        assert_eq!(*meta.get_field_codec(&Fields::RawSequence), Codecs::Gzip);
+        assert_eq!(meta.get_sort_order(), SortOrder::Coordinate);
+        assert_eq!(meta.view_blocks(&Fields::Pos)[0].numitems, 50_000);
+        reader.verify_file_digest().unwrap();
+
+        let out_path = dir.path().join("little.bam");
+        let options = Gbam2BamOptions::default().with_index(true).with_digests(true);
+        let index_path = gbam_to_bam(&gbam_path, &out_path, &options).unwrap().unwrap();
+        assert_eq!(index_path, dir.path().join("little.bam.bai"));
+        assert!(index_path.exists());
+        assert!(bam_records(&out_path) == bam_records(bam_path));
+
+        // Files without digest can't be checked.
+        let plain_path = dir.path().join("plain.gbam");
+        write_test_file(&plain_path, "", &[TestRecord::default()]);
+        let err = gbam_to_bam(&plain_path, &out_path, &options).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_convert_with_block_size_limits() {
+        let bam_path = Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/../test_data/little.bam"));
+        let dir = TempDir::new("gbam_convert").unwrap();
+        let gbam_path = dir.path().join("little.gbam");
+        let limits = |mapq| HashMap::from([(Fields::Mapq, mapq), (Fields::RawSequence, 8 * MEGA_BYTE_SIZE)]);
+        let options = Bam2GbamOptions::default().with_block_size_limits(limits(4096));
+        bam_to_gbam(bam_path, &gbam_path, &options).unwrap();
+
+        let reader = open_test_file(&gbam_path);
+        let meta = &reader.file_meta;
+        assert_eq!(meta.get_block_size_limit(&Fields::Mapq), 4096);
+        assert_eq!(meta.get_block_size_limit(&Fields::RawSequence), 8 * MEGA_BYTE_SIZE);
+        assert_eq!(meta.view_blocks(&Fields::Mapq)[0].numitems, 4096);
+        assert!(meta.get_block_size_limit(&Fields::RawQual) > meta.get_block_size_limit(&Fields::Pos));
+
+        let small_path = dir.path().join("small.gbam");
+        let options = options.with_block_size_limits(limits(1024));
+        let err = bam_to_gbam(bam_path, &small_path, &options).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(!small_path.exists());
+    }
+
+    /// `reg2bin()` as printed in SAM specification, kept verbatim.
+    #[allow(clippy::eq_op)]
+    fn spec_reg2bin(beg: i64, end: i64) -> u16 {
+        let end = end - 1;
+        let bin = if beg >> 14 == end >> 14 {
+            ((1 << 15) - 1) / 7 + (beg >> 14)
+        } else if beg >> 17 == end >> 17 {
+            ((1 << 12) - 1) / 7 + (beg >> 17)
+        } else if beg >> 20 == end >> 20 {
+            ((1 << 9) - 1) / 7 + (beg >> 20)
+        } else if beg >> 23 == end >> 23 {
+            ((1 << 6) - 1) / 7 + (beg >> 23)
+        } else if beg >> 26 == end >> 26 {
+            ((1 << 3) - 1) / 7 + (beg >> 26)
+        } else {
+            0
+        };
+        bin as u16
+    }
+
+    #[test]
+    fn test_exported_bins() {
+        let with = |refid, pos, flag, cigar: Vec<u32>, seq: &str| TestRecord {
+            flag,
+            cigar,
+            seq: seq.to_owned(),
+            qual: vec![30; seq.len()],
+            // Stale, as after records are moved.
+            bin: 1,
+            ..TestRecord::new(refid, pos, "r")
+        };
+        let records = [
+            with(0, 100, 0, vec![4 << 4], "ACGT"),
+            // 10M across a 16 kbp window.
+            with(0, 16_380, 0, vec![10 << 4], "ACGTACGTAC"),
+            // Unmapped next to its mate, its cigar is ignored.
+            with(0, 16_383, 4, vec![4 << 4], "ACGT"),
+            // 2S3I, no reference bases.
+            with(0, 20_000, 0, vec![2 << 4 | 4, 3 << 4 | 1], "ACGTA"),
+            // 2M3N2M across a 128 kbp window.
+            with(1, (1 << 17) - 3, 0, vec![2 << 4, 3 << 4 | 3, 2 << 4], "ACGT"),
+            with(-1, -1, 4, Vec::new(), "ACGT"),
+        ];
+        let dir = TempDir::new("gbam_convert").unwrap();
+        let gbam_path = dir.path().join("bins.gbam");
+        write_test_file(&gbam_path, "@HD\tVN:1.6\tSO:coordinate\n", &records);
+
+        for index in [false, true] {
+            let out_path = dir.path().join(format!("bins_{}.bam", index));
+            gbam_to_bam(&gbam_path, &out_path, &Gbam2BamOptions::default().with_index(index)).unwrap();
+            let data = bam_records(&out_path);
+            let mut rest = &data[..];
+            let mut bins = Vec::new();
+            while !rest.is_empty() {
+                let block_size = rest.read_u32::<LittleEndian>().unwrap() as usize;
+                let (rec, tail) = rest.split_at(block_size);
+                rest = tail;
+                let pos = i64::from(LittleEndian::read_i32(&rec[4..8]));
+                let l_read_name = usize::from(rec[8]);
+                let bin = LittleEndian::read_u16(&rec[10..12]);
+                let n_cigar_op = usize::from(LittleEndian::read_u16(&rec[12..14]));
+                let flag = LittleEndian::read_u16(&rec[14..16]);
+                let cigar = &rec[32 + l_read_name..32 + l_read_name + 4 * n_cigar_op];
+                let ref_len: i64 = cigar
+                    .chunks_exact(4)
+                    .map(LittleEndian::read_u32)
+                    .filter(|op| matches!(op & 0xf, 0 | 2 | 3 | 7 | 8))
+                    .map(|op| i64::from(op >> 4))
+                    .sum();
+                let span = if flag & 4 == 4 { 1 } else { ref_len.max(1) };
+                assert_eq!(bin, spec_reg2bin(pos, pos + span), "record {} at {}", bins.len(), pos);
+                bins.push(bin);
+            }
+            assert_eq!(bins, [4681, 585, 4681, 4682, 73, 4680]);
+        }
+    }
+
+    #[test]
+    fn test_temp_files_mode() {
+        assert_eq!(temp_files_mode("lz4_ram").unwrap(), TempFilesMode::InMemoryBlocksLZ4);
+        assert_eq!(temp_files_mode("disk").unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+}
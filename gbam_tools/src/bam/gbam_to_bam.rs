@@ -1,18 +1,30 @@
+use crate::meta::sam_header_text;
 use crate::reader::parse_tmplt::ParsingTemplate;
+use crate::reader::reader::Reader;
 use crate::reader::records::Records;
+use byteorder::{LittleEndian, WriteBytesExt};
 use rust_htslib::bam;
-use std::io::Write;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
 
 use std::convert::TryFrom;
 use std::fs::File;
 
+use super::bgzf::BgzfWriter;
+use super::convert::{self, Gbam2BamOptions};
+use super::index::{IndexBuilder, IndexFormat};
+
 /// Converts GBAM file to BAM file. This uses the `noodles bam writer`.
+#[deprecated(since = "0.1.0", note = "use bam::convert::gbam_to_bam() with Gbam2BamOptions")]
 pub fn gbam_to_bam(in_path: &str, out_path: &str) {
-    let file = File::open(in_path).unwrap();
-    let mut template = ParsingTemplate::new();
-    template.set_all();
-    let mut reader = crate::reader::reader::Reader::new(file, template).unwrap();
+    convert::gbam_to_bam(in_path.as_ref(), out_path.as_ref(), &Gbam2BamOptions::default()).unwrap();
+}
 
+/// Writes records of `reader` into BAM file at `out_path` with htslib,
+/// compressed by `threads` threads. Every field has to be enabled in parsing
+/// template.
+pub(super) fn write_bam(reader: &mut Reader, out_path: &Path, threads: usize) -> io::Result<()> {
+    let htslib_error = |err: rust_htslib::errors::Error| io::Error::other(err);
     let mut bam_header = bam::Header::new();
     let ref_seqs = reader.file_meta.get_ref_seqs();
 
@@ -24,16 +36,16 @@ pub fn gbam_to_bam(in_path: &str, out_path: &str) {
         );
     }
 
-    let mut records_it = Records::new(&mut reader);
+    let mut records_it = Records::new(reader);
 
-    let mut out = bam::Writer::from_path(out_path, &bam_header, bam::Format::Bam).unwrap();
-    out.set_threads(4).unwrap();
+    let mut out = bam::Writer::from_path(out_path, &bam_header, bam::Format::Bam).map_err(htslib_error)?;
+    out.set_threads(threads).map_err(htslib_error)?;
 
     let mut cigar_buf = Vec::new();
-    while let Some(rec) = records_it.next_rec() {
+    while let Some(rec) = records_it.next_rec()? {
         let mut record = bam::Record::new();
 
-        record.set_bin(rec.bin.unwrap());
+        record.set_bin(rec.computed_bin());
         record.set_tid(rec.refid.unwrap());
         record.set_mapq(rec.mapq.unwrap());
         record.set_pos(rec.pos.unwrap() as i64);
@@ -55,8 +67,13 @@ pub fn gbam_to_bam(in_path: &str, out_path: &str) {
             cigar_buf.push(op.op_type() as u8);
         });
 
-        let bam_cigar = bam::record::CigarString::try_from(&cigar_buf[..]).unwrap();
-        record.set_data(&rec.tags.as_ref().unwrap()[..]);
+        let bam_cigar = bam::record::CigarString::try_from(&cigar_buf[..]).map_err(htslib_error)?;
+        // A new record has no data buffer, which `set_data()` can't take
+        // an empty slice into.
+        let tags = &rec.tags.as_ref().unwrap()[..];
+        if !tags.is_empty() {
+            record.set_data(tags);
+        }
         record.set(
             &rec.read_name.as_ref().unwrap()[..rec.read_name.as_ref().unwrap().len() - 1],
             Some(&bam_cigar),
@@ -64,6 +81,63 @@ pub fn gbam_to_bam(in_path: &str, out_path: &str) {
             &qual[..],
         );
 
-        out.write(&record).unwrap();
+        out.write(&record).map_err(htslib_error)?;
+    }
+    Ok(())
+}
+
+/// Converts GBAM file to BAM file and writes its index alongside, in the
+/// same pass. The index is BAI, or CSI if some reference is longer than BAI
+/// supports, named after the BAM file with `.bai` or `.csi` appended.
+/// Records have to be sorted by coordinate. Returns path of the index.
+pub fn gbam_to_bam_with_index(in_path: &Path, out_path: &Path) -> io::Result<PathBuf> {
+    let mut template = ParsingTemplate::new();
+    template.set_all();
+    let mut reader = Reader::from_path(in_path, template)?;
+    let meta = reader.file_meta.clone();
+    let ref_seqs = meta.get_ref_seqs();
+    let format = IndexFormat::for_references(ref_seqs.iter().map(|(_, len)| u64::from(*len)));
+
+    let mut out = BgzfWriter::new(BufWriter::new(File::create(out_path)?));
+    write_bam_header(&mut out, sam_header_text(meta.get_sam_header()), ref_seqs)?;
+    // Header takes blocks of its own, like htslib writes it.
+    out.flush_block()?;
+    let mut index = IndexBuilder::new(format, ref_seqs.len(), out.virtual_position());
+    let mut bytes = Vec::new();
+    let mut records = reader.records();
+    while let Some(rec) = records.next_rec()? {
+        rec.convert_to_bam_bytes(&mut bytes);
+        out.write_all(&bytes)?;
+        let beg = i64::from(rec.pos.unwrap());
+        // Unmapped records and ones without reference bases take one base.
+        let span = if rec.is_unmapped() { 0 } else { rec.alignment_span() };
+        let end = beg + i64::from(span.max(1));
+        index.push(rec.refid.unwrap(), beg, end, !rec.is_unmapped(), out.virtual_position())?;
+    }
+    // Records end where the EOF block starts.
+    out.flush_block()?;
+    let index = index.finish(out.virtual_position());
+    out.finish()?;
+
+    let mut index_path = out_path.as_os_str().to_owned();
+    index_path.push(".");
+    index_path.push(format.extension());
+    let index_path = PathBuf::from(index_path);
+    index.write(BufWriter::new(File::create(&index_path)?))?;
+    Ok(index_path)
+}
+
+/// Writes BAM magic, header text and references.
+fn write_bam_header<W: Write>(out: &mut W, text: &[u8], ref_seqs: &[(String, u32)]) -> io::Result<()> {
+    out.write_all(b"BAM\x01")?;
+    out.write_u32::<LittleEndian>(text.len() as u32)?;
+    out.write_all(text)?;
+    out.write_u32::<LittleEndian>(ref_seqs.len() as u32)?;
+    for (name, len) in ref_seqs {
+        out.write_u32::<LittleEndian>(name.len() as u32 + 1)?;
+        out.write_all(name.as_bytes())?;
+        out.write_all(&[0])?;
+        out.write_u32::<LittleEndian>(*len)?;
     }
+    Ok(())
 }
@@ -0,0 +1,632 @@
+//! BAI and CSI indexes of coordinate sorted BAM files, built from virtual
+//! offsets of records while the file is written. Binning, linear index and
+//! merging of small bins follow htslib, so indexes match the ones of
+//! `samtools index` up to order of bins.
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+
+use byteorder::{LittleEndian, WriteBytesExt};
+
+use super::bgzf::BgzfWriter;
+
+/// Size of the smallest bins of BAI, 16 kbp, as a power of two.
+pub const BAI_MIN_SHIFT: u32 = 14;
+/// Levels of BAI binning below the root bin.
+pub const BAI_DEPTH: u32 = 5;
+
+// Chunks of bins spanning less compressed bytes are moved into the parent bin.
+const MIN_MARKER_DIST: u64 = 0x10000;
+
+/// Format of index files.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IndexFormat {
+    /// Covers references up to 2^29 bases.
+    Bai,
+    /// Bins of `2^min_shift` bases at the bottom of `depth` levels.
+    Csi { min_shift: u32, depth: u32 },
+}
+
+impl IndexFormat {
+    /// BAI, or CSI with the depth `samtools index -c` picks if some of
+    /// `ref_lens` don't fit BAI.
+    pub fn for_references(ref_lens: impl IntoIterator<Item = u64>) -> Self {
+        let max_len = ref_lens.into_iter().max().unwrap_or(0);
+        if max_len <= max_pos(BAI_MIN_SHIFT, BAI_DEPTH) as u64 {
+            return IndexFormat::Bai;
+        }
+        let max_len = max_len + 256;
+        let mut depth = 0;
+        let mut size: u64 = 1 << BAI_MIN_SHIFT;
+        while max_len > size {
+            depth += 1;
+            size <<= 3;
+        }
+        IndexFormat::Csi {
+            min_shift: BAI_MIN_SHIFT,
+            depth,
+        }
+    }
+
+    /// Appended to the name of the BAM file, like `out.bam.bai`.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            IndexFormat::Bai => "bai",
+            IndexFormat::Csi { .. } => "csi",
+        }
+    }
+
+    fn min_shift_and_depth(&self) -> (u32, u32) {
+        match *self {
+            IndexFormat::Bai => (BAI_MIN_SHIFT, BAI_DEPTH),
+            IndexFormat::Csi { min_shift, depth } => (min_shift, depth),
+        }
+    }
+}
+
+fn max_pos(min_shift: u32, depth: u32) -> i64 {
+    1 << (min_shift + 3 * depth)
+}
+
+/// First bin of `level`, 0 for the root.
+pub fn bin_first(level: u32) -> u32 {
+    ((1 << (3 * level)) - 1) / 7
+}
+
+fn bin_parent(bin: u32) -> u32 {
+    (bin - 1) >> 3
+}
+
+fn bin_level(mut bin: u32) -> u32 {
+    let mut level = 0;
+    while bin > 0 {
+        level += 1;
+        bin = bin_parent(bin);
+    }
+    level
+}
+
+/// Linear index window of the start of `bin`.
+fn bin_bot(bin: u32, depth: u32) -> usize {
+    let level = bin_level(bin);
+    ((bin - bin_first(level)) << (3 * (depth - level))) as usize
+}
+
+/// Smallest bin containing 0-based half-open interval `[beg, end)`, the
+/// `reg2bin()` of SAM specification for other depths.
+pub fn reg2bin(beg: i64, end: i64, min_shift: u32, depth: u32) -> u32 {
+    let end = end - 1;
+    let mut shift = min_shift;
+    let mut level = depth;
+    while level > 0 {
+        if beg >> shift == end >> shift {
+            return (i64::from(bin_first(level)) + (beg >> shift)) as u32;
+        }
+        level -= 1;
+        shift += 3;
+    }
+    0
+}
+
+/// Chunks of a reference, `(start, end)` virtual offsets by bin, and
+/// offsets of first records overlapping each `2^min_shift` window.
+#[derive(Default, Debug, Clone)]
+struct RefIndex {
+    bins: BTreeMap<u32, Vec<(u64, u64)>>,
+    linear: Vec<Option<u64>>,
+}
+
+impl RefIndex {
+    fn add_to_linear(&mut self, beg: i64, end: i64, offset: u64, min_shift: u32) {
+        let first = (beg >> min_shift) as usize;
+        let last = ((end - 1) >> min_shift) as usize;
+        if self.linear.len() <= last {
+            self.linear.resize(last + 1, None);
+        }
+        for window in &mut self.linear[first..=last] {
+            window.get_or_insert(offset);
+        }
+    }
+
+    /// Fills windows no record starts in with offsets of following ones.
+    fn fill_linear(&mut self) {
+        let mut next = None;
+        for window in self.linear.iter_mut().rev() {
+            match window {
+                Some(offset) => next = Some(*offset),
+                None => *window = next,
+            }
+        }
+    }
+
+    /// Moves chunks of bins spanning less than `MIN_MARKER_DIST` into their
+    /// parents and merges chunks starting in the same BGZF block.
+    fn compress_binning(&mut self, depth: u32, n_bins: u32) {
+        for level in (1..=depth).rev() {
+            // Deeper bins left in place are sorted too, as in htslib.
+            let bins: Vec<u32> = self.bins.range(bin_first(level)..n_bins).map(|(&bin, _)| bin).collect();
+            for bin in bins {
+                let chunks = self.bins.get_mut(&bin).unwrap();
+                if level < depth {
+                    chunks.sort_by_key(|chunk| chunk.0);
+                }
+                let span = (chunks[chunks.len() - 1].1 >> 16).wrapping_sub(chunks[0].0 >> 16);
+                let parent = bin_parent(bin);
+                if span < MIN_MARKER_DIST && self.bins.contains_key(&parent) {
+                    let chunks = self.bins.remove(&bin).unwrap();
+                    self.bins.get_mut(&parent).unwrap().extend(chunks);
+                }
+            }
+        }
+        if let Some(chunks) = self.bins.get_mut(&0) {
+            chunks.sort_by_key(|chunk| chunk.0);
+        }
+        for chunks in self.bins.range_mut(..n_bins).map(|(_, chunks)| chunks) {
+            let mut merged: Vec<(u64, u64)> = Vec::with_capacity(chunks.len());
+            for &chunk in chunks.iter() {
+                match merged.last_mut() {
+                    Some(last) if last.1 >> 16 >= chunk.0 >> 16 => last.1 = last.1.max(chunk.1),
+                    _ => merged.push(chunk),
+                }
+            }
+            *chunks = merged;
+        }
+    }
+}
+
+/// Collects records of a BAM file as they are written, see
+/// `gbam_to_bam_with_index()`.
+pub struct IndexBuilder {
+    format: IndexFormat,
+    min_shift: u32,
+    depth: u32,
+    refs: Vec<Option<RefIndex>>,
+    n_no_coor: u64,
+    // Mapped and unmapped records of the current reference.
+    n_mapped: u64,
+    n_unmapped: u64,
+    // Reference, bin and start of the chunk being collected.
+    save_tid: i32,
+    save_bin: Option<u32>,
+    save_off: u64,
+    // Reference, bin, start and end offset of the last record.
+    last_tid: i32,
+    last_bin: Option<u32>,
+    last_coor: i64,
+    last_off: u64,
+    // Start of the current reference.
+    off_beg: u64,
+}
+
+impl IndexBuilder {
+    /// Index of a file with `n_refs` references whose first record starts
+    /// at virtual offset `offset0`, right after the header.
+    pub fn new(format: IndexFormat, n_refs: usize, offset0: u64) -> Self {
+        let (min_shift, depth) = format.min_shift_and_depth();
+        Self {
+            format,
+            min_shift,
+            depth,
+            refs: vec![None; n_refs],
+            n_no_coor: 0,
+            n_mapped: 0,
+            n_unmapped: 0,
+            save_tid: -1,
+            save_bin: None,
+            save_off: offset0,
+            last_tid: -1,
+            last_bin: None,
+            last_coor: 0,
+            last_off: offset0,
+            off_beg: offset0,
+        }
+    }
+
+    // Bin holding offsets of the first and past the last record of a
+    // reference, and its mapped and unmapped records.
+    fn meta_bin(&self) -> u32 {
+        bin_first(self.depth + 1) + 1
+    }
+
+    fn add_chunk(&mut self, ref_id: i32, bin: u32, chunk: (u64, u64)) {
+        if let Some(Some(index)) = self.refs.get_mut(ref_id as usize) {
+            index.bins.entry(bin).or_default().push(chunk);
+        }
+    }
+
+    /// Adds the next record, placed on `ref_id` at 0-based `[beg, end)` and
+    /// ending at virtual offset `end_offset`. Records have to be sorted by
+    /// coordinate, with unplaced ones (`ref_id` -1) at the end.
+    pub fn push(&mut self, ref_id: i32, beg: i64, end: i64, is_mapped: bool, end_offset: u64) -> io::Result<()> {
+        let (mut beg, mut end) = if ref_id < 0 { (-1, 0) } else { (beg, end) };
+        if ref_id >= 0 {
+            let max_pos = max_pos(self.min_shift, self.depth);
+            if beg > max_pos || end > max_pos {
+                return Err(unindexable(format!(
+                    "Record at {}..{} of reference {} does not fit {} index",
+                    beg,
+                    end,
+                    ref_id,
+                    self.format.extension().to_uppercase()
+                )));
+            }
+            if ref_id as usize >= self.refs.len() {
+                return Err(unindexable(format!("Reference {} is not in the header", ref_id)));
+            }
+        }
+        if self.last_tid != ref_id {
+            if ref_id >= 0 && self.n_no_coor > 0 {
+                return Err(unindexable(String::from(
+                    "Unplaced records are followed by records of references",
+                )));
+            }
+            if ref_id >= 0 && self.refs[ref_id as usize].is_some() {
+                return Err(unindexable(format!("Records of reference {} are not contiguous", ref_id)));
+            }
+            self.last_tid = ref_id;
+            self.last_bin = None;
+        } else if ref_id >= 0 && self.last_coor > beg {
+            return Err(unindexable(format!(
+                "Records of reference {} are not sorted, {} follows {}",
+                ref_id, beg, self.last_coor
+            )));
+        }
+        if end < beg {
+            return Err(unindexable(format!(
+                "Record of reference {} ends at {} before its start {}",
+                ref_id, end, beg
+            )));
+        }
+
+        if ref_id >= 0 {
+            // Records before the reference start go to its first window.
+            beg = beg.max(0);
+            end = end.max(1);
+            let (offset, min_shift) = (self.last_off, self.min_shift);
+            self.refs[ref_id as usize]
+                .get_or_insert_with(Default::default)
+                .add_to_linear(beg, end, offset, min_shift);
+        } else {
+            self.n_no_coor += 1;
+        }
+
+        let bin = reg2bin(beg, end, self.min_shift, self.depth);
+        if self.last_bin != Some(bin) {
+            if let Some(save_bin) = self.save_bin {
+                self.add_chunk(self.save_tid, save_bin, (self.save_off, self.last_off));
+                if self.last_bin.is_none() {
+                    // Reference changed.
+                    let meta_bin = self.meta_bin();
+                    self.add_chunk(self.save_tid, meta_bin, (self.off_beg, self.last_off));
+                    self.add_chunk(self.save_tid, meta_bin, (self.n_mapped, self.n_unmapped));
+                    self.n_mapped = 0;
+                    self.n_unmapped = 0;
+                    self.off_beg = self.last_off;
+                }
+            }
+            self.save_off = self.last_off;
+            self.save_bin = Some(bin);
+            self.last_bin = Some(bin);
+            self.save_tid = ref_id;
+        }
+        if is_mapped {
+            self.n_mapped += 1;
+        } else {
+            self.n_unmapped += 1;
+        }
+        self.last_off = end_offset;
+        self.last_coor = beg;
+        Ok(())
+    }
+
+    /// Completes the index of a file whose records end at `final_offset`.
+    pub fn finish(mut self, final_offset: u64) -> Index {
+        if let Some(save_bin) = self.save_bin.filter(|_| self.save_tid >= 0) {
+            let meta_bin = self.meta_bin();
+            self.add_chunk(self.save_tid, save_bin, (self.save_off, final_offset));
+            self.add_chunk(self.save_tid, meta_bin, (self.off_beg, final_offset));
+            self.add_chunk(self.save_tid, meta_bin, (self.n_mapped, self.n_unmapped));
+        }
+        let n_bins = bin_first(self.depth + 1);
+        for index in self.refs.iter_mut().flatten() {
+            index.fill_linear();
+            index.compress_binning(self.depth, n_bins);
+        }
+        Index {
+            format: self.format,
+            refs: self.refs,
+            n_no_coor: self.n_no_coor,
+        }
+    }
+}
+
+fn unindexable(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+/// BAI or CSI index, see `IndexBuilder`.
+pub struct Index {
+    format: IndexFormat,
+    refs: Vec<Option<RefIndex>>,
+    n_no_coor: u64,
+}
+
+impl Index {
+    pub fn format(&self) -> IndexFormat {
+        self.format
+    }
+
+    /// Writes the index, BGZF compressed for CSI. Bins are written in
+    /// ascending order.
+    pub fn write<W: Write>(&self, mut out: W) -> io::Result<()> {
+        match self.format {
+            IndexFormat::Bai => {
+                out.write_all(b"BAI\x01")?;
+                self.write_refs(&mut out)?;
+                out.flush()
+            }
+            IndexFormat::Csi { min_shift, depth } => {
+                let mut out = BgzfWriter::new(out);
+                out.write_all(b"CSI\x01")?;
+                out.write_i32::<LittleEndian>(min_shift as i32)?;
+                out.write_i32::<LittleEndian>(depth as i32)?;
+                // No auxiliary data.
+                out.write_u32::<LittleEndian>(0)?;
+                self.write_refs(&mut out)?;
+                out.finish()?;
+                Ok(())
+            }
+        }
+    }
+
+    fn write_refs<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let (_, depth) = self.format.min_shift_and_depth();
+        let n_bins = bin_first(depth + 1);
+        let no_ref = RefIndex::default();
+        out.write_i32::<LittleEndian>(self.refs.len() as i32)?;
+        for index in &self.refs {
+            let index = index.as_ref().unwrap_or(&no_ref);
+            out.write_i32::<LittleEndian>(index.bins.len() as i32)?;
+            for (&bin, chunks) in &index.bins {
+                out.write_u32::<LittleEndian>(bin)?;
+                if let IndexFormat::Csi { .. } = self.format {
+                    // Offset of the first record overlapping the bin.
+                    let loff = if bin < n_bins {
+                        index.linear.get(bin_bot(bin, depth)).copied().flatten().unwrap_or(0)
+                    } else {
+                        0
+                    };
+                    out.write_u64::<LittleEndian>(loff)?;
+                }
+                out.write_i32::<LittleEndian>(chunks.len() as i32)?;
+                for &(beg, end) in chunks {
+                    out.write_u64::<LittleEndian>(beg)?;
+                    out.write_u64::<LittleEndian>(end)?;
+                }
+            }
+            if self.format == IndexFormat::Bai {
+                out.write_i32::<LittleEndian>(index.linear.len() as i32)?;
+                for offset in &index.linear {
+                    out.write_u64::<LittleEndian>(offset.unwrap_or(0))?;
+                }
+            }
+        }
+        out.write_u64::<LittleEndian>(self.n_no_coor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bam::gbam_to_bam::gbam_to_bam_with_index;
+    use crate::test_utils::{sam_header_bytes, TestRecord};
+    use crate::writer::{Writer, WriterSettings};
+    use byteorder::ReadBytesExt;
+    use flate2::read::MultiGzDecoder;
+    use rust_htslib::bam::index::{self, Type};
+    use std::io::Read;
+    use std::path::Path;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_reg2bin() {
+        let bin = |beg, end| reg2bin(beg, end, BAI_MIN_SHIFT, BAI_DEPTH);
+        assert_eq!(bin(0, 1), 4681);
+        assert_eq!(bin(0, 1 << 14), 4681);
+        assert_eq!(bin(1 << 14, (1 << 14) + 1), 4682);
+        assert_eq!(bin(0, (1 << 14) + 1), 585);
+        assert_eq!(bin(0, 1 << 26), 1);
+        assert_eq!(bin(0, 1 << 29), 0);
+        // Unplaced records.
+        assert_eq!(bin(-1, 0), 4680);
+        assert_eq!(bin_bot(585, BAI_DEPTH), 0);
+        assert_eq!(bin_bot(586, BAI_DEPTH), 8);
+        assert_eq!(bin_bot(4682, BAI_DEPTH), 1);
+    }
+
+    #[test]
+    fn test_index_format() {
+        assert_eq!(IndexFormat::for_references(vec![1_000, 1 << 29]), IndexFormat::Bai);
+        assert_eq!(
+            IndexFormat::for_references(vec![1_000, (1 << 29) + 1]),
+            IndexFormat::Csi { min_shift: 14, depth: 6 }
+        );
+        assert_eq!(
+            IndexFormat::for_references(vec![u64::from(u32::MAX)]),
+            IndexFormat::Csi { min_shift: 14, depth: 7 }
+        );
+    }
+
+    #[test]
+    fn test_unsorted_records() {
+        let mut builder = IndexBuilder::new(IndexFormat::Bai, 2, 0);
+        builder.push(1, 100, 200, true, 10).unwrap();
+        let err = builder.push(1, 50, 200, true, 20).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        let mut builder = IndexBuilder::new(IndexFormat::Bai, 2, 0);
+        builder.push(1, 100, 200, true, 10).unwrap();
+        builder.push(0, 100, 200, true, 20).unwrap();
+        assert!(builder.push(1, 300, 400, true, 30).is_err());
+
+        let mut builder = IndexBuilder::new(IndexFormat::Bai, 2, 0);
+        builder.push(-1, -1, 0, false, 10).unwrap();
+        assert!(builder.push(0, 100, 200, true, 20).is_err());
+    }
+
+    fn mapped(refid: i32, pos: i32, i: usize, cigar: Vec<u32>, query_len: usize) -> TestRecord {
+        let mut rec = TestRecord::new(refid, pos, &format!("read{}", i));
+        rec.cigar = cigar;
+        rec.seq = "ACGT".repeat(query_len / 4);
+        rec.qual = vec![30; query_len];
+        rec
+    }
+
+    // Sorted records at `positions` of each reference, with long ones
+    // falling into upper bins and placed unmapped ones, and unplaced ones at
+    // the end.
+    fn records(positions: &[Vec<i32>]) -> Vec<TestRecord> {
+        let mut records = Vec::new();
+        for (refid, positions) in positions.iter().enumerate() {
+            let refid = refid as i32;
+            for (i, &pos) in positions.iter().enumerate() {
+                let rec = match i % 500 {
+                    // 24M 20000N 24M
+                    7 => mapped(refid, pos, i, vec![24 << 4, (20_000 << 4) | 3, 24 << 4], 48),
+                    // 40M 100D 8M
+                    13 => mapped(refid, pos, i, vec![40 << 4, (100 << 4) | 2, 8 << 4], 48),
+                    250 => {
+                        let mut rec = TestRecord::new(refid, pos, &format!("read{}", i));
+                        rec.flag = 0x4;
+                        rec
+                    }
+                    _ => mapped(refid, pos, i, vec![48 << 4], 48),
+                };
+                records.push(rec);
+            }
+        }
+        for i in 0..100 {
+            let mut rec = TestRecord::new(-1, -1, &format!("unplaced{}", i));
+            rec.flag = 0x4;
+            records.push(rec);
+        }
+        records
+    }
+
+    fn write_gbam(path: &Path, ref_seqs: Vec<(String, u32)>, records: &[TestRecord]) {
+        let settings = WriterSettings {
+            sam_header: sam_header_bytes("@HD\tVN:1.6\tSO:coordinate\n", &ref_seqs),
+            ref_seqs,
+            full_command: String::from("test"),
+            ..Default::default()
+        };
+        let mut writer = Writer::create(path, settings).unwrap();
+        for rec in records {
+            writer.push_record(&rec.to_raw(), false).unwrap();
+        }
+        writer.finish_with_summary(false).unwrap();
+    }
+
+    /// Index with bins of every reference sorted, since htslib writes them
+    /// in hash table order.
+    fn normalized(index: &[u8]) -> Vec<u8> {
+        let mut data = Vec::new();
+        let mut input = if index.starts_with(b"BAI\x01") {
+            index
+        } else {
+            MultiGzDecoder::new(index).read_to_end(&mut data).unwrap();
+            &data[..]
+        };
+        let is_csi = input.starts_with(b"CSI\x01");
+        let mut out = Vec::new();
+        let header_len = if is_csi {
+            let l_aux = (&input[12..16]).read_u32::<LittleEndian>().unwrap() as usize;
+            16 + l_aux
+        } else {
+            4
+        };
+        out.extend_from_slice(&input[..header_len]);
+        input = &input[header_len..];
+
+        let n_ref = input.read_i32::<LittleEndian>().unwrap();
+        out.write_i32::<LittleEndian>(n_ref).unwrap();
+        for _ in 0..n_ref {
+            let n_bin = input.read_i32::<LittleEndian>().unwrap();
+            let mut bins = BTreeMap::new();
+            for _ in 0..n_bin {
+                let bin = input.read_u32::<LittleEndian>().unwrap();
+                let loff = if is_csi {
+                    input.read_u64::<LittleEndian>().unwrap()
+                } else {
+                    0
+                };
+                let n_chunk = input.read_i32::<LittleEndian>().unwrap() as usize;
+                let (chunks, rest) = input.split_at(16 * n_chunk);
+                input = rest;
+                bins.insert(bin, (loff, chunks));
+            }
+            out.write_i32::<LittleEndian>(n_bin).unwrap();
+            for (bin, (loff, chunks)) in bins {
+                out.write_u32::<LittleEndian>(bin).unwrap();
+                if is_csi {
+                    out.write_u64::<LittleEndian>(loff).unwrap();
+                }
+                out.write_i32::<LittleEndian>(chunks.len() as i32 / 16).unwrap();
+                out.extend_from_slice(chunks);
+            }
+            if !is_csi {
+                let n_intv = input.read_i32::<LittleEndian>().unwrap() as usize;
+                out.write_i32::<LittleEndian>(n_intv as i32).unwrap();
+                let (offsets, rest) = input.split_at(8 * n_intv);
+                input = rest;
+                out.extend_from_slice(offsets);
+            }
+        }
+        // Unplaced records count.
+        out.extend_from_slice(input);
+        out
+    }
+
+    fn assert_matches_htslib(ref_seqs: Vec<(String, u32)>, positions: &[Vec<i32>], format: IndexFormat) {
+        let dir = TempDir::new("gbam_index").unwrap();
+        let gbam_path = dir.path().join("in.gbam");
+        let bam_path = dir.path().join("out.bam");
+        write_gbam(&gbam_path, ref_seqs, &records(positions));
+
+        let index_path = gbam_to_bam_with_index(&gbam_path, &bam_path).unwrap();
+        assert_eq!(index_path, dir.path().join(format!("out.bam.{}", format.extension())));
+
+        let expected_path = dir.path().join("htslib.idx");
+        let index_type = match format {
+            IndexFormat::Bai => Type::Bai,
+            IndexFormat::Csi { min_shift, .. } => Type::Csi(min_shift),
+        };
+        index::build(&bam_path, Some(&expected_path), index_type, 0).unwrap();
+        let expected = std::fs::read(&expected_path).unwrap();
+        let written = std::fs::read(&index_path).unwrap();
+        assert_eq!(normalized(&written), normalized(&expected));
+    }
+
+    #[test]
+    fn test_bai_matches_htslib() {
+        let ref_seqs = vec![
+            (String::from("chr1"), 1_000_000),
+            (String::from("empty"), 5_000),
+            (String::from("chr2"), 1_000_000),
+        ];
+        let positions: Vec<i32> = (0..12_000).map(|i| i * 80).collect();
+        assert_matches_htslib(ref_seqs, &[positions.clone(), Vec::new(), positions], IndexFormat::Bai);
+    }
+
+    #[test]
+    fn test_csi_matches_htslib() {
+        let ref_seqs = vec![(String::from("chr1"), 1_000_000), (String::from("long"), 1 << 30)];
+        let positions: Vec<i32> = (0..12_000).map(|i| i * 80).collect();
+        // Spread over the long reference, half of them beyond BAI limits.
+        let long_positions: Vec<i32> = (0..12_000).map(|i| i * 80_000 + (i % 7) * 3).collect();
+        assert_matches_htslib(
+            ref_seqs,
+            &[positions, long_positions],
+            IndexFormat::Csi { min_shift: 14, depth: 6 },
+        );
+    }
+}
@@ -0,0 +1,412 @@
+//! Deterministic synthetic records for benchmarks of writer and reader
+//! changes, see `benches/pipeline.rs`. Records are generated from a seed, so
+//! every run with the same `SyntheticConfig` measures the same input.
+//!
+//! Records are paired reads on the references of the config, with read
+//! lengths, order and auxiliary data set by it. Unmapped records go last in
+//! sorted inputs. Bins are computed from positions and cigars, so records
+//! pass `validation::validate_record()` and export checks.
+use std::ops::RangeInclusive;
+
+use bam_tools::record::bamrawrecord::BAMRawRecord;
+use byteorder::{LittleEndian, WriteBytesExt};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use crate::bam::index::{reg2bin, BAI_DEPTH, BAI_MIN_SHIFT};
+use crate::utils::seq::encode_bases;
+use crate::writer::WriterSettings;
+
+/// Read group of records with tags.
+pub const SYNTHETIC_READ_GROUP: &str = "synthetic";
+
+/// Distribution of read lengths.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ReadLengths {
+    Fixed(u32),
+    /// Uniform over the range, like trimmed or long reads.
+    Uniform(RangeInclusive<u32>),
+}
+
+/// Auxiliary data of records.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TagProfile {
+    None,
+    /// NM, AS and RG.
+    Short,
+    /// NM, AS, RG, MD, MC and XA, and a B array, close to bwa output.
+    Long,
+}
+
+/// Records generated by `SyntheticRecords`.
+#[derive(Clone, Debug)]
+pub struct SyntheticConfig {
+    pub records: usize,
+    pub ref_seqs: Vec<(String, u32)>,
+    pub read_lengths: ReadLengths,
+    /// Records by coordinate, or at random positions.
+    pub sorted: bool,
+    pub tags: TagProfile,
+    /// Fraction of unmapped records.
+    pub unmapped_fraction: f64,
+    pub seed: u64,
+}
+
+impl Default for SyntheticConfig {
+    /// 100 000 sorted 150bp reads on three 50 Mb references, 1% unmapped,
+    /// short tags.
+    fn default() -> Self {
+        Self {
+            records: 100_000,
+            ref_seqs: (1..=3).map(|i| (format!("chr{}", i), 50_000_000)).collect(),
+            read_lengths: ReadLengths::Fixed(150),
+            sorted: true,
+            tags: TagProfile::Short,
+            unmapped_fraction: 0.01,
+            seed: 0,
+        }
+    }
+}
+
+impl SyntheticConfig {
+    pub fn with_records(mut self, records: usize) -> Self {
+        self.records = records;
+        self
+    }
+
+    pub fn with_ref_seqs(mut self, ref_seqs: Vec<(String, u32)>) -> Self {
+        self.ref_seqs = ref_seqs;
+        self
+    }
+
+    pub fn with_read_lengths(mut self, read_lengths: ReadLengths) -> Self {
+        self.read_lengths = read_lengths;
+        self
+    }
+
+    pub fn with_sorted(mut self, sorted: bool) -> Self {
+        self.sorted = sorted;
+        self
+    }
+
+    pub fn with_tags(mut self, tags: TagProfile) -> Self {
+        self.tags = tags;
+        self
+    }
+
+    pub fn with_unmapped_fraction(mut self, fraction: f64) -> Self {
+        self.unmapped_fraction = fraction;
+        self
+    }
+
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    pub fn iter(&self) -> SyntheticRecords {
+        SyntheticRecords::new(self.clone())
+    }
+
+    /// All records, in BAM layout without block_size.
+    pub fn generate(&self) -> Vec<BAMRawRecord<'static>> {
+        self.iter().collect()
+    }
+
+    /// Header bytes as in BAM, with sort order and the read group.
+    pub fn sam_header(&self) -> Vec<u8> {
+        let order = if self.sorted { "coordinate" } else { "unsorted" };
+        let mut text = format!("@HD\tVN:1.6\tSO:{}\n", order);
+        for (name, len) in &self.ref_seqs {
+            text.push_str(&format!("@SQ\tSN:{}\tLN:{}\n", name, len));
+        }
+        text.push_str(&format!("@RG\tID:{}\tSM:sample\n", SYNTHETIC_READ_GROUP));
+        let mut bytes = Vec::new();
+        bytes.write_u32::<LittleEndian>(text.len() as u32).unwrap();
+        bytes.extend_from_slice(text.as_bytes());
+        bytes.write_u32::<LittleEndian>(self.ref_seqs.len() as u32).unwrap();
+        for (name, len) in &self.ref_seqs {
+            bytes.write_u32::<LittleEndian>(name.len() as u32 + 1).unwrap();
+            bytes.extend_from_slice(name.as_bytes());
+            bytes.push(0);
+            bytes.write_u32::<LittleEndian>(*len).unwrap();
+        }
+        bytes
+    }
+
+    /// Default settings with references, header and sort order of records.
+    pub fn writer_settings(&self) -> WriterSettings {
+        WriterSettings {
+            ref_seqs: self.ref_seqs.clone(),
+            sam_header: self.sam_header(),
+            full_command: String::from("synthetic"),
+            is_sorted: self.sorted,
+            ..Default::default()
+        }
+    }
+}
+
+/// Iterator over records of a `SyntheticConfig`.
+pub struct SyntheticRecords {
+    config: SyntheticConfig,
+    rng: StdRng,
+    rec_num: usize,
+    mapped: usize,
+    // Sum of reference lengths.
+    genome_len: u64,
+    // Position on references laid end to end, for sorted records.
+    genome_pos: u64,
+    // Longest alignment span, reads start at least as far from reference ends.
+    max_span: u64,
+}
+
+impl SyntheticRecords {
+    fn new(config: SyntheticConfig) -> Self {
+        assert!(
+            (0.0..=1.0).contains(&config.unmapped_fraction),
+            "Fraction of unmapped records must be within [0, 1]"
+        );
+        let genome_len = config.ref_seqs.iter().map(|(_, len)| *len as u64).sum();
+        let mapped = if genome_len == 0 {
+            0
+        } else {
+            config.records - (config.records as f64 * config.unmapped_fraction).round() as usize
+        };
+        let longest = match &config.read_lengths {
+            ReadLengths::Fixed(len) => *len,
+            ReadLengths::Uniform(range) => *range.end(),
+        };
+        Self {
+            rng: StdRng::seed_from_u64(config.seed),
+            config,
+            rec_num: 0,
+            mapped,
+            genome_len,
+            genome_pos: 0,
+            // Deletions add 2 bases.
+            max_span: longest as u64 + 2,
+        }
+    }
+
+    fn read_len(&mut self) -> usize {
+        match &self.config.read_lengths {
+            ReadLengths::Fixed(len) => *len as usize,
+            ReadLengths::Uniform(range) => self.rng.gen_range(range.clone()) as usize,
+        }
+    }
+
+    /// Reference id and position of the next mapped record.
+    fn next_pos(&mut self) -> (i32, i32) {
+        let genome_pos = if self.config.sorted {
+            // Mapped records spread evenly over the references.
+            let max_gap = 2 * self.genome_len / self.mapped as u64;
+            self.genome_pos = (self.genome_pos + self.rng.gen_range(0..=max_gap)).min(self.genome_len - 1);
+            self.genome_pos
+        } else {
+            self.rng.gen_range(0..self.genome_len)
+        };
+        let mut offset = genome_pos;
+        for (ref_id, (_, len)) in self.config.ref_seqs.iter().enumerate() {
+            let len = *len as u64;
+            if offset < len {
+                // Reads don't hang over the end, unless longer than the
+                // reference. Order of sorted positions is kept.
+                let pos = offset.min(len.saturating_sub(self.max_span));
+                return (ref_id as i32, pos as i32);
+            }
+            offset -= len;
+        }
+        unreachable!("Position past the end of references")
+    }
+
+    fn record(&mut self) -> Vec<u8> {
+        let rec_num = self.rec_num;
+        let is_mapped = rec_num < self.mapped;
+        let read_len = self.read_len();
+        let seq: String = (0..read_len)
+            .map(|_| match self.rng.gen_range(0..500) {
+                0 => 'N',
+                base => ['A', 'C', 'G', 'T'][base % 4],
+            })
+            .collect();
+        let qual: Vec<u8> = (0..read_len).map(|_| self.rng.gen_range(2..=41)).collect();
+        let name = format!("syn{}.{}", self.config.seed, rec_num / 2);
+        let first_mate = rec_num.is_multiple_of(2);
+        let mate_flag = if first_mate { 0x40 } else { 0x80 | 0x10 };
+
+        let mut cigar: Vec<u32> = Vec::new();
+        let (ref_id, pos, mapq, flag, next_pos, tlen, bin) = if is_mapped {
+            let clip = if read_len > 20 { self.rng.gen_range(0..10) } else { 0 };
+            let half = read_len as u32 / 2;
+            let mut span = read_len as u64;
+            match self.rng.gen_range(0..10) {
+                0 if clip > 0 => cigar.extend([(clip << 4) | 4, (read_len as u32 - clip) << 4]),
+                1 if half > 0 => {
+                    cigar.extend([half << 4, (2 << 4) | 2, (read_len as u32 - half) << 4]);
+                    span += 2;
+                }
+                _ => cigar.push((read_len as u32) << 4),
+            }
+            if cigar[0] & 0xf == 4 {
+                span -= clip as u64;
+            }
+            let (ref_id, pos) = self.next_pos();
+            let mapq = if self.rng.gen_bool(0.9) { 60 } else { self.rng.gen_range(0..60) };
+            let insert = 200 + read_len as i32;
+            let (next_pos, tlen) = if first_mate {
+                (pos + 200, insert)
+            } else {
+                ((pos - 200).max(0), -insert)
+            };
+            let bin = reg2bin(pos as i64, pos as i64 + span.max(1) as i64, BAI_MIN_SHIFT, BAI_DEPTH);
+            (ref_id, pos, mapq, 0x1 | 0x2 | mate_flag, next_pos, tlen, bin as u16)
+        } else {
+            (-1, -1, 0, 0x1 | 0x4 | 0x8 | mate_flag, -1, 0, 4680)
+        };
+        let next_ref_id = ref_id;
+
+        let mut bytes = Vec::new();
+        bytes.write_i32::<LittleEndian>(ref_id).unwrap();
+        bytes.write_i32::<LittleEndian>(pos).unwrap();
+        bytes.write_u8(name.len() as u8 + 1).unwrap();
+        bytes.write_u8(mapq).unwrap();
+        bytes.write_u16::<LittleEndian>(bin).unwrap();
+        bytes.write_u16::<LittleEndian>(cigar.len() as u16).unwrap();
+        bytes.write_u16::<LittleEndian>(flag).unwrap();
+        bytes.write_u32::<LittleEndian>(read_len as u32).unwrap();
+        bytes.write_i32::<LittleEndian>(next_ref_id).unwrap();
+        bytes.write_i32::<LittleEndian>(next_pos).unwrap();
+        bytes.write_i32::<LittleEndian>(tlen).unwrap();
+        bytes.extend_from_slice(name.as_bytes());
+        bytes.push(0);
+        for op in &cigar {
+            bytes.write_u32::<LittleEndian>(*op).unwrap();
+        }
+        let seq_start = bytes.len();
+        bytes.resize(seq_start + read_len.div_ceil(2), 0);
+        encode_bases(seq.as_bytes(), &mut bytes[seq_start..]);
+        bytes.extend_from_slice(&qual);
+        self.tags(&mut bytes, is_mapped, read_len);
+        bytes
+    }
+
+    fn tags(&mut self, bytes: &mut Vec<u8>, is_mapped: bool, read_len: usize) {
+        if self.config.tags == TagProfile::None {
+            return;
+        }
+        if is_mapped {
+            let nm = self.rng.gen_range(0..5u8);
+            bytes.extend_from_slice(b"NMC");
+            bytes.push(nm);
+            bytes.extend_from_slice(b"ASi");
+            bytes.write_i32::<LittleEndian>(read_len as i32 - 5 * nm as i32).unwrap();
+        }
+        bytes.extend_from_slice(b"RGZ");
+        bytes.extend_from_slice(SYNTHETIC_READ_GROUP.as_bytes());
+        bytes.push(0);
+        if self.config.tags == TagProfile::Long && is_mapped {
+            let mismatch = self.rng.gen_range(0..read_len as u32);
+            let base = ['A', 'C', 'G', 'T'][mismatch as usize % 4];
+            let md = format!("{}{}{}", mismatch, base, read_len as u32 - mismatch - 1);
+            for (tag, value) in [(b"MDZ", md), (b"MCZ", format!("{}M", read_len))] {
+                bytes.extend_from_slice(tag);
+                bytes.extend_from_slice(value.as_bytes());
+                bytes.push(0);
+            }
+            if self.rng.gen_bool(0.2) {
+                let alt = self.rng.gen_range(0..1_000_000);
+                bytes.extend_from_slice(format!("XAZchr1,+{},{}M,1;\0", alt, read_len).as_bytes());
+            }
+            bytes.extend_from_slice(b"ZBBs");
+            bytes.write_u32::<LittleEndian>(4).unwrap();
+            for _ in 0..4 {
+                bytes.write_i16::<LittleEndian>(self.rng.gen_range(-100..100)).unwrap();
+            }
+        }
+    }
+}
+
+impl Iterator for SyntheticRecords {
+    type Item = BAMRawRecord<'static>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.rec_num == self.config.records {
+            return None;
+        }
+        let bytes = self.record();
+        self.rec_num += 1;
+        Some(BAMRawRecord::from(bytes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::meta::SortOrder;
+    use crate::test_utils::open_test_file;
+    use crate::validation::validate_record;
+    use crate::writer::Writer;
+    use bam_tools::record::fields::Fields;
+    use byteorder::ReadBytesExt;
+    use tempdir::TempDir;
+
+    fn key(rec: &BAMRawRecord) -> (u32, i32) {
+        let ref_id = rec.get_bytes(&Fields::RefID).read_i32::<LittleEndian>().unwrap();
+        (ref_id as u32, rec.get_bytes(&Fields::Pos).read_i32::<LittleEndian>().unwrap())
+    }
+
+    #[test]
+    fn test_deterministic() {
+        let config = SyntheticConfig::default().with_records(2_000).with_tags(TagProfile::Long);
+        let records = config.generate();
+        assert_eq!(records.len(), 2_000);
+        assert!(records == config.generate());
+        assert!(records != config.clone().with_seed(1).generate());
+    }
+
+    #[test]
+    fn test_records_round_trip() {
+        let dir = TempDir::new("gbam_bench_support").unwrap();
+        let configs = [
+            SyntheticConfig::default().with_records(20_000),
+            SyntheticConfig::default()
+                .with_records(5_000)
+                .with_sorted(false)
+                .with_tags(TagProfile::Long)
+                .with_read_lengths(ReadLengths::Uniform(1..=400)),
+            SyntheticConfig::default()
+                .with_records(3_001)
+                .with_tags(TagProfile::None)
+                .with_unmapped_fraction(0.5)
+                .with_ref_seqs(vec![(String::from("short"), 300)])
+                .with_read_lengths(ReadLengths::Uniform(50..=500)),
+        ];
+        for (idx, config) in configs.iter().enumerate() {
+            let records = config.generate();
+            let unmapped = records.iter().filter(|rec| key(rec).0 == u32::MAX).count();
+            assert_eq!(unmapped, (config.records as f64 * config.unmapped_fraction).round() as usize);
+            for rec in &records {
+                validate_record(rec, config.ref_seqs.len()).unwrap();
+            }
+            if config.sorted {
+                assert!(records.windows(2).all(|pair| key(&pair[0]) <= key(&pair[1])), "{}", idx);
+            }
+
+            let path = dir.path().join(format!("synthetic{}.gbam", idx));
+            let mut writer = Writer::create(&path, config.writer_settings()).unwrap();
+            writer.push_records(&records, false).unwrap();
+            writer.finish_with_summary(false).unwrap();
+
+            let mut reader = open_test_file(&path);
+            let order = if config.sorted { SortOrder::Coordinate } else { SortOrder::Unsorted };
+            assert_eq!(reader.file_meta.get_sort_order(), order);
+            assert_eq!(reader.num_records(), config.records);
+            let mut fetched = reader.records();
+            let mut bytes = Vec::new();
+            for (i, rec) in records.iter().enumerate() {
+                fetched.next_rec().unwrap().unwrap().convert_to_bam_bytes(&mut bytes);
+                assert_eq!(&bytes[4..], &rec[..], "record {} of config {}", i, idx);
+            }
+        }
+    }
+}
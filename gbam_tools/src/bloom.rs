@@ -0,0 +1,217 @@
+//! Bloom filters of read names, built per ReadName block at write time, so
+//! lookups by name decompress only blocks which may hold the name.
+use std::io;
+
+use bam_tools::record::fields::Fields;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::reader::reader::Reader;
+use crate::reader::record::GbamRecord;
+
+/// Bloom filter with double hashing. Bits are stored as hex string in meta.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct BloomFilter {
+    num_hashes: u32,
+    #[serde(serialize_with = "to_hex", deserialize_with = "from_hex")]
+    bits: Vec<u8>,
+}
+
+impl BloomFilter {
+    /// Filter sized for `num_keys` keys. About 1% false positives at 10 bits
+    /// per key.
+    pub fn new(num_keys: usize, bits_per_key: u32) -> Self {
+        let num_bits = std::cmp::max(64, num_keys * bits_per_key as usize);
+        // ln(2) * bits per key is optimal.
+        let num_hashes = ((bits_per_key as f64 * 0.69) as u32).clamp(1, 30);
+        Self {
+            num_hashes,
+            bits: vec![0; num_bits.div_ceil(8)],
+        }
+    }
+
+    /// Filter of NUL terminated names, concatenated like in ReadName blocks.
+    pub fn from_names(data: &[u8], num_names: u32, bits_per_key: u32) -> Self {
+        let mut filter = Self::new(num_names as usize, bits_per_key);
+        for name in data.split(|&b| b == 0).take(num_names as usize) {
+            filter.insert(name);
+        }
+        filter
+    }
+
+    /// Empty filter of the same size and number of hashes.
+    pub(crate) fn cleared(&self) -> Self {
+        Self {
+            num_hashes: self.num_hashes,
+            bits: vec![0; self.bits.len()],
+        }
+    }
+
+    fn bit_positions(&self, key: &[u8]) -> impl Iterator<Item = usize> {
+        let (h1, h2) = hash(key);
+        let num_bits = self.bits.len() as u64 * 8;
+        (0..self.num_hashes as u64).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % num_bits) as usize)
+    }
+
+    pub fn insert(&mut self, key: &[u8]) {
+        for bit in self.bit_positions(key) {
+            self.bits[bit / 8] |= 1 << (bit % 8);
+        }
+    }
+
+    /// False means `key` was never inserted.
+    pub fn may_contain(&self, key: &[u8]) -> bool {
+        self.bit_positions(key)
+            .all(|bit| self.bits[bit / 8] & (1 << (bit % 8)) != 0)
+    }
+}
+
+/// FNV-1a, finalized with two different mixers. Stable across platforms and
+/// Rust versions, unlike std hashers.
+pub(crate) fn hash(key: &[u8]) -> (u64, u64) {
+    let mut h: u64 = 0xcbf29ce484222325;
+    for &b in key {
+        h ^= b as u64;
+        h = h.wrapping_mul(0x100000001b3);
+    }
+    let mix = |mut z: u64| {
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    };
+    // Odd step, so probes don't repeat early.
+    (mix(h), mix(h ^ 0x9e3779b97f4a7c15) | 1)
+}
+
+fn to_hex<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+    let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    serializer.serialize_str(&hex)
+}
+
+fn from_hex<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+    let hex = String::deserialize(deserializer)?;
+    if hex.len() % 2 != 0 {
+        return Err(serde::de::Error::custom("Odd length of hex string"));
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).map_err(serde::de::Error::custom))
+        .collect()
+}
+
+impl Reader {
+    /// Indices of records named `name` (with or without NUL terminator), in
+    /// increasing order. ReadName has to be enabled in parsing template.
+    /// Blocks whose bloom filter rules the name out are skipped, others are
+    /// scanned. Filters are built by writers with `set_name_bloom_filter()`.
+    pub fn find_by_name(&mut self, name: &[u8]) -> io::Result<Vec<usize>> {
+        if !self.parsing_template.check_if_active(&[Fields::ReadName]) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "ReadName field has to be enabled in parsing template to find records by name.",
+            ));
+        }
+        let name = name.strip_suffix(&[0]).unwrap_or(name);
+        let candidates: Vec<(usize, usize)> = self
+            .file_meta
+            .view_blocks(&Fields::ReadName)
+            .iter()
+            .scan(0, |first_rec, block| {
+                let start = *first_rec;
+                *first_rec += block.numitems as usize;
+                let may_contain = match &block.bloom {
+                    Some(filter) => filter.may_contain(name),
+                    None => true,
+                };
+                Some((start, *first_rec, may_contain))
+            })
+            .filter(|&(start, end, may_contain)| may_contain && start < end)
+            .map(|(start, end, _)| (start, end))
+            .collect();
+
+        let mut found = Vec::new();
+        let mut rec = GbamRecord::default();
+        let column = self.get_column(&Fields::ReadName)?;
+        for (start, end) in candidates {
+            for rec_num in start..end {
+                column.fill_record_field(rec_num, &mut rec)?;
+                let rec_name = rec.read_name.as_deref().unwrap();
+                if rec_name.strip_suffix(&[0]).unwrap_or(rec_name) == name {
+                    found.push(rec_num);
+                }
+            }
+        }
+        Ok(self.logical_rec_nums(found))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{new_test_writer, open_test_file, TestRecord};
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_hex_roundtrip() {
+        let filter = BloomFilter {
+            num_hashes: 3,
+            bits: vec![0, 1, 0xab, 0xff],
+        };
+        let json = serde_json::to_string(&filter).unwrap();
+        assert_eq!(json, r#"{"num_hashes":3,"bits":"0001abff"}"#);
+        assert_eq!(serde_json::from_str::<BloomFilter>(&json).unwrap(), filter);
+    }
+
+    #[test]
+    fn test_find_by_name() {
+        const PAIRS: usize = 50_000;
+        const ROWS: u32 = 5_000;
+        let dir = TempDir::new("gbam_bloom").unwrap();
+        let path = dir.path().join("names.gbam");
+        let name = |i: usize| format!("run1:lane2:tile{}:{}", i % 97, i);
+        let mut writer = new_test_writer(&path, "");
+        writer.set_rows_per_block(ROWS);
+        writer.set_name_bloom_filter(10);
+        // Mates are next to each other, 100k records.
+        for i in 0..PAIRS {
+            for mate in 0..2 {
+                let rec = TestRecord::new(0, (2 * i + mate) as i32, &name(i));
+                writer.push_record(&rec.to_raw(), false).unwrap();
+            }
+        }
+        writer.finish_with_summary(false).unwrap();
+
+        let mut reader = open_test_file(&path);
+        let blocks = reader.file_meta.view_blocks(&Fields::ReadName).clone();
+        assert_eq!(blocks.len(), 2 * PAIRS / ROWS as usize);
+        // No false negatives: every name is in the filter of its block.
+        for i in 0..PAIRS {
+            let block = &blocks[2 * i / ROWS as usize];
+            assert!(block.bloom.as_ref().unwrap().may_contain(name(i).as_bytes()));
+        }
+
+        for i in [0, 2_499, 2_500, 31_337, PAIRS - 1] {
+            let before = reader.blocks_decompressed(&Fields::ReadName);
+            let found = reader.find_by_name(name(i).as_bytes()).unwrap();
+            assert_eq!(found, vec![2 * i, 2 * i + 1]);
+            // Own block, unless it is decompressed already, rarely a false
+            // positive one.
+            let decompressed = reader.blocks_decompressed(&Fields::ReadName) - before;
+            assert!(decompressed <= 3, "{}", decompressed);
+        }
+
+        let before = reader.blocks_decompressed(&Fields::ReadName);
+        let mut false_positives = 0;
+        for i in 0..200 {
+            let absent = format!("absent{}", i);
+            assert!(reader.find_by_name(absent.as_bytes()).unwrap().is_empty());
+            false_positives += blocks
+                .iter()
+                .filter(|b| b.bloom.as_ref().unwrap().may_contain(absent.as_bytes()))
+                .count();
+        }
+        let decompressed = reader.blocks_decompressed(&Fields::ReadName) - before;
+        assert!(decompressed as usize <= false_positives);
+        // About 1% of 200 * 20 checked blocks.
+        assert!(false_positives < 200, "{}", false_positives);
+    }
+}
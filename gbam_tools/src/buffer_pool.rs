@@ -0,0 +1,206 @@
+//! Block buffers reused by writer columns and compression threads.
+use std::sync::{Arc, Mutex};
+
+use crate::SIZE_LIMIT;
+
+/// Counters of a buffer pool since it was created.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BufferPoolStats {
+    /// Buffers allocated, since the pool had none to hand out.
+    pub allocated: u64,
+    /// Buffers handed out again, allocations avoided.
+    pub reused: u64,
+    /// Buffers dropped, since the pool held as many as it retains.
+    pub dropped: u64,
+    /// Buffers held now.
+    pub retained: usize,
+}
+
+struct Buffers {
+    free: Vec<Vec<u8>>,
+    max_retained: usize,
+    stats: BufferPoolStats,
+}
+
+/// Buffers handed back by columns and compression threads, kept for the next
+/// blocks. Clones share buffers, so writers created one after another, e.g.
+/// converting many small files, can share a pool with
+/// `Writer::set_buffer_pool()`.
+#[derive(Clone)]
+pub struct BufferPool(Arc<Mutex<Buffers>>);
+
+impl BufferPool {
+    /// Pool keeping up to `max_retained` buffers, other ones handed back are
+    /// freed. Full buffers take the block size limit of their field, 8 MB or
+    /// 16 MB by default.
+    pub fn new(max_retained: usize) -> Self {
+        Self(Arc::new(Mutex::new(Buffers {
+            free: Vec::new(),
+            max_retained,
+            stats: BufferPoolStats::default(),
+        })))
+    }
+
+    /// Empty buffer, the smallest one with at least `capacity` if there is
+    /// one, so large buffers are kept for whole blocks. Otherwise the largest
+    /// one, which grows as it's filled.
+    pub(crate) fn get(&self, capacity: usize) -> Vec<u8> {
+        let mut buffers = self.0.lock().unwrap();
+        let fitting = buffers
+            .free
+            .iter()
+            .enumerate()
+            .filter(|(_, buf)| buf.capacity() >= capacity)
+            .min_by_key(|(_, buf)| buf.capacity())
+            .or_else(|| buffers.free.iter().enumerate().max_by_key(|(_, buf)| buf.capacity()))
+            .map(|(pos, _)| pos);
+        match fitting {
+            Some(pos) => {
+                buffers.stats.reused += 1;
+                buffers.free.swap_remove(pos)
+            }
+            None => {
+                buffers.stats.allocated += 1;
+                Vec::with_capacity(capacity)
+            }
+        }
+    }
+
+    /// Takes `buf` back, buffers without capacity are dropped silently.
+    /// Ones grown past two blocks by oversized records are dropped too, so
+    /// they aren't held for the rest of the file.
+    pub(crate) fn put(&self, mut buf: Vec<u8>) {
+        if buf.capacity() == 0 {
+            return;
+        }
+        let mut buffers = self.0.lock().unwrap();
+        if buffers.free.len() < buffers.max_retained && buf.capacity() <= 2 * SIZE_LIMIT {
+            buf.clear();
+            buffers.free.push(buf);
+        } else {
+            buffers.stats.dropped += 1;
+        }
+    }
+
+    /// Drops retained buffers, largest first, until the rest take at most
+    /// `max_bytes`.
+    pub(crate) fn trim(&self, max_bytes: usize) {
+        let mut buffers = self.0.lock().unwrap();
+        buffers.free.sort_by_key(Vec::capacity);
+        let mut retained: usize = buffers.free.iter().map(Vec::capacity).sum();
+        while retained > max_bytes {
+            let buf = buffers.free.pop().unwrap();
+            retained -= buf.capacity();
+            buffers.stats.dropped += 1;
+        }
+    }
+
+    pub fn stats(&self) -> BufferPoolStats {
+        let buffers = self.0.lock().unwrap();
+        BufferPoolStats {
+            retained: buffers.free.len(),
+            ..buffers.stats
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{new_test_writer, TestRecord};
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::cell::Cell;
+    use tempdir::TempDir;
+
+    /// Counts allocations of at least a block size made by the current
+    /// thread, so tests running in parallel don't interfere.
+    struct CountingAlloc;
+
+    thread_local! {
+        static LARGE_ALLOCS: Cell<u64> = const { Cell::new(0) };
+    }
+
+    fn count(size: usize) {
+        if size >= SIZE_LIMIT {
+            let _ = LARGE_ALLOCS.try_with(|allocs| allocs.set(allocs.get() + 1));
+        }
+    }
+
+    unsafe impl GlobalAlloc for CountingAlloc {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            count(layout.size());
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout)
+        }
+
+        unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+            count(new_size);
+            System.realloc(ptr, layout, new_size)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: CountingAlloc = CountingAlloc;
+
+    #[test]
+    fn test_get_and_put() {
+        let pool = BufferPool::new(2);
+        let small = pool.get(10);
+        let large = pool.get(1_000);
+        assert_eq!(pool.stats().allocated, 2);
+        pool.put(small);
+        pool.put(large);
+        pool.put(Vec::with_capacity(5));
+        assert_eq!(pool.get(100).capacity(), 1_000);
+        // Smaller than requested, but the largest.
+        assert_eq!(pool.get(100).capacity(), 10);
+        pool.put(Vec::new());
+        pool.put(Vec::with_capacity(2 * SIZE_LIMIT + 1));
+        pool.put(Vec::with_capacity(10));
+        pool.put(Vec::with_capacity(30));
+        pool.trim(20);
+        assert_eq!(pool.get(0).capacity(), 10);
+        assert_eq!(
+            pool.stats(),
+            BufferPoolStats {
+                allocated: 2,
+                reused: 3,
+                dropped: 3,
+                retained: 0,
+            }
+        );
+    }
+
+    /// Allocations of block buffers on the writer thread, writing `blocks`
+    /// blocks per column.
+    fn large_allocs(blocks: i32, pool: &BufferPool) -> u64 {
+        let dir = TempDir::new("gbam_buffer_pool").unwrap();
+        let records: Vec<_> = (0..blocks * 100).map(|i| TestRecord::new(0, i, "r").to_raw()).collect();
+        let before = LARGE_ALLOCS.with(Cell::get);
+        let mut writer = new_test_writer(&dir.path().join("pooled.gbam"), "");
+        writer.set_rows_per_block(100);
+        writer.set_buffer_pool(pool.clone());
+        writer.push_records(&records, false).unwrap();
+        writer.finish_with_summary(false).unwrap();
+        LARGE_ALLOCS.with(Cell::get) - before
+    }
+
+    #[test]
+    fn test_blocks_reuse_buffers() {
+        let few = large_allocs(2, &BufferPool::new(64));
+        let many = large_allocs(50, &BufferPool::new(64));
+        // Buffers of the first blocks are reused for all following ones.
+        assert!(many < 2 * few, "{} {}", few, many);
+
+        // Buffers of a finished writer are reused by the next one.
+        let pool = BufferPool::new(64);
+        large_allocs(2, &pool);
+        let first = pool.stats();
+        assert!(first.retained > 0, "{:?}", first);
+        assert!(large_allocs(2, &pool) < few, "{:?}", pool.stats());
+        assert!(pool.stats().allocated - first.allocated < first.allocated, "{:?}", pool.stats());
+    }
+}
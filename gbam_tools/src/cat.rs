@@ -0,0 +1,206 @@
+//! Concatenation of GBAM files, the columnar analogue of `samtools cat`.
+use std::io::{self, Seek, SeekFrom, Write};
+
+use bam_tools::record::fields::Fields;
+
+use crate::meta::{FileInfo, SortOrder};
+use crate::reader::reader::Reader;
+use crate::source_index;
+use crate::writer::{write_meta_and_file_info, SyncOutput};
+use crate::GBAM_VERSION;
+use crate::provenance::PROVENANCE_EXTENSION;
+
+fn mismatch(idx: usize, what: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("Input {} differs from input 0 in {}", idx, what),
+    )
+}
+
+/// Writes records of `inputs`, in order, into `out`. Compressed blocks are
+/// copied verbatim, nothing is decompressed. Inputs must have the same
+/// reference sequences, codecs, sequence encoding and rows per block, and
+/// must not be encrypted. Header and sort order are taken from the first
+/// input, so inputs have to be in the order it claims. Write time analytics
+/// and linear index are not carried over. Returns total amount of bytes written.
+pub fn cat<W: Write + Seek + SyncOutput>(inputs: &[Reader], mut out: W) -> io::Result<u64> {
+    let first = match inputs.first() {
+        Some(reader) => &reader.file_meta,
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Nothing to concatenate",
+            ))
+        }
+    };
+    for (idx, reader) in inputs.iter().enumerate() {
+        let meta = &reader.file_meta;
+        if meta.get_ref_seqs() != first.get_ref_seqs() {
+            return Err(mismatch(idx, "reference sequences"));
+        }
+        if meta.get_seq_encoding() != first.get_seq_encoding()
+            || meta.get_reference_md5s() != first.get_reference_md5s()
+        {
+            return Err(mismatch(idx, "sequence encoding"));
+        }
+        if meta.get_rows_per_block() != first.get_rows_per_block() {
+            return Err(mismatch(idx, "rows per block"));
+        }
+        if meta.is_index_derived(&Fields::RawSeqLen) != first.is_index_derived(&Fields::RawSeqLen) {
+            return Err(mismatch(idx, "derived RawSequence index"));
+        }
+        for field in Fields::iterator() {
+            if meta.get_field_codec(field) != first.get_field_codec(field) {
+                return Err(mismatch(idx, &format!("codec of field {}", field)));
+            }
+            // Blocks are encrypted with their block number as nonce.
+            if meta.get_field_encryption(field).is_some() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Field {} of input {} is encrypted", field, idx),
+                ));
+            }
+        }
+    }
+
+    let mut file_meta = (**first).clone();
+    file_meta.clear_analytics();
+    file_meta.remove_extension(PROVENANCE_EXTENSION);
+    file_meta.set_linear_index(None);
+    file_meta.set_unmapped_tail_start(unmapped_tail_start(inputs));
+    // Alignment of blocks to records holds if only the last input ends with
+    // a partial block.
+    if let Some(rows) = first.get_rows_per_block() {
+        let aligned = inputs[..inputs.len() - 1]
+            .iter()
+            .all(|reader| reader.num_records() % rows as usize == 0);
+        if !aligned {
+            file_meta.set_rows_per_block(None);
+        }
+    }
+    let is_sorted = first.get_sort_order() == SortOrder::Coordinate;
+    let mut file_info = FileInfo::new(GBAM_VERSION, 0, 0, String::from("cat"), is_sorted);
+    out.seek(SeekFrom::Start(0))?;
+    out.write_all(&file_info.to_padded_bytes()?)?;
+
+    for field in Fields::iterator() {
+        file_meta.get_blocks(field).clear();
+        for (idx, reader) in inputs.iter().enumerate() {
+            for block in reader.file_meta.view_blocks(field) {
+                // Files without records still have an empty block per field,
+                // one is kept if all inputs are empty.
+                let is_last = idx == inputs.len() - 1;
+                if block.numitems == 0 && !(is_last && file_meta.view_blocks(field).is_empty()) {
+                    continue;
+                }
+                let data = reader.block_data(block)?;
+                let mut block = block.clone();
+                block.seekpos = out.stream_position()?;
+                out.write_all(&data)?;
+                file_meta.get_blocks(field).push(block);
+            }
+        }
+    }
+    source_index::copy_blocks(inputs, &mut out, &mut file_meta)?;
+
+    write_meta_and_file_info(&mut out, &mut file_meta, &mut file_info)
+}
+
+/// Start of unmapped tail of concatenated `inputs`, None if an input it may
+/// extend into has no marker.
+fn unmapped_tail_start(inputs: &[Reader]) -> Option<u64> {
+    let mut offset: u64 = inputs.iter().map(|reader| reader.num_records() as u64).sum();
+    let mut start = offset;
+    for reader in inputs.iter().rev() {
+        let amount = reader.num_records() as u64;
+        offset -= amount;
+        if amount == 0 {
+            continue;
+        }
+        let tail_start = reader.file_meta.get_unmapped_tail_start()?;
+        start = offset + tail_start.min(amount);
+        // Records before the tail are placed.
+        if tail_start > 0 {
+            break;
+        }
+    }
+    Some(start)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{
+        new_test_writer, open_test_file, write_test_file, write_test_file_with_limit, TestRecord,
+    };
+    use std::fs::File;
+    use tempdir::TempDir;
+
+    fn records_as_json(path: &std::path::Path) -> Vec<String> {
+        let mut reader = open_test_file(path);
+        let mut records = reader.records();
+        let mut res = Vec::new();
+        while let Some(rec) = records.next_rec().unwrap() {
+            res.push(serde_json::to_string(rec).unwrap());
+        }
+        res
+    }
+
+    #[test]
+    fn test_cat() {
+        let dir = TempDir::new("gbam_cat").unwrap();
+        let header = "@HD\tVN:1.6\tSO:coordinate\n";
+        let shards: Vec<Vec<TestRecord>> = (0..3)
+            .map(|ref_id| {
+                let n = [150_000, 0, 70_000][ref_id as usize];
+                (0..n)
+                    .map(|i| TestRecord::new(ref_id, i, &format!("{}{}", "r".repeat(i as usize % 200), i)))
+                    .collect()
+            })
+            .collect();
+        let mut inputs = Vec::new();
+        for (idx, shard) in shards.iter().enumerate() {
+            let path = dir.path().join(format!("shard{}.gbam", idx));
+            write_test_file_with_limit(&path, header, shard, &[Fields::ReadName]);
+            inputs.push(open_test_file(&path));
+        }
+        let catted = dir.path().join("cat.gbam");
+        cat(&inputs, File::create(&catted).unwrap()).unwrap();
+
+        let expected = dir.path().join("expected.gbam");
+        write_test_file(&expected, header, &shards.concat());
+        let reader = open_test_file(&catted);
+        assert_eq!(reader.num_records(), 220_000);
+        assert_eq!(reader.file_meta.get_sort_order(), SortOrder::Coordinate);
+        assert!(reader.file_meta.view_blocks(&Fields::ReadName).len() > 2);
+        let stats = reader.file_meta.view_blocks(&Fields::RefID).last().unwrap().stats.clone();
+        assert_eq!(stats.map(|s| (s.min_value, s.max_value)), Some((2, 2)));
+        assert!(records_as_json(&catted) == records_as_json(&expected));
+    }
+
+    #[test]
+    fn test_cat_mismatch() {
+        let dir = TempDir::new("gbam_cat").unwrap();
+        let plain = dir.path().join("plain.gbam");
+        write_test_file(&plain, "", &[TestRecord::default()]);
+        let aligned = dir.path().join("aligned.gbam");
+        let mut writer = new_test_writer(&aligned, "");
+        writer.set_rows_per_block(10);
+        writer.push_record(&TestRecord::default().to_raw(), false).unwrap();
+        writer.finish_with_summary(false).unwrap();
+
+        let inputs = [open_test_file(&plain), open_test_file(&aligned)];
+        let out = dir.path().join("cat.gbam");
+        let err = cat(&inputs, File::create(&out).unwrap()).unwrap_err();
+        assert_eq!(err.to_string(), "Input 1 differs from input 0 in rows per block");
+        assert!(cat(&[], File::create(&out).unwrap()).is_err());
+
+        let empty = dir.path().join("empty.gbam");
+        write_test_file(&empty, "", &[]);
+        let inputs = [open_test_file(&empty), open_test_file(&empty)];
+        cat(&inputs, File::create(&out).unwrap()).unwrap();
+        let reader = open_test_file(&out);
+        assert_eq!(reader.num_records(), 0);
+        assert_eq!(reader.file_meta.view_blocks(&Fields::ReadName).len(), 1);
+    }
+}
@@ -0,0 +1,541 @@
+//! Transforms of Flags, Mapq and index blocks applied before compression.
+//!
+//! Flags and Mapq columns hold few distinct values, often in long runs, and
+//! offsets in index columns grow by nearly constant steps, which generic
+//! codecs compress far from optimally. Transform is chosen per block and
+//! recorded in its meta, blocks are inverted to plain layout on read.
+//!
+//! FlagsRLE layout, runs of identical flags as LEB128 varints:
+//!
+//! | n_records: u32 | (value, count) * n_runs |
+//!
+//! MapqBitPack layout, indices into dictionary of distinct values packed at
+//! the minimal width, least significant bits first:
+//!
+//! | n_records: u32 | n_values: u8 | values: u8 * n_values | width: u8 | indices |
+//!
+//! OffsetDeltas layout, the first offset and differences of the following
+//! ones to their predecessors packed at the width of the largest one, like
+//! indices above. Offsets restart at records starting a data block, whose
+//! differences are taken to zero:
+//!
+//! | n_records: u32 | first: u32 | n_restarts: u32 | restarts: u32 * n_restarts | width: u8 | deltas |
+use bam_tools::record::fields::Fields;
+use byteorder::{ByteOrder, LittleEndian, ReadBytesExt, WriteBytesExt};
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::io;
+
+use crate::layout::INDEX_ENTRY_SIZE;
+use crate::{MAX_BLOCK_SIZE_LIMIT, U32_SIZE};
+
+/// Blocks with more distinct values are left as is.
+const MAX_DISTINCT: usize = 16;
+/// Flags blocks are run-length encoded if runs are at least this long on average.
+const MIN_AVG_RUN: usize = 4;
+
+/// Transform of a block, stored in its meta.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[allow(clippy::upper_case_acronyms)]
+pub enum ColumnTransform {
+    FlagsRLE,
+    MapqBitPack,
+    OffsetDeltas,
+}
+
+/// Picks transform of `field` block by cardinality and run count of its
+/// values, or by size of packed offset deltas for index fields. Returns it
+/// with the transformed block, or None if the block is better left as is.
+pub(crate) fn transform_block(field: Fields, data: &[u8]) -> Option<(ColumnTransform, Vec<u8>)> {
+    match field {
+        Fields::Flags => {
+            let values: Vec<u16> = data.chunks_exact(2).map(LittleEndian::read_u16).collect();
+            let runs = count_runs(&values);
+            if values.is_empty() || distinct(&values) > MAX_DISTINCT || runs * MIN_AVG_RUN > values.len() {
+                return None;
+            }
+            Some((ColumnTransform::FlagsRLE, encode_runs(&values)))
+        }
+        Fields::Mapq => {
+            if data.is_empty() || distinct(data) > MAX_DISTINCT {
+                return None;
+            }
+            Some((ColumnTransform::MapqBitPack, bit_pack(data)))
+        }
+        Fields::LName | Fields::NCigar | Fields::SequenceLength | Fields::RawTagsLen | Fields::RawSeqLen => {
+            if data.is_empty() {
+                return None;
+            }
+            let packed = delta_pack(data);
+            if packed.len() >= data.len() {
+                return None;
+            }
+            Some((ColumnTransform::OffsetDeltas, packed))
+        }
+        _ => None,
+    }
+}
+
+impl ColumnTransform {
+    /// Restores plain layout of a transformed block.
+    pub(crate) fn invert(self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            ColumnTransform::FlagsRLE => decode_runs(data),
+            ColumnTransform::MapqBitPack => bit_unpack(data),
+            ColumnTransform::OffsetDeltas => delta_unpack(data),
+        }
+    }
+}
+
+fn distinct<T: Copy + Ord>(values: &[T]) -> usize {
+    let mut seen: Vec<T> = Vec::new();
+    for &value in values {
+        if let Err(pos) = seen.binary_search(&value) {
+            if seen.len() == MAX_DISTINCT {
+                // Enough to reject the block.
+                return MAX_DISTINCT + 1;
+            }
+            seen.insert(pos, value);
+        }
+    }
+    seen.len()
+}
+
+fn count_runs(values: &[u16]) -> usize {
+    values.windows(2).filter(|pair| pair[0] != pair[1]).count() + usize::from(!values.is_empty())
+}
+
+fn write_varint(dest: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        dest.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    dest.push(value as u8);
+}
+
+fn read_varint(src: &mut &[u8]) -> io::Result<u64> {
+    let mut value = 0;
+    for shift in (0..64).step_by(7) {
+        let byte = src.read_u8()?;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(invalid("Varint is too long"))
+}
+
+fn encode_runs(values: &[u16]) -> Vec<u8> {
+    let mut dest = Vec::new();
+    dest.write_u32::<LittleEndian>(values.len() as u32).unwrap();
+    let mut start = 0;
+    while start < values.len() {
+        let value = values[start];
+        let len = values[start..].iter().take_while(|&&v| v == value).count();
+        write_varint(&mut dest, value as u64);
+        write_varint(&mut dest, len as u64);
+        start += len;
+    }
+    dest
+}
+
+fn decode_runs(mut src: &[u8]) -> io::Result<Vec<u8>> {
+    let n = src.read_u32::<LittleEndian>()? as usize;
+    let mut dest = Vec::with_capacity(2 * n);
+    while dest.len() < 2 * n {
+        let value = u16::try_from(read_varint(&mut src)?).map_err(|_| invalid("Flags value out of range"))?;
+        let count = read_varint(&mut src)? as usize;
+        if count == 0 || dest.len() + 2 * count > 2 * n {
+            return Err(invalid("Run exceeds number of records"));
+        }
+        for _ in 0..count {
+            dest.write_u16::<LittleEndian>(value).unwrap();
+        }
+    }
+    Ok(dest)
+}
+
+/// Bits needed to tell apart `n` values.
+fn width(n: usize) -> u32 {
+    usize::BITS - n.saturating_sub(1).leading_zeros()
+}
+
+fn bit_pack(values: &[u8]) -> Vec<u8> {
+    let mut dict = values.to_vec();
+    dict.sort_unstable();
+    dict.dedup();
+    let width = width(dict.len());
+    let mut dest = Vec::new();
+    dest.write_u32::<LittleEndian>(values.len() as u32).unwrap();
+    dest.push(dict.len() as u8);
+    dest.extend_from_slice(&dict);
+    dest.push(width as u8);
+    let indices = values.iter().map(|value| dict.binary_search(value).unwrap() as u32);
+    pack_bits(&mut dest, indices, width);
+    dest
+}
+
+fn bit_unpack(mut src: &[u8]) -> io::Result<Vec<u8>> {
+    let n = src.read_u32::<LittleEndian>()? as usize;
+    let n_values = src.read_u8()? as usize;
+    if src.len() < n_values + 1 {
+        return Err(invalid("Truncated dictionary"));
+    }
+    let (dict, rest) = src.split_at(n_values);
+    let width = rest[0] as usize;
+    let packed = &rest[1..];
+    if width > 8 || packed.len() < (n * width).div_ceil(8) {
+        return Err(invalid("Truncated packed values"));
+    }
+    (0..n)
+        .map(|i| {
+            let idx = unpack_bits(packed, i, width) as usize;
+            dict.get(idx).copied().ok_or_else(|| invalid("Index out of dictionary"))
+        })
+        .collect()
+}
+
+/// Appends `values` packed at `width` bits, least significant bits first.
+fn pack_bits(dest: &mut Vec<u8>, values: impl ExactSizeIterator<Item = u32>, width: u32) {
+    let width = width as usize;
+    let start = dest.len();
+    dest.resize(start + (values.len() * width).div_ceil(8), 0);
+    for (i, value) in values.enumerate() {
+        for bit in 0..width {
+            let pos = i * width + bit;
+            dest[start + pos / 8] |= (((value >> bit) & 1) as u8) << (pos % 8);
+        }
+    }
+}
+
+/// Value `i` of `packed` at `width` bits, up to 32.
+fn unpack_bits(packed: &[u8], i: usize, width: usize) -> u32 {
+    (0..width).fold(0, |value, bit| {
+        let pos = i * width + bit;
+        value | u32::from((packed[pos / 8] >> (pos % 8)) & 1) << bit
+    })
+}
+
+fn delta_pack(data: &[u8]) -> Vec<u8> {
+    let offsets: Vec<u32> = data.chunks_exact(INDEX_ENTRY_SIZE).map(LittleEndian::read_u32).collect();
+    let mut restarts = Vec::new();
+    let mut deltas = Vec::with_capacity(offsets.len());
+    for (i, pair) in offsets.windows(2).enumerate() {
+        if pair[1] < pair[0] {
+            restarts.push(i as u32 + 1);
+            deltas.push(pair[1]);
+        } else {
+            deltas.push(pair[1] - pair[0]);
+        }
+    }
+    let width = deltas.iter().map(|delta| u32::BITS - delta.leading_zeros()).max().unwrap_or(0);
+    let mut dest = Vec::new();
+    dest.write_u32::<LittleEndian>(offsets.len() as u32).unwrap();
+    dest.write_u32::<LittleEndian>(offsets[0]).unwrap();
+    dest.write_u32::<LittleEndian>(restarts.len() as u32).unwrap();
+    for restart in restarts {
+        dest.write_u32::<LittleEndian>(restart).unwrap();
+    }
+    dest.push(width as u8);
+    pack_bits(&mut dest, deltas.into_iter(), width);
+    dest
+}
+
+fn delta_unpack(mut src: &[u8]) -> io::Result<Vec<u8>> {
+    let n = src.read_u32::<LittleEndian>()? as usize;
+    let mut offset = src.read_u32::<LittleEndian>()?;
+    let n_restarts = src.read_u32::<LittleEndian>()? as usize;
+    // Blocks hold at least one record, within the largest block size limit.
+    if n == 0 || n > MAX_BLOCK_SIZE_LIMIT / INDEX_ENTRY_SIZE || n_restarts >= n {
+        return Err(invalid("Number of records out of range"));
+    }
+    if src.len() < n_restarts * U32_SIZE + 1 {
+        return Err(invalid("Truncated restarts"));
+    }
+    let (restarts, rest) = src.split_at(n_restarts * U32_SIZE);
+    let width = rest[0] as usize;
+    let packed = &rest[1..];
+    if width > 32 || packed.len() < ((n - 1) * width).div_ceil(8) {
+        return Err(invalid("Truncated packed deltas"));
+    }
+    let mut restarts = restarts.chunks_exact(U32_SIZE).map(LittleEndian::read_u32).peekable();
+    let mut dest = Vec::with_capacity(n * INDEX_ENTRY_SIZE);
+    dest.write_u32::<LittleEndian>(offset).unwrap();
+    for i in 1..n {
+        let delta = unpack_bits(packed, i - 1, width);
+        offset = if restarts.next_if_eq(&(i as u32)).is_some() {
+            delta
+        } else {
+            offset.checked_add(delta).ok_or_else(|| invalid("Offset out of range"))?
+        };
+        dest.write_u32::<LittleEndian>(offset).unwrap();
+    }
+    if restarts.next().is_some() {
+        return Err(invalid("Restarts out of order"));
+    }
+    Ok(dest)
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reader::reader::Reader;
+    use crate::test_utils::{new_test_writer, open_test_file, TestRecord};
+    use tempdir::TempDir;
+
+    fn flags_block(values: &[u16]) -> Vec<u8> {
+        let mut data = vec![0; 2 * values.len()];
+        LittleEndian::write_u16_into(values, &mut data);
+        data
+    }
+
+    #[test]
+    fn test_single_run() {
+        let data = flags_block(&[1187].repeat(1_000_000));
+        let (transform, packed) = transform_block(Fields::Flags, &data).unwrap();
+        assert_eq!(transform, ColumnTransform::FlagsRLE);
+        assert!(packed.len() < 16, "{}", packed.len());
+        assert_eq!(transform.invert(&packed).unwrap(), data);
+
+        let data = vec![60; 1_000_000];
+        let (transform, packed) = transform_block(Fields::Mapq, &data).unwrap();
+        assert_eq!(transform, ColumnTransform::MapqBitPack);
+        // Single value takes no bits.
+        assert_eq!(packed.len(), 4 + 1 + 1 + 1);
+        assert_eq!(transform.invert(&packed).unwrap(), data);
+        assert!(transform.invert(&packed[..5]).is_err());
+    }
+
+    #[test]
+    fn test_alternating_values() {
+        // Mates: runs are too short for RLE.
+        let data = flags_block(&[99, 147].repeat(1000));
+        assert!(transform_block(Fields::Flags, &data).is_none());
+        let data: Vec<u8> = (0..1001).map(|i| [0, 60, 0, 255, 7][i % 5]).collect();
+        let (transform, packed) = transform_block(Fields::Mapq, &data).unwrap();
+        assert_eq!(packed.len(), 4 + 1 + 4 + 1 + (1001 * 2usize).div_ceil(8));
+        assert_eq!(transform.invert(&packed).unwrap(), data);
+        let many: Vec<u8> = (0..=255).collect();
+        assert!(transform_block(Fields::Mapq, &many).is_none());
+    }
+
+    fn offsets_block(offsets: &[u32]) -> Vec<u8> {
+        let mut data = vec![0; INDEX_ENTRY_SIZE * offsets.len()];
+        LittleEndian::write_u32_into(offsets, &mut data);
+        data
+    }
+
+    #[test]
+    fn test_offset_deltas() {
+        // Reads of 150 bases, a data block starting in the middle.
+        let offsets: Vec<u32> = (1..=100_000).map(|i| 150 * (i % 60_000)).collect();
+        let data = offsets_block(&offsets);
+        let (transform, packed) = transform_block(Fields::SequenceLength, &data).unwrap();
+        assert_eq!(transform, ColumnTransform::OffsetDeltas);
+        // 8 bits per delta.
+        assert_eq!(packed.len(), 4 + 4 + 4 + 4 + 1 + 99_999);
+        assert_eq!(transform.invert(&packed).unwrap(), data);
+
+        // Single records don't shrink.
+        assert!(transform_block(Fields::LName, &offsets_block(&[7])).is_none());
+        let cases: [&[u32]; 4] = [
+            &[7],
+            // Records of zero length, also at starts of data blocks.
+            &[0, 0, 10, 10, 10, 25, 0, 0, 5, 0],
+            // Oversized records take data blocks of their own.
+            &[300, 600, 20_000_000, 300, u32::MAX, 0],
+            &[u32::MAX, u32::MAX],
+        ];
+        for offsets in cases {
+            let data = offsets_block(offsets);
+            assert_eq!(delta_unpack(&delta_pack(&data)).unwrap(), data, "{:?}", offsets);
+        }
+
+        let packed = delta_pack(&offsets_block(&[10, 0, 20, 0]));
+        assert!(delta_unpack(&packed[..packed.len() - 1]).is_err());
+        // Restarts of records 3 and 1.
+        let mut swapped = packed.clone();
+        LittleEndian::write_u32(&mut swapped[12..], 3);
+        LittleEndian::write_u32(&mut swapped[16..], 1);
+        assert!(delta_unpack(&swapped).is_err());
+        let mut empty = packed;
+        LittleEndian::write_u32(&mut empty[..4], 0);
+        assert!(delta_unpack(&empty).is_err());
+    }
+
+    #[test]
+    fn test_offset_deltas_file() {
+        let dir = TempDir::new("gbam_column_transform").unwrap();
+        let records: Vec<TestRecord> = (0..20_000)
+            .map(|i| {
+                let mut rec = TestRecord::new(0, i, &format!("r{}", i));
+                let len = if i == 7_777 { 10_000 } else { 100 };
+                rec.seq = "ACGT".repeat(len / 4);
+                rec.qual = vec![30; len];
+                rec
+            })
+            .collect();
+        let write = |name: &str, deltas: bool| {
+            let path = dir.path().join(name);
+            let mut writer = new_test_writer(&path, "");
+            writer.set_rows_per_block(5_000);
+            // Record 7777 doesn't fit.
+            writer.set_block_size_limit(Fields::RawSequence, 4096).unwrap();
+            writer.set_offset_deltas(deltas);
+            for rec in &records {
+                writer.push_record(&rec.to_raw(), false).unwrap();
+            }
+            writer.finish_with_summary(false).unwrap();
+            open_test_file(&path)
+        };
+        let mut plain = write("plain.gbam", false);
+        let mut packed = write("deltas.gbam", true);
+
+        let index_fields = [
+            Fields::LName,
+            Fields::NCigar,
+            Fields::SequenceLength,
+            Fields::RawTagsLen,
+            Fields::RawSeqLen,
+        ];
+        for field in &index_fields {
+            let size = |reader: &Reader| -> u64 {
+                reader.file_meta.view_blocks(field).iter().map(|block| block.uncompressed_size).sum()
+            };
+            // Offsets take 4 bytes per record in plain blocks, deltas up to 14 bits.
+            assert_eq!(size(&plain), 4 * 20_000, "{}", field);
+            assert!(size(&packed) * 3 < size(&plain), "{}: {}", field, size(&packed));
+            for block in packed.file_meta.view_blocks(field) {
+                assert_eq!(block.transform, Some(ColumnTransform::OffsetDeltas), "{}", field);
+            }
+        }
+
+        let (mut expected, mut got) = (Vec::new(), Vec::new());
+        let mut plain_records = plain.records();
+        let mut packed_records = packed.records();
+        for _ in &records {
+            plain_records.next_rec().unwrap().unwrap().convert_to_bytes(&mut expected);
+            packed_records.next_rec().unwrap().unwrap().convert_to_bytes(&mut got);
+            assert_eq!(got, expected);
+        }
+        assert!(packed_records.next_rec().unwrap().is_none());
+    }
+
+    /// Transforms known to readers of version 1.6.
+    #[derive(Deserialize, Debug, PartialEq)]
+    #[allow(clippy::upper_case_acronyms)]
+    enum TransformV16 {
+        FlagsRLE,
+        MapqBitPack,
+    }
+
+    fn collect_transforms(value: &serde_json::Value, transforms: &mut Vec<serde_json::Value>) {
+        match value {
+            serde_json::Value::Object(map) => {
+                for (key, value) in map {
+                    if key == "transform" {
+                        transforms.push(value.clone());
+                    } else {
+                        collect_transforms(value, transforms);
+                    }
+                }
+            }
+            serde_json::Value::Array(values) => {
+                values.iter().for_each(|value| collect_transforms(value, transforms));
+            }
+            _ => {}
+        }
+    }
+
+    #[test]
+    fn test_offset_deltas_under_v16_rules() {
+        let dir = TempDir::new("gbam_column_transform").unwrap();
+        let path = dir.path().join("deltas.gbam");
+        let mut writer = new_test_writer(&path, "");
+        writer.set_column_transforms(true);
+        writer.set_offset_deltas(true);
+        for i in 0..2_000 {
+            let rec = TestRecord::new(0, i, &format!("r{}", i));
+            writer.push_record(&rec.to_raw(), false).unwrap();
+        }
+        writer.finish_with_summary(false).unwrap();
+
+        // 1.6 readers take files of any 1.x version...
+        let bytes = std::fs::read(&path).unwrap();
+        let file_info = crate::reader::reader::parse_file_info(&bytes).unwrap();
+        assert_eq!(file_info.gbam_version, [1, 7]);
+
+        // ...and parse transforms of block meta, failing on offset deltas.
+        let reader = open_test_file(&path);
+        let meta = serde_json::to_value(&*reader.file_meta).unwrap();
+        let mut transforms = Vec::new();
+        collect_transforms(&meta, &mut transforms);
+        let parsed: Vec<_> = transforms
+            .into_iter()
+            .map(serde_json::from_value::<TransformV16>)
+            .collect();
+        assert!(parsed.iter().any(|t| t.as_ref().ok() == Some(&TransformV16::FlagsRLE)));
+        let errors: Vec<_> = parsed.into_iter().filter_map(Result::err).collect();
+        assert!(!errors.is_empty());
+        for err in errors {
+            assert!(err.to_string().contains("unknown variant `OffsetDeltas`"), "{}", err);
+        }
+
+        // Such an error fails parsing of the whole meta, as in this version
+        // for a transform it doesn't know, so the file is rejected.
+        let json = serde_json::to_string(&meta).unwrap().replace("\"OffsetDeltas\"", "\"Unknown\"");
+        let err = crate::reader::reader::parse_meta_json(json.as_bytes()).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("unknown variant `Unknown`"), "{}", err);
+    }
+
+    #[test]
+    fn test_block_boundaries() {
+        let dir = TempDir::new("gbam_column_transform").unwrap();
+        let path = dir.path().join("transformed.gbam");
+        let mut writer = new_test_writer(&path, "");
+        writer.set_column_transforms(true);
+        writer.set_rows_per_block(1_000);
+        let records: Vec<TestRecord> = (0..5_500)
+            .map(|i| {
+                let mut rec = TestRecord::new(0, i, "r");
+                // Runs cross block boundaries, second half is high cardinality.
+                rec.flag = if i < 3_000 { [0, 16][i as usize / 700 % 2] } else { i as u16 };
+                rec.mapq = if i < 3_000 { [60, 0][i as usize / 1_500] } else { i as u8 };
+                rec
+            })
+            .collect();
+        for (i, rec) in records.iter().enumerate() {
+            writer.push_record(&rec.to_raw(), false).unwrap();
+            if i == 2_345 {
+                writer.flush_all_columns(false, false).unwrap();
+            }
+        }
+        writer.finish_with_summary(false).unwrap();
+
+        let mut reader = open_test_file(&path);
+        let transforms = |field| {
+            reader
+                .file_meta
+                .view_blocks(field)
+                .iter()
+                .map(|block| block.transform)
+                .collect::<Vec<_>>()
+        };
+        let rle = Some(ColumnTransform::FlagsRLE);
+        let bit_pack = Some(ColumnTransform::MapqBitPack);
+        assert_eq!(transforms(&Fields::Flags), [rle, rle, rle, None, None, None, None]);
+        assert_eq!(transforms(&Fields::Mapq), [bit_pack, bit_pack, bit_pack, None, None, None, None]);
+
+        let mut fetched = reader.records();
+        for rec in &records {
+            let got = fetched.next_rec().unwrap().unwrap();
+            assert_eq!((got.flag, got.mapq), (Some(rec.flag), Some(rec.mapq)));
+        }
+        assert!(fetched.next_rec().unwrap().is_none());
+    }
+}
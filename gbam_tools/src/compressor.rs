@@ -1,26 +1,72 @@
 use super::Codecs;
+use crate::buffer_pool::BufferPool;
+use crate::error::CodecError;
+use crate::level_tuning::{candidate_levels, BlockLevel, LevelTuner, Sample};
+use crate::progress::CancellationToken;
+use crate::trace::{trace_record, trace_span, Counters, Dispatch, Timer};
 use crate::writer::BlockInfo;
-use crate::SIZE_LIMIT;
 use flume::{Receiver, Sender};
 use rayon::ThreadPool;
 
 use flate2::write::GzEncoder;
 use flate2::Compression;
+#[cfg(feature = "brotli")]
 use brotli::CompressorWriter;
-use zstd::stream::encode_all;
+#[cfg(feature = "zstd")]
+use zstd::stream::copy_encode;
 // use lz4::EncoderBuilder;
 use std::io::Write;
 
-use std::sync::{Mutex, OnceLock};
-use std::fmt::Write as FmtWrite; // For formatting into String
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+#[cfg(test)]
 use bam_tools::record::fields::Fields;
-use std::fs::File;
+use std::time::Instant;
 
 // use lz4_flex::block::{compress_into, get_maximum_output_size};
 use lzzzz::lz4;
+#[cfg(feature = "xz")]
 use xz2::write::XzEncoder;
 
 
+/// Compression threads, which can be shared by writers, so converting many
+/// small files doesn't spawn threads for each of them. Clones share threads.
+#[derive(Clone)]
+pub struct CompressorPool {
+    pool: Arc<ThreadPool>,
+    threads_started: Arc<AtomicUsize>,
+}
+
+impl CompressorPool {
+    pub fn new(thread_num: usize) -> Self {
+        let threads_started = Arc::new(AtomicUsize::new(0));
+        let counter = threads_started.clone();
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(thread_num)
+            .start_handler(move |_| {
+                counter.fetch_add(1, Ordering::Relaxed);
+            })
+            .build()
+            .unwrap();
+        Self {
+            pool: Arc::new(pool),
+            threads_started,
+        }
+    }
+
+    pub fn thread_num(&self) -> usize {
+        self.pool.current_num_threads()
+    }
+
+    /// Number of threads spawned since the pool was created.
+    pub fn threads_started(&self) -> usize {
+        self.threads_started.load(Ordering::Relaxed)
+    }
+}
+
 pub(crate) enum OrderingKey {
     Key(u64),
     UnusedBlock,
@@ -31,96 +77,268 @@ pub(crate) struct CompressTask {
     pub ordering_key: OrderingKey,
     pub block_info: BlockInfo,
     pub buf: Vec<u8>,
+    /// Failure of the codec, `buf` is empty then. Checked by
+    /// `write_data_and_update_meta()`.
+    pub result: Result<(), CodecError>,
+    /// `buf` holds the block uncompressed, since the codec didn't shrink it.
+    pub stored: bool,
+    // Submission number, completed tasks are handed out in this order.
+    seq: usize,
 }
+/// Compresses blocks of one writer. Buffers and queue of compressed blocks
+/// belong to the writer, only threads are shared, so `finish()` waits for
+/// blocks of this writer only.
 pub(crate) struct Compressor {
-    compr_pool: ThreadPool,
+    compr_pool: CompressorPool,
     compr_data_tx: Sender<CompressTask>,
     compr_data_rx: Receiver<CompressTask>,
-    /// Buffers shared among threads
-    buf_tx: Sender<Vec<u8>>,
-    buf_rx: Receiver<Vec<u8>>,
+    // Output buffers are taken from it, compressed blocks handed back.
+    buffers: BufferPool,
     // Total number of decompression queryies
     sent: usize,
     // Processed blocks number
     received: usize,
+    // Uncompressed bytes of blocks sent and not yet handed back.
+    queued_bytes: usize,
+    // Tasks completed ahead of the next one in submission order.
+    pending: BTreeMap<usize, CompressTask>,
+    cancel: Option<CancellationToken>,
+    levels: LevelTuner,
+    counters: Counters,
+    #[cfg(test)]
+    codec_hook: Option<CodecHook>,
 }
 
+// Called with field and block number before a block is compressed, so tests
+// can make codecs fail or panic.
+#[cfg(test)]
+pub(crate) type CodecHook = Arc<dyn Fn(Fields, u64) -> std::io::Result<()> + Send + Sync>;
+
 impl Compressor {
-    pub fn new(thread_num: usize) -> Self {
+    pub fn new(compr_pool: CompressorPool) -> Self {
         let (compr_data_tx, compr_data_rx) = flume::unbounded();
-        let (buf_tx, buf_rx) = flume::unbounded();
-        for _ in 0..thread_num {
-            buf_tx.send(vec![0; SIZE_LIMIT]).unwrap();
+        // Dummy tasks let as many blocks be compressed as there are threads
+        // before the writer waits. Their buffers are empty.
+        for _ in 0..compr_pool.thread_num() {
             compr_data_tx
                 .send(CompressTask {
                     ordering_key: OrderingKey::UnusedBlock,
                     block_info: BlockInfo::default(),
-                    buf: vec![0; SIZE_LIMIT],
+                    buf: Vec::new(),
+                    result: Ok(()),
+                    stored: false,
+                    seq: 0,
                 })
                 .unwrap();
         }
+        // Block being filled and compressed per thread, plus slack.
+        let buffers = BufferPool::new(2 * compr_pool.thread_num() + 2);
         Compressor {
-            compr_pool: rayon::ThreadPoolBuilder::new()
-                .num_threads(thread_num)
-                .build()
-                .unwrap(),
+            compr_pool,
             compr_data_tx,
             compr_data_rx,
-            buf_tx,
-            buf_rx,
+            buffers,
             sent: 0,
             received: 0,
+            queued_bytes: 0,
+            pending: BTreeMap::new(),
+            cancel: None,
+            levels: LevelTuner::new(),
+            counters: Counters::new("writer"),
+            #[cfg(test)]
+            codec_hook: None,
         }
     }
 
+    pub fn buffer_pool(&self) -> &BufferPool {
+        &self.buffers
+    }
+
+    pub fn set_buffer_pool(&mut self, buffers: BufferPool) {
+        self.buffers = buffers;
+    }
+
+    /// Once `token` is cancelled, blocks are handed back uncompressed.
+    pub fn set_cancellation_token(&mut self, token: CancellationToken) {
+        self.cancel = Some(token);
+    }
+
+    pub fn levels(&self) -> &LevelTuner {
+        &self.levels
+    }
+
+    pub fn levels_mut(&mut self) -> &mut LevelTuner {
+        &mut self.levels
+    }
+
+    /// Uncompressed size of blocks being compressed or waiting to be handed
+    /// back.
+    pub fn queued_bytes(&self) -> usize {
+        self.queued_bytes
+    }
+
+    /// Totals of the writer, blocks are counted as they are compressed.
+    pub fn counters(&self) -> &Counters {
+        &self.counters
+    }
+
+    #[cfg(test)]
+    pub(crate) fn set_codec_hook(&mut self, hook: CodecHook) {
+        self.codec_hook = Some(hook);
+    }
+
+    /// Compresses the whole of `data`, which has to be `uncompr_size` of
+    /// `block_info` long, so bytes a recycled buffer holds past the block
+    /// never end up in it.
     pub fn compress_block(
         &mut self,
         ordering_key: OrderingKey,
         block_info: BlockInfo,
         data: Vec<u8>,
     ) {
-        let buf_queue_tx = self.buf_tx.clone();
-        let buf_queue_rx = self.buf_rx.clone();
+        assert_eq!(
+            data.len(),
+            block_info.uncompr_size,
+            "Block of field {} doesn't match its uncompressed size",
+            block_info.field
+        );
+        let buffers = self.buffers.clone();
         let compressed_tx = self.compr_data_tx.clone();
+        let cancel = self.cancel.clone();
+        let seq = self.sent;
         self.sent += 1;
-        self.compr_pool.install(|| {
+        self.queued_bytes += block_info.uncompr_size;
+        let level = self.levels.block_level(block_info.field, block_info.codec);
+        let tuning = self.levels.shared_fields();
+        let counters = self.counters.clone();
+        let dispatch = Dispatch::current();
+        let block = match ordering_key {
+            OrderingKey::Key(key) => key,
+            OrderingKey::UnusedBlock => 0,
+        };
+        #[cfg(test)]
+        let codec_hook = self.codec_hook.clone();
+        self.compr_pool.pool.install(|| {
             rayon::spawn(move || {
-                let mut buf = buf_queue_rx.recv().unwrap();
-                buf.clear();
-                let compr_data = compress(&data[..block_info.uncompr_size], buf, block_info.codec);
-                buf_queue_tx.send(data).unwrap();
-
-                let field_name = format!("{:?}", block_info.field);
-                let uncompressed_size = block_info.uncompr_size;
-                let compressed_size = compr_data.len();
-
-                let log_line = format!(
-                    "Field: {}, Uncompressed: {}, Compressed: {}\n",
-                    field_name, uncompressed_size, compressed_size
-                );
-
-                compressed_tx
-                    .send(CompressTask {
-                        ordering_key,
-                        block_info,
-                        buf: compr_data,
-                    })
-                    .unwrap();
+                let _dispatch = dispatch.enter();
+                // Span closes before the block is handed back.
+                let (compr_data, result, stored) = {
+                    let span = trace_span!(
+                        "compress_block",
+                        field = %block_info.field,
+                        items = block_info.numitems,
+                        size = block_info.uncompr_size,
+                        compressed = tracing::field::Empty
+                    );
+                    let timer = Timer::start();
+                    let field = block_info.field;
+                    let codec = block_info.codec;
+                    // Panics of codecs are caught, so the thread keeps serving
+                    // other writers and this one gets its task back.
+                    let compressed = panic::catch_unwind(AssertUnwindSafe(|| -> std::io::Result<Vec<u8>> {
+                        #[cfg(test)]
+                        if let Some(hook) = &codec_hook {
+                            hook(field, block)?;
+                        }
+                        let buf = buffers.get(0);
+                        if cancel.as_ref().is_some_and(CancellationToken::is_cancelled) {
+                            return Ok(buf);
+                        }
+                        Ok(match level {
+                            BlockLevel::Default => compress(&data, buf, codec)?,
+                            BlockLevel::Fixed(level) => compress_at_level(&data, buf, codec, Some(level))?,
+                            BlockLevel::Sample(low, high) => {
+                                let start = Instant::now();
+                                let low_data = compress_at_level(&data, buf, codec, Some(low))?;
+                                let low_secs = start.elapsed().as_secs_f64();
+                                let start = Instant::now();
+                                let high_data = compress_at_level(&data, buffers.get(0), codec, Some(high))?;
+                                let sample = Sample {
+                                    low_size: low_data.len(),
+                                    low_secs,
+                                    high_size: high_data.len(),
+                                    high_secs: start.elapsed().as_secs_f64(),
+                                };
+                                tuning[field as usize].lock().unwrap().add_sample(sample);
+                                buffers.put(low_data);
+                                high_data
+                            }
+                        })
+                    }));
+                    let failed = |message: String| CodecError::CompressionFailed { field, block, message };
+                    let (compr_data, result) = match compressed {
+                        Ok(Ok(compr_data)) => (compr_data, Ok(())),
+                        Ok(Err(err)) => (Vec::new(), Err(failed(err.to_string()))),
+                        Err(panic) => (Vec::new(), Err(failed(panic_message(&*panic)))),
+                    };
+                    // Blocks the codec makes larger are written as they are.
+                    let stored = result.is_ok() && compr_data.len() > data.len();
+                    let compr_data = if stored {
+                        buffers.put(compr_data);
+                        data
+                    } else {
+                        buffers.put(data);
+                        compr_data
+                    };
+                    counters.add_codec_time(&timer);
+                    counters.add_block(block_info.uncompr_size as u64, compr_data.len() as u64);
+                    trace_record!(span, "compressed", compr_data.len());
+                    (compr_data, result, stored)
+                };
+
+                // Writer may be dropped without waiting for its blocks after
+                // cancellation.
+                let _ = compressed_tx.send(CompressTask {
+                    ordering_key,
+                    block_info,
+                    buf: compr_data,
+                    result,
+                    stored,
+                    seq,
+                });
             });
         });
     }
 
-    /// Drain completed tasks
+    /// Drain completed tasks. Tasks are returned in the order they were
+    /// submitted, so blocks are written in the same order regardless of the
+    /// number of threads. Initial dummy blocks may come first. Blocks which
+    /// failed to compress are returned too, with the error in `result`.
     pub fn get_compr_block(&mut self) -> CompressTask {
-        let task = self.compr_data_rx.recv().unwrap();
-        // Correct for first dummy blocks
-        if let OrderingKey::Key(_) = task.ordering_key {
-            self.received += 1;
+        loop {
+            if let Some(task) = self.pending.remove(&self.received) {
+                self.received += 1;
+                self.queued_bytes -= task.block_info.uncompr_size;
+                return task;
+            }
+            let task = self.compr_data_rx.recv().unwrap();
+            match task.ordering_key {
+                OrderingKey::UnusedBlock => return task,
+                OrderingKey::Key(_) => {
+                    self.pending.insert(task.seq, task);
+                }
+            }
         }
-        task
     }
 
-    /// Wait for all threads to finish and return leftovers
+    /// Gives back buffer of a task returned by `finish()`, so compression can
+    /// go on with the same number of blocks in flight.
+    pub fn recycle_buffer(&mut self, buf: Vec<u8>) {
+        self.buffers.put(buf);
+        self.compr_data_tx
+            .send(CompressTask {
+                ordering_key: OrderingKey::UnusedBlock,
+                block_info: BlockInfo::default(),
+                buf: Vec::new(),
+                result: Ok(()),
+                stored: false,
+                seq: 0,
+            })
+            .unwrap();
+    }
+
+    /// Wait for all blocks of this writer to be compressed and return
+    /// leftovers. Threads keep running.
     pub fn finish(&mut self) -> Vec<CompressTask> {
         let mut leftovers = Vec::new();
         while self.received != self.sent {
@@ -130,11 +348,25 @@ impl Compressor {
     }
 }
 
-pub fn compress(source: &[u8], mut dest: Vec<u8>, codec: Codecs) -> Vec<u8> {
+pub fn compress(source: &[u8], dest: Vec<u8>, codec: Codecs) -> std::io::Result<Vec<u8>> {
+    compress_at_level(source, dest, codec, None)
+}
+
+/// Compresses at `level` of the codec, see `level_tuning::level_range()`,
+/// or at its default level. Level is ignored by codecs without levels.
+pub fn compress_at_level(
+    source: &[u8],
+    mut dest: Vec<u8>,
+    codec: Codecs,
+    level: Option<i32>,
+) -> std::io::Result<Vec<u8>> {
+    let default_level = candidate_levels(codec).map_or(0, |(_, high)| high);
+    let level = level.unwrap_or(default_level);
     let compressed_bytes = match codec {
         Codecs::Gzip => {
-            let mut encoder = GzEncoder::new(dest, Compression::new(9));
-            encoder.write_all(source).unwrap();
+            dest.clear();
+            let mut encoder = GzEncoder::new(dest, Compression::new(level as u32));
+            encoder.write_all(source)?;
             encoder.finish()
         }
         Codecs::Lz4 => {
@@ -150,25 +382,28 @@ pub fn compress(source: &[u8], mut dest: Vec<u8>, codec: Codecs) -> Vec<u8> {
                 )),
             }
         }
+        #[cfg(feature = "brotli")]
         Codecs::Brotli => {
             dest.clear();
             {
-                let mut writer = CompressorWriter::new(&mut dest, 4096, 8, 22);
-                writer.write_all(source).unwrap();
-                writer.flush().unwrap();
+                let mut writer = CompressorWriter::new(&mut dest, 4096, level as u32, 22);
+                writer.write_all(source)?;
+                writer.flush()?;
             }
             Ok(dest)
         }
+        #[cfg(feature = "xz")]
         Codecs::Xz => {
-            let mut encoder = XzEncoder::new(Vec::new(), 6);
-            encoder.write_all(source).unwrap();
-            let compressed = encoder.finish().unwrap();
-            Ok(compressed)
+            dest.clear();
+            let mut encoder = XzEncoder::new(dest, level as u32);
+            encoder.write_all(source)?;
+            encoder.finish()
         }
+        #[cfg(feature = "zstd")]
         Codecs::Zstd => {
-            // encode_all returns a Vec<u8>
-            match encode_all(source, 14) {
-                Ok(c) => Ok(c),
+            dest.clear();
+            match copy_encode(source, &mut dest, level) {
+                Ok(()) => Ok(dest),
                 Err(_) => Err(std::io::Error::other(
                     "Zstd compression error",
                 )),
@@ -179,6 +414,17 @@ pub fn compress(source: &[u8], mut dest: Vec<u8>, codec: Codecs) -> Vec<u8> {
             dest.extend_from_slice(source);
             Ok(dest)
         }
+        // Writers refuse codecs which are compiled out.
+        #[allow(unreachable_patterns)]
+        codec => panic!("Codec {:?} is not compiled in.", codec),
+    };
+    compressed_bytes
+}
+
+fn panic_message(panic: &(dyn Any + Send)) -> String {
+    let message = match panic.downcast_ref::<&str>() {
+        Some(message) => message.to_string(),
+        None => panic.downcast_ref::<String>().cloned().unwrap_or_default(),
     };
-    compressed_bytes.unwrap()
+    format!("codec panicked: {}", message)
 }
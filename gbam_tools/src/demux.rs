@@ -0,0 +1,281 @@
+//! Splitting of GBAM files into one file per read group, by RG tags of
+//! records.
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::path::{Path, PathBuf};
+
+use bam_tools::record::bamrawrecord::BAMRawRecord;
+use bam_tools::record::fields::Fields;
+use byteorder::{LittleEndian, WriteBytesExt};
+
+use crate::meta::{sam_header_text, SortOrder};
+use crate::reader::reader::Reader;
+use crate::writer::{Writer, WriterSettings};
+
+/// Name of the output of records without RG tag, without extension.
+pub const UNKNOWN_READ_GROUP: &str = "unknown";
+
+/// Options of `split_by_read_group_with_options()`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ReadGroupSplitOptions {
+    /// Fail on records without RG tag instead of writing them into
+    /// `unknown.gbam`.
+    pub strict: bool,
+}
+
+/// Records written by `split_by_read_group()`.
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub struct ReadGroupCounts {
+    /// Records by read group id.
+    pub groups: BTreeMap<String, u64>,
+    /// Records without RG tag.
+    pub unknown: u64,
+}
+
+/// Writes records of file opened by `reader` into `out_dir`, one file named
+/// by read group id per read group, see
+/// `split_by_read_group_with_options()`.
+pub fn split_by_read_group(reader: &mut Reader, out_dir: &Path) -> io::Result<ReadGroupCounts> {
+    split_by_read_group_with_options(reader, out_dir, &ReadGroupSplitOptions::default())
+}
+
+/// Writes records of file opened by `reader` into `out_dir`, records with
+/// RG tag `id` into `id.gbam` and ones without it into `unknown.gbam`.
+/// Characters of ids other than alphanumeric ones, '-', '_' and '.' are
+/// replaced by '_'. Outputs are created when their first record comes, with
+/// the header of the file keeping only @RG lines of their group. Every field
+/// has to be enabled in parsing template. On errors outputs are left
+/// unfinished.
+pub fn split_by_read_group_with_options(
+    reader: &mut Reader,
+    out_dir: &Path,
+    options: &ReadGroupSplitOptions,
+) -> io::Result<ReadGroupCounts> {
+    let all_fields: Vec<Fields> = Fields::iterator().copied().collect();
+    if !reader.parsing_template.check_if_active(&all_fields) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "All fields have to be enabled in parsing template to split by read group.",
+        ));
+    }
+    let meta = reader.file_meta.clone();
+    let settings = WriterSettings {
+        codecs: Fields::iterator().map(|field| *meta.get_field_codec(field)).collect(),
+        // Writers run side by side, one compression thread each.
+        thread_num: 1,
+        ref_seqs: meta.get_ref_seqs().clone(),
+        full_command: String::from("split_by_read_group"),
+        is_sorted: meta.get_sort_order() == SortOrder::Coordinate,
+        create_dirs: true,
+        ..Default::default()
+    };
+
+    // Outputs by read group id, None for records without RG tag.
+    let mut outputs: BTreeMap<Option<Vec<u8>>, Writer<BufWriter<File>>> = BTreeMap::new();
+    // Read group ids by file name, to catch ids mapped to the same file.
+    let mut file_names: BTreeMap<String, Option<Vec<u8>>> = BTreeMap::new();
+    let mut counts = ReadGroupCounts::default();
+    let mut bytes = Vec::new();
+    let mut records = reader.records();
+    let mut rec_num: u64 = 0;
+    while let Some(rec) = records.next_rec()? {
+        let read_group = match rec.get_tag(b"RG")? {
+            Some(value) => match value.as_bytes() {
+                Some(id) => Some(id.to_vec()),
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("RG tag of record {} is not a string", rec_num),
+                    ))
+                }
+            },
+            None if options.strict => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Record {} has no RG tag", rec_num),
+                ))
+            }
+            None => None,
+        };
+
+        if !outputs.contains_key(&read_group) {
+            let name = match &read_group {
+                Some(id) => file_name(id),
+                None => String::from(UNKNOWN_READ_GROUP),
+            };
+            if let Some(other) = file_names.insert(name.clone(), read_group.clone()) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "Read groups {} and {} are both written to {}.gbam",
+                        display_id(&other),
+                        display_id(&read_group),
+                        name
+                    ),
+                ));
+            }
+            let settings = WriterSettings {
+                sam_header: subset_read_groups(meta.get_sam_header(), read_group.as_deref()),
+                ..settings.clone()
+            };
+            let path: PathBuf = out_dir.join(format!("{}.gbam", name));
+            let mut writer = Writer::create(path, settings)?;
+            writer.set_source_index(meta.has_source_index());
+            outputs.insert(read_group.clone(), writer);
+        }
+
+        rec.convert_to_bytes(&mut bytes);
+        // Without block_size.
+        let raw = BAMRawRecord(Cow::Borrowed(&bytes[4..]));
+        let writer = outputs.get_mut(&read_group).unwrap();
+        writer.push_record_with_source_index(&raw, rec.source_index(), false)?;
+        match read_group {
+            Some(id) => *counts.groups.entry(String::from_utf8_lossy(&id).into_owned()).or_default() += 1,
+            None => counts.unknown += 1,
+        }
+        rec_num += 1;
+    }
+
+    for writer in outputs.values_mut() {
+        writer.finish_with_summary(false)?;
+    }
+    Ok(counts)
+}
+
+fn file_name(id: &[u8]) -> String {
+    id.iter()
+        .map(|&c| match c {
+            b'-' | b'_' | b'.' => c as char,
+            c if c.is_ascii_alphanumeric() => c as char,
+            _ => '_',
+        })
+        .collect()
+}
+
+fn display_id(read_group: &Option<Vec<u8>>) -> Cow<'_, str> {
+    match read_group {
+        Some(id) => String::from_utf8_lossy(id),
+        None => Cow::Borrowed("without RG tag"),
+    }
+}
+
+/// Header bytes (`l_text`, text, `n_ref` and references, as in BAM) keeping
+/// only the @RG line of `read_group`, or no @RG lines for None.
+fn subset_read_groups(sam_header: &[u8], read_group: Option<&[u8]>) -> Vec<u8> {
+    let text = sam_header_text(sam_header);
+    let kept: Vec<u8> = text
+        .split_inclusive(|&c| c == b'\n')
+        .filter(|line| match line.strip_prefix(b"@RG\t") {
+            Some(rg) => rg
+                .split(|&c| c == b'\t' || c == b'\n' || c == b'\r')
+                .filter_map(|tag| tag.strip_prefix(b"ID:"))
+                .any(|id| Some(id) == read_group),
+            None => true,
+        })
+        .flatten()
+        .copied()
+        .collect();
+
+    let mut bytes = Vec::new();
+    bytes.write_u32::<LittleEndian>(kept.len() as u32).unwrap();
+    bytes.extend_from_slice(&kept);
+    // References follow the text.
+    let refs_start = (std::mem::size_of::<u32>() + text.len()).min(sam_header.len());
+    bytes.extend_from_slice(&sam_header[refs_start..]);
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{open_test_file, write_test_file, TestRecord};
+    use tempdir::TempDir;
+
+    const HEADER: &str = "@HD\tVN:1.6\tSO:coordinate\n\
+        @RG\tID:grp1\tSM:a\n\
+        @RG\tID:grp2\tSM:b\n\
+        @RG\tID:grp/3\tSM:c\n\
+        @PG\tID:test\n";
+
+    fn records() -> Vec<TestRecord> {
+        (0..1_000)
+            .map(|i| {
+                let mut rec = TestRecord::new(i / 400, i, &format!("read{}", i));
+                rec.tags = match i % 4 {
+                    0 | 1 => b"NMC\x01RGZgrp1\0".to_vec(),
+                    2 => b"RGZgrp2\0".to_vec(),
+                    _ => b"RGZgrp/3\0NMC\x02".to_vec(),
+                };
+                rec
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_split_by_read_group() {
+        let dir = TempDir::new("gbam_demux").unwrap();
+        let src = dir.path().join("in.gbam");
+        let mut records = records();
+        records[10].tags = b"NMC\x01".to_vec();
+        write_test_file(&src, HEADER, &records);
+
+        let out_dir = dir.path().join("groups");
+        let counts = split_by_read_group(&mut open_test_file(&src), &out_dir).unwrap();
+        let expected: BTreeMap<String, u64> =
+            [("grp1", 500), ("grp2", 249), ("grp/3", 250)].iter().map(|(id, n)| (id.to_string(), *n)).collect();
+        assert_eq!(counts, ReadGroupCounts { groups: expected, unknown: 1 });
+
+        let mut original = open_test_file(&src);
+        let mut all = Vec::new();
+        let mut recs = original.records();
+        while let Some(rec) = recs.next_rec().unwrap() {
+            all.push(serde_json::to_string(rec).unwrap());
+        }
+        for (name, rg_line, rg) in [
+            ("grp1", "@RG\tID:grp1\tSM:a\n", Some(&b"grp1"[..])),
+            ("grp2", "@RG\tID:grp2\tSM:b\n", Some(&b"grp2"[..])),
+            ("grp_3", "@RG\tID:grp/3\tSM:c\n", Some(&b"grp/3"[..])),
+            ("unknown", "", None),
+        ] {
+            let mut reader = open_test_file(&out_dir.join(format!("{}.gbam", name)));
+            assert_eq!(
+                sam_header_text(reader.file_meta.get_sam_header()),
+                format!("@HD\tVN:1.6\tSO:coordinate\n{}@PG\tID:test\n", rg_line).as_bytes()
+            );
+            assert_eq!(reader.file_meta.get_ref_seqs(), original.file_meta.get_ref_seqs());
+            assert_eq!(reader.file_meta.get_sort_order(), original.file_meta.get_sort_order());
+            // Records keep their order.
+            let mut expected = records
+                .iter()
+                .zip(&all)
+                .filter(|(rec, _)| find_rg(&rec.tags) == rg)
+                .map(|(_, json)| json);
+            let mut recs = reader.records();
+            while let Some(rec) = recs.next_rec().unwrap() {
+                assert_eq!(Some(&serde_json::to_string(rec).unwrap()), expected.next(), "{}", name);
+            }
+            assert!(expected.next().is_none(), "{}", name);
+        }
+    }
+
+    fn find_rg(tags: &[u8]) -> Option<&[u8]> {
+        crate::tags::find_tag(tags, b"RG").unwrap().and_then(|value| value.as_bytes())
+    }
+
+    #[test]
+    fn test_split_by_read_group_strict() {
+        let dir = TempDir::new("gbam_demux").unwrap();
+        let src = dir.path().join("in.gbam");
+        let mut records = records();
+        records[7].tags = Vec::new();
+        write_test_file(&src, HEADER, &records);
+
+        let options = ReadGroupSplitOptions { strict: true };
+        let err = split_by_read_group_with_options(&mut open_test_file(&src), &dir.path().join("groups"), &options)
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(err.to_string(), "Record 7 has no RG tag");
+    }
+}
@@ -0,0 +1,192 @@
+//! Column by column comparison of GBAM files.
+use std::fmt;
+use std::io;
+
+use bam_tools::record::fields::{is_data_field, Fields};
+
+use crate::reader::reader::Reader;
+use crate::reader::record::GbamRecord;
+
+/// Difference of one field.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FieldDiff {
+    pub field: Fields,
+    pub first_differing_record: usize,
+    pub differing_records: u64,
+}
+
+/// Result of `diff()`. Fields are compared over records present in both files.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiffReport {
+    /// Number of records in each file.
+    pub records: [usize; 2],
+    /// Fields with differing values, in order of comparison.
+    pub differing_fields: Vec<FieldDiff>,
+}
+
+impl DiffReport {
+    pub fn is_equal(&self) -> bool {
+        self.records[0] == self.records[1] && self.differing_fields.is_empty()
+    }
+
+    /// First record with differing value of `field`, if any.
+    pub fn first_difference(&self, field: Fields) -> Option<usize> {
+        self.differing_fields
+            .iter()
+            .find(|diff| diff.field == field)
+            .map(|diff| diff.first_differing_record)
+    }
+}
+
+impl fmt::Display for DiffReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_equal() {
+            return write!(f, "Files are equal, {} records", self.records[0]);
+        }
+        if self.records[0] != self.records[1] {
+            writeln!(f, "Record counts differ: {} vs {}", self.records[0], self.records[1])?;
+        }
+        for diff in &self.differing_fields {
+            writeln!(
+                f,
+                "{}: {} records differ, first at record {}",
+                diff.field, diff.differing_records, diff.first_differing_record
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Compares `fields` of files opened by `a` and `b`, other fields are
+/// ignored. Values are compared record by record, so files with different
+/// block boundaries or codecs compare equal if they hold the same records.
+/// Only data fields can be compared, offsets in index fields depend on block
+/// boundaries. Fields have to be enabled in parsing templates of both readers.
+pub fn diff(a: &mut Reader, b: &mut Reader, fields: &[Fields]) -> io::Result<DiffReport> {
+    for field in fields {
+        if !is_data_field(field) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Field {} is an index field, compare its data field instead", field),
+            ));
+        }
+    }
+    for reader in [&*a, &*b] {
+        if !reader.parsing_template.check_if_active(fields) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Compared fields have to be enabled in parsing templates of both readers.",
+            ));
+        }
+    }
+
+    let amount = std::cmp::min(a.amount, b.amount);
+    let mut differing_fields = Vec::new();
+    let (mut rec_a, mut rec_b) = (GbamRecord::default(), GbamRecord::default());
+    // Whole column at once, so blocks are decompressed one by one.
+    for field in fields {
+        let mut field_diff: Option<FieldDiff> = None;
+        for rec_num in 0..amount {
+            a.get_column(field)?.fill_record_field(rec_num, &mut rec_a)?;
+            b.get_column(field)?.fill_record_field(rec_num, &mut rec_b)?;
+            if !field_eq(&rec_a, &rec_b, field) {
+                field_diff
+                    .get_or_insert(FieldDiff {
+                        field: *field,
+                        first_differing_record: rec_num,
+                        differing_records: 0,
+                    })
+                    .differing_records += 1;
+            }
+        }
+        differing_fields.extend(field_diff);
+    }
+    Ok(DiffReport {
+        records: [a.amount, b.amount],
+        differing_fields,
+    })
+}
+
+fn field_eq(a: &GbamRecord, b: &GbamRecord, field: &Fields) -> bool {
+    match field {
+        Fields::RefID => a.refid == b.refid,
+        Fields::Pos => a.pos == b.pos,
+        Fields::Mapq => a.mapq == b.mapq,
+        Fields::Bin => a.bin == b.bin,
+        Fields::Flags => a.flag == b.flag,
+        Fields::NextRefID => a.next_ref_id == b.next_ref_id,
+        Fields::NextPos => a.next_pos == b.next_pos,
+        Fields::TemplateLength => a.tlen == b.tlen,
+        Fields::ReadName => a.read_name == b.read_name,
+        Fields::RawCigar => a.cigar == b.cigar,
+        Fields::RawSequence => a.seq == b.seq,
+        Fields::RawQual => a.qual == b.qual,
+        Fields::RawTags => a.tags == b.tags,
+        _ => unreachable!("Index fields are not compared"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{new_test_writer, open_test_file, write_test_file, TestRecord};
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_diff() {
+        let dir = TempDir::new("gbam_diff").unwrap();
+        let records: Vec<TestRecord> = (0..5_000)
+            .map(|i| TestRecord::new(0, i, &format!("read{}", i)))
+            .collect();
+        let all_fields: Vec<Fields> = Fields::iterator().copied().filter(is_data_field).collect();
+
+        let plain = dir.path().join("plain.gbam");
+        write_test_file(&plain, "", &records);
+        // Same records in smaller blocks.
+        let blocked = dir.path().join("blocked.gbam");
+        let mut writer = new_test_writer(&blocked, "");
+        writer.set_rows_per_block(333);
+        for rec in &records {
+            writer.push_record(&rec.to_raw(), false).unwrap();
+        }
+        writer.finish_with_summary(false).unwrap();
+        let report = diff(&mut open_test_file(&plain), &mut open_test_file(&blocked), &all_fields).unwrap();
+        assert!(report.is_equal(), "{}", report);
+
+        let mut mutated = records.clone();
+        mutated[1234].mapq = 7;
+        mutated[4000].tags = b"NMC\x01".to_vec();
+        let mutated_path = dir.path().join("mutated.gbam");
+        write_test_file(&mutated_path, "", &mutated);
+        let report = diff(&mut open_test_file(&plain), &mut open_test_file(&mutated_path), &all_fields).unwrap();
+        assert!(!report.is_equal());
+        assert_eq!(
+            report.differing_fields,
+            [
+                FieldDiff {
+                    field: Fields::Mapq,
+                    first_differing_record: 1234,
+                    differing_records: 1,
+                },
+                FieldDiff {
+                    field: Fields::RawTags,
+                    first_differing_record: 4000,
+                    differing_records: 1,
+                },
+            ]
+        );
+
+        // Ignored fields don't count.
+        let fields: Vec<Fields> = all_fields.iter().copied().filter(|f| *f != Fields::RawTags).collect();
+        let report = diff(&mut open_test_file(&plain), &mut open_test_file(&mutated_path), &fields).unwrap();
+        assert_eq!(report.first_difference(Fields::Mapq), Some(1234));
+        assert_eq!(report.first_difference(Fields::RawTags), None);
+
+        let truncated = dir.path().join("truncated.gbam");
+        write_test_file(&truncated, "", &records[..10]);
+        let report = diff(&mut open_test_file(&plain), &mut open_test_file(&truncated), &all_fields).unwrap();
+        assert_eq!(report.records, [5_000, 10]);
+        assert!(report.differing_fields.is_empty() && !report.is_equal());
+        assert!(diff(&mut open_test_file(&plain), &mut open_test_file(&truncated), &[Fields::LName]).is_err());
+    }
+}
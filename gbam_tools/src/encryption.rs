@@ -0,0 +1,205 @@
+//! Optional encryption of column blocks, applied after compression.
+//!
+//! Encrypted fields keep cipher, key identifier and a random nonce salt in
+//! their meta, the key itself is never stored. Block nonce is the salt
+//! followed by block index, so the same key can be reused for many files.
+//! Field and block index are authenticated too, so blocks can't be swapped.
+use aes_gcm::aead::{Aead, AeadInPlace, KeyInit, Payload};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use bam_tools::record::fields::Fields;
+use serde::{Deserialize, Serialize};
+use std::io;
+
+pub const KEY_SIZE: usize = 32;
+pub type EncryptionKey = [u8; KEY_SIZE];
+
+/// Nonce of the key check tag. Blocks never get this index.
+const KEY_CHECK_BLOCK: u64 = u32::MAX as u64;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Cipher {
+    Aes256Gcm,
+}
+
+/// Encryption parameters of a field, stored in its meta.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct FieldEncryption {
+    pub cipher: Cipher,
+    /// Identifies key for key provider, e.g. name in a key store.
+    pub key_id: String,
+    nonce_salt: [u8; 8],
+    /// Tag of an empty message, lets reader reject wrong key on open.
+    key_check: Vec<u8>,
+}
+
+impl FieldEncryption {
+    /// Generates parameters with a fresh salt, and the cipher for writing.
+    pub(crate) fn new(field: Fields, key_id: &str, key: &EncryptionKey) -> (Self, BlockCipher) {
+        let cipher = BlockCipher::new(field, key, rand::random());
+        let key_check = cipher.seal(KEY_CHECK_BLOCK, Vec::new());
+        let params = FieldEncryption {
+            cipher: Cipher::Aes256Gcm,
+            key_id: key_id.to_owned(),
+            nonce_salt: cipher.nonce_salt,
+            key_check,
+        };
+        (params, cipher)
+    }
+
+    /// Returns cipher for reading, fails if `key` is not the one used for writing.
+    pub(crate) fn open(&self, field: Fields, key: &EncryptionKey) -> io::Result<BlockCipher> {
+        let cipher = BlockCipher::new(field, key, self.nonce_salt);
+        cipher.decrypt(KEY_CHECK_BLOCK, &self.key_check).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("Wrong key '{}' for field {}", self.key_id, field),
+            )
+        })?;
+        Ok(cipher)
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct BlockCipher {
+    cipher: Aes256Gcm,
+    field: Fields,
+    nonce_salt: [u8; 8],
+}
+
+impl BlockCipher {
+    fn new(field: Fields, key: &EncryptionKey, nonce_salt: [u8; 8]) -> Self {
+        BlockCipher {
+            cipher: Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key)),
+            field,
+            nonce_salt,
+        }
+    }
+
+    fn nonce(&self, block_num: u64) -> [u8; 12] {
+        let mut nonce = [0; 12];
+        nonce[..8].copy_from_slice(&self.nonce_salt);
+        nonce[8..].copy_from_slice(&(block_num as u32).to_le_bytes());
+        nonce
+    }
+
+    fn aad(&self, block_num: u64) -> Vec<u8> {
+        let mut aad = self.field.to_string().into_bytes();
+        aad.extend_from_slice(&block_num.to_le_bytes());
+        aad
+    }
+
+    /// Encrypts block, appending authentication tag.
+    pub fn encrypt(&self, block_num: u64, buf: Vec<u8>) -> Vec<u8> {
+        assert!(block_num < KEY_CHECK_BLOCK, "Too many blocks in encrypted field");
+        self.seal(block_num, buf)
+    }
+
+    fn seal(&self, block_num: u64, mut buf: Vec<u8>) -> Vec<u8> {
+        self.cipher
+            .encrypt_in_place(
+                Nonce::from_slice(&self.nonce(block_num)),
+                &self.aad(block_num),
+                &mut buf,
+            )
+            .expect("Block encryption failed.");
+        buf
+    }
+
+    pub fn decrypt(&self, block_num: u64, data: &[u8]) -> io::Result<Vec<u8>> {
+        let payload = Payload {
+            msg: data,
+            aad: &self.aad(block_num),
+        };
+        self.cipher
+            .decrypt(Nonce::from_slice(&self.nonce(block_num)), payload)
+            .map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "Block {} of field {} failed authentication",
+                        block_num, self.field
+                    ),
+                )
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reader::{parse_tmplt::ParsingTemplate, reader::Reader};
+    use crate::test_utils::{new_test_writer, TestRecord};
+    use std::fs::File;
+    use tempdir::TempDir;
+
+    const KEY: EncryptionKey = [7; KEY_SIZE];
+
+    #[test]
+    fn test_encrypted_columns() {
+        let tmp_dir = TempDir::new("gbam_encryption").unwrap();
+        let path = tmp_dir.path().join("encrypted.gbam");
+        let recs: Vec<TestRecord> = (0..1000)
+            .map(|i| TestRecord::new(i % 3, i * 10, &format!("secret{}", i)))
+            .collect();
+        let mut writer = new_test_writer(&path, "");
+        writer.set_field_encryption(Fields::ReadName, "cohort-key", &KEY);
+        writer.set_field_encryption(Fields::RawSequence, "cohort-key", &KEY);
+        for rec in &recs {
+            writer.push_record(&rec.to_raw(), false).unwrap();
+        }
+        writer.finish_with_summary(false).unwrap();
+        assert!(!std::fs::read(&path)
+            .unwrap()
+            .windows(8)
+            .any(|w| w == b"secret99"));
+
+        // Plain fields are readable without key.
+        let tmplt = ParsingTemplate::new_with(&[Fields::RefID, Fields::Pos, Fields::Flags]);
+        let mut reader = Reader::new(File::open(&path).unwrap(), tmplt).unwrap();
+        let mut records = reader.records();
+        let mut n = 0;
+        while let Some(rec) = records.next_rec().unwrap() {
+            assert_eq!(rec.pos, Some(recs[n].pos));
+            n += 1;
+        }
+        assert_eq!(n, recs.len());
+
+        let tmplt = ParsingTemplate::new_with(&[Fields::Pos, Fields::ReadName]);
+        let err = Reader::new(File::open(&path).unwrap(), tmplt.clone())
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        assert!(err.to_string().contains("cohort-key"));
+
+        let wrong = |_: &str| Some([8; KEY_SIZE]);
+        let err = Reader::new_with_key_provider(File::open(&path).unwrap(), tmplt.clone(), &wrong)
+            .err()
+            .unwrap();
+        assert_eq!(err.to_string(), "Wrong key 'cohort-key' for field ReadName");
+
+        let provider = |id: &str| if id == "cohort-key" { Some(KEY) } else { None };
+        let mut reader =
+            Reader::new_with_key_provider(File::open(&path).unwrap(), tmplt, &provider).unwrap();
+        let mut records = reader.records();
+        let mut n = 0;
+        while let Some(rec) = records.next_rec().unwrap() {
+            assert_eq!(rec.pos, Some(recs[n].pos));
+            let name = rec.read_name.as_ref().unwrap();
+            assert_eq!(&name[..name.len() - 1], recs[n].name.as_bytes());
+            n += 1;
+        }
+        assert_eq!(n, recs.len());
+    }
+
+    #[test]
+    fn test_block_cipher() {
+        let (params, cipher) = FieldEncryption::new(Fields::RawQual, "k", &KEY);
+        let encrypted = cipher.encrypt(3, b"quality".to_vec());
+        assert_eq!(cipher.decrypt(3, &encrypted).unwrap(), b"quality");
+        // Block index and field are authenticated.
+        assert!(cipher.decrypt(4, &encrypted).is_err());
+        let other_field = params.open(Fields::RawTags, &KEY);
+        assert!(other_field.is_err());
+        assert!(params.open(Fields::RawQual, &KEY).is_ok());
+    }
+}
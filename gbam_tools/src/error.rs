@@ -0,0 +1,385 @@
+//! Errors specific to GBAM files. Reader functions return `GbamError`,
+//! which converts into `io::Error` with `?`. Elsewhere they are carried
+//! inside `io::Error`, so they can be told apart with `gbam_error()`, and
+//! converting such `io::Error` back yields the carried error. Error enums
+//! are `#[non_exhaustive]`, later versions may add variants.
+use std::path::{Path, PathBuf};
+use std::io;
+
+use bam_tools::record::fields::Fields;
+use thiserror::Error;
+
+use crate::meta::Codecs;
+
+/// Result of reader functions.
+pub type Result<T> = std::result::Result<T, GbamError>;
+
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum GbamError {
+    /// Reading or writing the underlying file or source failed, or it ended
+    /// early.
+    #[error(transparent)]
+    Io(io::Error),
+    /// File info or meta is malformed, or doesn't agree with itself.
+    #[error(transparent)]
+    Meta(#[from] MetaError),
+    /// Codec of a field is compiled out, or failed on a block.
+    #[error(transparent)]
+    Codec(#[from] CodecError),
+    /// Parsing template, or what was requested of a reader opened with it,
+    /// is invalid.
+    #[error(transparent)]
+    Template(#[from] TemplateError),
+    /// Data of blocks is damaged. Meta checks don't cover block contents,
+    /// see `Reader::check_blocks()`.
+    #[error(transparent)]
+    Corruption(#[from] CorruptionError),
+    /// Error while opening or creating file at `path`.
+    #[error("{}: {source}", path.display())]
+    AtPath { path: PathBuf, source: Box<GbamError> },
+    /// Filter expression is malformed at byte `offset`, where `token`
+    /// starts, see `filters::Filter::parse()`. Token is empty at the end.
+    #[error("{}", filter_syntax(offset, token, message))]
+    FilterSyntax { offset: usize, token: String, message: String },
+    /// Line `line` (1-based) of SAM or FASTQ input is malformed, see
+    /// `text_import`.
+    #[error("Line {line}: {message}")]
+    TextInput { line: u64, message: String },
+}
+
+#[derive(Debug, Clone, PartialEq, Error)]
+#[non_exhaustive]
+pub enum MetaError {
+    /// File has a major format version other than the one this build
+    /// reads, see `GBAM_VERSION`.
+    #[error(
+        "GBAM format version {}.{} is not supported, this build reads version {}.x",
+        found[0], found[1], supported[0]
+    )]
+    UnsupportedVersion { found: [u32; 2], supported: [u32; 2] },
+    /// Block `block` of `field` takes bytes `start..end`, not within data
+    /// `data_start..data_end` between file info (and space reserved for meta)
+    /// and meta.
+    #[error(
+        "Block {} of field {} at {}..{} is outside of data at {}..{}",
+        block, field, start, end, data_start, data_end
+    )]
+    BlockOutsideData { field: Fields, block: u64, start: u64, end: u64, data_start: u64, data_end: u64 },
+    /// Two blocks take some of the same bytes.
+    #[error("Block {block} of field {field} overlaps block {other_block} of field {other_field}")]
+    OverlappingBlocks { field: Fields, block: u64, other_field: Fields, other_block: u64 },
+    /// Blocks of `field` hold `items` items, other than `records` of RefID.
+    #[error("Field {field} has {items} records, but RefID has {records}, columns are misaligned")]
+    MisalignedFields { field: Fields, items: u64, records: u64 },
+    /// Field `field` has blocks, but `index`, the index column it's read
+    /// with, has none.
+    #[error("Field {field} can't be read, the file has no blocks of its index field {index}")]
+    MissingIndex { field: Fields, index: Fields },
+    /// Block `block` of `field` claims `size` decompressed bytes, more than
+    /// `limit` its codec yields from its size, see
+    /// `BlockMeta::max_uncompressed_size()`.
+    #[error(
+        "Block {} of field {} claims {} decompressed bytes, more than {} its codec yields",
+        block, field, size, limit
+    )]
+    OversizedBlock { field: Fields, block: u64, size: u64, limit: u64 },
+}
+
+#[derive(Debug, Clone, PartialEq, Error)]
+#[non_exhaustive]
+pub enum CodecError {
+    /// Field is compressed with a codec which was compiled out.
+    #[error(
+        "Codec {codec:?} of field {field} is not compiled in, rebuild gbam_tools with cargo feature \"{}\"",
+        codec.cargo_feature().unwrap_or("default")
+    )]
+    Unavailable { codec: Codecs, field: Fields },
+    /// Codec failed or panicked on block number `block` of `field` in a
+    /// compression thread.
+    #[error("Compression of block {block} of field {field} failed: {message}")]
+    CompressionFailed { field: Fields, block: u64, message: String },
+    /// Block `block` of `field` doesn't decompress, or not into as many
+    /// bytes as meta records.
+    #[error("Block {block} of field {field} can't be decompressed: {message}")]
+    DecompressionFailed { field: Fields, block: u64, message: String },
+}
+
+#[derive(Debug, Clone, PartialEq, Error)]
+#[non_exhaustive]
+pub enum TemplateError {
+    /// Index field `field` was selected in a parsing template on its own,
+    /// see `ParsingTemplate`.
+    #[error("Index field {field} is read along with its data field, it can't be selected on its own")]
+    IndexField { field: Fields },
+    /// Field `field` was requested from a reader whose columns were opened
+    /// without it, see `Reader::fetch_only()`.
+    #[error("Field {field} is not in parsing template the reader was opened with")]
+    FieldNotOpened { field: Fields },
+    /// Record `rec_num` was requested from a file of `records` records.
+    #[error("Record {rec_num} is out of the file with {records} records")]
+    RecordOutOfRange { rec_num: u64, records: u64 },
+}
+
+#[derive(Debug, Clone, PartialEq, Error)]
+#[non_exhaustive]
+pub enum CorruptionError {
+    /// Block `block` of `field` can't be read or decoded, or doesn't hold the
+    /// item read from it.
+    #[error("Block {block} of field {field} can't be read: {message}")]
+    UnreadableBlock { field: Fields, block: u64, message: String },
+    /// Digest of data region differs from the one recorded by the writer,
+    /// see `provenance`.
+    #[error(
+        "Digest {:016x} of data doesn't match {:016x} recorded by the writer, data is damaged",
+        actual, expected
+    )]
+    DigestMismatch { expected: u64, actual: u64 },
+}
+
+fn filter_syntax(offset: &usize, token: &str, message: &str) -> String {
+    if token.is_empty() {
+        format!("{} at offset {}, the end of filter expression", message, offset)
+    } else {
+        format!("{} at offset {} of filter expression: `{}`", message, offset, token)
+    }
+}
+
+impl GbamError {
+    /// Kind of `io::Error` the error converts into.
+    pub fn kind(&self) -> io::ErrorKind {
+        match self {
+            GbamError::Io(err) => err.kind(),
+            GbamError::Meta(MetaError::UnsupportedVersion { .. }) => io::ErrorKind::Unsupported,
+            GbamError::Codec(CodecError::Unavailable { .. }) => io::ErrorKind::Unsupported,
+            GbamError::Codec(CodecError::CompressionFailed { .. }) => io::ErrorKind::Other,
+            GbamError::Meta(_)
+            | GbamError::Codec(CodecError::DecompressionFailed { .. })
+            | GbamError::Corruption(_)
+            | GbamError::TextInput { .. } => io::ErrorKind::InvalidData,
+            GbamError::Template(_) | GbamError::FilterSyntax { .. } => io::ErrorKind::InvalidInput,
+            GbamError::AtPath { source, .. } => source.kind(),
+        }
+    }
+}
+
+/// Errors carried by `err` are taken out, others are `GbamError::Io`.
+impl From<io::Error> for GbamError {
+    fn from(err: io::Error) -> Self {
+        if gbam_error(&err).is_none() {
+            return GbamError::Io(err);
+        }
+        let kind = err.kind();
+        match err.into_inner().map(|inner| inner.downcast::<GbamError>()) {
+            Some(Ok(carried)) => *carried,
+            // Checked above.
+            Some(Err(inner)) => GbamError::Io(io::Error::new(kind, inner)),
+            None => GbamError::Io(kind.into()),
+        }
+    }
+}
+
+/// `GbamError::Io` is given back as is, others are carried with kind of
+/// `GbamError::kind()`.
+impl From<GbamError> for io::Error {
+    fn from(err: GbamError) -> Self {
+        match err {
+            GbamError::Io(err) => err,
+            err => io::Error::new(err.kind(), err),
+        }
+    }
+}
+
+macro_rules! into_io_error {
+    ($($error:ty),*) => {
+        $(impl From<$error> for io::Error {
+            fn from(err: $error) -> Self {
+                GbamError::from(err).into()
+            }
+        })*
+    };
+}
+
+into_io_error!(MetaError, CodecError, TemplateError, CorruptionError);
+
+/// `err` with `path` embedded, kind is kept.
+pub(crate) fn with_path(err: impl Into<GbamError>, path: &Path) -> GbamError {
+    GbamError::AtPath {
+        path: path.to_owned(),
+        source: Box::new(err.into()),
+    }
+}
+
+/// GBAM error carried by `err`, if any.
+pub fn gbam_error(err: &io::Error) -> Option<&GbamError> {
+    err.get_ref()?.downcast_ref()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::meta::{FileInfo, FileMeta};
+    use crate::reader::{parse_tmplt::ParsingTemplate, reader::Reader};
+    use crate::test_utils::{
+        new_test_writer, open_test_file, sam_header_bytes, test_ref_seqs, write_test_file, TestRecord,
+    };
+    use crate::writer::{write_meta_and_file_info, Writer, WriterSettings};
+    use bam_tools::record::fields::FIELDS_NUM;
+    use std::fs::{File, OpenOptions};
+    use std::io::{Cursor, Seek, SeekFrom};
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_reader_codec_check() {
+        let dir = TempDir::new("gbam_error").unwrap();
+        let path = dir.path().join("brotli_qual.gbam");
+        write_test_file(&path, "", &[TestRecord::default()]);
+        // Meta claims Brotli for an Lz4 column, enough to test opening.
+        let mut meta = (*open_test_file(&path).file_meta).clone();
+        meta.set_field_codec(&Fields::RawQual, Codecs::Brotli);
+        let mut file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.seek(SeekFrom::End(0)).unwrap();
+        let mut file_info = FileInfo::new([1, 0], 0, 0, String::from("test"), false);
+        write_meta_and_file_info(&mut file, &mut meta, &mut file_info).unwrap();
+
+        let mut tmplt = ParsingTemplate::new();
+        tmplt.set_all();
+        let result = Reader::new(File::open(&path).unwrap(), tmplt);
+        #[cfg(feature = "brotli")]
+        assert!(result.is_ok());
+        #[cfg(not(feature = "brotli"))]
+        {
+            let err = result.err().unwrap();
+            assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+            assert!(matches!(
+                err,
+                GbamError::Codec(CodecError::Unavailable {
+                    codec: Codecs::Brotli,
+                    field: Fields::RawQual,
+                })
+            ));
+            assert!(err.to_string().contains("cargo feature \"brotli\""));
+        }
+    }
+
+    #[test]
+    fn test_path_errors() {
+        let dir = TempDir::new("gbam_error").unwrap();
+        let missing = dir.path().join("missing.gbam");
+        let err = Reader::from_path(&missing, ParsingTemplate::new()).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert!(matches!(&err, GbamError::AtPath { path, .. } if *path == missing));
+        assert!(err.to_string().starts_with(&missing.display().to_string()));
+
+        let nested = dir.path().join("a/b/out.gbam");
+        let err = Writer::create(&nested, WriterSettings::default()).err().unwrap();
+        assert!(err.to_string().contains("a/b/out.gbam"));
+        let settings = WriterSettings {
+            create_dirs: true,
+            ..Default::default()
+        };
+        Writer::create(&nested, settings).unwrap().finish_with_summary(false).unwrap();
+        assert_eq!(open_test_file(&nested).num_records(), 0);
+
+        #[cfg(not(feature = "xz"))]
+        {
+            let path = dir.path().join("xz.gbam");
+            let settings = WriterSettings {
+                codecs: vec![Codecs::Xz; FIELDS_NUM],
+                ..Default::default()
+            };
+            let err = Writer::create(&path, settings).err().unwrap();
+            assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+            assert!(matches!(gbam_error(&err), Some(GbamError::AtPath { path: at, .. }) if *at == path));
+            assert!(err.to_string().contains("cargo feature \"xz\""), "{}", err);
+        }
+    }
+
+    #[test]
+    fn test_block_checks() {
+        let dir = TempDir::new("gbam_error").unwrap();
+        let path = dir.path().join("blocks.gbam");
+        let mut writer = new_test_writer(&path, "");
+        writer.set_rows_per_block(100);
+        for i in 0..1000 {
+            writer.push_record(&TestRecord::new(0, i, "r").to_raw(), false).unwrap();
+        }
+        writer.finish_with_summary(false).unwrap();
+        let meta = (*open_test_file(&path).file_meta).clone();
+        // Hand-crafted meta is appended, blocks stay where they are.
+        let open_with = |mut meta: FileMeta| {
+            let mut file = OpenOptions::new().write(true).open(&path).unwrap();
+            file.seek(SeekFrom::End(0)).unwrap();
+            let mut file_info = FileInfo::new([1, 0], 0, 0, String::from("test"), true);
+            write_meta_and_file_info(&mut file, &mut meta, &mut file_info).unwrap();
+            let err = Reader::new(File::open(&path).unwrap(), ParsingTemplate::new()).err().unwrap();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            match err {
+                GbamError::Meta(err) => err,
+                err => panic!("{}", err),
+            }
+        };
+
+        let mut overlapping = meta.clone();
+        let pos_blocks = overlapping.get_blocks(&Fields::Pos);
+        pos_blocks[1].seekpos = pos_blocks[0].seekpos + 1;
+        assert_eq!(
+            open_with(overlapping),
+            MetaError::OverlappingBlocks {
+                field: Fields::Pos,
+                block: 1,
+                other_field: Fields::Pos,
+                other_block: 0,
+            }
+        );
+
+        let mut outside = meta.clone();
+        outside.get_blocks(&Fields::Mapq)[2].seekpos = 10;
+        let err = open_with(outside);
+        assert!(
+            matches!(err, MetaError::BlockOutsideData { field: Fields::Mapq, block: 2, start: 10, .. }),
+            "{}",
+            err
+        );
+
+        // Totals are recorded anew, so only the other fields disagree.
+        let mut short = meta;
+        short.get_blocks(&Fields::Mapq).truncate(9);
+        assert_eq!(
+            open_with(short),
+            MetaError::MisalignedFields {
+                field: Fields::Mapq,
+                items: 900,
+                records: 1000,
+            }
+        );
+    }
+
+    #[test]
+    fn test_writer_codec_check() {
+        let ref_seqs = test_ref_seqs();
+        let result = Writer::new(
+            Cursor::new(Vec::new()),
+            vec![Codecs::Zstd; FIELDS_NUM],
+            1,
+            Vec::new(),
+            ref_seqs.clone(),
+            sam_header_bytes("", &ref_seqs),
+            String::from("test"),
+            false,
+            false,
+        );
+        #[cfg(feature = "zstd")]
+        result.unwrap().finish_with_summary(false).unwrap();
+        #[cfg(not(feature = "zstd"))]
+        {
+            let err = result.err().unwrap();
+            assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+            assert!(matches!(
+                gbam_error(&err),
+                Some(GbamError::Codec(CodecError::Unavailable { codec: Codecs::Zstd, .. }))
+            ));
+            assert!(err.to_string().contains("cargo feature \"zstd\""));
+        }
+    }
+}
@@ -0,0 +1,422 @@
+//! Extraction of records overlapping regions of a coordinate sorted file
+//! into a new GBAM file.
+use std::borrow::Cow;
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::path::Path;
+
+use bam_tools::record::bamrawrecord::BAMRawRecord;
+use bam_tools::record::fields::Fields;
+use byteorder::{LittleEndian, WriteBytesExt};
+
+use crate::analytics::{InsertSizeHistogram, InsertSizeStats};
+use crate::filters::Filter;
+use crate::meta::sam_header_text;
+use crate::reader::reader::Reader;
+use crate::reader::record::GbamRecord;
+use crate::reader::region::{Region, REGION_FIELDS};
+use crate::writer::{Writer, WriterSettings};
+
+/// ID of @PG lines added by `extract_region()`, suffixed with a number if
+/// the header has one already.
+pub const EXTRACT_PG_ID: &str = "gbam_extract";
+
+/// Options of `extract_region_with_options()`.
+#[derive(Clone, Debug, Default)]
+pub struct ExtractOptions {
+    /// Also write records sharing a read name with paired records in the
+    /// regions, wherever they are mapped. Found by a second pass over
+    /// ReadName blocks, skipping ones whose bloom filter rules the names out.
+    pub include_mates: bool,
+    /// Write only records in the regions which pass the filter. Mates of
+    /// passing records are written whether they pass or not.
+    pub filter: Option<Filter>,
+}
+
+/// Writes records overlapping `regions` into a new file at `out`, see
+/// `extract_region_with_options()`.
+pub fn extract_region(
+    reader: &mut Reader,
+    regions: &[(String, i32, i32)],
+    out: &Path,
+    settings: WriterSettings,
+) -> io::Result<u64> {
+    extract_region_with_options(reader, regions, out, settings, &ExtractOptions::default())
+}
+
+/// Writes records overlapping `regions`, given as (reference name, start,
+/// end), 0-based and half-open like `Region`, into a new file at `out`.
+/// Returns the number of records written. Overlapping regions are merged and
+/// each record is written once, in file order, so the output stays
+/// coordinate sorted. Only blocks planned by `Reader::plan_fetch()` are
+/// decoded. Mates outside the regions are not written, unless
+/// `options.include_mates` is set. Records failing `options.filter` are not
+/// written.
+///
+/// The file has to be coordinate sorted, with every field enabled in parsing
+/// template. References and header of the file are kept, with a @PG line
+/// describing the extraction added. `settings` give codecs and threads of
+/// the output. Stats of the output are collected for the subset like in the
+/// file: block stats of fields which have them, write time stats collectors,
+/// linear index, insert size histogram and source index, when present. On
+/// errors output is left unfinished.
+pub fn extract_region_with_options(
+    reader: &mut Reader,
+    regions: &[(String, i32, i32)],
+    out: &Path,
+    settings: WriterSettings,
+    options: &ExtractOptions,
+) -> io::Result<u64> {
+    let all_fields: Vec<Fields> = Fields::iterator().copied().collect();
+    if !reader.parsing_template.check_if_active(&all_fields) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "All fields have to be enabled in parsing template to extract regions.",
+        ));
+    }
+    let merged = merge_regions(&resolve_regions(reader, regions)?);
+
+    let mut scan_fields = REGION_FIELDS.to_vec();
+    scan_fields.extend_from_slice(&[Fields::Flags, Fields::ReadName]);
+    if let Some(filter) = &options.filter {
+        scan_fields.extend(filter.fields());
+    }
+    reader.fetch_only(&scan_fields)?;
+    let selected = select_records(reader, &merged, options);
+    reader.restore_template();
+    let selected = selected?;
+
+    let mut writer = output_writer(reader, regions, out, settings)?;
+    let mut rec = GbamRecord::default();
+    let mut bytes = Vec::new();
+    for &rec_num in &selected {
+        reader.fill_record(rec_num, &mut rec)?;
+        rec.convert_to_bytes(&mut bytes);
+        // Without block_size.
+        let raw = BAMRawRecord(Cow::Borrowed(&bytes[4..]));
+        writer.push_record_with_source_index(&raw, rec.source_index(), false)?;
+    }
+    writer.finish_with_summary(false)?;
+    Ok(selected.len() as u64)
+}
+
+/// Regions by reference id. Empty regions are dropped.
+fn resolve_regions(reader: &Reader, regions: &[(String, i32, i32)]) -> io::Result<Vec<Region>> {
+    let ref_seqs = reader.file_meta.get_ref_seqs();
+    let mut resolved = Vec::with_capacity(regions.len());
+    for (name, start, end) in regions {
+        let ref_id = ref_seqs.iter().position(|(ref_name, _)| ref_name == name).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Reference {} of region {}:{}-{} is not in the file", name, name, start, end),
+            )
+        })?;
+        if *start < 0 || end < start {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Region {}:{}-{} has negative start or ends before it", name, start, end),
+            ));
+        }
+        if end > start {
+            resolved.push(Region::new(ref_id as i32, *start, *end));
+        }
+    }
+    Ok(resolved)
+}
+
+/// Sorted regions, with overlapping and adjacent ones merged.
+fn merge_regions(regions: &[Region]) -> Vec<Region> {
+    let mut sorted = regions.to_vec();
+    sorted.sort_by_key(|region| (region.ref_id, region.start));
+    let mut merged: Vec<Region> = Vec::with_capacity(sorted.len());
+    for region in sorted {
+        match merged.last_mut() {
+            Some(last) if last.ref_id == region.ref_id && region.start <= last.end => {
+                last.end = last.end.max(region.end);
+            }
+            _ => merged.push(region),
+        }
+    }
+    merged
+}
+
+/// Numbers of records overlapping `regions`, and of their mates if asked
+/// for. Records spanning several regions are found once per region.
+fn select_records(
+    reader: &mut Reader,
+    regions: &[Region],
+    options: &ExtractOptions,
+) -> io::Result<BTreeSet<usize>> {
+    let mut selected = BTreeSet::new();
+    let mut mate_names = BTreeSet::new();
+    let meta = reader.file_meta.clone();
+    for region in regions {
+        let mut records = reader.fetch(region)?;
+        while let Some(rec) = records.next_rec()? {
+            if let Some(filter) = &options.filter {
+                if !filter.matches(rec, meta.get_ref_seqs())? {
+                    continue;
+                }
+            }
+            let paired = rec.flag.unwrap() & 0x1 != 0;
+            if options.include_mates && paired {
+                mate_names.insert(rec.read_name.clone().unwrap());
+            }
+            selected.insert(records.rec_num());
+        }
+    }
+    for name in mate_names {
+        selected.extend(reader.find_by_name(&name)?);
+    }
+    Ok(selected)
+}
+
+fn output_writer(
+    reader: &Reader,
+    regions: &[(String, i32, i32)],
+    out: &Path,
+    settings: WriterSettings,
+) -> io::Result<Writer<BufWriter<File>>> {
+    let meta = reader.file_meta.clone();
+    let mut collect_stats_for = settings.collect_stats_for.clone();
+    for field in Fields::iterator() {
+        let has_stats = meta.view_blocks(field).iter().any(|block| block.stats.is_some());
+        if has_stats && !collect_stats_for.contains(field) {
+            collect_stats_for.push(*field);
+        }
+    }
+    let command = if settings.full_command.is_empty() {
+        let regions: Vec<String> = regions
+            .iter()
+            .map(|(name, start, end)| format!("{}:{}-{}", name, start + 1, end))
+            .collect();
+        format!("extract_region {}", regions.join(" "))
+    } else {
+        settings.full_command.clone()
+    };
+    let settings = WriterSettings {
+        collect_stats_for,
+        ref_seqs: meta.get_ref_seqs().clone(),
+        sam_header: with_pg_line(meta.get_sam_header(), &command),
+        full_command: command,
+        is_sorted: true,
+        ..settings
+    };
+    let mut writer = Writer::create(out, settings)?;
+
+    let blocks = meta.view_blocks(&Fields::RefID);
+    if blocks.iter().any(|block| !block.extra_stats.is_empty()) {
+        writer.add_default_stats_collectors();
+    }
+    writer.set_linear_index(meta.get_linear_index().is_some());
+    writer.set_source_index(meta.has_source_index());
+    if let Some(summary) = meta.get_analytics(InsertSizeHistogram::NAME) {
+        let stats: InsertSizeStats = serde_json::from_value(summary.clone())
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        writer.register_collector(
+            Fields::TemplateLength,
+            Box::new(InsertSizeHistogram::new(stats.max_insert_size)),
+        );
+    }
+    Ok(writer)
+}
+
+/// Header bytes (`l_text`, text, `n_ref` and references, as in BAM) with a
+/// @PG line of `command` appended to the text. The line follows the last
+/// @PG line of the header in its PP tag.
+fn with_pg_line(sam_header: &[u8], command: &str) -> Vec<u8> {
+    let text = sam_header_text(sam_header);
+    let pg_ids: Vec<&[u8]> = text
+        .split(|&c| c == b'\n')
+        .filter_map(|line| line.strip_prefix(b"@PG\t"))
+        .filter_map(|pg| {
+            pg.split(|&c| c == b'\t' || c == b'\r')
+                .find_map(|tag| tag.strip_prefix(b"ID:"))
+        })
+        .collect();
+    let mut id = String::from(EXTRACT_PG_ID);
+    let mut suffix = 0;
+    while pg_ids.contains(&id.as_bytes()) {
+        suffix += 1;
+        id = format!("{}.{}", EXTRACT_PG_ID, suffix);
+    }
+    let mut line = format!("@PG\tID:{}\tPN:gbam", id);
+    if let Some(prev) = pg_ids.last() {
+        line.push_str("\tPP:");
+        line.push_str(&String::from_utf8_lossy(prev));
+    }
+    // Tabs and newlines would break the line.
+    let command: String = command.chars().map(|c| if c.is_control() { ' ' } else { c }).collect();
+    line.push_str(&format!("\tCL:{}\n", command));
+
+    // Text may be padded with NULs.
+    let text_len = text.iter().rposition(|&c| c != 0).map_or(0, |last| last + 1);
+    let mut new_text = text[..text_len].to_vec();
+    if !new_text.is_empty() && !new_text.ends_with(b"\n") {
+        new_text.push(b'\n');
+    }
+    new_text.extend_from_slice(line.as_bytes());
+    let mut bytes = Vec::new();
+    bytes.write_u32::<LittleEndian>(new_text.len() as u32).unwrap();
+    bytes.extend_from_slice(&new_text);
+    // References follow the text.
+    let refs_start = (std::mem::size_of::<u32>() + text.len()).min(sam_header.len());
+    bytes.extend_from_slice(&sam_header[refs_start..]);
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{new_test_writer, open_test_file, TestRecord};
+    use tempdir::TempDir;
+
+    const HEADER: &str = "@HD\tVN:1.6\tSO:coordinate\n@PG\tID:test\tPN:test\n";
+
+    /// Paired records on three references, with mates 5000 bases apart.
+    fn records() -> Vec<TestRecord> {
+        (0..3_000)
+            .map(|i| {
+                let (ref_id, n) = (i / 1_000, i % 1_000);
+                let mut rec = TestRecord::new(ref_id, n * 10, &format!("p{}_{}", ref_id, n % 500));
+                rec.flag = 0x1;
+                rec.cigar = vec![(5 + n as u32 % 50) << 4];
+                rec.tlen = n % 300;
+                rec
+            })
+            .collect()
+    }
+
+    fn write_input(path: &Path) {
+        let mut writer = new_test_writer(path, HEADER);
+        writer.set_rows_per_block(100);
+        writer.add_default_stats_collectors();
+        writer.set_linear_index(true);
+        writer.register_collector(Fields::TemplateLength, Box::new(InsertSizeHistogram::new(1000)));
+        for rec in records() {
+            writer.push_record(&rec.to_raw(), false).unwrap();
+        }
+        writer.finish_with_summary(false).unwrap();
+    }
+
+    fn read_name(rec: &TestRecord) -> Vec<u8> {
+        format!("{}\0", rec.name).into_bytes()
+    }
+
+    fn names_of(path: &Path) -> Vec<Vec<u8>> {
+        let mut reader = open_test_file(path);
+        let mut names = Vec::new();
+        let mut records = reader.records();
+        while let Some(rec) = records.next_rec().unwrap() {
+            names.push(rec.read_name.clone().unwrap());
+        }
+        names
+    }
+
+    #[test]
+    fn test_extract_region() {
+        let dir = TempDir::new("gbam_extract").unwrap();
+        let input = dir.path().join("input.gbam");
+        write_input(&input);
+        let regions = vec![
+            (String::from("chr2"), 2_000, 3_000),
+            (String::from("chr1"), 100, 500),
+            (String::from("chr1"), 400, 800),
+            (String::from("chr1"), 100, 500),
+        ];
+        let merged = [Region::new(0, 100, 800), Region::new(1, 2_000, 3_000)];
+
+        // Brute force over all records.
+        let mut expected = Vec::new();
+        let mut with_mates = BTreeSet::new();
+        for rec in records() {
+            let ref_len = rec.cigar[0] >> 4;
+            if merged.iter().any(|region| region.overlaps(rec.refid, rec.pos, ref_len)) {
+                with_mates.insert(rec.name.clone());
+                expected.push(rec);
+            }
+        }
+        let inserts = expected.iter().filter(|rec| rec.tlen > 0).count() as u64;
+        let expected: Vec<Vec<u8>> = expected.into_iter().map(|rec| read_name(&rec)).collect();
+        let expected_with_mates: Vec<Vec<u8>> = records()
+            .into_iter()
+            .filter(|rec| with_mates.contains(&rec.name))
+            .map(|rec| read_name(&rec))
+            .collect();
+        assert!(expected_with_mates.len() > expected.len());
+
+        let out = dir.path().join("extracted.gbam");
+        let mut reader = open_test_file(&input);
+        let written = extract_region(&mut reader, &regions, &out, WriterSettings::default()).unwrap();
+        assert_eq!(written, expected.len() as u64);
+        // Of 30 blocks.
+        assert!(reader.blocks_decompressed(&Fields::Pos) < 15);
+        assert_eq!(names_of(&out), expected);
+
+        let extracted = open_test_file(&out);
+        let text = crate::sam_export::sam_header(&extracted.file_meta);
+        assert!(
+            text.contains("@PG\tID:gbam_extract\tPN:gbam\tPP:test\tCL:extract_region chr2:2001-3000 "),
+            "{}",
+            text
+        );
+        assert_eq!(extracted.file_meta.get_ref_seqs(), reader.file_meta.get_ref_seqs());
+        assert!(extracted.file_meta.get_linear_index().is_some());
+        let stats: InsertSizeStats =
+            serde_json::from_value(extracted.analytics(InsertSizeHistogram::NAME).unwrap().clone()).unwrap();
+        assert_eq!(stats.count, inserts);
+
+        let out = dir.path().join("with_mates.gbam");
+        let options = ExtractOptions {
+            include_mates: true,
+            ..Default::default()
+        };
+        extract_region_with_options(&mut reader, &regions, &out, WriterSettings::default(), &options).unwrap();
+        assert_eq!(names_of(&out), expected_with_mates);
+
+        // Extracting again adds another @PG line.
+        let mut extracted = open_test_file(&out);
+        let again = dir.path().join("again.gbam");
+        extract_region(&mut extracted, &regions[..1], &again, WriterSettings::default()).unwrap();
+        let text = crate::sam_export::sam_header(&open_test_file(&again).file_meta);
+        assert!(text.contains("@PG\tID:gbam_extract.1\tPN:gbam\tPP:gbam_extract\t"), "{}", text);
+    }
+
+    #[test]
+    fn test_extract_filtered() {
+        let dir = TempDir::new("gbam_extract").unwrap();
+        let input = dir.path().join("input.gbam");
+        write_input(&input);
+        let regions = vec![(String::from("chr1"), 0, 5_000), (String::from("chr2"), 0, 5_000)];
+        let expected: Vec<Vec<u8>> = records()
+            .iter()
+            .filter(|rec| rec.refid < 2 && rec.pos < 5_000 && rec.tlen >= 250 && rec.name.ends_with('7'))
+            .map(read_name)
+            .collect();
+        assert!(!expected.is_empty());
+
+        let out = dir.path().join("filtered.gbam");
+        let mut reader = open_test_file(&input);
+        let options = ExtractOptions {
+            filter: Some(Filter::parse("tlen >= 250 && qname =~ '7$'").unwrap()),
+            ..Default::default()
+        };
+        let settings = WriterSettings::default();
+        let written = extract_region_with_options(&mut reader, &regions, &out, settings, &options).unwrap();
+        assert_eq!(written, expected.len() as u64);
+        assert_eq!(names_of(&out), expected);
+    }
+
+    #[test]
+    fn test_extract_unknown_reference() {
+        let dir = TempDir::new("gbam_extract").unwrap();
+        let input = dir.path().join("input.gbam");
+        write_input(&input);
+        let mut reader = open_test_file(&input);
+        let regions = [(String::from("chrX"), 0, 100)];
+        let out = dir.path().join("out.gbam");
+        let err = extract_region(&mut reader, &regions, &out, WriterSettings::default()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,411 @@
+//! FASTQ output of unaligned reads, formatted the way `samtools fastq` does.
+//! Only ReadName, Flags, RawSequence and RawQual columns are decoded.
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, Write};
+
+use bam_tools::record::fields::Fields;
+
+use crate::reader::reader::Reader;
+use crate::reader::record::GbamRecord;
+
+const BAM_FPAIRED: u16 = 0x1;
+const BAM_FREVERSE: u16 = 0x10;
+const BAM_FREAD1: u16 = 0x40;
+const BAM_FREAD2: u16 = 0x80;
+const BAM_FSECONDARY: u16 = 0x100;
+const BAM_FSUPPLEMENTARY: u16 = 0x800;
+
+/// Fields needed for FASTQ output.
+pub const FASTQ_FIELDS: [Fields; 4] = [
+    Fields::ReadName,
+    Fields::Flags,
+    Fields::RawSequence,
+    Fields::RawQual,
+];
+
+/// When "/1" and "/2" are appended to names of READ1 and READ2 reads.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReadSuffix {
+    /// Only in interleaved output, like `samtools fastq` by default.
+    Interleaved,
+    /// Like `samtools fastq -N`.
+    Always,
+    /// Like `samtools fastq -n`.
+    Never,
+}
+
+#[derive(Clone, Debug)]
+pub struct FastqOptions {
+    pub read_suffix: ReadSuffix,
+    /// Quality of bases of reads stored without qualities, like `samtools fastq -v`.
+    pub default_qual: u8,
+    /// Reads waiting for their mates are held in memory. For files not
+    /// grouped by name export fails when there are more of them.
+    pub max_pending: usize,
+}
+
+impl Default for FastqOptions {
+    fn default() -> Self {
+        Self {
+            read_suffix: ReadSuffix::Interleaved,
+            default_qual: 1,
+            max_pending: 1_000_000,
+        }
+    }
+}
+
+/// Counts of written and skipped reads.
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub struct FastqReport {
+    pub pairs: u64,
+    /// Unpaired reads and reads whose mate is not in the file.
+    pub singletons: u64,
+    pub secondary_or_supplementary: u64,
+    /// Reads with '*' sequence, which were not written.
+    pub missing_sequence: u64,
+}
+
+impl fmt::Display for FastqReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} pairs and {} singletons written, {} secondary or supplementary and {} reads without sequence skipped",
+            self.pairs, self.singletons, self.secondary_or_supplementary, self.missing_sequence
+        )
+    }
+}
+
+/// Writes primary reads of file opened by `reader` as FASTQ: READ1 and READ2
+/// mates into `out_r1` and `out_r2`, in the same order, and reads without a
+/// mate into `out_singletons`. Reverse strand reads are reverse complemented.
+/// Mates are paired by name. Name sorted or collated files are streamed,
+/// other ones need memory for reads whose mate wasn't reached yet, see
+/// `FastqOptions::max_pending`. FASTQ_FIELDS have to be enabled in parsing
+/// template.
+pub fn fastq_export<W: Write>(
+    reader: &mut Reader,
+    out_r1: W,
+    out_r2: W,
+    out_singletons: W,
+    options: &FastqOptions,
+) -> io::Result<FastqReport> {
+    export(reader, &mut Sink::Split([out_r1, out_r2, out_singletons]), options)
+}
+
+/// Same as `fastq_export()`, with mates and singletons in one output. Mates
+/// are written next to each other, READ1 first.
+pub fn fastq_export_interleaved<W: Write>(
+    reader: &mut Reader,
+    out: W,
+    options: &FastqOptions,
+) -> io::Result<FastqReport> {
+    export(reader, &mut Sink::Interleaved(out), options)
+}
+
+enum Sink<W> {
+    // READ1, READ2 and singletons.
+    Split([W; 3]),
+    Interleaved(W),
+}
+
+struct FastqRead {
+    rec_num: usize,
+    name: Vec<u8>,
+    // 0 for unpaired reads, 1 or 2 otherwise.
+    read_num: u8,
+    seq: Vec<u8>,
+    qual: Vec<u8>,
+}
+
+impl<W: Write> Sink<W> {
+    fn write(&mut self, read: &FastqRead, out_num: usize, options: &FastqOptions) -> io::Result<()> {
+        let (out, interleaved) = match self {
+            Sink::Split(outs) => (&mut outs[out_num], false),
+            Sink::Interleaved(out) => (out, true),
+        };
+        let suffix = match options.read_suffix {
+            ReadSuffix::Interleaved => interleaved,
+            ReadSuffix::Always => true,
+            ReadSuffix::Never => false,
+        };
+        out.write_all(b"@")?;
+        out.write_all(&read.name)?;
+        if suffix && read.read_num != 0 {
+            write!(out, "/{}", read.read_num)?;
+        }
+        out.write_all(b"\n")?;
+        out.write_all(&read.seq)?;
+        out.write_all(b"\n+\n")?;
+        out.write_all(&read.qual)?;
+        out.write_all(b"\n")
+    }
+
+    fn write_pair(
+        &mut self,
+        first: &FastqRead,
+        second: &FastqRead,
+        options: &FastqOptions,
+    ) -> io::Result<()> {
+        let (r1, r2) = if first.read_num == 1 { (first, second) } else { (second, first) };
+        self.write(r1, 0, options)?;
+        self.write(r2, 1, options)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Sink::Split(outs) => outs.iter_mut().try_for_each(|out| out.flush()),
+            Sink::Interleaved(out) => out.flush(),
+        }
+    }
+}
+
+fn export<W: Write>(
+    reader: &mut Reader,
+    sink: &mut Sink<W>,
+    options: &FastqOptions,
+) -> io::Result<FastqReport> {
+    if !reader.parsing_template.check_if_active(&FASTQ_FIELDS) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "ReadName, Flags, RawSequence and RawQual fields have to be enabled in parsing template to export FASTQ.",
+        ));
+    }
+    let name_grouped = reader.file_meta.get_sort_order().is_name_grouped();
+    reader.fetch_only(&FASTQ_FIELDS)?;
+    let res = write_reads(reader, sink, options, name_grouped);
+    reader.restore_template();
+    res
+}
+
+fn write_reads<W: Write>(
+    reader: &mut Reader,
+    sink: &mut Sink<W>,
+    options: &FastqOptions,
+    name_grouped: bool,
+) -> io::Result<FastqReport> {
+    let mut report = FastqReport::default();
+    // Reads waiting for their mate, by name.
+    let mut pending: HashMap<Vec<u8>, FastqRead> = HashMap::new();
+    let flush_pending = |pending: &mut HashMap<Vec<u8>, FastqRead>,
+                             sink: &mut Sink<W>,
+                             report: &mut FastqReport|
+     -> io::Result<()> {
+        let mut reads: Vec<FastqRead> = pending.drain().map(|(_, read)| read).collect();
+        reads.sort_unstable_by_key(|read| read.rec_num);
+        report.singletons += reads.len() as u64;
+        reads.iter().try_for_each(|read| sink.write(read, 2, options))
+    };
+
+    let mut records = reader.records();
+    let mut rec_num = 0;
+    while let Some(rec) = records.next_rec()? {
+        rec_num += 1;
+        let flag = rec.flag.unwrap();
+        if flag & (BAM_FSECONDARY | BAM_FSUPPLEMENTARY) != 0 {
+            report.secondary_or_supplementary += 1;
+            continue;
+        }
+        let read = match to_fastq_read(rec_num - 1, rec, options) {
+            Some(read) => read,
+            None => {
+                report.missing_sequence += 1;
+                continue;
+            }
+        };
+        if read.read_num == 0 {
+            report.singletons += 1;
+            sink.write(&read, 2, options)?;
+            continue;
+        }
+        // Mates of earlier names won't come anymore.
+        if name_grouped && !pending.is_empty() && !pending.contains_key(&read.name) {
+            flush_pending(&mut pending, sink, &mut report)?;
+        }
+        match pending.remove(&read.name) {
+            Some(mate) if mate.read_num != read.read_num => {
+                report.pairs += 1;
+                sink.write_pair(&mate, &read, options)?;
+            }
+            Some(mate) => {
+                // Two READ1 or READ2 reads of a template, the first one has no mate.
+                report.singletons += 1;
+                sink.write(&mate, 2, options)?;
+                pending.insert(read.name.clone(), read);
+            }
+            None => {
+                pending.insert(read.name.clone(), read);
+                if pending.len() > options.max_pending {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!(
+                            "More than {} reads wait for their mates. Collate the input by name first.",
+                            options.max_pending
+                        ),
+                    ));
+                }
+            }
+        }
+    }
+    flush_pending(&mut pending, sink, &mut report)?;
+    sink.flush()?;
+    Ok(report)
+}
+
+/// None for reads with '*' sequence.
+fn to_fastq_read(rec_num: usize, rec: &GbamRecord, options: &FastqOptions) -> Option<FastqRead> {
+    let qual = rec.qual.as_ref().unwrap();
+    if qual.is_empty() {
+        return None;
+    }
+    let flag = rec.flag.unwrap();
+    let name = rec.read_name.as_ref().unwrap();
+    let name = name.strip_suffix(&[0]).unwrap_or(name).to_vec();
+    let read_num = match flag & (BAM_FREAD1 | BAM_FREAD2) {
+        _ if flag & BAM_FPAIRED == 0 => 0,
+        BAM_FREAD1 => 1,
+        BAM_FREAD2 => 2,
+        _ => 0,
+    };
+    // Qualities are l_seq long, decoded sequence may lack trailing '=' or
+    // carry padding base.
+    let bases = rec.seq.as_ref().unwrap().as_bytes();
+    let mut seq = bases[..bases.len().min(qual.len())].to_vec();
+    seq.resize(qual.len(), b'=');
+    // Missing qualities are stored as 0xFF.
+    let mut qual: Vec<u8> = if qual[0] == 0xFF {
+        vec![options.default_qual + 33; qual.len()]
+    } else {
+        qual.iter().map(|q| q.wrapping_add(33)).collect()
+    };
+    if flag & BAM_FREVERSE != 0 {
+        seq.reverse();
+        seq.iter_mut().for_each(|base| *base = complement(*base));
+        qual.reverse();
+    }
+    Some(FastqRead { rec_num, name, read_num, seq, qual })
+}
+
+/// Complement of IUPAC base, as in samtools.
+fn complement(base: u8) -> u8 {
+    match base {
+        b'A' => b'T',
+        b'C' => b'G',
+        b'G' => b'C',
+        b'T' => b'A',
+        b'M' => b'K',
+        b'K' => b'M',
+        b'R' => b'Y',
+        b'Y' => b'R',
+        b'V' => b'B',
+        b'B' => b'V',
+        b'H' => b'D',
+        b'D' => b'H',
+        // S, W, N and '='.
+        other => other,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{open_test_file, write_test_file, TestRecord};
+    use tempdir::TempDir;
+
+    fn read(name: &str, flag: u16, seq: &str, qual: &[u8]) -> TestRecord {
+        let mut rec = TestRecord::new(0, 100, name);
+        rec.flag = flag;
+        rec.seq = seq.to_owned();
+        rec.qual = qual.to_vec();
+        rec.cigar = if seq.is_empty() { Vec::new() } else { vec![(seq.len() as u32) << 4] };
+        rec
+    }
+
+    fn test_records() -> Vec<TestRecord> {
+        vec![
+            read("p1", 0x1 | 0x40 | 0x20, "ACGTN", &[10, 20, 30, 40, 0]),
+            read("single", 0x10, "AACCM", &[1, 2, 3, 4, 5]),
+            read("p2", 0x1 | 0x80 | 0x10, "GGGTA", &[30, 30, 30, 30, 20]),
+            read("p1", 0x1 | 0x80 | 0x10, "TTTCA", &[5, 6, 7, 8, 9]),
+            read("p1", 0x1 | 0x40 | 0x800, "ACG", &[30, 30, 30]),
+            read("nomate", 0x1 | 0x40 | 0x8, "CAT", &[0xFF, 0xFF, 0xFF]),
+            read("star", 0x4, "", &[]),
+            read("p2", 0x1 | 0x40 | 0x20, "CCCAG", &[40, 40, 40, 40, 40]),
+            read("p1", 0x1 | 0x100 | 0x40, "ACGTN", &[10, 20, 30, 40, 0]),
+        ]
+    }
+
+    // Output of `samtools fastq -1 r1 -2 r2 -s singletons -0 singletons` on
+    // the same records, with READ1 and READ2 flags in this order.
+    const R1: &str = "@p1\nACGTN\n+\n+5?I!\n@p2\nCCCAG\n+\nIIIII\n";
+    const R2: &str = "@p1\nTGAAA\n+\n*)('&\n@p2\nTACCC\n+\n5????\n";
+    const SINGLETONS: &str = "@single\nKGGTT\n+\n&%$#\"\n@nomate\nCAT\n+\n\"\"\"\n";
+
+    #[test]
+    fn test_fastq_export() {
+        let dir = TempDir::new("gbam_fastq").unwrap();
+        let path = dir.path().join("unsorted.gbam");
+        write_test_file(&path, "@HD\tVN:1.6\tSO:unsorted\n", &test_records());
+
+        let mut reader = open_test_file(&path);
+        let (mut r1, mut r2, mut singletons) = (Vec::new(), Vec::new(), Vec::new());
+        let report =
+            fastq_export(&mut reader, &mut r1, &mut r2, &mut singletons, &FastqOptions::default())
+                .unwrap();
+        assert_eq!(String::from_utf8(r1).unwrap(), R1);
+        assert_eq!(String::from_utf8(r2).unwrap(), R2);
+        assert_eq!(String::from_utf8(singletons).unwrap(), SINGLETONS);
+        assert_eq!(
+            report,
+            FastqReport {
+                pairs: 2,
+                singletons: 2,
+                secondary_or_supplementary: 2,
+                missing_sequence: 1,
+            }
+        );
+
+        let options = FastqOptions {
+            max_pending: 1,
+            ..Default::default()
+        };
+        assert!(fastq_export_interleaved(&mut reader, Vec::new(), &options).is_err());
+        // Template is restored.
+        assert!(reader.records().next_rec().unwrap().unwrap().refid.is_some());
+    }
+
+    #[test]
+    fn test_fastq_export_interleaved() {
+        let dir = TempDir::new("gbam_fastq").unwrap();
+        let path = dir.path().join("collated.gbam");
+        let mut records = test_records();
+        // Grouped by name, with mates of `p2` in READ2, READ1 order.
+        records.sort_by_key(|rec| rec.name.clone());
+        write_test_file(&path, "@HD\tVN:1.6\tGO:query\n", &records);
+
+        let mut reader = open_test_file(&path);
+        let options = FastqOptions {
+            max_pending: 1,
+            ..Default::default()
+        };
+        let mut out = Vec::new();
+        let report = fastq_export_interleaved(&mut reader, &mut out, &options).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "@nomate/1\nCAT\n+\n\"\"\"\n\
+             @p1/1\nACGTN\n+\n+5?I!\n@p1/2\nTGAAA\n+\n*)('&\n\
+             @p2/1\nCCCAG\n+\nIIIII\n@p2/2\nTACCC\n+\n5????\n\
+             @single\nKGGTT\n+\n&%$#\"\n"
+        );
+        assert_eq!(report.pairs, 2);
+        assert_eq!(report.missing_sequence, 1);
+
+        let options = FastqOptions {
+            read_suffix: ReadSuffix::Never,
+            ..Default::default()
+        };
+        let mut out = Vec::new();
+        fastq_export_interleaved(&mut reader, &mut out, &options).unwrap();
+        assert!(String::from_utf8(out).unwrap().starts_with("@nomate\nCAT\n"));
+    }
+}
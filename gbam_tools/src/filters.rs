@@ -0,0 +1,861 @@
+//! Record filter expressions like the ones of `samtools view -e`, e.g.
+//! `mapq >= 30 && flag.paired && !flag.dup && rname == "chr1"`.
+//!
+//! Operands are fields of records, tags in brackets like `[NM]`, numbers
+//! and strings in single or double quotes, with `\\`, `\"`, `\'`, `\n`,
+//! `\t` and `\r` escapes. Operators, from the loosest binding: `||`, `&&`,
+//! comparisons `==`, `!=`, `<`, `<=`, `>`, `>=` and regex matches `=~`,
+//! `!~` (against a string literal), and unary `!` and `-`. Parentheses
+//! group.
+//!
+//! Fields are `flag`, its bits `flag.paired`, `flag.proper_pair`,
+//! `flag.unmap`, `flag.munmap`, `flag.reverse`, `flag.mreverse`,
+//! `flag.read1`, `flag.read2`, `flag.secondary`, `flag.qcfail`, `flag.dup`
+//! (or `flag.duplicate`) and `flag.supplementary`, `mapq`, `pos` (1-based
+//! as in SAM, 0 for unplaced records), `rname` (name of the reference, `*`
+//! for unplaced records), `tlen` and `qname`.
+//!
+//! Tags take the type of their values: integers and floats compare as
+//! numbers, strings, hex strings and characters as strings. Strings
+//! compared with numbers are parsed as numbers. Comparisons with missing
+//! tags, array tags or strings which aren't numbers are false, whatever
+//! the operator. A tag alone tests whether the record has it.
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::convert::TryFrom;
+use std::io;
+use std::sync::Arc;
+
+use bam_tools::record::fields::Fields;
+use regex::bytes::Regex;
+
+use crate::error::{GbamError, Result};
+use crate::meta::FileMeta;
+use crate::reader::reader::Reader;
+use crate::reader::record::GbamRecord;
+use crate::tags::TagValue;
+
+/// Expressions nest at most this deep, so parsing doesn't run out of stack.
+const MAX_DEPTH: usize = 64;
+
+/// Flag bits by name, as in `samtools flags`.
+const FLAG_BITS: [(&str, u16); 13] = [
+    ("paired", 0x1),
+    ("proper_pair", 0x2),
+    ("unmap", 0x4),
+    ("munmap", 0x8),
+    ("reverse", 0x10),
+    ("mreverse", 0x20),
+    ("read1", 0x40),
+    ("read2", 0x80),
+    ("secondary", 0x100),
+    ("qcfail", 0x200),
+    ("dup", 0x400),
+    ("duplicate", 0x400),
+    ("supplementary", 0x800),
+];
+
+/// Compiled filter expression, see the module documentation for syntax.
+#[derive(Clone, Debug)]
+pub struct Filter {
+    expr: Expr,
+}
+
+impl Filter {
+    /// Compiles `text`. Fails with `GbamError::FilterSyntax` pointing at
+    /// the offending token.
+    pub fn parse(text: &str) -> io::Result<Self> {
+        let tokens = tokenize(text)?;
+        let mut parser = Parser {
+            tokens: &tokens,
+            pos: 0,
+            depth: 0,
+            end: text.len(),
+        };
+        let expr = parser.or()?;
+        if let Some(token) = parser.peek() {
+            return Err(syntax_error(token.offset, &token.text, "Expected end of expression"));
+        }
+        Ok(Self { expr })
+    }
+
+    /// Data fields read to evaluate the filter, in fields order.
+    pub fn fields(&self) -> Vec<Fields> {
+        let mut fields = Vec::new();
+        self.expr.collect_fields(&mut fields);
+        fields.sort_unstable_by_key(|field| *field as usize);
+        fields.dedup();
+        fields
+    }
+
+    /// Whether `rec` passes the filter, with fields of `fields()` filled.
+    /// Reference names are looked up in `ref_seqs`. Fails if tags of the
+    /// record are malformed.
+    pub fn matches(&self, rec: &GbamRecord, ref_seqs: &[(String, u32)]) -> io::Result<bool> {
+        self.expr.test(&Record { rec, ref_seqs })
+    }
+}
+
+/// Record an expression is evaluated on.
+struct Record<'r> {
+    rec: &'r GbamRecord,
+    ref_seqs: &'r [(String, u32)],
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum CmpOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Operand {
+    Flag,
+    FlagBit(u16),
+    Mapq,
+    Pos,
+    Rname,
+    Tlen,
+    Qname,
+    Tag([u8; 2]),
+}
+
+impl Operand {
+    fn field(self) -> Fields {
+        match self {
+            Operand::Flag | Operand::FlagBit(_) => Fields::Flags,
+            Operand::Mapq => Fields::Mapq,
+            Operand::Pos => Fields::Pos,
+            Operand::Rname => Fields::RefID,
+            Operand::Tlen => Fields::TemplateLength,
+            Operand::Qname => Fields::ReadName,
+            Operand::Tag(_) => Fields::RawTags,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Expr {
+    Or(Box<Expr>, Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Neg(Box<Expr>),
+    Cmp(Box<Expr>, CmpOp, Box<Expr>),
+    // Negated for `!~`.
+    Match(Box<Expr>, Regex, bool),
+    Operand(Operand),
+    Literal(Value<'static>),
+}
+
+/// Value of an expression. Missing values are Null.
+#[derive(Clone, Debug, PartialEq)]
+enum Value<'a> {
+    Null,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    Str(Cow<'a, [u8]>),
+}
+
+impl Value<'_> {
+    fn is_true(&self) -> bool {
+        match self {
+            Value::Null => false,
+            Value::Bool(value) => *value,
+            Value::Int(value) => *value != 0,
+            Value::Float(value) => *value != 0.0,
+            Value::Str(_) => true,
+        }
+    }
+
+    /// Numeric value, strings parsed. None if there is none.
+    fn number(&self) -> Option<Value<'static>> {
+        match self {
+            Value::Null => None,
+            Value::Bool(value) => Some(Value::Int(i64::from(*value))),
+            Value::Int(value) => Some(Value::Int(*value)),
+            Value::Float(value) => Some(Value::Float(*value)),
+            Value::Str(bytes) => {
+                let text = std::str::from_utf8(bytes).ok()?.trim();
+                match text.parse::<i64>() {
+                    Ok(value) => Some(Value::Int(value)),
+                    Err(_) => text.parse::<f64>().ok().map(Value::Float),
+                }
+            }
+        }
+    }
+
+    fn compare(&self, other: &Value) -> Option<Ordering> {
+        if let (Value::Str(left), Value::Str(right)) = (self, other) {
+            return Some(left.cmp(right));
+        }
+        match (self.number()?, other.number()?) {
+            (Value::Int(left), Value::Int(right)) => Some(left.cmp(&right)),
+            (left, right) => as_float(&left).partial_cmp(&as_float(&right)),
+        }
+    }
+}
+
+fn as_float(value: &Value) -> f64 {
+    match *value {
+        Value::Int(value) => value as f64,
+        Value::Float(value) => value,
+        _ => f64::NAN,
+    }
+}
+
+impl<'a> From<TagValue<'a>> for Value<'a> {
+    fn from(value: TagValue<'a>) -> Self {
+        match value {
+            TagValue::Char(c) => Value::Str(Cow::Owned(vec![c])),
+            TagValue::Float(value) => Value::Float(f64::from(value)),
+            TagValue::String(bytes) | TagValue::Hex(bytes) => Value::Str(Cow::Borrowed(bytes)),
+            TagValue::Array(_) => Value::Null,
+            int => int.as_int().map_or(Value::Null, Value::Int),
+        }
+    }
+}
+
+impl Expr {
+    fn collect_fields(&self, fields: &mut Vec<Fields>) {
+        match self {
+            Expr::Or(left, right) | Expr::And(left, right) | Expr::Cmp(left, _, right) => {
+                left.collect_fields(fields);
+                right.collect_fields(fields);
+            }
+            Expr::Not(expr) | Expr::Neg(expr) | Expr::Match(expr, _, _) => expr.collect_fields(fields),
+            Expr::Operand(operand) => fields.push(operand.field()),
+            Expr::Literal(_) => {}
+        }
+    }
+
+    /// Truth of the expression, tags alone test for presence.
+    fn test(&self, rec: &Record) -> io::Result<bool> {
+        match self {
+            Expr::Operand(Operand::Tag(_)) => Ok(self.eval(rec)? != Value::Null),
+            expr => Ok(expr.eval(rec)?.is_true()),
+        }
+    }
+
+    fn eval<'r>(&'r self, rec: &Record<'r>) -> io::Result<Value<'r>> {
+        let value = match self {
+            Expr::Or(left, right) => Value::Bool(left.test(rec)? || right.test(rec)?),
+            Expr::And(left, right) => Value::Bool(left.test(rec)? && right.test(rec)?),
+            Expr::Not(expr) => Value::Bool(!expr.test(rec)?),
+            Expr::Neg(expr) => match expr.eval(rec)?.number() {
+                Some(Value::Int(value)) => Value::Int(-value),
+                Some(Value::Float(value)) => Value::Float(-value),
+                _ => Value::Null,
+            },
+            Expr::Cmp(left, op, right) => {
+                let ordering = left.eval(rec)?.compare(&right.eval(rec)?);
+                Value::Bool(ordering.is_some_and(|ordering| match op {
+                    CmpOp::Eq => ordering == Ordering::Equal,
+                    CmpOp::Ne => ordering != Ordering::Equal,
+                    CmpOp::Lt => ordering == Ordering::Less,
+                    CmpOp::Le => ordering != Ordering::Greater,
+                    CmpOp::Gt => ordering == Ordering::Greater,
+                    CmpOp::Ge => ordering != Ordering::Less,
+                }))
+            }
+            Expr::Match(expr, regex, negated) => match expr.eval(rec)? {
+                Value::Str(bytes) => Value::Bool(regex.is_match(&bytes) != *negated),
+                _ => Value::Bool(false),
+            },
+            Expr::Operand(operand) => operand_value(*operand, rec)?,
+            Expr::Literal(value) => value.clone(),
+        };
+        Ok(value)
+    }
+}
+
+fn operand_value<'r>(operand: Operand, rec: &Record<'r>) -> io::Result<Value<'r>> {
+    let r = rec.rec;
+    let int = |value: Option<i64>| value.map_or(Value::Null, Value::Int);
+    let value = match operand {
+        Operand::Flag => int(r.flag.map(i64::from)),
+        Operand::FlagBit(bit) => r.flag.map_or(Value::Null, |flag| Value::Bool(flag & bit != 0)),
+        Operand::Mapq => int(r.mapq.map(i64::from)),
+        Operand::Pos => int(r.pos.map(|pos| i64::from(pos) + 1)),
+        Operand::Tlen => int(r.tlen.map(i64::from)),
+        Operand::Rname => match r.refid {
+            Some(refid) => {
+                let name = usize::try_from(refid).ok().and_then(|refid| rec.ref_seqs.get(refid));
+                Value::Str(Cow::Borrowed(name.map_or(&b"*"[..], |(name, _)| name.as_bytes())))
+            }
+            None => Value::Null,
+        },
+        // Stored with the terminating NUL.
+        Operand::Qname => match &r.read_name {
+            Some(name) => Value::Str(Cow::Borrowed(name.strip_suffix(b"\0").unwrap_or(name))),
+            None => Value::Null,
+        },
+        Operand::Tag(tag) => r.get_tag(&tag)?.map_or(Value::Null, Value::from),
+    };
+    Ok(value)
+}
+
+fn syntax_error(offset: usize, token: &str, message: &str) -> io::Error {
+    GbamError::FilterSyntax {
+        offset,
+        token: token.to_owned(),
+        message: message.to_owned(),
+    }
+    .into()
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Tok {
+    Num(Value<'static>),
+    Str(Vec<u8>),
+    Ident(String),
+    Tag([u8; 2]),
+    Op(&'static str),
+}
+
+struct Token {
+    tok: Tok,
+    // Byte offset in the expression.
+    offset: usize,
+    text: String,
+}
+
+/// Operators, longer ones first.
+const OPS: [&str; 15] = [
+    "||", "&&", "==", "!=", "<=", ">=", "=~", "!~", "<", ">", "!", "-", "(", ")", "=",
+];
+
+fn tokenize(text: &str) -> io::Result<Vec<Token>> {
+    let bytes = text.as_bytes();
+    let mut tokens = Vec::new();
+    let mut pos = 0;
+    while pos < bytes.len() {
+        let c = bytes[pos];
+        let start = pos;
+        let tok = if c.is_ascii_whitespace() {
+            pos += 1;
+            continue;
+        } else if c == b'"' || c == b'\'' {
+            let (string, end) = string_literal(text, start)?;
+            pos = end;
+            Tok::Str(string)
+        } else if c == b'[' {
+            let tag = bytes.get(pos + 1..pos + 4).filter(|tag| {
+                tag[0].is_ascii_alphabetic() && tag[1].is_ascii_alphanumeric() && tag[2] == b']'
+            });
+            let tag = tag.ok_or_else(|| {
+                let end = text[start..].find(']').map_or(text.len(), |end| start + end + 1);
+                syntax_error(start, &text[start..end], "Expected tag of two characters like [NM]")
+            })?;
+            pos += 4;
+            Tok::Tag([tag[0], tag[1]])
+        } else if c.is_ascii_digit() || (c == b'.' && bytes.get(pos + 1).is_some_and(u8::is_ascii_digit)) {
+            let (number, end) = number_literal(text, start)?;
+            pos = end;
+            Tok::Num(number)
+        } else if c.is_ascii_alphabetic() || c == b'_' {
+            let ident = |c: &u8| c.is_ascii_alphanumeric() || matches!(*c, b'_' | b'.');
+            pos += bytes[pos..].iter().take_while(|c| ident(c)).count();
+            Tok::Ident(text[start..pos].to_owned())
+        } else {
+            let op = OPS.iter().copied().find(|op| text[start..].starts_with(op)).ok_or_else(|| {
+                let len = text[start..].chars().next().map_or(1, char::len_utf8);
+                syntax_error(start, &text[start..start + len], "Unexpected character")
+            })?;
+            if op == "=" {
+                return Err(syntax_error(start, "=", "Expected == for equality"));
+            }
+            pos += op.len();
+            Tok::Op(op)
+        };
+        tokens.push(Token {
+            tok,
+            offset: start,
+            text: text[start..pos].to_owned(),
+        });
+    }
+    Ok(tokens)
+}
+
+/// String starting with a quote at `start`, and its end.
+fn string_literal(text: &str, start: usize) -> io::Result<(Vec<u8>, usize)> {
+    let bytes = text.as_bytes();
+    let quote = bytes[start];
+    let mut string = Vec::new();
+    let mut pos = start + 1;
+    while pos < bytes.len() {
+        match bytes[pos] {
+            c if c == quote => return Ok((string, pos + 1)),
+            b'\\' => {
+                let escaped = match bytes.get(pos + 1) {
+                    Some(b'\\') => b'\\',
+                    Some(b'"') => b'"',
+                    Some(b'\'') => b'\'',
+                    Some(b'n') => b'\n',
+                    Some(b't') => b'\t',
+                    Some(b'r') => b'\r',
+                    Some(_) => {
+                        let len = text[pos + 1..].chars().next().map_or(1, char::len_utf8);
+                        return Err(syntax_error(pos, &text[pos..pos + 1 + len], "Unknown escape"));
+                    }
+                    None => break,
+                };
+                string.push(escaped);
+                pos += 2;
+            }
+            c => {
+                string.push(c);
+                pos += 1;
+            }
+        }
+    }
+    Err(syntax_error(start, &text[start..], "Unterminated string"))
+}
+
+/// Integer, hexadecimal integer or float starting at `start`, and its end.
+fn number_literal(text: &str, start: usize) -> io::Result<(Value<'static>, usize)> {
+    let bytes = text.as_bytes();
+    let mut end = start;
+    while end < bytes.len() {
+        let c = bytes[end];
+        let exponent_sign = matches!(c, b'+' | b'-') && matches!(bytes[end - 1], b'e' | b'E');
+        if !(c.is_ascii_alphanumeric() || c == b'.' || (exponent_sign && !text[start..].starts_with("0x"))) {
+            break;
+        }
+        end += 1;
+    }
+    let literal = &text[start..end];
+    let value = match literal.strip_prefix("0x") {
+        Some(hex) => i64::from_str_radix(hex, 16).ok().map(Value::Int),
+        None => match literal.parse::<i64>() {
+            Ok(value) => Some(Value::Int(value)),
+            Err(_) => literal.parse::<f64>().ok().map(Value::Float),
+        },
+    };
+    let value = value.ok_or_else(|| syntax_error(start, literal, "Malformed number"))?;
+    Ok((value, end))
+}
+
+struct Parser<'t> {
+    tokens: &'t [Token],
+    pos: usize,
+    depth: usize,
+    // Length of the expression, where errors at its end point.
+    end: usize,
+}
+
+impl<'t> Parser<'t> {
+    fn peek(&self) -> Option<&'t Token> {
+        self.tokens.get(self.pos)
+    }
+
+    /// Takes the next token if it's operator `op`.
+    fn take_op(&mut self, op: &str) -> bool {
+        let found = matches!(self.peek(), Some(Token { tok: Tok::Op(next), .. }) if *next == op);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn error_here(&self, message: &str) -> io::Error {
+        match self.peek() {
+            Some(token) => syntax_error(token.offset, &token.text, message),
+            None => syntax_error(self.end, "", message),
+        }
+    }
+
+    fn or(&mut self) -> io::Result<Expr> {
+        let mut expr = self.and()?;
+        while self.take_op("||") {
+            expr = Expr::Or(Box::new(expr), Box::new(self.and()?));
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> io::Result<Expr> {
+        let mut expr = self.comparison()?;
+        while self.take_op("&&") {
+            expr = Expr::And(Box::new(expr), Box::new(self.comparison()?));
+        }
+        Ok(expr)
+    }
+
+    fn comparison(&mut self) -> io::Result<Expr> {
+        let left = self.unary()?;
+        for (op, negated) in [("=~", false), ("!~", true)] {
+            if self.take_op(op) {
+                let regex = match self.peek() {
+                    Some(Token { tok: Tok::Str(pattern), offset, text }) => {
+                        let pattern = String::from_utf8_lossy(pattern);
+                        Regex::new(&pattern).map_err(|err| {
+                            syntax_error(*offset, text, &format!("Malformed regex: {}", err))
+                        })?
+                    }
+                    _ => return Err(self.error_here("Expected regex in a string")),
+                };
+                self.pos += 1;
+                return Ok(Expr::Match(Box::new(left), regex, negated));
+            }
+        }
+        let ops = [
+            ("==", CmpOp::Eq),
+            ("!=", CmpOp::Ne),
+            ("<=", CmpOp::Le),
+            (">=", CmpOp::Ge),
+            ("<", CmpOp::Lt),
+            (">", CmpOp::Gt),
+        ];
+        for (op, cmp_op) in ops {
+            if self.take_op(op) {
+                return Ok(Expr::Cmp(Box::new(left), cmp_op, Box::new(self.unary()?)));
+            }
+        }
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> io::Result<Expr> {
+        if self.depth == MAX_DEPTH {
+            return Err(self.error_here("Expression nests too deep"));
+        }
+        self.depth += 1;
+        let expr = if self.take_op("!") {
+            Expr::Not(Box::new(self.unary()?))
+        } else if self.take_op("-") {
+            Expr::Neg(Box::new(self.unary()?))
+        } else {
+            self.primary()?
+        };
+        self.depth -= 1;
+        Ok(expr)
+    }
+
+    fn primary(&mut self) -> io::Result<Expr> {
+        let token = match self.peek() {
+            Some(token) => token,
+            None => return Err(self.error_here("Expected operand")),
+        };
+        let expr = match &token.tok {
+            Tok::Num(value) => Expr::Literal(value.clone()),
+            Tok::Str(string) => Expr::Literal(Value::Str(Cow::Owned(string.clone()))),
+            Tok::Tag(tag) => Expr::Operand(Operand::Tag(*tag)),
+            Tok::Ident(name) => Expr::Operand(
+                operand(name).ok_or_else(|| syntax_error(token.offset, &token.text, "Unknown field"))?,
+            ),
+            Tok::Op("(") => {
+                self.pos += 1;
+                let expr = self.or()?;
+                if !self.take_op(")") {
+                    return Err(self.error_here("Expected )"));
+                }
+                return Ok(expr);
+            }
+            Tok::Op(_) => return Err(self.error_here("Expected operand")),
+        };
+        self.pos += 1;
+        Ok(expr)
+    }
+}
+
+/// Field named `name`.
+fn operand(name: &str) -> Option<Operand> {
+    let operand = match name {
+        "flag" => Operand::Flag,
+        "mapq" => Operand::Mapq,
+        "pos" => Operand::Pos,
+        "rname" => Operand::Rname,
+        "tlen" => Operand::Tlen,
+        "qname" => Operand::Qname,
+        _ => {
+            let bit = name.strip_prefix("flag.")?;
+            let (_, bit) = FLAG_BITS.iter().find(|(name, _)| *name == bit)?;
+            Operand::FlagBit(*bit)
+        }
+    };
+    Some(operand)
+}
+
+/// Iterates over records passing a filter. Created by
+/// [`Reader::records_filtered`].
+pub struct FilteredRecords<'a> {
+    reader: &'a mut Reader,
+    filter: &'a Filter,
+    meta: Arc<FileMeta>,
+    cur_rec: usize,
+    buf: GbamRecord,
+    filter_fields: Vec<Fields>,
+    other_fields: Vec<Fields>,
+}
+
+impl FilteredRecords<'_> {
+    /// Next matching record, None past the last one. Fails like
+    /// `Reader::fill_record()`, or if tags of a record are malformed, the
+    /// error names the record. Iteration may go on after errors.
+    pub fn next_rec(&mut self) -> Result<Option<&GbamRecord>> {
+        while self.cur_rec < self.reader.amount {
+            let rec_num = self.cur_rec;
+            self.cur_rec += 1;
+            if self.read_if_matches(rec_num)? {
+                return Ok(Some(&self.buf));
+            }
+        }
+        Ok(None)
+    }
+
+    // Reads fields of the filter of record `rec_num`, and other fields if it
+    // matches.
+    fn read_if_matches(&mut self, rec_num: usize) -> Result<bool> {
+        let physical = self.reader.physical_rec_num(rec_num)?;
+        for field in &self.filter_fields {
+            self.reader.get_column(field)?.fill_record_field(physical, &mut self.buf)?;
+        }
+        let matches = self
+            .filter
+            .matches(&self.buf, self.meta.get_ref_seqs())
+            .map_err(|err| io::Error::new(err.kind(), format!("Record {}: {}", rec_num, err)))?;
+        if !matches {
+            return Ok(false);
+        }
+        // Other fields are only read for matching records.
+        for field in &self.other_fields {
+            self.reader.get_column(field)?.fill_record_field(physical, &mut self.buf)?;
+        }
+        self.reader.fill_source_index(physical, &mut self.buf)?;
+        Ok(true)
+    }
+}
+
+impl Reader {
+    /// Get iterator over records passing `filter`, like `samtools view -e`.
+    /// Fields read by the filter must be enabled in parsing template, and
+    /// are decoded for every record, other fields of the template only for
+    /// matching ones.
+    pub fn records_filtered<'a>(&'a mut self, filter: &'a Filter) -> Result<FilteredRecords<'a>> {
+        let filter_fields = filter.fields();
+        let template = &self.parsing_template;
+        if let Some(field) = filter_fields.iter().find(|field| !template.check_if_active(&[**field])) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Filter reads field {}, which is not in parsing template", field),
+            )
+            .into());
+        }
+        let other_fields = self
+            .parsing_template
+            .get_active_data_fields_iter()
+            .filter(|field| !filter_fields.contains(*field))
+            .copied()
+            .collect();
+        self.start_query();
+        Ok(FilteredRecords {
+            meta: self.file_meta.clone(),
+            reader: self,
+            filter,
+            cur_rec: 0,
+            buf: GbamRecord::default(),
+            filter_fields,
+            other_fields,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::gbam_error;
+    use crate::test_utils::{open_test_file, test_ref_seqs, write_test_file, TestRecord};
+    use tempdir::TempDir;
+
+    fn test_records() -> Vec<TestRecord> {
+        (0..300)
+            .map(|i| {
+                let mut rec = TestRecord::new(i % 4 - 1, i * 10, &format!("run{}:{}", i % 3, i));
+                rec.mapq = (i % 70) as u8;
+                rec.flag = [0x1 | 0x40, 0x1 | 0x80 | 0x400, 0x10, 0x4][i as usize % 4];
+                rec.tlen = i - 150;
+                rec.tags = match i % 5 {
+                    0 => b"NMC\x02".to_vec(),
+                    1 => b"NMi\x07\0\0\0RGZgrp1\0".to_vec(),
+                    2 => b"RGZgrp2\0XAA+".to_vec(),
+                    3 => [&b"AFf"[..], &0.25f32.to_le_bytes()[..]].concat(),
+                    _ => b"NMZ12\0".to_vec(),
+                };
+                rec
+            })
+            .collect()
+    }
+
+    fn syntax(text: &str) -> (usize, String) {
+        let err = Filter::parse(text).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        match gbam_error(&err) {
+            Some(GbamError::FilterSyntax { offset, token, .. }) => (*offset, token.clone()),
+            _ => panic!("{}", err),
+        }
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(syntax("mapq >= 30 && flag.paried"), (14, String::from("flag.paried")));
+        assert_eq!(syntax("mapq >= 30 &&"), (13, String::new()));
+        assert_eq!(syntax("(mapq > 3 || pos < 5"), (20, String::new()));
+        assert_eq!(syntax("mapq > 3)"), (8, String::from(")")));
+        assert_eq!(syntax("mapq = 3"), (5, String::from("=")));
+        assert_eq!(syntax("mapq == 3 == 4"), (10, String::from("==")));
+        assert_eq!(syntax("[N] > 1"), (0, String::from("[N]")));
+        assert_eq!(syntax("rname == \"chr1"), (9, String::from("\"chr1")));
+        assert_eq!(syntax("rname == 'ch\\qr1'"), (12, String::from("\\q")));
+        assert_eq!(syntax("qname =~ mapq"), (9, String::from("mapq")));
+        assert_eq!(syntax("qname =~ 'run(1'"), (9, String::from("'run(1'")));
+        assert_eq!(syntax("pos > 1e"), (6, String::from("1e")));
+        assert_eq!(syntax("mapq # 3"), (5, String::from("#")));
+        assert_eq!(syntax(&"!".repeat(100)), (MAX_DEPTH, String::from("!")));
+        let err = Filter::parse("mapq >= 30 && flag.paried").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Unknown field at offset 14 of filter expression: `flag.paried`"
+        );
+    }
+
+    /// Records of `records` passing `filter`, by position.
+    fn passing(filter: &str, records: &[TestRecord]) -> Vec<i32> {
+        let filter = Filter::parse(filter).unwrap();
+        let ref_seqs = test_ref_seqs();
+        records
+            .iter()
+            .filter(|rec| {
+                let rec = GbamRecord {
+                    refid: Some(rec.refid),
+                    pos: Some(rec.pos),
+                    mapq: Some(rec.mapq),
+                    flag: Some(rec.flag),
+                    tlen: Some(rec.tlen),
+                    read_name: Some(format!("{}\0", rec.name).into_bytes()),
+                    tags: Some(rec.tags.clone()),
+                    ..Default::default()
+                };
+                filter.matches(&rec, &ref_seqs).unwrap()
+            })
+            .map(|rec| rec.pos)
+            .collect()
+    }
+
+    #[test]
+    fn test_precedence() {
+        let records = test_records();
+        let pos = |pred: &dyn Fn(&TestRecord) -> bool| -> Vec<i32> {
+            records.iter().filter(|rec| pred(rec)).map(|rec| rec.pos).collect()
+        };
+        // && binds tighter than ||, comparisons tighter than both.
+        assert_eq!(
+            passing("mapq < 5 || mapq > 60 && flag.paired", &records),
+            pos(&|rec| rec.mapq < 5 || (rec.mapq > 60 && rec.flag & 0x1 != 0))
+        );
+        assert_eq!(
+            passing("(mapq < 5 || mapq > 60) && flag.paired", &records),
+            pos(&|rec| (rec.mapq < 5 || rec.mapq > 60) && rec.flag & 0x1 != 0)
+        );
+        // ! binds tighter than comparisons, as in C.
+        assert_eq!(passing("!flag.dup == 1", &records), pos(&|rec| rec.flag & 0x400 == 0));
+        assert_eq!(passing("!(tlen > -3)", &records), pos(&|rec| rec.tlen <= -3));
+        assert_eq!(passing("-tlen >= 3", &records), pos(&|rec| -rec.tlen >= 3));
+        assert_eq!(passing("!!flag.reverse", &records), pos(&|rec| rec.flag & 0x10 != 0));
+        assert_eq!(
+            passing("flag == 0x10 || flag == 4", &records),
+            pos(&|rec| rec.flag == 0x10 || rec.flag == 4)
+        );
+        // 1-based positions.
+        assert_eq!(passing("pos <= 20", &records), vec![0, 10]);
+    }
+
+    #[test]
+    fn test_strings() {
+        let records = vec![
+            TestRecord::new(0, 0, "a'b"),
+            TestRecord::new(1, 1, "a\"b"),
+            TestRecord::new(2, 2, "a\\b"),
+            TestRecord::new(-1, 3, "run1:7"),
+        ];
+        assert_eq!(passing(r#"qname == 'a\'b'"#, &records), vec![0]);
+        assert_eq!(passing(r#"qname == "a\"b""#, &records), vec![1]);
+        assert_eq!(passing(r#"qname == 'a"b' || qname == "a\\b""#, &records), vec![1, 2]);
+        assert_eq!(passing(r#"qname =~ "^a.b$""#, &records), vec![0, 1, 2]);
+        // Backslash escaped in the string and in the regex.
+        assert_eq!(passing(r#"qname !~ '\\\\'"#, &records), vec![0, 1, 3]);
+        assert_eq!(passing("rname == 'chr2' || rname == '*'", &records), vec![1, 3]);
+        assert_eq!(passing("rname > 'chr1'", &records), vec![1, 2]);
+    }
+
+    #[test]
+    fn test_tag_coercion() {
+        let records = test_records();
+        // Tags of record i are picked by i % 5, see `test_records()`.
+        let check = |filter: &str, kinds: &[i32]| {
+            let passed: Vec<i32> = passing(filter, &records).into_iter().map(|pos| pos / 10).collect();
+            let expected: Vec<i32> = (0..records.len() as i32).filter(|i| kinds.contains(&(i % 5))).collect();
+            assert_eq!(passed, expected, "{}", filter);
+        };
+        // Integers of any size, and strings holding numbers.
+        check("[NM] == 2", &[0]);
+        check("[NM] > 5", &[1, 4]);
+        check("[NM] == '12'", &[4]);
+        check("[NM] == 12.0", &[4]);
+        // Floats and characters.
+        check("[AF] < 0.5 && [AF] > 0.2", &[3]);
+        check("[XA] == '+'", &[2]);
+        // Strings which aren't numbers compare false, even with !=.
+        check("[RG] != 3", &[]);
+        check("[RG] > 'grp1'", &[2]);
+        // Tags alone test presence, comparisons with missing ones are false.
+        check("[RG]", &[1, 2]);
+        check("![NM]", &[2, 3]);
+        check("[XA] != '-'", &[2]);
+        check("[NM] >= 0 || ![NM]", &[0, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn test_records_filtered() {
+        let dir = TempDir::new("gbam_filters").unwrap();
+        let path = dir.path().join("filter.gbam");
+        let records = test_records();
+        write_test_file(&path, "", &records);
+
+        let text = "mapq >= 30 && flag.paired && !flag.duplicate && rname == 'chr1' || qname =~ '^run2:1.$'";
+        let filter = Filter::parse(text).unwrap();
+        assert_eq!(
+            filter.fields(),
+            vec![Fields::RefID, Fields::Mapq, Fields::Flags, Fields::ReadName]
+        );
+        let expected: Vec<i32> = records
+            .iter()
+            .filter(|rec| {
+                let name = rec.name.as_bytes();
+                (rec.mapq >= 30 && rec.flag & 0x1 != 0 && rec.flag & 0x400 == 0 && rec.refid == 0)
+                    || (name.starts_with(b"run2:1") && name.len() == 7)
+            })
+            .map(|rec| rec.pos)
+            .collect();
+        assert!(!expected.is_empty());
+        assert_eq!(passing(text, &records), expected);
+
+        let mut reader = open_test_file(&path);
+        let mut filtered = reader.records_filtered(&filter).unwrap();
+        let mut found = Vec::new();
+        while let Some(rec) = filtered.next_rec().unwrap() {
+            // Other fields are read for matching records.
+            assert_eq!(rec.tlen, Some(rec.pos.unwrap() / 10 - 150));
+            found.push(rec.pos.unwrap());
+        }
+        assert_eq!(found, expected);
+
+        let mut template = crate::reader::parse_tmplt::ParsingTemplate::new();
+        template.set(&Fields::Mapq, true);
+        let mut reader = Reader::new(std::fs::File::open(&path).unwrap(), template).unwrap();
+        let err = reader.records_filtered(&filter).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,90 @@
+//! Entry points of fuzz targets in `fuzz/`. Each takes arbitrary bytes and
+//! has to return, with an error or not, without panicking or allocating
+//! more than the bytes justify.
+use bam_tools::record::fields::{Fields, FIELDS_NUM};
+use byteorder::{ByteOrder, LittleEndian};
+
+use crate::column_transform::ColumnTransform;
+use crate::meta::{BlockMeta, Codecs, FileMeta, SeqEncoding};
+use crate::reader::column::decode_field_block;
+use crate::reader::parse_tmplt::ParsingTemplate;
+use crate::reader::reader::{parse_file_info, parse_meta_json, Reader};
+
+const CODECS: [Codecs; 6] = [
+    Codecs::Gzip,
+    Codecs::Lz4,
+    Codecs::Brotli,
+    Codecs::Zstd,
+    Codecs::Xz,
+    Codecs::NoCompression,
+];
+
+/// Bytes describing the block of `block()`.
+const BLOCK_HEADER_SIZE: usize = 11;
+
+/// Parses `data` as the head of a file.
+pub fn file_info(data: &[u8]) {
+    if let Ok(file_info) = parse_file_info(data) {
+        let _ = file_info.data_range();
+    }
+}
+
+/// Parses `data` as meta JSON, with the checks of opened files.
+pub fn file_meta(data: &[u8]) {
+    if let Ok(meta) = parse_meta_json(data) {
+        let _ = meta.check_record_counts();
+        let _ = meta.check_block_ranges(0..u64::MAX);
+    }
+}
+
+/// Decodes a block of a column. The first bytes of `data` pick codec,
+/// field, flags (stored, transform, stripe and 2-bit sequences), items and
+/// decompressed size, the rest is the block as stored.
+pub fn block(data: &[u8]) {
+    if data.len() < BLOCK_HEADER_SIZE {
+        return;
+    }
+    let (header, bytes) = data.split_at(BLOCK_HEADER_SIZE);
+    let codec = CODECS[header[0] as usize % CODECS.len()];
+    let field = Fields::iterator().nth(header[1] as usize % FIELDS_NUM).copied().unwrap_or(Fields::RefID);
+    let flags = header[2];
+    let mut meta = FileMeta::new(codec, Vec::new(), Vec::new(), false);
+    if flags & 0x10 != 0 {
+        meta.set_seq_encoding(SeqEncoding::TwoBit);
+    }
+    let block = BlockMeta {
+        numitems: LittleEndian::read_u32(&header[3..7]),
+        block_size: bytes.len() as u32,
+        uncompressed_size: u64::from(LittleEndian::read_u32(&header[7..11])),
+        stored: flags & 0x1 != 0,
+        transform: match flags & 0x6 {
+            0x2 => Some(ColumnTransform::FlagsRLE),
+            0x4 => Some(ColumnTransform::MapqBitPack),
+            _ => None,
+        },
+        stripe: if flags & 0x8 != 0 { Some(u64::from(flags >> 5)) } else { None },
+        ..Default::default()
+    };
+    // As checked when meta is parsed, see `FileMeta::check_sizes()`.
+    let limit = block.max_uncompressed_size(codec, meta.get_block_size_limit(&field));
+    if !codec.is_available() || block.uncompressed_size > limit {
+        return;
+    }
+    let _ = decode_field_block(&meta, field, &block, bytes, None);
+}
+
+/// Opens file held in `data` with all fields, checks its blocks and reads
+/// all its records.
+pub fn open(data: &[u8]) {
+    let mut template = ParsingTemplate::new();
+    template.set_all();
+    let mut reader = match Reader::from_bytes(data.to_vec(), template) {
+        Ok(reader) => reader,
+        Err(_) => return,
+    };
+    if reader.check_blocks().is_err() {
+        return;
+    }
+    let mut records = reader.records();
+    while let Ok(Some(_)) = records.next_rec() {}
+}
@@ -0,0 +1,326 @@
+//! Structured dump of file info, meta and block tables, for debugging files.
+//! Block data is checked, but only meta has to be readable.
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::File;
+use std::io;
+use std::path::Path;
+
+use bam_tools::record::fields::Fields;
+use memmap2::Mmap;
+use serde::Serialize;
+
+use crate::error::with_path;
+use crate::meta::{BlockMeta, Codecs, FileMeta, SeqEncoding, SortOrder, Stat};
+use crate::reader::column::decompress_block;
+use crate::reader::reader::{meta_in_tail, meta_pos, meta_read_start, parse_file_info};
+use crate::stats::stat_ref_id;
+use crate::writer::calc_crc_for_meta_bytes;
+
+/// Report of `inspect()`, serializes into JSON.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct FileReport {
+    pub version: [u32; 2],
+    pub creation_command: String,
+    pub is_sorted: bool,
+    pub file_size: u64,
+    pub meta_offset: u64,
+    /// Meta matches crc32 of file info. Meta is reported even if it doesn't.
+    pub meta_crc_ok: bool,
+    pub sort_order: SortOrder,
+    pub seq_encoding: SeqEncoding,
+    pub rows_per_block: Option<u32>,
+    pub ref_seqs: Vec<(String, u32)>,
+    /// Items of RefID.
+    pub records: u64,
+    pub linear_index: bool,
+    pub fields: Vec<FieldReport>,
+    /// Descriptions of failed checks, empty for sound files.
+    pub failed_checks: Vec<String>,
+}
+
+/// Blocks of one field.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct FieldReport {
+    pub field: Fields,
+    pub codec: Codecs,
+    /// Key id of encrypted fields.
+    pub encryption: Option<String>,
+    pub blocks: usize,
+    pub items: u64,
+    pub compressed_bytes: u64,
+    pub uncompressed_bytes: u64,
+    pub first_block_stats: Option<StatReport>,
+    pub last_block_stats: Option<StatReport>,
+    /// Number of blocks by transform, see `column_transform`.
+    pub transforms: BTreeMap<String, usize>,
+    /// Blocks with read name bloom filter.
+    pub bloom_filters: usize,
+    /// Names of write time collectors with block stats.
+    pub extra_stats: BTreeSet<String>,
+}
+
+/// Block stat, RefID of unplaced reads is -1.
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StatReport {
+    pub min: i32,
+    pub max: i32,
+}
+
+/// Reports file at `path`. Fails only if file info or meta can't be parsed,
+/// other problems are listed in `failed_checks`: meta crc32, blocks missing
+/// or outside of data, blocks which don't decompress to their size, and
+/// fields with other number of items than RefID. Blocks of encrypted fields
+/// are not decompressed.
+pub fn inspect<P: AsRef<Path>>(path: P) -> io::Result<FileReport> {
+    let path = path.as_ref();
+    let file = File::open(path).map_err(|err| with_path(err, path))?;
+    let mmap = unsafe { Mmap::map(&file).map_err(|err| with_path(err, path))? };
+    inspect_bytes(&mmap).map_err(|err| with_path(err, path).into())
+}
+
+/// Same as `inspect()`, for a file held in memory.
+pub fn inspect_bytes(bytes: &[u8]) -> io::Result<FileReport> {
+    let file_info = parse_file_info(bytes)?;
+    let meta_offset = meta_pos(&file_info, bytes.len() as u64)?;
+    let meta_bytes = meta_in_tail(&file_info, &bytes[meta_read_start(meta_offset) as usize..]);
+    let meta: FileMeta = serde_json::from_slice(meta_bytes).map_err(|err| {
+        io::Error::new(io::ErrorKind::InvalidData, format!("File meta JSON is damaged: {}", err))
+    })?;
+
+    let mut failed_checks = Vec::new();
+    let meta_crc_ok = calc_crc_for_meta_bytes(meta_bytes) == file_info.crc32;
+    if !meta_crc_ok {
+        failed_checks.push(String::from("Meta doesn't match crc32 of file info"));
+    }
+    if let Err(err) = meta.check_record_counts() {
+        failed_checks.push(err.to_string());
+    }
+    // Blocks end before the prefix of meta, or the end of file if meta is
+    // in space reserved after file info.
+    let data_end = file_info.data_range().end.min(bytes.len() as u64);
+    let fields = Fields::iterator()
+        .map(|field| field_report(&meta, *field, bytes, data_end, &mut failed_checks))
+        .collect();
+
+    Ok(FileReport {
+        version: file_info.gbam_version,
+        creation_command: file_info.creation_command,
+        is_sorted: file_info.is_sorted,
+        file_size: bytes.len() as u64,
+        meta_offset,
+        meta_crc_ok,
+        sort_order: meta.get_sort_order(),
+        seq_encoding: meta.get_seq_encoding(),
+        rows_per_block: meta.get_rows_per_block(),
+        ref_seqs: meta.get_ref_seqs().clone(),
+        records: meta.count_items(&Fields::RefID),
+        linear_index: meta.get_linear_index().is_some(),
+        fields,
+        failed_checks,
+    })
+}
+
+fn field_report(
+    meta: &FileMeta,
+    field: Fields,
+    bytes: &[u8],
+    data_end: u64,
+    failed_checks: &mut Vec<String>,
+) -> FieldReport {
+    let blocks = meta.view_blocks(&field);
+    let codec = *meta.get_field_codec(&field);
+    let encryption = meta.get_field_encryption(&field).map(|encryption| encryption.key_id.clone());
+    let stat_report = |block: Option<&BlockMeta>| {
+        block.and_then(|block| block.stats.as_ref()).map(|stat: &Stat| match field {
+            Fields::RefID => StatReport {
+                min: stat_ref_id(stat.min_value),
+                max: stat_ref_id(stat.max_value),
+            },
+            _ => StatReport {
+                min: stat.min_value,
+                max: stat.max_value,
+            },
+        })
+    };
+    let mut report = FieldReport {
+        field,
+        codec,
+        encryption: encryption.clone(),
+        blocks: blocks.len(),
+        items: meta.count_items(&field),
+        compressed_bytes: 0,
+        uncompressed_bytes: 0,
+        first_block_stats: stat_report(blocks.first()),
+        last_block_stats: stat_report(blocks.last()),
+        transforms: BTreeMap::new(),
+        bloom_filters: 0,
+        extra_stats: BTreeSet::new(),
+    };
+
+    let mut uncompressed = Vec::new();
+    for (block_num, block) in blocks.iter().enumerate() {
+        report.compressed_bytes += block.block_size as u64;
+        report.uncompressed_bytes += block.uncompressed_size;
+        if let Some(transform) = block.transform {
+            *report.transforms.entry(format!("{:?}", transform)).or_default() += 1;
+        }
+        report.bloom_filters += block.bloom.is_some() as usize;
+        report.extra_stats.extend(block.extra_stats.keys().cloned());
+
+        let end = block.seekpos + block.block_size as u64;
+        // No block starts at file info, see `FileMeta::check_no_missing_blocks()`.
+        if block.seekpos == 0 {
+            failed_checks.push(format!("Block {} of field {} was never written", block_num, field));
+            continue;
+        }
+        if end > data_end {
+            failed_checks.push(format!(
+                "Block {} of field {} ends at {}, past data ending at {}",
+                block_num, field, end, data_end
+            ));
+            continue;
+        }
+        if encryption.is_some() || block.uncompressed_size == 0 {
+            continue;
+        }
+        let data = &bytes[block.seekpos as usize..end as usize];
+        uncompressed.resize(block.uncompressed_size as usize, 0);
+        let decompressed = decompress_block(data, &mut uncompressed, &block.codec(codec)).and_then(|()| {
+            if uncompressed.len() as u64 == block.uncompressed_size {
+                return Ok(());
+            }
+            Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} bytes, expected {}", uncompressed.len(), block.uncompressed_size),
+            ))
+        });
+        if let Err(err) = decompressed {
+            failed_checks.push(format!("Block {} of field {} can't be decompressed: {}", block_num, field, err));
+        }
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{write_test_file, TestRecord};
+    use serde_json::json;
+    use tempdir::TempDir;
+
+    fn fixture(dir: &TempDir) -> std::path::PathBuf {
+        let path = dir.path().join("inspected.gbam");
+        let records = [
+            TestRecord::new(0, 10, "r0"),
+            TestRecord::new(1, 20, "r1"),
+            TestRecord::new(-1, -1, "u"),
+        ];
+        write_test_file(&path, "@HD\tVN:1.6\tSO:coordinate\n", &records);
+        path
+    }
+
+    /// Report as JSON, with offsets and compressed sizes zeroed.
+    fn normalized(report: &FileReport) -> serde_json::Value {
+        let mut value = serde_json::to_value(report).unwrap();
+        value["file_size"] = json!(0);
+        value["meta_offset"] = json!(0);
+        for field in value["fields"].as_array_mut().unwrap() {
+            field["compressed_bytes"] = json!(0);
+        }
+        value
+    }
+
+    #[test]
+    fn test_inspect() {
+        let dir = TempDir::new("gbam_inspect").unwrap();
+        let path = fixture(&dir);
+        let report = inspect(&path).unwrap();
+        assert_eq!(report.file_size, std::fs::metadata(&path).unwrap().len());
+        assert!(report.meta_offset > report.fields.iter().map(|field| field.compressed_bytes).sum::<u64>());
+
+        let mut value = normalized(&report);
+        let fields = value["fields"].take();
+        assert_eq!(
+            value,
+            json!({
+                "version": [1, 7],
+                "creation_command": "test",
+                "is_sorted": false,
+                "file_size": 0,
+                "meta_offset": 0,
+                "meta_crc_ok": true,
+                "sort_order": "Coordinate",
+                "seq_encoding": "Nibble",
+                "rows_per_block": null,
+                "ref_seqs": [["chr1", 1_000_000], ["chr2", 1_000_000], ["chr3", 1_000_000]],
+                "records": 3,
+                "linear_index": false,
+                "fields": null,
+                "failed_checks": [],
+            })
+        );
+        let field = |name: &str| {
+            fields.as_array().unwrap().iter().find(|field| field["field"] == json!(name)).unwrap().clone()
+        };
+        let expected = |name: &str, uncompressed_bytes: u64, stats: serde_json::Value| {
+            json!({
+                "field": name,
+                "codec": "Lz4",
+                "encryption": null,
+                "blocks": 1,
+                "items": 3,
+                "compressed_bytes": 0,
+                "uncompressed_bytes": uncompressed_bytes,
+                "first_block_stats": stats,
+                "last_block_stats": stats,
+                "transforms": {},
+                "bloom_filters": 0,
+                "extra_stats": [],
+            })
+        };
+        assert_eq!(field("RefID"), expected("RefID", 12, json!({"min": 0, "max": -1})));
+        assert_eq!(field("Pos"), expected("Pos", 12, json!({"min": -1, "max": 20})));
+        assert_eq!(field("ReadName"), expected("ReadName", 8, json!(null)));
+        assert_eq!(field("LName"), expected("LName", 12, json!(null)));
+    }
+
+    #[test]
+    fn test_inspect_damaged_file() {
+        let dir = TempDir::new("gbam_inspect").unwrap();
+        // Blocks of the fixture are too small to compress, stored ones can't
+        // be told from damaged.
+        let path = dir.path().join("damaged.gbam");
+        let records: Vec<TestRecord> = (0..1_000).map(|i| TestRecord::new(0, i, "r")).collect();
+        write_test_file(&path, "@HD\tVN:1.6\tSO:coordinate\n", &records);
+        let mut bytes = std::fs::read(&path).unwrap();
+        let report = inspect_bytes(&bytes).unwrap();
+
+        // Unreadable RefID block and file info pointing to other meta crc32.
+        let block = report_block(&bytes, Fields::RefID);
+        assert!(!block.stored);
+        bytes[block.seekpos as usize..][..block.block_size as usize].fill(0xff);
+        let mut file_info = parse_file_info(&bytes).unwrap();
+        file_info.crc32 = !file_info.crc32;
+        bytes[..crate::meta::FILE_INFO_SIZE].copy_from_slice(&file_info.to_padded_bytes().unwrap());
+
+        let damaged = inspect_bytes(&bytes).unwrap();
+        assert!(!damaged.meta_crc_ok);
+        assert_eq!(damaged.failed_checks.len(), 2, "{:?}", damaged.failed_checks);
+        assert_eq!(damaged.failed_checks[0], "Meta doesn't match crc32 of file info");
+        assert!(
+            damaged.failed_checks[1].starts_with("Block 0 of field RefID can't be decompressed: "),
+            "{}",
+            damaged.failed_checks[1]
+        );
+        // Everything else is still reported.
+        assert_eq!(damaged.fields, report.fields);
+        assert!(crate::reader::reader::Reader::from_bytes(bytes, Default::default()).is_err());
+    }
+
+    fn report_block(bytes: &[u8], field: Fields) -> BlockMeta {
+        let file_info = parse_file_info(bytes).unwrap();
+        let meta_offset = meta_pos(&file_info, bytes.len() as u64).unwrap() as usize;
+        let meta: FileMeta = serde_json::from_slice(&bytes[meta_offset..]).unwrap();
+        meta.view_blocks(&field)[0].clone()
+    }
+}
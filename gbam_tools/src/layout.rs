@@ -0,0 +1,135 @@
+//! Layout of integers stored in files. They are little endian whatever the
+//! host, and are read and written through byteorder, never by reinterpreting
+//! memory. Offsets and sizes are u64 on disk, they are converted to usize
+//! with `to_usize()`, so 32-bit hosts fail on what they can't address
+//! instead of silently wrapping.
+//!
+//! Integers in JSON of file info and meta are plain decimal numbers. Tests
+//! here pin exact bytes of all of them.
+use std::convert::TryFrom;
+use std::io;
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use crate::meta::META_PREFIX_SIZE;
+
+/// Bytes of an entry of index fields, see `index_entry()`.
+pub const INDEX_ENTRY_SIZE: usize = 4;
+
+/// Crc32 and length of meta JSON, stored right before it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MetaPrefix {
+    pub crc32: u32,
+    pub len: u64,
+}
+
+impl MetaPrefix {
+    pub fn encode(&self) -> [u8; META_PREFIX_SIZE] {
+        let mut bytes = [0; META_PREFIX_SIZE];
+        LittleEndian::write_u32(&mut bytes[..4], self.crc32);
+        LittleEndian::write_u64(&mut bytes[4..], self.len);
+        bytes
+    }
+
+    /// Prefix at the start of `bytes`, None if they are too short.
+    pub fn decode(bytes: &[u8]) -> Option<Self> {
+        let bytes = bytes.get(..META_PREFIX_SIZE)?;
+        Some(Self {
+            crc32: LittleEndian::read_u32(&bytes[..4]),
+            len: LittleEndian::read_u64(&bytes[4..]),
+        })
+    }
+}
+
+/// End offset of an item in the decompressed data block of its variable
+/// sized field, decoded from `item` of the index field.
+pub fn index_entry(item: &[u8]) -> usize {
+    LittleEndian::read_u32(item) as usize
+}
+
+/// Encoded index entry, see `index_entry()`. Blocks are limited to
+/// `SIZE_LIMIT`, far below `u32::MAX`.
+pub fn encode_index_entry(end: usize) -> [u8; INDEX_ENTRY_SIZE] {
+    let mut bytes = [0; INDEX_ENTRY_SIZE];
+    LittleEndian::write_u32(&mut bytes, u32::try_from(end).expect("Index entry doesn't fit into u32"));
+    bytes
+}
+
+/// `value` read from file as usize, failing on hosts where it doesn't fit.
+pub fn to_usize(value: u64) -> io::Result<usize> {
+    usize::try_from(value).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("{} doesn't fit into {}-bit usize of this host", value, usize::BITS),
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::meta::{BlockMeta, FileInfo, Stat, FILE_INFO_SIZE};
+
+    #[test]
+    fn test_meta_prefix_bytes() {
+        let prefix = MetaPrefix {
+            crc32: 0x0403_0201,
+            len: 0x0c0b_0a09_0807_0605,
+        };
+        let bytes = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12];
+        assert_eq!(prefix.encode(), bytes);
+        assert_eq!(MetaPrefix::decode(&bytes), Some(prefix));
+        assert_eq!(MetaPrefix::decode(&bytes[..11]), None);
+    }
+
+    #[test]
+    fn test_index_entry_bytes() {
+        assert_eq!(encode_index_entry(0x0001_0203), [3, 2, 1, 0]);
+        assert_eq!(index_entry(&[0xff, 0, 0, 0x80]), 0x8000_00ff);
+        assert_eq!(index_entry(&[1, 0, 0, 0, 0xaa]), 1);
+    }
+
+    #[test]
+    fn test_to_usize() {
+        assert_eq!(to_usize(0x1234).unwrap(), 0x1234);
+        let large = to_usize(u64::MAX);
+        if usize::BITS < 64 {
+            assert_eq!(large.unwrap_err().kind(), io::ErrorKind::Unsupported);
+        } else {
+            assert_eq!(large.unwrap() as u64, u64::MAX);
+        }
+    }
+
+    #[test]
+    fn test_file_info_bytes() {
+        let mut file_info = FileInfo::new([1, 5], 5_000_000_000, 0xdead_beef, String::from("gbam x"), true);
+        file_info.reserved_meta = 4096;
+        let bytes = file_info.to_padded_bytes().unwrap();
+        let json: &[u8] = br#"{"magic":"geeBAM10","gbam_version":[1,5],"seekpos":5000000000,"crc32":3735928559,"is_sorted":true,"creation_command":"gbam x","reserved_meta":4096}"#;
+        assert_eq!(&bytes[..json.len()], json);
+        assert_eq!(bytes.len(), FILE_INFO_SIZE);
+        assert!(bytes[json.len()..].iter().all(|&byte| byte == 0));
+    }
+
+    #[test]
+    fn test_block_meta_bytes() {
+        let block = BlockMeta {
+            seekpos: 5_000_000_000,
+            numitems: 4000,
+            block_size: 0x0102_0304,
+            uncompressed_size: 1 << 33,
+            stats: Some(Stat {
+                min_value: -1,
+                max_value: i32::MAX,
+            }),
+            ..Default::default()
+        };
+        let json = serde_json::to_string(&block).unwrap();
+        assert_eq!(
+            json,
+            r#"{"seekpos":5000000000,"numitems":4000,"block_size":16909060,"uncompressed_size":8589934592,"stats":{"min_value":-1,"max_value":2147483647}}"#
+        );
+        let parsed: BlockMeta = serde_json::from_str(&json).unwrap();
+        assert_eq!((parsed.seekpos, parsed.uncompressed_size), (5_000_000_000, 1 << 33));
+    }
+}
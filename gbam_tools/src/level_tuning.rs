@@ -0,0 +1,333 @@
+//! Compression levels of fields. A level can be pinned per field with
+//! `Writer::set_compression_level()`. In adaptive mode, see
+//! `Writer::set_adaptive_levels()`, the first blocks of every other field
+//! are compressed at a low and a high level of its codec, and the high one
+//! is kept for the rest of the blocks if it saves enough bytes for the time
+//! it takes, the low one otherwise. Quality columns barely shrink at high
+//! levels, while read names do. Levels and samples behind them are stored in
+//! meta extension `compression_levels`. Decompression doesn't depend on
+//! levels, blocks of one field may be compressed at different ones.
+use std::collections::BTreeMap;
+use std::io;
+use std::ops::RangeInclusive;
+use std::sync::{Arc, Mutex};
+
+use bam_tools::record::fields::{Fields, FIELDS_NUM};
+use serde::{Deserialize, Serialize};
+
+use crate::meta::{Codecs, FileMeta};
+
+/// Name of meta extension holding levels, see `FileMeta::get_extension()`.
+pub const LEVELS_EXTENSION: &str = "compression_levels";
+
+/// Settings of adaptive mode.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AdaptiveLevels {
+    /// Blocks of each field compressed at both levels before one is picked.
+    pub sample_blocks: usize,
+    /// Compressed bytes the high level has to save per second it takes
+    /// longer than the low one, over sampled blocks. `f64::INFINITY` always
+    /// picks the low level, 0 picks the high one unless it does worse.
+    pub min_bytes_saved_per_sec: f64,
+}
+
+impl Default for AdaptiveLevels {
+    fn default() -> Self {
+        Self {
+            sample_blocks: 2,
+            min_bytes_saved_per_sec: (512 << 10) as f64,
+        }
+    }
+}
+
+/// Level of a field, as recorded in meta.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct LevelDecision {
+    pub codec: Codecs,
+    pub level: i32,
+    /// Set with `Writer::set_compression_level()`, nothing was sampled.
+    pub pinned: bool,
+    /// Blocks compressed at both levels, they keep the high level output.
+    #[serde(default)]
+    pub sampled_blocks: usize,
+    /// Bytes the high level saved per extra second, over sampled blocks.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bytes_saved_per_sec: Option<f64>,
+}
+
+/// Levels recorded by the writer, by field name. Empty for files written
+/// without pinned levels or adaptive mode.
+pub fn recorded_levels(meta: &FileMeta) -> io::Result<BTreeMap<String, LevelDecision>> {
+    match meta.get_extension(LEVELS_EXTENSION) {
+        Some(levels) => serde_json::from_value(levels.clone()).map_err(|err| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Meta extension {} is damaged: {}", LEVELS_EXTENSION, err),
+            )
+        }),
+        None => Ok(BTreeMap::new()),
+    }
+}
+
+/// Low and high level adaptive mode picks from, the high one is the level
+/// used by default. None for codecs without levels.
+pub fn candidate_levels(codec: Codecs) -> Option<(i32, i32)> {
+    match codec {
+        Codecs::Gzip => Some((1, 9)),
+        Codecs::Zstd => Some((3, 14)),
+        Codecs::Xz => Some((1, 6)),
+        Codecs::Brotli => Some((4, 8)),
+        Codecs::Lz4 | Codecs::NoCompression => None,
+    }
+}
+
+/// Levels `codec` accepts, brotli quality for Brotli and preset for Xz.
+pub fn level_range(codec: Codecs) -> Option<RangeInclusive<i32>> {
+    match codec {
+        Codecs::Gzip | Codecs::Xz => Some(0..=9),
+        Codecs::Zstd => Some(1..=22),
+        Codecs::Brotli => Some(0..=11),
+        Codecs::Lz4 | Codecs::NoCompression => None,
+    }
+}
+
+/// Sizes and compression times of a block at both levels.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Sample {
+    pub low_size: usize,
+    pub low_secs: f64,
+    pub high_size: usize,
+    pub high_secs: f64,
+}
+
+/// Level a block is compressed at.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum BlockLevel {
+    /// Default level of the codec.
+    Default,
+    Fixed(i32),
+    /// Compressed at both levels, the high level output is kept.
+    Sample(i32, i32),
+}
+
+#[derive(Default)]
+pub(crate) struct FieldTuning {
+    codec: Option<Codecs>,
+    // Blocks handed out for sampling, their samples may not be back yet.
+    started: usize,
+    samples: Vec<Sample>,
+    chosen: Option<(i32, f64)>,
+}
+
+impl FieldTuning {
+    pub fn add_sample(&mut self, sample: Sample) {
+        self.samples.push(sample);
+    }
+
+    // Picks from samples collected so far.
+    fn choose(&mut self, codec: Codecs, min_bytes_saved_per_sec: f64) -> (i32, f64) {
+        let (low, high) = candidate_levels(codec).unwrap();
+        let sum = |get: fn(&Sample) -> f64| self.samples.iter().map(get).sum::<f64>();
+        let saved = sum(|s| s.low_size as f64) - sum(|s| s.high_size as f64);
+        // At least a nanosecond, so the rate stays finite for tiny blocks.
+        let extra_secs = (sum(|s| s.high_secs) - sum(|s| s.low_secs)).max(1e-9);
+        let rate = saved / extra_secs;
+        let level = if rate >= min_bytes_saved_per_sec { high } else { low };
+        *self.chosen.insert((level, rate))
+    }
+}
+
+/// Pinned levels and adaptive mode state of a writer, by field. Tuning
+/// state is shared with compression threads, which add samples.
+pub(crate) struct LevelTuner {
+    pinned: Vec<Option<i32>>,
+    adaptive: Option<AdaptiveLevels>,
+    fields: Arc<Vec<Mutex<FieldTuning>>>,
+}
+
+impl LevelTuner {
+    pub fn new() -> Self {
+        Self {
+            pinned: vec![None; FIELDS_NUM],
+            adaptive: None,
+            fields: Arc::new((0..FIELDS_NUM).map(|_| Mutex::new(FieldTuning::default())).collect()),
+        }
+    }
+
+    pub fn pin(&mut self, field: Fields, level: i32) {
+        self.pinned[field as usize] = Some(level);
+    }
+
+    pub fn set_adaptive(&mut self, adaptive: Option<AdaptiveLevels>) {
+        self.adaptive = adaptive;
+    }
+
+    pub fn is_adaptive(&self) -> bool {
+        self.adaptive.is_some()
+    }
+
+    pub fn shared_fields(&self) -> Arc<Vec<Mutex<FieldTuning>>> {
+        self.fields.clone()
+    }
+
+    /// Level of the next block of `field`. Blocks submitted once all samples
+    /// are handed out, but not back yet, get the default level.
+    pub fn block_level(&self, field: Fields, codec: Codecs) -> BlockLevel {
+        if let Some(level) = self.pinned[field as usize] {
+            return BlockLevel::Fixed(level);
+        }
+        let (adaptive, (low, high)) = match (self.adaptive, candidate_levels(codec)) {
+            (Some(adaptive), Some(levels)) => (adaptive, levels),
+            _ => return BlockLevel::Default,
+        };
+        let mut tuning = self.fields[field as usize].lock().unwrap();
+        tuning.codec = Some(codec);
+        if let Some((level, _)) = tuning.chosen {
+            return BlockLevel::Fixed(level);
+        }
+        if tuning.started < adaptive.sample_blocks {
+            tuning.started += 1;
+            return BlockLevel::Sample(low, high);
+        }
+        if tuning.samples.len() < tuning.started {
+            return BlockLevel::Default;
+        }
+        BlockLevel::Fixed(tuning.choose(codec, adaptive.min_bytes_saved_per_sec).0)
+    }
+
+    /// Levels of pinned and sampled fields, to be stored in meta. Fields
+    /// with fewer blocks than `sample_blocks` get a level picked from the
+    /// ones they have. Called once all blocks are compressed.
+    pub fn decisions(&self, codec_of: impl Fn(Fields) -> Codecs) -> BTreeMap<String, LevelDecision> {
+        let mut decisions = BTreeMap::new();
+        for field in Fields::iterator() {
+            let codec = codec_of(*field);
+            if let Some(level) = self.pinned[*field as usize] {
+                let decision = LevelDecision {
+                    codec,
+                    level,
+                    pinned: true,
+                    sampled_blocks: 0,
+                    bytes_saved_per_sec: None,
+                };
+                decisions.insert(field.to_string(), decision);
+                continue;
+            }
+            let mut tuning = self.fields[*field as usize].lock().unwrap();
+            let (codec, adaptive) = match (tuning.codec, self.adaptive) {
+                (Some(codec), Some(adaptive)) if !tuning.samples.is_empty() => (codec, adaptive),
+                _ => continue,
+            };
+            let (level, rate) = match tuning.chosen {
+                Some(chosen) => chosen,
+                None => tuning.choose(codec, adaptive.min_bytes_saved_per_sec),
+            };
+            let decision = LevelDecision {
+                codec,
+                level,
+                pinned: false,
+                sampled_blocks: tuning.samples.len(),
+                bytes_saved_per_sec: Some(rate),
+            };
+            decisions.insert(field.to_string(), decision);
+        }
+        decisions
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reader::{parse_tmplt::ParsingTemplate, reader::Reader};
+    use crate::test_utils::{sam_header_bytes, test_ref_seqs, TestRecord};
+    use crate::writer::{Writer, WriterSettings};
+    use std::path::Path;
+    use tempdir::TempDir;
+
+    fn records() -> Vec<TestRecord> {
+        (0..5_000)
+            .map(|i| {
+                let mut rec = TestRecord::new(0, i * 3, &format!("read_{}_{}", i % 7, i));
+                rec.mapq = (i % 60) as u8;
+                rec
+            })
+            .collect()
+    }
+
+    fn write(path: &Path, codec: Codecs, adaptive: AdaptiveLevels, pinned: Option<(Fields, i32)>) {
+        let ref_seqs = test_ref_seqs();
+        let settings = WriterSettings {
+            codecs: vec![codec; FIELDS_NUM],
+            sam_header: sam_header_bytes("", &ref_seqs),
+            ref_seqs,
+            full_command: String::from("test"),
+            ..Default::default()
+        };
+        let mut writer = Writer::create(path, settings).unwrap();
+        writer.set_rows_per_block(500);
+        writer.set_adaptive_levels(Some(adaptive));
+        if let Some((field, level)) = pinned {
+            writer.set_compression_level(field, level);
+        }
+        for rec in records() {
+            writer.push_record(&rec.to_raw(), false).unwrap();
+        }
+        writer.finish_with_summary(false).unwrap();
+    }
+
+    fn check_records(path: &Path) {
+        let mut tmplt = ParsingTemplate::new();
+        tmplt.set_all();
+        let mut reader = Reader::from_path(path, tmplt).unwrap();
+        let mut recs = reader.records();
+        for expected in records() {
+            let rec = recs.next_rec().unwrap().unwrap();
+            assert_eq!(rec.pos, Some(expected.pos));
+            assert_eq!(rec.mapq, Some(expected.mapq));
+            let name = format!("{}\0", expected.name);
+            assert_eq!(rec.read_name.as_deref(), Some(name.as_bytes()));
+        }
+        assert!(recs.next_rec().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_adaptive_levels() {
+        let dir = TempDir::new("gbam_levels").unwrap();
+        let mut codecs = vec![Codecs::Gzip];
+        if cfg!(feature = "zstd") {
+            codecs.push(Codecs::Zstd);
+        }
+        for codec in codecs {
+            let (low, high) = candidate_levels(codec).unwrap();
+            // Blocks past the sampled ones are compressed at the low level,
+            // sampled ones keep the high level output.
+            let path = dir.path().join("low.gbam");
+            let adaptive = AdaptiveLevels {
+                sample_blocks: 2,
+                min_bytes_saved_per_sec: f64::INFINITY,
+            };
+            write(&path, codec, adaptive, Some((Fields::ReadName, 5)));
+            check_records(&path);
+            let meta = Reader::from_path(&path, ParsingTemplate::new()).unwrap().file_meta;
+            let levels = recorded_levels(&meta).unwrap();
+            let pos = &levels["Pos"];
+            assert_eq!((pos.codec, pos.level, pos.pinned, pos.sampled_blocks), (codec, low, false, 2));
+            assert!(pos.bytes_saved_per_sec.is_some());
+            assert_eq!(levels["Mapq"].level, low);
+            let name = &levels["ReadName"];
+            assert_eq!((name.level, name.pinned, name.sampled_blocks), (5, true, 0));
+
+            let path = dir.path().join("high.gbam");
+            let adaptive = AdaptiveLevels {
+                sample_blocks: 3,
+                min_bytes_saved_per_sec: 0.0,
+            };
+            write(&path, codec, adaptive, None);
+            check_records(&path);
+            let meta = Reader::from_path(&path, ParsingTemplate::new()).unwrap().file_meta;
+            let levels = recorded_levels(&meta).unwrap();
+            assert_eq!((levels["RefID"].level, levels["RefID"].sampled_blocks), (high, 3));
+            assert!(!levels["ReadName"].pinned);
+        }
+    }
+}
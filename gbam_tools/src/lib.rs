@@ -8,28 +8,62 @@ pub mod bam {
     pub mod bam_to_gbam;
     /// GBAM to BAM converter
     pub mod gbam_to_bam;
+    /// Conversions configured with option structs
+    pub mod convert;
+    /// BGZF writer tracking virtual offsets
+    pub mod bgzf;
+    /// BAI and CSI indexes built while writing BAM files
+    pub mod index;
 }
 ///
 pub mod utils {
     /// BED reader
     pub mod bed;
+    /// Batch encoding and decoding of 4-bit packed sequences
+    pub mod seq;
 }
 
 pub mod reader {
     pub mod column;
+    /// Records with lazily parsed fields
+    pub mod lazy;
     pub mod parse_tmplt;
     /// GBAM reader
     #[allow(clippy::module_inception)]
     pub mod reader;
     pub mod record;
     pub mod records;
+    /// Region queries
+    pub mod region;
+    /// Lookup of mates of paired reads
+    pub mod mate;
+    /// Deep check of blocks of untrusted files
+    pub mod check;
+    /// Reader shared by threads, with a cursor per thread
+    pub mod shared;
+    /// Conversion into noodles records
+    #[cfg(feature = "noodles")]
+    pub mod noodles;
+    /// Reader over tokio asynchronous sources
+    #[cfg(feature = "async")]
+    pub mod async_reader;
+    /// Storage read by blocks at offsets
+    pub mod source;
+    /// Typed decoding of fixed sized columns
+    pub mod typed;
+    /// Files read over HTTP(S) with range requests
+    #[cfg(feature = "http")]
+    pub mod http;
 }
 
-#[cfg(not(feature = "python-ffi"))]
 pub mod query {
+    /// Readers decode CIGAR with it, so it's kept in Python builds
     pub mod cigar;
+    #[cfg(not(feature = "python-ffi"))]
     pub mod depth;
+    #[cfg(not(feature = "python-ffi"))]
     pub mod flagstat;
+    #[cfg(not(feature = "python-ffi"))]
     pub mod int2str;
     //pub mod markdup {
     //    pub mod markdup;
@@ -39,23 +73,146 @@ pub mod query {
 
 /// Manages parallel compression
 mod compressor;
+/// Reuse of block buffers by writers
+pub mod buffer_pool;
+/// Compression levels of fields, pinned or picked by sampling blocks
+pub mod level_tuning;
 /// Meta information for GBAM file
 pub mod meta;
-/// Manages stats collection
-mod stats;
+/// Little endian layout of integers stored in files
+mod layout;
+/// GBAM specific errors
+pub mod error;
+/// Comparators of field values for block stats
+pub mod stats;
 /// GBAM writer
 pub mod writer;
+/// Checks of BAM records before writing
+pub mod validation;
+/// Records built from typed values, without BAM input
+pub mod record_builder;
+/// 2-bit packing of sequence column
+mod seq_packing;
+/// Reference based compression of sequence column
+pub mod ref_compression;
+/// Run-length and bit-packing transforms of Flags, Mapq and index columns
+pub mod column_transform;
+/// Recovery of files with interrupted finalization
+pub mod recover;
+/// Editing of meta of finished files, without rewriting blocks
+pub mod meta_edit;
+/// Content digest and provenance of files
+pub mod provenance;
+/// Optional column of ingest ordinals of records
+pub mod source_index;
+/// Per-field encryption of column blocks
+pub mod encryption;
+/// Write time collectors of field statistics
+pub mod analytics;
+/// Python bindings
+#[cfg(feature = "python-ffi")]
+mod python;
+/// SAM text output
+pub mod sam_export;
+/// Recompression of GBAM files with other codecs
+pub mod transcode;
+/// Subsetting of reference sequences on write
+pub mod ref_subset;
+/// Translation of reference ids between headers
+pub mod ref_map;
+/// Concatenation of GBAM files without decompression
+pub mod cat;
+/// Read name bloom filters
+pub mod bloom;
+/// Linear index for region fetch
+pub mod linear_index;
+/// Extraction of regions into new GBAM files
+pub mod extract;
+/// Reordering of nearly coordinate sorted records on write
+pub mod reorder;
+/// Duplicate marking by rewriting Flags column
+pub mod markdup;
+/// Splitting of GBAM files into record balanced shards
+pub mod split;
+/// Splitting of GBAM files by read group
+pub mod demux;
+/// Merging of GBAM files with renaming of colliding read groups
+pub mod merge;
+/// Column by column comparison of GBAM files
+pub mod diff;
+/// Rewriting of selected fields with per value closures
+pub mod rewrite;
+/// FASTQ output of reads
+pub mod fastq_export;
+/// SAM text and FASTQ input
+pub mod text_import;
+/// Parsing of auxiliary data and filtering of records by tags
+pub mod tags;
+/// Record filter expressions like the ones of samtools view
+pub mod filters;
+/// Progress reporting and cancellation of writes
+pub mod progress;
+/// Tracing spans and summaries of writer and reader hot paths
+pub mod trace;
+/// Structured dump of file info, meta and block tables
+pub mod inspect;
+/// Synthetic records for benchmarks
+#[cfg(feature = "bench")]
+pub mod bench_support;
+/// Entry points of fuzz targets
+#[cfg(feature = "fuzzing")]
+pub mod fuzz;
+
+#[cfg(test)]
+mod test_utils;
 
 // use self::writer::Writer;
 // pub use {ParsingTemplate, Reader};
 use self::writer::Writer;
+#[allow(deprecated)]
 pub use bam::bam_to_gbam::{bam_sort_to_gbam, bam_to_gbam};
+pub use bam::convert::{Bam2GbamOptions, Gbam2BamOptions};
 pub use bam_tools::record::fields::Fields;
 pub use meta::Codecs;
+pub use cat::cat;
+pub use diff::diff;
+pub use inspect::inspect;
+pub use merge::merge;
+pub use rewrite::rewrite;
+pub use compressor::CompressorPool;
+pub use split::split;
+pub use transcode::transcode;
 
 const U32_SIZE: usize = mem::size_of::<u32>();
 const MEGA_BYTE_SIZE: usize = 1_048_576;
 
-/// 16777216 bytes
+/// Block size limit of fixed sized fields, and of all fields of files
+/// which don't record their limits.
 const SIZE_LIMIT: usize = 8 * MEGA_BYTE_SIZE;
+/// Block size limit of variable sized fields, whose records are larger.
+const VAR_SIZE_LIMIT: usize = 2 * SIZE_LIMIT;
+/// Bounds of block size limits set with `Writer::set_block_size_limit()`.
+pub const MIN_BLOCK_SIZE_LIMIT: usize = 4 * 1024;
+pub const MAX_BLOCK_SIZE_LIMIT: usize = 64 * MEGA_BYTE_SIZE;
+/// Largest record the writer takes. A record larger than the block size
+/// limit gets a block of its own, so no block holds more than the limit
+/// plus this, which bounds what readers decompress.
+pub const MAX_RECORD_SIZE: usize = 256 * MEGA_BYTE_SIZE;
 static GBAM_MAGIC: &[u8] = b"geeBAM10";
+/// Format version written to file info, as [major, minor]. Minor versions
+/// add meta: optional meta, which older readers skip (new `FileMeta` fields
+/// with serde defaults, or entries of `FileMeta::extensions`, where new
+/// features keep their meta), or, as noted below, values older readers fail
+/// to parse, so they reject such files instead of misreading them. Files of
+/// any minor version of the major one are read. Major versions change block
+/// layout or the meaning of existing meta, and readers refuse other major
+/// versions with `MetaError::UnsupportedVersion`.
+///
+/// 1.1 adds meta extensions, 1.2 index spans of variable sized fields, 1.3
+/// stripes of blocks shared by grouped fields, 1.4 content digest and
+/// provenance, 1.5 meta in space reserved after file info (older readers
+/// reject files with meta there, finding blocks after meta), 1.6 block size
+/// limits of fields, 1.7 offset deltas of index blocks (older readers reject
+/// files with them as damaged, failing to parse the unknown transform of
+/// their block meta).
+const GBAM_VERSION: [u32; 2] = [1, 7];
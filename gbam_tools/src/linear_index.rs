@@ -0,0 +1,121 @@
+//! Linear index of coordinate sorted files, like the linear index of BAI.
+//! Maps fixed size bins of each reference to the first record which may
+//! overlap them, so region fetch starts without searching.
+use std::convert::TryFrom;
+
+use bam_tools::record::bamrawrecord::BAMRawRecord;
+use bam_tools::record::fields::Fields;
+use byteorder::{LittleEndian, ReadBytesExt};
+use serde::{Deserialize, Serialize};
+
+/// Bin size of BAI linear index.
+pub const LINEAR_INDEX_BIN_SIZE: u32 = 16_384;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct LinearIndex {
+    bin_size: u32,
+    // Indexed by reference id, then by bin. Number of the first record whose
+    // alignment ends after the bin start.
+    refs: Vec<Vec<u64>>,
+}
+
+impl LinearIndex {
+    /// Record to start scanning from for records overlapping `start` on
+    /// `ref_id`. Records before it end before `start`. None if no record is
+    /// placed on the reference.
+    pub fn first_candidate(&self, ref_id: i32, start: i32) -> Option<u64> {
+        let bins = self.refs.get(usize::try_from(ref_id).ok()?)?;
+        let bin = std::cmp::max(start, 0) as usize / self.bin_size as usize;
+        // Past the last record starting bin, scan from the last one.
+        bins.get(bin).or_else(|| bins.last()).copied()
+    }
+}
+
+/// Collects linear index of pushed records. Index is dropped if records turn
+/// out not to be coordinate sorted.
+pub(crate) struct LinearIndexBuilder {
+    refs: Vec<Vec<Option<u64>>>,
+    last: (i32, i32),
+    sorted: bool,
+}
+
+impl LinearIndexBuilder {
+    pub fn new() -> Self {
+        Self {
+            refs: Vec::new(),
+            last: (0, 0),
+            sorted: true,
+        }
+    }
+
+    pub fn observe(&mut self, rec_num: u64, rec: &BAMRawRecord) {
+        if !self.sorted {
+            return;
+        }
+        let read_i32 = |field| rec.get_bytes(field).read_i32::<LittleEndian>().unwrap();
+        let (ref_id, pos) = (read_i32(&Fields::RefID), read_i32(&Fields::Pos));
+        // Unmapped records are at the end of sorted files.
+        if ref_id < 0 || pos < 0 {
+            self.last = (i32::MAX, i32::MAX);
+            return;
+        }
+        if (ref_id, pos) < self.last {
+            self.sorted = false;
+            return;
+        }
+        self.last = (ref_id, pos);
+
+        let end = pos as u64 + std::cmp::max(ref_len(rec.get_bytes(&Fields::RawCigar)), 1) as u64;
+        let first_bin = pos as usize / LINEAR_INDEX_BIN_SIZE as usize;
+        let last_bin = ((end - 1) / LINEAR_INDEX_BIN_SIZE as u64) as usize;
+        if self.refs.len() <= ref_id as usize {
+            self.refs.resize(ref_id as usize + 1, Vec::new());
+        }
+        let bins = &mut self.refs[ref_id as usize];
+        if bins.len() <= last_bin {
+            bins.resize(last_bin + 1, None);
+        }
+        for bin in &mut bins[first_bin..=last_bin] {
+            bin.get_or_insert(rec_num);
+        }
+    }
+
+    pub fn finish(self) -> Option<LinearIndex> {
+        if !self.sorted {
+            return None;
+        }
+        // Bins without overlapping records point to the next record.
+        let refs = self
+            .refs
+            .into_iter()
+            .map(|bins| {
+                let mut next = None;
+                let mut filled: Vec<u64> = bins
+                    .into_iter()
+                    .rev()
+                    .map(|bin| {
+                        next = bin.or(next);
+                        next.unwrap()
+                    })
+                    .collect();
+                filled.reverse();
+                filled
+            })
+            .collect();
+        Some(LinearIndex {
+            bin_size: LINEAR_INDEX_BIN_SIZE,
+            refs,
+        })
+    }
+}
+
+// Reference bases covered by raw BAM cigar: M, D, N, = and X.
+fn ref_len(mut cigar: &[u8]) -> u32 {
+    let mut len = 0;
+    while let Ok(op) = cigar.read_u32::<LittleEndian>() {
+        if matches!(op & 0xf, 0 | 2 | 3 | 7 | 8) {
+            len += op >> 4;
+        }
+    }
+    len
+}
@@ -0,0 +1,398 @@
+//! Duplicate marking, GBAM to GBAM. Only Flags column is rewritten, blocks of
+//! other columns are copied without decompression.
+use std::collections::{HashMap, HashSet};
+use std::io::{self, Seek, SeekFrom, Write};
+
+use bam_tools::record::fields::{Fields, FIELDS_NUM};
+use byteorder::{ByteOrder, LittleEndian};
+
+use crate::compressor::{Compressor, CompressorPool, OrderingKey};
+use crate::layout::to_usize;
+use crate::meta::{FileInfo, SortOrder};
+use crate::query::cigar::{base_coverage, Op};
+use crate::reader::column::decompress_block;
+use crate::reader::reader::Reader;
+use crate::reader::record::GbamRecord;
+use crate::source_index;
+use crate::writer::{write_data_and_update_meta, write_meta_and_file_info, BlockInfo, SyncOutput};
+use crate::GBAM_VERSION;
+use crate::provenance::PROVENANCE_EXTENSION;
+
+const BAM_FPAIRED: u16 = 0x1;
+const BAM_FUNMAP: u16 = 0x4;
+const BAM_FREVERSE: u16 = 0x10;
+const BAM_FSECONDARY: u16 = 0x100;
+const BAM_FDUP: u16 = 0x400;
+const BAM_FSUPPLEMENTARY: u16 = 0x800;
+
+/// Qualities below this don't count into read score, as in Picard.
+const MIN_SCORED_QUAL: u8 = 15;
+
+/// Fields needed to find duplicates.
+pub const MARKDUP_FIELDS: [Fields; 6] = [
+    Fields::RefID,
+    Fields::Pos,
+    Fields::Flags,
+    Fields::ReadName,
+    Fields::RawCigar,
+    Fields::RawQual,
+];
+
+/// Counts named as in Picard MarkDuplicates metrics.
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub struct DuplicationMetrics {
+    pub unpaired_reads_examined: u64,
+    pub read_pairs_examined: u64,
+    pub secondary_or_supplementary_rds: u64,
+    pub unmapped_reads: u64,
+    pub unpaired_read_duplicates: u64,
+    pub read_pair_duplicates: u64,
+    // TODO: optical duplicates need tile and coordinates parsed from names.
+    pub read_pair_optical_duplicates: u64,
+}
+
+impl DuplicationMetrics {
+    pub fn percent_duplication(&self) -> f64 {
+        let examined = self.unpaired_reads_examined + 2 * self.read_pairs_examined;
+        if examined == 0 {
+            return 0.0;
+        }
+        (self.unpaired_read_duplicates + 2 * self.read_pair_duplicates) as f64 / examined as f64
+    }
+}
+
+// Unclipped 5' end of a read: reference id, position and strand.
+type End = (i32, i64, bool);
+
+struct Read {
+    end: End,
+    score: u64,
+}
+
+/// Writes copy of file opened by `reader` into `out`, with duplicate flag
+/// set on duplicates and cleared on other records. Pairs are duplicates if
+/// both their ends match, fragments if their end matches a pair end or
+/// another fragment. Of each group the read or pair with the highest sum of
+/// base qualities stays, ties go to the first one in the file. Secondary,
+/// supplementary and unmapped records get the flag of their primary reads.
+/// Reads are paired by name, so the whole file is examined, in any order.
+/// MARKDUP_FIELDS have to be enabled in parsing template, and Flags must not
+/// be encrypted. Write time analytics are not carried over.
+pub fn mark_duplicates<W: Write + Seek + SyncOutput>(
+    reader: &mut Reader,
+    out: W,
+) -> io::Result<DuplicationMetrics> {
+    if !reader.parsing_template.check_if_active(&MARKDUP_FIELDS) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "RefID, Pos, Flags, ReadName, RawCigar and RawQual fields have to be enabled in parsing template to mark duplicates.",
+        ));
+    }
+    if let Some(encryption) = reader.file_meta.get_field_encryption(&Fields::Flags) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "Field Flags is encrypted with key '{}', it can't be rewritten",
+                encryption.key_id
+            ),
+        ));
+    }
+    let (flags, metrics) = find_duplicates(reader)?;
+    write_with_flags(reader, out, &flags)?;
+    Ok(metrics)
+}
+
+/// New flags of records, in column order.
+fn find_duplicates(reader: &mut Reader) -> io::Result<(Vec<u16>, DuplicationMetrics)> {
+    let mut metrics = DuplicationMetrics::default();
+    let mut flags = Vec::with_capacity(reader.amount);
+    let mut reads: HashMap<usize, Read> = HashMap::new();
+    // Primary mapped reads by name.
+    let mut by_name: HashMap<Vec<u8>, Vec<usize>> = HashMap::new();
+    let mut names = Vec::with_capacity(reader.amount);
+    let mut rec = GbamRecord::default();
+    for rec_num in 0..reader.amount {
+        for field in &MARKDUP_FIELDS {
+            reader.get_column(field)?.fill_record_field(rec_num, &mut rec)?;
+        }
+        let flag = rec.flag.unwrap() & !BAM_FDUP;
+        flags.push(flag);
+        names.push(rec.read_name.take().unwrap());
+        if flag & (BAM_FSECONDARY | BAM_FSUPPLEMENTARY) != 0 {
+            metrics.secondary_or_supplementary_rds += 1;
+        } else if flag & BAM_FUNMAP != 0 {
+            metrics.unmapped_reads += 1;
+        } else {
+            let score = rec
+                .qual
+                .as_ref()
+                .unwrap()
+                .iter()
+                .filter(|&&q| q >= MIN_SCORED_QUAL && q != 0xff)
+                .map(|&q| q as u64)
+                .sum();
+            reads.insert(rec_num, Read { end: unclipped_end(&rec), score });
+            by_name.entry(names[rec_num].clone()).or_default().push(rec_num);
+        }
+    }
+
+    let mut pair_list = Vec::new();
+    let mut fragment_list = Vec::new();
+    for rec_nums in by_name.values() {
+        match rec_nums[..] {
+            [first, second] if flags[first] & flags[second] & BAM_FPAIRED != 0 => {
+                pair_list.push((first, second));
+            }
+            // Unpaired reads, and reads with unmapped or missing mate.
+            _ => fragment_list.extend_from_slice(rec_nums),
+        }
+    }
+    pair_list.sort_unstable();
+    fragment_list.sort_unstable();
+    metrics.read_pairs_examined = pair_list.len() as u64;
+    metrics.unpaired_reads_examined = fragment_list.len() as u64;
+
+    // Best pair or fragment of each group, as (score, record number).
+    let mut duplicates = Vec::new();
+    let mut pairs: HashMap<(End, End), (u64, usize)> = HashMap::new();
+    let mut pair_ends = HashSet::new();
+    for &(first, second) in &pair_list {
+        let (a, b) = (&reads[&first], &reads[&second]);
+        pair_ends.insert(a.end);
+        pair_ends.insert(b.end);
+        let key = if a.end <= b.end { (a.end, b.end) } else { (b.end, a.end) };
+        let candidate = (a.score + b.score, first);
+        match pairs.get_mut(&key) {
+            Some(best) => duplicates.push(keep_best(best, candidate)),
+            None => {
+                pairs.insert(key, candidate);
+            }
+        }
+    }
+    metrics.read_pair_duplicates = duplicates.len() as u64;
+
+    let mut fragments: HashMap<End, (u64, usize)> = HashMap::new();
+    for &rec_num in &fragment_list {
+        let read = &reads[&rec_num];
+        let candidate = (read.score, rec_num);
+        if pair_ends.contains(&read.end) {
+            duplicates.push(rec_num);
+        } else if let Some(best) = fragments.get_mut(&read.end) {
+            duplicates.push(keep_best(best, candidate));
+        } else {
+            fragments.insert(read.end, candidate);
+        }
+    }
+    metrics.unpaired_read_duplicates = duplicates.len() as u64 - metrics.read_pair_duplicates;
+
+    // Whole templates of duplicates are marked.
+    let duplicate_names: HashSet<&[u8]> = duplicates.iter().map(|&rec_num| &names[rec_num][..]).collect();
+    for (rec_num, flag) in flags.iter_mut().enumerate() {
+        if duplicate_names.contains(&names[rec_num][..]) {
+            *flag |= BAM_FDUP;
+        }
+    }
+    Ok((flags, metrics))
+}
+
+/// Replaces `best` with `candidate` if it scores higher, ties go to the lower
+/// record number. Returns record number of the loser.
+fn keep_best(best: &mut (u64, usize), candidate: (u64, usize)) -> usize {
+    if candidate.0 > best.0 || (candidate.0 == best.0 && candidate.1 < best.1) {
+        std::mem::replace(best, candidate).1
+    } else {
+        candidate.1
+    }
+}
+
+fn unclipped_end(rec: &GbamRecord) -> End {
+    let ops = &rec.cigar.as_ref().unwrap().0;
+    let clipped = |ops: &mut dyn Iterator<Item = &Op>| -> i64 {
+        ops.take_while(|op| matches!(op.op_type(), 'S' | 'H'))
+            .map(|op| op.length() as i64)
+            .sum()
+    };
+    let pos = rec.pos.unwrap() as i64;
+    let reverse = rec.flag.unwrap() & BAM_FREVERSE != 0;
+    let five_prime = if reverse {
+        pos + base_coverage(ops) as i64 - 1 + clipped(&mut ops.iter().rev())
+    } else {
+        pos - clipped(&mut ops.iter())
+    };
+    (rec.refid.unwrap(), five_prime, reverse)
+}
+
+fn write_with_flags<W: Write + Seek + SyncOutput>(
+    reader: &Reader,
+    mut out: W,
+    flags: &[u16],
+) -> io::Result<()> {
+    let old_meta = &reader.file_meta;
+    let mut file_meta = (**old_meta).clone();
+    file_meta.clear_analytics();
+    file_meta.remove_extension(PROVENANCE_EXTENSION);
+    let is_sorted = old_meta.get_sort_order() == SortOrder::Coordinate;
+    let mut file_info = FileInfo::new(GBAM_VERSION, 0, 0, String::from("markdup"), is_sorted);
+    out.seek(SeekFrom::Start(0))?;
+    out.write_all(&file_info.to_padded_bytes()?)?;
+
+    let ciphers = vec![None; FIELDS_NUM];
+    let mut compressor = Compressor::new(CompressorPool::new(1));
+    for field in Fields::iterator() {
+        file_meta.get_blocks(field).clear();
+        let mut first_rec = 0;
+        for (block_num, block) in old_meta.view_blocks(field).iter().enumerate() {
+            let data = reader.block_data(block)?;
+            if *field != Fields::Flags {
+                let mut block = block.clone();
+                block.seekpos = out.stream_position()?;
+                out.write_all(&data)?;
+                file_meta.get_blocks(field).push(block);
+                continue;
+            }
+
+            let codec = *old_meta.get_field_codec(field);
+            let mut uncompressed = vec![0; to_usize(block.uncompressed_size)?];
+            if block.uncompressed_size > 0 {
+                decompress_block(&data, &mut uncompressed, &block.codec(codec))?;
+            }
+            if let Some(transform) = block.transform {
+                uncompressed = transform.invert(&uncompressed)?;
+            }
+            uncompressed = block.stripe_of(field, uncompressed)?;
+            let numitems = block.numitems as usize;
+            let new_flags = &flags[first_rec..first_rec + numitems];
+            LittleEndian::write_u16_into(new_flags, &mut uncompressed[..2 * numitems]);
+            first_rec += numitems;
+            let block_info = BlockInfo {
+                numitems: block.numitems,
+                uncompr_size: uncompressed.len(),
+                field: *field,
+                stats: None,
+                bloom: None,
+                transform: None,
+                extra_stats: block.extra_stats.clone(),
+                codec,
+                stripes: Vec::new(),
+            };
+            compressor.compress_block(OrderingKey::Key(block_num as u64), block_info, uncompressed);
+            let mut task = compressor.get_compr_block();
+            if let OrderingKey::Key(key) = task.ordering_key {
+                write_data_and_update_meta(&mut out, &mut file_meta, &ciphers, key, &mut task)?;
+            }
+        }
+    }
+    for mut task in compressor.finish() {
+        if let OrderingKey::Key(key) = task.ordering_key {
+            write_data_and_update_meta(&mut out, &mut file_meta, &ciphers, key, &mut task)?;
+        }
+    }
+    source_index::copy_blocks(std::slice::from_ref(reader), &mut out, &mut file_meta)?;
+    write_meta_and_file_info(&mut out, &mut file_meta, &mut file_info)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{open_test_file, write_test_file, TestRecord};
+    use std::fs::File;
+    use tempdir::TempDir;
+
+    const FWD: u16 = 0x1 | 0x20 | 0x40;
+    const REV: u16 = 0x1 | 0x10 | 0x80;
+
+    fn read(name: &str, pos: i32, flag: u16, cigar: Vec<u32>, qual: u8) -> TestRecord {
+        let mut rec = TestRecord::new(0, pos, name);
+        rec.flag = flag;
+        rec.seq = "ACGTACGTAC".to_owned();
+        rec.qual = vec![qual; 10];
+        rec.cigar = cigar;
+        rec
+    }
+
+    fn pair(name: &str, pos: i32, mate_pos: i32, qual: u8) -> [TestRecord; 2] {
+        [
+            read(name, pos, FWD, vec![10 << 4], qual),
+            read(name, mate_pos, REV, vec![10 << 4], qual),
+        ]
+    }
+
+    #[test]
+    fn test_mark_duplicates() {
+        let dir = TempDir::new("gbam_markdup").unwrap();
+        let src = dir.path().join("in.gbam");
+        let dst = dir.path().join("marked.gbam");
+
+        let [a1, a2] = pair("a", 100, 300, 30);
+        // Qualities below 15 don't count, so `b` scores lowest.
+        let [b1, b2] = pair("b", 100, 300, 14);
+        // Soft clip moves the start, unclipped 5' end is the same as of `a`.
+        let [mut c1, mut c2] = pair("c", 102, 300, 40);
+        c1.cigar = vec![(2 << 4) | 4, 8 << 4];
+        c2.cigar = vec![8 << 4, (2 << 4) | 5];
+        // Reverse 5' end is at unclipped alignment end, the insertion moves
+        // it to 307 for `d`.
+        let [d1, mut d2] = pair("d", 100, 300, 30);
+        d2.cigar = vec![8 << 4, (2 << 4) | 1];
+        let [mut e1, e2] = pair("e", 100, 400, 30);
+        e1.flag |= BAM_FDUP;
+        let fragment_at_pair = read("f", 100, 0, vec![10 << 4], 40);
+        let mut mate_unmapped = read("g", 500, 0x1 | 0x8 | 0x10, vec![10 << 4], 30);
+        mate_unmapped.flag |= 0x40;
+        let mut unmapped_mate = read("g", 500, 0x1 | 0x4 | 0x80, Vec::new(), 30);
+        unmapped_mate.flag |= 0x20;
+        let fragment = read("h", 500, 0x10, vec![10 << 4], 20);
+        let supplementary = read("b", 1000, 0x800 | 0x1 | 0x40, vec![10 << 4], 30);
+        let mut unmapped = read("u", -1, 0x4, Vec::new(), 30);
+        unmapped.refid = -1;
+        let records = vec![
+            a1, b1, c1, d1, e1, fragment_at_pair, a2, b2, c2, d2, e2, mate_unmapped,
+            unmapped_mate, fragment, supplementary, unmapped,
+        ];
+        write_test_file(&src, "@HD\tVN:1.6\tSO:coordinate\n", &records);
+
+        let mut reader = open_test_file(&src);
+        let metrics = mark_duplicates(&mut reader, File::create(&dst).unwrap()).unwrap();
+        assert_eq!(
+            metrics,
+            DuplicationMetrics {
+                unpaired_reads_examined: 3,
+                read_pairs_examined: 5,
+                secondary_or_supplementary_rds: 1,
+                unmapped_reads: 2,
+                unpaired_read_duplicates: 2,
+                read_pair_duplicates: 2,
+                read_pair_optical_duplicates: 0,
+            }
+        );
+        assert!((metrics.percent_duplication() - 6.0 / 13.0).abs() < 1e-9);
+
+        let mut marked = open_test_file(&dst);
+        let mut fetched = marked.records();
+        let mut dups = Vec::new();
+        for rec in &records {
+            let marked_rec = fetched.next_rec().unwrap().unwrap();
+            assert_eq!(marked_rec.flag.unwrap() & !BAM_FDUP, rec.flag & !BAM_FDUP);
+            assert_eq!(marked_rec.pos, Some(rec.pos));
+            if marked_rec.flag.unwrap() & BAM_FDUP != 0 {
+                dups.push(String::from_utf8_lossy(marked_rec.read_name.as_ref().unwrap()).into_owned());
+            }
+        }
+        // `c` wins its group, `d` ends elsewhere. `h` scores lower than `g`.
+        assert_eq!(dups, ["a\0", "b\0", "f\0", "a\0", "b\0", "h\0", "b\0"]);
+
+        // Only Flags blocks are rewritten.
+        for field in Fields::iterator() {
+            let old = reader.file_meta.view_blocks(field);
+            let new = marked.file_meta.view_blocks(field);
+            assert_eq!(old.len(), new.len());
+            if *field == Fields::Flags {
+                continue;
+            }
+            for (old, new) in old.iter().zip(new.iter()) {
+                assert!(reader.block_data(old).unwrap() == marked.block_data(new).unwrap());
+            }
+        }
+    }
+}
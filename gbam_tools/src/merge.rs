@@ -0,0 +1,473 @@
+//! Merging of GBAM files, possibly of different samples. Read group ids
+//! colliding between inputs are renamed in @RG lines of the header and in RG
+//! tags of records, see `ReadGroupRenaming`.
+use std::borrow::Cow;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::io;
+use std::path::Path;
+
+use bam_tools::record::bamrawrecord::BAMRawRecord;
+use bam_tools::record::fields::Fields;
+use byteorder::{LittleEndian, WriteBytesExt};
+
+use crate::meta::{sam_header_text, SortOrder};
+use crate::reader::reader::Reader;
+use crate::reader::record::GbamRecord;
+use crate::tags::find_tag;
+use crate::tags::rewrite::{replace_record_tags, set_string_tag};
+use crate::writer::{Writer, WriterSettings};
+
+/// How read group ids of inputs are renamed by `merge()`. Inputs may share
+/// an id after renaming only if their @RG lines are identical, the lines
+/// are combined then.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum ReadGroupRenaming {
+    /// Ids are kept.
+    #[default]
+    Keep,
+    /// Ids found in more than one input get suffix `-<input index>` in every
+    /// input having them.
+    SuffixInputIndex,
+    /// Ids of input `i` found in map `i` are replaced by the mapped ones,
+    /// others are kept, as are ids of inputs without a map.
+    Map(Vec<HashMap<String, String>>),
+}
+
+/// Options of `merge()`.
+#[derive(Clone, Debug, Default)]
+pub struct MergeOptions {
+    pub read_groups: ReadGroupRenaming,
+    /// Records without RG tag get the read group of their input, if its
+    /// header has exactly one @RG line.
+    pub assign_sole_read_group: bool,
+}
+
+/// Read group ids of records of an input, as written.
+struct InputReadGroups {
+    renamed: HashMap<Vec<u8>, Vec<u8>>,
+    // For records without RG tag.
+    sole: Option<Vec<u8>>,
+}
+
+impl InputReadGroups {
+    /// Puts tags of `rec` with RG tag replaced into `tags`, returns false
+    /// if the read group is kept.
+    fn retag(&self, rec: &GbamRecord, tags: &mut Vec<u8>) -> io::Result<bool> {
+        let rec_tags = rec.tags.as_deref().unwrap_or_default();
+        let new_id = match find_tag(rec_tags, b"RG")? {
+            Some(value) => value.as_bytes().and_then(|id| self.renamed.get(id)),
+            None => self.sole.as_ref(),
+        };
+        match new_id {
+            Some(id) => {
+                tags.clear();
+                tags.extend_from_slice(rec_tags);
+                set_string_tag(tags, b"RG", id)?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+}
+
+/// Writes records of `inputs` into a new file at `path`, returns amount of
+/// records written. Coordinate sorted inputs are merged by coordinate, ties
+/// going in input order, others are concatenated and the output is
+/// unsorted. Inputs must have the same reference sequences and sort order,
+/// and every field has to be enabled in their parsing templates. Header is
+/// the one of the first input, with @RG lines of all inputs, renamed by
+/// `options`, following its @HD and @SQ lines. Other lines of later inputs
+/// are dropped. Codecs are taken from the first input. Source indices of
+/// records are kept if every input has them.
+pub fn merge<P: AsRef<Path>>(inputs: &mut [Reader], path: P, options: &MergeOptions) -> io::Result<u64> {
+    let first = match inputs.first() {
+        Some(reader) => reader.file_meta.clone(),
+        None => return Err(io::Error::new(io::ErrorKind::InvalidInput, "Nothing to merge")),
+    };
+    let all_fields: Vec<Fields> = Fields::iterator().copied().collect();
+    for (idx, reader) in inputs.iter().enumerate() {
+        let mismatch = |what: &str| {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Input {} differs from input 0 in {}", idx, what),
+            ))
+        };
+        if reader.file_meta.get_ref_seqs() != first.get_ref_seqs() {
+            return mismatch("reference sequences");
+        }
+        if reader.file_meta.get_sort_order() != first.get_sort_order() {
+            return mismatch("sort order");
+        }
+        if !reader.parsing_template.check_if_active(&all_fields) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("All fields have to be enabled in parsing template of input {} to merge.", idx),
+            ));
+        }
+    }
+    let (sam_header, read_groups) = merge_headers(inputs, options)?;
+
+    let sorted = first.get_sort_order() == SortOrder::Coordinate;
+    let settings = WriterSettings {
+        codecs: Fields::iterator().map(|field| *first.get_field_codec(field)).collect(),
+        ref_seqs: first.get_ref_seqs().clone(),
+        sam_header,
+        full_command: String::from("merge"),
+        is_sorted: sorted,
+        ..Default::default()
+    };
+    let mut writer = Writer::create(path, settings)?;
+    if !sorted {
+        writer.set_sort_order(SortOrder::Unsorted);
+    }
+    writer.set_source_index(inputs.iter().all(|reader| reader.file_meta.has_source_index()));
+
+    let mut cursors = vec![0; inputs.len()];
+    let mut heads: Vec<GbamRecord> = inputs.iter().map(|_| GbamRecord::default()).collect();
+    let mut queue = BinaryHeap::new();
+    for (idx, reader) in inputs.iter_mut().enumerate() {
+        if reader.num_records() > 0 {
+            reader.fill_record(0, &mut heads[idx])?;
+            queue.push(Reverse((merge_key(&heads[idx], sorted), idx)));
+        }
+    }
+    let mut bytes = Vec::new();
+    let mut tags = Vec::new();
+    let mut written = 0;
+    while let Some(Reverse((_, idx))) = queue.pop() {
+        let rec = &heads[idx];
+        rec.convert_to_bytes(&mut bytes);
+        let retagged = read_groups[idx].retag(rec, &mut tags).map_err(|err| {
+            io::Error::new(err.kind(), format!("Record {} of input {}: {}", cursors[idx], idx, err))
+        })?;
+        if retagged {
+            replace_record_tags(&mut bytes, rec.tags.as_ref().map_or(0, Vec::len), &tags)?;
+        }
+        // Without block_size.
+        writer.push_record_with_source_index(
+            &BAMRawRecord(Cow::Borrowed(&bytes[4..])),
+            rec.source_index(),
+            false,
+        )?;
+        written += 1;
+
+        cursors[idx] += 1;
+        if cursors[idx] < inputs[idx].num_records() {
+            inputs[idx].fill_record(cursors[idx], &mut heads[idx])?;
+            queue.push(Reverse((merge_key(&heads[idx], sorted), idx)));
+        }
+    }
+    writer.finish_with_summary(false)?;
+    Ok(written)
+}
+
+// Unmapped records go last, as in coordinate sorted files. Unsorted inputs
+// share a single key, so they follow each other.
+fn merge_key(rec: &GbamRecord, sorted: bool) -> (i32, i32) {
+    match rec.refid.unwrap() {
+        _ if !sorted => (0, 0),
+        ref_id if ref_id < 0 => (i32::MAX, i32::MAX),
+        ref_id => (ref_id, rec.pos.unwrap()),
+    }
+}
+
+/// Header bytes of the output, as in BAM, and read groups of records of
+/// each input.
+fn merge_headers(inputs: &[Reader], options: &MergeOptions) -> io::Result<(Vec<u8>, Vec<InputReadGroups>)> {
+    // (id, line) of @RG lines by input.
+    let rg_lines: Vec<Vec<(&[u8], &[u8])>> = inputs
+        .iter()
+        .map(|reader| {
+            header_lines(reader.file_meta.get_sam_header())
+                .filter_map(|line| Some((read_group_id(line)?, line)))
+                .collect()
+        })
+        .collect();
+    let mut id_counts: HashMap<&[u8], usize> = HashMap::new();
+    for lines in &rg_lines {
+        for &(id, _) in lines {
+            *id_counts.entry(id).or_default() += 1;
+        }
+    }
+
+    // (id, line, input) of @RG lines of the output.
+    let mut merged: Vec<(Vec<u8>, Vec<u8>, usize)> = Vec::new();
+    let mut read_groups = Vec::new();
+    for (idx, lines) in rg_lines.iter().enumerate() {
+        let mut renamed: HashMap<Vec<u8>, Vec<u8>> = HashMap::new();
+        match &options.read_groups {
+            ReadGroupRenaming::Keep => {}
+            ReadGroupRenaming::SuffixInputIndex => {
+                for &(id, _) in lines.iter().filter(|(id, _)| id_counts[id] > 1) {
+                    let new_id = format!("{}-{}", String::from_utf8_lossy(id), idx);
+                    renamed.insert(id.to_vec(), new_id.into_bytes());
+                }
+            }
+            ReadGroupRenaming::Map(maps) => {
+                for (old, new) in maps.get(idx).into_iter().flatten() {
+                    if new.is_empty() || new.bytes().any(|c| c.is_ascii_whitespace() || c == 0) {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            format!("Invalid read group id '{}' for input {}", new, idx),
+                        ));
+                    }
+                    renamed.insert(old.as_bytes().to_vec(), new.as_bytes().to_vec());
+                }
+            }
+        }
+
+        for &(id, line) in lines {
+            let new_id = renamed.get(id).map_or(id, Vec::as_slice);
+            let line = rename_read_group(line, new_id);
+            match merged.iter().find(|(other, ..)| other == new_id) {
+                Some((_, other_line, _)) if *other_line == line => {}
+                Some((_, _, other_idx)) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!(
+                            "Read group {} of input {} collides with one of input {}",
+                            String::from_utf8_lossy(new_id),
+                            idx,
+                            other_idx
+                        ),
+                    ))
+                }
+                None => merged.push((new_id.to_vec(), line, idx)),
+            }
+        }
+        let sole = match lines[..] {
+            [(id, _)] if options.assign_sole_read_group => {
+                Some(renamed.get(id).cloned().unwrap_or_else(|| id.to_vec()))
+            }
+            _ => None,
+        };
+        read_groups.push(InputReadGroups { renamed, sole });
+    }
+
+    let sam_header = inputs[0].file_meta.get_sam_header();
+    let is_head = |line: &&[u8]| line.starts_with(b"@HD") || line.starts_with(b"@SQ");
+    let mut text = Vec::new();
+    let head = header_lines(sam_header).filter(is_head);
+    let rest = header_lines(sam_header).filter(|line| !is_head(line) && read_group_id(line).is_none());
+    let rg = merged.iter().map(|(_, line, _)| line.as_slice());
+    for line in head.chain(rg).chain(rest) {
+        text.extend_from_slice(line);
+        text.push(b'\n');
+    }
+    let mut bytes = Vec::new();
+    bytes.write_u32::<LittleEndian>(text.len() as u32).unwrap();
+    bytes.extend_from_slice(&text);
+    // References follow the text.
+    let refs_start = (std::mem::size_of::<u32>() + sam_header_text(sam_header).len()).min(sam_header.len());
+    bytes.extend_from_slice(&sam_header[refs_start..]);
+    Ok((bytes, read_groups))
+}
+
+// Text may be padded with NULs.
+fn header_lines(sam_header: &[u8]) -> impl Iterator<Item = &[u8]> {
+    sam_header_text(sam_header)
+        .split(|&c| c == b'\n' || c == 0)
+        .map(|line| line.strip_suffix(b"\r").unwrap_or(line))
+        .filter(|line| !line.is_empty())
+}
+
+fn read_group_id(line: &[u8]) -> Option<&[u8]> {
+    line.strip_prefix(b"@RG\t")?
+        .split(|&c| c == b'\t')
+        .find_map(|tag| tag.strip_prefix(b"ID:"))
+}
+
+/// @RG `line` with its ID replaced by `id`.
+fn rename_read_group(line: &[u8], id: &[u8]) -> Vec<u8> {
+    let tags = line.split(|&c| c == b'\t').map(|tag| match tag.strip_prefix(b"ID:") {
+        Some(_) => [&b"ID:"[..], id].concat(),
+        None => tag.to_vec(),
+    });
+    tags.collect::<Vec<_>>().join(&b'\t')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{open_test_file, write_test_file, TestRecord};
+    use tempdir::TempDir;
+
+    const HEADER_A: &str = "@HD\tVN:1.6\tSO:coordinate\n\
+        @SQ\tSN:chr1\tLN:100\n\
+        @RG\tID:grp1\tSM:a\n\
+        @PG\tID:test\n";
+    const HEADER_B: &str = "@HD\tVN:1.6\tSO:coordinate\n\
+        @RG\tID:grp1\tSM:b\n\
+        @RG\tID:grp2\tSM:b\n\
+        @CO\tsecond input\n";
+
+    fn record(pos: i32, tags: &[u8]) -> TestRecord {
+        TestRecord {
+            tags: tags.to_vec(),
+            ..TestRecord::new(pos / 400, pos, &format!("read{}", pos))
+        }
+    }
+
+    /// Input A has even positions, all in grp1, one without RG tag. Input B
+    /// has odd positions, in grp1 and grp2.
+    fn write_inputs(dir: &Path, header_b: &str) -> Vec<Reader> {
+        let a: Vec<TestRecord> = (0..500)
+            .map(|i| match i {
+                7 => record(2 * i, b"NMC\x01"),
+                _ => record(2 * i, b"NMC\x01RGZgrp1\0"),
+            })
+            .collect();
+        let b: Vec<TestRecord> = (0..400)
+            .map(|i| match i % 2 {
+                0 => record(2 * i + 1, b"RGZgrp1\0XAA+"),
+                _ => record(2 * i + 1, b"RGZgrp2\0"),
+            })
+            .collect();
+        write_test_file(&dir.join("a.gbam"), HEADER_A, &a);
+        write_test_file(&dir.join("b.gbam"), header_b, &b);
+        vec![open_test_file(&dir.join("a.gbam")), open_test_file(&dir.join("b.gbam"))]
+    }
+
+    /// (pos, RG, other tags) of records of file at `path`.
+    fn records(path: &Path) -> Vec<(i32, Option<String>, Vec<u8>)> {
+        let mut reader = open_test_file(path);
+        let mut records = reader.records();
+        let mut res = Vec::new();
+        while let Some(rec) = records.next_rec().unwrap() {
+            let mut tags = rec.tags.clone().unwrap();
+            let rg = rec.get_tag(b"RG").unwrap();
+            let rg = rg.map(|value| String::from_utf8_lossy(value.as_bytes().unwrap()).into_owned());
+            crate::tags::rewrite::remove_tag(&mut tags, b"RG").unwrap();
+            res.push((rec.pos.unwrap(), rg, tags));
+        }
+        res
+    }
+
+    fn header(path: &Path) -> String {
+        String::from_utf8(sam_header_text(open_test_file(path).file_meta.get_sam_header()).to_vec()).unwrap()
+    }
+
+    #[test]
+    fn test_merge_suffix() {
+        let dir = TempDir::new("gbam_merge").unwrap();
+        let mut inputs = write_inputs(dir.path(), HEADER_B);
+        let out = dir.path().join("merged.gbam");
+        let err = merge(&mut inputs, &out, &MergeOptions::default()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(err.to_string(), "Read group grp1 of input 1 collides with one of input 0");
+
+        let options = MergeOptions {
+            read_groups: ReadGroupRenaming::SuffixInputIndex,
+            assign_sole_read_group: true,
+        };
+        assert_eq!(merge(&mut inputs, &out, &options).unwrap(), 900);
+        assert_eq!(
+            header(&out),
+            "@HD\tVN:1.6\tSO:coordinate\n\
+            @SQ\tSN:chr1\tLN:100\n\
+            @RG\tID:grp1-0\tSM:a\n\
+            @RG\tID:grp1-1\tSM:b\n\
+            @RG\tID:grp2\tSM:b\n\
+            @PG\tID:test\n"
+        );
+        let reader = open_test_file(&out);
+        assert_eq!(reader.file_meta.get_sort_order(), SortOrder::Coordinate);
+        assert_eq!(reader.file_meta.get_ref_seqs(), inputs[0].file_meta.get_ref_seqs());
+
+        let merged = records(&out);
+        let positions: Vec<i32> = merged.iter().map(|(pos, ..)| *pos).collect();
+        let mut expected: Vec<i32> = (0..500).map(|i| 2 * i).chain((0..400).map(|i| 2 * i + 1)).collect();
+        expected.sort_unstable();
+        assert_eq!(positions, expected);
+        for (pos, rg, tags) in merged {
+            let (want_rg, want_tags) = match (pos % 2, pos / 2 % 2) {
+                (0, _) => ("grp1-0", &b"NMC\x01"[..]),
+                (_, 0) => ("grp1-1", &b"XAA+"[..]),
+                _ => ("grp2", &b""[..]),
+            };
+            assert_eq!((rg.as_deref(), &tags[..]), (Some(want_rg), want_tags), "{}", pos);
+        }
+    }
+
+    #[test]
+    fn test_merge_map() {
+        let dir = TempDir::new("gbam_merge").unwrap();
+        let mut inputs = write_inputs(dir.path(), HEADER_B);
+        let out = dir.path().join("merged.gbam");
+        let map = |pairs: &[(&str, &str)]| -> HashMap<String, String> {
+            pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+        };
+        let options = MergeOptions {
+            read_groups: ReadGroupRenaming::Map(vec![HashMap::new(), map(&[("grp1", "b1"), ("other", "x")])]),
+            assign_sole_read_group: false,
+        };
+        merge(&mut inputs, &out, &options).unwrap();
+        assert!(header(&out).contains("@RG\tID:grp1\tSM:a\n@RG\tID:b1\tSM:b\n@RG\tID:grp2\tSM:b\n@PG"));
+        let merged = records(&out);
+        assert_eq!(merged.iter().filter(|(_, rg, _)| rg.is_none()).count(), 1);
+        assert_eq!(merged.iter().filter(|(_, rg, _)| rg.as_deref() == Some("b1")).count(), 200);
+        assert_eq!(merged.iter().filter(|(_, rg, _)| rg.as_deref() == Some("grp1")).count(), 499);
+
+        let options = MergeOptions {
+            read_groups: ReadGroupRenaming::Map(vec![map(&[("grp1", "grp2")])]),
+            ..Default::default()
+        };
+        assert!(merge(&mut inputs, &out, &options).is_err());
+        let options = MergeOptions {
+            read_groups: ReadGroupRenaming::Map(vec![map(&[("grp1", "a b")])]),
+            ..Default::default()
+        };
+        assert!(merge(&mut inputs, &out, &options).is_err());
+    }
+
+    #[test]
+    fn test_merge_identical_read_groups() {
+        let dir = TempDir::new("gbam_merge").unwrap();
+        let header_b = "@HD\tVN:1.6\tSO:coordinate\n@RG\tID:grp1\tSM:a\n@RG\tID:grp2\tSM:b\n";
+        let mut inputs = write_inputs(dir.path(), header_b);
+        let out = dir.path().join("merged.gbam");
+        merge(&mut inputs, &out, &MergeOptions::default()).unwrap();
+        assert_eq!(
+            header(&out),
+            "@HD\tVN:1.6\tSO:coordinate\n@SQ\tSN:chr1\tLN:100\n\
+            @RG\tID:grp1\tSM:a\n@RG\tID:grp2\tSM:b\n@PG\tID:test\n"
+        );
+        let merged = records(&out);
+        assert_eq!(merged.iter().filter(|(_, rg, _)| rg.as_deref() == Some("grp1")).count(), 699);
+    }
+
+    #[test]
+    fn test_merge_unsorted() {
+        let dir = TempDir::new("gbam_merge").unwrap();
+        let header = "@RG\tID:grp1\tSM:a\n";
+        let a: Vec<TestRecord> = (0..300).rev().map(|i| record(i, b"RGZgrp1\0")).collect();
+        let b: Vec<TestRecord> = (0..200).map(|i| record(i, b"")).collect();
+        write_test_file(&dir.path().join("a.gbam"), header, &a);
+        write_test_file(&dir.path().join("b.gbam"), header, &b);
+        let mut inputs = vec![
+            open_test_file(&dir.path().join("a.gbam")),
+            open_test_file(&dir.path().join("b.gbam")),
+        ];
+        let out = dir.path().join("merged.gbam");
+        let options = MergeOptions {
+            read_groups: ReadGroupRenaming::SuffixInputIndex,
+            assign_sole_read_group: true,
+        };
+        merge(&mut inputs, &out, &options).unwrap();
+        assert_eq!(open_test_file(&out).file_meta.get_sort_order(), SortOrder::Unsorted);
+        let merged = records(&out);
+        let expected: Vec<(i32, Option<String>, Vec<u8>)> = (0..300)
+            .rev()
+            .map(|pos| (pos, Some(String::from("grp1-0")), Vec::new()))
+            .chain((0..200).map(|pos| (pos, Some(String::from("grp1-1")), Vec::new())))
+            .collect();
+        assert_eq!(merged, expected);
+
+        let sorted = dir.path().join("sorted.gbam");
+        write_test_file(&sorted, HEADER_A, &b);
+        inputs.push(open_test_file(&sorted));
+        let err = merge(&mut inputs, &out, &options).unwrap_err();
+        assert_eq!(err.to_string(), "Input 2 differs from input 0 in sort order");
+    }
+}
@@ -1,14 +1,27 @@
 use super::GBAM_MAGIC;
+use crate::bloom::BloomFilter;
+use crate::column_transform::ColumnTransform;
+use crate::encryption::FieldEncryption;
+use crate::error::{CodecError, MetaError};
+use crate::linear_index::LinearIndex;
+use crate::reader::reader::meta_read_start;
+use crate::source_index::SOURCE_INDEX_EXTENSION;
 use crate::writer::FIELD_CODEC_MAP;
-use bam_tools::record::fields::{field_item_size, Fields, FIELDS_NUM};
+use crate::{MAX_BLOCK_SIZE_LIMIT, MAX_RECORD_SIZE, MIN_BLOCK_SIZE_LIMIT, SIZE_LIMIT};
+use bam_tools::record::fields::{
+    field_item_size, field_type, var_size_field_to_index, FieldType, Fields, FIELDS_NUM,
+};
+use byteorder::{LittleEndian, ReadBytesExt};
 use serde::ser::{SerializeMap, Serializer};
 use serde::{Deserialize, Deserializer, Serialize};
 use std::marker::PhantomData;
+use std::ops::Range;
 
 use serde::de::{MapAccess, Visitor};
 // use serde::de::{Deserialize, Deserializer};
 // use serde_json::Result;
-use std::collections::HashMap;
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
 
 /// Holds data related to GBAM file: gbam version, seekpos to meta.
 #[derive(Serialize, Deserialize, Clone, Debug, Default)]
@@ -19,6 +32,10 @@ pub(crate) struct FileInfo {
     pub crc32: u32,
     pub is_sorted: bool,
     pub creation_command: String,
+    /// Bytes after file info reserved for meta, see
+    /// `Writer::reserve_meta_bytes()`. Blocks start after them.
+    #[serde(default)]
+    pub reserved_meta: u64,
 }
 
 impl FileInfo {
@@ -36,13 +53,53 @@ impl FileInfo {
             crc32,
             creation_command: full_command,
             is_sorted,
+            reserved_meta: 0,
         }
     }
+
+    /// Meta is in the space reserved after file info, not after blocks.
+    pub fn meta_in_reserved_space(&self) -> bool {
+        self.seekpos < (FILE_INFO_SIZE as u64).saturating_add(self.reserved_meta)
+    }
+
+    /// Bytes blocks may take: after file info and reserved space, up to the
+    /// prefix of meta unless meta is in reserved space.
+    pub fn data_range(&self) -> Range<u64> {
+        let start = (FILE_INFO_SIZE as u64).saturating_add(self.reserved_meta);
+        if self.meta_in_reserved_space() {
+            start..u64::MAX
+        } else {
+            start..meta_read_start(self.seekpos)
+        }
+    }
+}
+
+impl FileInfo {
+    /// Serializes into JSON padded with zeros to `FILE_INFO_SIZE`.
+    pub fn to_padded_bytes(&self) -> std::io::Result<Vec<u8>> {
+        let mut bytes = serde_json::to_vec(self)?;
+        if bytes.len() >= FILE_INFO_SIZE {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "File info JSON is {} bytes long, it must be shorter than {}. Creation command is too long?",
+                    bytes.len(),
+                    FILE_INFO_SIZE
+                ),
+            ));
+        }
+        bytes.resize(FILE_INFO_SIZE, 0);
+        Ok(bytes)
+    }
 }
 
 /// Should be enough for JSON.
 pub const FILE_INFO_SIZE: usize = 1000;
 
+/// Meta JSON is preceded by its crc32 (u32) and length (u64), so it can be
+/// found if file info was never written.
+pub const META_PREFIX_SIZE: usize = 12;
+
 /// Type of encoding used in GBAM writer
 /// TODO: use MessagePack or another compact form of serialization.
 #[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
@@ -61,6 +118,50 @@ pub enum Codecs {
     NoCompression,
 }
 
+impl Codecs {
+    /// Cargo feature which compiles the codec in, None for codecs which are
+    /// always available.
+    pub fn cargo_feature(self) -> Option<&'static str> {
+        match self {
+            Codecs::Brotli => Some("brotli"),
+            Codecs::Zstd => Some("zstd"),
+            Codecs::Xz => Some("xz"),
+            Codecs::Gzip | Codecs::Lz4 | Codecs::NoCompression => None,
+        }
+    }
+
+    pub fn is_available(self) -> bool {
+        match self {
+            Codecs::Brotli => cfg!(feature = "brotli"),
+            Codecs::Zstd => cfg!(feature = "zstd"),
+            Codecs::Xz => cfg!(feature = "xz"),
+            Codecs::Gzip | Codecs::Lz4 | Codecs::NoCompression => true,
+        }
+    }
+
+    /// Bound of ratio of decompressed to compressed size of a block, see
+    /// `BlockMeta::max_uncompressed_size()`.
+    pub fn max_ratio(self) -> u64 {
+        match self {
+            Codecs::NoCompression => 1,
+            Codecs::Lz4 => 255,
+            Codecs::Gzip => 1_032,
+            Codecs::Zstd | Codecs::Xz => 32_768,
+            // Meta-blocks of repeats take a few bytes each.
+            Codecs::Brotli => 1 << 22,
+        }
+    }
+
+    /// Fails with `CodecError::Unavailable` if the codec is compiled out.
+    pub fn check_available(self, field: Fields) -> Result<(), CodecError> {
+        if self.is_available() {
+            Ok(())
+        } else {
+            Err(CodecError::Unavailable { codec: self, field })
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 /// Currently block stats only for RefID or POS are supported.
 pub struct Stat {
@@ -96,6 +197,65 @@ impl Default for Stat {
     }
 }
 
+/// Order of records in GBAM file.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum SortOrder {
+    /// Nothing is known about records order.
+    #[default]
+    Unknown,
+    Unsorted,
+    /// Sorted by read name (`SO:queryname`).
+    QueryName,
+    /// Records with the same read name are adjacent, groups are in no particular order (`GO:query`).
+    Collated,
+    /// Sorted by RefID and POS (`SO:coordinate`).
+    Coordinate,
+}
+
+impl SortOrder {
+    /// Extracts sort order from the @HD line of SAM header text.
+    pub fn from_sam_header(text: &[u8]) -> Self {
+        let text = String::from_utf8_lossy(text);
+        let hd_line = match text.lines().find(|line| line.starts_with("@HD")) {
+            Some(line) => line,
+            None => return SortOrder::Unknown,
+        };
+        let mut so = None;
+        let mut go = None;
+        for field in hd_line.split('\t') {
+            if let Some(val) = field.strip_prefix("SO:") {
+                so = Some(val);
+            } else if let Some(val) = field.strip_prefix("GO:") {
+                go = Some(val);
+            }
+        }
+        match (so, go) {
+            (Some("queryname"), _) => SortOrder::QueryName,
+            (Some("coordinate"), _) => SortOrder::Coordinate,
+            (_, Some("query")) => SortOrder::Collated,
+            (Some("unsorted"), _) => SortOrder::Unsorted,
+            _ => SortOrder::Unknown,
+        }
+    }
+
+    /// Records sharing a read name are guaranteed to be adjacent.
+    pub fn is_name_grouped(&self) -> bool {
+        matches!(self, SortOrder::QueryName | SortOrder::Collated)
+    }
+}
+
+/// Encoding of RawSequence column blocks.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum SeqEncoding {
+    /// BAM 4-bit encoding, as is.
+    #[default]
+    Nibble,
+    /// ACGT-only sequences are repacked at 2 bits per base, see `seq_packing`.
+    TwoBit,
+    /// Mapped reads store differences from a reference, see `ref_compression`.
+    Reference,
+}
+
 #[derive(Serialize, Deserialize, Clone, Default)]
 pub struct BlockMeta {
     pub seekpos: u64,
@@ -103,6 +263,91 @@ pub struct BlockMeta {
     pub block_size: u32,
     pub uncompressed_size: u64,
     pub stats: Option<Stat>,
+    /// Read names in the block, ReadName blocks only.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bloom: Option<BloomFilter>,
+    /// Transform applied before compression, Flags, Mapq and index blocks only.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub transform: Option<ColumnTransform>,
+    /// Stats of write time collectors by their names, see
+    /// `stats::StatsCollector`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub extra_stats: BTreeMap<String, u64>,
+    /// Offset of the field's values in decompressed data of a block shared
+    /// by a group of fields, see `Writer::set_field_group()`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stripe: Option<u64>,
+    /// Block is written uncompressed, since the field's codec made it larger.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub stored: bool,
+}
+
+impl BlockMeta {
+    /// Codec the block is decompressed with, `field_codec` unless the block
+    /// is stored.
+    pub fn codec(&self, field_codec: Codecs) -> Codecs {
+        if self.stored {
+            Codecs::NoCompression
+        } else {
+            field_codec
+        }
+    }
+
+    /// Most decompressed bytes the block may claim with `field_codec`: what
+    /// the codec yields from its size, and at least `size_limit` of the
+    /// field unless the block is stored, but never more than `size_limit`
+    /// plus `MAX_RECORD_SIZE`, which no block written holds. Readers size
+    /// buffers by the claim.
+    pub fn max_uncompressed_size(&self, field_codec: Codecs, size_limit: usize) -> u64 {
+        let size = u64::from(self.block_size);
+        match self.codec(field_codec) {
+            Codecs::NoCompression => size,
+            codec => size
+                .saturating_mul(codec.max_ratio())
+                .max(size_limit as u64)
+                .min((size_limit + MAX_RECORD_SIZE) as u64),
+        }
+    }
+
+    /// Stat stored by collector `name`, None if it wasn't collected.
+    pub fn extra_stat(&self, name: &str) -> Option<u64> {
+        self.extra_stats.get(name).copied()
+    }
+
+    /// Values of `field` in decompressed `data` of the block, which holds
+    /// values of the other fields of its group too if it's shared.
+    pub fn stripe_of(&self, field: &Fields, mut data: Vec<u8>) -> std::io::Result<Vec<u8>> {
+        if let Some(offset) = self.stripe {
+            let len = self.numitems as u64 * field_item_size(field).unwrap_or(0) as u64;
+            let end = offset.checked_add(len).filter(|&end| end <= data.len() as u64).ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!(
+                        "Stripe of field {} of {} bytes at {} is past the end of its block of {} bytes",
+                        field,
+                        len,
+                        offset,
+                        data.len()
+                    ),
+                )
+            })?;
+            data.truncate(end as usize);
+            data.drain(..offset as usize);
+        }
+        Ok(data)
+    }
+}
+
+/// Records of an index block of a variable sized field and the data blocks
+/// its end offsets point into. Offsets restart from 0 at the first record of
+/// every data block.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct IndexSpan {
+    pub first_record: u64,
+    pub num_records: u32,
+    /// Data block numbers with their first records. The first data block
+    /// may start before the index block and the last one end after it.
+    pub data_blocks: Vec<(u32, u64)>,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -110,6 +355,19 @@ pub struct FieldMeta {
     item_size: Option<u32>, // NONE for variable sized fields
     codec: Codecs,
     blocks: Vec<BlockMeta>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    encryption: Option<FieldEncryption>,
+    // Sum of numitems of blocks, recorded when the file is finished. Files
+    // written before it was recorded have None.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    numitems: Option<u64>,
+    // Index blocks of variable sized fields, by index block number.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    index_spans: Vec<IndexSpan>,
+    // Bytes at which blocks were cut, see `Writer::set_block_size_limit()`.
+    // Files written before it was recorded have None.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    block_size_limit: Option<u32>,
 }
 
 impl FieldMeta {
@@ -118,6 +376,10 @@ impl FieldMeta {
             item_size: field_item_size(field).map(|v| v as u32), // TODO
             codec,
             blocks: Vec::<BlockMeta>::new(),
+            encryption: None,
+            numitems: None,
+            index_spans: Vec::new(),
+            block_size_limit: None,
         }
     }
 }
@@ -128,6 +390,10 @@ impl Default for FieldMeta {
             item_size: None,
             codec: Codecs::Gzip,
             blocks: Vec::<BlockMeta>::new(),
+            encryption: None,
+            numitems: None,
+            index_spans: Vec::new(),
+            block_size_limit: None,
         }
     }
 }
@@ -142,6 +408,35 @@ pub struct FileMeta {
     field_to_meta: [FieldMeta; FIELDS_NUM],
     sam_header: Vec<u8>,
     name_to_ref_id: Vec<(String, u32)>,
+    // Files written before sort order was recorded have it as Unknown.
+    #[serde(default)]
+    sort_order: SortOrder,
+    #[serde(default)]
+    seq_encoding: SeqEncoding,
+    // Set if blocks of fixed sized fields hold exactly this many records,
+    // except the last one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    rows_per_block: Option<u32>,
+    // Summaries of write time collectors, by collector name.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    analytics: BTreeMap<String, Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    linear_index: Option<LinearIndex>,
+    // Number of the first record of the trailing run of unplaced records,
+    // number of records if there is none. Unknown if None.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    unmapped_tail_start: Option<u64>,
+    // MD5 of reference sequences RawSequence is encoded against, by
+    // reference id. Empty for sequences missing from the reference.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    reference_md5s: Vec<String>,
+    // Meta of features added in minor format versions, by name. Kept as
+    // is by readers which don't know them, see `GBAM_VERSION`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    extensions: BTreeMap<String, Value>,
+    // Index fields left out, readers derive them from other columns.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    derived_indices: Vec<Fields>,
 }
 
 impl FileMeta {
@@ -149,12 +444,118 @@ impl FileMeta {
         &self.name_to_ref_id
     }
 
+    /// Replaces reference sequences, along with header bytes listing them
+    /// (laid out as in BAM, see `bam_tools::Reader::read_header()`).
+    pub fn set_ref_seqs(&mut self, ref_seqs: Vec<(String, u32)>, sam_header: Vec<u8>) {
+        self.name_to_ref_id = ref_seqs;
+        self.sam_header = sam_header;
+    }
+
+    pub fn get_sort_order(&self) -> SortOrder {
+        self.sort_order
+    }
+
+    pub fn set_sort_order(&mut self, sort_order: SortOrder) {
+        self.sort_order = sort_order;
+    }
+
+    pub fn get_rows_per_block(&self) -> Option<u32> {
+        self.rows_per_block
+    }
+
+    pub fn set_rows_per_block(&mut self, rows_per_block: Option<u32>) {
+        self.rows_per_block = rows_per_block;
+    }
+
+    pub fn get_seq_encoding(&self) -> SeqEncoding {
+        self.seq_encoding
+    }
+
+    pub fn set_seq_encoding(&mut self, seq_encoding: SeqEncoding) {
+        self.seq_encoding = seq_encoding;
+    }
+
+    /// Set for files written with `Writer::set_reference()`.
+    pub fn get_reference_md5s(&self) -> &[String] {
+        &self.reference_md5s
+    }
+
+    pub(crate) fn set_reference_md5s(&mut self, md5s: Vec<String>) {
+        self.reference_md5s = md5s;
+    }
+
+    /// Set for RawSeqLen in files written with
+    /// `Writer::set_derived_seq_index()`, the field has no items.
+    pub fn is_index_derived(&self, field: &Fields) -> bool {
+        self.derived_indices.contains(field)
+    }
+
+    pub(crate) fn set_index_derived(&mut self, field: Fields, derived: bool) {
+        self.derived_indices.retain(|derived| *derived != field);
+        if derived {
+            self.derived_indices.push(field);
+        }
+    }
+
+    /// Meta stored under `name` by a feature which keeps it in extensions.
+    pub fn get_extension(&self, name: &str) -> Option<&Value> {
+        self.extensions.get(name)
+    }
+
+    pub fn set_extension(&mut self, name: &str, value: Value) {
+        self.extensions.insert(name.to_owned(), value);
+    }
+
+    pub fn remove_extension(&mut self, name: &str) -> Option<Value> {
+        self.extensions.remove(name)
+    }
+
+    /// Set for files written with `Writer::set_source_index()`.
+    pub fn has_source_index(&self) -> bool {
+        self.extensions.contains_key(SOURCE_INDEX_EXTENSION)
+    }
+
+    /// Names of all extensions, including ones unknown to this build.
+    pub fn extension_names(&self) -> impl Iterator<Item = &str> {
+        self.extensions.keys().map(String::as_str)
+    }
+
+    /// Set for coordinate sorted files written with `Writer::set_linear_index()`.
+    pub fn get_linear_index(&self) -> Option<&LinearIndex> {
+        self.linear_index.as_ref()
+    }
+
+    pub fn set_linear_index(&mut self, linear_index: Option<LinearIndex>) {
+        self.linear_index = linear_index;
+    }
+
+    /// Number of the first record of the trailing run of unplaced records
+    /// (RefID -1), or number of records if the file doesn't end with one.
+    /// Set by writers collecting RefID stats, None for other files.
+    pub fn get_unmapped_tail_start(&self) -> Option<u64> {
+        self.unmapped_tail_start
+    }
+
+    pub fn set_unmapped_tail_start(&mut self, start: Option<u64>) {
+        self.unmapped_tail_start = start;
+    }
+
     #[allow(dead_code)]
     pub fn get_sam_header(&self) -> &[u8] {
         &self.sam_header[..]
     }
 }
 
+/// Returns SAM header text from header bytes stored in meta (`l_text` followed by text).
+pub(crate) fn sam_header_text(sam_header: &[u8]) -> &[u8] {
+    if sam_header.len() < std::mem::size_of::<u32>() {
+        return &[];
+    }
+    let l_text = (&sam_header[..4]).read_u32::<LittleEndian>().unwrap() as usize;
+    let end = std::cmp::min(4 + l_text, sam_header.len());
+    &sam_header[4..end]
+}
+
 // To make metadata easier to read, convert to json where fields are represented
 // as strings with their names, not numbers in enum.
 
@@ -165,11 +566,12 @@ fn serialize_field_to_meta<S>(
 where
     S: Serializer,
 {
-    let mut map = FieldMetaMap(HashMap::new());
+    // Written in fields order, so the same data always gives the same bytes.
+    let mut map = serializer.serialize_map(Some(FIELDS_NUM))?;
     for field in Fields::iterator() {
-        map.0.insert(*field, meta[*field as usize].clone());
+        map.serialize_entry(&field.to_string(), &meta[*field as usize])?;
     }
-    map.serialize(serializer)
+    map.end()
 }
 
 fn from_str<'de, D>(deserializer: D) -> Result<[FieldMeta; FIELDS_NUM], D::Error>
@@ -180,7 +582,8 @@ where
     let mut field_to_meta: [FieldMeta; FIELDS_NUM] = Default::default();
 
     for field in Fields::iterator() {
-        field_to_meta[*field as usize] = map.0.remove(field).unwrap();
+        let missing = || <D::Error as serde::de::Error>::custom(format!("missing meta of field {}", field));
+        field_to_meta[*field as usize] = map.0.remove(field).ok_or_else(missing)?;
     }
 
     Ok(field_to_meta)
@@ -195,9 +598,12 @@ impl Serialize for FieldMetaMap {
     where
         S: Serializer,
     {
+        // In fields order, not in hash order.
         let mut map = serializer.serialize_map(Some(self.0.len()))?;
-        for (k, v) in &self.0 {
-            map.serialize_entry(&k.to_string(), &v)?;
+        for field in Fields::iterator() {
+            if let Some(v) = self.0.get(field) {
+                map.serialize_entry(&field.to_string(), v)?;
+            }
         }
         map.end()
     }
@@ -262,8 +668,17 @@ impl FileMeta {
 
         FileMeta {
             field_to_meta: map,
+            sort_order: SortOrder::from_sam_header(sam_header_text(&sam_header)),
             sam_header,
             name_to_ref_id: ref_seqs,
+            seq_encoding: SeqEncoding::Nibble,
+            rows_per_block: None,
+            analytics: BTreeMap::new(),
+            linear_index: None,
+            unmapped_tail_start: None,
+            reference_md5s: Vec::new(),
+            extensions: BTreeMap::new(),
+            derived_indices: Vec::new(),
         }
     }
 
@@ -281,7 +696,273 @@ impl FileMeta {
         &self.field_to_meta[*field as usize].item_size
     }
 
+    /// Number of items in blocks of `field`.
+    pub fn count_items(&self, field: &Fields) -> u64 {
+        self.view_blocks(field)
+            .iter()
+            .map(|block| u64::from(block.numitems))
+            .sum()
+    }
+
+    /// Records current number of items of every field, to be checked by
+    /// `check_record_counts()` on open.
+    pub(crate) fn record_item_totals(&mut self) {
+        for field in Fields::iterator() {
+            self.field_to_meta[*field as usize].numitems = Some(self.count_items(field));
+        }
+    }
+
+    /// Records index spans of every variable sized field with an index
+    /// column, to be used by readers and checked by `check_record_counts()`.
+    pub(crate) fn record_index_spans(&mut self) {
+        for field in Fields::iterator() {
+            let spans = match field_type(field) {
+                FieldType::VariableSized => self.index_spans(field),
+                FieldType::FixedSized => Vec::new(),
+            };
+            self.field_to_meta[*field as usize].index_spans = spans;
+        }
+    }
+
+    /// Index spans of variable sized `field` recorded by the writer. Empty
+    /// for files written before they were recorded, for fields left out and
+    /// for fields with derived index.
+    pub fn get_index_spans(&self, field: &Fields) -> &[IndexSpan] {
+        &self.field_to_meta[*field as usize].index_spans
+    }
+
+    // Pairs index blocks of `field` with its data blocks by their items.
+    fn index_spans(&self, field: &Fields) -> Vec<IndexSpan> {
+        let index = var_size_field_to_index(field);
+        if self.is_index_derived(&index) {
+            return Vec::new();
+        }
+        let data = self.view_blocks(field);
+        let mut spans = Vec::new();
+        let (mut first_record, mut data_num, mut data_start) = (0, 0, 0);
+        for block in self.view_blocks(&index) {
+            let end = first_record + u64::from(block.numitems);
+            let mut data_blocks = Vec::new();
+            while data_num < data.len() && data_start < end {
+                let data_end = data_start + u64::from(data[data_num].numitems);
+                if data_end > first_record {
+                    data_blocks.push((data_num as u32, data_start));
+                }
+                // Data block goes on in the next index block.
+                if data_end > end {
+                    break;
+                }
+                data_start = data_end;
+                data_num += 1;
+            }
+            spans.push(IndexSpan {
+                first_record,
+                num_records: block.numitems,
+                data_blocks,
+            });
+            first_record = end;
+        }
+        spans
+    }
+
+    /// First record of every block of `field`. Data blocks of variable sized
+    /// fields are located by index spans, if they were recorded.
+    pub fn block_starts(&self, field: &Fields) -> Vec<u64> {
+        let mut starts: Vec<u64> = self
+            .view_blocks(field)
+            .iter()
+            .scan(0, |next, block| {
+                let start = *next;
+                *next += u64::from(block.numitems);
+                Some(start)
+            })
+            .collect();
+        for span in self.get_index_spans(field) {
+            for &(block_num, first_record) in &span.data_blocks {
+                if let Some(start) = starts.get_mut(block_num as usize) {
+                    *start = first_record;
+                }
+            }
+        }
+        starts
+    }
+
+    /// Fails if a block was never written. Blocks are recorded by their
+    /// number as they come from compressor, later ones may be recorded first
+    /// and leave default placeholders before them. No written block starts
+    /// at offset 0, which holds file info, so remaining placeholders are
+    /// told apart by it.
+    pub(crate) fn check_no_missing_blocks(&self) -> std::io::Result<()> {
+        for field in Fields::iterator() {
+            if let Some(block_num) = self.view_blocks(field).iter().position(|block| block.seekpos == 0) {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("Block {} of field {} was never written", block_num, field),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Fails if blocks of a field hold other number of items than recorded
+    /// for it, if a non-empty field has other number of records than RefID,
+    /// or if recorded index spans don't pair index and data blocks of a
+    /// field. Otherwise readers would silently pair values of different
+    /// records.
+    pub fn check_record_counts(&self) -> std::io::Result<()> {
+        let records = self.count_items(&Fields::RefID);
+        for field in Fields::iterator() {
+            let items = self.count_items(field);
+            if let Some(total) = self.field_to_meta[*field as usize].numitems {
+                if total != items {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        format!(
+                            "Blocks of field {} hold {} items, but meta records {}",
+                            field, items, total
+                        ),
+                    ));
+                }
+            }
+            // Fields left out by write template have no items.
+            if items != 0 && items != records {
+                return Err(MetaError::MisalignedFields {
+                    field: *field,
+                    items,
+                    records,
+                }
+                .into());
+            }
+            let spans = self.get_index_spans(field);
+            let variable = matches!(field_type(field), FieldType::VariableSized);
+            if !spans.is_empty() && (!variable || spans != self.index_spans(field)) {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("Index spans of field {} don't match its blocks and index blocks", field),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Fails if item sizes of fields differ from the ones of the format, if
+    /// block size limits are out of bounds, or if a block claims more
+    /// decompressed bytes than its codec yields, see
+    /// `BlockMeta::max_uncompressed_size()`.
+    pub fn check_sizes(&self) -> std::io::Result<()> {
+        for field in Fields::iterator() {
+            let size_limit = self.get_block_size_limit(field);
+            if !(MIN_BLOCK_SIZE_LIMIT..=MAX_BLOCK_SIZE_LIMIT).contains(&size_limit) {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("Field {} has block size limit {} out of bounds", field, size_limit),
+                ));
+            }
+            let item_size = *self.get_field_size(field);
+            let expected = field_item_size(field).map(|size| size as u32);
+            if item_size != expected {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("Field {} has item size {:?}, not {:?}", field, item_size, expected),
+                ));
+            }
+            let codec = *self.get_field_codec(field);
+            for (block, block_meta) in self.view_blocks(field).iter().enumerate() {
+                let limit = block_meta.max_uncompressed_size(codec, size_limit);
+                if block_meta.uncompressed_size > limit {
+                    return Err(MetaError::OversizedBlock {
+                        field: *field,
+                        block: block as u64,
+                        size: block_meta.uncompressed_size,
+                        limit,
+                    }
+                    .into());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that blocks of all fields lie within `data`, see
+    /// `FileInfo::data_range()`, and don't overlap, in whatever order they
+    /// were written.
+    pub fn check_block_ranges(&self, data: Range<u64>) -> std::io::Result<()> {
+        let mut ranges = Vec::new();
+        for field in Fields::iterator() {
+            for (block, block_meta) in self.view_blocks(field).iter().enumerate() {
+                let start = block_meta.seekpos;
+                let end = start.saturating_add(u64::from(block_meta.block_size));
+                let block = block as u64;
+                if start < data.start || end > data.end {
+                    return Err(MetaError::BlockOutsideData {
+                        field: *field,
+                        block,
+                        start,
+                        end,
+                        data_start: data.start,
+                        data_end: data.end,
+                    }
+                    .into());
+                }
+                ranges.push((start, end, *field, block, block_meta.stripe.is_some()));
+            }
+        }
+        // Any overlap shows between neighbours once sorted by start. Blocks
+        // shared by grouped fields take the very same bytes.
+        ranges.sort_unstable_by_key(|&(start, end, ..)| (start, end));
+        for pair in ranges.windows(2) {
+            let (other_start, other_end, other_field, other_block, other_shared) = pair[0];
+            let (start, end, field, block, shared) = pair[1];
+            let same_stripes = shared && other_shared && (start, end) == (other_start, other_end);
+            if start < other_end && !same_stripes {
+                return Err(MetaError::OverlappingBlocks {
+                    field,
+                    block,
+                    other_field,
+                    other_block,
+                }
+                .into());
+            }
+        }
+        Ok(())
+    }
+
     pub fn get_field_codec(&self, field: &Fields) -> &Codecs {
         &self.field_to_meta[*field as usize].codec
     }
+
+    pub fn set_field_codec(&mut self, field: &Fields, codec: Codecs) {
+        self.field_to_meta[*field as usize].codec = codec;
+    }
+
+    /// Bytes at which blocks of `field` were cut, `SIZE_LIMIT` for files
+    /// which don't record it. Records larger than that get larger blocks.
+    pub fn get_block_size_limit(&self, field: &Fields) -> usize {
+        self.field_to_meta[*field as usize].block_size_limit.map_or(SIZE_LIMIT, |limit| limit as usize)
+    }
+
+    pub(crate) fn set_block_size_limit(&mut self, field: &Fields, bytes: usize) {
+        self.field_to_meta[*field as usize].block_size_limit = Some(bytes as u32);
+    }
+
+    /// Encryption parameters, None if the field is stored in plain.
+    pub fn get_field_encryption(&self, field: &Fields) -> Option<&FieldEncryption> {
+        self.field_to_meta[*field as usize].encryption.as_ref()
+    }
+
+    pub fn set_field_encryption(&mut self, field: &Fields, encryption: Option<FieldEncryption>) {
+        self.field_to_meta[*field as usize].encryption = encryption;
+    }
+
+    pub fn get_analytics(&self, name: &str) -> Option<&Value> {
+        self.analytics.get(name)
+    }
+
+    pub fn set_analytics(&mut self, name: &str, summary: Value) {
+        self.analytics.insert(name.to_owned(), summary);
+    }
+
+    pub(crate) fn clear_analytics(&mut self) {
+        self.analytics.clear();
+    }
 }
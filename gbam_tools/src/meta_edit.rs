@@ -0,0 +1,250 @@
+//! Editing of meta of finished files, e.g. fixing names in the stored header,
+//! without rewriting blocks.
+//!
+//! New meta is appended at the end of file and synced before file info is
+//! switched to it, so the file stays valid if the process dies at any point.
+//! If new meta fits where the old one was, it's then copied there, file info
+//! is switched again and the file is truncated after it. Otherwise the old
+//! meta is left as a dead region between blocks and new meta, which readers
+//! never look at. Readers ignore bytes after meta whose prefix tells its
+//! length, so a copy interrupted before truncation is harmless too. Files
+//! with space reserved for meta after file info, see
+//! `Writer::reserve_meta_bytes()`, get meta copied there instead if it fits.
+//!
+//! Edits are not safe against concurrent access. A reader opening the file
+//! mid-edit may read file info before a switch and meta after it, and fail
+//! on crc32 mismatch. Readers which keep the file mapped may be killed by
+//! SIGBUS on truncation. Two edits of the same file at once corrupt it.
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use crate::error::with_path;
+use crate::layout::MetaPrefix;
+use crate::meta::{FileInfo, FileMeta, FILE_INFO_SIZE, META_PREFIX_SIZE};
+use crate::reader::reader::{meta_in_tail, meta_pos, meta_read_start, parse_file_info, parse_meta};
+use crate::writer::write_prefixed_meta;
+
+/// Applies `edit` to meta of file at `path` and writes it back. Errors carry
+/// the path, see `GbamError::AtPath`.
+pub fn edit_meta<P: AsRef<Path>, F: FnOnce(&mut FileMeta)>(path: P, edit: F) -> io::Result<()> {
+    let path = path.as_ref();
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path)
+        .and_then(|mut file| edit_file_meta(&mut file, edit))
+        .map_err(|err| with_path(err, path).into())
+}
+
+/// Same as `edit_meta()`, for `file` opened for reading and writing. Fails
+/// without touching the file if edited meta doesn't match its blocks.
+pub fn edit_file_meta<F: FnOnce(&mut FileMeta)>(file: &mut File, edit: F) -> io::Result<()> {
+    let size = file.metadata()?.len();
+    let mut head = Vec::with_capacity(FILE_INFO_SIZE);
+    file.seek(SeekFrom::Start(0))?;
+    (&mut *file).take(FILE_INFO_SIZE as u64).read_to_end(&mut head)?;
+    let mut file_info = parse_file_info(&head)?;
+    let read_start = meta_read_start(meta_pos(&file_info, size)?);
+    let mut tail = Vec::new();
+    file.seek(SeekFrom::Start(read_start))?;
+    file.read_to_end(&mut tail)?;
+    let mut meta = parse_meta(&file_info, meta_in_tail(&file_info, &tail))?;
+    edit(&mut meta);
+    meta.check_record_counts()?;
+
+    // Meta of files written before prefixes were added starts the region.
+    let prefixed = file_info.seekpos - read_start == META_PREFIX_SIZE as u64
+        && MetaPrefix::decode(&tail).map(|prefix| prefix.crc32) == Some(file_info.crc32);
+    let region_start = if prefixed { read_start } else { file_info.seekpos };
+    // Where the file ends without old meta.
+    let in_reserved_space = file_info.meta_in_reserved_space();
+    let data_end = if in_reserved_space { size } else { region_start };
+
+    file.seek(SeekFrom::Start(size))?;
+    let (appended_pos, crc32) = write_prefixed_meta(file, &meta)?;
+    let appended_len = file.stream_position()? - size;
+    switch_meta(file, &mut file_info, appended_pos, crc32)?;
+    let target = if appended_len <= file_info.reserved_meta {
+        Some(FILE_INFO_SIZE as u64)
+    } else if !in_reserved_space && appended_len <= size - region_start {
+        Some(region_start)
+    } else {
+        None
+    };
+    if let Some(target) = target {
+        file.seek(SeekFrom::Start(target))?;
+        let (seekpos, crc32) = write_prefixed_meta(file, &meta)?;
+        let end = file.stream_position()?.max(data_end);
+        switch_meta(file, &mut file_info, seekpos, crc32)?;
+        file.set_len(end)?;
+        file.sync_data()?;
+    }
+    Ok(())
+}
+
+/// Points file info to meta at `seekpos`, once meta is on disk.
+fn switch_meta(file: &mut File, file_info: &mut FileInfo, seekpos: u64, crc32: u32) -> io::Result<()> {
+    file.sync_data()?;
+    file_info.seekpos = seekpos;
+    file_info.crc32 = crc32;
+    file.seek(SeekFrom::Start(0))?;
+    file.write_all(&file_info.to_padded_bytes()?)?;
+    file.sync_data()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::inspect::inspect;
+    use crate::reader::reader::Reader;
+    use crate::test_utils::{open_test_file, sam_header_bytes, write_test_file, TestRecord};
+    use crate::writer::calc_crc_for_meta_bytes;
+    use bam_tools::record::fields::Fields;
+    use tempdir::TempDir;
+
+    const HEADER: &str = "@HD\tVN:1.6\n@RG\tID:rg1\tSM:smaple\n";
+
+    fn records() -> Vec<TestRecord> {
+        (0..1000)
+            .map(|i| TestRecord::new(i % 3, i * 10, &format!("r{}", i)))
+            .collect()
+    }
+
+    fn rename(names: &[&str]) -> impl FnOnce(&mut FileMeta) {
+        let names: Vec<String> = names.iter().map(|name| name.to_string()).collect();
+        move |meta| {
+            let ref_seqs: Vec<(String, u32)> = meta
+                .get_ref_seqs()
+                .iter()
+                .zip(names)
+                .map(|((_, len), name)| (name, *len))
+                .collect();
+            let text = HEADER.replace("smaple", "sample");
+            let sam_header = sam_header_bytes(&text, &ref_seqs);
+            meta.set_ref_seqs(ref_seqs, sam_header);
+        }
+    }
+
+    fn check_file(path: &Path, names: &[&str]) {
+        let recs = records();
+        let mut reader = open_test_file(path);
+        let ref_names: Vec<&str> =
+            reader.file_meta.get_ref_seqs().iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(ref_names, names);
+        let sam_header = crate::sam_export::sam_header(&reader.file_meta);
+        assert!(sam_header.contains("SM:sample\n"), "{}", sam_header);
+        let mut records = reader.records();
+        let mut n = 0;
+        while let Some(rec) = records.next_rec().unwrap() {
+            assert_eq!(rec.refid, Some(recs[n].refid));
+            assert_eq!(rec.pos, Some(recs[n].pos));
+            n += 1;
+        }
+        assert_eq!(n, recs.len());
+        assert!(inspect(path).unwrap().failed_checks.is_empty());
+    }
+
+    #[test]
+    fn test_edit_meta_in_place() {
+        let tmp_dir = TempDir::new("gbam_meta_edit").unwrap();
+        let path = tmp_dir.path().join("edited.gbam");
+        write_test_file(&path, HEADER, &records());
+        let size = std::fs::metadata(&path).unwrap().len();
+
+        edit_meta(&path, rename(&["1", "2", "3"])).unwrap();
+        assert!(std::fs::metadata(&path).unwrap().len() < size);
+        check_file(&path, &["1", "2", "3"]);
+    }
+
+    #[test]
+    fn test_edit_meta_appended() {
+        let tmp_dir = TempDir::new("gbam_meta_edit").unwrap();
+        let path = tmp_dir.path().join("edited.gbam");
+        write_test_file(&path, HEADER, &records());
+        let size = std::fs::metadata(&path).unwrap().len();
+
+        let names = ["chromosome_1", "chromosome_2", "chromosome_3"];
+        edit_meta(&path, rename(&names)).unwrap();
+        // Old meta is left behind, new one follows it.
+        assert!(std::fs::metadata(&path).unwrap().len() > size);
+        check_file(&path, &names);
+
+        // Edits of edited files don't grow them if meta fits.
+        edit_meta(&path, rename(&["1", "2", "3"])).unwrap();
+        check_file(&path, &["1", "2", "3"]);
+    }
+
+    #[test]
+    fn test_edit_meta_rejects_mismatched_meta() {
+        let tmp_dir = TempDir::new("gbam_meta_edit").unwrap();
+        let path = tmp_dir.path().join("edited.gbam");
+        write_test_file(&path, HEADER, &records());
+        let bytes = std::fs::read(&path).unwrap();
+
+        let err = edit_meta(&path, |meta| meta.get_blocks(&Fields::Pos).clear()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(std::fs::read(&path).unwrap(), bytes);
+    }
+
+    #[test]
+    fn test_edit_meta_in_reserved_space() {
+        let tmp_dir = TempDir::new("gbam_meta_edit").unwrap();
+        let path = tmp_dir.path().join("edited.gbam");
+        let mut writer = crate::test_utils::new_test_writer(&path, HEADER);
+        writer.reserve_meta_bytes(50_000).unwrap();
+        for rec in records() {
+            writer.push_record(&rec.to_raw(), false).unwrap();
+        }
+        writer.finish_with_summary(false).unwrap();
+        let size = std::fs::metadata(&path).unwrap().len();
+        let meta_at = || parse_file_info(&std::fs::read(&path).unwrap()).unwrap().seekpos;
+        let in_head = (FILE_INFO_SIZE + META_PREFIX_SIZE) as u64;
+        assert_eq!(meta_at(), in_head);
+
+        edit_meta(&path, rename(&["1", "2", "3"])).unwrap();
+        assert_eq!(meta_at(), in_head);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), size);
+        check_file(&path, &["1", "2", "3"]);
+
+        // Doesn't fit, goes to the end of file.
+        edit_meta(&path, |meta| meta.set_extension("note", serde_json::json!("x".repeat(60_000)))).unwrap();
+        assert!(meta_at() > size);
+        check_file(&path, &["1", "2", "3"]);
+
+        // Fits again, the end of file is cut off.
+        edit_meta(&path, |meta| {
+            meta.remove_extension("note");
+        })
+        .unwrap();
+        assert_eq!(meta_at(), in_head);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), size);
+        check_file(&path, &["1", "2", "3"]);
+    }
+
+    /// Edit interrupted after new meta was appended but before file info was
+    /// switched, or after meta was copied into place but before truncation.
+    #[test]
+    fn test_interrupted_edit() {
+        let tmp_dir = TempDir::new("gbam_meta_edit").unwrap();
+        let path = tmp_dir.path().join("edited.gbam");
+        write_test_file(&path, HEADER, &records());
+        let mut meta = open_test_file(&path).file_meta.as_ref().clone();
+        rename(&["1", "2", "3"])(&mut meta);
+        let meta_json = serde_json::to_vec(&meta).unwrap();
+        let mut prefix = Vec::new();
+        prefix.extend_from_slice(&calc_crc_for_meta_bytes(&meta_json).to_le_bytes());
+        prefix.extend_from_slice(&(meta_json.len() as u64).to_le_bytes());
+
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&prefix).unwrap();
+        file.write_all(&meta_json).unwrap();
+        drop(file);
+        let reader = Reader::from_path(&path, Default::default()).unwrap();
+        assert_eq!(reader.file_meta.get_ref_seqs()[0].0, "chr1");
+
+        edit_meta(&path, |_| ()).unwrap();
+        let reader = Reader::from_path(&path, Default::default()).unwrap();
+        assert_eq!(reader.file_meta.get_ref_seqs()[0].0, "chr1");
+    }
+}
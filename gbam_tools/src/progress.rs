@@ -0,0 +1,163 @@
+//! Progress reporting and cancellation of long writes.
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Progress callbacks are throttled to one per this interval.
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(250);
+
+/// State of a writer passed to progress callback.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ProgressEvent {
+    pub records_pushed: u64,
+    /// Bytes of records waiting in column buffers for compression.
+    pub bytes_buffered: u64,
+    pub blocks_compressed: u64,
+    /// Compressed bytes of blocks written out, without meta.
+    pub bytes_written: u64,
+    /// Set on the last event, sent by `Writer::finish_with_summary()`.
+    pub finished: bool,
+}
+
+/// Shared flag aborting a writer. Clones refer to the same flag, so it can
+/// be cancelled from another thread.
+#[derive(Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+pub(crate) fn cancelled_error() -> io::Error {
+    io::Error::other("Writing was cancelled")
+}
+
+/// Counters of written blocks and throttling of progress callback.
+#[derive(Default)]
+pub(crate) struct Progress {
+    callback: Option<Box<dyn FnMut(ProgressEvent)>>,
+    last_report: Option<Instant>,
+    pub blocks_compressed: u64,
+    pub bytes_written: u64,
+}
+
+impl Progress {
+    pub fn set_callback(&mut self, callback: Box<dyn FnMut(ProgressEvent)>) {
+        self.callback = Some(callback);
+        self.last_report = None;
+    }
+
+    pub fn block_written(&mut self, bytes: u64) {
+        self.blocks_compressed += 1;
+        self.bytes_written += bytes;
+    }
+
+    /// True if callback is set and was not called for a while. Last event
+    /// is always due.
+    pub fn is_due(&self, finished: bool) -> bool {
+        self.callback.is_some()
+            && (finished
+                || self
+                    .last_report
+                    .is_none_or(|last| last.elapsed() >= PROGRESS_INTERVAL))
+    }
+
+    pub fn report(&mut self, event: ProgressEvent) {
+        if let Some(callback) = self.callback.as_mut() {
+            callback(event);
+            self.last_report = Some(Instant::now());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reader::{parse_tmplt::ParsingTemplate, reader::Reader};
+    use crate::test_utils::{new_test_writer, open_test_file, TestRecord};
+    use bam_tools::record::bamrawrecord::BAMRawRecord;
+    use bam_tools::record::fields::Fields;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use tempdir::TempDir;
+
+    fn batch(start: i32, len: i32) -> Vec<BAMRawRecord<'static>> {
+        (start..start + len).map(|i| TestRecord::new(0, i, "r").to_raw()).collect()
+    }
+
+    #[test]
+    fn test_progress_callback() {
+        let dir = TempDir::new("gbam_progress").unwrap();
+        let path = dir.path().join("progress.gbam");
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let mut writer = new_test_writer(&path, "");
+        writer.set_rows_per_block(10_000);
+        let sink = events.clone();
+        writer.set_progress_callback(Box::new(move |event| sink.borrow_mut().push(event)));
+        for i in 0..100 {
+            writer.push_records(&batch(i * 1_000, 1_000), false).unwrap();
+        }
+        writer.finish_with_summary(false).unwrap();
+
+        let events = events.borrow();
+        // Throttled, the first push reports right away.
+        assert!(events.len() >= 2 && events.len() < 100, "{}", events.len());
+        assert_eq!(events[0].records_pushed, 1_000);
+        assert!(events[0].bytes_buffered > 0);
+        assert!(events.windows(2).all(|pair| pair[0].records_pushed <= pair[1].records_pushed));
+        let last = events.last().unwrap();
+        assert!(last.finished);
+        assert!(events[..events.len() - 1].iter().all(|event| !event.finished));
+        assert_eq!(last.records_pushed, 100_000);
+        assert_eq!(last.bytes_buffered, 0);
+
+        let reader = open_test_file(&path);
+        let (mut blocks, mut bytes) = (0, 0);
+        for field in Fields::iterator() {
+            for block in reader.file_meta.view_blocks(field) {
+                blocks += 1;
+                bytes += block.block_size as u64;
+            }
+        }
+        assert_eq!((last.blocks_compressed, last.bytes_written), (blocks, bytes));
+    }
+
+    #[test]
+    fn test_cancel_after_records() {
+        let dir = TempDir::new("gbam_progress").unwrap();
+        let path = dir.path().join("cancelled.gbam");
+        let token = CancellationToken::new();
+        let mut writer = new_test_writer(&path, "");
+        writer.set_rows_per_block(1_000);
+        writer.set_cancellation_token(token.clone());
+        let mut pushed = 0;
+        for i in 0..100 {
+            match writer.push_records(&batch(i * 1_000, 1_000), false) {
+                Ok(()) => pushed += 1_000,
+                Err(err) => {
+                    assert_eq!(err.to_string(), "Writing was cancelled");
+                    break;
+                }
+            }
+            if pushed == 5_000 {
+                token.cancel();
+            }
+        }
+        assert_eq!(pushed, 5_000);
+        assert!(writer.finish_with_summary(false).is_err());
+        drop(writer);
+        // Meta was never written.
+        assert!(Reader::from_path(&path, ParsingTemplate::new()).is_err());
+    }
+}
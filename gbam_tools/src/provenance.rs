@@ -0,0 +1,240 @@
+//! Content digest and provenance of files, written with
+//! `Writer::set_content_digest()` and kept in meta extension `provenance`.
+//!
+//! The digest is xxHash64 (seed 0) of the data region, bytes from
+//! `Provenance::data_start`, after file info and space reserved for meta, up
+//! to `Provenance::data_end`, where blocks end. That's all blocks, and meta
+//! snapshots of `Writer::flush_all_columns()` among them, in file order. File
+//! info and final meta are not covered: they are written once the digest is
+//! known, and meta may be edited later without touching blocks, see
+//! `meta_edit`. Tools writing new files from blocks of others drop the
+//! provenance of their inputs.
+use std::hash::Hasher;
+use std::io;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use twox_hash::XxHash64;
+
+use crate::error::CorruptionError;
+use crate::meta::{FileMeta, FILE_INFO_SIZE};
+use crate::reader::reader::Reader;
+use crate::SIZE_LIMIT;
+
+/// Name of meta extension holding provenance, see
+/// `FileMeta::get_extension()`.
+pub const PROVENANCE_EXTENSION: &str = "provenance";
+
+/// Name of the digest algorithm, as recorded in meta.
+pub const DIGEST_ALGORITHM: &str = "xxh64";
+
+/// Identity of a file, recorded by the writer.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Provenance {
+    /// `DIGEST_ALGORITHM`.
+    pub algorithm: String,
+    /// Digest of data region as 16 hex digits.
+    pub digest: String,
+    /// Start of data region, recorded since 1.5.
+    #[serde(default = "default_data_start")]
+    pub data_start: u64,
+    /// End of data region.
+    pub data_end: u64,
+    /// Random (version 4) UUID.
+    pub uuid: String,
+    /// Seconds since Unix epoch.
+    pub created: u64,
+    /// Name and version of the writing tool.
+    pub tool: String,
+}
+
+impl Provenance {
+    pub(crate) fn new(digest: u64, data_start: u64, data_end: u64) -> Self {
+        Self {
+            algorithm: String::from(DIGEST_ALGORITHM),
+            digest: format!("{:016x}", digest),
+            data_start,
+            data_end,
+            uuid: new_uuid(),
+            created: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since| since.as_secs()),
+            tool: format!("gbam_tools {}", env!("CARGO_PKG_VERSION")),
+        }
+    }
+
+    /// Provenance recorded in `meta`, None for files written without it.
+    pub fn from_meta(meta: &FileMeta) -> io::Result<Option<Self>> {
+        let value = match meta.get_extension(PROVENANCE_EXTENSION) {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+        let provenance: Provenance = serde_json::from_value(value.clone()).map_err(|err| damaged(&err))?;
+        if provenance.algorithm != DIGEST_ALGORITHM {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("Digest algorithm {} is not supported", provenance.algorithm),
+            ));
+        }
+        provenance.digest_value()?;
+        Ok(Some(provenance))
+    }
+
+    pub fn digest_value(&self) -> io::Result<u64> {
+        u64::from_str_radix(&self.digest, 16).map_err(|err| damaged(&err))
+    }
+}
+
+fn default_data_start() -> u64 {
+    FILE_INFO_SIZE as u64
+}
+
+fn damaged(err: &dyn std::fmt::Display) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Meta extension {} is damaged: {}", PROVENANCE_EXTENSION, err),
+    )
+}
+
+/// Running digest of the data region, fed by the writer in file order.
+pub(crate) fn new_digest() -> XxHash64 {
+    XxHash64::with_seed(0)
+}
+
+fn new_uuid() -> String {
+    let mut bytes: [u8; 16] = rand::random();
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex: String = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!("{}-{}-{}-{}-{}", &hex[..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..])
+}
+
+impl Reader {
+    /// Provenance recorded by the writer, None if the file was written
+    /// without `Writer::set_content_digest()`.
+    pub fn provenance(&self) -> io::Result<Option<Provenance>> {
+        Provenance::from_meta(&self.file_meta)
+    }
+
+    /// Digest of data region recorded by the writer, see `provenance`.
+    pub fn file_digest(&self) -> io::Result<Option<u64>> {
+        self.provenance()?.map(|provenance| provenance.digest_value()).transpose()
+    }
+
+    /// UUID given to the file by the writer.
+    pub fn file_uuid(&self) -> io::Result<Option<String>> {
+        Ok(self.provenance()?.map(|provenance| provenance.uuid))
+    }
+
+    /// Recomputes digest of data region and compares it with the recorded
+    /// one, failing with `CorruptionError::DigestMismatch` if they differ. Reads
+    /// the whole data region.
+    pub fn verify_file_digest(&self) -> io::Result<()> {
+        let provenance = self.provenance()?.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "File has no content digest, it was written without Writer::set_content_digest()",
+            )
+        })?;
+        let expected = provenance.digest_value()?;
+        let mut digest = new_digest();
+        let mut offset = provenance.data_start;
+        while offset < provenance.data_end {
+            let len = (provenance.data_end - offset).min(SIZE_LIMIT as u64);
+            digest.write(&self.mmap.read_at(offset, len as usize)?);
+            offset += len;
+        }
+        let actual = digest.finish();
+        if actual != expected {
+            return Err(CorruptionError::DigestMismatch { expected, actual }.into());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::meta_edit::edit_meta;
+    use crate::test_utils::{new_test_writer, open_test_file, write_test_file, TestRecord};
+    use bam_tools::record::fields::Fields;
+    use std::path::Path;
+    use tempdir::TempDir;
+
+    fn write(path: &Path) {
+        let mut writer = new_test_writer(path, "@HD\tVN:1.6\n");
+        writer.set_rows_per_block(500);
+        writer.set_content_digest(true);
+        for i in 0..3_000 {
+            writer.push_record(&TestRecord::new(i % 3, i, &format!("r{}", i)).to_raw(), false).unwrap();
+            if i == 1_700 {
+                writer.flush_all_columns(true, false).unwrap();
+            }
+        }
+        writer.finish_with_summary(false).unwrap();
+    }
+
+    #[test]
+    fn test_file_digest() {
+        let dir = TempDir::new("gbam_provenance").unwrap();
+        let path = dir.path().join("digest.gbam");
+        write(&path);
+        let reader = open_test_file(&path);
+        let provenance = reader.provenance().unwrap().unwrap();
+        assert_eq!(provenance.algorithm, DIGEST_ALGORITHM);
+        assert_eq!(provenance.uuid.len(), 36);
+        assert_eq!(&provenance.uuid[14..15], "4");
+        assert!(provenance.tool.starts_with("gbam_tools "));
+        assert!(provenance.created > 0);
+        assert_eq!(reader.file_digest().unwrap(), Some(provenance.digest_value().unwrap()));
+        assert_eq!(reader.file_uuid().unwrap(), Some(provenance.uuid.clone()));
+        reader.verify_file_digest().unwrap();
+
+        let other = dir.path().join("other.gbam");
+        write(&other);
+        assert_ne!(open_test_file(&other).file_uuid().unwrap(), reader.file_uuid().unwrap());
+
+        let plain = dir.path().join("plain.gbam");
+        write_test_file(&plain, "", &[TestRecord::default()]);
+        let plain = open_test_file(&plain);
+        assert_eq!(plain.file_digest().unwrap(), None);
+        assert_eq!(plain.verify_file_digest().unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_tampered_data() {
+        let dir = TempDir::new("gbam_provenance").unwrap();
+        let path = dir.path().join("digest.gbam");
+        write(&path);
+        let block = open_test_file(&path).file_meta.view_blocks(&Fields::Pos)[2].clone();
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes[block.seekpos as usize + 3] ^= 1;
+        std::fs::write(&path, bytes).unwrap();
+
+        let err = open_test_file(&path).verify_file_digest().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(matches!(
+            crate::error::gbam_error(&err),
+            Some(crate::error::GbamError::Corruption(CorruptionError::DigestMismatch { .. }))
+        ));
+    }
+
+    #[test]
+    fn test_meta_edit_keeps_digest() {
+        let dir = TempDir::new("gbam_provenance").unwrap();
+        let path = dir.path().join("digest.gbam");
+        write(&path);
+        let before = open_test_file(&path).provenance().unwrap();
+        // Grows meta, so it's appended after the old one.
+        edit_meta(&path, |meta| meta.set_extension("note", serde_json::json!("x".repeat(10_000)))).unwrap();
+        let reader = open_test_file(&path);
+        assert_eq!(reader.provenance().unwrap(), before);
+        reader.verify_file_digest().unwrap();
+
+        edit_meta(&path, |meta| {
+            meta.remove_extension("note");
+        })
+        .unwrap();
+        open_test_file(&path).verify_file_digest().unwrap();
+    }
+}
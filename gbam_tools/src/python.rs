@@ -0,0 +1,238 @@
+//! Read-only Python bindings. Built with `maturin develop` from the
+//! repository root, which enables the `python-ffi` feature.
+// Triggered by code generated by pyo3 macros.
+#![allow(clippy::useless_conversion)]
+use std::fs::File;
+use std::io;
+use std::path::PathBuf;
+
+use bam_tools::record::fields::Fields;
+use byteorder::{LittleEndian, ReadBytesExt};
+use pyo3::exceptions::{PyKeyError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::{PyBytes, PyDict};
+
+use crate::error::GbamError;
+use crate::reader::parse_tmplt::ParsingTemplate;
+use crate::reader::reader::Reader;
+use crate::reader::record::GbamRecord;
+use crate::reader::region::Region;
+
+/// Record dict keys of data fields, also accepted in `fields` of `open()`.
+const FIELD_NAMES: [(&str, Fields); 13] = [
+    ("ref_id", Fields::RefID),
+    ("pos", Fields::Pos),
+    ("mapq", Fields::Mapq),
+    ("bin", Fields::Bin),
+    ("flags", Fields::Flags),
+    ("next_ref_id", Fields::NextRefID),
+    ("next_pos", Fields::NextPos),
+    ("tlen", Fields::TemplateLength),
+    ("read_name", Fields::ReadName),
+    ("cigar", Fields::RawCigar),
+    ("seq", Fields::RawSequence),
+    ("qual", Fields::RawQual),
+    ("tags", Fields::RawTags),
+];
+
+/// Raised as the Python exception of its `io::ErrorKind`.
+impl From<GbamError> for PyErr {
+    fn from(err: GbamError) -> Self {
+        io::Error::from(err).into()
+    }
+}
+
+/// GBAM file reader. Iterating yields records as dicts holding requested
+/// fields only.
+#[pyclass]
+pub struct PyReader {
+    reader: Reader,
+    cur_rec: usize,
+    buf: GbamRecord,
+}
+
+#[pymethods]
+impl PyReader {
+    /// Opens GBAM file, reading `fields` (all by default).
+    #[staticmethod]
+    #[pyo3(signature = (path, fields=None))]
+    fn open(path: PathBuf, fields: Option<Vec<String>>) -> PyResult<Self> {
+        let mut tmplt = ParsingTemplate::new();
+        match fields {
+            Some(names) => {
+                for name in names {
+                    let (_, field) = FIELD_NAMES
+                        .iter()
+                        .find(|(n, _)| *n == name)
+                        .ok_or_else(|| PyKeyError::new_err(format!("Unknown field '{}'", name)))?;
+                    tmplt.set(field, true);
+                }
+            }
+            None => tmplt.set_all(),
+        }
+        Ok(PyReader {
+            reader: Reader::new(File::open(path)?, tmplt)?,
+            cur_rec: 0,
+            buf: GbamRecord::default(),
+        })
+    }
+
+    fn num_records(&self) -> usize {
+        self.reader.num_records()
+    }
+
+    fn __len__(&self) -> usize {
+        self.reader.num_records()
+    }
+
+    /// Starts iteration from the first record.
+    fn __iter__(mut slf: PyRefMut<'_, Self>) -> PyRefMut<'_, Self> {
+        slf.cur_rec = 0;
+        slf
+    }
+
+    fn __next__(mut slf: PyRefMut<'_, Self>, py: Python<'_>) -> PyResult<Option<PyObject>> {
+        let this = &mut *slf;
+        if this.cur_rec >= this.reader.num_records() {
+            return Ok(None);
+        }
+        // Fetching may decompress a block, other threads can run meanwhile.
+        let (reader, buf, rec_num) = (&mut this.reader, &mut this.buf, this.cur_rec);
+        this.cur_rec += 1;
+        py.allow_threads(|| reader.fill_record(rec_num, buf))?;
+        Ok(Some(record_to_dict(py, &this.buf)?.into_any().unbind()))
+    }
+
+    /// Records overlapping 0-based half-open interval on `ref_name`. The file
+    /// has to be coordinate sorted, and opened with ref_id, pos and cigar.
+    fn fetch(
+        &mut self,
+        py: Python<'_>,
+        ref_name: &str,
+        start: i32,
+        end: i32,
+    ) -> PyResult<Vec<PyObject>> {
+        let ref_id = self
+            .reader
+            .file_meta
+            .get_ref_seqs()
+            .iter()
+            .position(|(name, _)| name == ref_name)
+            .ok_or_else(|| PyKeyError::new_err(format!("Unknown reference '{}'", ref_name)))?;
+        let region = Region::new(ref_id as i32, start, end);
+        let reader = &mut self.reader;
+        let records = py.allow_threads(|| -> io::Result<Vec<GbamRecord>> {
+            let mut fetched = reader.fetch(&region)?;
+            let mut records = Vec::new();
+            while let Some(rec) = fetched.next_rec()? {
+                records.push(rec.clone());
+            }
+            Ok(records)
+        })?;
+        records
+            .iter()
+            .map(|rec| Ok(record_to_dict(py, rec)?.into_any().unbind()))
+            .collect()
+    }
+}
+
+fn record_to_dict<'py>(py: Python<'py>, rec: &GbamRecord) -> PyResult<Bound<'py, PyDict>> {
+    let dict = PyDict::new_bound(py);
+    let ints = [
+        ("ref_id", rec.refid.map(i64::from)),
+        ("pos", rec.pos.map(i64::from)),
+        ("mapq", rec.mapq.map(i64::from)),
+        ("bin", rec.bin.map(i64::from)),
+        ("flags", rec.flag.map(i64::from)),
+        ("next_ref_id", rec.next_ref_id.map(i64::from)),
+        ("next_pos", rec.next_pos.map(i64::from)),
+        ("tlen", rec.tlen.map(i64::from)),
+    ];
+    for (key, val) in ints {
+        if let Some(val) = val {
+            dict.set_item(key, val)?;
+        }
+    }
+    if let Some(name) = &rec.read_name {
+        // Stored NUL terminated.
+        let name = name.strip_suffix(&[0]).unwrap_or(name);
+        dict.set_item("read_name", String::from_utf8_lossy(name))?;
+    }
+    if let Some(cigar) = &rec.cigar {
+        let cigar = match cigar.ops().len() {
+            0 => None,
+            _ => Some(cigar.to_string()),
+        };
+        dict.set_item("cigar", cigar)?;
+    }
+    if let Some(seq) = &rec.seq {
+        dict.set_item("seq", seq)?;
+    }
+    if let Some(qual) = &rec.qual {
+        dict.set_item("qual", PyBytes::new_bound(py, qual))?;
+    }
+    if let Some(tags) = &rec.tags {
+        dict.set_item("tags", tags_to_dict(py, tags)?)?;
+    }
+    Ok(dict)
+}
+
+/// Converts BAM auxiliary data into {tag: value}. Arrays become lists.
+fn tags_to_dict<'py>(py: Python<'py>, mut data: &[u8]) -> PyResult<Bound<'py, PyDict>> {
+    let dict = PyDict::new_bound(py);
+    while !data.is_empty() {
+        if data.len() < 3 {
+            return Err(PyValueError::new_err("Truncated tag"));
+        }
+        let tag = String::from_utf8_lossy(&data[..2]).into_owned();
+        let tag_type = data[2];
+        data = &data[3..];
+        let value = match tag_type {
+            b'Z' | b'H' => {
+                let end = data
+                    .iter()
+                    .position(|&b| b == 0)
+                    .ok_or_else(|| PyValueError::new_err(format!("Tag {} is not terminated", tag)))?;
+                let value = String::from_utf8_lossy(&data[..end]).into_py(py);
+                data = &data[end + 1..];
+                value
+            }
+            b'B' => {
+                let item_type = data.read_u8()?;
+                let len = data.read_u32::<LittleEndian>()?;
+                let items = (0..len)
+                    .map(|_| read_tag_scalar(py, item_type, &mut data))
+                    .collect::<PyResult<Vec<PyObject>>>()?;
+                items.into_py(py)
+            }
+            _ => read_tag_scalar(py, tag_type, &mut data)?,
+        };
+        dict.set_item(tag, value)?;
+    }
+    Ok(dict)
+}
+
+fn read_tag_scalar(py: Python<'_>, tag_type: u8, data: &mut &[u8]) -> PyResult<PyObject> {
+    Ok(match tag_type {
+        b'A' => (data.read_u8()? as char).to_string().into_py(py),
+        b'c' => data.read_i8()?.into_py(py),
+        b'C' => data.read_u8()?.into_py(py),
+        b's' => data.read_i16::<LittleEndian>()?.into_py(py),
+        b'S' => data.read_u16::<LittleEndian>()?.into_py(py),
+        b'i' => data.read_i32::<LittleEndian>()?.into_py(py),
+        b'I' => data.read_u32::<LittleEndian>()?.into_py(py),
+        b'f' => data.read_f32::<LittleEndian>()?.into_py(py),
+        _ => {
+            return Err(PyValueError::new_err(format!(
+                "Unknown tag type '{}'",
+                tag_type as char
+            )))
+        }
+    })
+}
+
+#[pymodule]
+fn gbam_tools(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<PyReader>()?;
+    Ok(())
+}
@@ -5,7 +5,7 @@ use serde::{Deserialize, Serialize};
 use byteorder::ByteOrder;
 use byteorder::WriteBytesExt;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Op(pub u32);
 
 impl Op {
@@ -44,7 +44,7 @@ impl Op {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Cigar(pub Vec<Op>);
 
 pub fn base_coverage(arr: &[Op]) -> u32 {
@@ -231,7 +231,7 @@ pub fn main_depth(
             .unwrap();
 
             for (dest, rec_num) in records_range {
-                reader.fill_record(rec_num, &mut rec);
+                reader.fill_record(rec_num, &mut rec).unwrap();
                 dest.refid = rec.refid.unwrap();
                 dest.pos = rec.pos.unwrap();
                 dest.cigar = base_coverage(&rec.cigar.as_ref().unwrap().0[..]);
@@ -261,7 +261,7 @@ pub fn collect_stats(file: File) {
                 Reader::new_with_meta(file.try_clone().unwrap(), tmplt, &file_meta, None).unwrap();
 
             for rec_num in records_range {
-                reader.fill_record(rec_num, &mut rec);
+                reader.fill_record(rec_num, &mut rec).unwrap();
                 collect(&rec, &mut stats);
             }
 
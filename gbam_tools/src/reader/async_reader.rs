@@ -0,0 +1,519 @@
+//! Reader over asynchronous sources, like object storage. Blocks are read
+//! through tokio `AsyncRead + AsyncSeek` and decompressed on blocking
+//! threads, so the executor never stalls on I/O or decompression.
+use std::io::{self, SeekFrom};
+use std::sync::Arc;
+
+use bam_tools::record::fields::{field_type, var_size_field_to_index, FieldType, Fields, FIELDS_NUM};
+use futures::stream::{self, Stream};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt};
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+
+use super::column::decompress_into;
+use super::parse_tmplt::ParsingTemplate;
+use super::reader::{
+    check_index_columns, meta_in_tail, meta_read_start, parse_file_info, parse_meta_json, MAX_META_SIZE,
+};
+use super::record::GbamRecord;
+use super::region::{Region, REGION_FIELDS};
+use crate::layout::{index_entry, to_usize, INDEX_ENTRY_SIZE};
+use crate::meta::{BlockMeta, FileMeta, SeqEncoding, SortOrder, FILE_INFO_SIZE, META_PREFIX_SIZE};
+use crate::query::cigar::base_coverage;
+use crate::seq_packing::unpack_block;
+use crate::writer::calc_crc_for_meta_bytes;
+
+/// Blocks of each column read ahead of the current one by default.
+pub const DEFAULT_PREFETCH: usize = 2;
+
+/// Async counterpart of `Reader`. Every stream gets its own block buffers,
+/// so several streams over one reader may be consumed concurrently. Reads
+/// of one source are serialized, but read ahead of blocks is issued
+/// concurrently for all columns. Encrypted fields are not supported.
+pub struct AsyncReader<R> {
+    source: Arc<Mutex<R>>,
+    pub parsing_template: ParsingTemplate,
+    pub amount: usize,
+    pub file_meta: Arc<FileMeta>,
+    prefetch: usize,
+}
+
+impl<R> AsyncReader<R>
+where
+    R: AsyncRead + AsyncSeek + Unpin + Send + 'static,
+{
+    pub async fn new(mut inner: R, parsing_template: ParsingTemplate) -> io::Result<Self> {
+        inner.seek(SeekFrom::Start(0)).await?;
+        let mut info_bytes = Vec::with_capacity(FILE_INFO_SIZE);
+        (&mut inner).take(FILE_INFO_SIZE as u64).read_to_end(&mut info_bytes).await?;
+        let file_info = parse_file_info(&info_bytes)?;
+        if file_info.seekpos < FILE_INFO_SIZE as u64 {
+            return Err(invalid_data(format!(
+                "File info has invalid meta position {}, the file was probably not finalized.",
+                file_info.seekpos
+            )));
+        }
+        let read_start = meta_read_start(file_info.seekpos);
+        inner.seek(SeekFrom::Start(read_start)).await?;
+        let mut tail = Vec::new();
+        if file_info.meta_in_reserved_space() {
+            let reserved_end = file_info.data_range().start;
+            (&mut inner).take(reserved_end - read_start).read_to_end(&mut tail).await?;
+        } else {
+            // Longer meta is refused anyway.
+            let limit = MAX_META_SIZE + META_PREFIX_SIZE as u64 + 1;
+            (&mut inner).take(limit).read_to_end(&mut tail).await?;
+        }
+        let meta_bytes = meta_in_tail(&file_info, &tail);
+        if calc_crc_for_meta_bytes(meta_bytes) != file_info.crc32 {
+            return Err(invalid_data("Metadata JSON was damaged.".to_owned()));
+        }
+        let file_meta = parse_meta_json(meta_bytes)?;
+
+        for field in parsing_template.get_active_fields_iter() {
+            if let Some(encryption) = file_meta.get_field_encryption(field) {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    format!(
+                        "Field {} is encrypted with key '{}', async reader can't decrypt it",
+                        field, encryption.key_id
+                    ),
+                ));
+            }
+            if !file_meta.view_blocks(field).is_empty() {
+                file_meta.get_field_codec(field).check_available(*field)?;
+            }
+        }
+        if file_meta.is_index_derived(&Fields::RawSeqLen)
+            && parsing_template.check_if_active(&[Fields::RawSequence])
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Index of RawSequence is derived from sequence lengths, async reader can't read it",
+            ));
+        }
+        check_index_columns(&file_meta, &parsing_template)?;
+        file_meta.check_record_counts()?;
+        file_meta.check_block_ranges(file_info.data_range())?;
+        let amount = file_meta
+            .view_blocks(&Fields::RefID)
+            .iter()
+            .map(|block| block.numitems as usize)
+            .sum();
+        Ok(Self {
+            source: Arc::new(Mutex::new(inner)),
+            parsing_template,
+            amount,
+            file_meta: Arc::new(file_meta),
+            prefetch: DEFAULT_PREFETCH,
+        })
+    }
+
+    pub fn num_records(&self) -> usize {
+        self.amount
+    }
+
+    /// Number of blocks of each column read ahead of the current one.
+    pub fn set_prefetch(&mut self, blocks: usize) {
+        self.prefetch = blocks;
+    }
+
+    /// Stream of all records, with fields enabled in parsing template.
+    pub fn records(&self) -> impl Stream<Item = io::Result<GbamRecord>> + Send + 'static {
+        let amount = self.amount;
+        stream::try_unfold((self.cursor(), 0), move |(mut cursor, rec_num)| async move {
+            if rec_num == amount {
+                return Ok(None);
+            }
+            let mut rec = GbamRecord::default();
+            cursor.fill_record(rec_num, &mut rec).await?;
+            Ok(Some((rec, (cursor, rec_num + 1))))
+        })
+    }
+
+    /// Stream of records overlapping `region`, like `Reader::fetch()`. The
+    /// file has to be coordinate sorted, RefID, Pos and RawCigar have to be
+    /// enabled in parsing template.
+    pub async fn fetch(
+        &self,
+        region: &Region,
+    ) -> io::Result<impl Stream<Item = io::Result<GbamRecord>> + Send + 'static> {
+        let sort_order = self.file_meta.get_sort_order();
+        if sort_order != SortOrder::Coordinate {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Region fetch requires a coordinate sorted file, but sort order is {:?}.",
+                    sort_order
+                ),
+            ));
+        }
+        if !self.parsing_template.check_if_active(&REGION_FIELDS) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "RefID, Pos and RawCigar fields have to be enabled in parsing template to fetch a region.",
+            ));
+        }
+        let mut cursor = self.cursor();
+        let start = match self.file_meta.get_linear_index() {
+            Some(linear_index) => linear_index.first_candidate(region.ref_id, region.start),
+            None => None,
+        };
+        let start = match start {
+            Some(rec_num) => rec_num as usize,
+            None => cursor.first_rec_of_ref(region.ref_id, self.amount).await?,
+        };
+
+        let (region, amount) = (*region, self.amount);
+        Ok(stream::try_unfold((cursor, start), move |(mut cursor, mut rec_num)| async move {
+            let mut rec = GbamRecord::default();
+            while rec_num < amount {
+                cursor.fill_fields(rec_num, &REGION_FIELDS, &mut rec).await?;
+                rec_num += 1;
+                let (ref_id, pos) = (rec.refid.unwrap(), rec.pos.unwrap());
+                // Sorted, so nothing overlaps after this record.
+                if ref_id != region.ref_id || pos >= region.end {
+                    return Ok(None);
+                }
+                let ref_len = base_coverage(&rec.cigar.as_ref().unwrap().0);
+                if region.overlaps(ref_id, pos, ref_len) {
+                    cursor.fill_record(rec_num - 1, &mut rec).await?;
+                    return Ok(Some((rec, (cursor, rec_num))));
+                }
+            }
+            Ok(None)
+        }))
+    }
+
+    fn cursor(&self) -> Cursor<R> {
+        let columns = (0..FIELDS_NUM)
+            .map(|_| None)
+            .collect::<Vec<Option<AsyncColumn<R>>>>();
+        let mut cursor = Cursor {
+            columns,
+            fields: self.parsing_template.get_active_data_fields_iter().copied().collect(),
+            prefetch: self.prefetch,
+        };
+        for field in self.parsing_template.get_active_fields_iter() {
+            cursor.columns[*field as usize] = Some(AsyncColumn::new(
+                self.source.clone(),
+                self.file_meta.clone(),
+                *field,
+            ));
+        }
+        cursor
+    }
+}
+
+// Columns of one stream.
+struct Cursor<R> {
+    columns: Vec<Option<AsyncColumn<R>>>,
+    // Active data fields.
+    fields: Vec<Fields>,
+    prefetch: usize,
+}
+
+impl<R> Cursor<R>
+where
+    R: AsyncRead + AsyncSeek + Unpin + Send + 'static,
+{
+    async fn fill_record(&mut self, rec_num: usize, rec: &mut GbamRecord) -> io::Result<()> {
+        let fields = std::mem::take(&mut self.fields);
+        let res = self.fill_fields(rec_num, &fields, rec).await;
+        self.fields = fields;
+        res
+    }
+
+    async fn fill_fields(
+        &mut self,
+        rec_num: usize,
+        fields: &[Fields],
+        rec: &mut GbamRecord,
+    ) -> io::Result<()> {
+        for field in fields {
+            let (start, data) = self.item_block(*field, rec_num).await?;
+            match field_type(field) {
+                FieldType::FixedSized => {
+                    let size = self.column(*field).meta.get_field_size(field).unwrap() as usize;
+                    rec.parse_from_bytes(field, &data[(rec_num - start) * size..][..size]);
+                }
+                FieldType::VariableSized => {
+                    let index = var_size_field_to_index(field);
+                    let begin = if rec_num == start {
+                        0
+                    } else {
+                        self.end_offset(index, rec_num - 1).await?
+                    };
+                    let end = self.end_offset(index, rec_num).await?;
+                    rec.parse_from_bytes(field, &data[begin..end]);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn end_offset(&mut self, index: Fields, rec_num: usize) -> io::Result<usize> {
+        let (start, data) = self.item_block(index, rec_num).await?;
+        Ok(index_entry(&data[(rec_num - start) * INDEX_ENTRY_SIZE..]))
+    }
+
+    async fn item_block(&mut self, field: Fields, rec_num: usize) -> io::Result<(usize, Arc<Vec<u8>>)> {
+        let prefetch = self.prefetch;
+        self.column(field).item_block(rec_num, prefetch).await
+    }
+
+    fn column(&mut self, field: Fields) -> &mut AsyncColumn<R> {
+        self.columns[field as usize].as_mut().unwrap()
+    }
+
+    /// Binary search for the first record on reference `ref_id`, like
+    /// `Reader::first_rec_of_ref()`. Nothing is read ahead meanwhile.
+    async fn first_rec_of_ref(&mut self, ref_id: i32, amount: usize) -> io::Result<usize> {
+        let prefetch = std::mem::replace(&mut self.prefetch, 0);
+        let mut rec = GbamRecord::default();
+        let (mut lo, mut hi) = (0, amount);
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            self.fill_fields(mid, &[Fields::RefID], &mut rec).await?;
+            let mid_ref_id = rec.refid.unwrap();
+            if mid_ref_id >= ref_id || mid_ref_id == -1 {
+                hi = mid;
+            } else {
+                lo = mid + 1;
+            }
+        }
+        self.prefetch = prefetch;
+        Ok(lo)
+    }
+}
+
+type BlockTask = JoinHandle<io::Result<Vec<u8>>>;
+
+// Blocks of one field, read and decompressed by background tasks.
+struct AsyncColumn<R> {
+    source: Arc<Mutex<R>>,
+    meta: Arc<FileMeta>,
+    field: Fields,
+    // First record of each block, followed by total number of records.
+    starts: Vec<usize>,
+    pending: Vec<(usize, BlockTask)>,
+    // The current block and the one used before it.
+    loaded: Vec<(usize, Arc<Vec<u8>>)>,
+}
+
+impl<R> AsyncColumn<R>
+where
+    R: AsyncRead + AsyncSeek + Unpin + Send + 'static,
+{
+    fn new(source: Arc<Mutex<R>>, meta: Arc<FileMeta>, field: Fields) -> Self {
+        // Variable sized fields are located by index spans, if recorded.
+        let mut starts: Vec<usize> =
+            meta.block_starts(&field).into_iter().map(|start| start as usize).collect();
+        starts.push(meta.count_items(&field) as usize);
+        Self {
+            source,
+            meta,
+            field,
+            starts,
+            pending: Vec::new(),
+            loaded: Vec::new(),
+        }
+    }
+
+    /// Block holding item `rec_num`, with number of its first item.
+    async fn item_block(&mut self, rec_num: usize, prefetch: usize) -> io::Result<(usize, Arc<Vec<u8>>)> {
+        let block_num = self.starts.partition_point(|&start| start <= rec_num) - 1;
+        let start = self.starts[block_num];
+        if let Some((_, data)) = self.loaded.iter().find(|(num, _)| *num == block_num) {
+            return Ok((start, data.clone()));
+        }
+
+        // Read ahead blocks behind the current one are not needed anymore.
+        self.pending.retain(|(num, task)| {
+            let needed = (block_num..=block_num + prefetch).contains(num);
+            if !needed {
+                task.abort();
+            }
+            needed
+        });
+        let num_blocks = self.starts.len() - 1;
+        for num in block_num..std::cmp::min(block_num + prefetch + 1, num_blocks) {
+            if !self.pending.iter().any(|(pending, _)| *pending == num) {
+                let task = self.spawn_read(num);
+                self.pending.push((num, task));
+            }
+        }
+        let pos = self.pending.iter().position(|(num, _)| *num == block_num).unwrap();
+        let (_, task) = self.pending.remove(pos);
+        let data = Arc::new(task.await.map_err(|e| io::Error::other(e.to_string()))??);
+
+        if self.loaded.len() == 2 {
+            self.loaded.remove(0);
+        }
+        self.loaded.push((block_num, data.clone()));
+        Ok((start, data))
+    }
+
+    fn spawn_read(&self, block_num: usize) -> BlockTask {
+        let (source, meta, field) = (self.source.clone(), self.meta.clone(), self.field);
+        tokio::spawn(async move {
+            let block = meta.view_blocks(&field)[block_num].clone();
+            let mut data = vec![0; block.block_size as usize];
+            {
+                let mut source = source.lock().await;
+                source.seek(SeekFrom::Start(block.seekpos)).await?;
+                source.read_exact(&mut data).await?;
+            }
+            tokio::task::spawn_blocking(move || decompress(&meta, field, &block, &data))
+                .await
+                .map_err(|e| io::Error::other(e.to_string()))?
+        })
+    }
+}
+
+impl<R> Drop for AsyncColumn<R> {
+    fn drop(&mut self) {
+        for (_, task) in &self.pending {
+            task.abort();
+        }
+    }
+}
+
+fn decompress(meta: &FileMeta, field: Fields, block: &BlockMeta, data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut buffer = Vec::new();
+    if block.uncompressed_size > 0 {
+        let expected = to_usize(block.uncompressed_size)?;
+        decompress_into(data, &mut buffer, &block.codec(*meta.get_field_codec(&field)), expected)?;
+        if field == Fields::RawSequence {
+            match meta.get_seq_encoding() {
+                SeqEncoding::Nibble => {}
+                SeqEncoding::TwoBit => buffer = unpack_block(&buffer)?,
+                SeqEncoding::Reference => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "RawSequence is encoded against a reference, which async reader doesn't support",
+                    ))
+                }
+            }
+        }
+        if let Some(transform) = block.transform {
+            buffer = transform.invert(&buffer)?;
+        }
+    }
+    block.stripe_of(&field, buffer)
+}
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{new_test_writer, open_test_file, TestRecord};
+    use futures::TryStreamExt;
+    use tempdir::TempDir;
+
+    const SORTED: &str = "@HD\tVN:1.6\tSO:coordinate\n";
+
+    fn write_sorted(path: &std::path::Path) {
+        let mut writer = new_test_writer(path, SORTED);
+        writer.set_rows_per_block(1_000);
+        writer.set_seq_packing(true);
+        for ref_id in 0..3 {
+            for i in 0..4_000 {
+                let mut rec = TestRecord::new(ref_id, i * 10, &format!("r{}_{}", ref_id, i));
+                rec.seq = "ACGTN".repeat(i as usize % 7);
+                rec.qual = vec![30; rec.seq.len()];
+                rec.cigar = vec![(rec.seq.len().max(1) as u32) << 4];
+                writer.push_record(&rec.to_raw(), false).unwrap();
+            }
+        }
+        writer.finish_with_summary(false).unwrap();
+    }
+
+    fn sync_records(path: &std::path::Path, region: Option<Region>) -> Vec<String> {
+        let mut reader = open_test_file(path);
+        let mut res = Vec::new();
+        match region {
+            Some(region) => {
+                let mut records = reader.fetch(&region).unwrap();
+                while let Some(rec) = records.next_rec().unwrap() {
+                    res.push(serde_json::to_string(rec).unwrap());
+                }
+            }
+            None => {
+                let mut records = reader.records();
+                while let Some(rec) = records.next_rec().unwrap() {
+                    res.push(serde_json::to_string(rec).unwrap());
+                }
+            }
+        }
+        res
+    }
+
+    async fn async_records<R>(reader: &AsyncReader<R>, region: Option<Region>) -> Vec<String>
+    where
+        R: AsyncRead + AsyncSeek + Unpin + Send + 'static,
+    {
+        let records: Vec<GbamRecord> = match region {
+            Some(region) => reader.fetch(&region).await.unwrap().try_collect().await.unwrap(),
+            None => reader.records().try_collect().await.unwrap(),
+        };
+        records.iter().map(|rec| serde_json::to_string(rec).unwrap()).collect()
+    }
+
+    fn all_fields() -> ParsingTemplate {
+        let mut tmplt = ParsingTemplate::new();
+        tmplt.set_all();
+        tmplt
+    }
+
+    #[tokio::test]
+    async fn test_async_reader_cursor() {
+        let dir = TempDir::new("gbam_async").unwrap();
+        let path = dir.path().join("sorted.gbam");
+        write_sorted(&path);
+
+        let bytes = std::fs::read(&path).unwrap();
+        let reader = AsyncReader::new(std::io::Cursor::new(bytes), all_fields()).await.unwrap();
+        assert_eq!(reader.num_records(), 12_000);
+        let expected = sync_records(&path, None);
+        assert!(async_records(&reader, None).await == expected);
+        let regions = [
+            Region::new(1, 15_005, 21_000),
+            Region::new(2, 0, 5),
+            Region::new(0, 50_000, 60_000),
+        ];
+        for region in regions {
+            assert!(async_records(&reader, Some(region)).await == sync_records(&path, Some(region)));
+        }
+
+        let pos_only = ParsingTemplate::new_with(&[Fields::Pos]);
+        let mut reader = AsyncReader::new(std::io::Cursor::new(std::fs::read(&path).unwrap()), pos_only)
+            .await
+            .unwrap();
+        reader.set_prefetch(0);
+        let records: Vec<GbamRecord> = reader.records().try_collect().await.unwrap();
+        assert_eq!(records[4_321].pos, Some(3_210));
+        assert!(records[0].read_name.is_none());
+        assert!(reader.fetch(&Region::new(0, 0, 10)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_async_reader_file() {
+        let dir = TempDir::new("gbam_async").unwrap();
+        let path = dir.path().join("sorted.gbam");
+        write_sorted(&path);
+
+        let file = tokio::fs::File::open(&path).await.unwrap();
+        let reader = AsyncReader::new(file, all_fields()).await.unwrap();
+        // Streams of one reader are independent.
+        let (all, region) = futures::join!(
+            async_records(&reader, None),
+            async_records(&reader, Some(Region::new(1, 100, 30_000)))
+        );
+        assert!(all == sync_records(&path, None));
+        assert!(region == sync_records(&path, Some(Region::new(1, 100, 30_000))));
+    }
+}
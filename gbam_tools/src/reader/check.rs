@@ -0,0 +1,254 @@
+//! Deep check of blocks of files from untrusted sources.
+use std::collections::BTreeSet;
+use std::io;
+use std::sync::Arc;
+
+use bam_tools::record::fields::{field_item_size, field_type, var_size_field_to_index, FieldType, Fields};
+
+use super::column::decode_field_block;
+use super::reader::Reader;
+use crate::layout::{index_entry, INDEX_ENTRY_SIZE};
+use crate::source_index::{SourceIndexColumn, SourceIndexMeta};
+use crate::U32_SIZE;
+
+impl Reader {
+    /// Decodes every block read by columns of parsing template, and the
+    /// source index, and checks they hold what meta says: values of every
+    /// item of fixed sized fields, offsets of variable sized ones within
+    /// their blocks. Meta is checked when the file is opened, blocks aren't,
+    /// and columns panic on damaged ones, so files from untrusted sources
+    /// are checked with it before reading. Reads the whole file.
+    pub fn check_blocks(&self) -> io::Result<()> {
+        let meta = &self.file_meta;
+        let mut fixed = Vec::new();
+        let mut variable = Vec::new();
+        for &field in self.parsing_template.get_active_data_fields_iter() {
+            // Fields left out by write template have no items.
+            if self.amount > 0 && meta.count_items(&field) == 0 {
+                return Err(invalid_data(format!(
+                    "Field {} has no items, but the file has {} records",
+                    field, self.amount
+                )));
+            }
+            if matches!(field_type(&field), FieldType::FixedSized) {
+                fixed.push(field);
+                continue;
+            }
+            variable.push(field);
+            fixed.push(self.index_field(field)?);
+        }
+        // Active index fields, derived ones have no blocks.
+        fixed.extend(self.parsing_template.get_active_fields_iter().filter(|field| {
+            matches!(field_type(field), FieldType::FixedSized) && !meta.view_blocks(field).is_empty()
+        }));
+        fixed.sort_unstable_by_key(|field| *field as usize);
+        fixed.dedup();
+        for &field in &fixed {
+            self.check_fixed(field)?;
+        }
+        for &field in &variable {
+            self.check_offsets(field)?;
+        }
+        if let Some(column) = SourceIndexMeta::from_file_meta(meta)? {
+            let starts: Vec<usize> = column
+                .blocks
+                .iter()
+                .scan(0, |next, block| {
+                    let start = *next;
+                    *next += block.numitems as usize;
+                    Some((start, block.numitems))
+                })
+                .filter(|&(_, numitems)| numitems > 0)
+                .map(|(start, _)| start)
+                .collect();
+            let mut column = SourceIndexColumn::new(Arc::new(column), self.mmap.clone());
+            for start in starts {
+                column.get(start)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Fixed sized field offsets of variable sized `field` are read from.
+    fn index_field(&self, field: Fields) -> io::Result<Fields> {
+        let index = var_size_field_to_index(&field);
+        if !self.file_meta.is_index_derived(&index) {
+            return Ok(index);
+        }
+        if self.amount > 0 && self.file_meta.count_items(&Fields::RawQual) == 0 {
+            return Err(invalid_data(format!(
+                "Offsets of field {} are derived from RawQual, which has no items",
+                field
+            )));
+        }
+        Ok(Fields::SequenceLength)
+    }
+
+    /// Decoded block `block_num` of `field`.
+    fn decode_checked(&self, field: Fields, block_num: usize) -> io::Result<Vec<u8>> {
+        let block = &self.file_meta.view_blocks(&field)[block_num];
+        let data = self.block_data(block)?;
+        let decrypted;
+        let data = match &self.ciphers[field as usize] {
+            Some(cipher) => {
+                decrypted = cipher.decrypt(block_num as u64, &data)?;
+                &decrypted[..]
+            }
+            None => &data[..],
+        };
+        decode_field_block(&self.file_meta, field, block, data, self.reference.get()).map_err(|err| {
+            io::Error::new(err.kind(), format!("Block {} of field {}: {}", block_num, field, err))
+        })
+    }
+
+    fn check_fixed(&self, field: Fields) -> io::Result<()> {
+        let item_size = field_item_size(&field).unwrap_or(0);
+        for (block_num, block) in self.file_meta.view_blocks(&field).iter().enumerate() {
+            let len = self.decode_checked(field, block_num)?.len();
+            if len != block.numitems as usize * item_size {
+                return Err(invalid_data(format!(
+                    "Block {} of field {} has {} bytes, not {} of its {} items",
+                    block_num,
+                    field,
+                    len,
+                    block.numitems as usize * item_size,
+                    block.numitems
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that offsets of records in blocks of variable sized `field`
+    /// are non-decreasing within each block and end within it, whether they
+    /// are stored or derived from sequence lengths.
+    fn check_offsets(&self, field: Fields) -> io::Result<()> {
+        let meta = &self.file_meta;
+        let index = self.index_field(field)?;
+        let mut ends = Vec::new();
+        for block_num in 0..meta.view_blocks(&index).len() {
+            let data = self.decode_checked(index, block_num)?;
+            ends.extend(data.chunks_exact(INDEX_ENTRY_SIZE).map(index_entry));
+        }
+        let derived = index != var_size_field_to_index(&field);
+        // Offsets in SequenceLength restart at first records of RawQual blocks.
+        let restarts: BTreeSet<u64> = meta.block_starts(&Fields::RawQual).into_iter().collect();
+        let end_of = |rec_num: usize| {
+            ends.get(rec_num)
+                .copied()
+                .ok_or_else(|| invalid_data(format!("Index {} has no offset of record {}", index, rec_num)))
+        };
+        let starts = meta.block_starts(&field);
+        for (block_num, block) in meta.view_blocks(&field).iter().enumerate() {
+            let len = self.decode_checked(field, block_num)?.len();
+            let first = starts[block_num] as usize;
+            let mut start = 0;
+            for rec_num in first..first + block.numitems as usize {
+                let end = if !derived {
+                    end_of(rec_num)?
+                } else if restarts.contains(&(rec_num as u64)) {
+                    start + end_of(rec_num)?.div_ceil(2)
+                } else {
+                    let prev = rec_num.checked_sub(1).map_or(Ok(0), end_of)?;
+                    let seq_len = end_of(rec_num)?.saturating_sub(prev);
+                    start + seq_len.div_ceil(2)
+                };
+                // Cigars are read as whole ops.
+                if end < start
+                    || end > len
+                    || (field == Fields::RawCigar && !(end - start).is_multiple_of(U32_SIZE))
+                {
+                    return Err(invalid_data(format!(
+                        "Record {} of field {} takes bytes {}..{} of block {} of {} bytes",
+                        rec_num, field, start, end, block_num, len
+                    )));
+                }
+                start = end;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::meta::Codecs;
+    use crate::reader::parse_tmplt::ParsingTemplate;
+    use crate::test_utils::{open_test_file, sam_header_bytes, test_ref_seqs, TestRecord};
+    use crate::writer::{Writer, WriterSettings};
+    use bam_tools::record::fields::FIELDS_NUM;
+    use std::path::Path;
+    use tempdir::TempDir;
+
+    /// Bytes of file with blocks of 50 records stored uncompressed, so
+    /// they can be damaged in place.
+    fn write_plain(path: &Path, derived: bool) -> Vec<u8> {
+        let ref_seqs = test_ref_seqs();
+        let settings = WriterSettings {
+            codecs: vec![Codecs::NoCompression; FIELDS_NUM],
+            sam_header: sam_header_bytes("", &ref_seqs),
+            ref_seqs,
+            full_command: String::from("test"),
+            ..Default::default()
+        };
+        let mut writer = Writer::create(path, settings).unwrap();
+        writer.set_rows_per_block(50);
+        writer.set_derived_seq_index(derived);
+        for i in 0..200 {
+            writer.push_record(&TestRecord::new(0, i, &format!("r{}", i)).to_raw(), false).unwrap();
+        }
+        writer.finish_with_summary(false).unwrap();
+        std::fs::read(path).unwrap()
+    }
+
+    fn open_and_check(bytes: Vec<u8>) -> io::Result<Reader> {
+        let mut template = ParsingTemplate::new();
+        template.set_all();
+        let reader = Reader::from_bytes(bytes, template)?;
+        reader.check_blocks()?;
+        Ok(reader)
+    }
+
+    #[test]
+    fn test_damaged_offsets() {
+        let dir = TempDir::new("gbam_check").unwrap();
+        for derived in [false, true] {
+            let path = dir.path().join(format!("plain_{}.gbam", derived));
+            let bytes = write_plain(&path, derived);
+            open_and_check(bytes.clone()).unwrap();
+            let meta = open_test_file(&path).file_meta.clone();
+            // The last offset of the first block of an index points past its data block.
+            let index = if derived { Fields::SequenceLength } else { Fields::LName };
+            let block = &meta.view_blocks(&index)[0];
+            let end = (block.seekpos + u64::from(block.block_size)) as usize;
+            let mut damaged = bytes.clone();
+            damaged[end - INDEX_ENTRY_SIZE..end].copy_from_slice(&u32::MAX.to_le_bytes());
+            let err = open_and_check(damaged).err().unwrap();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            assert!(err.to_string().starts_with("Record 49 of field "), "{}", err);
+        }
+    }
+
+    #[test]
+    fn test_damaged_bytes() {
+        let dir = TempDir::new("gbam_check").unwrap();
+        let bytes = write_plain(&dir.path().join("plain.gbam"), false);
+        // Files which pass the checks are read to the end, whatever byte is damaged.
+        let mut readable = 0;
+        for pos in (0..bytes.len()).step_by(7) {
+            let mut damaged = bytes.clone();
+            damaged[pos] ^= 0xff;
+            if let Ok(mut reader) = open_and_check(damaged) {
+                let mut records = reader.records();
+                while records.next_rec().unwrap().is_some() {}
+                readable += 1;
+            }
+        }
+        assert!(readable > 0);
+    }
+}
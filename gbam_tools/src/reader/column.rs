@@ -1,17 +1,26 @@
-use std::{collections::BTreeMap, io::Result, sync::Arc};
+use std::collections::{BTreeMap, VecDeque};
+use std::fs::File;
+use std::ops::Range;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 
-use super::reader::generate_block_treemap;
+use super::reader::{generate_block_treemap, FileBytes};
 use super::record::GbamRecord;
-use crate::SIZE_LIMIT;
-use bam_tools::record::fields::Fields;
-use byteorder::{LittleEndian, ReadBytesExt};
-use flate2::write::GzDecoder;
+use bam_tools::record::fields::{Fields, FIELDS_NUM};
+use flate2::read::GzDecoder;
 use lzzzz::lz4;
-use memmap2::Mmap;
-use std::convert::TryFrom;
-use std::io::{Read, Write};
+use once_cell::sync::OnceCell;
+use std::io::Read;
+#[cfg(feature = "xz")]
 use xz2::read::XzDecoder;
 
+use crate::encryption::BlockCipher;
+use crate::error::{CodecError, CorruptionError, GbamError, Result};
+use crate::layout::{index_entry, to_usize};
+use crate::meta::{BlockMeta, SeqEncoding};
+use crate::ref_compression::{decode_block, RefSeqMap};
+use crate::seq_packing::unpack_block;
+use crate::trace::{trace_span, Counters, Timer};
 use crate::{meta::FileMeta, Codecs};
 
 // Contains fields needed both for fixed sized fields and variable sized fields.
@@ -22,84 +31,541 @@ pub struct Inner {
     range_end: usize,
     field: Fields,
     buffer: Vec<u8>,
-    reader: Arc<Mmap>,
+    reader: Arc<FileBytes>,
+    // Set for encrypted fields.
+    cipher: Option<BlockCipher>,
+    // Block currently held in buffer.
+    cur_block: Option<usize>,
+    // Shared by reader columns, holds blocks other than the current ones.
+    block_cache: Arc<BlockCache>,
+    // Decompressed blocks count, indexed by field. Shared by reader columns.
+    decompressed: Arc<Vec<AtomicU64>>,
+    read_ahead: Arc<ReadAhead>,
+    // Blocks already requested by read ahead.
+    requested: Range<usize>,
+    // Set by `Reader::set_reference()`, shared by reader columns.
+    reference: Arc<OnceCell<RefSeqMap>>,
+    // Totals of the reader, shared by its columns.
+    counters: Counters,
 }
 
 impl Inner {
-    pub(crate) fn new(meta: Arc<FileMeta>, field: Fields, reader: Arc<Mmap>) -> Self {
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn new(
+        meta: Arc<FileMeta>,
+        field: Fields,
+        reader: Arc<FileBytes>,
+        cipher: Option<BlockCipher>,
+        decompressed: Arc<Vec<AtomicU64>>,
+        read_ahead: Arc<ReadAhead>,
+        block_cache: Arc<BlockCache>,
+        reference: Arc<OnceCell<RefSeqMap>>,
+        counters: Counters,
+    ) -> Self {
         Inner {
             meta,
             range_begin: 0,
             range_end: 0,
             field,
-            buffer: Vec::<u8>::with_capacity(SIZE_LIMIT * 2),
+            // Sized by each block, records larger than SIZE_LIMIT make larger ones.
+            buffer: Vec::new(),
             reader,
+            cipher,
+            cur_block: None,
+            block_cache,
+            decompressed,
+            read_ahead,
+            requested: 0..0,
+            reference,
+            counters,
         }
     }
+
+    /// Makes `block_num` current, the previous block goes to the cache.
+    /// Returns false if it was cached, and no decompression is needed.
+    fn swap_in_block(&mut self, block_num: usize) -> bool {
+        if self.cur_block == Some(block_num) {
+            return false;
+        }
+        let cache = &self.block_cache;
+        let prev = self.cur_block.replace(block_num);
+        let cached = cache.take(self.field, block_num);
+        let hit = cached.is_some();
+        let prev_buffer = std::mem::replace(&mut self.buffer, cached.unwrap_or_default());
+        let spare = match prev {
+            Some(prev) => cache.insert(self.field, prev, prev_buffer),
+            None => Some(prev_buffer),
+        };
+        // Keep an allocation for the block about to be decompressed.
+        if let (false, Some(spare)) = (hit, spare) {
+            self.buffer = spare;
+        }
+        !hit
+    }
+
+    /// Drops the current block, it's read again when needed.
+    fn release(&mut self) {
+        self.buffer = Vec::new();
+        self.cur_block = None;
+        self.range_begin = 0;
+        self.range_end = 0;
+        self.requested = 0..0;
+    }
+}
+
+/// Bytes held by decompressed blocks of a reader, see
+/// `Reader::memory_usage()`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MemoryUsage {
+    /// Current blocks of open columns, with their index columns, by field.
+    pub columns: Vec<(Fields, usize)>,
+    /// Blocks of the block cache, which is shared by cursors of a
+    /// `SharedReader`.
+    pub block_cache: usize,
+}
+
+impl MemoryUsage {
+    pub fn total(&self) -> usize {
+        self.columns.iter().map(|(_, bytes)| bytes).sum::<usize>() + self.block_cache
+    }
+}
+
+/// Hits, misses and evictions of reader block cache since the file was
+/// opened, and decompressed bytes it holds.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    pub bytes: u64,
+}
+
+/// Number of independently locked parts of the block cache.
+const CACHE_SHARDS: usize = 16;
+
+type Shard = VecDeque<((Fields, usize), Vec<u8>)>;
+
+/// Decompressed blocks of all columns of a reader, by field and block
+/// number. Blocks are spread over shards locked on their own, so readers
+/// sharing a file, see `SharedReader`, rarely wait on each other. Once the
+/// byte budget is exceeded, least recently used blocks of the shard being
+/// inserted to are evicted, then of the following ones. Blocks currently
+/// used by columns are not in the cache, they are taken out on hit and put
+/// back when columns move on.
+pub(crate) struct BlockCache {
+    budget: AtomicUsize,
+    // Least recently used first. Counters change under the shard lock, so
+    // `bytes` never falls below the bytes held.
+    shards: Vec<Mutex<Shard>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+    bytes: AtomicU64,
+}
+
+impl BlockCache {
+    pub fn new(budget: usize) -> Self {
+        Self {
+            budget: AtomicUsize::new(budget),
+            shards: (0..CACHE_SHARDS).map(|_| Mutex::new(VecDeque::new())).collect(),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+            bytes: AtomicU64::new(0),
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+            bytes: self.bytes.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn set_budget(&self, budget: usize) {
+        self.budget.store(budget, Ordering::Relaxed);
+        self.evict(0);
+    }
+
+    pub fn clear(&self) {
+        for shard in &self.shards {
+            let mut shard = shard.lock().unwrap();
+            for (_, buf) in shard.drain(..) {
+                self.bytes.fetch_sub(buf.len() as u64, Ordering::Relaxed);
+            }
+        }
+    }
+
+    fn shard_of(field: Fields, block_num: usize) -> usize {
+        (block_num.wrapping_mul(FIELDS_NUM) + field as usize) % CACHE_SHARDS
+    }
+
+    fn over_budget(&self) -> bool {
+        self.bytes.load(Ordering::Relaxed) > self.budget.load(Ordering::Relaxed) as u64
+    }
+
+    fn take(&self, field: Fields, block_num: usize) -> Option<Vec<u8>> {
+        let mut shard = self.shards[Self::shard_of(field, block_num)].lock().unwrap();
+        match shard.iter().position(|(key, _)| *key == (field, block_num)) {
+            Some(pos) => {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                let (_, buf) = shard.remove(pos).unwrap();
+                self.bytes.fetch_sub(buf.len() as u64, Ordering::Relaxed);
+                Some(buf)
+            }
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+
+    /// Caches a block, unless it's over budget on its own. Returns a buffer
+    /// which can be reused: the block itself if it was not cached, or one
+    /// of evicted blocks.
+    fn insert(&self, field: Fields, block_num: usize, buf: Vec<u8>) -> Option<Vec<u8>> {
+        if buf.len() > self.budget.load(Ordering::Relaxed) {
+            return Some(buf);
+        }
+        let shard_num = Self::shard_of(field, block_num);
+        {
+            let mut shard = self.shards[shard_num].lock().unwrap();
+            self.bytes.fetch_add(buf.len() as u64, Ordering::Relaxed);
+            shard.push_back(((field, block_num), buf));
+        }
+        self.evict(shard_num)
+    }
+
+    // Locks one shard at a time, so concurrent evictions don't deadlock.
+    fn evict(&self, first_shard: usize) -> Option<Vec<u8>> {
+        let mut spare = None;
+        for i in 0..CACHE_SHARDS {
+            if !self.over_budget() {
+                break;
+            }
+            let mut shard = self.shards[(first_shard + i) % CACHE_SHARDS].lock().unwrap();
+            while self.over_budget() {
+                let (_, buf) = match shard.pop_front() {
+                    Some(block) => block,
+                    None => break,
+                };
+                self.bytes.fetch_sub(buf.len() as u64, Ordering::Relaxed);
+                self.evictions.fetch_add(1, Ordering::Relaxed);
+                spare = Some(buf);
+            }
+        }
+        spare
+    }
+}
+
+/// Number of blocks of each column requested at once with sequential hint.
+pub(crate) const READ_AHEAD_BLOCKS: usize = 8;
+
+/// Amount of block bytes requested from the file and number of requests.
+/// Without sequential hint every fetched block is a request of its own.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ReadStats {
+    pub bytes_read: u64,
+    pub read_calls: u64,
+}
+
+/// Read ahead of blocks, shared by reader columns. With sequential hint,
+/// columns request their upcoming blocks from the kernel with
+/// `posix_fadvise(WILLNEED)`, blocks lying next to each other in the file
+/// in one call. Hints are no-ops on non-unix targets and for files in
+/// memory, but are counted the same.
+pub(crate) struct ReadAhead {
+    file: Option<File>,
+    enabled: AtomicBool,
+    bytes_read: AtomicU64,
+    read_calls: AtomicU64,
+}
+
+impl ReadAhead {
+    pub fn new(file: Option<File>) -> Self {
+        Self {
+            file,
+            enabled: AtomicBool::new(false),
+            bytes_read: AtomicU64::new(0),
+            read_calls: AtomicU64::new(0),
+        }
+    }
+
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed);
+        if enabled {
+            self.advise(0, 0, Advice::Sequential);
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    pub fn stats(&self) -> ReadStats {
+        ReadStats {
+            bytes_read: self.bytes_read.load(Ordering::Relaxed),
+            read_calls: self.read_calls.load(Ordering::Relaxed),
+        }
+    }
+
+    fn request(&self, offset: u64, len: u64) {
+        self.bytes_read.fetch_add(len, Ordering::Relaxed);
+        self.read_calls.fetch_add(1, Ordering::Relaxed);
+        if self.is_enabled() {
+            self.advise(offset, len, Advice::WillNeed);
+        }
+    }
+
+    // Hints are best effort, failures are ignored.
+    #[cfg(unix)]
+    fn advise(&self, offset: u64, len: u64, advice: Advice) {
+        use std::convert::TryFrom;
+        use std::os::unix::io::AsRawFd;
+        let file = match &self.file {
+            Some(file) => file,
+            None => return,
+        };
+        let advice = match advice {
+            Advice::Sequential => libc::POSIX_FADV_SEQUENTIAL,
+            Advice::WillNeed => libc::POSIX_FADV_WILLNEED,
+        };
+        let (offset, len) = match (libc::off_t::try_from(offset), libc::off_t::try_from(len)) {
+            (Ok(offset), Ok(len)) => (offset, len),
+            _ => return,
+        };
+        unsafe {
+            libc::posix_fadvise(file.as_raw_fd(), offset, len, advice);
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn advise(&self, _offset: u64, _len: u64, _advice: Advice) {}
+}
+
+enum Advice {
+    Sequential,
+    WillNeed,
+}
+
+impl Inner {
+    /// Counts read of `block_num`. With sequential hint, requests it with
+    /// the next blocks, unless it was requested before.
+    fn read_block(&mut self, block_num: usize) {
+        let blocks = self.meta.view_blocks(&self.field);
+        if !self.read_ahead.is_enabled() {
+            let block = &blocks[block_num];
+            self.read_ahead.request(block.seekpos, block.block_size as u64);
+            return;
+        }
+        if self.requested.contains(&block_num) {
+            return;
+        }
+        let end = std::cmp::min(block_num + READ_AHEAD_BLOCKS, blocks.len());
+        let (mut start, mut len) = (blocks[block_num].seekpos, 0);
+        for block in &blocks[block_num..end] {
+            if block.seekpos != start + len {
+                self.read_ahead.request(start, len);
+                start = block.seekpos;
+                len = 0;
+            }
+            len += block.block_size as u64;
+        }
+        self.read_ahead.request(start, len);
+        self.requested = block_num..end;
+    }
 }
 
 /// Defines how columns will operate. It is needed since variable sized fields
 /// columns also require parsing of additional fixed sized fields columns.
 pub trait Column {
-    // Fills GbamRecord field with data from corresponding BAM record.
-    fn fill_record_field(&mut self, item_num: usize, rec: &mut GbamRecord);
+    /// Fills GbamRecord field with data from corresponding BAM record. Fails
+    /// with `CorruptionError::UnreadableBlock` if the block of the item can't
+    /// be read, decoded, or doesn't hold it, `CodecError::DecompressionFailed`
+    /// if it can't be decompressed, and `GbamError::Io` if the source fails.
+    fn fill_record_field(&mut self, item_num: usize, rec: &mut GbamRecord) -> Result<()>;
+    /// Bytes of item as stored in BAM record, little endian. Fails like
+    /// `fill_record_field()`.
+    fn raw_item(&mut self, item_num: usize) -> Result<&[u8]>;
+    /// Bytes allocated for decompressed blocks.
+    fn buffer_bytes(&self) -> usize;
+    /// Drops decompressed blocks, they are read again when needed.
+    fn release_buffers(&mut self);
 }
 
 /// GBAM file column. Responsible for fetching data.
-pub struct FixedColumn(Inner, usize);
+pub struct FixedColumn(Inner, usize, Option<BTreeMap<usize, usize>>);
 
 impl Column for FixedColumn {
     /// Fetches data into provider record buffer. If item is located outside of
     /// currently loaded data block, the new block will be loaded and
     /// decompressed.
-    fn fill_record_field(&mut self, item_num: usize, rec: &mut GbamRecord) {
-        rec.parse_from_bytes(&self.0.field.clone(), self.get_item(item_num));
+    fn fill_record_field(&mut self, item_num: usize, rec: &mut GbamRecord) -> Result<()> {
+        let field = self.0.field;
+        rec.parse_from_bytes(&field, self.try_get_item(item_num)?);
+        Ok(())
+    }
+
+    fn raw_item(&mut self, item_num: usize) -> Result<&[u8]> {
+        self.try_get_item(item_num)
+    }
+
+    fn buffer_bytes(&self) -> usize {
+        self.0.buffer.capacity()
+    }
+
+    fn release_buffers(&mut self) {
+        self.0.release();
     }
 }
 
 impl FixedColumn {
     pub fn new(inner: Inner, field_size: usize) -> Self {
-        Self(inner, field_size)
+        // Blocks are of equal size except maybe the last one, unless the
+        // file was split or concatenated.
+        let blocks = inner.meta.view_blocks(&inner.field);
+        let uniform = blocks
+            .iter()
+            .take(blocks.len().saturating_sub(1))
+            .all(|block| block.numitems == blocks[0].numitems)
+            && blocks.last().is_none_or(|last| last.numitems <= blocks[0].numitems)
+            && blocks.first().is_none_or(|first| first.numitems > 0);
+        let blocks_map = if uniform {
+            None
+        } else {
+            Some(generate_block_treemap(&inner.meta, &inner.field))
+        };
+        Self(inner, field_size, blocks_map)
     }
-    fn get_item(&mut self, item_num: usize) -> &[u8] {
-        if let Some(block_num) = self.find_block(item_num) {
-            Self::update_buffer(&mut self.0, block_num);
+    fn try_get_item(&mut self, item_num: usize) -> Result<&[u8]> {
+        if let Some((range_begin, block_num)) = self.find_block(item_num) {
+            Self::update_buffer(&mut self.0, block_num, range_begin)?;
         }
         let rec_num_in_block = item_num - self.0.range_begin;
         let item_size = self.1;
         let offset = rec_num_in_block * item_size;
-        &self.0.buffer[offset..offset + item_size]
+        let inner = &self.0;
+        inner.buffer.get(offset..offset + item_size).ok_or_else(|| {
+            let msg = format!("Item {} is past the end of the block", item_num);
+            unreadable(inner.field, inner.cur_block.unwrap_or_default(), msg)
+        })
     }
     // Finds blocks where record is located. None is returned if block is already loaded.
-    fn find_block(&self, item_num: usize) -> Option<usize> {
+    fn find_block(&self, item_num: usize) -> Option<(usize, usize)> {
         if item_num >= self.0.range_begin && item_num < self.0.range_end {
             return None;
         }
+        if let Some(blocks) = &self.2 {
+            return blocks
+                .range(..=item_num)
+                .next_back()
+                .map(|(&range_begin, &block_num)| (range_begin, block_num));
+        }
         // All blocks sizes are equal except maybe the last one since it's a fixed sized column and block size limit is constant.
-        let block_len = self.0.meta.view_blocks(&self.0.field)[0].numitems;
-        Some(item_num / block_len as usize)
+        // Without blocks, fetching any fails.
+        let blocks = self.0.meta.view_blocks(&self.0.field);
+        let block_len = blocks.first().map_or(1, |block| block.numitems as usize);
+        let block_num = item_num / block_len;
+        Some((block_num * block_len, block_num))
     }
 
-    fn update_buffer(inner: &mut Inner, block_num: usize) {
-        fetch_block(inner, block_num).unwrap();
-        let block_len = inner.meta.view_blocks(&inner.field)[0].numitems as usize;
+    fn update_buffer(inner: &mut Inner, block_num: usize, range_begin: usize) -> Result<()> {
+        fetch_block_or_release(inner, block_num)?;
         let cur_block_len = inner.meta.view_blocks(&inner.field)[block_num].numitems as usize;
-        inner.range_begin = block_num * block_len;
+        inner.range_begin = range_begin;
         inner.range_end = inner.range_begin + cur_block_len;
+        Ok(())
     }
 }
 
 /// Column managing access to variable sized data. Utilizes another column (for fixed sized fields) to index data.
 pub struct VariableColumn {
     inner: Inner,
-    index: FixedColumn,
+    index: Offsets,
     // Used to quickly determine what block record belongs to.
     blocks: BTreeMap<usize, usize>,
 }
 
+enum Offsets {
+    /// Index column of end offsets of records in their data block.
+    Stored(FixedColumn),
+    Derived(DerivedOffsets),
+}
+
+/// End offsets of records in RawSequence blocks of files without RawSeqLen
+/// column, see `FileMeta::is_index_derived()`. Sequence lengths are taken
+/// from SequenceLength, the index of RawQual, and offsets are summed once
+/// per block.
+struct DerivedOffsets {
+    qual_index: FixedColumn,
+    // First records of RawQual blocks, offsets in SequenceLength restart at them.
+    qual_blocks: BTreeMap<usize, usize>,
+    // Records of the block offsets are summed for.
+    records: Range<usize>,
+    ends: Vec<usize>,
+}
+
+impl DerivedOffsets {
+    fn seq_len(&mut self, rec_num: usize) -> Result<usize> {
+        let end = index_entry(self.qual_index.try_get_item(rec_num)?);
+        let starts_block = self.qual_blocks.contains_key(&rec_num);
+        let prev = match rec_num.checked_sub(1) {
+            Some(prev) if !starts_block => index_entry(self.qual_index.try_get_item(prev)?),
+            _ => 0,
+        };
+        Ok(end.saturating_sub(prev))
+    }
+
+    fn item_range(&mut self, records: Range<usize>, item_num: usize) -> Result<Range<usize>> {
+        if self.records != records {
+            self.ends.clear();
+            let mut end = 0;
+            for rec_num in records.clone() {
+                // Bases are packed two per byte.
+                end += self.seq_len(rec_num)?.div_ceil(2);
+                self.ends.push(end);
+            }
+            self.records = records;
+        }
+        let pos = item_num - self.records.start;
+        let start = if pos == 0 { 0 } else { self.ends[pos - 1] };
+        Ok(start..self.ends[pos])
+    }
+}
+
 impl Column for VariableColumn {
-    fn fill_record_field(&mut self, item_num: usize, rec: &mut GbamRecord) {
-        rec.parse_from_bytes(&self.inner.field.clone(), self.get_item(item_num));
+    fn fill_record_field(&mut self, item_num: usize, rec: &mut GbamRecord) -> Result<()> {
+        let field = self.inner.field;
+        rec.parse_from_bytes(&field, self.try_get_item(item_num)?);
+        Ok(())
+    }
+
+    fn raw_item(&mut self, item_num: usize) -> Result<&[u8]> {
+        self.try_get_item(item_num)
+    }
+
+    fn buffer_bytes(&self) -> usize {
+        let index_bytes = match &self.index {
+            Offsets::Stored(index) => index.buffer_bytes(),
+            Offsets::Derived(derived) => {
+                derived.qual_index.buffer_bytes() + derived.ends.capacity() * std::mem::size_of::<usize>()
+            }
+        };
+        self.inner.buffer.capacity() + index_bytes
+    }
+
+    fn release_buffers(&mut self) {
+        self.inner.release();
+        match &mut self.index {
+            Offsets::Stored(index) => index.release_buffers(),
+            Offsets::Derived(derived) => {
+                derived.qual_index.release_buffers();
+                derived.records = 0..0;
+                derived.ends = Vec::new();
+            }
+        }
     }
 }
 
@@ -108,23 +574,59 @@ impl VariableColumn {
         Self {
             blocks: generate_block_treemap(&inner.meta, &inner.field),
             inner,
-            index,
+            index: Offsets::Stored(index),
+        }
+    }
+
+    /// RawSequence column of a file without RawSeqLen column, `qual_index`
+    /// is SequenceLength column.
+    pub fn with_derived_index(inner: Inner, qual_index: FixedColumn) -> Self {
+        let qual_blocks = generate_block_treemap(&inner.meta, &Fields::RawQual);
+        Self {
+            blocks: generate_block_treemap(&inner.meta, &inner.field),
+            inner,
+            index: Offsets::Derived(DerivedOffsets {
+                qual_index,
+                qual_blocks,
+                records: 0..0,
+                ends: Vec::new(),
+            }),
         }
     }
 
-    fn get_item(&mut self, item_num: usize) -> &[u8] {
+    fn try_get_item(&mut self, item_num: usize) -> Result<&[u8]> {
         if let Some((range_begin, block_num)) = self.find_block(item_num) {
-            Self::update_buffer(&mut self.inner, block_num, range_begin);
+            Self::update_buffer(&mut self.inner, block_num, range_begin)?;
+        }
+        let block_num = self.inner.cur_block.unwrap_or_default();
+        if item_num >= self.inner.range_end {
+            let msg = format!("Item {} is past the end of the block", item_num);
+            return Err(unreadable(self.inner.field, block_num, msg));
         }
         let rec_num_in_block = item_num - self.inner.range_begin;
-        let mut read_offset =
-            |n| self.index.get_item(n).read_u32::<LittleEndian>().unwrap() as usize;
-        let start = match rec_num_in_block {
-            0 => 0,
-            _ => read_offset(item_num - 1),
+        let range = match &mut self.index {
+            Offsets::Stored(index) => {
+                let start = match rec_num_in_block {
+                    0 => 0,
+                    _ => index_entry(index.try_get_item(item_num - 1)?),
+                };
+                start..index_entry(index.try_get_item(item_num)?)
+            }
+            Offsets::Derived(derived) => {
+                derived.item_range(self.inner.range_begin..self.inner.range_end, item_num)?
+            }
         };
-        let end = read_offset(item_num);
-        &self.inner.buffer[start..end]
+        let inner = &self.inner;
+        inner.buffer.get(range.clone()).ok_or_else(|| {
+            let msg = format!(
+                "Item {} takes bytes {}..{} of the block of {} bytes",
+                item_num,
+                range.start,
+                range.end,
+                inner.buffer.len()
+            );
+            unreadable(inner.field, block_num, msg)
+        })
     }
 
     // Finds blocks where record is located. None is returned if block is already loaded.
@@ -144,66 +646,212 @@ impl VariableColumn {
         )
     }
 
-    fn update_buffer(inner: &mut Inner, block_num: usize, range_begin: usize) {
-        fetch_block(inner, block_num).unwrap();
+    fn update_buffer(inner: &mut Inner, block_num: usize, range_begin: usize) -> Result<()> {
+        fetch_block_or_release(inner, block_num)?;
         let block_len = inner.meta.view_blocks(&inner.field)[block_num].numitems as usize;
         inner.range_begin = range_begin;
         inner.range_end = inner.range_begin + block_len;
+        Ok(())
     }
 }
 
-/// Fetch and decompress a data block.
+/// Fetches block like `fetch_block()`. On errors the buffer may hold part of
+/// the block, it is dropped, so the block is fetched again when needed.
+fn fetch_block_or_release(inner: &mut Inner, block_num: usize) -> Result<()> {
+    fetch_block(inner, block_num).inspect_err(|_| inner.release())
+}
+
+/// `err` of decoding block `block_num` of `field`.
+fn unreadable(field: Fields, block_num: usize, err: impl std::fmt::Display) -> GbamError {
+    let message = err.to_string();
+    let block = block_num as u64;
+    CorruptionError::UnreadableBlock { field, block, message }.into()
+}
+
+/// Fetch and decompress a data block, unless it's cached. Errors of the
+/// source are `GbamError::Io`, of the codec `CodecError::DecompressionFailed`,
+/// others `CorruptionError::UnreadableBlock`.
 fn fetch_block(inner_column: &mut Inner, block_num: usize) -> Result<()> {
+    let field = inner_column.field;
+    if block_num >= inner_column.meta.view_blocks(&field).len() {
+        return Err(unreadable(field, block_num, "File has no such block"));
+    }
+    if !inner_column.swap_in_block(block_num) {
+        return Ok(());
+    }
     // println!("Fetching for {}", inner_column.field);
-    let field = &inner_column.field;
-    let block_meta = inner_column.meta.view_blocks(field).get(block_num).unwrap();
+    inner_column.read_block(block_num);
+    let block_meta = &inner_column.meta.view_blocks(&field)[block_num];
     let reader = &inner_column.reader;
     let block_size = block_meta.block_size;
     let uncompressed_size = block_meta.uncompressed_size;
+    let counters = &inner_column.counters;
+    let _span = trace_span!("fetch_block", field = %field, block = block_num, size = block_size);
+    let timer = Timer::start();
 
-    let data = &reader[usize::try_from(block_meta.seekpos).unwrap()
-        ..usize::try_from(block_meta.seekpos + block_size as u64).unwrap()];
+    let data = reader.read_at(block_meta.seekpos, block_size as usize)?;
+    let decrypted;
+    let data = match &inner_column.cipher {
+        Some(cipher) => {
+            decrypted = cipher
+                .decrypt(block_num as u64, &data)
+                .map_err(|err| unreadable(field, block_num, err))?;
+            &decrypted[..]
+        }
+        None => &data[..],
+    };
+    counters.add_io_time(&timer);
+    counters.add_block(uncompressed_size, u64::from(block_size));
     // inner_column.buffer.clear();
     // dbg!(uncompressed_size);
-    inner_column.buffer.resize(uncompressed_size as usize, 0);
-    let codec = inner_column.meta.get_field_codec(field);
+    let expected = to_usize(uncompressed_size).map_err(|err| unreadable(field, block_num, err))?;
+    inner_column.buffer.clear();
+    let codec = block_meta.codec(*inner_column.meta.get_field_codec(&field));
 
     if uncompressed_size > 0 {
-        decompress_block(data, &mut inner_column.buffer, codec).expect("Decompression failed.");
+        let _span =
+            trace_span!("decompress_block", field = %field, block = block_num, size = uncompressed_size);
+        let timer = Timer::start();
+        inner_column.decompressed[field as usize].fetch_add(1, Ordering::Relaxed);
+        decompress_into(data, &mut inner_column.buffer, &codec, expected).map_err(|err| {
+            CodecError::DecompressionFailed {
+                field,
+                block: block_num as u64,
+                message: err.to_string(),
+            }
+        })?;
+        let buffer = std::mem::take(&mut inner_column.buffer);
+        inner_column.buffer =
+            decode_buffer(inner_column, block_meta, buffer).map_err(|err| unreadable(field, block_num, err))?;
+        counters.add_codec_time(&timer);
     }
 
     Ok(())
 }
 
+/// Decodes decompressed `buffer` of block `block_meta` of the column.
+fn decode_buffer(
+    inner_column: &Inner,
+    block_meta: &BlockMeta,
+    mut buffer: Vec<u8>,
+) -> std::io::Result<Vec<u8>> {
+    let field = &inner_column.field;
+    if *field == Fields::RawSequence {
+        match inner_column.meta.get_seq_encoding() {
+            SeqEncoding::Nibble => {}
+            SeqEncoding::TwoBit => buffer = unpack_block(&buffer)?,
+            SeqEncoding::Reference => {
+                let refs = inner_column.reference.get().ok_or_else(|| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        "RawSequence is encoded against a reference, see Reader::set_reference()",
+                    )
+                })?;
+                buffer = decode_block(&buffer, refs)?;
+            }
+        }
+    }
+    if let Some(transform) = block_meta.transform {
+        buffer = transform.invert(&buffer)?;
+    }
+    block_meta.stripe_of(field, buffer)
+}
+
+/// Decompresses and decodes `data` of block `block` of `field`, decrypted,
+/// into the values columns read. Unlike columns, fails on damaged blocks.
+/// `reference` decodes RawSequence encoded against one.
+pub(crate) fn decode_field_block(
+    meta: &FileMeta,
+    field: Fields,
+    block: &BlockMeta,
+    data: &[u8],
+    reference: Option<&RefSeqMap>,
+) -> std::io::Result<Vec<u8>> {
+    let mut buffer = Vec::new();
+    if block.uncompressed_size == 0 {
+        return Ok(buffer);
+    }
+    let expected = to_usize(block.uncompressed_size)?;
+    decompress_into(data, &mut buffer, &block.codec(*meta.get_field_codec(&field)), expected)?;
+    if field == Fields::RawSequence {
+        match meta.get_seq_encoding() {
+            SeqEncoding::Nibble => {}
+            SeqEncoding::TwoBit => buffer = unpack_block(&buffer)?,
+            SeqEncoding::Reference => {
+                let refs = reference.ok_or_else(|| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        "RawSequence is encoded against a reference, see Reader::set_reference()",
+                    )
+                })?;
+                buffer = decode_block(&buffer, refs)?;
+            }
+        }
+    }
+    if let Some(transform) = block.transform {
+        buffer = transform.invert(&buffer)?;
+    }
+    block.stripe_of(&field, buffer)
+}
+
+/// Decompresses `source` into `dest`, presized to decompressed size of the
+/// block recorded in meta. Fails if `source` decompresses into any other
+/// number of bytes, decoders never write past it.
 pub fn decompress_block(source: &[u8], dest: &mut Vec<u8>, codec: &Codecs) -> std::io::Result<()> {
-    use std::io::Write;
+    let expected = dest.len();
+    decompress_into(source, dest, codec, expected)
+}
+
+/// As `decompress_block()`, `expected` being the decompressed size recorded
+/// in meta. Stream decoders grow `dest` with what they yield instead of
+/// being handed a buffer of the claimed size.
+pub(crate) fn decompress_into(
+    source: &[u8],
+    dest: &mut Vec<u8>,
+    codec: &Codecs,
+    expected: usize,
+) -> std::io::Result<()> {
     match codec {
-        Codecs::Gzip => {
-            let mut decoder = GzDecoder::new(dest);
-            decoder.write_all(source).unwrap();
-            decoder.try_finish().unwrap();
-        }
+        Codecs::Gzip => read_bounded(GzDecoder::new(source), dest, expected)?,
         Codecs::Lz4 => {
-            lz4::decompress(source, dest).unwrap();
-        }
-        Codecs::Brotli => {
-            dest.clear();
-            let mut decompressor = brotli::Decompressor::new(source, 4096);
-            decompressor.read_to_end(dest)?;
-        }
-        Codecs::Zstd => {
-            dest.clear();
-            let mut decoder = zstd::stream::Decoder::new(source)?;
-            decoder.read_to_end(dest)?;
-        }
-        Codecs::Xz => {
-            let mut decoder = XzDecoder::new(source);
-            decoder.read_to_end(dest)?;
+            dest.resize(expected, 0);
+            let len = lz4::decompress(source, dest).map_err(|err| {
+                std::io::Error::new(std::io::ErrorKind::InvalidData, format!("Damaged LZ4 block: {}", err))
+            })?;
+            dest.truncate(len);
         }
+        #[cfg(feature = "brotli")]
+        Codecs::Brotli => read_bounded(brotli::Decompressor::new(source, 4096), dest, expected)?,
+        #[cfg(feature = "zstd")]
+        Codecs::Zstd => read_bounded(zstd::stream::Decoder::new(source)?, dest, expected)?,
+        #[cfg(feature = "xz")]
+        Codecs::Xz => read_bounded(XzDecoder::new(source), dest, expected)?,
         Codecs::NoCompression => {
             dest.clear();
             dest.extend_from_slice(source);
         }
+        // Readers refuse files with codecs which are compiled out.
+        #[allow(unreachable_patterns)]
+        codec => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                format!("Codec {:?} is not compiled in.", codec),
+            ))
+        }
     };
+    if dest.len() != expected {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("Block decompressed into {} bytes, meta records {}", dest.len(), expected),
+        ));
+    }
+    Ok(())
+}
+
+/// Reads `decoder` into `dest`, up to one byte more than `limit` to tell
+/// if it yields more.
+fn read_bounded<R: Read>(decoder: R, dest: &mut Vec<u8>, limit: usize) -> std::io::Result<()> {
+    dest.clear();
+    decoder.take(limit as u64 + 1).read_to_end(dest)?;
     Ok(())
 }
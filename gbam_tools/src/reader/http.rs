@@ -0,0 +1,360 @@
+//! GBAM files served over HTTP(S), like ones on object storage. Every block
+//! is fetched with a range request, open with `Reader::from_source()`.
+use std::io::{self, Read};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+use std::time::Duration;
+
+use super::source::ReadBlockAt;
+
+const DEFAULT_RETRIES: u32 = 3;
+const DEFAULT_BACKOFF: Duration = Duration::from_millis(200);
+const DEFAULT_MAX_PARALLEL: usize = 4;
+
+/// Requests in flight and their limit, shared by clones of a source.
+struct Limiter {
+    // In flight, limit.
+    slots: Mutex<(usize, usize)>,
+    freed: Condvar,
+}
+
+struct Slot<'a>(&'a Limiter);
+
+impl Limiter {
+    fn new(max: usize) -> Self {
+        Self {
+            slots: Mutex::new((0, max)),
+            freed: Condvar::new(),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, (usize, usize)> {
+        self.slots.lock().unwrap()
+    }
+
+    fn acquire(&self) -> Slot<'_> {
+        let mut slots = self.lock();
+        while slots.0 >= slots.1 {
+            slots = self.freed.wait(slots).unwrap();
+        }
+        slots.0 += 1;
+        Slot(self)
+    }
+
+    fn set_max(&self, max: usize) {
+        self.lock().1 = max;
+        self.freed.notify_all();
+    }
+}
+
+impl Drop for Slot<'_> {
+    fn drop(&mut self) {
+        self.0.lock().0 -= 1;
+        self.0.freed.notify_one();
+    }
+}
+
+/// File at `url` read with HTTP range requests. Clones share the limit of
+/// parallel requests, so readers opened from clones of one source, e.g. one
+/// per thread, don't flood the server.
+#[derive(Clone)]
+pub struct HttpSource {
+    url: String,
+    agent: ureq::Agent,
+    retries: u32,
+    backoff: Duration,
+    limiter: Arc<Limiter>,
+}
+
+/// Failed attempt of a request, and whether it's worth retrying.
+struct Failure {
+    error: io::Error,
+    retry: bool,
+}
+
+impl Failure {
+    fn fatal(error: io::Error) -> Self {
+        Self { error, retry: false }
+    }
+
+    fn transient(error: io::Error) -> Self {
+        Self { error, retry: true }
+    }
+}
+
+impl HttpSource {
+    pub fn new(url: &str) -> Self {
+        Self {
+            url: url.to_owned(),
+            agent: ureq::AgentBuilder::new()
+                .timeout_connect(Duration::from_secs(30))
+                .timeout_read(Duration::from_secs(60))
+                .build(),
+            retries: DEFAULT_RETRIES,
+            backoff: DEFAULT_BACKOFF,
+            limiter: Arc::new(Limiter::new(DEFAULT_MAX_PARALLEL)),
+        }
+    }
+
+    /// Failed requests are repeated up to `retries` times, waiting `backoff`
+    /// before the first retry and doubling it before each next one. Only
+    /// connection errors, truncated responses and statuses 429 and 5xx are
+    /// retried. Defaults are 3 retries and 200 ms.
+    pub fn set_retries(&mut self, retries: u32, backoff: Duration) {
+        self.retries = retries;
+        self.backoff = backoff;
+    }
+
+    /// Limits requests in flight of this source and its clones, 4 by default.
+    pub fn set_max_parallel(&mut self, max: usize) {
+        assert!(max > 0, "At least one request must be allowed");
+        self.limiter.set_max(max);
+    }
+
+    /// Requests `len` bytes at `offset` once, returns them with the value of
+    /// Content-Range header.
+    fn get_range(&self, offset: u64, len: usize) -> Result<(Vec<u8>, String), Failure> {
+        let range = format!("bytes={}-{}", offset, offset + len as u64 - 1);
+        let response = match self.agent.get(&self.url).set("Range", &range).call() {
+            Ok(response) => response,
+            Err(ureq::Error::Status(status, _)) => {
+                let error = io::Error::other(format!("GET {} ({}) failed with status {}", self.url, range, status));
+                return Err(match status {
+                    429 | 500..=599 => Failure::transient(error),
+                    _ => Failure::fatal(error),
+                });
+            }
+            Err(err) => return Err(Failure::transient(io::Error::other(err.to_string()))),
+        };
+        if response.status() != 206 {
+            return Err(Failure::fatal(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("Server of {} doesn't support range requests", self.url),
+            )));
+        }
+        let content_range = response.header("Content-Range").unwrap_or_default().to_owned();
+        let mut data = Vec::with_capacity(len);
+        response
+            .into_reader()
+            .take(len as u64 + 1)
+            .read_to_end(&mut data)
+            .map_err(Failure::transient)?;
+        if data.len() != len {
+            return Err(Failure::transient(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("Got {} bytes of {} requested at {} from {}", data.len(), len, offset, self.url),
+            )));
+        }
+        Ok((data, content_range))
+    }
+
+    fn get_with_retries(&self, offset: u64, len: usize) -> io::Result<(Vec<u8>, String)> {
+        let _slot = self.limiter.acquire();
+        let mut backoff = self.backoff;
+        let mut attempt = 0;
+        loop {
+            match self.get_range(offset, len) {
+                Ok(result) => return Ok(result),
+                Err(failure) if !failure.retry || attempt == self.retries => return Err(failure.error),
+                Err(_) => {
+                    attempt += 1;
+                    thread::sleep(backoff);
+                    backoff *= 2;
+                }
+            }
+        }
+    }
+}
+
+impl ReadBlockAt for HttpSource {
+    fn read_at(&self, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+        if len == 0 {
+            return Ok(Vec::new());
+        }
+        self.get_with_retries(offset, len).map(|(data, _)| data)
+    }
+
+    /// Taken from Content-Range of the first byte, so servers not answering
+    /// HEAD requests, like presigned URLs, work too.
+    fn size(&self) -> io::Result<u64> {
+        let (_, content_range) = self.get_with_retries(0, 1)?;
+        content_range
+            .rsplit('/')
+            .next()
+            .and_then(|size| size.trim().parse().ok())
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Server of {} sent no file size, Content-Range is '{}'", self.url, content_range),
+                )
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reader::{parse_tmplt::ParsingTemplate, reader::Reader};
+    use crate::test_utils::{open_test_file, write_test_file, TestRecord};
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tempdir::TempDir;
+
+    /// Serves `data` with range requests, failing the first `failures` of
+    /// them with status 503.
+    #[derive(Default)]
+    struct Server {
+        data: Vec<u8>,
+        failures: AtomicUsize,
+        requests: AtomicUsize,
+        in_flight: AtomicUsize,
+        max_in_flight: AtomicUsize,
+        delay: Duration,
+    }
+
+    impl Server {
+        fn start(self) -> (String, Arc<Server>) {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let url = format!("http://{}/test.gbam", listener.local_addr().unwrap());
+            let server = Arc::new(self);
+            let shared = server.clone();
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    let server = shared.clone();
+                    thread::spawn(move || server.handle(stream.unwrap()));
+                }
+            });
+            (url, server)
+        }
+
+        fn handle(&self, mut stream: TcpStream) {
+            let mut range = None;
+            for line in BufReader::new(&stream).lines() {
+                let line = line.unwrap();
+                if line.is_empty() {
+                    break;
+                }
+                if let Some(value) = line.strip_prefix("Range: bytes=") {
+                    let (start, end) = value.split_once('-').unwrap();
+                    range = Some((start.parse::<usize>().unwrap(), end.parse::<usize>().unwrap()));
+                }
+            }
+            self.requests.fetch_add(1, Ordering::SeqCst);
+            let in_flight = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+            self.max_in_flight.fetch_max(in_flight, Ordering::SeqCst);
+            thread::sleep(self.delay);
+            let failed = self
+                .failures
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+                .is_ok();
+            let (start, end) = range.unwrap();
+            let (status, headers, body) = if failed {
+                ("503 Service Unavailable", String::new(), &[][..])
+            } else if start >= self.data.len() {
+                ("416 Range Not Satisfiable", format!("Content-Range: bytes */{}\r\n", self.data.len()), &[][..])
+            } else {
+                let end = end.min(self.data.len() - 1);
+                (
+                    "206 Partial Content",
+                    format!("Content-Range: bytes {}-{}/{}\r\n", start, end, self.data.len()),
+                    &self.data[start..=end],
+                )
+            };
+            self.in_flight.fetch_sub(1, Ordering::SeqCst);
+            write!(
+                stream,
+                "HTTP/1.1 {}\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n",
+                status,
+                headers,
+                body.len()
+            )
+            .unwrap();
+            stream.write_all(body).unwrap();
+        }
+    }
+
+    fn test_file(dir: &TempDir) -> std::path::PathBuf {
+        let path = dir.path().join("remote.gbam");
+        let records: Vec<_> = (0..1_000).map(|i| TestRecord::new(i % 3, i * 10, &format!("r{}", i))).collect();
+        write_test_file(&path, "", &records);
+        path
+    }
+
+    #[test]
+    fn test_read_over_http() {
+        let dir = TempDir::new("gbam_http").unwrap();
+        let path = test_file(&dir);
+        let (url, server) = Server {
+            data: std::fs::read(&path).unwrap(),
+            ..Default::default()
+        }
+        .start();
+
+        let mut template = ParsingTemplate::new();
+        template.set_all();
+        let mut remote = Reader::from_source(HttpSource::new(&url), template).unwrap();
+        let mut local = open_test_file(&path);
+        assert_eq!(remote.num_records(), 1_000);
+        // Size, file info and meta.
+        assert_eq!(server.requests.load(Ordering::SeqCst), 3);
+
+        let (mut remote_records, mut local_records) = (remote.records(), local.records());
+        while let Some(rec) = local_records.next_rec().unwrap() {
+            assert_eq!(format!("{:?}", remote_records.next_rec().unwrap().unwrap()), format!("{:?}", rec));
+        }
+        assert!(remote_records.next_rec().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_retries() {
+        let dir = TempDir::new("gbam_http").unwrap();
+        let data = std::fs::read(test_file(&dir)).unwrap();
+        let (url, server) = Server {
+            data: data.clone(),
+            failures: AtomicUsize::new(2),
+            ..Default::default()
+        }
+        .start();
+
+        let mut source = HttpSource::new(&url);
+        source.set_retries(1, Duration::from_millis(1));
+        let err = source.read_at(0, 16).err().unwrap();
+        assert!(err.to_string().contains("status 503"), "{}", err);
+        assert_eq!(server.requests.load(Ordering::SeqCst), 2);
+
+        server.failures.store(2, Ordering::SeqCst);
+        source.set_retries(2, Duration::from_millis(1));
+        assert_eq!(source.read_at(10, 16).unwrap(), &data[10..26]);
+        assert_eq!(source.size().unwrap(), data.len() as u64);
+
+        // Not retried.
+        let err = source.read_at(data.len() as u64, 1).err().unwrap();
+        assert!(err.to_string().contains("status 416"), "{}", err);
+        assert_eq!(server.requests.load(Ordering::SeqCst), 7);
+    }
+
+    #[test]
+    fn test_max_parallel() {
+        let (url, server) = Server {
+            data: vec![7; 1_000],
+            delay: Duration::from_millis(20),
+            ..Default::default()
+        }
+        .start();
+
+        let mut source = HttpSource::new(&url);
+        source.set_max_parallel(2);
+        let threads: Vec<_> = (0..8)
+            .map(|i| {
+                let source = source.clone();
+                thread::spawn(move || source.read_at(i * 100, 100).unwrap())
+            })
+            .collect();
+        for handle in threads {
+            assert_eq!(handle.join().unwrap(), vec![7; 100]);
+        }
+        assert_eq!(server.requests.load(Ordering::SeqCst), 8);
+        assert!(server.max_in_flight.load(Ordering::SeqCst) <= 2);
+    }
+}
@@ -0,0 +1,239 @@
+//! Records with variable sized fields parsed on first access.
+use bam_tools::record::fields::{field_type, FieldType, Fields};
+
+use super::reader::Reader;
+use super::record::GbamRecord;
+use crate::error::Result;
+use crate::query::cigar::Cigar;
+
+/// Iterates over records, parsing fixed sized fields of the template only.
+/// Created by [`Reader::lazy_records`].
+pub struct LazyRecords<'a> {
+    reader: &'a mut Reader,
+    cur_rec: usize,
+    buf: GbamRecord,
+    eager_fields: Vec<Fields>,
+}
+
+impl<'a> LazyRecords<'a> {
+    /// Next record, None past the last one. Fails with errors of
+    /// `Reader::fill_record()`.
+    pub fn next_rec(&mut self) -> Result<Option<LazyRecord<'_>>> {
+        if self.cur_rec == self.reader.amount {
+            return Ok(None);
+        }
+        let rec_num = self.reader.physical_rec_num(self.cur_rec)?;
+        self.cur_rec += 1;
+        for field in &self.eager_fields {
+            self.reader
+                .get_column(field)?
+                .fill_record_field(rec_num, &mut self.buf)?;
+        }
+        Ok(Some(LazyRecord {
+            reader: self.reader,
+            rec_num,
+            rec: &mut self.buf,
+            loaded: Vec::new(),
+        }))
+    }
+}
+
+/// Record handle. Fixed sized fields are parsed already, variable sized ones
+/// are parsed on first access, decompressing their block if needed, which
+/// fails like `Reader::fill_record()`. Fields missing from parsing template
+/// are None.
+pub struct LazyRecord<'a> {
+    reader: &'a mut Reader,
+    // Position in columns.
+    rec_num: usize,
+    rec: &'a mut GbamRecord,
+    // Lazy fields parsed for this record.
+    loaded: Vec<Fields>,
+}
+
+impl<'a> LazyRecord<'a> {
+    pub fn ref_id(&self) -> Option<i32> {
+        self.rec.refid
+    }
+
+    pub fn pos(&self) -> Option<i32> {
+        self.rec.pos
+    }
+
+    pub fn mapq(&self) -> Option<u8> {
+        self.rec.mapq
+    }
+
+    pub fn bin(&self) -> Option<u16> {
+        self.rec.bin
+    }
+
+    pub fn flag(&self) -> Option<u16> {
+        self.rec.flag
+    }
+
+    pub fn next_ref_id(&self) -> Option<i32> {
+        self.rec.next_ref_id
+    }
+
+    pub fn next_pos(&self) -> Option<i32> {
+        self.rec.next_pos
+    }
+
+    pub fn tlen(&self) -> Option<i32> {
+        self.rec.tlen
+    }
+
+    /// NUL terminated, as in BAM.
+    pub fn read_name(&mut self) -> Result<Option<&[u8]>> {
+        if !self.load(Fields::ReadName)? {
+            return Ok(None);
+        }
+        Ok(self.rec.read_name.as_deref())
+    }
+
+    pub fn cigar(&mut self) -> Result<Option<&Cigar>> {
+        if !self.load(Fields::RawCigar)? {
+            return Ok(None);
+        }
+        Ok(self.rec.cigar.as_ref())
+    }
+
+    pub fn seq(&mut self) -> Result<Option<&str>> {
+        if !self.load(Fields::RawSequence)? {
+            return Ok(None);
+        }
+        Ok(self.rec.seq.as_deref())
+    }
+
+    pub fn qual(&mut self) -> Result<Option<&[u8]>> {
+        if !self.load(Fields::RawQual)? {
+            return Ok(None);
+        }
+        Ok(self.rec.qual.as_deref())
+    }
+
+    pub fn tags(&mut self) -> Result<Option<&[u8]>> {
+        if !self.load(Fields::RawTags)? {
+            return Ok(None);
+        }
+        Ok(self.rec.tags.as_deref())
+    }
+
+    // Buffer keeps values of previous records, so parsed fields are tracked.
+    // False for fields missing from parsing template.
+    fn load(&mut self, field: Fields) -> Result<bool> {
+        if !self.reader.parsing_template.check_if_active(&[field]) {
+            return Ok(false);
+        }
+        if !self.loaded.contains(&field) {
+            self.reader
+                .get_column(&field)?
+                .fill_record_field(self.rec_num, self.rec)?;
+            self.loaded.push(field);
+        }
+        Ok(true)
+    }
+}
+
+impl Reader {
+    /// Get iterator over records, which parses variable sized fields (read
+    /// name, cigar, sequence, qualities and tags) only when accessed. Fields
+    /// still have to be enabled in parsing template, but enabled fields that
+    /// are never accessed cost nothing.
+    pub fn lazy_records(&mut self) -> LazyRecords<'_> {
+        let eager_fields = self
+            .parsing_template
+            .get_active_data_fields_iter()
+            .filter(|f| matches!(field_type(f), FieldType::FixedSized))
+            .copied()
+            .collect();
+        LazyRecords {
+            reader: self,
+            cur_rec: 0,
+            buf: GbamRecord::default(),
+            eager_fields,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{open_test_file, write_test_file_with_limit, TestRecord};
+    use tempdir::TempDir;
+
+    const RECORDS_NUM: i32 = 40_000;
+
+    fn long_reads_file(dir: &TempDir) -> std::path::PathBuf {
+        let path = dir.path().join("long_reads.gbam");
+        let bases = ["A", "C", "G", "T"];
+        let records: Vec<TestRecord> = (0..RECORDS_NUM)
+            .map(|i| {
+                let mut rec = TestRecord::new(0, i, &format!("read{}", i));
+                rec.seq = (0..1000).map(|j| bases[(i as usize + j) % 4]).collect();
+                rec.qual = vec![30; 1000];
+                rec.cigar = vec![1000 << 4];
+                rec
+            })
+            .collect();
+        write_test_file_with_limit(&path, "", &records, &[Fields::RawSequence]);
+        path
+    }
+
+    #[test]
+    fn test_lazy_records() {
+        let dir = TempDir::new("gbam_lazy").unwrap();
+        let path = long_reads_file(&dir);
+        let mut reader = open_test_file(&path);
+        let seq_blocks = reader.file_meta.view_blocks(&Fields::RawSequence).len();
+        assert!(seq_blocks > 2);
+
+        let mut records = reader.lazy_records();
+        let mut n = 0;
+        while let Some(rec) = records.next_rec().unwrap() {
+            assert_eq!(rec.pos(), Some(n));
+            assert_eq!(rec.flag(), Some(0));
+            n += 1;
+        }
+        assert_eq!(n, RECORDS_NUM);
+        assert_eq!(reader.blocks_decompressed(&Fields::RawSequence), 0);
+        assert_eq!(reader.blocks_decompressed(&Fields::RawQual), 0);
+        assert_eq!(reader.blocks_decompressed(&Fields::Pos), 1);
+
+        // Sequences of the last records only, all in the last block.
+        let mut records = reader.lazy_records();
+        while let Some(mut rec) = records.next_rec().unwrap() {
+            let pos = rec.pos().unwrap();
+            if pos >= RECORDS_NUM - 10 {
+                let seq = rec.seq().unwrap().unwrap();
+                assert_eq!(&seq[..4], ["ACGT", "CGTA", "GTAC", "TACG"][pos as usize % 4]);
+                assert_eq!(rec.read_name().unwrap().unwrap(), format!("read{}\0", pos).as_bytes());
+            }
+        }
+        assert_eq!(reader.blocks_decompressed(&Fields::RawSequence), 1);
+    }
+
+    #[test]
+    fn test_block_cache() {
+        let dir = TempDir::new("gbam_lazy").unwrap();
+        let path = long_reads_file(&dir);
+        let mut rec = GbamRecord::default();
+        let last = RECORDS_NUM as usize - 1;
+
+        let mut reader = open_test_file(&path);
+        for rec_num in [0, last, 0, last] {
+            reader.fill_record(rec_num, &mut rec).unwrap();
+        }
+        assert_eq!(reader.blocks_decompressed(&Fields::RawSequence), 4);
+
+        let mut reader = open_test_file(&path);
+        reader.set_block_cache_size(1);
+        for rec_num in [0, last, 0, last] {
+            reader.fill_record(rec_num, &mut rec).unwrap();
+            assert_eq!(rec.pos, Some(rec_num as i32));
+            assert_eq!(rec.seq.as_ref().unwrap().len(), 1000);
+        }
+        assert_eq!(reader.blocks_decompressed(&Fields::RawSequence), 2);
+    }
+}
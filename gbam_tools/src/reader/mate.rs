@@ -0,0 +1,227 @@
+//! Lookup of mates of paired reads in coordinate sorted files.
+use std::io;
+
+use bam_tools::record::fields::Fields;
+
+use super::reader::Reader;
+use super::record::GbamRecord;
+use super::region::{FetchOptions, Region, RegionRecords, UNPLACED_REF_ID};
+use crate::error::Result;
+
+const BAM_FPAIRED: u16 = 0x1;
+const BAM_FMUNMAP: u16 = 0x8;
+const BAM_FREAD1: u16 = 0x40;
+const BAM_FREAD2: u16 = 0x80;
+const BAM_FSECONDARY: u16 = 0x100;
+const BAM_FSUPPLEMENTARY: u16 = 0x800;
+
+/// Fields mates are matched by, besides the ones of region fetch.
+const MATE_FIELDS: [Fields; 4] = [Fields::Flags, Fields::ReadName, Fields::NextRefID, Fields::NextPos];
+
+impl Reader {
+    /// Primary record of the other segment of paired `record`, e.g. one
+    /// returned by `fetch()`. Records starting at the mate position are
+    /// fetched and matched by read name and first/last segment flags. Mates
+    /// flagged unmapped and not found there are looked for by name among
+    /// unplaced reads. If several records match, the one pointing back at
+    /// `record` wins. None if `record` isn't paired or its mate is missing.
+    /// The file has to be coordinate sorted, Flags, ReadName, NextRefID and
+    /// NextPos have to be enabled in parsing template besides the fields of
+    /// `fetch()`, and set in `record`.
+    pub fn find_mate(&mut self, record: &GbamRecord) -> Result<Option<GbamRecord>> {
+        if !self.parsing_template.check_if_active(&MATE_FIELDS) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Flags, ReadName, NextRefID and NextPos fields have to be enabled in parsing template \
+                 to find mates.",
+            )
+            .into());
+        }
+        let (flag, next_ref_id, next_pos) = match (record.flag, record.next_ref_id, record.next_pos) {
+            (Some(flag), Some(next_ref_id), Some(next_pos)) if record.read_name.is_some() => {
+                (flag, next_ref_id, next_pos)
+            }
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Record needs Flags, ReadName, NextRefID and NextPos to find its mate.",
+                )
+                .into())
+            }
+        };
+        if flag & BAM_FPAIRED == 0 {
+            return Ok(None);
+        }
+
+        if next_ref_id != UNPLACED_REF_ID && next_pos >= 0 {
+            let region = Region::new(next_ref_id, next_pos, next_pos.saturating_add(1));
+            let mate = best_mate(record, self.fetch(&region)?, Some(next_pos))?;
+            if mate.is_some() {
+                return Ok(mate);
+            }
+        }
+        if flag & BAM_FMUNMAP == 0 {
+            return Ok(None);
+        }
+        // Unmapped mates without a position are in the tail of the file.
+        let options = FetchOptions { include_unplaced: true };
+        best_mate(record, self.fetch_with_options(&Region::unplaced(), &options)?, None)
+    }
+}
+
+/// Mate of `record` among `candidates` starting at `pos`, if given. The
+/// first one pointing back at `record`, otherwise the first one found.
+fn best_mate(
+    record: &GbamRecord,
+    mut candidates: RegionRecords,
+    pos: Option<i32>,
+) -> Result<Option<GbamRecord>> {
+    let mut first = None;
+    while let Some(candidate) = candidates.next_rec()? {
+        if pos.is_some_and(|pos| candidate.pos != Some(pos)) || !is_mate(record, candidate) {
+            continue;
+        }
+        if candidate.next_ref_id == record.refid && candidate.next_pos == record.pos {
+            return Ok(Some(candidate.clone()));
+        }
+        first.get_or_insert_with(|| candidate.clone());
+    }
+    Ok(first)
+}
+
+/// Primary record of the same template, of the other segment.
+fn is_mate(record: &GbamRecord, candidate: &GbamRecord) -> bool {
+    let segment = |flag: u16| flag & (BAM_FREAD1 | BAM_FREAD2);
+    let (flag, candidate_flag) = (record.flag.unwrap(), candidate.flag.unwrap());
+    candidate.read_name == record.read_name
+        && candidate_flag & (BAM_FSECONDARY | BAM_FSUPPLEMENTARY) == 0
+        && candidate_flag & BAM_FPAIRED != 0
+        && segment(candidate_flag) != segment(flag)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reader::parse_tmplt::ParsingTemplate;
+    use crate::test_utils::{new_test_writer, open_test_file, TestRecord};
+    use tempdir::TempDir;
+
+    fn read(ref_id: i32, pos: i32, name: &str, flag: u16, next: (i32, i32)) -> TestRecord {
+        let mut rec = TestRecord {
+            flag,
+            next_refid: next.0,
+            next_pos: next.1,
+            ..TestRecord::new(ref_id, pos, name)
+        };
+        if flag & 0x4 != 0 {
+            rec.cigar = Vec::new();
+        }
+        rec
+    }
+
+    fn name(rec: &GbamRecord) -> &[u8] {
+        let name = rec.read_name.as_ref().unwrap();
+        &name[..name.len() - 1]
+    }
+
+    /// Record of `name` with `flag`, as returned by fetch of its position.
+    fn fetched(reader: &mut Reader, region: Region, read_name: &str, flag: u16) -> GbamRecord {
+        let options = FetchOptions { include_unplaced: true };
+        let mut records = reader.fetch_with_options(&region, &options).unwrap();
+        while let Some(rec) = records.next_rec().unwrap() {
+            if name(rec) == read_name.as_bytes() && rec.flag == Some(flag) {
+                return rec.clone();
+            }
+        }
+        panic!("{} is not in the file", read_name);
+    }
+
+    #[test]
+    fn test_find_mate() {
+        let dir = TempDir::new("gbam_mate").unwrap();
+        let path = dir.path().join("mates.gbam");
+        let records = [
+            read(0, 100, "chroms", 0x1 | 0x20 | 0x40, (1, 500)),
+            read(0, 300, "same_pos", 0x1 | 0x40, (0, 300)),
+            read(0, 300, "same_pos", 0x1 | 0x80 | 0x800, (2, 10)),
+            read(0, 300, "other", 0x1 | 0x80, (0, 300)),
+            read(0, 300, "same_pos", 0x1 | 0x80, (0, 300)),
+            read(0, 400, "placed", 0x1 | 0x8 | 0x40, (0, 400)),
+            read(0, 400, "placed", 0x1 | 0x4 | 0x80, (0, 400)),
+            read(0, 600, "unplaced", 0x1 | 0x8 | 0x40, (-1, -1)),
+            read(0, 700, "missing", 0x1 | 0x40, (2, 100)),
+            read(0, 800, "missing_unmapped", 0x1 | 0x8 | 0x40, (-1, -1)),
+            read(0, 850, "duplicated", 0x1 | 0x40, (1, 850)),
+            read(0, 900, "single", 0, (-1, -1)),
+            read(1, 500, "chroms", 0x1 | 0x10 | 0x80, (0, 100)),
+            read(1, 850, "duplicated", 0x1 | 0x80, (2, 5)),
+            read(1, 850, "duplicated", 0x1 | 0x80, (0, 850)),
+            read(-1, -1, "unplaced", 0x1 | 0x4 | 0x80, (0, 600)),
+        ];
+        let mut writer = new_test_writer(&path, "@HD\tVN:1.6\tSO:coordinate\n");
+        writer.set_rows_per_block(4);
+        for rec in &records {
+            writer.push_record(&rec.to_raw(), false).unwrap();
+        }
+        writer.finish_with_summary(false).unwrap();
+        let mut reader = open_test_file(&path);
+        let mut mate_of = |ref_id, pos, read_name: &str, flag| {
+            let rec = fetched(&mut reader, Region::new(ref_id, pos, pos + 1), read_name, flag);
+            reader.find_mate(&rec).unwrap()
+        };
+
+        // Mates on different references find each other.
+        let mate = mate_of(0, 100, "chroms", 0x1 | 0x20 | 0x40).unwrap();
+        assert_eq!((mate.refid, mate.pos, mate.flag), (Some(1), Some(500), Some(0x1 | 0x10 | 0x80)));
+        let mate = mate_of(1, 500, "chroms", 0x1 | 0x10 | 0x80).unwrap();
+        assert_eq!((mate.refid, mate.pos), (Some(0), Some(100)));
+
+        // Neither the record itself, its supplementary alignment nor reads
+        // of other templates at the same position are mates.
+        let mate = mate_of(0, 300, "same_pos", 0x1 | 0x40).unwrap();
+        assert_eq!((name(&mate), mate.flag), (&b"same_pos"[..], Some(0x1 | 0x80)));
+        let mate = mate_of(0, 300, "same_pos", 0x1 | 0x80).unwrap();
+        assert_eq!(mate.flag, Some(0x1 | 0x40));
+        assert!(mate_of(0, 300, "other", 0x1 | 0x80).is_none());
+
+        // Duplicated mate pointing back wins.
+        let mate = mate_of(0, 850, "duplicated", 0x1 | 0x40).unwrap();
+        assert_eq!((mate.next_ref_id, mate.next_pos), (Some(0), Some(850)));
+
+        // Unmapped mates, placed at the read or in the unplaced tail.
+        let mate = mate_of(0, 400, "placed", 0x1 | 0x8 | 0x40).unwrap();
+        assert_eq!((mate.refid, mate.pos, mate.flag), (Some(0), Some(400), Some(0x1 | 0x4 | 0x80)));
+        let mate = mate_of(0, 400, "placed", 0x1 | 0x4 | 0x80).unwrap();
+        assert_eq!(mate.flag, Some(0x1 | 0x8 | 0x40));
+        let mate = mate_of(0, 600, "unplaced", 0x1 | 0x8 | 0x40).unwrap();
+        assert_eq!((mate.refid, mate.pos, name(&mate)), (Some(-1), Some(-1), &b"unplaced"[..]));
+
+        // Missing mates and unpaired reads.
+        assert!(mate_of(0, 700, "missing", 0x1 | 0x40).is_none());
+        assert!(mate_of(0, 800, "missing_unmapped", 0x1 | 0x8 | 0x40).is_none());
+        assert!(mate_of(0, 900, "single", 0).is_none());
+
+        let unplaced = fetched(&mut reader, Region::unplaced(), "unplaced", 0x1 | 0x4 | 0x80);
+        assert_eq!(reader.find_mate(&unplaced).unwrap().unwrap().pos, Some(600));
+    }
+
+    #[test]
+    fn test_find_mate_requires_fields() {
+        let dir = TempDir::new("gbam_mate").unwrap();
+        let path = dir.path().join("mates.gbam");
+        let mut writer = new_test_writer(&path, "@HD\tVN:1.6\tSO:coordinate\n");
+        writer.push_record(&read(0, 10, "r", 0x1 | 0x40, (0, 20)).to_raw(), false).unwrap();
+        writer.push_record(&read(0, 20, "r", 0x1 | 0x80, (0, 10)).to_raw(), false).unwrap();
+        writer.finish_with_summary(false).unwrap();
+
+        let mut reader = open_test_file(&path);
+        let rec = fetched(&mut reader, Region::new(0, 10, 11), "r", 0x1 | 0x40);
+        let mut without_name = rec.clone();
+        without_name.read_name = None;
+        assert_eq!(reader.find_mate(&without_name).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+
+        let fields = [Fields::RefID, Fields::Pos, Fields::RawCigar, Fields::Flags];
+        let mut reader = Reader::from_path(&path, ParsingTemplate::new_with(&fields)).unwrap();
+        assert_eq!(reader.find_mate(&rec).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,324 @@
+//! Conversion of GBAM records into noodles-sam owned records.
+use std::convert::TryFrom;
+use std::io::{self, ErrorKind};
+use std::num::NonZeroUsize;
+
+use bam_tools::record::fields::Fields;
+use byteorder::{LittleEndian, ReadBytesExt};
+use noodles_core::Position;
+use noodles_sam::alignment::record::cigar::op::{Kind, Op};
+use noodles_sam::alignment::record::data::field::Tag;
+use noodles_sam::alignment::record::{Flags, MappingQuality};
+use noodles_sam::alignment::record_buf::data::field::value::Array;
+use noodles_sam::alignment::record_buf::data::field::Value;
+use noodles_sam::alignment::record_buf::{Cigar, Data, QualityScores, Sequence};
+use noodles_sam::alignment::RecordBuf;
+use noodles_sam::header::record::value::{map::ReferenceSequence, Map};
+use noodles_sam::Header;
+
+use super::reader::Reader;
+use super::record::GbamRecord;
+use super::records::Records;
+use crate::meta::{sam_header_text, FileMeta};
+
+fn invalid_data<E: Into<Box<dyn std::error::Error + Send + Sync>>>(err: E) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, err)
+}
+
+/// Builds noodles header from SAM header text stored in meta. If the text has
+/// no @SQ lines, reference sequences are taken from `ref_seqs`, like BAM
+/// readers do with binary reference sequences.
+pub fn noodles_header(meta: &FileMeta) -> io::Result<Header> {
+    let text = sam_header_text(meta.get_sam_header());
+    let text = std::str::from_utf8(text).map_err(invalid_data)?;
+    let mut header: Header = text.trim_end_matches('\0').parse().map_err(invalid_data)?;
+
+    if header.reference_sequences().is_empty() {
+        for (name, len) in meta.get_ref_seqs() {
+            let len = NonZeroUsize::new(*len as usize).ok_or_else(|| {
+                invalid_data(format!("Reference sequence {} has zero length", name))
+            })?;
+            header.reference_sequences_mut().insert(
+                name.as_bytes().to_vec().into(),
+                Map::<ReferenceSequence>::new(len),
+            );
+        }
+    }
+    Ok(header)
+}
+
+fn to_position(pos: i32) -> Option<Position> {
+    usize::try_from(pos).ok().and_then(|p| Position::new(p + 1))
+}
+
+fn to_kind(op: u32) -> io::Result<Kind> {
+    Ok(match op & 0xf {
+        0 => Kind::Match,
+        1 => Kind::Insertion,
+        2 => Kind::Deletion,
+        3 => Kind::Skip,
+        4 => Kind::SoftClip,
+        5 => Kind::HardClip,
+        6 => Kind::Pad,
+        7 => Kind::SequenceMatch,
+        8 => Kind::SequenceMismatch,
+        n => return Err(invalid_data(format!("Invalid CIGAR operation {}", n))),
+    })
+}
+
+fn read_array(src: &mut &[u8]) -> io::Result<Array> {
+    let subtype = src.read_u8()?;
+    let n = src.read_u32::<LittleEndian>()? as usize;
+    macro_rules! read_n {
+        ($read:expr) => {
+            (0..n).map(|_| $read).collect::<io::Result<Vec<_>>>()?
+        };
+    }
+    Ok(match subtype {
+        b'c' => Array::Int8(read_n!(src.read_i8())),
+        b'C' => Array::UInt8(read_n!(src.read_u8())),
+        b's' => Array::Int16(read_n!(src.read_i16::<LittleEndian>())),
+        b'S' => Array::UInt16(read_n!(src.read_u16::<LittleEndian>())),
+        b'i' => Array::Int32(read_n!(src.read_i32::<LittleEndian>())),
+        b'I' => Array::UInt32(read_n!(src.read_u32::<LittleEndian>())),
+        b'f' => Array::Float(read_n!(src.read_f32::<LittleEndian>())),
+        t => {
+            return Err(invalid_data(format!(
+                "Invalid array subtype {}",
+                t as char
+            )))
+        }
+    })
+}
+
+fn read_string(src: &mut &[u8]) -> io::Result<Vec<u8>> {
+    let len = src
+        .iter()
+        .position(|&b| b == 0)
+        .ok_or_else(|| invalid_data("Tag string is not NUL terminated"))?;
+    let s = src[..len].to_vec();
+    *src = &src[len + 1..];
+    Ok(s)
+}
+
+/// Parses raw BAM auxiliary data.
+fn parse_data(mut src: &[u8]) -> io::Result<Data> {
+    let mut fields = Vec::new();
+    while !src.is_empty() {
+        if src.len() < 3 {
+            return Err(invalid_data("Truncated tag"));
+        }
+        let tag = Tag::from([src[0], src[1]]);
+        let val_type = src[2];
+        src = &src[3..];
+        let value = match val_type {
+            b'A' => Value::Character(src.read_u8()?),
+            b'c' => Value::Int8(src.read_i8()?),
+            b'C' => Value::UInt8(src.read_u8()?),
+            b's' => Value::Int16(src.read_i16::<LittleEndian>()?),
+            b'S' => Value::UInt16(src.read_u16::<LittleEndian>()?),
+            b'i' => Value::Int32(src.read_i32::<LittleEndian>()?),
+            b'I' => Value::UInt32(src.read_u32::<LittleEndian>()?),
+            b'f' => Value::Float(src.read_f32::<LittleEndian>()?),
+            b'Z' => Value::String(read_string(&mut src)?.into()),
+            b'H' => Value::Hex(read_string(&mut src)?.into()),
+            b'B' => Value::Array(read_array(&mut src)?),
+            t => return Err(invalid_data(format!("Invalid tag type {}", t as char))),
+        };
+        fields.push((tag, value));
+    }
+    Ok(fields.into_iter().collect())
+}
+
+fn missing_field(field: Fields) -> io::Error {
+    io::Error::new(
+        ErrorKind::InvalidInput,
+        format!("{} is not fetched, enable all fields in parsing template", field),
+    )
+}
+
+/// Converts fully parsed GBAM record into noodles record.
+pub fn to_record_buf(rec: &GbamRecord) -> io::Result<RecordBuf> {
+    let refid = rec.refid.ok_or_else(|| missing_field(Fields::RefID))?;
+    let pos = rec.pos.ok_or_else(|| missing_field(Fields::Pos))?;
+    let next_ref_id = rec.next_ref_id.ok_or_else(|| missing_field(Fields::NextRefID))?;
+    let next_pos = rec.next_pos.ok_or_else(|| missing_field(Fields::NextPos))?;
+    let name = rec.read_name.as_ref().ok_or_else(|| missing_field(Fields::ReadName))?;
+    let cigar = rec.cigar.as_ref().ok_or_else(|| missing_field(Fields::RawCigar))?;
+    let seq = rec.seq.as_ref().ok_or_else(|| missing_field(Fields::RawSequence))?;
+    let qual = rec.qual.as_ref().ok_or_else(|| missing_field(Fields::RawQual))?;
+    let tags = rec.tags.as_ref().ok_or_else(|| missing_field(Fields::RawTags))?;
+
+    let mut builder = RecordBuf::builder()
+        .set_flags(Flags::from(rec.flag.ok_or_else(|| missing_field(Fields::Flags))?))
+        .set_template_length(rec.tlen.ok_or_else(|| missing_field(Fields::TemplateLength))?);
+
+    // Name is NUL terminated, '*' means it's missing.
+    let name = name.strip_suffix(&[0]).unwrap_or(name);
+    if name != b"*" {
+        builder = builder.set_name(name.to_vec());
+    }
+    if let Ok(id) = usize::try_from(refid) {
+        builder = builder.set_reference_sequence_id(id);
+    }
+    if let Some(start) = to_position(pos) {
+        builder = builder.set_alignment_start(start);
+    }
+    if let Some(mapq) = MappingQuality::new(rec.mapq.ok_or_else(|| missing_field(Fields::Mapq))?) {
+        builder = builder.set_mapping_quality(mapq);
+    }
+    if let Ok(id) = usize::try_from(next_ref_id) {
+        builder = builder.set_mate_reference_sequence_id(id);
+    }
+    if let Some(start) = to_position(next_pos) {
+        builder = builder.set_mate_alignment_start(start);
+    }
+
+    let mut data = parse_data(tags)?;
+    // GBAM stores CIGAR already taken from CG tag for long alignments, while
+    // the tag itself is left in data. Drop it, as BAM readers do after resolving.
+    let raw_ops: Vec<u32> = cigar.0.iter().map(|op| op.0).collect();
+    if let Some(Value::Array(Array::UInt32(cg))) = data.get(&Tag::CIGAR) {
+        if *cg == raw_ops {
+            data = data
+                .iter()
+                .filter(|(tag, _)| *tag != Tag::CIGAR)
+                .map(|(tag, value)| (tag, value.clone()))
+                .collect();
+        }
+    }
+
+    let ops = cigar
+        .0
+        .iter()
+        .map(|op| Ok(Op::new(to_kind(op.0)?, op.length() as usize)))
+        .collect::<io::Result<Vec<_>>>()?;
+
+    // Qualities are always l_seq long, while decoded sequence may carry padding
+    // base or lack trailing '='.
+    let l_seq = qual.len();
+    let sequence: Sequence = seq.bytes().chain(std::iter::repeat(b'=')).take(l_seq).collect();
+    let quality_scores = if qual.iter().all(|&q| q == 0xff) {
+        QualityScores::default()
+    } else {
+        QualityScores::from(qual.clone())
+    };
+
+    Ok(builder
+        .set_cigar(Cigar::from(ops))
+        .set_sequence(sequence)
+        .set_quality_scores(quality_scores)
+        .set_data(data)
+        .build())
+}
+
+/// Iterates over GBAM records converted into noodles records.
+pub struct NoodlesRecords<'a> {
+    records: Records<'a>,
+}
+
+impl<'a> Iterator for NoodlesRecords<'a> {
+    type Item = io::Result<RecordBuf>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.records.next_rec() {
+            Ok(rec) => rec.map(to_record_buf),
+            Err(err) => Some(Err(err.into())),
+        }
+    }
+}
+
+impl Reader {
+    /// noodles header matching this file.
+    pub fn noodles_header(&self) -> io::Result<Header> {
+        noodles_header(&self.file_meta)
+    }
+
+    /// Get iterator over records converted into noodles records. All data
+    /// fields have to be enabled in parsing template.
+    pub fn noodles_records(&mut self) -> io::Result<NoodlesRecords<'_>> {
+        let all_fields = [
+            Fields::RefID,
+            Fields::Pos,
+            Fields::Mapq,
+            Fields::Bin,
+            Fields::Flags,
+            Fields::NextRefID,
+            Fields::NextPos,
+            Fields::TemplateLength,
+            Fields::ReadName,
+            Fields::RawCigar,
+            Fields::RawSequence,
+            Fields::RawQual,
+            Fields::RawTags,
+        ];
+        if let Some(field) = all_fields
+            .iter()
+            .find(|f| !self.parsing_template.check_if_active(&[**f]))
+        {
+            return Err(missing_field(*field));
+        }
+        Ok(NoodlesRecords {
+            records: self.records(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bam::convert::{bam_to_gbam, Bam2GbamOptions};
+    use crate::test_utils::open_test_file;
+    use std::fs::File;
+    use std::path::Path;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_noodles_records_match_bam_reader() {
+        let bam_path = concat!(env!("CARGO_MANIFEST_DIR"), "/../test_data/little.bam");
+        let tmp_dir = TempDir::new("gbam_noodles").unwrap();
+        let gbam_path = tmp_dir.path().join("little.gbam");
+        let options = Bam2GbamOptions::default().with_command(String::from("test"));
+        bam_to_gbam(Path::new(bam_path), &gbam_path, &options).unwrap();
+
+        let mut bam_reader = noodles_bam::io::Reader::new(File::open(bam_path).unwrap());
+        let bam_header = bam_reader.read_header().unwrap();
+
+        let mut gbam_reader = open_test_file(&gbam_path);
+        let header = gbam_reader.noodles_header().unwrap();
+        assert_eq!(header.reference_sequences(), bam_header.reference_sequences());
+
+        let mut bam_records = bam_reader.record_bufs(&bam_header);
+        let mut count = 0;
+        for rec in gbam_reader.noodles_records().unwrap() {
+            let expected = bam_records.next().unwrap().unwrap();
+            assert_eq!(rec.unwrap(), expected, "record {}", count);
+            count += 1;
+        }
+        assert!(bam_records.next().is_none());
+        assert!(count > 0);
+    }
+
+    #[test]
+    fn test_parse_data() {
+        let mut raw = Vec::new();
+        raw.extend_from_slice(b"XAAc");
+        raw.extend_from_slice(b"NMC\x05");
+        raw.extend_from_slice(b"XFf");
+        raw.extend_from_slice(&1.5f32.to_le_bytes());
+        raw.extend_from_slice(b"RGZgrp\0");
+        raw.extend_from_slice(b"XHH1AE3\0");
+        raw.extend_from_slice(b"XBBs\x02\0\0\0");
+        raw.extend_from_slice(&(-3i16).to_le_bytes());
+        raw.extend_from_slice(&7i16.to_le_bytes());
+        let data = parse_data(&raw).unwrap();
+        let get = |tag: &[u8; 2]| data.get(&Tag::from(*tag)).unwrap().clone();
+        assert_eq!(get(b"XA"), Value::Character(b'c'));
+        assert_eq!(get(b"NM"), Value::UInt8(5));
+        assert_eq!(get(b"XF"), Value::Float(1.5));
+        assert_eq!(get(b"RG"), Value::String("grp".into()));
+        assert_eq!(get(b"XH"), Value::Hex("1AE3".into()));
+        assert_eq!(get(b"XB"), Value::Array(Array::Int16(vec![-3, 7])));
+        assert!(parse_data(b"XZZab").is_err());
+    }
+}
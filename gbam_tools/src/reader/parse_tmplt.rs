@@ -1,9 +1,16 @@
 use bam_tools::record::fields::{
     field_type, is_data_field, var_size_field_to_index, FieldType, Fields, FIELDS_NUM,
 };
+use crate::error::{Result, TemplateError};
+#[cfg(feature = "python-ffi")]
+use {bam_tools::record::fields::DATA_FIELDS_NUM, pyo3::prelude::*};
 
-/// This struct regulates what fields are getting parsed from GBAM file.
+/// This struct regulates what fields are getting parsed from GBAM file. Only
+/// data fields are selected. Index fields (LName, NCigar, SequenceLength,
+/// RawSeqLen, RawTagsLen) are read along with their variable sized data
+/// fields and are active exactly while those are, the reader manages them.
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "python-ffi", pyclass)]
 pub struct ParsingTemplate {
     inner: Vec<Option<Fields>>,
     // Cache.
@@ -27,8 +34,35 @@ impl ParsingTemplate {
         }
         empty
     }
-    /// Set field value
+
+    /// Like `new_with()`, but fails with `TemplateError::IndexField` on
+    /// index fields instead of panicking.
+    pub fn try_new_with(fields_to_set: &[Fields]) -> Result<Self> {
+        let mut template = Self::new();
+        for field in fields_to_set {
+            template.try_set(field, true)?;
+        }
+        Ok(template)
+    }
+
+    /// Like `set()`, but fails on index fields instead of panicking.
+    pub fn try_set(&mut self, field: &Fields, val: bool) -> Result<()> {
+        check_data_field(field)?;
+        self.set(field, val);
+        Ok(())
+    }
+
+    /// Like `set_all_except()`, but fails on index fields instead of
+    /// panicking.
+    pub fn try_set_all_except(&mut self, disable: &[Fields]) -> Result<()> {
+        disable.iter().try_for_each(check_data_field)?;
+        self.set_all_except(disable);
+        Ok(())
+    }
+
+    /// Set field value. Panics on index fields, see `ParsingTemplate`.
     pub fn set(&mut self, field: &Fields, val: bool) {
+        assert_data_field(field);
         match field_type(field) {
             FieldType::FixedSized => {
                 self.inner[*field as usize] = Self::bool_to_val(field, val);
@@ -48,7 +82,8 @@ impl ParsingTemplate {
             false => None,
         }
     }
-    /// Get iterator over fields currently requested for parsing
+    /// Get iterator over fields currently requested for parsing, with index
+    /// fields of variable sized ones
     #[allow(clippy::needless_lifetimes)]
     pub fn get_active_fields_iter<'a>(&'a self) -> impl Iterator<Item = &'a Fields> {
         self.inner.iter().filter_map(|x| x.as_ref())
@@ -86,24 +121,13 @@ impl ParsingTemplate {
         self.set_active();
     }
 
-    /// Set all fields to active state, except some
+    /// Set all fields to active state, except some data fields. Panics on
+    /// index fields, see `ParsingTemplate`.
     pub fn set_all_except(&mut self, disable: &[Fields]) {
-        for (field, val) in Fields::iterator().zip(self.inner.iter_mut()) {
-            let mut good = true;
-            for f in disable {
-                if f == field {
-                    good = false;
-                    break;
-                }
-            }
-            if !good {
-                continue;
-            }
-            if val.is_none() {
-                *val = Some(*field);
-            }
+        disable.iter().for_each(assert_data_field);
+        for field in Fields::iterator().filter(|field| is_data_field(field) && !disable.contains(field)) {
+            self.set(field, true);
         }
-        self.set_active();
     }
     /// Set all fields to disabled state
     pub fn clear(&mut self) {
@@ -114,6 +138,8 @@ impl ParsingTemplate {
         self.set_active();
     }
 
+    /// Whether all `fields` are active, index fields are while their data
+    /// fields are.
     pub fn check_if_active(&self, fields: &[Fields]) -> bool {
         for &field in fields.iter() {
             if self.inner[field as usize].is_none() {
@@ -124,6 +150,19 @@ impl ParsingTemplate {
     }
 }
 
+fn assert_data_field(field: &Fields) {
+    if let Err(err) = check_data_field(field) {
+        panic!("{}", err);
+    }
+}
+
+fn check_data_field(field: &Fields) -> Result<()> {
+    if !is_data_field(field) {
+        return Err(TemplateError::IndexField { field: *field }.into());
+    }
+    Ok(())
+}
+
 impl Default for ParsingTemplate {
     fn default() -> Self {
         Self::new()
@@ -144,3 +183,178 @@ impl ParsingTemplate {
         tmplt
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::{GbamError, MetaError};
+    use crate::meta::FileInfo;
+    use crate::query::cigar::{Cigar, Op};
+    use crate::reader::reader::Reader;
+    use crate::reader::record::GbamRecord;
+    use crate::test_utils::{new_test_writer, open_test_file, TestRecord};
+    use crate::writer::write_meta_and_file_info;
+    use bam_tools::record::fields::DATA_FIELDS_NUM;
+    use std::fs::OpenOptions;
+    use std::io::{Seek, SeekFrom};
+    use std::path::Path;
+    use tempdir::TempDir;
+
+    fn records() -> Vec<TestRecord> {
+        (0..3_000)
+            .map(|i| {
+                let len = 1 + i as usize % 9;
+                TestRecord {
+                    mapq: (i % 61) as u8,
+                    bin: 4681 + (i % 3) as u16,
+                    flag: (i % 2) as u16 * 16,
+                    next_refid: (i + 1) % 3,
+                    next_pos: 2 * i,
+                    tlen: i - 1_500,
+                    cigar: vec![(len as u32) << 4],
+                    seq: "ACGTN".chars().cycle().skip(i as usize % 5).take(len).collect(),
+                    qual: (0..len).map(|j| (j * 3 % 40) as u8).collect(),
+                    tags: if i % 2 == 0 { b"NMC\x01".to_vec() } else { Vec::new() },
+                    ..TestRecord::new(i % 3, i, &format!("{}{}", "n".repeat(i as usize % 30), i))
+                }
+            })
+            .collect()
+    }
+
+    fn write(path: &Path, records: &[TestRecord]) {
+        let mut writer = new_test_writer(path, "");
+        writer.set_rows_per_block(500);
+        for rec in records {
+            writer.push_record(&rec.to_raw(), false).unwrap();
+        }
+        writer.finish_with_summary(false).unwrap();
+    }
+
+    fn expected(rec: &TestRecord) -> GbamRecord {
+        GbamRecord {
+            refid: Some(rec.refid),
+            pos: Some(rec.pos),
+            mapq: Some(rec.mapq),
+            bin: Some(rec.bin),
+            flag: Some(rec.flag),
+            next_ref_id: Some(rec.next_refid),
+            next_pos: Some(rec.next_pos),
+            tlen: Some(rec.tlen),
+            read_name: Some(format!("{}\0", rec.name).into_bytes()),
+            cigar: Some(Cigar::new(rec.cigar.iter().map(|&op| Op::new(op)).collect())),
+            seq: Some(rec.seq.clone()),
+            qual: Some(rec.qual.clone()),
+            tags: Some(rec.tags.clone()),
+            source_index: None,
+        }
+    }
+
+    fn value(rec: &GbamRecord, field: Fields) -> String {
+        match field {
+            Fields::RefID => format!("{:?}", rec.refid),
+            Fields::Pos => format!("{:?}", rec.pos),
+            Fields::Mapq => format!("{:?}", rec.mapq),
+            Fields::Bin => format!("{:?}", rec.bin),
+            Fields::Flags => format!("{:?}", rec.flag),
+            Fields::NextRefID => format!("{:?}", rec.next_ref_id),
+            Fields::NextPos => format!("{:?}", rec.next_pos),
+            Fields::TemplateLength => format!("{:?}", rec.tlen),
+            Fields::ReadName => format!("{:?}", rec.read_name),
+            Fields::RawCigar => format!("{:?}", rec.cigar),
+            Fields::RawSequence => format!("{:?}", rec.seq),
+            Fields::RawQual => format!("{:?}", rec.qual),
+            Fields::RawTags => format!("{:?}", rec.tags),
+            _ => unreachable!("{} is an index field", field),
+        }
+    }
+
+    #[test]
+    fn test_single_field_templates() {
+        let dir = TempDir::new("gbam_template").unwrap();
+        let path = dir.path().join("fields.gbam");
+        let records = records();
+        write(&path, &records);
+
+        let data_fields: Vec<Fields> = Fields::iterator().copied().filter(is_data_field).collect();
+        assert_eq!(data_fields.len(), DATA_FIELDS_NUM);
+        for &field in &data_fields {
+            let template = ParsingTemplate::new_with(&[field]);
+            assert_eq!(template.get_active_data_fields_iter().collect::<Vec<_>>(), [&field]);
+            let mut reader = Reader::from_path(&path, template).unwrap();
+            let mut fetched = reader.records();
+            for (i, rec) in records.iter().enumerate() {
+                let want = expected(rec);
+                let got = fetched.next_rec().unwrap().unwrap();
+                for &other in &data_fields {
+                    let want = if other == field { value(&want, other) } else { String::from("None") };
+                    assert_eq!(value(got, other), want, "{} of record {}, template of {}", other, i, field);
+                }
+            }
+            assert!(fetched.next_rec().unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn test_index_fields_follow_data_fields() {
+        let mut template = ParsingTemplate::new_with(&[Fields::Pos, Fields::ReadName]);
+        assert!(template.check_if_active(&[Fields::ReadName, Fields::LName]));
+        assert_eq!(template.get_active_fields(), [Fields::Pos, Fields::ReadName, Fields::LName]);
+        template.set(&Fields::ReadName, false);
+        assert!(!template.check_if_active(&[Fields::LName]));
+
+        template.set_all_except(&[Fields::RawQual, Fields::RawTags]);
+        assert!(template.check_if_active(&[Fields::LName, Fields::NCigar, Fields::RawSeqLen]));
+        assert!(!template.check_if_active(&[Fields::SequenceLength]));
+        assert!(!template.check_if_active(&[Fields::RawTagsLen]));
+        assert_eq!(template.get_active_data_fields_iter().count(), DATA_FIELDS_NUM - 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "Index field LName is read along with its data field")]
+    fn test_index_field_not_selectable() {
+        ParsingTemplate::new().set(&Fields::LName, true);
+    }
+
+    #[test]
+    fn test_index_field_errors() {
+        let is_expected = |err: &GbamError| {
+            matches!(err, GbamError::Template(TemplateError::IndexField { field: Fields::NCigar }))
+        };
+        let err = ParsingTemplate::try_new_with(&[Fields::Pos, Fields::NCigar]).err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        assert!(is_expected(&err));
+        let mut template = ParsingTemplate::try_new_with(&[Fields::Pos]).unwrap();
+        assert!(is_expected(&template.try_set(&Fields::NCigar, false).unwrap_err()));
+        let err = template.try_set_all_except(&[Fields::RawQual, Fields::NCigar]).unwrap_err();
+        assert!(is_expected(&err));
+        // Nothing is set on errors.
+        assert_eq!(template.get_active_fields(), [Fields::Pos]);
+    }
+
+    #[test]
+    fn test_missing_index_column() {
+        let dir = TempDir::new("gbam_template").unwrap();
+        let path = dir.path().join("no_lname.gbam");
+        write(&path, &records()[..100]);
+        let mut meta = (*open_test_file(&path).file_meta).clone();
+        meta.get_blocks(&Fields::LName).clear();
+        let mut file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.seek(SeekFrom::End(0)).unwrap();
+        let mut file_info = FileInfo::new([1, 0], 0, 0, String::from("test"), false);
+        write_meta_and_file_info(&mut file, &mut meta, &mut file_info).unwrap();
+
+        let err = Reader::from_path(&path, ParsingTemplate::new_with(&[Fields::ReadName])).err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        let err = match err {
+            GbamError::AtPath { source, .. } => *source,
+            err => panic!("{}", err),
+        };
+        assert!(matches!(
+            err,
+            GbamError::Meta(MetaError::MissingIndex {
+                field: Fields::ReadName,
+                index: Fields::LName,
+            })
+        ));
+    }
+}
@@ -1,4 +1,6 @@
 use std::collections::BTreeMap;
+use std::ops::Range;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 use std::{borrow::Borrow, fs::File};
 
@@ -7,19 +9,39 @@ use bam_tools::record::fields::{
 };
 use memmap2::Mmap;
 use memmap2::MmapOptions;
+use once_cell::sync::OnceCell;
+use std::borrow::Cow;
+use std::io::Read;
+use std::path::Path;
 
-use crate::meta::{BlockMeta, FileInfo, FileMeta, FILE_INFO_SIZE};
+use crate::encryption::{BlockCipher, EncryptionKey};
+use crate::error::{with_path, MetaError, Result, TemplateError};
+use crate::layout::MetaPrefix;
+use crate::meta::{BlockMeta, FileInfo, FileMeta, FILE_INFO_SIZE, META_PREFIX_SIZE};
+use crate::ref_compression::{check_reference, RefSeqMap, Reference};
+use crate::source_index::{SourceIndexColumn, SourceIndexMeta};
+use crate::trace::{Counters, SummaryOnDrop};
 use crate::writer::calc_crc_for_meta_bytes;
+use crate::{GBAM_MAGIC, GBAM_VERSION};
 
 use super::{
-    column::{Column, FixedColumn, Inner, VariableColumn},
+    column::{
+        BlockCache, CacheStats, Column, FixedColumn, Inner, MemoryUsage, ReadAhead, ReadStats, VariableColumn,
+    },
     parse_tmplt::ParsingTemplate,
     record::GbamRecord,
-    records::Records,
+    records::{NameGroups, Records},
+    source::{ReadBlockAt, StreamedBlocks},
 };
 
 use std::convert::TryFrom;
 
+/// Bound of meta size, space reserved for it included. Readers allocate
+/// meta before parsing it.
+pub const MAX_META_SIZE: u64 = 1 << 30;
+/// Bound of nesting of arrays and objects in meta JSON.
+pub const MAX_META_DEPTH: usize = 64;
+
 pub struct Reader {
     // Instead of hashmap. Empty columns will contain None.
     pub columns: Vec<Option<Box<dyn Column + Send>>>,
@@ -27,14 +49,179 @@ pub struct Reader {
     original_template: ParsingTemplate,
     pub amount: usize,
     pub file_meta: Arc<FileMeta>,
-    // Kept so File won't drop while used by mmap.
-    _inner: Box<File>,
+    pub(crate) index_mapping: Option<Arc<Vec<u32>>>,
+    /// Whole file, mapped or in memory.
+    pub mmap: Arc<FileBytes>,
+    // Decompressed blocks count, indexed by field.
+    decompressed: Arc<Vec<AtomicU64>>,
+    read_ahead: Arc<ReadAhead>,
+    // Belongs to the bytes above, so reopened files start with an empty one.
+    block_cache: Arc<BlockCache>,
+    pub(super) reference: Arc<OnceCell<RefSeqMap>>,
+    // Indexed by field, set for active encrypted fields.
+    pub(super) ciphers: Arc<Vec<Option<BlockCipher>>>,
+    // Queries started, and the last one each column was used by, by field.
+    queries: u64,
+    last_used: Vec<u64>,
+    // See `set_buffer_ttl()`.
+    buffer_ttl: Option<u64>,
+    // Set for files with source index, see `GbamRecord::source_index()`.
+    source_index: Option<SourceIndexColumn>,
+    // Emits totals of columns when the reader is dropped.
+    _summary: SummaryOnDrop,
+}
+
+/// Everything of an open file but its columns, shared by readers made with
+/// `SharedReader::cursor()`.
+#[derive(Clone)]
+pub(crate) struct ReaderParts {
+    parsing_template: ParsingTemplate,
+    amount: usize,
+    pub(super) file_meta: Arc<FileMeta>,
     index_mapping: Option<Arc<Vec<u32>>>,
-    pub mmap: Arc<Mmap>,
+    mmap: Arc<FileBytes>,
+    decompressed: Arc<Vec<AtomicU64>>,
+    read_ahead: Arc<ReadAhead>,
+    pub(super) block_cache: Arc<BlockCache>,
+    reference: Arc<OnceCell<RefSeqMap>>,
+    ciphers: Arc<Vec<Option<BlockCipher>>>,
+    source_index: Option<Arc<SourceIndexMeta>>,
+}
+
+/// Bytes of an open GBAM file.
+pub enum FileBytes {
+    Mapped(Mmap),
+    Owned(Vec<u8>),
+    /// Read by blocks on demand, see `Reader::from_source()`.
+    Source(Box<dyn ReadBlockAt>),
+}
+
+impl FileBytes {
+    /// Reads `len` bytes at `offset`, borrowed unless the file is read from
+    /// a source.
+    pub fn read_at(&self, offset: u64, len: usize) -> std::io::Result<Cow<'_, [u8]>> {
+        let bytes: &[u8] = match self {
+            FileBytes::Mapped(mmap) => mmap,
+            FileBytes::Owned(bytes) => bytes,
+            FileBytes::Source(source) => return source.read_at(offset, len).map(Cow::Owned),
+        };
+        usize::try_from(offset)
+            .ok()
+            .and_then(|start| bytes.get(start..start.checked_add(len)?))
+            .map(Cow::Borrowed)
+            .ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::UnexpectedEof,
+                    format!("{} bytes at {} are past the end of file", len, offset),
+                )
+            })
+    }
 }
 
 impl Reader {
-    pub fn new(inner: File, parsing_template: ParsingTemplate) -> std::io::Result<Self> {
+    /// Opens file at `path`. Errors carry the path, see `GbamError::AtPath`.
+    pub fn from_path<P: AsRef<Path>>(path: P, parsing_template: ParsingTemplate) -> Result<Self> {
+        let path = path.as_ref();
+        let file = File::open(path).map_err(|err| with_path(err, path))?;
+        Self::new(file, parsing_template).map_err(|err| with_path(err, path))
+    }
+
+    /// Reads file held in memory, e.g. in tests or where files can't be
+    /// mapped.
+    pub fn from_bytes(bytes: Vec<u8>, parsing_template: ParsingTemplate) -> Result<Self> {
+        let file_meta = verify_and_parse_meta(&bytes)?;
+        Self::open(
+            FileBytes::Owned(bytes),
+            None,
+            parsing_template,
+            &Arc::new(file_meta),
+            None,
+            &|_| None,
+        )
+    }
+
+    /// Opens file read by blocks from `source`, like a remote one, see
+    /// `reader::http::HttpSource`. File info and meta take a request each,
+    /// then every fetched block takes one more.
+    pub fn from_source<S: ReadBlockAt + 'static>(
+        source: S,
+        parsing_template: ParsingTemplate,
+    ) -> Result<Self> {
+        let size = source.size()?;
+        let head = source.read_at(0, FILE_INFO_SIZE.min(size as usize))?;
+        let file_info = parse_file_info(&head)?;
+        let start = meta_read_start(meta_pos(&file_info, size)?);
+        let end = if file_info.meta_in_reserved_space() {
+            file_info.data_range().start.min(size)
+        } else {
+            size
+        };
+        if end - start > MAX_META_SIZE {
+            return Err(invalid_data(format!(
+                "Meta takes {} bytes, more than {} allowed",
+                end - start,
+                MAX_META_SIZE
+            )).into());
+        }
+        let buf = source.read_at(start, (end - start) as usize)?;
+        let file_meta = parse_meta(&file_info, meta_in_tail(&file_info, &buf))?;
+        Self::open(
+            FileBytes::Source(Box::new(source)),
+            None,
+            parsing_template,
+            &Arc::new(file_meta),
+            None,
+            &|_| None,
+        )
+    }
+
+    /// Opens file read once from start to end, like one piped from another
+    /// process, without seeking. Meta has to be in space reserved after file
+    /// info, see `Writer::reserve_meta_bytes()`. Blocks of fields of parsing
+    /// template are kept in memory, other bytes are skipped.
+    pub fn from_stream<R: Read>(mut stream: R, parsing_template: ParsingTemplate) -> Result<Self> {
+        let mut head = vec![0; FILE_INFO_SIZE];
+        stream.read_exact(&mut head)?;
+        let file_info = parse_file_info(&head)?;
+        if file_info.seekpos >= FILE_INFO_SIZE as u64 && !file_info.meta_in_reserved_space() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Meta is at the end of file, so the file can't be read as a stream. Write it with \
+                 Writer::reserve_meta_bytes() large enough for meta.",
+            ).into());
+        }
+        let data_start = file_info.data_range().start;
+        let read_start = meta_read_start(meta_pos(&file_info, data_start)?);
+        // Grows with bytes read, not with the size file info claims.
+        let reserved_len = data_start - FILE_INFO_SIZE as u64;
+        let mut reserved = Vec::new();
+        (&mut stream).take(reserved_len).read_to_end(&mut reserved)?;
+        if (reserved.len() as u64) < reserved_len {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "Stream ends within space reserved for meta",
+            ).into());
+        }
+        let tail = &reserved[(read_start - FILE_INFO_SIZE as u64) as usize..];
+        let file_meta = parse_meta(&file_info, meta_in_tail(&file_info, tail))?;
+        let ranges = template_blocks(&file_meta, &parsing_template);
+        let blocks = StreamedBlocks::read(stream, data_start, &ranges)?;
+        Self::open(
+            FileBytes::Source(Box::new(blocks)),
+            None,
+            parsing_template,
+            &Arc::new(file_meta),
+            None,
+            &|_| None,
+        )
+    }
+
+    /// Raw bytes of block as stored in file, compressed and encrypted.
+    pub fn block_data(&self, block: &BlockMeta) -> Result<Cow<'_, [u8]>> {
+        Ok(self.mmap.read_at(block.seekpos, block.block_size as usize)?)
+    }
+
+    pub fn new(inner: File, parsing_template: ParsingTemplate) -> Result<Self> {
         let inner = inner;
         let mmap = unsafe { Mmap::map(inner.borrow())? };
         let file_meta = verify_and_parse_meta(&mmap)?;
@@ -45,71 +232,350 @@ impl Reader {
         inner: File,
         parsing_template: ParsingTemplate,
         index_mapping: Option<Arc<Vec<u32>>>,
-    ) -> std::io::Result<Self> {
+    ) -> Result<Self> {
         let inner = inner;
         let mmap = unsafe { Mmap::map(inner.borrow())? };
         let file_meta = verify_and_parse_meta(&mmap)?;
         Self::new_with_meta(inner, parsing_template, &Arc::new(file_meta), index_mapping)
     }
 
+    /// Opens file with encrypted fields. `key_provider` gets key id stored
+    /// in meta and returns the key, it's called for active encrypted fields
+    /// only. Fails if a key is missing or wrong.
+    pub fn new_with_key_provider(
+        inner: File,
+        parsing_template: ParsingTemplate,
+        key_provider: &dyn Fn(&str) -> Option<EncryptionKey>,
+    ) -> Result<Self> {
+        let mmap = unsafe { Mmap::map(inner.borrow())? };
+        let file_meta = verify_and_parse_meta(&mmap)?;
+        Self::open_file(inner, parsing_template, &Arc::new(file_meta), None, key_provider)
+    }
+
     pub fn new_with_meta(
-        _inner: File,
+        inner: File,
+        parsing_template: ParsingTemplate,
+        file_meta: &Arc<FileMeta>,
+        index_mapping: Option<Arc<Vec<u32>>>,
+    ) -> Result<Self> {
+        Self::open_file(inner, parsing_template, file_meta, index_mapping, &|_| None)
+    }
+
+    fn open_file(
+        inner: File,
         parsing_template: ParsingTemplate,
         file_meta: &Arc<FileMeta>,
         index_mapping: Option<Arc<Vec<u32>>>,
-    ) -> std::io::Result<Self> {
-        let _copy = _inner.try_clone()?;
-        let _inner: Box<File> = Box::new(_inner);
+        key_provider: &dyn Fn(&str) -> Option<EncryptionKey>,
+    ) -> Result<Self> {
+        // Mapping stays valid after the file is closed.
+        let mmap = unsafe { MmapOptions::new().map(&inner)? };
+        Self::open(
+            FileBytes::Mapped(mmap),
+            Some(inner),
+            parsing_template,
+            file_meta,
+            index_mapping,
+            key_provider,
+        )
+    }
 
-        let mmap = Arc::new(unsafe { MmapOptions::new().map(&_copy)? });
+    fn open(
+        bytes: FileBytes,
+        file: Option<File>,
+        parsing_template: ParsingTemplate,
+        file_meta: &Arc<FileMeta>,
+        index_mapping: Option<Arc<Vec<u32>>>,
+        key_provider: &dyn Fn(&str) -> Option<EncryptionKey>,
+    ) -> Result<Self> {
+        for field in Fields::iterator() {
+            if !file_meta.view_blocks(field).is_empty() {
+                file_meta.get_field_codec(field).check_available(*field)?;
+            }
+        }
+        check_index_columns(file_meta, &parsing_template)?;
+        file_meta.check_record_counts()?;
+        let mmap = Arc::new(bytes);
         // mmap.advise(memmap2::Advice::WillNeed)?;
         // Consumes up to 16 percent of runtime on big files (20GB).
         // verify(&mmap)?;
-        let amount = usize::try_from(
-            file_meta
-                .view_blocks(&Fields::RefID)
-                .iter()
-                .fold(0, |acc: u64, x| acc + u64::from(x.numitems)),
-        )
-        .unwrap();
-        let meta = file_meta.clone();
+        let amount = usize::try_from(file_meta.count_items(&Fields::RefID))
+            .map_err(|_| invalid_data("File has more records than fit in memory".to_owned()))?;
+        let mut ciphers = vec![None; FIELDS_NUM];
+        for &field in parsing_template.get_active_fields_iter() {
+            ciphers[field as usize] = field_cipher(field, file_meta, key_provider)?;
+        }
+        if derived_seq_index(file_meta, &parsing_template) {
+            let field = Fields::SequenceLength;
+            ciphers[field as usize] = field_cipher(field, file_meta, key_provider)?;
+        }
+        let source_index = SourceIndexMeta::from_file_meta(file_meta)?;
+        if let Some(numitems) = source_index.as_ref().map(SourceIndexMeta::numitems) {
+            if numitems != amount as u64 {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("Source index has {} items, but the file has {} records", numitems, amount),
+                ).into());
+            }
+        }
 
-        Ok(Self {
-            columns: init_columns(&mmap, &parsing_template, &meta),
-            original_template: parsing_template.clone(),
+        Ok(Self::from_parts(ReaderParts {
             parsing_template,
-            file_meta: meta,
             amount,
-            _inner,
+            file_meta: file_meta.clone(),
+            index_mapping,
             mmap,
-            index_mapping: index_mapping.clone(),
-        })
+            decompressed: Arc::new((0..FIELDS_NUM).map(|_| AtomicU64::new(0)).collect()),
+            read_ahead: Arc::new(ReadAhead::new(file)),
+            block_cache: Arc::new(BlockCache::new(0)),
+            reference: Arc::new(OnceCell::new()),
+            ciphers: Arc::new(ciphers),
+            source_index: source_index.map(Arc::new),
+        }))
     }
 
-    #[inline(always)]
-    pub fn fill_record(&mut self, mut rec_num: usize, rec: &mut GbamRecord) {
-        if let Some(index_map) = &self.index_mapping {
-            rec_num = index_map[rec_num] as usize;
+    /// Reader with columns of its own over `parts`.
+    pub(crate) fn from_parts(parts: ReaderParts) -> Self {
+        let counters = Counters::new("reader");
+        let source_index = parts
+            .source_index
+            .clone()
+            .map(|meta| SourceIndexColumn::new(meta, parts.mmap.clone()));
+        Self {
+            columns: init_columns(&parts, &counters),
+            original_template: parts.parsing_template.clone(),
+            parsing_template: parts.parsing_template,
+            file_meta: parts.file_meta,
+            amount: parts.amount,
+            mmap: parts.mmap,
+            index_mapping: parts.index_mapping,
+            decompressed: parts.decompressed,
+            read_ahead: parts.read_ahead,
+            block_cache: parts.block_cache,
+            reference: parts.reference,
+            ciphers: parts.ciphers,
+            queries: 0,
+            last_used: vec![0; FIELDS_NUM],
+            buffer_ttl: None,
+            source_index,
+            _summary: SummaryOnDrop::new(&counters),
+        }
+    }
+
+    /// Shared parts of the reader, with its original parsing template.
+    pub(crate) fn parts(&self) -> ReaderParts {
+        ReaderParts {
+            parsing_template: self.original_template.clone(),
+            amount: self.amount,
+            file_meta: self.file_meta.clone(),
+            index_mapping: self.index_mapping.clone(),
+            mmap: self.mmap.clone(),
+            decompressed: self.decompressed.clone(),
+            read_ahead: self.read_ahead.clone(),
+            block_cache: self.block_cache.clone(),
+            reference: self.reference.clone(),
+            ciphers: self.ciphers.clone(),
+            source_index: self.source_index.as_ref().map(SourceIndexColumn::meta),
         }
-        assert!(rec_num < self.amount);
+    }
+
+    /// Fills `rec` with fields of record `rec_num` active in parsing
+    /// template. Fails with `TemplateError::RecordOutOfRange`,
+    /// `TemplateError::FieldNotOpened`, or errors of the columns, see
+    /// `Column::fill_record_field()`. Fields read before the failure are left
+    /// in `rec`.
+    #[inline(always)]
+    pub fn fill_record(&mut self, rec_num: usize, rec: &mut GbamRecord) -> Result<()> {
+        let rec_num = self.physical_rec_num(rec_num)?;
         for &field in self.parsing_template.get_active_data_fields_iter() {
             self.columns[field as usize]
                 .as_mut()
-                .unwrap()
-                .fill_record_field(rec_num, rec);
+                .ok_or(TemplateError::FieldNotOpened { field })?
+                .fill_record_field(rec_num, rec)?;
+        }
+        self.fill_source_index(rec_num, rec)
+    }
+
+    /// Sets source index of `rec` to the one of record at physical position
+    /// `rec_num`, for files with source index.
+    #[inline(always)]
+    pub(crate) fn fill_source_index(&mut self, rec_num: usize, rec: &mut GbamRecord) -> Result<()> {
+        if let Some(column) = self.source_index.as_mut() {
+            rec.source_index = Some(column.get(rec_num)?);
+        }
+        Ok(())
+    }
+
+    /// Position of record in columns, differs if the file has an index mapping.
+    /// Fails with `TemplateError::RecordOutOfRange` for records out of the file.
+    #[inline(always)]
+    pub(crate) fn physical_rec_num(&self, rec_num: usize) -> Result<usize> {
+        let out_of_range = || TemplateError::RecordOutOfRange {
+            rec_num: rec_num as u64,
+            records: self.amount as u64,
+        };
+        let physical = match &self.index_mapping {
+            Some(index_map) => *index_map.get(rec_num).ok_or_else(out_of_range)? as usize,
+            None => rec_num,
+        };
+        if physical >= self.amount {
+            return Err(out_of_range().into());
+        }
+        Ok(physical)
+    }
+
+    /// Inverse of `physical_rec_num()`, sorted. Scans the whole index mapping,
+    /// if the file has one.
+    pub(crate) fn logical_rec_nums(&self, physical: Vec<usize>) -> Vec<usize> {
+        match &self.index_mapping {
+            Some(index_map) => {
+                let physical: std::collections::HashSet<usize> = physical.into_iter().collect();
+                (0..self.amount)
+                    .filter(|&rec_num| physical.contains(&(index_map[rec_num] as usize)))
+                    .collect()
+            }
+            None => physical,
+        }
+    }
+
+    /// Keeps recently used decompressed blocks of all columns, besides the
+    /// current ones, up to `bytes` in total. Least recently used blocks are
+    /// evicted first, roughly, see `column::BlockCache`. Speeds up access
+    /// jumping between blocks, like repeated fetches of nearby regions. No
+    /// blocks are cached by default.
+    pub fn set_block_cache_bytes(&mut self, bytes: usize) {
+        self.block_cache.set_budget(bytes);
+    }
+
+    /// Sets cache budget to fit `blocks` full blocks of each column, sized by
+    /// block size limits of the file, see `FileMeta::get_block_size_limit()`
+    /// and `set_block_cache_bytes()`. Blocks of records larger than the
+    /// limit take more.
+    pub fn set_block_cache_size(&mut self, blocks: usize) {
+        let block_bytes: usize = Fields::iterator()
+            .map(|field| self.file_meta.get_block_size_limit(field))
+            .sum();
+        self.set_block_cache_bytes(blocks * block_bytes);
+    }
+
+    /// Drops cached blocks, stats are kept.
+    pub fn clear_block_cache(&mut self) {
+        self.block_cache.clear();
+    }
+
+    /// Block cache counters since the file was opened, to size the cache.
+    pub fn cache_stats(&self) -> CacheStats {
+        self.block_cache.stats()
+    }
+
+    /// Drops decompressed blocks of all columns and the block cache, which
+    /// is shared by cursors of a `SharedReader`. Blocks are read again when
+    /// needed, so long-lived readers don't keep blocks of columns used once.
+    pub fn reset_buffers(&mut self) {
+        for column in self.columns.iter_mut().flatten() {
+            column.release_buffers();
+        }
+        if let Some(column) = self.source_index.as_mut() {
+            column.release();
+        }
+        self.block_cache.clear();
+    }
+
+    /// Columns not used by `queries` queries in a row drop their
+    /// decompressed blocks, like `reset_buffers()` does. Queries are
+    /// `records()` and its variants, region fetches and `typed_column()`,
+    /// and use the columns of parsing template they start with. Off by
+    /// default.
+    pub fn set_buffer_ttl(&mut self, queries: Option<u64>) {
+        self.buffer_ttl = queries;
+    }
+
+    /// Bytes of decompressed blocks held by columns and the block cache.
+    pub fn memory_usage(&self) -> MemoryUsage {
+        MemoryUsage {
+            columns: Fields::iterator()
+                .filter_map(|field| {
+                    let column = self.columns[*field as usize].as_ref()?;
+                    Some((*field, column.buffer_bytes()))
+                })
+                .collect(),
+            block_cache: self.block_cache.stats().bytes as usize,
+        }
+    }
+
+    /// Counts a query using columns of parsing template and drops blocks of
+    /// columns idle for longer than `set_buffer_ttl()` allows.
+    pub(crate) fn start_query(&mut self) {
+        self.queries += 1;
+        for &field in self.parsing_template.get_active_fields_iter() {
+            self.last_used[field as usize] = self.queries;
+        }
+        if let Some(ttl) = self.buffer_ttl {
+            for (field, column) in self.columns.iter_mut().enumerate() {
+                if let Some(column) = column {
+                    if self.queries - self.last_used[field] > ttl {
+                        column.release_buffers();
+                    }
+                }
+            }
+        }
+    }
+
+    /// Tells the kernel that the file is read sequentially, and makes every
+    /// active column request its next blocks ahead of use, see
+    /// `column::READ_AHEAD_BLOCKS`. Adjacent blocks of a column are requested
+    /// in one call. Meant for full file scans, random access only loads
+    /// blocks which won't be used. Off by default, no-op on non-unix targets.
+    pub fn sequential_hint(&mut self, enabled: bool) {
+        #[cfg(unix)]
+        if let (true, FileBytes::Mapped(mmap)) = (enabled, &*self.mmap) {
+            // Best effort, like the file hints.
+            let _ = mmap.advise(memmap2::Advice::Sequential);
         }
+        self.read_ahead.set_enabled(enabled);
+    }
+
+    /// Block bytes and read requests since the file was opened.
+    pub fn read_stats(&self) -> ReadStats {
+        self.read_ahead.stats()
+    }
+
+    /// Amount of `field` blocks decompressed since the file was opened.
+    pub fn blocks_decompressed(&self, field: &Fields) -> u64 {
+        self.decompressed[*field as usize].load(Ordering::Relaxed)
+    }
+
+    /// Sets reference to decode sequences of files written with
+    /// `Writer::set_reference()`. Fails if it lacks a sequence the file was
+    /// encoded against, or its MD5 differs, and if a reference is already
+    /// set.
+    pub fn set_reference(&mut self, reference: Arc<Reference>) -> Result<()> {
+        check_reference(&self.file_meta, &reference)?;
+        let refs = RefSeqMap::new(reference, self.file_meta.get_ref_seqs());
+        self.reference.set(refs).map_err(|_| {
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, "Reference is already set").into()
+        })
     }
 
-    pub fn get_column(&mut self, field: &Fields) -> &mut Box<dyn Column + Send> {
-        self.columns[*field as usize].as_mut().unwrap()
+    /// Column of `field`. Fails with `TemplateError::FieldNotOpened` if the
+    /// reader was opened without it.
+    pub fn get_column(&mut self, field: &Fields) -> Result<&mut Box<dyn Column + Send>> {
+        let field = *field;
+        Ok(self.columns[field as usize]
+            .as_mut()
+            .ok_or(TemplateError::FieldNotOpened { field })?)
     }
 
-    // Temporarily disable fetching for fields which are not needed
-    pub fn fetch_only(&mut self, fields: &[Fields]) {
-        self.parsing_template.clear();
-        for field in fields {
-            self.parsing_template.set(field, true);
+    /// Temporarily disables fetching of fields which are not needed, see
+    /// `restore_template()`. Fails with `TemplateError::IndexField` or
+    /// `TemplateError::FieldNotOpened`, leaving the template as is.
+    pub fn fetch_only(&mut self, fields: &[Fields]) -> Result<()> {
+        let template = ParsingTemplate::try_new_with(fields)?;
+        if let Some(&field) = fields.iter().find(|field| self.columns[**field as usize].is_none()) {
+            return Err(TemplateError::FieldNotOpened { field }.into());
         }
+        self.parsing_template = template;
+        Ok(())
     }
 
     // Restores original template if some fields fetching was paused.
@@ -117,86 +583,1259 @@ impl Reader {
         self.parsing_template = self.original_template.clone();
     }
 
+    /// Returns indices of `field` blocks whose stats satisfy `predicate`,
+    /// called with little endian min and max values. Blocks without stats
+    /// are always returned, since nothing is known about them, and empty
+    /// blocks never are. Stats are collected only for fields passed to the
+    /// writer in `collect_stats_for`.
+    pub fn blocks_overlapping<P>(&self, field: &Fields, predicate: P) -> Vec<usize>
+    where
+        P: Fn(&[u8], &[u8]) -> bool,
+    {
+        self.file_meta
+            .view_blocks(field)
+            .iter()
+            .enumerate()
+            .filter(|(_, block)| block.numitems > 0)
+            .filter(|(_, block)| match &block.stats {
+                Some(stat) => predicate(
+                    &stat.min_value.to_le_bytes(),
+                    &stat.max_value.to_le_bytes(),
+                ),
+                None => true,
+            })
+            .map(|(idx, _)| idx)
+            .collect()
+    }
+
+    /// Stat of write time collector `name` for each `field` block, see
+    /// `stats::StatsCollector`. None for blocks written without it.
+    pub fn block_stats<'a>(&'a self, field: &Fields, name: &'a str) -> impl Iterator<Item = Option<u64>> + 'a {
+        self.file_meta
+            .view_blocks(field)
+            .iter()
+            .map(move |block| block.extra_stat(name))
+    }
+
+    /// Indices of blocks holding `records`, the same for every fixed sized
+    /// field. None unless the file was written with `set_rows_per_block()`.
+    pub fn blocks_for_records(&self, records: Range<usize>) -> Option<Range<usize>> {
+        let rows = self.file_meta.get_rows_per_block()? as usize;
+        if records.is_empty() {
+            return Some(0..0);
+        }
+        Some(records.start / rows..(records.end - 1) / rows + 1)
+    }
+
+    pub fn num_records(&self) -> usize {
+        self.amount
+    }
+
+    /// Average size of a record in bytes, uncompressed, as stored in columns
+    /// of all fields. Close to BAM record size, but sequences count packed if
+    /// written with `Writer::set_seq_packing()`. Zero for empty files.
+    pub fn estimated_record_size(&self) -> usize {
+        if self.amount == 0 {
+            return 0;
+        }
+        let total: u64 = Fields::iterator()
+            .flat_map(|field| self.file_meta.view_blocks(field))
+            .map(|block| block.uncompressed_size)
+            .sum();
+        (total / self.amount as u64) as usize
+    }
+
+    /// Summary stored by a write time collector, e.g. `InsertSizeHistogram`.
+    pub fn analytics(&self, name: &str) -> Option<&serde_json::Value> {
+        self.file_meta.get_analytics(name)
+    }
+
     /// Get iterator over all GBAM records (according to parsing template).
     pub fn records(&mut self) -> Records {
         Records::new(self)
     }
+
+    /// Get iterator over records of `range`, by record number, like a page
+    /// of `records()`. Blocks are looked up by record counts, so only the
+    /// ones covering the range are decompressed, for every active field.
+    /// Fails with `TemplateError::RecordOutOfRange` if the range is not
+    /// within the file, reporting its last record, or its start if the
+    /// range is reversed.
+    pub fn records_range(&mut self, range: Range<u64>) -> Result<Records<'_>> {
+        if range.start > range.end || range.end > self.amount as u64 {
+            let rec_num = if range.start > range.end { range.start } else { range.end - 1 };
+            return Err(TemplateError::RecordOutOfRange {
+                rec_num,
+                records: self.amount as u64,
+            }
+            .into());
+        }
+        Ok(Records::new_range(self, range.start as usize..range.end as usize))
+    }
+
+    /// Number of the first record of the trailing run of unplaced reads, see
+    /// `FileMeta::get_unmapped_tail_start()`. None if the file has no such
+    /// marker, or records are read through an index mapping.
+    pub fn unmapped_tail_start(&self) -> Option<usize> {
+        if self.index_mapping.is_some() {
+            return None;
+        }
+        let start = self.file_meta.get_unmapped_tail_start()?;
+        Some((start as usize).min(self.amount))
+    }
+
+    /// Get iterator over records before the unmapped tail, see
+    /// `unmapped_tail_start()`. Blocks holding only tail records are never
+    /// decompressed. Fails if the file has no tail marker.
+    pub fn mapped_records(&mut self) -> Result<Records<'_>> {
+        let start = self.tail_start_or_err()?;
+        Ok(Records::new_range(self, 0..start))
+    }
+
+    /// Get iterator over the unmapped tail, see `unmapped_tail_start()`.
+    /// Blocks holding only records before it are never decompressed. Fails
+    /// if the file has no tail marker.
+    pub fn unmapped_tail(&mut self) -> Result<Records<'_>> {
+        let start = self.tail_start_or_err()?;
+        let amount = self.amount;
+        Ok(Records::new_range(self, start..amount))
+    }
+
+    fn tail_start_or_err(&self) -> std::io::Result<usize> {
+        self.unmapped_tail_start().ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "File has no unmapped tail marker, it was written without RefID stats",
+            )
+        })
+    }
+
+    /// Get iterator over groups of records sharing a read name. Only name
+    /// sorted or collated files are supported, since the grouping relies on
+    /// records with the same name being adjacent. ReadName has to be enabled
+    /// in parsing template.
+    pub fn records_by_name(&mut self) -> Result<NameGroups<'_>> {
+        let sort_order = self.file_meta.get_sort_order();
+        if !sort_order.is_name_grouped() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "Grouping by read name requires a name sorted or collated file, but sort order is {:?}. \
+                     Sort the input by name first (bam_tools::sorting::sort::sort_bam with SortBy::Name).",
+                    sort_order
+                ),
+            ).into());
+        }
+        if !self.parsing_template.check_if_active(&[Fields::ReadName]) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "ReadName field has to be enabled in parsing template to group records by name.",
+            ).into());
+        }
+        Ok(NameGroups::new(self.records()))
+    }
 }
 
-fn init_columns(
-    mmap: &Arc<Mmap>,
-    parse_template: &ParsingTemplate,
-    meta: &Arc<FileMeta>,
-) -> Vec<Option<Box<dyn Column + Send>>> {
+fn init_columns(parts: &ReaderParts, counters: &Counters) -> Vec<Option<Box<dyn Column + Send>>> {
     let mut res = Vec::new();
     (0..FIELDS_NUM).for_each(|_| res.push(None));
-    for &field in parse_template.get_active_fields_iter() {
-        res[field as usize] = Some(init_col(field, mmap, meta));
+    for &field in parts.parsing_template.get_active_fields_iter() {
+        res[field as usize] = Some(init_col(field, parts, counters));
     }
     res
 }
 
-fn init_col(field: Fields, mmap: &Arc<Mmap>, meta: &Arc<FileMeta>) -> Box<dyn Column + Send> {
-    let inner = Inner::new(meta.clone(), field, mmap.clone());
+fn field_cipher(
+    field: Fields,
+    meta: &FileMeta,
+    key_provider: &dyn Fn(&str) -> Option<EncryptionKey>,
+) -> std::io::Result<Option<BlockCipher>> {
+    let params = match meta.get_field_encryption(&field) {
+        Some(params) => params,
+        None => return Ok(None),
+    };
+    let key = key_provider(&params.key_id).ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::PermissionDenied,
+            format!(
+                "Field {} is encrypted with key '{}', but no key was provided. \
+                 Use Reader::new_with_key_provider or exclude the field from parsing template.",
+                field, params.key_id
+            ),
+        )
+    })?;
+    params.open(field, &key).map(Some)
+}
+
+fn init_col(field: Fields, parts: &ReaderParts, counters: &Counters) -> Box<dyn Column + Send> {
+    let meta = &parts.file_meta;
+    let inner = |field: Fields| {
+        Inner::new(
+            meta.clone(),
+            field,
+            parts.mmap.clone(),
+            parts.ciphers[field as usize].clone(),
+            parts.decompressed.clone(),
+            parts.read_ahead.clone(),
+            parts.block_cache.clone(),
+            parts.reference.clone(),
+            counters.clone(),
+        )
+    };
     match field_type(&field) {
         FieldType::FixedSized => Box::new(FixedColumn::new(
-            inner,
+            inner(field),
             meta.get_field_size(&field).unwrap() as usize,
         )),
         FieldType::VariableSized => {
-            let idx_field = var_size_field_to_index(&field);
-            let idx_inner = Inner::new(meta.clone(), idx_field, mmap.clone());
+            let mut idx_field = var_size_field_to_index(&field);
+            let derived = meta.is_index_derived(&idx_field);
+            if derived {
+                idx_field = Fields::SequenceLength;
+            }
             let idx_col =
-                FixedColumn::new(idx_inner, meta.get_field_size(&idx_field).unwrap() as usize);
-            Box::new(VariableColumn::new(inner, idx_col))
+                FixedColumn::new(inner(idx_field), meta.get_field_size(&idx_field).unwrap() as usize);
+            if derived {
+                Box::new(VariableColumn::with_derived_index(inner(field), idx_col))
+            } else {
+                Box::new(VariableColumn::new(inner(field), idx_col))
+            }
         }
     }
 }
 
-fn parse_file_info(mmap: &Mmap) -> FileInfo {
-    let file_info_bytes = &mmap[0..FILE_INFO_SIZE];
-    let end_of_json = file_info_bytes.iter().position(|&r| r == 0).unwrap();
-    let file_info_str = String::from_utf8(file_info_bytes[..end_of_json].to_owned()).unwrap();
-    serde_json::from_str(&file_info_str).expect("File meta json string was damaged.")
+// RawSequence is read with offsets derived from SequenceLength, see
+// `VariableColumn::with_derived_index()`.
+fn derived_seq_index(meta: &FileMeta, template: &ParsingTemplate) -> bool {
+    meta.is_index_derived(&Fields::RawSeqLen) && template.check_if_active(&[Fields::RawSequence])
 }
 
-#[allow(dead_code)]
-fn verify(mmap: &Mmap) -> std::io::Result<()> {
-    let file_info = parse_file_info(mmap);
-    // Read file meta
-    let buf = &mmap[file_info.seekpos as usize..];
-    if calc_crc_for_meta_bytes(buf) != file_info.crc32 {
-        return Err(std::io::Error::new(
-            std::io::ErrorKind::InvalidInput,
-            "Metadata JSON was damaged.",
-        ));
+/// Fails if a variable sized field of `template` has blocks, but the index
+/// column it's read with has none.
+pub(crate) fn check_index_columns(meta: &FileMeta, template: &ParsingTemplate) -> std::io::Result<()> {
+    for &field in template.get_active_data_fields_iter() {
+        if !matches!(field_type(&field), FieldType::VariableSized) || meta.view_blocks(&field).is_empty() {
+            continue;
+        }
+        let mut index = var_size_field_to_index(&field);
+        if meta.is_index_derived(&index) {
+            index = Fields::SequenceLength;
+        }
+        if meta.view_blocks(&index).is_empty() {
+            return Err(MetaError::MissingIndex { field, index }.into());
+        }
     }
     Ok(())
 }
-fn verify_and_parse_meta(mmap: &Mmap) -> std::io::Result<FileMeta> {
-    let file_info = parse_file_info(mmap);
-    // Read file meta
-    let buf = &mmap[file_info.seekpos as usize..];
+
+/// Offsets and sizes of blocks read by columns of `template`, sorted.
+fn template_blocks(meta: &FileMeta, template: &ParsingTemplate) -> Vec<(u64, u32)> {
+    let mut fields = Vec::new();
+    for &field in template.get_active_fields_iter() {
+        fields.push(field);
+        if matches!(field_type(&field), FieldType::VariableSized) {
+            fields.push(var_size_field_to_index(&field));
+        }
+    }
+    if derived_seq_index(meta, template) {
+        fields.push(Fields::SequenceLength);
+    }
+    let mut ranges: Vec<(u64, u32)> = fields
+        .iter()
+        .flat_map(|field| meta.view_blocks(field))
+        .map(|block| (block.seekpos, block.block_size))
+        .collect();
+    // Blocks shared by grouped fields are listed by each of them.
+    ranges.sort_unstable();
+    ranges.dedup();
+    ranges
+}
+
+fn invalid_data(msg: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, msg)
+}
+
+pub(crate) fn parse_file_info(bytes: &[u8]) -> std::io::Result<FileInfo> {
+    let damaged = || {
+        invalid_data(
+            "Not a GBAM file or file info is damaged. If the file was not finalized, \
+             try gbam_tools::recover::recover_meta."
+                .to_owned(),
+        )
+    };
+    if bytes.len() < FILE_INFO_SIZE {
+        return Err(damaged());
+    }
+    let file_info_bytes = &bytes[0..FILE_INFO_SIZE];
+    let end_of_json = file_info_bytes
+        .iter()
+        .position(|&r| r == 0)
+        .unwrap_or(FILE_INFO_SIZE);
+    let file_info: FileInfo =
+        serde_json::from_slice(&file_info_bytes[..end_of_json]).map_err(|_| damaged())?;
+    if file_info.magic.as_bytes() != GBAM_MAGIC {
+        return Err(damaged());
+    }
+    // Newer minor versions add meta this build skips, or fails to parse
+    // below, see `GBAM_VERSION`.
+    if file_info.gbam_version[0] != GBAM_VERSION[0] {
+        return Err(MetaError::UnsupportedVersion {
+            found: file_info.gbam_version,
+            supported: GBAM_VERSION,
+        }
+        .into());
+    }
+    if file_info.reserved_meta > MAX_META_SIZE {
+        return Err(invalid_data(format!(
+            "File info reserves {} bytes for meta, more than {} allowed",
+            file_info.reserved_meta, MAX_META_SIZE
+        )));
+    }
+    Ok(file_info)
+}
+
+/// Position of meta in file of `size` bytes.
+pub(crate) fn meta_pos(file_info: &FileInfo, size: u64) -> std::io::Result<u64> {
+    let seekpos = file_info.seekpos;
+    if seekpos < FILE_INFO_SIZE as u64 || seekpos >= size {
+        return Err(invalid_data(format!(
+            "File info has invalid meta position {} (file size is {}), the file was \
+             probably not finalized. Try gbam_tools::recover::recover_meta.",
+            seekpos, size
+        )));
+    }
+    Ok(seekpos)
+}
+
+/// Where to start reading meta at `seekpos`, its prefix included if there
+/// is room for one.
+pub(crate) fn meta_read_start(seekpos: u64) -> u64 {
+    if seekpos >= (FILE_INFO_SIZE + META_PREFIX_SIZE) as u64 {
+        seekpos - META_PREFIX_SIZE as u64
+    } else {
+        seekpos
+    }
+}
+
+/// Meta bytes in `tail`, read from `meta_read_start()` to the end of file.
+/// Meta ends where its prefix says if the prefix matches file info, bytes
+/// after it are ignored (see `meta_edit`). Otherwise meta takes the rest.
+pub(crate) fn meta_in_tail<'a>(file_info: &FileInfo, tail: &'a [u8]) -> &'a [u8] {
+    let prefix_len = (file_info.seekpos - meta_read_start(file_info.seekpos)) as usize;
+    let (prefix, meta) = tail.split_at(prefix_len.min(tail.len()));
+    match MetaPrefix::decode(prefix) {
+        Some(prefix) if prefix.crc32 == file_info.crc32 && prefix.len <= meta.len() as u64 => {
+            &meta[..prefix.len as usize]
+        }
+        _ => meta,
+    }
+}
+
+fn check_meta_crc(file_info: &FileInfo, buf: &[u8]) -> std::io::Result<()> {
     if calc_crc_for_meta_bytes(buf) != file_info.crc32 {
-        return Err(std::io::Error::new(
-            std::io::ErrorKind::InvalidInput,
-            "Metadata JSON was damaged.",
+        return Err(invalid_data(
+            "Metadata JSON was damaged. Try gbam_tools::recover::recover_meta.".to_owned(),
         ));
     }
-    let file_meta_json_str = String::from_utf8(buf.to_owned()).unwrap();
-    Ok(serde_json::from_str(&file_meta_json_str).expect("File meta json string was damaged."))
+    Ok(())
+}
+
+/// Returns meta bytes pointed to by file info, checking their crc32.
+fn meta_bytes(mmap: &[u8]) -> std::io::Result<&[u8]> {
+    let file_info = parse_file_info(mmap)?;
+    let start = meta_read_start(meta_pos(&file_info, mmap.len() as u64)?) as usize;
+    let buf = meta_in_tail(&file_info, &mmap[start..]);
+    check_meta_crc(&file_info, buf)?;
+    Ok(buf)
+}
+
+pub(crate) fn parse_meta(file_info: &FileInfo, buf: &[u8]) -> std::io::Result<FileMeta> {
+    check_meta_crc(file_info, buf)?;
+    let meta = parse_meta_json(buf)?;
+    meta.check_block_ranges(file_info.data_range())?;
+    Ok(meta)
+}
+
+/// Parses meta JSON within `MAX_META_SIZE` and `MAX_META_DEPTH`, checking
+/// sizes of fields and blocks.
+pub(crate) fn parse_meta_json(buf: &[u8]) -> std::io::Result<FileMeta> {
+    check_meta_limits(buf, MAX_META_SIZE, MAX_META_DEPTH)?;
+    let meta: FileMeta = serde_json::from_slice(buf)
+        .map_err(|e| invalid_data(format!("File meta JSON is damaged: {}", e)))?;
+    meta.check_sizes()?;
+    Ok(meta)
+}
+
+/// Fails if meta JSON `buf` is longer than `max_size` or nests arrays and
+/// objects deeper than `max_depth`.
+fn check_meta_limits(buf: &[u8], max_size: u64, max_depth: usize) -> std::io::Result<()> {
+    if buf.len() as u64 > max_size {
+        return Err(invalid_data(format!(
+            "Meta takes {} bytes, more than {} allowed",
+            buf.len(),
+            max_size
+        )));
+    }
+    let (mut depth, mut in_string, mut escaped) = (0, false, false);
+    for &byte in buf {
+        if in_string {
+            match byte {
+                _ if escaped => escaped = false,
+                b'\\' => escaped = true,
+                b'"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        match byte {
+            b'"' => in_string = true,
+            b'[' | b'{' => {
+                depth += 1;
+                if depth > max_depth {
+                    return Err(invalid_data(format!(
+                        "Meta JSON nests deeper than {} levels",
+                        max_depth
+                    )));
+                }
+            }
+            b']' | b'}' => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+#[allow(dead_code)]
+fn verify(mmap: &[u8]) -> std::io::Result<()> {
+    meta_bytes(mmap).map(|_| ())
+}
+
+fn verify_and_parse_meta(mmap: &[u8]) -> std::io::Result<FileMeta> {
+    let file_info = parse_file_info(mmap)?;
+    let start = meta_read_start(meta_pos(&file_info, mmap.len() as u64)?) as usize;
+    parse_meta(&file_info, meta_in_tail(&file_info, &mmap[start..]))
 }
 
 // The tree map will be used to quickly determine which block record belong to.
 pub(crate) fn generate_block_treemap(meta: &FileMeta, field: &Fields) -> BTreeMap<usize, usize> {
-    meta.view_blocks(field)
-        .iter()
+    meta.block_starts(field)
+        .into_iter()
         .enumerate()
-        // Prefix sum.
-        .scan(0, |acc: &mut u64, (block_index, x): (usize, &BlockMeta)| {
-            let current_chunk = Some((usize::try_from(*acc).unwrap(), block_index));
-            *acc += x.numitems as u64;
-            current_chunk
-        })
+        .map(|(block_index, start)| (usize::try_from(start).unwrap(), block_index))
         .collect()
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::reader::region::Region;
+    use std::convert::TryInto;
+    use crate::test_utils::{
+        new_test_writer, open_test_file, sam_header_bytes, test_ref_seqs, write_test_file, write_test_file_with_limit,
+        TestRecord,
+    };
+    use bam_tools::record::fields::Fields;
+    use super::{
+        check_meta_limits, parse_file_info, parse_meta_json, ParsingTemplate, Reader, MAX_META_DEPTH,
+        MAX_META_SIZE,
+    };
+    use crate::compressor::compress;
+    use crate::reader::column::{decompress_block, decompress_into};
+    use crate::error::{gbam_error, CodecError, CorruptionError, GbamError, MetaError, TemplateError};
+    use crate::meta::{BlockMeta, Codecs, FileInfo, FileMeta, FILE_INFO_SIZE, META_PREFIX_SIZE};
+    use crate::reader::record::GbamRecord;
+    use crate::reader::source::ReadBlockAt;
+    use std::path::Path;
+    use crate::{GBAM_VERSION, MAX_RECORD_SIZE, SIZE_LIMIT};
+    use serde_json::json;
+    use crate::writer::{write_meta_and_file_info, Writer, WriterSettings};
+    use std::fs::{File, OpenOptions};
+    use std::io::{Seek, SeekFrom, Write};
+    use tempdir::TempDir;
+
+    const SORTED: &str = "@HD\tVN:1.6\tSO:coordinate\n";
+
+    #[test]
+    fn test_empty_file() {
+        let dir = TempDir::new("gbam_test").unwrap();
+        let path = dir.path().join("empty.gbam");
+        write_test_file(&path, SORTED, &[]);
+
+        let mut reader = open_test_file(&path);
+        assert_eq!(reader.num_records(), 0);
+        assert!(reader.records().next_rec().unwrap().is_none());
+        assert!(reader.fetch(&Region::new(0, 0, 1000)).unwrap().next_rec().unwrap().is_none());
+        assert_eq!(
+            reader.records_by_name().err().unwrap().kind(),
+            std::io::ErrorKind::InvalidInput
+        );
+
+        let path = dir.path().join("empty_packed.gbam");
+        let mut writer = new_test_writer(&path, "@HD\tVN:1.6\tSO:queryname\n");
+        writer.set_seq_packing(true);
+        writer.finish_with_summary(false).unwrap();
+        let mut reader = open_test_file(&path);
+        assert_eq!(reader.num_records(), 0);
+        assert!(reader.records_by_name().unwrap().next().is_none());
+    }
+
+    #[test]
+    fn test_blocks_overlapping() {
+        let dir = TempDir::new("gbam_test").unwrap();
+        let path = dir.path().join("stats.gbam");
+        // Pos column holds ~2M items per block.
+        let records: Vec<TestRecord> = (0..4_300_000)
+            .map(|i| TestRecord::new(0, i * 2, "r"))
+            .collect();
+        write_test_file(&path, SORTED, &records);
+
+        let mut reader = open_test_file(&path);
+        let blocks = reader.file_meta.view_blocks(&Fields::Pos).clone();
+        assert!(blocks.len() > 2);
+        let mut first = 0;
+        for block in &blocks {
+            let stat = block.stats.as_ref().unwrap();
+            let last = first + block.numitems as usize - 1;
+            assert_eq!(stat.min_value, records[first].pos);
+            assert_eq!(stat.max_value, records[last].pos);
+            first = last + 1;
+        }
+
+        let read_i32 = |b: &[u8]| i32::from_le_bytes(b.try_into().unwrap());
+        let overlapping = |start: i32, end: i32| {
+            reader.blocks_overlapping(&Fields::Pos, |min, max| {
+                read_i32(min) < end && read_i32(max) >= start
+            })
+        };
+        let boundary = blocks[0].stats.as_ref().unwrap().max_value;
+        assert_eq!(overlapping(0, 10), vec![0]);
+        assert_eq!(overlapping(boundary, boundary + 4), vec![0, 1]);
+        assert!(overlapping(-10, -1).is_empty());
+        assert_eq!(overlapping(0, i32::MAX).len(), blocks.len());
+
+        // No stats collected for Mapq, so nothing can be skipped.
+        let mapq_blocks = reader.file_meta.view_blocks(&Fields::Mapq).len();
+        assert_eq!(
+            reader.blocks_overlapping(&Fields::Mapq, |_, _| false).len(),
+            mapq_blocks
+        );
+        assert_eq!(reader.records().next_rec().unwrap().unwrap().pos, Some(0));
+    }
+
+    #[test]
+    fn test_single_record_file() {
+        let dir = TempDir::new("gbam_test").unwrap();
+        let path = dir.path().join("single.gbam");
+        write_test_file(&path, SORTED, &[TestRecord::new(1, 100, "single")]);
+
+        let mut reader = open_test_file(&path);
+        assert_eq!(reader.num_records(), 1);
+        for field in Fields::iterator() {
+            assert_eq!(reader.file_meta.view_blocks(field).len(), 1);
+        }
+        let mut records = reader.records();
+        assert_eq!(records.next_rec().unwrap().unwrap().pos, Some(100));
+        assert!(records.next_rec().unwrap().is_none());
+
+        let mut fetched = reader.fetch(&Region::new(1, 0, 1000)).unwrap();
+        assert_eq!(fetched.next_rec().unwrap().unwrap().pos, Some(100));
+        assert!(fetched.next_rec().unwrap().is_none());
+        assert!(reader.fetch(&Region::new(0, 0, 1000)).unwrap().next_rec().unwrap().is_none());
+        assert!(reader.fetch(&Region::new(2, 0, 1000)).unwrap().next_rec().unwrap().is_none());
+    }
+
+    fn write_with_reserved_meta(path: &Path, reserved: usize) -> Vec<TestRecord> {
+        let records: Vec<TestRecord> =
+            (0..1000).map(|i| TestRecord::new(i % 3, i, &format!("r{}", i))).collect();
+        let mut writer = new_test_writer(path, SORTED);
+        writer.reserve_meta_bytes(reserved).unwrap();
+        writer.set_content_digest(true);
+        writer.set_rows_per_block(300);
+        for rec in &records {
+            writer.push_record(&rec.to_raw(), false).unwrap();
+        }
+        writer.finish_with_summary(false).unwrap();
+        records
+    }
+
+    fn check_records(reader: &mut Reader, expected: &[TestRecord]) {
+        let mut records = reader.records();
+        for rec in expected {
+            let fetched = records.next_rec().unwrap().unwrap();
+            assert_eq!(fetched.pos, Some(rec.pos));
+            assert_eq!(fetched.read_name.as_deref(), Some(format!("{}\0", rec.name).as_bytes()));
+        }
+        assert!(records.next_rec().unwrap().is_none());
+    }
+
+    fn blocks(meta: &FileMeta) -> impl Iterator<Item = &BlockMeta> {
+        Fields::iterator().flat_map(move |field| meta.view_blocks(field))
+    }
+
+    #[test]
+    fn test_meta_in_reserved_space() {
+        let dir = TempDir::new("gbam_test").unwrap();
+        let path = dir.path().join("reserved.gbam");
+        let records = write_with_reserved_meta(&path, 100_000);
+
+        let bytes = std::fs::read(&path).unwrap();
+        let file_info = parse_file_info(&bytes).unwrap();
+        assert_eq!(file_info.seekpos, (FILE_INFO_SIZE + META_PREFIX_SIZE) as u64);
+        let mut reader = open_test_file(&path);
+        let data_start = (FILE_INFO_SIZE + 100_000) as u64;
+        assert!(blocks(&reader.file_meta).all(|block| block.seekpos >= data_start));
+        // Nothing follows the last block.
+        let blocks_end = blocks(&reader.file_meta).map(|block| block.seekpos + block.block_size as u64).max();
+        assert_eq!(blocks_end, Some(bytes.len() as u64));
+        check_records(&mut reader, &records);
+        reader.verify_file_digest().unwrap();
+        assert!(crate::inspect::inspect(&path).unwrap().failed_checks.is_empty());
+
+        // A slice is read forward only, it can't seek.
+        let template = ParsingTemplate::new_with(&[Fields::RefID, Fields::Pos, Fields::ReadName]);
+        let mut streamed = Reader::from_stream(&bytes[..], template).unwrap();
+        check_records(&mut streamed, &records);
+        // Blocks of other fields were skipped.
+        let mapq_block = streamed.file_meta.view_blocks(&Fields::Mapq)[0].clone();
+        assert_eq!(streamed.block_data(&mapq_block).err().unwrap().kind(), std::io::ErrorKind::NotFound);
+
+        let mut template = ParsingTemplate::new();
+        template.set_all();
+        assert!(Reader::from_stream(&bytes[..bytes.len() - 1], template).is_err());
+    }
+
+    #[test]
+    fn test_meta_overflowing_reserved_space() {
+        let dir = TempDir::new("gbam_test").unwrap();
+        let path = dir.path().join("overflow.gbam");
+        let records = write_with_reserved_meta(&path, 64);
+
+        let bytes = std::fs::read(&path).unwrap();
+        let file_info = parse_file_info(&bytes).unwrap();
+        assert!(!file_info.meta_in_reserved_space());
+        let mut reader = open_test_file(&path);
+        let data_start = (FILE_INFO_SIZE + 64) as u64;
+        assert_eq!(blocks(&reader.file_meta).map(|block| block.seekpos).min(), Some(data_start));
+        assert!(file_info.seekpos > blocks(&reader.file_meta).map(|block| block.seekpos).max().unwrap());
+        check_records(&mut reader, &records);
+        reader.verify_file_digest().unwrap();
+        assert!(crate::inspect::inspect(&path).unwrap().failed_checks.is_empty());
+
+        let err = Reader::from_stream(&bytes[..], ParsingTemplate::new_with(&[Fields::Pos])).err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_from_bytes() {
+        let dir = TempDir::new("gbam_test").unwrap();
+        let path = dir.path().join("in_memory.gbam");
+        let records: Vec<TestRecord> = (0..100).map(|i| TestRecord::new(i % 3, i, &format!("r{}", i))).collect();
+        write_test_file(&path, SORTED, &records);
+
+        let mut template = ParsingTemplate::new();
+        template.set_all();
+        let mut reader = Reader::from_bytes(std::fs::read(&path).unwrap(), template).unwrap();
+        reader.sequential_hint(true);
+        let mut fetched = reader.records();
+        for rec in &records {
+            let got = fetched.next_rec().unwrap().unwrap();
+            assert_eq!((got.refid, got.pos), (Some(rec.refid), Some(rec.pos)));
+        }
+        assert!(fetched.next_rec().unwrap().is_none());
+        assert!(Reader::from_bytes(b"GBAM".to_vec(), ParsingTemplate::new()).is_err());
+    }
+
+    #[test]
+    fn test_misaligned_columns() {
+        let dir = TempDir::new("gbam_test").unwrap();
+        let path = dir.path().join("misaligned.gbam");
+        let mut writer = new_test_writer(&path, SORTED);
+        writer.set_rows_per_block(1_000);
+        let records: Vec<_> = (0..2_500).map(|i| TestRecord::new(0, i, "r").to_raw()).collect();
+        writer.push_records(&records, false).unwrap();
+        writer.finish_with_summary(false).unwrap();
+
+        let mut meta = (*open_test_file(&path).file_meta).clone();
+        meta.check_record_counts().unwrap();
+        // Block lost an item, recorded total is kept.
+        meta.get_blocks(&Fields::Mapq)[1].numitems -= 1;
+        let err = meta.check_record_counts().err().unwrap();
+        assert!(err.to_string().contains("Blocks of field Mapq hold 2499 items, but meta records 2500"), "{}", err);
+
+        // Totals are recorded anew, columns still disagree.
+        let mut file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.seek(SeekFrom::End(0)).unwrap();
+        let mut file_info = FileInfo::new([1, 0], 0, 0, String::from("test"), true);
+        write_meta_and_file_info(&mut file, &mut meta, &mut file_info).unwrap();
+        let mut template = ParsingTemplate::new();
+        template.set_all();
+        let err = Reader::from_path(&path, template).err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("Field Mapq has 2499 records, but RefID has 2500"), "{}", err);
+    }
+
+    #[test]
+    fn test_index_spans() {
+        let dir = TempDir::new("gbam_test").unwrap();
+        let path = dir.path().join("big_tags.gbam");
+        // RawTags blocks are flushed every 5 records of 1.5 MB, its index
+        // keeps all records in one block.
+        let records: Vec<TestRecord> = (0..12)
+            .map(|i| {
+                let mut rec = TestRecord::new(0, i, "r");
+                let len = 1_500_000 + i as usize;
+                rec.tags = b"XBBC".to_vec();
+                rec.tags.extend_from_slice(&(len as u32).to_le_bytes());
+                rec.tags.resize(rec.tags.len() + len, i as u8);
+                rec
+            })
+            .collect();
+        write_test_file_with_limit(&path, SORTED, &records, &[Fields::RawTags]);
+
+        let mut reader = open_test_file(&path);
+        let meta = reader.file_meta.clone();
+        assert_eq!(meta.view_blocks(&Fields::RawTags).len(), 3);
+        assert_eq!(meta.view_blocks(&Fields::RawTagsLen).len(), 1);
+        let spans = meta.get_index_spans(&Fields::RawTags);
+        assert_eq!(spans.len(), 1);
+        assert_eq!((spans[0].first_record, spans[0].num_records), (0, 12));
+        assert_eq!(spans[0].data_blocks, [(0, 0), (1, 5), (2, 10)]);
+        assert_eq!(meta.get_index_spans(&Fields::ReadName)[0].data_blocks, [(0, 0)]);
+        assert!(meta.get_index_spans(&Fields::Pos).is_empty());
+
+        // Backwards, so every record crosses into another data block.
+        let mut rec = GbamRecord::default();
+        for i in (0..records.len()).rev() {
+            reader.fill_record(i, &mut rec).unwrap();
+            assert_eq!(rec.tags.as_ref().unwrap(), &records[i].tags, "record {}", i);
+        }
+
+        // Item moved between data blocks, totals still agree.
+        let mut meta = (*meta).clone();
+        meta.get_blocks(&Fields::RawTags)[0].numitems += 1;
+        meta.get_blocks(&Fields::RawTags)[1].numitems -= 1;
+        let err = meta.check_record_counts().err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("Index spans of field RawTags"), "{}", err);
+    }
+
+    #[test]
+    fn test_unsupported_version() {
+        let dir = TempDir::new("gbam_test").unwrap();
+        let path = dir.path().join("v2.gbam");
+        write_test_file(&path, SORTED, &[TestRecord::default()]);
+        let mut file_info = parse_file_info(&std::fs::read(&path).unwrap()).unwrap();
+        assert_eq!(file_info.gbam_version, GBAM_VERSION);
+        file_info.gbam_version = [2, 0];
+        let mut file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.write_all(&file_info.to_padded_bytes().unwrap()).unwrap();
+
+        let err = Reader::from_path(&path, ParsingTemplate::new()).err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::Unsupported);
+        assert!(err.to_string().contains("version 2.0 is not supported"), "{}", err);
+        let err = parse_file_info(&std::fs::read(&path).unwrap()).err().unwrap();
+        assert!(matches!(
+            gbam_error(&err),
+            Some(GbamError::Meta(MetaError::UnsupportedVersion {
+                found: [2, 0],
+                supported: GBAM_VERSION,
+            }))
+        ));
+    }
+
+    #[test]
+    fn test_newer_minor_version() {
+        let dir = TempDir::new("gbam_test").unwrap();
+        let path = dir.path().join("v1_9.gbam");
+        let records: Vec<_> = (0..10).map(|i| TestRecord::new(0, i, "r")).collect();
+        write_test_file(&path, SORTED, &records);
+        // Meta of a future build: an extension and a field unknown here.
+        let mut meta = (*open_test_file(&path).file_meta).clone();
+        meta.set_extension("future_feature", json!({"version": 3, "blocks": [1, 2]}));
+        let mut future_meta = serde_json::to_value(&meta).unwrap();
+        future_meta["future_field"] = json!([1, 2, 3]);
+        let mut meta: FileMeta = serde_json::from_value(future_meta).unwrap();
+        let mut file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.seek(SeekFrom::End(0)).unwrap();
+        let mut file_info = FileInfo::new([GBAM_VERSION[0], 9], 0, 0, String::from("test"), true);
+        write_meta_and_file_info(&mut file, &mut meta, &mut file_info).unwrap();
+
+        let mut reader = open_test_file(&path);
+        assert_eq!(reader.num_records(), 10);
+        assert_eq!(reader.records().next_rec().unwrap().unwrap().pos, Some(0));
+        let meta = &reader.file_meta;
+        assert_eq!(meta.extension_names().collect::<Vec<_>>(), ["future_feature"]);
+        assert_eq!(meta.get_extension("future_feature").unwrap()["blocks"], json!([1, 2]));
+        // Unknown extensions are kept when meta is written again.
+        let json = serde_json::to_value(&**meta).unwrap();
+        assert!(json["extensions"]["future_feature"].is_object());
+    }
+
+    #[test]
+    fn test_sequential_hint() {
+        let dir = TempDir::new("gbam_test").unwrap();
+        let path = dir.path().join("scan.gbam");
+        let mut writer = new_test_writer(&path, SORTED);
+        writer.set_rows_per_block(5_000);
+        // One batch, so blocks of each column are mostly written one after another.
+        let records: Vec<_> = (0..200_000).map(|i| TestRecord::new(0, i, "r").to_raw()).collect();
+        writer.push_records(&records, false).unwrap();
+        writer.finish_with_summary(false).unwrap();
+
+        let scan = |hint: bool| {
+            let template = ParsingTemplate::new_with(&[Fields::RefID, Fields::Pos]);
+            let mut reader = Reader::new(File::open(&path).unwrap(), template).unwrap();
+            reader.sequential_hint(hint);
+            let mut records = reader.records();
+            let mut n = 0;
+            while let Some(rec) = records.next_rec().unwrap() {
+                assert_eq!(rec.pos, Some(n));
+                n += 1;
+            }
+            assert_eq!(n, 200_000);
+            reader.read_stats()
+        };
+        let plain = scan(false);
+        let hinted = scan(true);
+        assert_eq!(plain.read_calls, 80);
+        assert_eq!(hinted.bytes_read, plain.bytes_read);
+        assert!(hinted.read_calls * 4 <= plain.read_calls, "{:?}", hinted);
+    }
+
+    #[test]
+    fn test_estimated_record_size() {
+        let dir = TempDir::new("gbam_test").unwrap();
+        for (name, read_len) in [("short", 50), ("long", 10_000)] {
+            let path = dir.path().join(format!("{}.gbam", name));
+            let records: Vec<TestRecord> = (0..200)
+                .map(|i| {
+                    let mut rec = TestRecord::new(0, i, &format!("read{}", i));
+                    rec.seq = "ACGT".repeat(read_len / 4);
+                    rec.qual = vec![30; read_len];
+                    rec.cigar = vec![(read_len as u32) << 4];
+                    rec
+                })
+                .collect();
+            write_test_file(&path, SORTED, &records);
+
+            let mut reader = open_test_file(&path);
+            // BAM records, with block_size.
+            let bam_size =
+                records.iter().map(|rec| rec.to_bytes().len() + 4).sum::<usize>() / records.len();
+            let estimate = reader.estimated_record_size();
+            assert!(estimate.abs_diff(bam_size) * 10 < bam_size, "{} vs {}", estimate, bam_size);
+
+            let mut fetched = reader.records();
+            let rec = fetched.next_rec().unwrap().unwrap();
+            // Sequence is decoded into a char per base.
+            let parsed = rec.read_name.as_ref().unwrap().len() + 4 + 2 * read_len;
+            assert!(rec.heap_size() >= parsed && rec.heap_size() < 2 * parsed);
+        }
+    }
+
+    #[test]
+    fn test_unmapped_tail() {
+        let dir = TempDir::new("gbam_test").unwrap();
+        let mut records = Vec::new();
+        for ref_id in 0..2 {
+            for pos in (0..2_500).step_by(10) {
+                records.push(TestRecord::new(ref_id, pos, &format!("r{}_{}", ref_id, pos)));
+            }
+        }
+        for i in 0..1_000 {
+            let mut unmapped = TestRecord::new(-1, -1, &format!("u{}", i));
+            unmapped.flag = 4;
+            unmapped.cigar.clear();
+            records.push(unmapped);
+        }
+        let path = dir.path().join("tail.gbam");
+        let mut writer = new_test_writer(&path, SORTED);
+        writer.set_rows_per_block(100);
+        for rec in &records {
+            writer.push_record(&rec.to_raw(), false).unwrap();
+        }
+        writer.finish_with_summary(false).unwrap();
+
+        let mut reader = open_test_file(&path);
+        assert_eq!(reader.unmapped_tail_start(), Some(500));
+        let mut mapped = reader.mapped_records().unwrap();
+        let mut n = 0;
+        while mapped.next_rec().unwrap().is_some() {
+            n += 1;
+        }
+        assert_eq!(n, 500);
+        assert_eq!(reader.blocks_decompressed(&Fields::RefID), 5);
+
+        let mut reader = open_test_file(&path);
+        let mut tail = reader.unmapped_tail().unwrap();
+        let mut names = Vec::new();
+        while let Some(rec) = tail.next_rec().unwrap() {
+            assert_eq!(rec.refid, Some(-1));
+            names.push(rec.read_name.clone().unwrap());
+        }
+        assert_eq!(names.len(), 1_000);
+        assert_eq!(names[0], b"u0\0");
+        assert_eq!(reader.blocks_decompressed(&Fields::RefID), 10);
+
+        // Fetch stops at the tail.
+        let mut reader = open_test_file(&path);
+        let mut fetched = reader.fetch(&Region::new(1, 0, i32::MAX)).unwrap();
+        let mut n = 0;
+        while fetched.next_rec().unwrap().is_some() {
+            n += 1;
+        }
+        assert_eq!(n, 250);
+        assert!(reader.blocks_decompressed(&Fields::RefID) <= 5);
+
+        // Without RefID stats the tail is unknown.
+        let plain = dir.path().join("plain.gbam");
+        let ref_seqs = test_ref_seqs();
+        let settings = WriterSettings {
+            collect_stats_for: Vec::new(),
+            sam_header: sam_header_bytes(SORTED, &ref_seqs),
+            ref_seqs,
+            ..Default::default()
+        };
+        let mut writer = Writer::create(&plain, settings).unwrap();
+        writer.push_record(&records[600].to_raw(), false).unwrap();
+        writer.finish_with_summary(false).unwrap();
+        let mut reader = open_test_file(&plain);
+        assert_eq!(reader.unmapped_tail_start(), None);
+        assert_eq!(reader.unmapped_tail().err().unwrap().kind(), std::io::ErrorKind::InvalidInput);
+
+        // File without trailing unplaced reads has an empty tail, one amid
+        // placed reads is not part of it.
+        records[100] = TestRecord::new(-1, -1, "early");
+        write_test_file(&plain, "", &records[..500]);
+        assert_eq!(open_test_file(&plain).unmapped_tail_start(), Some(500));
+    }
+
+    #[test]
+    fn test_buffer_release() {
+        let dir = TempDir::new("gbam_test").unwrap();
+        let path = dir.path().join("buffers.gbam");
+        let records: Vec<TestRecord> = (0..5_000)
+            .map(|i| TestRecord {
+                tags: format!("XAZ{}{}\0", "t".repeat(200), i).into_bytes(),
+                ..TestRecord::new(0, i, &format!("r{}", i))
+            })
+            .collect();
+        let mut writer = new_test_writer(&path, SORTED);
+        writer.set_rows_per_block(1_000);
+        for rec in &records {
+            writer.push_record(&rec.to_raw(), false).unwrap();
+        }
+        writer.finish_with_summary(false).unwrap();
+
+        let scan = |reader: &mut Reader, field: Fields| {
+            reader.fetch_only(&[field]).unwrap();
+            let mut recs = reader.records();
+            let mut n = 0;
+            while let Some(rec) = recs.next_rec().unwrap() {
+                if field == Fields::RawTags {
+                    assert_eq!(rec.tags.as_ref(), Some(&records[n].tags));
+                }
+                n += 1;
+            }
+            assert_eq!(n, records.len());
+            reader.restore_template();
+        };
+        let bytes_of = |reader: &Reader, field: Fields| {
+            let usage = reader.memory_usage();
+            usage.columns.iter().find(|(column, _)| *column == field).unwrap().1
+        };
+
+        let mut reader = open_test_file(&path);
+        reader.set_block_cache_bytes(64 << 20);
+        scan(&mut reader, Fields::RawTags);
+        let tag_heavy = reader.memory_usage();
+        assert!(bytes_of(&reader, Fields::RawTags) > 200_000);
+        assert!(tag_heavy.block_cache > 0);
+        scan(&mut reader, Fields::Pos);
+        assert!(reader.memory_usage().total() > tag_heavy.total());
+
+        reader.reset_buffers();
+        assert_eq!(reader.memory_usage().total(), 0);
+        scan(&mut reader, Fields::Pos);
+        assert!(bytes_of(&reader, Fields::Pos) > 0);
+        assert_eq!(bytes_of(&reader, Fields::RawTags), 0);
+        assert!(reader.memory_usage().total() < tag_heavy.total());
+
+        // Tags are dropped after two position-only queries in a row.
+        let mut reader = open_test_file(&path);
+        reader.set_buffer_ttl(Some(2));
+        for _ in 0..3 {
+            scan(&mut reader, Fields::RawTags);
+            let tag_heavy = reader.memory_usage().total();
+            scan(&mut reader, Fields::Pos);
+            scan(&mut reader, Fields::Pos);
+            assert!(bytes_of(&reader, Fields::RawTags) > 0);
+            scan(&mut reader, Fields::Pos);
+            assert_eq!(bytes_of(&reader, Fields::RawTags), 0);
+            assert_eq!(bytes_of(&reader, Fields::RawTagsLen), 0);
+            assert!(bytes_of(&reader, Fields::Pos) > 0);
+            assert!(reader.memory_usage().total() < tag_heavy);
+        }
+    }
+
+    #[test]
+    fn test_meta_limits() {
+        let nested = |depth: usize| "[".repeat(depth) + &"]".repeat(depth);
+        assert!(check_meta_limits(nested(4).as_bytes(), 100, 4).is_ok());
+        let err = check_meta_limits(nested(5).as_bytes(), 100, 4).err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        // Brackets in strings don't nest.
+        assert!(check_meta_limits(br#"{"a": "[[\"[[", "b": []}"#, 100, 2).is_ok());
+        assert!(check_meta_limits(&[b' '; 101], 100, 4).is_err());
+        let err = parse_meta_json(nested(MAX_META_DEPTH + 1).as_bytes()).err().unwrap();
+        assert!(err.to_string().contains("nests deeper"), "{}", err);
+
+        let mut file_info = FileInfo::new(GBAM_VERSION, 0, 0, String::from("test"), false);
+        file_info.reserved_meta = MAX_META_SIZE + 1;
+        let err = parse_file_info(&file_info.to_padded_bytes().unwrap()).err().unwrap();
+        assert!(err.to_string().contains("reserves"), "{}", err);
+        file_info.reserved_meta = u64::MAX;
+        assert_eq!(file_info.data_range(), u64::MAX..u64::MAX);
+    }
+
+    #[test]
+    fn test_meta_sizes() {
+        let dir = TempDir::new("gbam_test").unwrap();
+        let path = dir.path().join("sizes.gbam");
+        write_test_file(&path, SORTED, &[TestRecord::default()]);
+        let meta = (*open_test_file(&path).file_meta).clone();
+        let parse = |meta: &serde_json::Value| parse_meta_json(&serde_json::to_vec(meta).unwrap());
+        let json = serde_json::to_value(&meta).unwrap();
+        assert!(parse(&json).is_ok());
+
+        // Buffers are sized by claimed sizes, so claims are bounded by block sizes.
+        let mut oversized = meta.clone();
+        oversized.get_blocks(&Fields::RawQual)[0].uncompressed_size = 1 << 40;
+        let err = parse(&serde_json::to_value(&oversized).unwrap()).err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert!(matches!(
+            gbam_error(&err),
+            Some(GbamError::Meta(MetaError::OversizedBlock { field: Fields::RawQual, block: 0, .. }))
+        ));
+        let mut stored = meta.clone();
+        let block = &mut stored.get_blocks(&Fields::Pos)[0];
+        block.stored = true;
+        block.uncompressed_size = u64::from(block.block_size) + 1;
+        assert!(parse(&serde_json::to_value(&stored).unwrap()).is_err());
+
+        let mut missing = json.clone();
+        missing["field_to_meta"].as_object_mut().unwrap().remove("Pos");
+        let err = parse(&missing).err().unwrap();
+        assert!(err.to_string().contains("missing meta of field Pos"), "{}", err);
+        let mut item_size = json;
+        item_size["field_to_meta"]["Pos"]["item_size"] = json!(1);
+        let err = parse(&item_size).err().unwrap();
+        assert!(err.to_string().contains("item size"), "{}", err);
+    }
+
+    #[test]
+    fn test_claimed_size_cap() {
+        let dir = TempDir::new("gbam_test").unwrap();
+        let path = dir.path().join("claims.gbam");
+        write_test_file(&path, SORTED, &[TestRecord::default()]);
+        let meta = (*open_test_file(&path).file_meta).clone();
+        let cap = (SIZE_LIMIT + MAX_RECORD_SIZE) as u64;
+
+        // A tiny block of a codec with a huge ratio can't claim more than
+        // the largest block written.
+        let tiny = BlockMeta { block_size: 4096, ..Default::default() };
+        assert_eq!(tiny.max_uncompressed_size(Codecs::Brotli, SIZE_LIMIT), cap);
+        assert_eq!(tiny.max_uncompressed_size(Codecs::Gzip, SIZE_LIMIT), SIZE_LIMIT as u64);
+        let mut claims = meta;
+        claims.set_field_codec(&Fields::RawQual, Codecs::Brotli);
+        let field_cap = (claims.get_block_size_limit(&Fields::RawQual) + MAX_RECORD_SIZE) as u64;
+        let block = &mut claims.get_blocks(&Fields::RawQual)[0];
+        block.stored = false;
+        block.block_size = 4096;
+        block.uncompressed_size = field_cap + 1;
+        let err = parse_meta_json(&serde_json::to_vec(&claims).unwrap()).err().unwrap();
+        match gbam_error(&err) {
+            Some(GbamError::Meta(MetaError::OversizedBlock { limit, .. })) => assert_eq!(*limit, field_cap),
+            other => panic!("{:?}", other),
+        }
+
+        // Claims within the cap aren't allocated up front.
+        let data = vec![7; 1000];
+        let block = compress(&data, Vec::new(), Codecs::Gzip).unwrap();
+        let mut dest = Vec::new();
+        assert!(decompress_into(&block, &mut dest, &Codecs::Gzip, cap as usize).is_err());
+        assert!(dest.capacity() < 1 << 20, "{}", dest.capacity());
+    }
+
+    #[test]
+    fn test_decompress_bounds() {
+        let data: Vec<u8> = (0..10_000).map(|i| (i % 7) as u8).collect();
+        for codec in [Codecs::Gzip, Codecs::Lz4, Codecs::NoCompression] {
+            let block = compress(&data, Vec::new(), codec).unwrap();
+            let mut dest = vec![0; data.len()];
+            decompress_block(&block, &mut dest, &codec).unwrap();
+            assert_eq!(dest, data);
+            // Meta claiming other sizes fails instead of growing buffers.
+            for len in [0, data.len() - 1, data.len() + 1] {
+                let mut dest = vec![0; len];
+                let err = decompress_block(&block, &mut dest, &codec).err().unwrap();
+                assert_eq!(err.kind(), std::io::ErrorKind::InvalidData, "{:?}", codec);
+                assert!(dest.len() <= len + 1 || codec == Codecs::NoCompression);
+            }
+        }
+    }
+
+    /// Writes 100 records, blocks stay uncompressed so tests can damage them.
+    fn write_plain_file(path: &Path) {
+        let ref_seqs = test_ref_seqs();
+        let settings = WriterSettings {
+            codecs: vec![Codecs::NoCompression; bam_tools::record::fields::FIELDS_NUM],
+            sam_header: sam_header_bytes("", &ref_seqs),
+            ref_seqs,
+            full_command: String::from("test"),
+            ..Default::default()
+        };
+        let mut writer = Writer::create(path, settings).unwrap();
+        for i in 0..100 {
+            writer.push_record(&TestRecord::new(0, i, &format!("r{}", i)).to_raw(), false).unwrap();
+        }
+        writer.finish_with_summary(false).unwrap();
+    }
+
+    #[test]
+    fn test_fallible_reads() {
+        let dir = TempDir::new("gbam_test").unwrap();
+        let path = dir.path().join("plain.gbam");
+        write_plain_file(&path);
+
+        let template = ParsingTemplate::new_with(&[Fields::Pos, Fields::ReadName]);
+        let mut reader = Reader::from_path(&path, template.clone()).unwrap();
+        let mut rec = GbamRecord::default();
+        let err = reader.fill_record(100, &mut rec).err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        assert!(matches!(
+            err,
+            GbamError::Template(TemplateError::RecordOutOfRange { rec_num: 100, records: 100 })
+        ));
+
+        let err = reader.fetch_only(&[Fields::Flags]).err().unwrap();
+        assert!(matches!(
+            err,
+            GbamError::Template(TemplateError::FieldNotOpened { field: Fields::Flags })
+        ));
+        let err = reader.fetch_only(&[Fields::LName]).err().unwrap();
+        assert!(matches!(err, GbamError::Template(TemplateError::IndexField { field: Fields::LName })));
+        reader.fetch_only(&[Fields::Pos]).unwrap();
+        reader.fill_record(99, &mut rec).unwrap();
+        assert_eq!(rec.pos, Some(99));
+
+        // The last offset of the name index points past the names block.
+        let meta = reader.file_meta.clone();
+        let block = &meta.view_blocks(&Fields::LName)[0];
+        let end = (block.seekpos + u64::from(block.block_size)) as usize;
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes[end - 4..end].copy_from_slice(&u32::MAX.to_le_bytes());
+        let mut reader = Reader::from_bytes(bytes, template).unwrap();
+        let mut records = reader.records();
+        for _ in 0..99 {
+            records.next_rec().unwrap().unwrap();
+        }
+        let err = records.next_rec().err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        match err {
+            GbamError::Corruption(CorruptionError::UnreadableBlock { field, block, .. }) => {
+                assert_eq!((field, block), (Fields::ReadName, 0));
+            }
+            other => panic!("Unexpected error {:?}", other),
+        }
+        assert!(records.next_rec().unwrap().is_none());
+    }
+
+    /// File in memory whose reads at `failing` offset fail, like a dropped
+    /// connection.
+    struct FailingSource {
+        bytes: Vec<u8>,
+        failing: u64,
+    }
+
+    impl ReadBlockAt for FailingSource {
+        fn read_at(&self, offset: u64, len: usize) -> std::io::Result<Vec<u8>> {
+            if offset == self.failing {
+                return Err(std::io::ErrorKind::ConnectionReset.into());
+            }
+            Ok(self.bytes[offset as usize..offset as usize + len].to_vec())
+        }
+
+        fn size(&self) -> std::io::Result<u64> {
+            Ok(self.bytes.len() as u64)
+        }
+    }
+
+    #[test]
+    fn test_error_variants() {
+        let dir = TempDir::new("gbam_test").unwrap();
+        let path = dir.path().join("plain.gbam");
+        write_plain_file(&path);
+        let bytes = std::fs::read(&path).unwrap();
+        let meta = (*open_test_file(&path).file_meta).clone();
+        let template = ParsingTemplate::new_with(&[Fields::Pos, Fields::ReadName, Fields::RawQual]);
+        let mut rec = GbamRecord::default();
+
+        // Source fails once the file is open.
+        let source = FailingSource {
+            bytes: bytes.clone(),
+            failing: meta.view_blocks(&Fields::Pos)[0].seekpos,
+        };
+        let mut reader = Reader::from_source(source, template.clone()).unwrap();
+        match reader.fill_record(0, &mut rec).err().unwrap() {
+            GbamError::Io(err) => assert_eq!(err.kind(), std::io::ErrorKind::ConnectionReset),
+            other => panic!("{:?}", other),
+        }
+
+        let mut file_info = parse_file_info(&bytes).unwrap();
+        file_info.gbam_version = [GBAM_VERSION[0] + 1, 0];
+        let mut newer = bytes.clone();
+        newer[..FILE_INFO_SIZE].copy_from_slice(&file_info.to_padded_bytes().unwrap());
+        let err = Reader::from_bytes(newer, template.clone()).err().unwrap();
+        assert!(matches!(err, GbamError::Meta(MetaError::UnsupportedVersion { .. })), "{:?}", err);
+
+        // Meta claims Gzip for uncompressed qualities.
+        let mut claims = meta.clone();
+        claims.set_field_codec(&Fields::RawQual, Codecs::Gzip);
+        claims.get_blocks(&Fields::RawQual)[0].stored = false;
+        let mut file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.seek(SeekFrom::End(0)).unwrap();
+        let mut file_info = FileInfo::new([1, 0], 0, 0, String::from("test"), false);
+        write_meta_and_file_info(&mut file, &mut claims, &mut file_info).unwrap();
+        let mut reader = Reader::from_path(&path, template.clone()).unwrap();
+        let err = reader.fill_record(0, &mut rec).err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert!(
+            matches!(
+                err,
+                GbamError::Codec(CodecError::DecompressionFailed { field: Fields::RawQual, block: 0, .. })
+            ),
+            "{:?}",
+            err
+        );
+
+        let mut reader = Reader::from_bytes(bytes.clone(), template.clone()).unwrap();
+        let err = reader.get_column(&Fields::Flags).err().unwrap();
+        assert!(matches!(err, GbamError::Template(TemplateError::FieldNotOpened { field: Fields::Flags })));
+
+        // The first offset of the name index points past the names block.
+        let block = &meta.view_blocks(&Fields::LName)[0];
+        let start = block.seekpos as usize;
+        let mut damaged = bytes;
+        damaged[start..start + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        let mut reader = Reader::from_bytes(damaged, template).unwrap();
+        let err = reader.fill_record(0, &mut rec).err().unwrap();
+        assert!(
+            matches!(
+                err,
+                GbamError::Corruption(CorruptionError::UnreadableBlock { field: Fields::ReadName, .. })
+            ),
+            "{:?}",
+            err
+        );
+    }
+}
@@ -3,18 +3,18 @@ use std::io::Write;
 use itertools::Itertools;
 use serde::{Deserialize, Serialize};
 
-use bam_tools::record::{
-    bamrawrecord::{decode_seq, put_sequence},
-    fields::Fields,
-};
+use bam_tools::record::fields::Fields;
 
+use crate::bam::index::{reg2bin, BAI_DEPTH, BAI_MIN_SHIFT};
 use crate::query::cigar::base_coverage;
+use crate::reader::typed::{ColumnDecoder, FlagsColumn, MapqColumn, PosColumn, RefIdColumn};
+use crate::utils::seq::{decode_seq, encode_bases};
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use std::mem;
 
 use crate::{query::cigar::Cigar, query::cigar::Op, U32_SIZE};
 
-#[derive(Debug, Default, Serialize, Deserialize)]
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
 /// Represents a GBAM record in which some fields may be omitted.
 pub struct GbamRecord {
     /// Reference sequence ID
@@ -43,6 +43,9 @@ pub struct GbamRecord {
     pub qual: Option<Vec<u8>>,
     /// List of auxiliary data
     pub tags: Option<Vec<u8>>,
+    /// Ordinal of the record where it was first written, see `source_index()`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) source_index: Option<u64>,
 }
 
 pub fn parse_cigar(bytes: &[u8], prealloc: &mut Cigar) {
@@ -55,13 +58,21 @@ pub fn parse_cigar(bytes: &[u8], prealloc: &mut Cigar) {
 // TODO :: ADD TEMPLATE LENGTHS TO GBAM RECORD
 // TODO :: REMOVE CG TAG FROM ORIGINAL FILE
 impl GbamRecord {
+    /// Position of the record among records pushed to the writer which
+    /// first wrote it, kept through sorting, merging and extraction. Set
+    /// for records of files written with `Writer::set_source_index()`.
+    pub fn source_index(&self) -> Option<u64> {
+        self.source_index
+    }
+
     pub(crate) fn parse_from_bytes(&mut self, field: &Fields, mut bytes: &[u8]) {
         match field {
-            Fields::RefID => self.refid = Some(bytes.read_i32::<LittleEndian>().unwrap()),
-            Fields::Pos => self.pos = Some(bytes.read_i32::<LittleEndian>().unwrap()),
-            Fields::Mapq => self.mapq = Some(bytes[0].to_owned()),
+            // Raw values, as in BAM, see `typed` for sentinels.
+            Fields::RefID => self.refid = Some(RefIdColumn::raw(bytes)),
+            Fields::Pos => self.pos = Some(PosColumn::raw(bytes)),
+            Fields::Mapq => self.mapq = Some(MapqColumn::raw(bytes)),
             Fields::Bin => self.bin = Some(bytes.read_u16::<LittleEndian>().unwrap()),
-            Fields::Flags => self.flag = Some(bytes.read_u16::<LittleEndian>().unwrap()),
+            Fields::Flags => self.flag = Some(FlagsColumn::raw(bytes)),
             Fields::NextRefID => self.next_ref_id = Some(bytes.read_i32::<LittleEndian>().unwrap()),
             Fields::NextPos => self.next_pos = Some(bytes.read_i32::<LittleEndian>().unwrap()),
             Fields::TemplateLength => self.tlen = Some(bytes.read_i32::<LittleEndian>().unwrap()),
@@ -76,6 +87,20 @@ impl GbamRecord {
         }
     }
 
+    /// Bytes allocated on heap by parsed fields, for budgeting of buffered
+    /// records. Add `mem::size_of::<GbamRecord>()` for the total footprint.
+    pub fn heap_size(&self) -> usize {
+        let vec_size = |v: &Option<Vec<u8>>| v.as_ref().map_or(0, Vec::capacity);
+        vec_size(&self.read_name)
+            + vec_size(&self.qual)
+            + vec_size(&self.tags)
+            + self.seq.as_ref().map_or(0, String::capacity)
+            + self
+                .cigar
+                .as_ref()
+                .map_or(0, |cigar| cigar.0.capacity() * mem::size_of::<Op>())
+    }
+
     /// Only support full records. Do not call if the GBAM record is not fully filled.
     ///
     /// Layout:
@@ -100,7 +125,20 @@ impl GbamRecord {
     /// tag                              char[2]
     /// val_type                         char
     /// tag_value                        by_val_type
+    ///
+    /// Bin is written as stored, see `convert_to_bam_bytes()` for export.
     pub fn convert_to_bytes(&self, bytes: &mut Vec<u8>) {
+        self.write_bytes(bytes, self.bin.unwrap());
+    }
+
+    /// Like `convert_to_bytes()`, with bin recomputed by `computed_bin()`
+    /// instead of the stored one, which may be stale or missing. Used where
+    /// BAM files are written, so validators accept them.
+    pub fn convert_to_bam_bytes(&self, bytes: &mut Vec<u8>) {
+        self.write_bytes(bytes, self.computed_bin());
+    }
+
+    fn write_bytes(&self, bytes: &mut Vec<u8>, bin: u16) {
         let n_byte = mem::size_of::<u32>()
             + mem::size_of::<i32>() * 2
             + mem::size_of::<u8>() * 2
@@ -109,7 +147,7 @@ impl GbamRecord {
             + mem::size_of::<i32>() * 3
             + self.cigar.as_ref().unwrap().0.len() * mem::size_of::<u32>()
             + self.read_name.as_ref().unwrap().len()
-            + self.seq.as_ref().unwrap_or(&String::new()).len().div_ceil(2)
+            + self.qual.as_ref().unwrap_or(&Vec::new()).len().div_ceil(2)
             + self.qual.as_ref().unwrap_or(&Vec::new()).len()
             + self.tags.as_ref().unwrap().len();
 
@@ -129,7 +167,7 @@ impl GbamRecord {
             .unwrap();
         (&mut bytes[13..14]).write_u8(self.mapq.unwrap()).unwrap();
         (&mut bytes[14..16])
-            .write_u16::<LittleEndian>(self.bin.unwrap())
+            .write_u16::<LittleEndian>(bin)
             .unwrap();
         (&mut bytes[16..18])
             .write_u16::<LittleEndian>(self.cigar.as_ref().unwrap().0.len() as u16)
@@ -164,14 +202,13 @@ impl GbamRecord {
             .ops()
             .zip_eq(cigar.chunks_mut(mem::size_of::<u32>()))
             .for_each(|(op, mut buf)| buf.write_u32::<LittleEndian>(op.0).unwrap());
-        let seq_len = self.seq.as_ref().unwrap_or(&String::new()).len().div_ceil(2);
+        // Decoded sequence may lack trailing '=' base or carry nonzero
+        // padding, so its length is taken from qualities, which are always
+        // l_seq long.
+        let seq_len = self.qual.as_ref().unwrap_or(&Vec::new()).len().div_ceil(2);
         let (seq, unsized_data) = unsized_data.split_at_mut(seq_len);
-        put_sequence(
-            seq,
-            self.seq.as_ref().unwrap_or(&String::new()).len(),
-            self.seq.as_ref().unwrap_or(&String::new()),
-        )
-        .unwrap();
+        let no_bases = String::new();
+        encode_bases(self.seq.as_ref().unwrap_or(&no_bases).as_bytes(), seq);
         let (mut qual, mut unsized_data) =
             unsized_data.split_at_mut(self.qual.as_ref().unwrap_or(&Vec::new()).len());
         qual.write_all(self.qual.as_ref().unwrap_or(&Vec::new()))
@@ -194,6 +231,15 @@ impl GbamRecord {
         base_coverage(&self.cigar.as_ref().unwrap().0[..])
     }
 
+    /// BAI bin of the record, `reg2bin()` over `[pos, pos + span)`, as htslib
+    /// computes it. Unmapped reads and ones without reference bases span one
+    /// base, so unplaced reads (pos -1) get bin 4680.
+    pub fn computed_bin(&self) -> u16 {
+        let span = if self.is_unmapped() { 1 } else { self.alignment_span().max(1) };
+        let beg = i64::from(self.pos.unwrap());
+        reg2bin(beg, beg + i64::from(span), BAI_MIN_SHIFT, BAI_DEPTH) as u16
+    }
+
     /// Returns the alignment start.
     pub fn alignment_start(&self) -> Option<u32> {
         Option::from(self.pos.unwrap() as u32)
@@ -227,3 +273,77 @@ impl std::fmt::Display for GbamRecord {
         std::fmt::Debug::fmt(self, f)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::sam_export::to_sam_string;
+    use crate::test_utils::{new_test_writer, open_test_file, TestRecord};
+    use bam_tools::record::bamrawrecord::BAMRawRecord;
+    use tempdir::TempDir;
+
+    fn with_seq(name: &str, flag: u16, seq: &str, qual: Vec<u8>) -> TestRecord {
+        let mut rec = TestRecord::new(0, 100, name);
+        rec.flag = flag;
+        rec.cigar = if flag & 4 == 0 { vec![5 << 4] } else { Vec::new() };
+        rec.seq = seq.to_owned();
+        rec.qual = qual;
+        rec
+    }
+
+    // Bytes as written by htslib, with zero padding of odd length sequences.
+    fn bam_bytes(rec: &TestRecord) -> Vec<u8> {
+        let mut bytes = rec.to_bytes();
+        if rec.seq.len() % 2 == 1 {
+            let padding = 32 + rec.name.len() + 1 + 4 * rec.cigar.len() + rec.seq.len() / 2;
+            bytes[padding] &= 0xf0;
+        }
+        let mut prefixed = (bytes.len() as u32).to_le_bytes().to_vec();
+        prefixed.extend_from_slice(&bytes);
+        prefixed
+    }
+
+    #[test]
+    fn test_missing_seq_and_qual_round_trip() {
+        let records = [
+            with_seq("primary", 0, "ACGTA", vec![30, 31, 32, 33, 34]),
+            with_seq("secondary", 0x100, "", Vec::new()),
+            with_seq("no_qual", 4, "ACGTN", vec![0xff; 5]),
+            with_seq("unmapped_empty", 4, "", Vec::new()),
+            with_seq("eq_inside", 0, "A=C=G", vec![20; 5]),
+            with_seq("eq_last", 0, "ACG=", vec![20; 4]),
+        ];
+        let expected_sam = [
+            "ACGTA\t?@ABC",
+            "*\t*",
+            "ACGTN\t*",
+            "*\t*",
+            "A=C=G\t55555",
+            "ACG=\t5555",
+        ];
+        let dir = TempDir::new("gbam_record").unwrap();
+        for seq_packing in [false, true] {
+            let path = dir.path().join(format!("missing_{}.gbam", seq_packing));
+            let mut writer = new_test_writer(&path, "");
+            writer.set_seq_packing(seq_packing);
+            for rec in &records {
+                let raw = BAMRawRecord::from(bam_bytes(rec)[4..].to_vec());
+                writer.push_record(&raw, false).unwrap();
+            }
+            writer.finish_with_summary(false).unwrap();
+
+            let mut reader = open_test_file(&path);
+            let meta = reader.file_meta.clone();
+            let mut fetched = reader.records();
+            let mut bytes = Vec::new();
+            for (rec, sam) in records.iter().zip(expected_sam.iter()) {
+                let gbam_rec = fetched.next_rec().unwrap().unwrap();
+                gbam_rec.convert_to_bytes(&mut bytes);
+                assert_eq!(bytes, bam_bytes(rec), "{}", rec.name);
+                let line = to_sam_string(gbam_rec, &meta).unwrap();
+                let columns: Vec<&str> = line.trim_end().split('\t').collect();
+                assert_eq!(columns[9..11].join("\t"), *sam, "{}", rec.name);
+            }
+            assert!(fetched.next_rec().unwrap().is_none());
+        }
+    }
+}
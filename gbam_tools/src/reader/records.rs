@@ -1,3 +1,9 @@
+use std::ops::Range;
+
+use bam_tools::record::fields::Fields;
+
+use crate::error::{Result, TemplateError};
+
 use super::{reader::Reader, record::GbamRecord};
 
 /// Iterates over GBAM file.
@@ -6,24 +12,302 @@ pub struct Records<'a> {
     cur_rec: usize,
     rec_amount: usize,
     buf: GbamRecord,
+    // Required and excluded flags, see `filter_flags()`.
+    flags: Option<(u16, u16)>,
+    // Fields read after flags match.
+    other_fields: Vec<Fields>,
 }
 
 impl<'a> Records<'a> {
     pub fn new(reader: &'a mut Reader) -> Self {
+        let amount = reader.amount;
+        Self::new_range(reader, 0..amount)
+    }
+
+    /// Iterates over records of `range`, which has to be within the file.
+    pub(crate) fn new_range(reader: &'a mut Reader, range: Range<usize>) -> Self {
+        debug_assert!(range.end <= reader.amount);
+        reader.start_query();
         Self {
-            rec_amount: reader.amount,
             reader,
-            cur_rec: 0,
+            cur_rec: range.start,
+            rec_amount: range.end,
             buf: GbamRecord::default(),
+            flags: None,
+            other_fields: Vec::new(),
+        }
+    }
+
+    /// Skips records which lack any of `required` flags or have any of
+    /// `excluded` ones, like `samtools view -f required -F excluded`. Other
+    /// fields are only read for records passing the filter. Flags must be
+    /// enabled in parsing template.
+    pub fn filter_flags(mut self, required: u16, excluded: u16) -> Result<Self> {
+        if !self.reader.parsing_template.check_if_active(&[Fields::Flags]) {
+            return Err(TemplateError::FieldNotOpened { field: Fields::Flags }.into());
+        }
+        self.other_fields = self
+            .reader
+            .parsing_template
+            .get_active_data_fields_iter()
+            .filter(|&&field| field != Fields::Flags)
+            .copied()
+            .collect();
+        self.flags = Some((required, excluded));
+        Ok(self)
+    }
+
+    /// Next record, None past the last one. Fails with errors of
+    /// `Reader::fill_record()`, iteration may go on past failed records.
+    pub fn next_rec(&mut self) -> Result<Option<&GbamRecord>> {
+        let (required, excluded) = match self.flags {
+            Some(flags) => flags,
+            None => {
+                if self.cur_rec == self.rec_amount {
+                    return Ok(None);
+                }
+                let rec_num = self.cur_rec;
+                self.cur_rec += 1;
+                self.reader.fill_record(rec_num, &mut self.buf)?;
+                return Ok(Some(&self.buf));
+            }
+        };
+        while self.cur_rec < self.rec_amount {
+            let rec_num = self.cur_rec;
+            self.cur_rec += 1;
+            if self.read_if_flags_match(rec_num, required, excluded)? {
+                return Ok(Some(&self.buf));
+            }
+        }
+        Ok(None)
+    }
+
+    // Reads flags of record `rec_num`, and other fields if they match.
+    fn read_if_flags_match(&mut self, rec_num: usize, required: u16, excluded: u16) -> Result<bool> {
+        let rec_num = self.reader.physical_rec_num(rec_num)?;
+        self.reader
+            .get_column(&Fields::Flags)?
+            .fill_record_field(rec_num, &mut self.buf)?;
+        let flag = self.buf.flag.unwrap();
+        if flag & required != required || flag & excluded != 0 {
+            return Ok(false);
+        }
+        for field in &self.other_fields {
+            self.reader
+                .get_column(field)?
+                .fill_record_field(rec_num, &mut self.buf)?;
+        }
+        self.reader.fill_source_index(rec_num, &mut self.buf)?;
+        Ok(true)
+    }
+}
+
+/// Iterates over groups of adjacent records sharing a read name. Created by
+/// [`Reader::records_by_name`].
+pub struct NameGroups<'a> {
+    records: Records<'a>,
+    // First record of the next group, read while looking for the end of the current one.
+    pending: Option<GbamRecord>,
+}
+
+impl<'a> NameGroups<'a> {
+    pub fn new(records: Records<'a>) -> Self {
+        Self {
+            records,
+            pending: None,
+        }
+    }
+}
+
+impl<'a> NameGroups<'a> {
+    fn next_group(&mut self) -> Result<Option<Vec<GbamRecord>>> {
+        let first = match self.pending.take() {
+            Some(rec) => rec,
+            None => match self.records.next_rec()? {
+                Some(rec) => rec.clone(),
+                None => return Ok(None),
+            },
+        };
+        let mut group = vec![first];
+        while let Some(rec) = self.records.next_rec()? {
+            if rec.read_name != group[0].read_name {
+                self.pending = Some(rec.clone());
+                break;
+            }
+            group.push(rec.clone());
+        }
+        Ok(Some(group))
+    }
+}
+
+impl<'a> Iterator for NameGroups<'a> {
+    type Item = Result<Vec<GbamRecord>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_group().transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Records;
+    use crate::error::{GbamError, TemplateError};
+    use crate::reader::{parse_tmplt::ParsingTemplate, reader::Reader};
+    use crate::test_utils::{open_test_file, write_test_file, write_test_file_with_limit, TestRecord};
+    use bam_tools::record::fields::Fields;
+    use tempdir::TempDir;
+
+    // Long names make ReadName column span several blocks.
+    fn name(i: usize) -> String {
+        format!("{:0200}", i)
+    }
+
+    #[test]
+    fn test_records_by_name_across_blocks() {
+        let dir = TempDir::new("gbam_test").unwrap();
+        let path = dir.path().join("by_name.gbam");
+
+        let pairs = 21_000;
+        // Leading singleton shifts pairs so one of them straddles the first block boundary.
+        let mut records = vec![TestRecord::new(0, 10, &name(0))];
+        for i in 1..=pairs {
+            let mut first = TestRecord::new(0, i as i32, &name(i));
+            first.flag = 0x41;
+            let mut second = TestRecord::new(1, i as i32, &name(i));
+            second.flag = 0x81;
+            records.push(first);
+            records.push(second);
+            if i % 1000 == 0 {
+                let mut supplementary = TestRecord::new(2, 5, &name(i));
+                supplementary.flag = 0x800 | 0x41;
+                records.push(supplementary);
+            }
+        }
+        write_test_file_with_limit(&path, "@HD\tVN:1.6\tSO:queryname\n", &records, &[Fields::ReadName]);
+
+        let mut reader = open_test_file(&path);
+        let name_blocks = reader.file_meta.view_blocks(&Fields::ReadName);
+        assert!(name_blocks.len() > 1);
+        let boundary = name_blocks[0].numitems as usize;
+        assert_eq!(records[boundary - 1].name, records[boundary].name);
+
+        let groups = reader.records_by_name().unwrap().map(Result::unwrap).collect::<Vec<_>>();
+        assert_eq!(groups.len(), pairs + 1);
+        assert_eq!(groups[0].len(), 1);
+        for (i, group) in groups.iter().enumerate().skip(1) {
+            let expected_len = if i % 1000 == 0 { 3 } else { 2 };
+            assert_eq!(group.len(), expected_len);
+            for rec in group {
+                assert_eq!(rec.read_name.as_ref().unwrap()[..200], *name(i).as_bytes());
+            }
         }
     }
 
-    pub fn next_rec(&mut self) -> Option<&GbamRecord> {
-        if self.cur_rec == self.rec_amount {
-            return None;
+    #[test]
+    fn test_records_by_name_unsorted_file() {
+        let dir = TempDir::new("gbam_test").unwrap();
+        let path = dir.path().join("unsorted.gbam");
+        write_test_file(&path, "@HD\tVN:1.6\tSO:unsorted\n", &[TestRecord::default()]);
+
+        let mut reader = open_test_file(&path);
+        let err = reader.records_by_name().err().unwrap();
+        assert!(err.to_string().contains("SortBy::Name"));
+    }
+
+    fn all_records_json(reader: &mut Reader) -> Vec<String> {
+        let mut all = Vec::new();
+        let mut records = reader.records();
+        while let Some(rec) = records.next_rec().unwrap() {
+            all.push(serde_json::to_string(rec).unwrap());
         }
-        self.reader.fill_record(self.cur_rec, &mut self.buf);
-        self.cur_rec += 1;
-        Some(&self.buf)
+        all
+    }
+
+    fn range_json(records: &mut Records) -> Vec<String> {
+        let mut fetched = Vec::new();
+        while let Some(rec) = records.next_rec().unwrap() {
+            fetched.push(serde_json::to_string(rec).unwrap());
+        }
+        fetched
+    }
+
+    #[test]
+    fn test_records_range() {
+        let dir = TempDir::new("gbam_test").unwrap();
+        let path = dir.path().join("range.gbam");
+        let records: Vec<TestRecord> = (0..60_000).map(|i| TestRecord::new(0, i, &name(i as usize))).collect();
+        write_test_file_with_limit(&path, "@HD\tVN:1.6\tSO:coordinate\n", &records, &[Fields::ReadName]);
+
+        let mut reader = open_test_file(&path);
+        let name_blocks = reader.file_meta.view_blocks(&Fields::ReadName);
+        assert!(name_blocks.len() > 1);
+        let boundary = name_blocks[0].numitems as u64;
+        let all = all_records_json(&mut reader);
+
+        // Starts mid-block, in the second ReadName block only.
+        let mut reader = open_test_file(&path);
+        let range = boundary + 1_000..boundary + 1_050;
+        let fetched = range_json(&mut reader.records_range(range.clone()).unwrap());
+        let expected: Vec<String> = all.iter().skip(range.start as usize).take(50).cloned().collect();
+        assert_eq!(fetched, expected);
+        assert_eq!(reader.blocks_decompressed(&Fields::ReadName), 1);
+
+        // Straddles the block boundary.
+        let mut reader = open_test_file(&path);
+        let range = boundary - 25..boundary + 25;
+        let fetched = range_json(&mut reader.records_range(range.clone()).unwrap());
+        let expected: Vec<String> = all.iter().skip(range.start as usize).take(50).cloned().collect();
+        assert_eq!(fetched, expected);
+        assert_eq!(reader.blocks_decompressed(&Fields::ReadName), 2);
+
+        assert!(reader.records_range(0..0).unwrap().next_rec().unwrap().is_none());
+        let err = reader.records_range(59_990..60_001).err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        assert!(matches!(
+            err,
+            GbamError::Template(TemplateError::RecordOutOfRange { rec_num: 60_000, records: 60_000 })
+        ));
+    }
+
+    #[test]
+    fn test_records_range_with_template_and_flags() {
+        let dir = TempDir::new("gbam_test").unwrap();
+        let path = dir.path().join("range_flags.gbam");
+        let records: Vec<TestRecord> = (0..60_000)
+            .map(|i| {
+                let mut rec = TestRecord::new(0, i, &name(i as usize));
+                rec.flag = match i % 3 {
+                    0 => 0x10,
+                    1 => 0x10 | 0x400,
+                    _ => 0,
+                };
+                rec
+            })
+            .collect();
+        write_test_file_with_limit(&path, "@HD\tVN:1.6\tSO:coordinate\n", &records, &[Fields::ReadName]);
+
+        let template = ParsingTemplate::new_with(&[Fields::Flags, Fields::Pos, Fields::ReadName]);
+        let mut reader = Reader::from_path(&path, template.clone()).unwrap();
+        let all = all_records_json(&mut reader);
+        let boundary = reader.file_meta.view_blocks(&Fields::ReadName)[0].numitems as u64;
+
+        let mut reader = Reader::from_path(&path, template).unwrap();
+        let range = boundary - 100..boundary + 100;
+        let mut filtered = reader.records_range(range.clone()).unwrap().filter_flags(0x10, 0x400).unwrap();
+        let fetched = range_json(&mut filtered);
+        let expected: Vec<String> = all
+            .iter()
+            .enumerate()
+            .skip(range.start as usize)
+            .take(200)
+            .filter(|(i, _)| i % 3 == 0)
+            .map(|(_, json)| json.clone())
+            .collect();
+        assert!(!fetched.is_empty());
+        assert_eq!(fetched, expected);
+
+        let mut reader = Reader::from_path(&path, ParsingTemplate::new_with(&[Fields::Pos])).unwrap();
+        let err = reader.records_range(0..10).unwrap().filter_flags(0x10, 0).err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
     }
 }
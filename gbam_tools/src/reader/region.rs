@@ -0,0 +1,1161 @@
+//! Region queries over coordinate sorted files.
+use std::io;
+use std::ops::Range;
+
+use bam_tools::record::fields::Fields;
+
+use super::parse_tmplt::ParsingTemplate;
+use super::reader::Reader;
+use super::record::GbamRecord;
+use crate::error::Result;
+use crate::meta::{BlockMeta, SortOrder};
+use crate::query::cigar::base_coverage;
+use crate::stats::{stat_value, NULLS};
+
+/// RefID of unplaced reads, the "*" region of samtools.
+pub const UNPLACED_REF_ID: i32 = -1;
+
+/// Genomic interval, 0-based and half-open like BED.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Region {
+    pub ref_id: i32,
+    pub start: i32,
+    pub end: i32,
+}
+
+impl Region {
+    pub fn new(ref_id: i32, start: i32, end: i32) -> Self {
+        Region { ref_id, start, end }
+    }
+
+    /// The "*" region, all unplaced reads. Fetched only with
+    /// `FetchOptions::include_unplaced`.
+    pub fn unplaced() -> Self {
+        Region::new(UNPLACED_REF_ID, 0, i32::MAX)
+    }
+
+    /// Checks if alignment starting at `pos` and covering `ref_len` bases
+    /// overlaps the region. Alignments without reference bases, like
+    /// unmapped reads placed at their mate, are treated as covering one base,
+    /// like samtools does. Unplaced reads overlap only the "*" region.
+    pub fn overlaps(&self, ref_id: i32, pos: i32, ref_len: u32) -> bool {
+        if ref_id == UNPLACED_REF_ID {
+            return self.ref_id == UNPLACED_REF_ID;
+        }
+        let end = pos as i64 + std::cmp::max(ref_len, 1) as i64;
+        ref_id == self.ref_id && (pos as i64) < self.end as i64 && end > self.start as i64
+    }
+}
+
+/// Options of [`Reader::fetch_with_options`].
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FetchOptions {
+    /// Fetch of `Region::unplaced()` returns unplaced reads, the RefID -1
+    /// tail of the file. Nothing is returned otherwise.
+    pub include_unplaced: bool,
+}
+
+/// Fields needed to decide whether record overlaps a region.
+pub(crate) const REGION_FIELDS: [Fields; 3] = [Fields::RefID, Fields::Pos, Fields::RawCigar];
+
+/// Iterates over records overlapping a region. Created by [`Reader::fetch`].
+pub struct RegionRecords<'a> {
+    reader: &'a mut Reader,
+    region: Region,
+    cur_rec: usize,
+    // Records from here on are unplaced, unless they are fetched.
+    end: usize,
+    buf: GbamRecord,
+    // Records are scanned with region fields only, other fields are fetched
+    // for matches. Swapped with reader template while scanning.
+    scan_template: ParsingTemplate,
+}
+
+impl<'a> RegionRecords<'a> {
+    /// Number of the record last returned by `next_rec()`, as taken by
+    /// `Reader::fill_record()`.
+    pub fn rec_num(&self) -> usize {
+        self.cur_rec - 1
+    }
+
+    /// Decodes `fields` of the record last returned by `next_rec()` and
+    /// returns it. For fields left out of the reader template, so they are
+    /// decoded only for records the caller needs them of. Fails like
+    /// `Reader::fill_record()`.
+    pub fn fill_fields(&mut self, fields: &[Fields]) -> Result<&GbamRecord> {
+        let template = std::mem::replace(
+            &mut self.reader.parsing_template,
+            ParsingTemplate::try_new_with(fields)?,
+        );
+        let filled = self.reader.fill_record(self.cur_rec - 1, &mut self.buf);
+        self.reader.parsing_template = template;
+        filled?;
+        Ok(&self.buf)
+    }
+
+    /// Next record overlapping the region, None past the last one. Fails
+    /// like `Reader::fill_record()`.
+    pub fn next_rec(&mut self) -> Result<Option<&GbamRecord>> {
+        while self.cur_rec < self.end {
+            std::mem::swap(&mut self.reader.parsing_template, &mut self.scan_template);
+            let filled = self.reader.fill_record(self.cur_rec, &mut self.buf);
+            std::mem::swap(&mut self.reader.parsing_template, &mut self.scan_template);
+            filled?;
+            self.cur_rec += 1;
+            let ref_id = self.buf.refid.unwrap();
+            let pos = self.buf.pos.unwrap();
+            // Sorted, so nothing overlaps after this record.
+            if ref_id != self.region.ref_id || pos >= self.region.end {
+                self.cur_rec = self.end;
+                return Ok(None);
+            }
+            let ref_len = base_coverage(&self.buf.cigar.as_ref().unwrap().0);
+            if self.region.overlaps(ref_id, pos, ref_len) {
+                self.reader.fill_record(self.cur_rec - 1, &mut self.buf)?;
+                return Ok(Some(&self.buf));
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Options of [`Reader::fetch_many_with_options`].
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FetchManyOptions {
+    pub fetch: FetchOptions,
+    /// Record overlapping several regions is returned once, with the
+    /// smallest index of them. Otherwise once for each.
+    pub dedupe: bool,
+}
+
+/// Iterates over records overlapping any of several regions. Created by
+/// [`Reader::fetch_many`].
+pub struct MultiRegionRecords<'a> {
+    reader: &'a mut Reader,
+    regions: Vec<Region>,
+    // Indices of regions in file order of their starts.
+    sorted: Vec<usize>,
+    groups: Vec<FetchPlan>,
+    // Groups started so far, the last one is scanned.
+    started: usize,
+    // Position in `sorted` of the first region of the scanned group.
+    first_region: usize,
+    cur_rec: usize,
+    end: usize,
+    // Records before this one were matched against all regions.
+    scanned: usize,
+    dedupe: bool,
+    // Regions of the last record not returned yet, last one first.
+    pending: Vec<usize>,
+    buf: GbamRecord,
+    scan_template: ParsingTemplate,
+}
+
+impl<'a> MultiRegionRecords<'a> {
+    /// Index of a region the record overlaps, in regions passed to
+    /// `Reader::fetch_many()`, and the record. Records come in file order,
+    /// ones overlapping several regions are returned for each, by region
+    /// index, unless deduplicated. None past the last one. Fails like
+    /// `Reader::fill_record()`.
+    pub fn next_rec(&mut self) -> Result<Option<(usize, &GbamRecord)>> {
+        loop {
+            if let Some(region) = self.pending.pop() {
+                return Ok(Some((region, &self.buf)));
+            }
+            if !self.next_match()? {
+                return Ok(None);
+            }
+        }
+    }
+
+    /// Number of the record last returned by `next_rec()`, as taken by
+    /// `Reader::fill_record()`.
+    pub fn rec_num(&self) -> usize {
+        self.scanned - 1
+    }
+
+    /// Scans to the next record overlapping any region, fills it and its
+    /// regions. False if there are none.
+    fn next_match(&mut self) -> Result<bool> {
+        loop {
+            if self.cur_rec >= self.end && !self.next_group()? {
+                return Ok(false);
+            }
+            if self.cur_rec >= self.end {
+                continue;
+            }
+            std::mem::swap(&mut self.reader.parsing_template, &mut self.scan_template);
+            let filled = self.reader.fill_record(self.cur_rec, &mut self.buf);
+            std::mem::swap(&mut self.reader.parsing_template, &mut self.scan_template);
+            filled?;
+            let ref_id = self.buf.refid.unwrap();
+            let pos = self.buf.pos.unwrap();
+            let group = self.groups[self.started - 1].region;
+            // Sorted, so nothing after this record overlaps the group. The
+            // record may overlap the next one.
+            if ref_id != group.ref_id || pos >= group.end {
+                self.cur_rec = self.end;
+                continue;
+            }
+            self.cur_rec += 1;
+            self.scanned = self.cur_rec;
+            let ref_len = base_coverage(&self.buf.cigar.as_ref().unwrap().0);
+            let rec_end = pos as i64 + std::cmp::max(ref_len, 1) as i64;
+            let mut matches = Vec::new();
+            // Regions of previous groups end before the record starts.
+            for &index in &self.sorted[self.first_region..] {
+                let region = &self.regions[index];
+                let past_record = ref_id != UNPLACED_REF_ID && region.start as i64 >= rec_end;
+                if region.ref_id != ref_id || past_record {
+                    break;
+                }
+                if region.overlaps(ref_id, pos, ref_len) {
+                    matches.push(index);
+                }
+            }
+            if matches.is_empty() {
+                continue;
+            }
+            matches.sort_unstable();
+            if self.dedupe {
+                matches.truncate(1);
+            }
+            matches.reverse();
+            self.pending = matches;
+            self.reader.fill_record(self.cur_rec - 1, &mut self.buf)?;
+            return Ok(true);
+        }
+    }
+
+    /// Moves to records of the next group. Records already scanned are
+    /// skipped, so columns only move forward. False if there are none.
+    fn next_group(&mut self) -> Result<bool> {
+        let plan = match self.groups.get(self.started) {
+            Some(plan) => plan,
+            None => return Ok(false),
+        };
+        self.started += 1;
+        let group_key = region_key(&plan.region);
+        while self.first_region < self.sorted.len()
+            && region_key(&self.regions[self.sorted[self.first_region]]) < group_key
+        {
+            self.first_region += 1;
+        }
+        let records = self.scanned.max(plan.records.start)..plan.records.end;
+        self.cur_rec = if plan.indexed || records.is_empty() {
+            records.start
+        } else {
+            std::mem::swap(&mut self.reader.parsing_template, &mut self.scan_template);
+            let cur_rec = self.reader.first_rec_of_ref(plan.region.ref_id, records.clone());
+            std::mem::swap(&mut self.reader.parsing_template, &mut self.scan_template);
+            cur_rec?
+        };
+        self.end = records.end;
+        Ok(true)
+    }
+}
+
+/// Order of regions in sorted files, unplaced ones last.
+fn region_key(region: &Region) -> (bool, i32, i32) {
+    (region.ref_id == UNPLACED_REF_ID, region.ref_id, region.start)
+}
+
+/// Stored bytes of a block, compressed and encrypted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BlockRange {
+    pub seekpos: u64,
+    pub block_size: u32,
+}
+
+/// Records and blocks read by a region fetch, computed from meta by
+/// [`Reader::plan_fetch`]. Executed by [`Reader::fetch_planned`], which reads
+/// no other blocks.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FetchPlan {
+    pub region: Region,
+    /// Records scanned, a superset of the ones overlapping the region.
+    pub records: Range<usize>,
+    /// Scan starts at linear index entry, instead of searching for the first
+    /// record of the reference.
+    pub indexed: bool,
+    /// Blocks holding `records`, by field of parsing template, in file
+    /// order.
+    pub blocks: Vec<(Fields, Vec<BlockRange>)>,
+}
+
+/// Plans of a multi-region fetch, computed from meta by
+/// [`Reader::plan_fetch_many`]. Overlapping and adjacent regions are merged
+/// into groups, fetched by one scan.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MultiFetchPlan {
+    pub regions: Vec<Region>,
+    /// Plans of merged regions, in file order.
+    pub groups: Vec<FetchPlan>,
+    /// Union of blocks of groups, by field of parsing template, in file
+    /// order.
+    pub blocks: Vec<(Fields, Vec<BlockRange>)>,
+}
+
+impl MultiFetchPlan {
+    /// Total bytes of the blocks, each counted once.
+    pub fn bytes(&self) -> u64 {
+        self.blocks
+            .iter()
+            .flat_map(|(_, ranges)| ranges)
+            .map(|range| range.block_size as u64)
+            .sum()
+    }
+}
+
+impl FetchPlan {
+    /// Byte ranges of all fields.
+    pub fn ranges(&self) -> impl Iterator<Item = &BlockRange> {
+        self.blocks.iter().flat_map(|(_, ranges)| ranges)
+    }
+
+    /// Total bytes of the blocks.
+    pub fn bytes(&self) -> u64 {
+        self.ranges().map(|range| range.block_size as u64).sum()
+    }
+}
+
+impl Reader {
+    /// Get iterator over records overlapping `region`. The file has to be
+    /// coordinate sorted, RefID, Pos and RawCigar have to be enabled in
+    /// parsing template. Unmapped reads placed at their mate are returned
+    /// like mapped ones, as samtools does. Scan starts from linear index
+    /// entry, if the file has one, otherwise from the first record of the
+    /// reference. Blocks of unplaced reads are never scanned, if the file has
+    /// their counts, see `Writer::add_default_stats_collectors()`.
+    pub fn fetch(&mut self, region: &Region) -> Result<RegionRecords<'_>> {
+        self.fetch_with_options(region, &FetchOptions::default())
+    }
+
+    /// Same as `fetch()`, with unplaced reads fetched if asked for.
+    pub fn fetch_with_options(
+        &mut self,
+        region: &Region,
+        options: &FetchOptions,
+    ) -> Result<RegionRecords<'_>> {
+        let plan = self.plan_fetch_with_options(region, options)?;
+        self.fetch_planned(&plan)
+    }
+
+    /// Blocks `fetch()` of the region reads, for every field of parsing
+    /// template. Only meta is read. Besides linear index and unplaced counts
+    /// used by `fetch()`, RefID and Pos block stats narrow the scanned
+    /// records, if the file has them.
+    pub fn plan_fetch(&self, ref_id: i32, start: i32, end: i32) -> Result<FetchPlan> {
+        self.plan_fetch_with_options(&Region::new(ref_id, start, end), &FetchOptions::default())
+    }
+
+    /// Same as `plan_fetch()`, for `fetch_with_options()`.
+    pub fn plan_fetch_with_options(&self, region: &Region, options: &FetchOptions) -> Result<FetchPlan> {
+        self.check_fetchable()?;
+        // Index holds physical record numbers.
+        let indexed = match (&self.index_mapping, self.file_meta.get_linear_index()) {
+            (None, Some(linear_index)) => linear_index.first_candidate(region.ref_id, region.start),
+            _ => None,
+        };
+        let records = match (region.ref_id == UNPLACED_REF_ID, options.include_unplaced) {
+            (true, false) => 0..0,
+            // Records are mapped to blocks by their physical numbers.
+            _ if self.index_mapping.is_some() => 0..self.amount,
+            (unplaced, _) => {
+                let (mut first, mut last) = self.records_of_ref(region);
+                if unplaced {
+                    first = first.max(self.mapped_end());
+                } else {
+                    last = last.min(self.mapped_end());
+                }
+                let first = indexed.map_or(first, |rec_num| rec_num as usize);
+                first..last.max(first)
+            }
+        };
+
+        let template = &self.parsing_template;
+        let blocks = if records.is_empty() {
+            Vec::new()
+        } else {
+            template
+                .get_active_fields_iter()
+                .map(|field| (*field, self.blocks_of_records(field, &records)))
+                .collect()
+        };
+        Ok(FetchPlan {
+            region: *region,
+            records,
+            indexed: indexed.is_some(),
+            blocks,
+        })
+    }
+
+    /// Iterates over records of `plan`, made for this file and parsing
+    /// template.
+    pub fn fetch_planned(&mut self, plan: &FetchPlan) -> Result<RegionRecords<'_>> {
+        if !self.parsing_template.check_if_active(&REGION_FIELDS) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "RefID, Pos and RawCigar fields have to be enabled in parsing template to fetch a region.",
+            ).into());
+        }
+        self.start_query();
+        let mut scan_template = ParsingTemplate::new_with(&REGION_FIELDS);
+        let cur_rec = if plan.indexed || plan.records.is_empty() {
+            plan.records.start
+        } else {
+            std::mem::swap(&mut self.parsing_template, &mut scan_template);
+            let cur_rec = self.first_rec_of_ref(plan.region.ref_id, plan.records.clone());
+            std::mem::swap(&mut self.parsing_template, &mut scan_template);
+            cur_rec?
+        };
+        Ok(RegionRecords {
+            reader: self,
+            region: plan.region,
+            cur_rec,
+            end: plan.records.end,
+            buf: GbamRecord::default(),
+            scan_template,
+        })
+    }
+
+    /// Get iterator over records overlapping any of `regions`, for BED-like
+    /// lists of many small intervals. Requirements are the ones of
+    /// `fetch()`. Regions are sorted and merged, so records are scanned once
+    /// in file order and every block is decompressed at most once, if the
+    /// file has a linear index.
+    pub fn fetch_many(&mut self, regions: &[Region]) -> Result<MultiRegionRecords<'_>> {
+        self.fetch_many_with_options(regions, &FetchManyOptions::default())
+    }
+
+    /// Same as `fetch_many()`, with options of `fetch_with_options()` and
+    /// deduplication of records overlapping several regions.
+    pub fn fetch_many_with_options(
+        &mut self,
+        regions: &[Region],
+        options: &FetchManyOptions,
+    ) -> Result<MultiRegionRecords<'_>> {
+        let plan = self.plan_fetch_many(regions, &options.fetch)?;
+        self.fetch_many_planned(&plan, options.dedupe)
+    }
+
+    /// Blocks `fetch_many()` of the regions reads, like `plan_fetch()`.
+    pub fn plan_fetch_many(&self, regions: &[Region], options: &FetchOptions) -> Result<MultiFetchPlan> {
+        self.check_fetchable()?;
+        let mut sorted = regions.to_vec();
+        sorted.sort_by_key(region_key);
+        let mut merged: Vec<Region> = Vec::new();
+        for region in sorted {
+            match merged.last_mut() {
+                Some(last) if last.ref_id == region.ref_id && region.start <= last.end => {
+                    last.end = last.end.max(region.end);
+                }
+                _ => merged.push(region),
+            }
+        }
+        let groups = merged
+            .iter()
+            .map(|region| self.plan_fetch_with_options(region, options))
+            .collect::<Result<Vec<_>>>()?;
+        let blocks = self
+            .parsing_template
+            .get_active_fields_iter()
+            .map(|field| {
+                let mut ranges: Vec<BlockRange> = groups
+                    .iter()
+                    .flat_map(|group| group.blocks.iter().filter(|(f, _)| f == field))
+                    .flat_map(|(_, ranges)| ranges.iter().copied())
+                    .collect();
+                ranges.sort_by_key(|range| range.seekpos);
+                ranges.dedup();
+                (*field, ranges)
+            })
+            .collect();
+        Ok(MultiFetchPlan {
+            regions: regions.to_vec(),
+            groups,
+            blocks,
+        })
+    }
+
+    /// Iterates over records of `plan`, made for this file and parsing
+    /// template.
+    pub fn fetch_many_planned(
+        &mut self,
+        plan: &MultiFetchPlan,
+        dedupe: bool,
+    ) -> Result<MultiRegionRecords<'_>> {
+        self.check_fetchable()?;
+        self.start_query();
+        let mut sorted: Vec<usize> = (0..plan.regions.len()).collect();
+        sorted.sort_by_key(|&index| region_key(&plan.regions[index]));
+        Ok(MultiRegionRecords {
+            reader: self,
+            regions: plan.regions.clone(),
+            sorted,
+            groups: plan.groups.clone(),
+            started: 0,
+            first_region: 0,
+            cur_rec: 0,
+            end: 0,
+            scanned: 0,
+            dedupe,
+            pending: Vec::new(),
+            buf: GbamRecord::default(),
+            scan_template: ParsingTemplate::new_with(&REGION_FIELDS),
+        })
+    }
+
+    /// Fails unless the file is coordinate sorted and region fields are in
+    /// parsing template.
+    fn check_fetchable(&self) -> Result<()> {
+        let sort_order = self.file_meta.get_sort_order();
+        if sort_order != SortOrder::Coordinate {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Region fetch requires a coordinate sorted file, but sort order is {:?}.",
+                    sort_order
+                ),
+            ).into());
+        }
+        if !self.parsing_template.check_if_active(&REGION_FIELDS) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "RefID, Pos and RawCigar fields have to be enabled in parsing template to fetch a region.",
+            ).into());
+        }
+        Ok(())
+    }
+
+    /// Records of blocks which may hold records of `region` on its
+    /// reference, by RefID stats, ending early if Pos stats show that
+    /// records are past the region. Blocks without stats may hold anything.
+    fn records_of_ref(&self, region: &Region) -> (usize, usize) {
+        let ref_id = stat_value(&Fields::RefID, &region.ref_id.to_le_bytes());
+        let mut first = None;
+        let mut last = self.amount;
+        let mut start = 0;
+        for block in self.file_meta.view_blocks(&Fields::RefID) {
+            if block.numitems == 0 {
+                continue;
+            }
+            let end = start + block.numitems as usize;
+            match (&block.stats, first) {
+                (Some(stat), None) if stat.max_value < ref_id => {}
+                (_, None) => first = Some(start),
+                (Some(stat), Some(_)) if stat.min_value > ref_id => {
+                    last = start;
+                    break;
+                }
+                _ => {}
+            }
+            start = end;
+        }
+        let first = first.unwrap_or(self.amount);
+        if region.ref_id == UNPLACED_REF_ID {
+            return (first, last);
+        }
+
+        // Scan stops at the first record past the region, on the reference
+        // or a following one.
+        let ref_blocks = self.file_meta.view_blocks(&Fields::RefID);
+        let mut start = 0;
+        for block in self.file_meta.view_blocks(&Fields::Pos) {
+            if start >= last {
+                break;
+            }
+            let past_region = match &block.stats {
+                Some(stat) => start >= first && block.numitems > 0 && stat.min_value >= region.end,
+                None => false,
+            };
+            if past_region && matches!(min_ref_id_at(ref_blocks, start), Some(min) if min >= ref_id) {
+                last = start;
+                break;
+            }
+            start += block.numitems as usize;
+        }
+        (first, last)
+    }
+
+    /// Blocks of `field` holding any of `records`.
+    fn blocks_of_records(&self, field: &Fields, records: &Range<usize>) -> Vec<BlockRange> {
+        let mut ranges = Vec::new();
+        let mut start = 0;
+        for block in self.file_meta.view_blocks(field) {
+            let end = start + block.numitems as usize;
+            if start >= records.end {
+                break;
+            }
+            if end > records.start && end > start {
+                ranges.push(BlockRange {
+                    seekpos: block.seekpos,
+                    block_size: block.block_size,
+                });
+            }
+            start = end;
+        }
+        ranges
+    }
+
+    /// Start of unmapped tail, if the file has its marker. Otherwise start
+    /// of trailing RefID blocks holding unplaced records only, by their
+    /// write time counts. Number of records if there are none, or the counts
+    /// weren't collected.
+    fn mapped_end(&self) -> usize {
+        if self.index_mapping.is_some() {
+            return self.amount;
+        }
+        if let Some(start) = self.unmapped_tail_start() {
+            return start;
+        }
+        let mut start = 0;
+        for block in self.file_meta.view_blocks(&Fields::RefID) {
+            let numitems = block.numitems as usize;
+            if numitems > 0 && block.extra_stat(NULLS) == Some(numitems as u64) {
+                return start;
+            }
+            start += numitems;
+        }
+        self.amount
+    }
+
+    /// Binary search for the first record on reference `ref_id` among
+    /// `records`, or the first unplaced one. Unplaced records (RefID -1) are
+    /// placed at the end of sorted files.
+    fn first_rec_of_ref(&mut self, ref_id: i32, records: Range<usize>) -> Result<usize> {
+        let mut rec = GbamRecord::default();
+        let (mut lo, mut hi) = (records.start, records.end);
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            self.fill_record(mid, &mut rec)?;
+            let mid_ref_id = rec.refid.unwrap();
+            if mid_ref_id == UNPLACED_REF_ID || (ref_id != UNPLACED_REF_ID && mid_ref_id >= ref_id) {
+                hi = mid;
+            } else {
+                lo = mid + 1;
+            }
+        }
+        Ok(lo)
+    }
+}
+
+/// Smallest RefID stat of the block holding record `rec_num`, None if it
+/// has no stats.
+fn min_ref_id_at(blocks: &[BlockMeta], rec_num: usize) -> Option<i32> {
+    let mut start = 0;
+    for block in blocks {
+        let end = start + block.numitems as usize;
+        if rec_num < end {
+            return block.stats.as_ref().map(|stat| stat.min_value);
+        }
+        start = end;
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reader::source::ReadBlockAt;
+    use crate::test_utils::{
+        new_test_writer, open_test_file, sam_header_bytes, test_ref_seqs, write_test_file, TestRecord,
+    };
+    use crate::writer::{Writer, WriterSettings};
+    use std::convert::TryInto;
+    use std::fs::File;
+    use std::io::BufWriter;
+    use std::path::Path;
+    use std::sync::{Arc, Mutex};
+    use tempdir::TempDir;
+
+    const SORTED: &str = "@HD\tVN:1.6\tSO:coordinate\n";
+
+    /// Writer of a sorted file without block stats, so fetch can't narrow
+    /// its scan by them.
+    fn new_writer_without_stats(path: &Path) -> Writer<BufWriter<File>> {
+        let ref_seqs = test_ref_seqs();
+        let settings = WriterSettings {
+            collect_stats_for: Vec::new(),
+            sam_header: sam_header_bytes(SORTED, &ref_seqs),
+            ref_seqs,
+            full_command: String::from("test"),
+            ..Default::default()
+        };
+        Writer::create(path, settings).unwrap()
+    }
+
+    #[test]
+    fn test_fetch() {
+        let dir = TempDir::new("gbam_region").unwrap();
+        let path = dir.path().join("sorted.gbam");
+        let mut records = Vec::new();
+        for ref_id in 0..3 {
+            for pos in (0..100_000).step_by(10) {
+                records.push(TestRecord::new(ref_id, pos, &format!("r{}_{}", ref_id, pos)));
+            }
+        }
+        let mut unmapped = TestRecord::new(-1, -1, "unmapped");
+        unmapped.flag = 4;
+        unmapped.cigar.clear();
+        records.push(unmapped);
+        write_test_file(&path, SORTED, &records);
+
+        let mut reader = open_test_file(&path);
+        // Reads are 4 bases long, so the one at 990 overlaps too.
+        let mut fetched = reader.fetch(&Region::new(1, 993, 1_010)).unwrap();
+        let mut positions = Vec::new();
+        while let Some(rec) = fetched.next_rec().unwrap() {
+            assert_eq!(rec.refid, Some(1));
+            assert!(rec.read_name.is_some());
+            positions.push(rec.pos.unwrap());
+        }
+        assert_eq!(positions, vec![990, 1_000]);
+
+        let mut fetched = reader.fetch(&Region::new(2, 99_993, 200_000)).unwrap();
+        assert_eq!(fetched.next_rec().unwrap().unwrap().pos, Some(99_990));
+        assert!(fetched.next_rec().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_fetch_linear_index() {
+        let dir = TempDir::new("gbam_region").unwrap();
+        let mut records = Vec::new();
+        for ref_id in 0..3 {
+            for pos in (0..500_000).step_by(50) {
+                let mut rec = TestRecord::new(ref_id, pos, &format!("r{}_{}", ref_id, pos));
+                // Long alignments reach into following bins.
+                if pos % 20_000 == 0 {
+                    rec.cigar = vec![30_000 << 4];
+                }
+                records.push(rec);
+            }
+        }
+        let write = |name: &str, linear_index: bool| {
+            let path = dir.path().join(name);
+            // Plain file has no block stats either, only the index narrows
+            // the scan.
+            let mut writer = if linear_index {
+                new_test_writer(&path, SORTED)
+            } else {
+                new_writer_without_stats(&path)
+            };
+            writer.set_rows_per_block(1_000);
+            writer.set_linear_index(linear_index);
+            for rec in &records {
+                writer.push_record(&rec.to_raw(), false).unwrap();
+            }
+            writer.finish_with_summary(false).unwrap();
+            open_test_file(&path)
+        };
+        let mut plain = write("plain.gbam", false);
+        let mut indexed = write("indexed.gbam", true);
+        assert!(plain.file_meta.get_linear_index().is_none());
+        assert!(indexed.file_meta.get_linear_index().is_some());
+
+        let fetch = |reader: &mut Reader, region| {
+            let before = reader.blocks_decompressed(&Fields::Pos);
+            let mut fetched = reader.fetch(&region).unwrap();
+            let mut names = Vec::new();
+            while let Some(rec) = fetched.next_rec().unwrap() {
+                names.push(rec.read_name.clone().unwrap());
+            }
+            (names, reader.blocks_decompressed(&Fields::Pos) - before)
+        };
+        for region in [
+            Region::new(0, 0, 100),
+            Region::new(1, 25_000, 26_000),
+            Region::new(1, 40_010, 40_020),
+            Region::new(2, 333_333, 444_444),
+            Region::new(2, 499_990, 600_000),
+            Region::new(2, 700_000, 800_000),
+        ] {
+            let (expected, plain_blocks) = fetch(&mut plain, region);
+            let (names, indexed_blocks) = fetch(&mut indexed, region);
+            assert_eq!(names, expected);
+            assert!(indexed_blocks < plain_blocks, "{} {}", indexed_blocks, plain_blocks);
+        }
+        // Region inside alignment starting at 20000.
+        let (names, _) = fetch(&mut indexed, Region::new(1, 45_000, 45_001));
+        assert_eq!(names.first().unwrap(), b"r1_20000\0");
+    }
+
+    #[test]
+    fn test_fetch_skips_unmapped_blocks() {
+        let dir = TempDir::new("gbam_region").unwrap();
+        let mut records = Vec::new();
+        for ref_id in 0..2 {
+            for pos in (0..2_500).step_by(10) {
+                records.push(TestRecord::new(ref_id, pos, &format!("r{}_{}", ref_id, pos)));
+            }
+        }
+        for i in 0..1_000 {
+            let mut unmapped = TestRecord::new(-1, -1, &format!("u{}", i));
+            unmapped.flag = 4;
+            unmapped.cigar.clear();
+            records.push(unmapped);
+        }
+        let write = |name: &str, stats: bool| {
+            let path = dir.path().join(name);
+            let mut writer = if stats {
+                new_test_writer(&path, SORTED)
+            } else {
+                new_writer_without_stats(&path)
+            };
+            writer.set_rows_per_block(100);
+            if stats {
+                writer.add_default_stats_collectors();
+            }
+            for rec in &records {
+                writer.push_record(&rec.to_raw(), false).unwrap();
+            }
+            writer.finish_with_summary(false).unwrap();
+            open_test_file(&path)
+        };
+        let mut plain = write("plain.gbam", false);
+        let mut counted = write("counted.gbam", true);
+        assert_eq!(counted.mapped_end(), 500);
+        assert_eq!(plain.mapped_end(), 1_500);
+
+        let fetch = |reader: &mut Reader, region| {
+            let before = reader.blocks_decompressed(&Fields::RefID);
+            let mut fetched = reader.fetch(&region).unwrap();
+            let mut names = Vec::new();
+            while let Some(rec) = fetched.next_rec().unwrap() {
+                names.push(rec.read_name.clone().unwrap());
+            }
+            (names, reader.blocks_decompressed(&Fields::RefID) - before)
+        };
+        for region in [Region::new(0, 0, 100), Region::new(1, 2_400, 3_000), Region::new(1, 5_000, 6_000)] {
+            let (expected, plain_blocks) = fetch(&mut plain, region);
+            let (names, counted_blocks) = fetch(&mut counted, region);
+            assert_eq!(names, expected);
+            assert!(counted_blocks < plain_blocks, "{} {}", counted_blocks, plain_blocks);
+        }
+        let (names, _) = fetch(&mut counted, Region::new(1, 2_400, 3_000));
+        assert_eq!(names.last().unwrap(), b"r1_2490\0");
+    }
+
+    #[test]
+    fn test_fetch_unmapped_mates() {
+        let dir = TempDir::new("gbam_region").unwrap();
+        let read = |ref_id, pos, name: &str| TestRecord {
+            flag: 0x1,
+            ..TestRecord::new(ref_id, pos, name)
+        };
+        // Unmapped, placed at the position of its mate.
+        let mate = |ref_id, pos, name: &str| TestRecord {
+            flag: 0x1 | 0x4,
+            cigar: Vec::new(),
+            ..TestRecord::new(ref_id, pos, name)
+        };
+        let mut records = vec![
+            read(0, 97, "read_97"),
+            mate(0, 99, "mate_99"),
+            mate(0, 100, "mate_100"),
+            read(0, 100, "read_100"),
+            read(0, 150, "read_150"),
+            mate(0, 199, "mate_199"),
+            mate(0, 200, "mate_200"),
+            read(0, 200, "read_200"),
+            read(1, 0, "read_r1"),
+            mate(1, 0, "mate_r1"),
+        ];
+        for i in 0..3 {
+            records.push(mate(-1, -1, &format!("unplaced_{}", i)));
+        }
+        let fetch = |reader: &mut Reader, region, include_unplaced| {
+            let options = FetchOptions { include_unplaced };
+            let mut fetched = reader.fetch_with_options(&region, &options).unwrap();
+            let mut names = Vec::new();
+            while let Some(rec) = fetched.next_rec().unwrap() {
+                let name = rec.read_name.as_ref().unwrap();
+                names.push(String::from_utf8_lossy(&name[..name.len() - 1]).into_owned());
+            }
+            names
+        };
+        for stats in [false, true] {
+            let path = dir.path().join(format!("mates_{}.gbam", stats));
+            let mut writer = new_test_writer(&path, SORTED);
+            writer.set_rows_per_block(4);
+            if stats {
+                writer.add_default_stats_collectors();
+            }
+            for rec in &records {
+                writer.push_record(&rec.to_raw(), false).unwrap();
+            }
+            writer.finish_with_summary(false).unwrap();
+            let mut reader = open_test_file(&path);
+
+            // Mates end where the region starts, or start where it ends.
+            assert_eq!(
+                fetch(&mut reader, Region::new(0, 100, 200), false),
+                vec!["read_97", "mate_100", "read_100", "read_150", "mate_199"]
+            );
+            assert_eq!(fetch(&mut reader, Region::new(1, 0, 1), false), vec!["read_r1", "mate_r1"]);
+            assert!(fetch(&mut reader, Region::unplaced(), false).is_empty());
+            assert_eq!(
+                fetch(&mut reader, Region::unplaced(), true),
+                vec!["unplaced_0", "unplaced_1", "unplaced_2"]
+            );
+            // Only placed reads are returned for references.
+            assert_eq!(fetch(&mut reader, Region::new(1, 0, 1), true), vec!["read_r1", "mate_r1"]);
+
+            // Block of reference 1 and unplaced reads doesn't span reference 0.
+            let stat = reader.file_meta.view_blocks(&Fields::RefID)[2].stats.clone().unwrap();
+            assert_eq!((stat.min_value, stat.max_value), (1, crate::stats::UNPLACED_STAT));
+            let read_i32 = |b: &[u8]| i32::from_le_bytes(b.try_into().unwrap());
+            let on_ref_0 =
+                reader.blocks_overlapping(&Fields::RefID, |min, max| read_i32(min) <= 0 && read_i32(max) >= 0);
+            assert_eq!(on_ref_0, vec![0, 1]);
+        }
+    }
+
+    /// File in memory, recording every read.
+    struct RecordingSource {
+        bytes: Vec<u8>,
+        reads: Arc<Mutex<Vec<BlockRange>>>,
+    }
+
+    impl ReadBlockAt for RecordingSource {
+        fn read_at(&self, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+            self.reads.lock().unwrap().push(BlockRange {
+                seekpos: offset,
+                block_size: len as u32,
+            });
+            Ok(self.bytes[offset as usize..offset as usize + len].to_vec())
+        }
+
+        fn size(&self) -> io::Result<u64> {
+            Ok(self.bytes.len() as u64)
+        }
+    }
+
+    #[test]
+    fn test_plan_fetch() {
+        let dir = TempDir::new("gbam_region").unwrap();
+        let mut records = Vec::new();
+        for ref_id in 0..3 {
+            for pos in (0..50_000).step_by(10) {
+                let mut rec = TestRecord::new(ref_id, pos, &format!("r{}_{}", ref_id, pos));
+                if pos % 5_000 == 0 {
+                    rec.cigar = vec![3_000 << 4];
+                }
+                records.push(rec);
+            }
+        }
+        for i in 0..500 {
+            records.push(TestRecord {
+                flag: 0x4,
+                cigar: Vec::new(),
+                ..TestRecord::new(-1, -1, &format!("u{}", i))
+            });
+        }
+        for linear_index in [false, true] {
+            let path = dir.path().join(format!("planned_{}.gbam", linear_index));
+            let mut writer = new_test_writer(&path, SORTED);
+            writer.set_rows_per_block(500);
+            writer.set_linear_index(linear_index);
+            for rec in &records {
+                writer.push_record(&rec.to_raw(), false).unwrap();
+            }
+            writer.finish_with_summary(false).unwrap();
+            let mut expected = open_test_file(&path);
+
+            let reads = Arc::new(Mutex::new(Vec::new()));
+            let source = RecordingSource {
+                bytes: std::fs::read(&path).unwrap(),
+                reads: reads.clone(),
+            };
+            let mut template = ParsingTemplate::new();
+            template.set_all();
+            let mut reader = Reader::from_source(source, template).unwrap();
+            // File info and meta.
+            reads.lock().unwrap().clear();
+            let all_blocks: usize = Fields::iterator().map(|field| reader.file_meta.view_blocks(field).len()).sum();
+
+            for (region, include_unplaced) in [
+                (Region::new(0, 0, 100), false),
+                (Region::new(1, 20_005, 21_000), false),
+                (Region::new(2, 49_000, 60_000), false),
+                (Region::new(2, 70_000, 80_000), false),
+                (Region::unplaced(), true),
+                (Region::unplaced(), false),
+            ] {
+                let options = FetchOptions { include_unplaced };
+                let names = |reader: &mut Reader| {
+                    let mut fetched = reader.fetch_with_options(&region, &options).unwrap();
+                    let mut names = Vec::new();
+                    while let Some(rec) = fetched.next_rec().unwrap() {
+                        names.push(rec.read_name.clone().unwrap());
+                    }
+                    names
+                };
+                let plan = reader.plan_fetch_with_options(&region, &options).unwrap();
+                assert!(reads.lock().unwrap().is_empty());
+                assert!(plan.ranges().count() < all_blocks / 2, "{:?}", region);
+                if region.ref_id != UNPLACED_REF_ID {
+                    assert_eq!(plan, reader.plan_fetch(region.ref_id, region.start, region.end).unwrap());
+                }
+
+                let mut fetched = Vec::new();
+                {
+                    let mut records = reader.fetch_planned(&plan).unwrap();
+                    while let Some(rec) = records.next_rec().unwrap() {
+                        fetched.push(rec.read_name.clone().unwrap());
+                    }
+                }
+                assert_eq!(fetched, names(&mut expected), "{:?}", region);
+                // Every block read is in the plan.
+                for read in reads.lock().unwrap().drain(..) {
+                    assert!(plan.ranges().any(|range| *range == read), "{:?} {:?}", region, read);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_fetch_block_cache() {
+        let dir = TempDir::new("gbam_region").unwrap();
+        let path = dir.path().join("cached.gbam");
+        let mut writer = new_test_writer(&path, SORTED);
+        writer.set_rows_per_block(1_000);
+        for pos in (0..200_000).step_by(10) {
+            writer.push_record(&TestRecord::new(0, pos, &format!("r{}", pos)).to_raw(), false).unwrap();
+        }
+        writer.finish_with_summary(false).unwrap();
+
+        let decompressed = |reader: &Reader| -> u64 {
+            Fields::iterator().map(|field| reader.blocks_decompressed(field)).sum()
+        };
+        let fetch_all = |reader: &mut Reader| {
+            let mut fetched_recs = 0;
+            for i in 0..100 {
+                let mut fetched = reader.fetch(&Region::new(0, i * 500, i * 500 + 20_000)).unwrap();
+                while fetched.next_rec().unwrap().is_some() {
+                    fetched_recs += 1;
+                }
+            }
+            fetched_recs
+        };
+
+        let mut plain = open_test_file(&path);
+        let expected = fetch_all(&mut plain);
+        let mut cached = open_test_file(&path);
+        let n_blocks: u64 = Fields::iterator()
+            .map(|field| cached.file_meta.view_blocks(field).len() as u64)
+            .sum();
+        cached.set_block_cache_bytes(256 << 20);
+        assert_eq!(fetch_all(&mut cached), expected);
+        // Every block is decompressed once at most.
+        assert!(decompressed(&cached) <= n_blocks, "{} {}", decompressed(&cached), n_blocks);
+        assert!(decompressed(&plain) > 10 * decompressed(&cached));
+        let stats = cached.cache_stats();
+        assert!(stats.hits > 100 && stats.evictions == 0, "{:?}", stats);
+        assert!(stats.bytes > 0);
+
+        // A block of each column is just over the budget.
+        let budget = cached.file_meta.view_blocks(&Fields::ReadName)[0].uncompressed_size;
+        cached.clear_block_cache();
+        cached.set_block_cache_bytes(budget as usize);
+        assert_eq!(cached.cache_stats().bytes, 0);
+        assert_eq!(fetch_all(&mut cached), expected);
+        let stats = cached.cache_stats();
+        assert!(stats.evictions > 0 && stats.bytes <= budget, "{:?}", stats);
+
+        // Reopened file starts anew.
+        assert_eq!(open_test_file(&path).cache_stats(), Default::default());
+    }
+
+    #[test]
+    fn test_fetch_unsorted_file() {
+        let dir = TempDir::new("gbam_region").unwrap();
+        let path = dir.path().join("unsorted.gbam");
+        write_test_file(&path, "", &[TestRecord::default()]);
+
+        let mut reader = open_test_file(&path);
+        let err = reader.fetch(&Region::new(0, 0, 10)).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_fetch_many() {
+        let dir = TempDir::new("gbam_region").unwrap();
+        let path = dir.path().join("indexed.gbam");
+        let mut writer = new_test_writer(&path, SORTED);
+        writer.set_rows_per_block(1_000);
+        writer.set_linear_index(true);
+        for ref_id in 0..3 {
+            for pos in (0..500_000).step_by(50) {
+                let mut rec = TestRecord::new(ref_id, pos, &format!("r{}_{}", ref_id, pos));
+                if pos % 20_000 == 0 {
+                    rec.cigar = vec![30_000 << 4];
+                }
+                writer.push_record(&rec.to_raw(), false).unwrap();
+            }
+        }
+        writer.finish_with_summary(false).unwrap();
+
+        // Intervals overlap each other and long alignments.
+        let mut bed = String::new();
+        for i in 0..1_000 {
+            let start = i * 7_919 % 490_000;
+            bed.push_str(&format!("chr{}\t{}\t{}\n", i % 3 + 1, start, start + 50 + i * 31 % 400));
+        }
+        let intervals = crate::utils::bed::parse_bed(&mut bed.as_bytes()).unwrap();
+        let mut regions = Vec::new();
+        for (ref_id, (name, _)) in test_ref_seqs().iter().enumerate() {
+            for &(start, end) in &intervals[name] {
+                regions.push(Region::new(ref_id as i32, start as i32, end as i32));
+            }
+        }
+        assert_eq!(regions.len(), 1_000);
+
+        let mut reader = open_test_file(&path);
+        let mut expected = vec![Vec::new(); regions.len()];
+        for (index, region) in regions.iter().enumerate() {
+            let mut fetched = reader.fetch(region).unwrap();
+            while let Some(rec) = fetched.next_rec().unwrap() {
+                expected[index].push(rec.read_name.clone().unwrap());
+            }
+        }
+        let single_blocks = reader.blocks_decompressed(&Fields::Pos);
+
+        let mut reader = open_test_file(&path);
+        let plan = reader.plan_fetch_many(&regions, &FetchOptions::default()).unwrap();
+        assert!(plan.groups.len() < regions.len());
+        let mut names = vec![Vec::new(); regions.len()];
+        let mut rec_nums = Vec::new();
+        let mut fetched = reader.fetch_many(&regions).unwrap();
+        while let Some((index, rec)) = fetched.next_rec().unwrap() {
+            names[index].push(rec.read_name.clone().unwrap());
+            rec_nums.push(fetched.rec_num());
+        }
+        assert_eq!(names, expected);
+        assert!(rec_nums.windows(2).all(|pair| pair[0] <= pair[1]));
+        // Every block is decompressed once at most.
+        for (field, blocks) in &plan.blocks {
+            let decompressed = reader.blocks_decompressed(field);
+            assert!(decompressed <= blocks.len() as u64, "{} {} {}", field, decompressed, blocks.len());
+        }
+        assert!(reader.blocks_decompressed(&Fields::Pos) * 10 < single_blocks);
+
+        let options = FetchManyOptions {
+            dedupe: true,
+            ..Default::default()
+        };
+        let mut fetched = reader.fetch_many_with_options(&regions, &options).unwrap();
+        let mut unique = Vec::new();
+        while let Some((index, _)) = fetched.next_rec().unwrap() {
+            unique.push((fetched.rec_num(), index));
+        }
+        rec_nums.dedup();
+        assert_eq!(unique.iter().map(|(rec_num, _)| *rec_num).collect::<Vec<_>>(), rec_nums);
+        for (rec_num, index) in unique {
+            let mut rec = GbamRecord::default();
+            reader.fill_record(rec_num, &mut rec).unwrap();
+            let name = rec.read_name.unwrap();
+            assert_eq!(expected.iter().position(|names| names.contains(&name)), Some(index));
+        }
+    }
+}
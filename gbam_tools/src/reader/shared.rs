@@ -0,0 +1,120 @@
+//! Reader of one file shared by threads. Meta, file bytes, decryption keys
+//! and the block cache are parsed or allocated once, and each thread takes a
+//! cursor, a `Reader` with columns of its own, to run region fetches or
+//! scans on. File bytes are mapped or read at offsets, so cursors don't
+//! share a file position. Cursors are cheap to make, e.g. one per task of
+//! a rayon or tokio pool.
+use std::path::Path;
+use std::sync::Arc;
+
+use super::column::CacheStats;
+use super::parse_tmplt::ParsingTemplate;
+use super::reader::{Reader, ReaderParts};
+use crate::error::Result;
+use crate::meta::FileMeta;
+
+#[derive(Clone)]
+pub struct SharedReader {
+    parts: ReaderParts,
+}
+
+impl SharedReader {
+    /// Shares file of `reader`, cursors get its original parsing template
+    /// and decryption keys, as well as its block cache and reference.
+    pub fn new(reader: Reader) -> Self {
+        Self { parts: reader.parts() }
+    }
+
+    pub fn from_path<P: AsRef<Path>>(path: P, parsing_template: ParsingTemplate) -> Result<Self> {
+        Reader::from_path(path, parsing_template).map(Self::new)
+    }
+
+    /// Reader over the shared file, to be used by one thread. Blocks it
+    /// decompresses go to the shared cache when it moves on.
+    pub fn cursor(&self) -> Reader {
+        Reader::from_parts(self.parts.clone())
+    }
+
+    pub fn file_meta(&self) -> &Arc<FileMeta> {
+        &self.parts.file_meta
+    }
+
+    /// Budget of the block cache shared by all cursors, see
+    /// `Reader::set_block_cache_bytes()`.
+    pub fn set_block_cache_bytes(&self, bytes: usize) {
+        self.parts.block_cache.set_budget(bytes);
+    }
+
+    /// Block cache counters of all cursors.
+    pub fn cache_stats(&self) -> CacheStats {
+        self.parts.block_cache.stats()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reader::region::Region;
+    use crate::test_utils::{new_test_writer, open_test_file, TestRecord};
+    use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+    use tempdir::TempDir;
+
+    const SORTED: &str = "@HD\tVN:1.6\tSO:coordinate\n";
+
+    fn fetch_names(reader: &mut Reader, region: &Region) -> Vec<Vec<u8>> {
+        let mut names = Vec::new();
+        let mut records = reader.fetch(region).unwrap();
+        while let Some(rec) = records.next_rec().unwrap() {
+            names.push(rec.read_name.clone().unwrap());
+        }
+        names
+    }
+
+    #[test]
+    fn test_concurrent_fetch() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<SharedReader>();
+
+        let dir = TempDir::new("gbam_shared").unwrap();
+        let path = dir.path().join("shared.gbam");
+        let mut writer = new_test_writer(&path, SORTED);
+        writer.set_rows_per_block(500);
+        for ref_id in 0..3 {
+            for pos in (0..100_000).step_by(20) {
+                let name = format!("r{}_{}", ref_id, pos);
+                writer.push_record(&TestRecord::new(ref_id, pos, &name).to_raw(), false).unwrap();
+            }
+        }
+        writer.finish_with_summary(false).unwrap();
+
+        let mut rng = StdRng::seed_from_u64(349);
+        let regions: Vec<Region> = (0..64)
+            .map(|_| {
+                let start = rng.gen_range(0..100_000);
+                Region::new(rng.gen_range(0..3), start, start + rng.gen_range(100..10_000))
+            })
+            .collect();
+        let mut single = open_test_file(&path);
+        let expected: Vec<Vec<Vec<u8>>> =
+            regions.iter().map(|region| fetch_names(&mut single, region)).collect();
+        assert!(expected.iter().all(|names| !names.is_empty()));
+
+        let shared = SharedReader::new(open_test_file(&path));
+        shared.set_block_cache_bytes(4 << 20);
+        std::thread::scope(|scope| {
+            for thread in 0..16 {
+                let (shared, regions, expected) = (&shared, &regions, &expected);
+                scope.spawn(move || {
+                    let mut cursor = shared.cursor();
+                    let mut order: Vec<usize> = (0..regions.len()).collect();
+                    order.shuffle(&mut StdRng::seed_from_u64(thread));
+                    for i in order {
+                        assert_eq!(fetch_names(&mut cursor, &regions[i]), expected[i], "{:?}", regions[i]);
+                    }
+                });
+            }
+        });
+        let stats = shared.cache_stats();
+        assert!(stats.hits > 0 && stats.bytes <= 4 << 20, "{:?}", stats);
+    }
+}
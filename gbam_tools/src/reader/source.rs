@@ -0,0 +1,133 @@
+//! Storage read by blocks at given offsets, for files that can't be mapped,
+//! like ones on object storage.
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, Read};
+
+/// Storage holding a GBAM file. Reader asks for file info and meta at
+/// opening, then for whole compressed blocks, so implementations should make
+/// one request per call.
+pub trait ReadBlockAt: Send + Sync {
+    /// Reads exactly `len` bytes at `offset`.
+    fn read_at(&self, offset: u64, len: usize) -> io::Result<Vec<u8>>;
+
+    /// Size of the whole file.
+    fn size(&self) -> io::Result<u64>;
+}
+
+impl ReadBlockAt for File {
+    fn read_at(&self, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+        let mut buf = vec![0; len];
+        #[cfg(unix)]
+        std::os::unix::fs::FileExt::read_exact_at(self, &mut buf, offset)?;
+        #[cfg(windows)]
+        {
+            let mut read = 0;
+            while read < len {
+                match std::os::windows::fs::FileExt::seek_read(self, &mut buf[read..], offset + read as u64)? {
+                    0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+                    n => read += n,
+                }
+            }
+        }
+        Ok(buf)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.metadata()?.len())
+    }
+}
+
+impl<T: ReadBlockAt + ?Sized> ReadBlockAt for Box<T> {
+    fn read_at(&self, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+        (**self).read_at(offset, len)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        (**self).size()
+    }
+}
+
+/// Blocks kept while reading a file once from start to end, see
+/// `Reader::from_stream()`. Other bytes can't be read.
+pub(crate) struct StreamedBlocks {
+    blocks: BTreeMap<u64, Vec<u8>>,
+    size: u64,
+}
+
+impl StreamedBlocks {
+    /// Reads blocks at `ranges`, sorted by offset, from `stream` positioned
+    /// at `pos`, skipping bytes between them.
+    pub(crate) fn read<R: Read>(mut stream: R, mut pos: u64, ranges: &[(u64, u32)]) -> io::Result<Self> {
+        let mut blocks = BTreeMap::new();
+        for &(offset, len) in ranges {
+            if offset < pos {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Block at {} starts before the end of previous one at {}", offset, pos),
+                ));
+            }
+            let gap = offset - pos;
+            if io::copy(&mut (&mut stream).take(gap), &mut io::sink())? < gap {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            // Grows with bytes read, not with the size meta claims.
+            let mut block = Vec::new();
+            if (&mut stream).take(u64::from(len)).read_to_end(&mut block)? < len as usize {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            pos = offset + u64::from(len);
+            blocks.insert(offset, block);
+        }
+        Ok(Self { blocks, size: pos })
+    }
+}
+
+impl ReadBlockAt for StreamedBlocks {
+    fn read_at(&self, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+        match self.blocks.get(&offset) {
+            Some(block) if block.len() == len => Ok(block.clone()),
+            _ => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!(
+                    "{} bytes at {} were not kept from the stream, only blocks of fields of parsing \
+                     template are",
+                    len, offset
+                ),
+            )),
+        }
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reader::{parse_tmplt::ParsingTemplate, reader::Reader};
+    use crate::test_utils::{write_test_file, TestRecord};
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_read_file_by_blocks() {
+        let dir = TempDir::new("gbam_source").unwrap();
+        let path = dir.path().join("source.gbam");
+        let records: Vec<_> = (0..100).map(|i| TestRecord::new(0, i, &format!("r{}", i))).collect();
+        write_test_file(&path, "", &records);
+
+        let file = File::open(&path).unwrap();
+        assert_eq!(file.size().unwrap(), std::fs::metadata(&path).unwrap().len());
+        assert!(file.read_at(file.size().unwrap() - 1, 2).is_err());
+        let mut template = ParsingTemplate::new();
+        template.set_all();
+        let mut reader = Reader::from_source(file, template).unwrap();
+        let mut fetched = reader.records();
+        for i in 0..100 {
+            let rec = fetched.next_rec().unwrap().unwrap();
+            assert_eq!((rec.pos, rec.read_name.as_deref()), (Some(i), Some(format!("r{}\0", i).as_bytes())));
+        }
+        assert!(fetched.next_rec().unwrap().is_none());
+    }
+}
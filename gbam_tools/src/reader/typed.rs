@@ -0,0 +1,247 @@
+//! Decoding of fixed sized columns into typed values, with BAM sentinels
+//! (RefID -1, MAPQ 255) turned into None. Record parsing reads raw values
+//! with the same decoders, so both agree on byte layout.
+use std::convert::TryFrom;
+use std::marker::PhantomData;
+
+use bam_tools::record::fields::Fields;
+use byteorder::{ByteOrder, LittleEndian};
+
+use super::reader::Reader;
+use crate::error::{Result, TemplateError};
+
+/// Decoder of a fixed sized field.
+pub trait ColumnDecoder {
+    const FIELD: Fields;
+    /// Value as stored in BAM record.
+    type Raw: Copy;
+    type Value;
+
+    /// Reads little endian raw value from item bytes.
+    fn raw(bytes: &[u8]) -> Self::Raw;
+    /// Value of `raw`, None for sentinels.
+    fn value(raw: Self::Raw) -> Self::Value;
+
+    fn decode(bytes: &[u8]) -> Self::Value {
+        Self::value(Self::raw(bytes))
+    }
+}
+
+/// 0-based position, -1 for unplaced records.
+pub struct PosColumn;
+
+impl ColumnDecoder for PosColumn {
+    const FIELD: Fields = Fields::Pos;
+    type Raw = i32;
+    type Value = i32;
+
+    fn raw(bytes: &[u8]) -> i32 {
+        LittleEndian::read_i32(bytes)
+    }
+
+    fn value(raw: i32) -> i32 {
+        raw
+    }
+}
+
+/// Reference id, None for unplaced records (-1).
+pub struct RefIdColumn;
+
+impl ColumnDecoder for RefIdColumn {
+    const FIELD: Fields = Fields::RefID;
+    type Raw = i32;
+    type Value = Option<u32>;
+
+    fn raw(bytes: &[u8]) -> i32 {
+        LittleEndian::read_i32(bytes)
+    }
+
+    fn value(raw: i32) -> Option<u32> {
+        u32::try_from(raw).ok()
+    }
+}
+
+/// Mapping quality, None if unavailable (255).
+pub struct MapqColumn;
+
+impl ColumnDecoder for MapqColumn {
+    const FIELD: Fields = Fields::Mapq;
+    type Raw = u8;
+    type Value = Option<u8>;
+
+    fn raw(bytes: &[u8]) -> u8 {
+        bytes[0]
+    }
+
+    fn value(raw: u8) -> Option<u8> {
+        match raw {
+            255 => None,
+            mapq => Some(mapq),
+        }
+    }
+}
+
+/// Bitwise flags.
+pub struct FlagsColumn;
+
+impl ColumnDecoder for FlagsColumn {
+    const FIELD: Fields = Fields::Flags;
+    type Raw = u16;
+    type Value = u16;
+
+    fn raw(bytes: &[u8]) -> u16 {
+        LittleEndian::read_u16(bytes)
+    }
+
+    fn value(raw: u16) -> u16 {
+        raw
+    }
+}
+
+/// Iterates over values of a column decoded by `D`, in record order.
+/// Created by [`Reader::typed_column`].
+pub struct TypedColumn<'a, D: ColumnDecoder> {
+    reader: &'a mut Reader,
+    cur_rec: usize,
+    decoder: PhantomData<D>,
+}
+
+impl<'a, D: ColumnDecoder> TypedColumn<'a, D> {
+    /// Decodes values of following records into `out`, up to its length.
+    /// Returns amount of values written, less than the length only at the
+    /// end of the file. Fails like `Reader::fill_record()`, values of
+    /// records before the failed one are written.
+    pub fn next_batch(&mut self, out: &mut [D::Value]) -> Result<usize> {
+        let count = out.len().min(self.reader.amount - self.cur_rec);
+        for slot in out[..count].iter_mut() {
+            let rec_num = self.reader.physical_rec_num(self.cur_rec)?;
+            *slot = D::decode(self.reader.get_column(&D::FIELD)?.raw_item(rec_num)?);
+            self.cur_rec += 1;
+        }
+        Ok(count)
+    }
+}
+
+impl<'a, D: ColumnDecoder> Iterator for TypedColumn<'a, D> {
+    type Item = Result<D::Value>;
+
+    fn next(&mut self) -> Option<Result<D::Value>> {
+        if self.cur_rec == self.reader.amount {
+            return None;
+        }
+        let rec_num = self.cur_rec;
+        self.cur_rec += 1;
+        let value = self
+            .reader
+            .physical_rec_num(rec_num)
+            .and_then(|rec_num| self.reader.get_column(&D::FIELD)?.raw_item(rec_num).map(D::decode));
+        Some(value)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let left = self.reader.amount - self.cur_rec;
+        (left, Some(left))
+    }
+}
+
+impl<'a, D: ColumnDecoder> ExactSizeIterator for TypedColumn<'a, D> {}
+
+impl Reader {
+    /// Get iterator over values of one fixed sized column, like
+    /// `reader.typed_column::<MapqColumn>()`. Only the column is read, its
+    /// field has to be enabled in parsing template.
+    pub fn typed_column<D: ColumnDecoder>(&mut self) -> Result<TypedColumn<'_, D>> {
+        if !self.parsing_template.check_if_active(&[D::FIELD]) {
+            return Err(TemplateError::FieldNotOpened { field: D::FIELD }.into());
+        }
+        self.start_query();
+        Ok(TypedColumn {
+            reader: self,
+            cur_rec: 0,
+            decoder: PhantomData,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reader::parse_tmplt::ParsingTemplate;
+    use crate::test_utils::{write_test_file, TestRecord};
+    use tempdir::TempDir;
+
+    fn records() -> Vec<TestRecord> {
+        (0..3_000)
+            .map(|i| {
+                if i % 10 == 9 {
+                    let mut rec = TestRecord::new(-1, -1, &format!("unmapped{}", i));
+                    rec.flag = 0x4;
+                    rec.mapq = 255;
+                    rec
+                } else {
+                    let mut rec = TestRecord::new(i / 1_000, i, &format!("read{}", i));
+                    rec.flag = 0x10 * (i % 2) as u16;
+                    rec.mapq = (i % 61) as u8;
+                    rec
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_typed_columns() {
+        let dir = TempDir::new("gbam_typed").unwrap();
+        let path = dir.path().join("typed.gbam");
+        let records = records();
+        write_test_file(&path, "", &records);
+
+        let template = ParsingTemplate::new_with(&[Fields::RefID, Fields::Pos, Fields::Mapq, Fields::Flags]);
+        let mut reader = Reader::from_path(&path, template).unwrap();
+        let pos: Vec<i32> = reader.typed_column::<PosColumn>().unwrap().collect::<Result<_>>().unwrap();
+        let ref_ids: Vec<Option<u32>> = reader.typed_column::<RefIdColumn>().unwrap().collect::<Result<_>>().unwrap();
+        let mapqs: Vec<Option<u8>> = reader.typed_column::<MapqColumn>().unwrap().collect::<Result<_>>().unwrap();
+        let flags: Vec<u16> = reader.typed_column::<FlagsColumn>().unwrap().collect::<Result<_>>().unwrap();
+        for (i, rec) in records.iter().enumerate() {
+            assert_eq!(pos[i], rec.pos);
+            assert_eq!(ref_ids[i], u32::try_from(rec.refid).ok());
+            assert_eq!(mapqs[i], Some(rec.mapq).filter(|&mapq| mapq != 255));
+            assert_eq!(flags[i], rec.flag);
+        }
+        assert_eq!(pos[9], -1);
+        assert_eq!(ref_ids[9], None);
+        assert_eq!(mapqs[9], None);
+
+        // Records keep raw values.
+        let mut fetched = reader.records();
+        for _ in 0..9 {
+            fetched.next_rec().unwrap().unwrap();
+        }
+        let rec = fetched.next_rec().unwrap().unwrap();
+        assert_eq!((rec.refid, rec.pos, rec.mapq), (Some(-1), Some(-1), Some(255)));
+    }
+
+    #[test]
+    fn test_typed_column_batches() {
+        let dir = TempDir::new("gbam_typed").unwrap();
+        let path = dir.path().join("typed.gbam");
+        let records = records();
+        write_test_file(&path, "", &records);
+
+        let mut reader = Reader::from_path(&path, ParsingTemplate::new_with(&[Fields::Mapq])).unwrap();
+        let mut column = reader.typed_column::<MapqColumn>().unwrap();
+        let mut batch = [None; 256];
+        let mut mapqs = Vec::new();
+        loop {
+            let count = column.next_batch(&mut batch).unwrap();
+            mapqs.extend_from_slice(&batch[..count]);
+            if count < batch.len() {
+                break;
+            }
+        }
+        assert_eq!(mapqs.len(), records.len());
+        assert!(mapqs.iter().zip(&records).all(|(&mapq, rec)| mapq == Some(rec.mapq).filter(|&m| m != 255)));
+
+        let err = reader.typed_column::<PosColumn>().err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    }
+}
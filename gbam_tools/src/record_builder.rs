@@ -0,0 +1,516 @@
+//! Records built from typed values, for simulators and test harnesses which
+//! write GBAM without BAM input. `GbamRecordBuilder::build()` encodes every
+//! field once, as BAM lays it out, and checks the record the way the writer
+//! validates records. `Writer::push_built_record()` then copies the fields
+//! into their columns with no further encoding, and write options apply to
+//! built records as to any other.
+use std::convert::TryFrom;
+use std::io;
+
+use bam_tools::record::bamrawrecord::BAMRawRecord;
+use bam_tools::record::fields::Fields;
+use byteorder::{LittleEndian, WriteBytesExt};
+
+use crate::bam::index::{reg2bin, BAI_DEPTH, BAI_MIN_SHIFT};
+use crate::utils::seq::encode_bases;
+use crate::validation::validate_record;
+
+/// Cigar operations by their BAM code.
+const CIGAR_OPS: &[u8; 9] = b"MIDNSHP=X";
+/// Bases by their BAM code.
+const BASES: &[u8; 16] = b"=ACMGRSVTWYHKDBN";
+const BAM_FUNMAP: u16 = 4;
+
+/// Typed fields of a record. Unset fields are those of an unmapped read
+/// without sequence: no reference, position -1, flag 0x4, mapq 255. Bin is
+/// computed from position and cigar unless set.
+#[derive(Clone, Debug)]
+pub struct GbamRecordBuilder {
+    ref_id: i32,
+    pos: i32,
+    mapq: u8,
+    bin: Option<u16>,
+    flags: u16,
+    next_ref_id: i32,
+    next_pos: i32,
+    tlen: i32,
+    read_name: Vec<u8>,
+    cigar: Vec<(u32, char)>,
+    seq: Vec<u8>,
+    qual: Option<Vec<u8>>,
+    tags: Vec<u8>,
+}
+
+impl Default for GbamRecordBuilder {
+    fn default() -> Self {
+        Self {
+            ref_id: -1,
+            pos: -1,
+            mapq: 255,
+            bin: None,
+            flags: BAM_FUNMAP,
+            next_ref_id: -1,
+            next_pos: -1,
+            tlen: 0,
+            read_name: b"*".to_vec(),
+            cigar: Vec::new(),
+            seq: Vec::new(),
+            qual: None,
+            tags: Vec::new(),
+        }
+    }
+}
+
+impl GbamRecordBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Name without the terminating NUL.
+    pub fn read_name(mut self, name: &[u8]) -> Self {
+        self.read_name = name.to_vec();
+        self
+    }
+
+    pub fn flags(mut self, flags: u16) -> Self {
+        self.flags = flags;
+        self
+    }
+
+    pub fn ref_id(mut self, ref_id: i32) -> Self {
+        self.ref_id = ref_id;
+        self
+    }
+
+    /// 0-based leftmost position.
+    pub fn pos(mut self, pos: i32) -> Self {
+        self.pos = pos;
+        self
+    }
+
+    pub fn mapq(mut self, mapq: u8) -> Self {
+        self.mapq = mapq;
+        self
+    }
+
+    pub fn bin(mut self, bin: u16) -> Self {
+        self.bin = Some(bin);
+        self
+    }
+
+    pub fn next_ref_id(mut self, ref_id: i32) -> Self {
+        self.next_ref_id = ref_id;
+        self
+    }
+
+    pub fn next_pos(mut self, pos: i32) -> Self {
+        self.next_pos = pos;
+        self
+    }
+
+    pub fn tlen(mut self, tlen: i32) -> Self {
+        self.tlen = tlen;
+        self
+    }
+
+    /// Operations as (length, op) pairs, op being one of "MIDNSHP=X".
+    pub fn cigar(mut self, ops: &[(u32, char)]) -> Self {
+        self.cigar = ops.to_vec();
+        self
+    }
+
+    /// Bases, one of "=ACMGRSVTWYHKDBN" each, in either case.
+    pub fn seq(self, bases: &str) -> Self {
+        self.seq_bytes(bases.as_bytes())
+    }
+
+    pub fn seq_bytes(mut self, bases: &[u8]) -> Self {
+        self.seq = bases.to_vec();
+        self
+    }
+
+    /// Phred qualities, not offset by 33. Missing qualities are 0xff bytes.
+    pub fn qual(mut self, qual: &[u8]) -> Self {
+        self.qual = Some(qual.to_vec());
+        self
+    }
+
+    pub fn tags(mut self, tags: TagsBuilder) -> Self {
+        self.tags = tags.data;
+        self
+    }
+
+    /// Encodes the record. Fails with InvalidInput on values BAM can't hold
+    /// and on records `validate_record()` rejects, except for reference ids
+    /// past the header, which the writer checks.
+    pub fn build(&self) -> io::Result<BuiltRecord> {
+        if self.read_name.is_empty() || self.read_name.len() > 254 || self.read_name.contains(&0) {
+            return Err(invalid_input(format!(
+                "Read name {:?} must be 1 to 254 bytes long, without NUL",
+                String::from_utf8_lossy(&self.read_name)
+            )));
+        }
+        let n_cigar = u16::try_from(self.cigar.len())
+            .map_err(|_| invalid_input(format!("Cigar has {} operations", self.cigar.len())))?;
+        let mut cigar = Vec::with_capacity(self.cigar.len());
+        for &(len, op) in &self.cigar {
+            let code = CIGAR_OPS.iter().position(|&c| char::from(c) == op);
+            match code {
+                Some(code) if len < (1 << 28) => cigar.push((len << 4) | code as u32),
+                _ => return Err(invalid_input(format!("Invalid cigar operation {}{}", len, op))),
+            }
+        }
+        if let Some(base) = self.seq.iter().find(|base| !BASES.contains(&base.to_ascii_uppercase())) {
+            return Err(invalid_input(format!("Invalid base {:?}", char::from(*base))));
+        }
+        let l_seq = self.seq.len();
+        if let Some(qual) = self.qual.as_ref().filter(|qual| qual.len() != l_seq) {
+            return Err(invalid_input(format!(
+                "{} qualities for {} bases",
+                qual.len(),
+                l_seq
+            )));
+        }
+        let bin = self.bin.unwrap_or_else(|| self.computed_bin(&cigar));
+
+        let mut bytes = Vec::new();
+        bytes.write_i32::<LittleEndian>(self.ref_id)?;
+        bytes.write_i32::<LittleEndian>(self.pos)?;
+        bytes.write_u8(self.read_name.len() as u8 + 1)?;
+        bytes.write_u8(self.mapq)?;
+        bytes.write_u16::<LittleEndian>(bin)?;
+        bytes.write_u16::<LittleEndian>(n_cigar)?;
+        bytes.write_u16::<LittleEndian>(self.flags)?;
+        bytes.write_u32::<LittleEndian>(l_seq as u32)?;
+        bytes.write_i32::<LittleEndian>(self.next_ref_id)?;
+        bytes.write_i32::<LittleEndian>(self.next_pos)?;
+        bytes.write_i32::<LittleEndian>(self.tlen)?;
+        bytes.extend_from_slice(&self.read_name);
+        bytes.push(0);
+        for op in cigar {
+            bytes.write_u32::<LittleEndian>(op)?;
+        }
+        let seq_start = bytes.len();
+        bytes.resize(seq_start + l_seq.div_ceil(2), 0);
+        encode_bases(&self.seq, &mut bytes[seq_start..]);
+        match &self.qual {
+            Some(qual) => bytes.extend_from_slice(qual),
+            None => bytes.resize(bytes.len() + l_seq, 0xff),
+        }
+        bytes.extend_from_slice(&self.tags);
+
+        let raw = BAMRawRecord::from(bytes);
+        validate_record(&raw, i32::MAX as usize)
+            .map_err(|violation| invalid_input(format!("Record is invalid: {}", violation)))?;
+        Ok(BuiltRecord { raw })
+    }
+
+    // Bin of the reference span, of one base for unmapped reads.
+    fn computed_bin(&self, cigar: &[u32]) -> u16 {
+        let span: i64 = cigar
+            .iter()
+            .filter(|&&op| matches!(op & 0xf, 0 | 2 | 3 | 7 | 8))
+            .map(|&op| i64::from(op >> 4))
+            .sum();
+        let beg = i64::from(self.pos);
+        reg2bin(beg, beg + span.max(1), BAI_MIN_SHIFT, BAI_DEPTH) as u16
+    }
+}
+
+fn invalid_input(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+/// Record encoded by `GbamRecordBuilder`, see `Writer::push_built_record()`.
+#[derive(Clone, Debug)]
+pub struct BuiltRecord {
+    raw: BAMRawRecord<'static>,
+}
+
+impl BuiltRecord {
+    /// Encoding of `field`, as stored in its column.
+    pub fn field_bytes(&self, field: &Fields) -> &[u8] {
+        self.raw.get_bytes(field)
+    }
+
+    pub(crate) fn raw(&self) -> &BAMRawRecord<'static> {
+        &self.raw
+    }
+}
+
+/// Auxiliary data of a record, tags in the order they are added.
+#[derive(Clone, Debug, Default)]
+pub struct TagsBuilder {
+    data: Vec<u8>,
+}
+
+impl TagsBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A type tag.
+    pub fn char(mut self, tag: [u8; 2], value: u8) -> Self {
+        self.push(tag, b'A');
+        self.data.push(value);
+        self
+    }
+
+    /// Integer stored in the smallest type holding it, as samtools does.
+    /// Panics if the value doesn't fit into i32 or u32.
+    pub fn int(mut self, tag: [u8; 2], value: i64) -> Self {
+        if value < 0 {
+            if value >= i64::from(i8::MIN) {
+                self.push(tag, b'c');
+                self.data.write_i8(value as i8).unwrap();
+            } else if value >= i64::from(i16::MIN) {
+                self.push(tag, b's');
+                self.data.write_i16::<LittleEndian>(value as i16).unwrap();
+            } else {
+                let value = i32::try_from(value).expect("Tag value doesn't fit into i32");
+                self.push(tag, b'i');
+                self.data.write_i32::<LittleEndian>(value).unwrap();
+            }
+        } else if value <= i64::from(u8::MAX) {
+            self.push(tag, b'C');
+            self.data.push(value as u8);
+        } else if value <= i64::from(u16::MAX) {
+            self.push(tag, b'S');
+            self.data.write_u16::<LittleEndian>(value as u16).unwrap();
+        } else {
+            let value = u32::try_from(value).expect("Tag value doesn't fit into u32");
+            self.push(tag, b'I');
+            self.data.write_u32::<LittleEndian>(value).unwrap();
+        }
+        self
+    }
+
+    pub fn float(mut self, tag: [u8; 2], value: f32) -> Self {
+        self.push(tag, b'f');
+        self.data.write_f32::<LittleEndian>(value).unwrap();
+        self
+    }
+
+    /// Z type tag. Panics if the value contains NUL.
+    pub fn string(mut self, tag: [u8; 2], value: &str) -> Self {
+        assert!(!value.as_bytes().contains(&0), "Tag string contains NUL");
+        self.push(tag, b'Z');
+        self.data.extend_from_slice(value.as_bytes());
+        self.data.push(0);
+        self
+    }
+
+    /// B type tag of i32 items.
+    pub fn int_array(mut self, tag: [u8; 2], values: &[i32]) -> Self {
+        self.push_array_header(tag, b'i', values.len());
+        for &value in values {
+            self.data.write_i32::<LittleEndian>(value).unwrap();
+        }
+        self
+    }
+
+    /// H type tag. Panics unless the value is an even number of hex digits.
+    pub fn hex(mut self, tag: [u8; 2], value: &str) -> Self {
+        assert!(is_hex(value), "Tag value is not a hex string");
+        self.push(tag, b'H');
+        self.data.extend_from_slice(value.as_bytes());
+        self.data.push(0);
+        self
+    }
+
+    /// B type tag of integer items of `item_type`, one of "cCsSiI", as SAM
+    /// text gives it. Panics on other types and values the type can't hold.
+    pub fn typed_int_array(mut self, tag: [u8; 2], item_type: u8, values: &[i64]) -> Self {
+        assert!(b"cCsSiI".contains(&item_type), "Invalid tag array type");
+        self.push_array_header(tag, item_type, values.len());
+        for &value in values {
+            assert!(fits_int_type(item_type, value), "Tag value doesn't fit into its type");
+            match item_type {
+                b'c' => self.data.write_i8(value as i8),
+                b'C' => self.data.write_u8(value as u8),
+                b's' => self.data.write_i16::<LittleEndian>(value as i16),
+                b'S' => self.data.write_u16::<LittleEndian>(value as u16),
+                b'i' => self.data.write_i32::<LittleEndian>(value as i32),
+                _ => self.data.write_u32::<LittleEndian>(value as u32),
+            }
+            .unwrap();
+        }
+        self
+    }
+
+    /// B type tag of f32 items.
+    pub fn float_array(mut self, tag: [u8; 2], values: &[f32]) -> Self {
+        self.push_array_header(tag, b'f', values.len());
+        for &value in values {
+            self.data.write_f32::<LittleEndian>(value).unwrap();
+        }
+        self
+    }
+
+    /// Tags encoded as in BAM.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    fn push(&mut self, tag: [u8; 2], val_type: u8) {
+        self.data.extend_from_slice(&tag);
+        self.data.push(val_type);
+    }
+
+    fn push_array_header(&mut self, tag: [u8; 2], item_type: u8, len: usize) {
+        self.push(tag, b'B');
+        self.data.push(item_type);
+        let len = u32::try_from(len).expect("Too many tag array items");
+        self.data.write_u32::<LittleEndian>(len).unwrap();
+    }
+}
+
+/// Checks if integer tag type `item_type` holds `value`.
+pub(crate) fn fits_int_type(item_type: u8, value: i64) -> bool {
+    match item_type {
+        b'c' => i8::try_from(value).is_ok(),
+        b'C' => u8::try_from(value).is_ok(),
+        b's' => i16::try_from(value).is_ok(),
+        b'S' => u16::try_from(value).is_ok(),
+        b'i' => i32::try_from(value).is_ok(),
+        b'I' => u32::try_from(value).is_ok(),
+        _ => false,
+    }
+}
+
+/// Checks if `value` is an even number of hex digits, as H type tags hold.
+pub(crate) fn is_hex(value: &str) -> bool {
+    value.len().is_multiple_of(2) && value.bytes().all(|c| c.is_ascii_hexdigit())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::query::cigar::Op;
+    use crate::tags::TagValue;
+    use crate::test_utils::{new_test_writer, open_test_file, TestRecord};
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_built_records_round_trip() {
+        let tags = TagsBuilder::new()
+            .int(*b"NM", 3)
+            .int(*b"XS", -70_000)
+            .string(*b"RG", "grp1")
+            .char(*b"XT", b'U')
+            .float(*b"XF", 0.5)
+            .int_array(*b"ZB", &[1, -2, 3]);
+        let records = vec![
+            GbamRecordBuilder::new()
+                .read_name(b"mapped")
+                .flags(0x63)
+                .ref_id(1)
+                .pos(1_000)
+                .mapq(42)
+                .next_ref_id(1)
+                .next_pos(1_200)
+                .tlen(250)
+                .cigar(&[(3, 'S'), (5, 'M'), (2, 'D'), (2, 'I')])
+                .seq("ACGTNacgta")
+                .qual(&[10, 20, 30, 40, 35, 25, 15, 5, 0, 41])
+                .tags(tags.clone()),
+            // Sequence and qualities omitted, '*' in SAM.
+            GbamRecordBuilder::new().read_name(b"no_seq").ref_id(0).pos(7).flags(0).cigar(&[(9, 'M')]),
+            // Qualities omitted, odd length.
+            GbamRecordBuilder::new().read_name(b"unmapped").seq("ACGTA"),
+        ];
+
+        let dir = TempDir::new("gbam_record_builder").unwrap();
+        let path = dir.path().join("built.gbam");
+        let mut writer = new_test_writer(&path, "");
+        for record in &records {
+            writer.push_built_record(&record.build().unwrap(), false).unwrap();
+        }
+        writer.finish_with_summary(false).unwrap();
+
+        let mut reader = open_test_file(&path);
+        let mut fetched = reader.records();
+        let rec = fetched.next_rec().unwrap().unwrap().clone();
+        assert_eq!((rec.refid, rec.pos, rec.mapq, rec.flag), (Some(1), Some(1_000), Some(42), Some(0x63)));
+        assert_eq!((rec.next_ref_id, rec.next_pos, rec.tlen), (Some(1), Some(1_200), Some(250)));
+        assert_eq!(rec.read_name.as_deref(), Some(&b"mapped\0"[..]));
+        let ops: Vec<u32> = rec.cigar.as_ref().unwrap().0.iter().map(|op| op.0).collect();
+        assert_eq!(ops, vec![(3 << 4) | 4, 5 << 4, (2 << 4) | 2, (2 << 4) | 1]);
+        assert_eq!(rec.seq.as_deref(), Some("ACGTNACGTA"));
+        assert_eq!(rec.qual.as_deref(), Some(&[10, 20, 30, 40, 35, 25, 15, 5, 0, 41][..]));
+        assert_eq!(rec.tags.as_deref(), Some(tags.as_bytes()));
+        assert_eq!(rec.get_tag(b"NM").unwrap(), Some(TagValue::UInt8(3)));
+        assert_eq!(rec.get_tag(b"XS").unwrap(), Some(TagValue::Int32(-70_000)));
+        assert_eq!(rec.get_tag(b"RG").unwrap(), Some(TagValue::String(b"grp1")));
+        assert_eq!(rec.get_tag(b"XT").unwrap(), Some(TagValue::Char(b'U')));
+        assert_eq!(rec.get_tag(b"XF").unwrap(), Some(TagValue::Float(0.5)));
+        let array = rec.get_tag(b"ZB").unwrap().unwrap().as_array().unwrap();
+        assert_eq!(array.iter().map(|v| v.as_int().unwrap()).collect::<Vec<_>>(), vec![1, -2, 3]);
+        // Span of 7 bases, M and D.
+        assert_eq!(rec.bin, Some(reg2bin(1_000, 1_007, BAI_MIN_SHIFT, BAI_DEPTH) as u16));
+        assert_eq!(rec.alignment_span(), 7);
+
+        let rec = fetched.next_rec().unwrap().unwrap();
+        assert_eq!(rec.read_name.as_deref(), Some(&b"no_seq\0"[..]));
+        assert_eq!(rec.cigar.as_ref().unwrap().0, vec![Op::new(9 << 4)]);
+        assert_eq!(rec.seq.as_deref(), Some(""));
+        assert_eq!(rec.qual.as_deref(), Some(&[][..]));
+
+        let rec = fetched.next_rec().unwrap().unwrap();
+        assert_eq!((rec.refid, rec.pos, rec.mapq, rec.flag), (Some(-1), Some(-1), Some(255), Some(4)));
+        assert_eq!(rec.bin, Some(4680));
+        assert_eq!(rec.seq.as_deref(), Some("ACGTA"));
+        assert_eq!(rec.qual.as_deref(), Some(&[0xff; 5][..]));
+        assert!(fetched.next_rec().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_same_bytes_as_bam_records() {
+        let mut expected = TestRecord::new(2, 500, "read7");
+        expected.flag = 16;
+        expected.cigar = vec![(2 << 4) | 4, 6 << 4];
+        expected.seq = String::from("ACGTTGCA");
+        expected.qual = vec![30, 31, 32, 33, 34, 35, 36, 37];
+        expected.tags = TagsBuilder::new().int(*b"NM", 1).as_bytes().to_vec();
+        let built = GbamRecordBuilder::new()
+            .read_name(b"read7")
+            .ref_id(2)
+            .pos(500)
+            .mapq(expected.mapq)
+            .bin(expected.bin)
+            .flags(16)
+            .cigar(&[(2, 'S'), (6, 'M')])
+            .seq("ACGTTGCA")
+            .qual(&expected.qual)
+            .tags(TagsBuilder::new().int(*b"NM", 1))
+            .build()
+            .unwrap();
+        let raw = expected.to_raw();
+        assert_eq!(built.raw()[..], raw[..]);
+        for field in [Fields::ReadName, Fields::RawCigar, Fields::RawSequence, Fields::RawTags] {
+            assert_eq!(built.field_bytes(&field), raw.get_bytes(&field), "{}", field);
+        }
+    }
+
+    #[test]
+    fn test_invalid_records() {
+        let mapped = || GbamRecordBuilder::new().read_name(b"r").ref_id(0).pos(10).flags(0);
+        let invalid = [
+            mapped().qual(&[30, 30]).seq("ACG"),
+            mapped().cigar(&[(4, 'Q')]),
+            mapped().seq("ACGZ"),
+            mapped().read_name(b""),
+            mapped().read_name(b"a\0b"),
+            // Rejected by the write-time validator.
+            mapped().cigar(&[(4, 'M')]).seq("ACG"),
+            mapped().ref_id(-1),
+            mapped().pos(-1),
+            GbamRecordBuilder::new().pos(5),
+        ];
+        for (i, builder) in invalid.iter().enumerate() {
+            let err = builder.build().unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput, "{}: {}", i, err);
+        }
+        assert!(mapped().cigar(&[(4, 'M')]).seq("ACGT").build().is_ok());
+    }
+}
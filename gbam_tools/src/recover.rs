@@ -0,0 +1,252 @@
+//! Recovery of GBAM files whose writing was interrupted in
+//! `Writer::finish_with_summary()`.
+//!
+//! Writer puts meta JSON at the end of file, prefixed with its crc32 and
+//! length, and overwrites file info only after meta is synced. So a file with
+//! placeholder or damaged file info may still have complete meta, which can be
+//! found by scanning from the end of file. Meta snapshots written by
+//! `Writer::flush_all_columns()` may be followed by more blocks, the last
+//! complete meta is used and whatever follows it is cut off. Meta written to
+//! space reserved after file info, see `Writer::reserve_meta_bytes()`, is
+//! found last, and is followed by its blocks, which are kept.
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+use bam_tools::record::fields::Fields;
+
+use crate::layout::MetaPrefix;
+use crate::meta::{FileInfo, FileMeta, SortOrder, FILE_INFO_SIZE, META_PREFIX_SIZE};
+use crate::reader::reader::MAX_META_SIZE;
+use crate::writer::calc_crc_for_meta_bytes;
+use crate::GBAM_VERSION;
+
+/// Bytes scanned for meta prefixes at a time. Meta following a prefix is read
+/// on its own, up to `MAX_META_SIZE` bytes.
+const SCAN_WINDOW: usize = 1 << 20;
+
+/// Looks for the last meta in `file`, truncates the file after it and
+/// rewrites file info to point to it. Returns position of meta. File must be opened for reading and writing.
+pub fn recover_meta(file: &mut File) -> io::Result<u64> {
+    let (seekpos, meta_len, crc32, meta) = find_meta(file, SCAN_WINDOW)?.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            "No complete meta found at the end of file, writing was interrupted before \
+             meta was written. The file can't be recovered.",
+        )
+    })?;
+
+    let mut file_info = FileInfo::new(
+        GBAM_VERSION,
+        seekpos,
+        crc32,
+        "recovered".to_owned(),
+        meta.get_sort_order() == SortOrder::Coordinate,
+    );
+    let blocks_start = Fields::iterator()
+        .flat_map(|field| meta.view_blocks(field))
+        .map(|block| block.seekpos)
+        .min();
+    match blocks_start.filter(|&start| start > seekpos) {
+        Some(start) => file_info.reserved_meta = start - FILE_INFO_SIZE as u64,
+        None => file.set_len(seekpos + meta_len as u64)?,
+    }
+    file.seek(SeekFrom::Start(0))?;
+    file.write_all(&file_info.to_padded_bytes()?)?;
+    file.sync_data()?;
+    Ok(seekpos)
+}
+
+/// Scans backwards for position where meta starts: length before it fits
+/// into the rest of file and `MAX_META_SIZE`, crc32 matches and JSON parses.
+/// Prefixes are read `window` bytes at a time from the end of file, meta of
+/// a fitting length on its own unless the window holds it.
+fn find_meta(file: &mut File, window: usize) -> io::Result<Option<(u64, usize, u32, FileMeta)>> {
+    let file_len = file.seek(SeekFrom::End(0))?;
+    let min_pos = (FILE_INFO_SIZE + META_PREFIX_SIZE) as u64;
+    let (mut buf, mut meta_buf) = (Vec::new(), Vec::new());
+    // Positions below `end` are left to check.
+    let mut end = file_len;
+    while end > min_pos {
+        let lo = end.saturating_sub(window as u64).max(min_pos);
+        let start = lo - META_PREFIX_SIZE as u64;
+        read_at(file, start, (end - start) as usize, &mut buf)?;
+        for pos in (lo..end).rev() {
+            let offset = (pos - start) as usize;
+            let prefix = &buf[offset - META_PREFIX_SIZE..offset];
+            let MetaPrefix { crc32, len } = match MetaPrefix::decode(prefix) {
+                Some(prefix) => prefix,
+                None => continue,
+            };
+            if len == 0 || len > MAX_META_SIZE || len > file_len - pos {
+                continue;
+            }
+            let meta_bytes = if pos + len <= end {
+                &buf[offset..offset + len as usize]
+            } else {
+                read_at(file, pos, len as usize, &mut meta_buf)?;
+                &meta_buf[..]
+            };
+            if calc_crc_for_meta_bytes(meta_bytes) != crc32 {
+                continue;
+            }
+            if let Ok(meta) = serde_json::from_slice(meta_bytes) {
+                return Ok(Some((pos, meta_bytes.len(), crc32, meta)));
+            }
+        }
+        end = lo;
+    }
+    Ok(None)
+}
+
+/// Reads `len` bytes at `offset` of `file` into `buf`.
+fn read_at(file: &mut File, offset: u64, len: usize, buf: &mut Vec<u8>) -> io::Result<()> {
+    buf.resize(len, 0);
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reader::parse_tmplt::ParsingTemplate;
+    use crate::reader::reader::Reader;
+    use crate::test_utils::{open_test_file, write_test_file, TestRecord};
+    use bam_tools::record::fields::Fields;
+    use std::fs::OpenOptions;
+    use tempdir::TempDir;
+
+    fn records() -> Vec<TestRecord> {
+        (0..1000)
+            .map(|i| TestRecord::new(i % 3, i * 10, &format!("r{}", i)))
+            .collect()
+    }
+
+    fn open_err(path: &std::path::Path) -> String {
+        let file = File::open(path).unwrap();
+        match Reader::new(file, ParsingTemplate::new_with(&[])) {
+            Ok(_) => panic!("damaged file was opened"),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    /// Simulates crash before file info was overwritten by putting back
+    /// placeholder written by `Writer::new()`, or zeros.
+    fn crash(path: &std::path::Path, file_info: &[u8]) {
+        let mut file = OpenOptions::new().write(true).open(path).unwrap();
+        file.write_all(file_info).unwrap();
+    }
+
+    #[test]
+    fn test_recover_meta() {
+        let tmp_dir = TempDir::new("gbam_recover").unwrap();
+        let path = tmp_dir.path().join("crashed.gbam");
+        let recs = records();
+        let placeholder = FileInfo::new([1, 0], 0, 0, String::new(), false)
+            .to_padded_bytes()
+            .unwrap();
+
+        for file_info in [placeholder, vec![0; FILE_INFO_SIZE]] {
+            write_test_file(&path, "", &recs);
+            crash(&path, &file_info);
+            assert!(open_err(&path).contains("recover_meta"));
+
+            let mut file = OpenOptions::new().read(true).write(true).open(&path).unwrap();
+            recover_meta(&mut file).unwrap();
+
+            let mut reader = open_test_file(&path);
+            let mut records = reader.records();
+            let mut n = 0;
+            while let Some(rec) = records.next_rec().unwrap() {
+                assert_eq!(rec.pos, Some(recs[n].pos));
+                let name = rec.read_name.as_ref().unwrap();
+                assert_eq!(&name[..name.len() - 1], recs[n].name.as_bytes());
+                n += 1;
+            }
+            assert_eq!(n, recs.len());
+        }
+    }
+
+    #[test]
+    fn test_recover_truncated_meta() {
+        let tmp_dir = TempDir::new("gbam_recover").unwrap();
+        let path = tmp_dir.path().join("truncated.gbam");
+        write_test_file(&path, "", &records());
+        crash(&path, &[0; FILE_INFO_SIZE]);
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        let len = file.metadata().unwrap().len();
+        file.set_len(len - 10).unwrap();
+
+        let mut file = OpenOptions::new().read(true).write(true).open(&path).unwrap();
+        let err = recover_meta(&mut file).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_recover_meta_snapshot() {
+        let tmp_dir = TempDir::new("gbam_recover").unwrap();
+        let path = tmp_dir.path().join("streamed.gbam");
+        let recs = records();
+        let mut writer = crate::test_utils::new_test_writer(&path, "");
+        for rec in &recs[..10] {
+            writer.push_record(&rec.to_raw(), false).unwrap();
+        }
+        writer.flush_all_columns(true, false).unwrap();
+        // Blocks written after the snapshot are not in its meta.
+        for rec in &recs[10..15] {
+            writer.push_record(&rec.to_raw(), false).unwrap();
+        }
+        writer.flush_all_columns(false, false).unwrap();
+        for rec in &recs[15..20] {
+            writer.push_record(&rec.to_raw(), false).unwrap();
+        }
+        // Crash without finish().
+        drop(writer);
+        assert!(open_err(&path).contains("recover_meta"));
+
+        let mut file = OpenOptions::new().read(true).write(true).open(&path).unwrap();
+        recover_meta(&mut file).unwrap();
+        let mut reader = open_test_file(&path);
+        assert_eq!(reader.num_records(), 10);
+        let mut records = reader.records();
+        let mut n = 0;
+        while let Some(rec) = records.next_rec().unwrap() {
+            assert_eq!(rec.pos, Some(recs[n].pos));
+            let name = rec.read_name.as_ref().unwrap();
+            assert_eq!(&name[..name.len() - 1], recs[n].name.as_bytes());
+            n += 1;
+        }
+        assert_eq!(n, 10);
+    }
+
+    #[test]
+    fn test_scan_windows() {
+        let tmp_dir = TempDir::new("gbam_recover").unwrap();
+        let path = tmp_dir.path().join("streamed.gbam");
+        let recs = records();
+        let mut writer = crate::test_utils::new_test_writer(&path, "");
+        for rec in &recs[..10] {
+            writer.push_record(&rec.to_raw(), false).unwrap();
+        }
+        writer.flush_all_columns(true, false).unwrap();
+        for rec in &recs[10..] {
+            writer.push_record(&rec.to_raw(), false).unwrap();
+        }
+        writer.flush_all_columns(false, false).unwrap();
+        drop(writer);
+
+        // Meta of the snapshot spans small windows, blocks follow it.
+        let mut file = File::open(&path).unwrap();
+        let (pos, len, _, meta) = find_meta(&mut file, SCAN_WINDOW).unwrap().unwrap();
+        assert!(pos + (len as u64) < file.metadata().unwrap().len());
+        assert_eq!(meta.count_items(&Fields::RefID), 10);
+        for window in [1, 100, 4096] {
+            let (win_pos, win_len, ..) = find_meta(&mut file, window).unwrap().unwrap();
+            assert_eq!((win_pos, win_len), (pos, len), "window of {}", window);
+        }
+
+        let path = tmp_dir.path().join("zeros.gbam");
+        std::fs::write(&path, vec![0; FILE_INFO_SIZE + 100]).unwrap();
+        let mut file = File::open(&path).unwrap();
+        assert!(find_meta(&mut file, 7).unwrap().is_none());
+    }
+}
@@ -0,0 +1,581 @@
+//! Reference based compression of RawSequence blocks.
+//!
+//! Bases of mapped reads are compared with the reference at positions given
+//! by Pos and CIGAR, and only differences are stored. Encoded block layout:
+//!
+//! | n_records: u32 | record * n_records |
+//!
+//! Each record starts with `flags: u8` and `l_seq: u32`. Records without bit
+//! 0 of flags hold BAM sequence bytes verbatim. Reference encoded ones go on
+//! with
+//!
+//! | ref_id: i32 | pos: i32 | n_cigar_op: u32 | cigar: u32 * n_cigar_op | bitmap | bases |
+//!
+//! Bitmap has a bit per base, set for bases which differ from the reference
+//! or aren't aligned to it (insertions and soft clips). Their 4-bit codes
+//! follow, two per byte. Upper 4 bits of flags keep the padding nibble of odd
+//! length sequences. Unmapped reads, reads with ambiguity codes and reads on
+//! sequences missing from the reference are stored verbatim. Blocks are
+//! decoded into plain BAM encoding on read, so column index offsets stay
+//! valid. MD5 of each used reference sequence is kept in meta, readers
+//! refuse references which don't match it.
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+use bam_tools::record::bamrawrecord::BAMRawRecord;
+use bam_tools::record::fields::Fields;
+use byteorder::{ByteOrder, LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::meta::FileMeta;
+
+const REFERENCE_ENCODED: u8 = 1;
+const UNMAPPED_FLAG: u16 = 0x4;
+
+/// Reference sequences held in memory, upper cased.
+pub struct Reference {
+    seqs: Vec<(String, Vec<u8>)>,
+    ids: HashMap<String, usize>,
+}
+
+impl Reference {
+    /// Reads FASTA file at `path`. Sequences are named by the header line up
+    /// to the first whitespace.
+    pub fn from_fasta<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let text = fs::read(path)?;
+        let mut seqs: Vec<(String, Vec<u8>)> = Vec::new();
+        for line in text.split(|&c| c == b'\n') {
+            let line = line.strip_suffix(b"\r").unwrap_or(line);
+            if let Some(header) = line.strip_prefix(b">") {
+                let name = header.split(|c| c.is_ascii_whitespace()).next().unwrap_or_default();
+                seqs.push((String::from_utf8_lossy(name).into_owned(), Vec::new()));
+            } else if !line.is_empty() {
+                match seqs.last_mut() {
+                    Some((_, seq)) => seq.extend_from_slice(line),
+                    None => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "FASTA sequence data comes before the first header line",
+                        ))
+                    }
+                }
+            }
+        }
+        Self::from_sequences(seqs)
+    }
+
+    /// Fails on repeated names.
+    pub fn from_sequences(mut seqs: Vec<(String, Vec<u8>)>) -> io::Result<Self> {
+        let mut ids = HashMap::with_capacity(seqs.len());
+        for (id, (name, seq)) in seqs.iter_mut().enumerate() {
+            seq.make_ascii_uppercase();
+            if ids.insert(name.clone(), id).is_some() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Reference sequence '{}' is listed twice", name),
+                ));
+            }
+        }
+        Ok(Self { seqs, ids })
+    }
+
+    pub fn get(&self, name: &str) -> Option<&[u8]> {
+        self.ids.get(name).map(|&id| &self.seqs[id].1[..])
+    }
+
+    /// MD5 of sequence `name` as hex string, like M5 tag of SAM @SQ lines.
+    pub fn md5(&self, name: &str) -> Option<String> {
+        self.get(name).map(|seq| format!("{:x}", md5::compute(seq)))
+    }
+}
+
+/// Reference sequences by reference id of a file, matched by name and
+/// length.
+#[derive(Clone)]
+pub(crate) struct RefSeqMap {
+    reference: Arc<Reference>,
+    seq_ids: Vec<Option<usize>>,
+}
+
+impl RefSeqMap {
+    pub fn new(reference: Arc<Reference>, ref_seqs: &[(String, u32)]) -> Self {
+        let seq_ids = ref_seqs
+            .iter()
+            .map(|(name, len)| {
+                let id = *reference.ids.get(name)?;
+                Some(id).filter(|&id| reference.seqs[id].1.len() == *len as usize)
+            })
+            .collect();
+        Self { reference, seq_ids }
+    }
+
+    fn get(&self, ref_id: i32) -> Option<&[u8]> {
+        let id = (*self.seq_ids.get(usize::try_from(ref_id).ok()?)?)?;
+        Some(&self.reference.seqs[id].1)
+    }
+
+    /// MD5 of each reference sequence, empty for ones missing from the
+    /// reference.
+    pub fn md5s(&self) -> Vec<String> {
+        self.seq_ids
+            .iter()
+            .map(|id| match id {
+                Some(id) => format!("{:x}", md5::compute(&self.reference.seqs[*id].1)),
+                None => String::new(),
+            })
+            .collect()
+    }
+}
+
+/// Fails if `reference` lacks a sequence the file was encoded against, or
+/// has it with other MD5.
+pub(crate) fn check_reference(meta: &FileMeta, reference: &Reference) -> io::Result<()> {
+    for ((name, _), expected) in meta.get_ref_seqs().iter().zip(meta.get_reference_md5s()) {
+        if expected.is_empty() {
+            continue;
+        }
+        match reference.md5(name) {
+            Some(md5) if md5 == *expected => {}
+            Some(md5) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "Reference sequence '{}' has MD5 {}, but sequences were encoded against {}",
+                        name, md5, expected
+                    ),
+                ))
+            }
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Reference sequence '{}' is missing from reference", name),
+                ))
+            }
+        }
+    }
+    Ok(())
+}
+
+struct Alignment {
+    ref_id: i32,
+    pos: i32,
+    flag: u16,
+    l_seq: usize,
+    cigar: Vec<u32>,
+}
+
+/// Collects alignments of records in the current RawSequence block of a
+/// writer, and encodes the block when it's flushed.
+pub(crate) struct RefEncoder {
+    refs: RefSeqMap,
+    alignments: Vec<Alignment>,
+}
+
+impl RefEncoder {
+    pub fn new(refs: RefSeqMap) -> Self {
+        Self {
+            refs,
+            alignments: Vec::new(),
+        }
+    }
+
+    pub fn observe(&mut self, rec: &BAMRawRecord) {
+        let read_i32 = |field| rec.get_bytes(field).read_i32::<LittleEndian>().unwrap();
+        let mut cigar_bytes = rec.get_bytes(&Fields::RawCigar);
+        let mut cigar = Vec::with_capacity(cigar_bytes.len() / 4);
+        while let Ok(op) = cigar_bytes.read_u32::<LittleEndian>() {
+            cigar.push(op);
+        }
+        self.alignments.push(Alignment {
+            ref_id: read_i32(&Fields::RefID),
+            pos: read_i32(&Fields::Pos),
+            flag: rec.get_bytes(&Fields::Flags).read_u16::<LittleEndian>().unwrap(),
+            l_seq: rec.get_len_val(&Fields::SequenceLength),
+            cigar,
+        });
+    }
+
+    /// Encodes block of concatenated BAM sequences of observed records.
+    pub fn encode_block(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut res = Vec::with_capacity(data.len() / 2);
+        res.write_u32::<LittleEndian>(self.alignments.len() as u32).unwrap();
+        let mut offset = 0;
+        for aln in &self.alignments {
+            let seq = &data[offset..offset + aln.l_seq.div_ceil(2)];
+            offset += seq.len();
+            let padding = if aln.l_seq % 2 == 1 {
+                get_nibble(seq, aln.l_seq) << 4
+            } else {
+                0
+            };
+            match self.encode_record(aln, seq) {
+                Some((bitmap, bases)) => {
+                    res.push(REFERENCE_ENCODED | padding);
+                    res.write_u32::<LittleEndian>(aln.l_seq as u32).unwrap();
+                    res.write_i32::<LittleEndian>(aln.ref_id).unwrap();
+                    res.write_i32::<LittleEndian>(aln.pos).unwrap();
+                    res.write_u32::<LittleEndian>(aln.cigar.len() as u32).unwrap();
+                    for &op in &aln.cigar {
+                        res.write_u32::<LittleEndian>(op).unwrap();
+                    }
+                    res.extend_from_slice(&bitmap);
+                    res.extend_from_slice(&bases);
+                }
+                None => {
+                    res.push(padding);
+                    res.write_u32::<LittleEndian>(aln.l_seq as u32).unwrap();
+                    res.extend_from_slice(seq);
+                }
+            }
+        }
+        debug_assert_eq!(offset, data.len());
+        self.alignments.clear();
+        res
+    }
+
+    // Difference bitmap and bases, None if the record is stored verbatim.
+    fn encode_record(&self, aln: &Alignment, seq: &[u8]) -> Option<(Vec<u8>, Vec<u8>)> {
+        if aln.flag & UNMAPPED_FLAG != 0 || aln.l_seq == 0 {
+            return None;
+        }
+        let ref_seq = self.refs.get(aln.ref_id)?;
+        let ref_positions = aligned_positions(&aln.cigar, aln.pos, aln.l_seq)?;
+        let mut bitmap = vec![0; aln.l_seq.div_ceil(8)];
+        let mut bases = Vec::new();
+        let mut n_bases = 0;
+        for (i, ref_pos) in ref_positions.into_iter().enumerate() {
+            let nibble = get_nibble(seq, i);
+            if !matches!(nibble, 1 | 2 | 4 | 8) {
+                return None;
+            }
+            let same = match ref_pos {
+                Some(ref_pos) => nibble == base_code(*ref_seq.get(ref_pos)?),
+                None => false,
+            };
+            if !same {
+                bitmap[i / 8] |= 1 << (i % 8);
+                if n_bases % 2 == 0 {
+                    bases.push(nibble << 4);
+                } else {
+                    *bases.last_mut().unwrap() |= nibble;
+                }
+                n_bases += 1;
+            }
+        }
+        Some((bitmap, bases))
+    }
+}
+
+/// Restores BAM encoding of block produced by `RefEncoder::encode_block()`.
+pub(crate) fn decode_block(data: &[u8], refs: &RefSeqMap) -> io::Result<Vec<u8>> {
+    let mut cursor = data;
+    let n = cursor.read_u32::<LittleEndian>()?;
+    let mut res = Vec::with_capacity(data.len() * 2);
+    for _ in 0..n {
+        let flags = cursor.read_u8()?;
+        let l_seq = cursor.read_u32::<LittleEndian>()? as usize;
+        if flags & REFERENCE_ENCODED == 0 {
+            res.extend_from_slice(take(&mut cursor, l_seq.div_ceil(2))?);
+            continue;
+        }
+        let ref_id = cursor.read_i32::<LittleEndian>()?;
+        let pos = cursor.read_i32::<LittleEndian>()?;
+        let n_cigar_op = cursor.read_u32::<LittleEndian>()? as usize;
+        let cigar: Vec<u32> = take(&mut cursor, 4 * n_cigar_op)?
+            .chunks_exact(4)
+            .map(LittleEndian::read_u32)
+            .collect();
+        let bitmap = take(&mut cursor, l_seq.div_ceil(8))?;
+        let n_bases: usize = bitmap.iter().map(|byte| byte.count_ones() as usize).sum();
+        let bases = take(&mut cursor, n_bases.div_ceil(2))?;
+        let mut base_num = 0;
+
+        let ref_seq = refs.get(ref_id).ok_or_else(|| {
+            invalid_data(format!("No reference sequence for reference id {}", ref_id))
+        })?;
+        let ref_positions = aligned_positions(&cigar, pos, l_seq)
+            .ok_or_else(|| invalid_data(format!("CIGAR doesn't match sequence of {} bases", l_seq)))?;
+        let start = res.len();
+        res.resize(start + l_seq.div_ceil(2), 0);
+        let seq = &mut res[start..];
+        for (i, ref_pos) in ref_positions.into_iter().enumerate() {
+            let nibble = if bitmap[i / 8] & (1 << (i % 8)) != 0 {
+                base_num += 1;
+                get_nibble(bases, base_num - 1)
+            } else {
+                ref_pos
+                    .and_then(|ref_pos| ref_seq.get(ref_pos))
+                    .map(|&base| base_code(base))
+                    .ok_or_else(|| invalid_data(String::from("Sequence is past the end of reference")))?
+            };
+            seq[i / 2] |= nibble << (4 * (1 - i % 2));
+        }
+        if l_seq % 2 == 1 {
+            seq[l_seq / 2] |= flags >> 4;
+        }
+    }
+    Ok(res)
+}
+
+/// Keeps records `lo..hi` of block produced by `RefEncoder::encode_block()`.
+pub(crate) fn slice_block(data: &[u8], lo: usize, hi: usize) -> io::Result<Vec<u8>> {
+    let mut cursor = data;
+    let n = cursor.read_u32::<LittleEndian>()? as usize;
+    if hi > n {
+        return Err(invalid_data(format!("Sequence block holds {} records, not {}", n, hi)));
+    }
+    let mut res = Vec::new();
+    res.write_u32::<LittleEndian>((hi - lo) as u32).unwrap();
+    for i in 0..hi {
+        let start = cursor;
+        let flags = cursor.read_u8()?;
+        let l_seq = cursor.read_u32::<LittleEndian>()? as usize;
+        if flags & REFERENCE_ENCODED == 0 {
+            take(&mut cursor, l_seq.div_ceil(2))?;
+        } else {
+            take(&mut cursor, 8)?;
+            let n_cigar_op = cursor.read_u32::<LittleEndian>()? as usize;
+            take(&mut cursor, 4 * n_cigar_op)?;
+            let bitmap = take(&mut cursor, l_seq.div_ceil(8))?;
+            let n_bases: usize = bitmap.iter().map(|byte| byte.count_ones() as usize).sum();
+            take(&mut cursor, n_bases.div_ceil(2))?;
+        }
+        if i >= lo {
+            res.extend_from_slice(&start[..start.len() - cursor.len()]);
+        }
+    }
+    Ok(res)
+}
+
+// Reference position of each base of the read, None for bases not aligned
+// to it. None if CIGAR doesn't cover exactly `l_seq` bases.
+fn aligned_positions(cigar: &[u32], pos: i32, l_seq: usize) -> Option<Vec<Option<usize>>> {
+    let mut ref_pos = usize::try_from(pos).ok()?;
+    let mut positions = Vec::with_capacity(l_seq);
+    for &op in cigar {
+        let len = (op >> 4) as usize;
+        if matches!(op & 0xf, 0 | 1 | 4 | 7 | 8) && positions.len() + len > l_seq {
+            return None;
+        }
+        match op & 0xf {
+            // M, = and X.
+            0 | 7 | 8 => {
+                positions.extend((ref_pos..ref_pos + len).map(Some));
+                ref_pos += len;
+            }
+            // I and S.
+            1 | 4 => positions.extend(std::iter::repeat_n(None, len)),
+            // D and N.
+            2 | 3 => ref_pos += len,
+            // H and P.
+            5 | 6 => {}
+            _ => return None,
+        }
+    }
+    Some(positions).filter(|positions| positions.len() == l_seq)
+}
+
+// BAM 4-bit code of reference base, N for other characters.
+fn base_code(base: u8) -> u8 {
+    b"=ACMGRSVTWYHKDBN".iter().position(|&c| c == base).unwrap_or(15) as u8
+}
+
+fn get_nibble(seq: &[u8], i: usize) -> u8 {
+    (seq[i / 2] >> (4 * (1 - i % 2))) & 0xf
+}
+
+fn take<'a>(cursor: &mut &'a [u8], len: usize) -> io::Result<&'a [u8]> {
+    if cursor.len() < len {
+        return Err(invalid_data(String::from("Reference encoded sequence block is truncated")));
+    }
+    let (head, rest) = cursor.split_at(len);
+    *cursor = rest;
+    Ok(head)
+}
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::meta::SeqEncoding;
+    use crate::reader::parse_tmplt::ParsingTemplate;
+    use crate::reader::reader::Reader;
+    use crate::test_utils::{sam_header_bytes, TestRecord};
+    use crate::writer::{Writer, WriterSettings};
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+    use tempdir::TempDir;
+
+    const REF_LEN: usize = 100_000;
+
+    fn random_bases(rng: &mut StdRng, len: usize) -> String {
+        (0..len).map(|_| b"ACGT"[rng.gen_range(0..4)] as char).collect()
+    }
+
+    // Reads of `chr1`, `chr2` and `chr3`, the last one missing from
+    // reference, with substitutions, indels, clips, ambiguity codes and
+    // unmapped ones.
+    fn records(rng: &mut StdRng, ref_seqs: &[String]) -> Vec<TestRecord> {
+        let op = |len: u32, kind: u32| (len << 4) | kind;
+        (0..3_000)
+            .map(|i| {
+                let ref_id = i % 3;
+                let pos = rng.gen_range(0..REF_LEN - 200);
+                let mut rec = TestRecord::new(ref_id as i32, pos as i32, &format!("read{}", i));
+                let ref_seq = &ref_seqs[ref_id.min(1)].as_bytes()[pos..];
+                // 10S40M2I30M5D18M3H, 100 bases.
+                rec.cigar = vec![op(10, 4), op(40, 0), op(2, 1), op(30, 0), op(5, 2), op(18, 0), op(3, 5)];
+                let mut seq = random_bases(rng, 10).into_bytes();
+                seq.extend_from_slice(&ref_seq[..40]);
+                seq.extend_from_slice(random_bases(rng, 2).as_bytes());
+                seq.extend_from_slice(&ref_seq[40..70]);
+                seq.extend_from_slice(&ref_seq[75..93]);
+                for _ in 0..rng.gen_range(0..4) {
+                    seq[rng.gen_range(0..100)] = b"ACGT"[rng.gen_range(0..4)];
+                }
+                match i % 10 {
+                    0 => seq[50] = b'N',
+                    1 => {
+                        rec.flag = UNMAPPED_FLAG;
+                        rec.cigar.clear();
+                    }
+                    2 => {
+                        // Odd length.
+                        seq.pop();
+                        rec.cigar[5] = op(17, 0);
+                    }
+                    _ => {}
+                }
+                rec.seq = String::from_utf8(seq).unwrap();
+                rec.qual = vec![30; rec.seq.len()];
+                rec
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_reference_round_trip() {
+        let dir = TempDir::new("gbam_ref_compression").unwrap();
+        let mut rng = StdRng::seed_from_u64(11);
+        let ref_bases = [random_bases(&mut rng, REF_LEN), random_bases(&mut rng, REF_LEN)];
+        let fasta = dir.path().join("ref.fa");
+        let mut text = String::from(">chr1 first\n");
+        for line in ref_bases[0].to_lowercase().as_bytes().chunks(60) {
+            text.push_str(std::str::from_utf8(line).unwrap());
+            text.push('\n');
+        }
+        text.push_str(&format!(">chr2\r\n{}\r\n", ref_bases[1]));
+        std::fs::write(&fasta, text).unwrap();
+        let reference = Arc::new(Reference::from_fasta(&fasta).unwrap());
+        assert_eq!(reference.get("chr1"), Some(ref_bases[0].as_bytes()));
+
+        let ref_seqs: Vec<(String, u32)> =
+            ["chr1", "chr2", "chr3"].iter().map(|name| (name.to_string(), REF_LEN as u32)).collect();
+        let records = records(&mut rng, &ref_bases);
+        let (mut sizes, mut paths) = (Vec::new(), Vec::new());
+        for with_reference in [false, true] {
+            let path = dir.path().join(format!("{}.gbam", with_reference));
+            let settings = WriterSettings {
+                ref_seqs: ref_seqs.clone(),
+                sam_header: sam_header_bytes("", &ref_seqs),
+                ..Default::default()
+            };
+            let mut writer = Writer::create(&path, settings).unwrap();
+            if with_reference {
+                writer.set_reference(reference.clone());
+            }
+            for rec in &records {
+                writer.push_record(&rec.to_raw(), false).unwrap();
+            }
+            writer.finish_with_summary(false).unwrap();
+            let reader = Reader::from_path(&path, ParsingTemplate::new()).unwrap();
+            let blocks = reader.file_meta.view_blocks(&Fields::RawSequence);
+            sizes.push(blocks.iter().map(|block| block.block_size).sum::<u32>());
+            paths.push(path);
+        }
+        assert!(sizes[1] < sizes[0], "{:?}", sizes);
+
+        let template = ParsingTemplate::new_with(&[Fields::RawSequence]);
+        let mut reader = Reader::from_path(&paths[1], template).unwrap();
+        assert_eq!(reader.file_meta.get_seq_encoding(), SeqEncoding::Reference);
+        let md5s = reader.file_meta.get_reference_md5s();
+        assert_eq!(md5s[0], reference.md5("chr1").unwrap());
+        assert!(md5s[2].is_empty());
+        reader.set_reference(reference.clone()).unwrap();
+        for (i, rec) in records.iter().enumerate() {
+            let expected = rec.to_raw();
+            let column = reader.get_column(&Fields::RawSequence).unwrap();
+            assert_eq!(column.raw_item(i).unwrap(), expected.get_bytes(&Fields::RawSequence), "record {}", i);
+        }
+        assert_eq!(reader.set_reference(reference).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_reference_mismatch() {
+        let dir = TempDir::new("gbam_ref_compression").unwrap();
+        let path = dir.path().join("encoded.gbam");
+        let mut rng = StdRng::seed_from_u64(12);
+        let bases = random_bases(&mut rng, REF_LEN).into_bytes();
+        let reference = Reference::from_sequences(vec![(String::from("chr1"), bases.clone())]).unwrap();
+        let ref_seqs = vec![(String::from("chr1"), REF_LEN as u32)];
+        let settings = WriterSettings {
+            ref_seqs: ref_seqs.clone(),
+            sam_header: sam_header_bytes("", &ref_seqs),
+            ..Default::default()
+        };
+        let mut writer = Writer::create(&path, settings).unwrap();
+        writer.set_reference(Arc::new(reference));
+        let mut rec = TestRecord::new(0, 10, "read");
+        rec.seq = String::from_utf8(bases[10..14].to_vec()).unwrap();
+        writer.push_record(&rec.to_raw(), false).unwrap();
+        writer.finish_with_summary(false).unwrap();
+
+        let mut changed = bases.clone();
+        changed[4_000] = if changed[4_000] == b'A' { b'C' } else { b'A' };
+        let mut reader = Reader::from_path(&path, ParsingTemplate::new()).unwrap();
+        let other = Reference::from_sequences(vec![(String::from("chr1"), changed)]).unwrap();
+        let err = reader.set_reference(Arc::new(other)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(err.to_string().starts_with("Reference sequence 'chr1' has MD5"), "{}", err);
+        let missing = Reference::from_sequences(vec![(String::from("chr2"), bases)]).unwrap();
+        let err = reader.set_reference(Arc::new(missing)).unwrap_err();
+        assert_eq!(err.to_string(), "Reference sequence 'chr1' is missing from reference");
+    }
+
+    #[test]
+    fn test_slice_block() {
+        let seqs = vec![(String::from("chr1"), b"ACGTACGTAC".to_vec())];
+        let reference = Reference::from_sequences(seqs).unwrap();
+        let refs = RefSeqMap::new(Arc::new(reference), &[(String::from("chr1"), 10)]);
+        let mut encoder = RefEncoder::new(refs.clone());
+        let mut data = Vec::new();
+        let mut expected = Vec::new();
+        for (i, seq) in ["GTAC", "GTTCA", "NNA"].iter().enumerate() {
+            let rec = TestRecord {
+                pos: 2,
+                cigar: vec![(seq.len() as u32) << 4],
+                seq: seq.to_string(),
+                qual: vec![30; seq.len()],
+                name: format!("r{}", i),
+                ..Default::default()
+            };
+            let raw = rec.to_raw();
+            encoder.observe(&raw);
+            data.extend_from_slice(raw.get_bytes(&Fields::RawSequence));
+            expected.push(raw.get_bytes(&Fields::RawSequence).to_vec());
+        }
+        let encoded = encoder.encode_block(&data);
+        // Only "NNA" is verbatim, the first read matches the reference.
+        assert_eq!(encoded[4], REFERENCE_ENCODED);
+        assert_eq!(encoded[25], 0);
+        assert_eq!(decode_block(&encoded, &refs).unwrap(), data);
+        let sliced = slice_block(&encoded, 1, 3).unwrap();
+        assert_eq!(decode_block(&sliced, &refs).unwrap(), expected[1..].concat());
+        assert!(decode_block(&encoded[..encoded.len() - 1], &refs).is_err());
+    }
+}
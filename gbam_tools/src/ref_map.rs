@@ -0,0 +1,165 @@
+//! Translation of reference ids of records from inputs whose header lists
+//! reference sequences in other order than the writer.
+use std::borrow::Cow;
+use std::convert::TryFrom;
+use std::io;
+
+use bam_tools::record::bamrawrecord::BAMRawRecord;
+use bam_tools::record::fields::Fields;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+/// Map from reference ids of `source_refs` to ids of `target_refs`, matched
+/// by name, to be passed to `Writer::set_ref_map()`. Fails if a source
+/// sequence is missing from target or has other length.
+pub fn build_ref_map(
+    source_refs: &[(String, u32)],
+    target_refs: &[(String, u32)],
+) -> io::Result<Vec<i32>> {
+    source_refs
+        .iter()
+        .map(|(name, len)| {
+            let target_id = target_refs
+                .iter()
+                .position(|(target_name, _)| target_name == name)
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("Reference sequence '{}' is missing from target", name),
+                    )
+                })?;
+            let target_len = target_refs[target_id].1;
+            if target_len != *len {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "Reference sequence '{}' has length {}, but {} in target",
+                        name, len, target_len
+                    ),
+                ));
+            }
+            Ok(target_id as i32)
+        })
+        .collect()
+}
+
+/// Reference id translation and bounds check applied on push.
+#[derive(Default)]
+pub(crate) struct RefMap {
+    // Indexed by source reference id.
+    pub new_ids: Option<Vec<i32>>,
+    pub strict: bool,
+}
+
+impl RefMap {
+    pub fn is_active(&self) -> bool {
+        self.new_ids.is_some() || self.strict
+    }
+
+    /// `records` with translated RefID and NextRefID. Ids out of range of the
+    /// map (or of `n_refs` without map) are kept as is, unless in strict
+    /// mode, where the whole batch is rejected. `first_rec` is the number of
+    /// the first record, for error messages.
+    pub fn apply(
+        &self,
+        records: &[BAMRawRecord],
+        n_refs: usize,
+        first_rec: u64,
+    ) -> io::Result<Vec<BAMRawRecord<'static>>> {
+        let n_refs = self.new_ids.as_ref().map_or(n_refs, Vec::len);
+        let mut mapped = Vec::with_capacity(records.len());
+        for (i, rec) in records.iter().enumerate() {
+            let mut rec = BAMRawRecord(Cow::Owned(rec.0.to_vec()));
+            for (field, range) in [(Fields::RefID, 0..4), (Fields::NextRefID, 20..24)] {
+                let ref_id = rec.get_bytes(&field).read_i32::<LittleEndian>().unwrap();
+                let idx = match usize::try_from(ref_id) {
+                    Ok(idx) if idx < n_refs => idx,
+                    // Unmapped.
+                    _ if ref_id == -1 => continue,
+                    _ if self.strict => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!(
+                                "Record {} has {} {} with {} reference sequences",
+                                first_rec + i as u64,
+                                field,
+                                ref_id,
+                                n_refs
+                            ),
+                        ))
+                    }
+                    _ => continue,
+                };
+                if let Some(new_ids) = &self.new_ids {
+                    (&mut rec.0.to_mut()[range])
+                        .write_i32::<LittleEndian>(new_ids[idx])
+                        .unwrap();
+                }
+            }
+            mapped.push(rec);
+        }
+        Ok(mapped)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{new_test_writer, open_test_file, test_ref_seqs, TestRecord};
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_build_ref_map() {
+        let target = test_ref_seqs();
+        let source = vec![target[2].clone(), target[0].clone()];
+        assert_eq!(build_ref_map(&source, &target).unwrap(), vec![2, 0]);
+
+        let missing = vec![(String::from("chrX"), 1_000_000)];
+        assert!(build_ref_map(&missing, &target).is_err());
+        let other_len = vec![(String::from("chr1"), 5)];
+        assert!(build_ref_map(&other_len, &target).is_err());
+    }
+
+    #[test]
+    fn test_merge_permuted_refs() {
+        let dir = TempDir::new("gbam_ref_map").unwrap();
+        let path = dir.path().join("merged.gbam");
+        let target = test_ref_seqs();
+        // Second input lists chr3, chr1, chr2.
+        let permuted = vec![target[2].clone(), target[0].clone(), target[1].clone()];
+        let with_mate = |ref_id, pos, next_ref_id, name| {
+            let mut rec = TestRecord::new(ref_id, pos, name);
+            rec.next_refid = next_ref_id;
+            rec.next_pos = pos;
+            rec.to_raw()
+        };
+        // The same reads on the same chromosomes, by ids of each input.
+        let first = [with_mate(0, 10, 2, "a"), with_mate(2, 20, -1, "b")];
+        let second = [with_mate(1, 10, 0, "a"), with_mate(0, 20, -1, "b")];
+
+        let mut writer = new_test_writer(&path, "");
+        writer.set_strict_ref_ids(true);
+        writer.set_ref_map(Some(build_ref_map(&target, &target).unwrap()));
+        writer.push_records(&first, false).unwrap();
+        writer.set_ref_map(Some(build_ref_map(&permuted, &target).unwrap()));
+        writer.push_records(&second, false).unwrap();
+        // Out of bounds of the second input, whole batch is rejected.
+        let bad = [with_mate(0, 30, -1, "c"), with_mate(3, 30, -1, "d")];
+        assert!(writer.push_records(&bad, false).is_err());
+        writer.finish_with_summary(false).unwrap();
+
+        let mut reader = open_test_file(&path);
+        let mut fetched = Vec::new();
+        let mut recs = reader.records();
+        while let Some(rec) = recs.next_rec().unwrap() {
+            fetched.push((
+                rec.refid.unwrap(),
+                rec.pos.unwrap(),
+                rec.next_ref_id.unwrap(),
+            ));
+        }
+        assert_eq!(
+            fetched,
+            vec![(0, 10, 2), (2, 20, -1), (0, 10, 2), (2, 20, -1)]
+        );
+    }
+}
@@ -0,0 +1,229 @@
+//! Writing records of selected reference sequences only.
+use std::borrow::Cow;
+use std::convert::TryFrom;
+use std::fmt;
+
+use bam_tools::record::bamrawrecord::BAMRawRecord;
+use bam_tools::record::fields::Fields;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::meta::sam_header_text;
+
+const BAM_FMUNMAP: u16 = 0x8;
+const BAM_FMREVERSE: u16 = 0x20;
+
+/// Counts of records changed by `Writer::set_kept_refs()`, see
+/// `Writer::ref_subset_report()`.
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub struct RefSubsetReport {
+    /// Records on other references, or unmapped ones, which were not written.
+    pub records_dropped: u64,
+    /// Written records whose mate is on a dropped reference. Their mate is
+    /// marked unmapped.
+    pub mates_unmapped: u64,
+}
+
+impl fmt::Display for RefSubsetReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} records on dropped references skipped, {} mates on dropped references marked unmapped",
+            self.records_dropped, self.mates_unmapped
+        )
+    }
+}
+
+/// Maps reference ids of pushed records onto compacted list of kept references.
+pub(crate) struct RefSubset {
+    // Indexed by old reference id, -1 for dropped references.
+    new_ids: Vec<i32>,
+    pub report: RefSubsetReport,
+}
+
+impl RefSubset {
+    /// `kept` must be sorted, deduplicated and in range of `n_refs`.
+    pub fn new(kept: &[i32], n_refs: usize) -> Self {
+        let mut new_ids = vec![-1; n_refs];
+        for (new_id, &old_id) in kept.iter().enumerate() {
+            new_ids[old_id as usize] = new_id as i32;
+        }
+        Self {
+            new_ids,
+            report: RefSubsetReport::default(),
+        }
+    }
+
+    /// Number of references before subsetting.
+    pub fn n_refs(&self) -> usize {
+        self.new_ids.len()
+    }
+
+    fn new_id(&self, ref_id: i32) -> i32 {
+        match usize::try_from(ref_id) {
+            Ok(idx) => self.new_ids.get(idx).copied().unwrap_or(-1),
+            Err(_) => -1,
+        }
+    }
+
+    /// Record with remapped reference ids, or None if it has to be dropped.
+    pub fn apply(&mut self, rec: &BAMRawRecord) -> Option<BAMRawRecord<'static>> {
+        let read_i32 = |field| rec.get_bytes(field).read_i32::<LittleEndian>().unwrap();
+        let ref_id = self.new_id(read_i32(&Fields::RefID));
+        if ref_id == -1 {
+            self.report.records_dropped += 1;
+            return None;
+        }
+        let old_next_ref_id = read_i32(&Fields::NextRefID);
+        let next_ref_id = self.new_id(old_next_ref_id);
+        let mut rec = BAMRawRecord(Cow::Owned(rec.0.to_vec()));
+        let bytes = rec.0.to_mut();
+        (&mut bytes[0..4]).write_i32::<LittleEndian>(ref_id).unwrap();
+        (&mut bytes[20..24]).write_i32::<LittleEndian>(next_ref_id).unwrap();
+        if old_next_ref_id != -1 && next_ref_id == -1 {
+            self.report.mates_unmapped += 1;
+            let flags = (&bytes[14..16]).read_u16::<LittleEndian>().unwrap();
+            let flags = (flags | BAM_FMUNMAP) & !BAM_FMREVERSE;
+            (&mut bytes[14..16]).write_u16::<LittleEndian>(flags).unwrap();
+            // Next pos and template length.
+            (&mut bytes[24..28]).write_i32::<LittleEndian>(-1).unwrap();
+            (&mut bytes[28..32]).write_i32::<LittleEndian>(0).unwrap();
+        }
+        Some(rec)
+    }
+}
+
+/// Header bytes (`l_text`, text, `n_ref` and references, as in BAM) with
+/// @SQ lines and references not in `kept` removed.
+pub(crate) fn subset_sam_header(
+    sam_header: &[u8],
+    ref_seqs: &[(String, u32)],
+    kept: &[i32],
+) -> Vec<u8> {
+    let kept_refs: Vec<&(String, u32)> = kept.iter().map(|&id| &ref_seqs[id as usize]).collect();
+    let text = String::from_utf8_lossy(sam_header_text(sam_header));
+    let text: String = text
+        .trim_end_matches('\0')
+        .split_inclusive('\n')
+        .filter(|line| match line.strip_prefix("@SQ\t") {
+            Some(sq) => sq
+                .trim_end()
+                .split('\t')
+                .filter_map(|tag| tag.strip_prefix("SN:"))
+                .any(|name| kept_refs.iter().any(|(kept_name, _)| kept_name == name)),
+            None => true,
+        })
+        .collect();
+
+    let mut bytes = Vec::new();
+    bytes.write_u32::<LittleEndian>(text.len() as u32).unwrap();
+    bytes.extend_from_slice(text.as_bytes());
+    bytes.write_u32::<LittleEndian>(kept_refs.len() as u32).unwrap();
+    for (name, len) in kept_refs {
+        bytes.write_u32::<LittleEndian>(name.len() as u32 + 1).unwrap();
+        bytes.extend_from_slice(name.as_bytes());
+        bytes.push(0);
+        bytes.write_u32::<LittleEndian>(*len).unwrap();
+    }
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::validation::ValidationMode;
+    use crate::test_utils::{new_test_writer, open_test_file, TestRecord};
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_kept_refs() {
+        let dir = TempDir::new("gbam_ref_subset").unwrap();
+        let path = dir.path().join("subset.gbam");
+        let header = "@HD\tVN:1.6\n\
+            @SQ\tSN:chr1\tLN:1000000\n\
+            @SQ\tSN:chr2\tLN:1000000\n\
+            @SQ\tSN:chr3\tLN:1000000\n\
+            @PG\tID:test\n";
+        let mut writer = new_test_writer(&path, header);
+        writer.set_kept_ref_names(&["chr3", "chr1"]).unwrap();
+        assert!(writer.set_kept_ref_names(&["chrX"]).is_err());
+
+        let with_mate = |ref_id, name, next_ref_id, flag| {
+            let mut rec = TestRecord::new(ref_id, 100, name);
+            rec.next_refid = next_ref_id;
+            rec.next_pos = 200;
+            rec.tlen = 150;
+            rec.flag = flag;
+            rec
+        };
+        let mut unmapped = TestRecord::new(-1, -1, "unmapped");
+        unmapped.flag = 4;
+        let records = [
+            with_mate(0, "mate_on_kept", 2, 0x1 | 0x20),
+            with_mate(1, "dropped", 0, 0x1),
+            with_mate(0, "mate_on_dropped", 1, 0x1 | 0x20),
+            with_mate(2, "unpaired", -1, 0),
+            unmapped,
+        ];
+        for rec in &records {
+            writer.push_record(&rec.to_raw(), false).unwrap();
+        }
+        assert_eq!(
+            writer.ref_subset_report(),
+            Some(&RefSubsetReport {
+                records_dropped: 2,
+                mates_unmapped: 1,
+            })
+        );
+        writer.finish_with_summary(false).unwrap();
+        assert_eq!(writer.ref_subset_report().map(|report| report.records_dropped), Some(2));
+
+        let mut reader = open_test_file(&path);
+        let ref_seqs = reader.file_meta.get_ref_seqs().clone();
+        assert_eq!(
+            ref_seqs,
+            vec![(String::from("chr1"), 1_000_000), (String::from("chr3"), 1_000_000)]
+        );
+        assert_eq!(
+            sam_header_text(reader.file_meta.get_sam_header()),
+            b"@HD\tVN:1.6\n@SQ\tSN:chr1\tLN:1000000\n@SQ\tSN:chr3\tLN:1000000\n@PG\tID:test\n"
+        );
+
+        let mut fetched = Vec::new();
+        let mut recs = reader.records();
+        while let Some(rec) = recs.next_rec().unwrap() {
+            fetched.push((
+                String::from_utf8_lossy(rec.read_name.as_ref().unwrap()).into_owned(),
+                rec.refid.unwrap(),
+                rec.next_ref_id.unwrap(),
+                rec.next_pos.unwrap(),
+                rec.tlen.unwrap(),
+                rec.flag.unwrap(),
+            ));
+        }
+        assert_eq!(
+            fetched,
+            vec![
+                (String::from("mate_on_kept\0"), 0, 1, 200, 150, 0x1 | 0x20),
+                (String::from("mate_on_dropped\0"), 0, -1, -1, 0, 0x1 | 0x8),
+                (String::from("unpaired\0"), 1, -1, 200, 150, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_strict_validation_of_subset() {
+        let dir = TempDir::new("gbam_ref_subset").unwrap();
+        let mut writer = new_test_writer(&dir.path().join("strict.gbam"), "");
+        writer.set_kept_refs(&[0]);
+        writer.set_validation_mode(ValidationMode::Strict);
+        writer.push_record(&TestRecord::new(0, 10, "kept").to_raw(), false).unwrap();
+        let batch = [
+            TestRecord::new(1, 10, "dropped").to_raw(),
+            TestRecord::new(2, 10, "dropped").to_raw(),
+            TestRecord::new(0, -5, "bad").to_raw(),
+        ];
+        let err = writer.push_records(&batch, false).unwrap_err();
+        // Dropped records are counted, as the caller passed them.
+        assert!(err.to_string().starts_with("Record 3 is invalid: "), "{}", err);
+    }
+}
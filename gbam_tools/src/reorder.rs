@@ -0,0 +1,216 @@
+//! Reordering window of the writer, for input which is coordinate sorted up
+//! to a bounded displacement of records, like output of some aligners.
+//! Records are held in a min-heap by (RefID, Pos) and written out once the
+//! window is full, so block stats of sorted fields stay tight.
+use std::borrow::Cow;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::io;
+
+use bam_tools::record::bamrawrecord::BAMRawRecord;
+use bam_tools::record::fields::Fields;
+use byteorder::{LittleEndian, ReadBytesExt};
+
+/// Counts of reordering window, see `Writer::reorder_stats()`.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReorderStats {
+    /// Records which came after a record with greater key.
+    pub reordered: u64,
+    /// Records which came after a record with greater key was written,
+    /// displaced by more than the window size. They are written out of order.
+    pub overflowed: u64,
+}
+
+/// Records pushed out of the window, and their source indices, empty
+/// unless given with the records.
+#[derive(Default)]
+pub(crate) struct Released {
+    pub records: Vec<BAMRawRecord<'static>>,
+    pub sources: Vec<u64>,
+}
+
+/// Record held in the window: its key, arrival, bytes and source index.
+type Held = ((i32, i32), u64, Vec<u8>, Option<u64>);
+
+pub(crate) struct ReorderWindow {
+    size: usize,
+    strict: bool,
+    // Min-heap by key, then by arrival, so equal keys keep their order.
+    // Records go with their source index, if the writer keeps one.
+    heap: BinaryHeap<Reverse<Held>>,
+    received: u64,
+    last_in: Option<(i32, i32)>,
+    // Greatest key written so far.
+    last_out: Option<(i32, i32)>,
+    stats: ReorderStats,
+}
+
+impl ReorderWindow {
+    pub fn new(size: usize, strict: bool) -> Self {
+        assert!(size > 0, "Reorder window must hold at least one record.");
+        Self {
+            size,
+            strict,
+            heap: BinaryHeap::with_capacity(size + 1),
+            received: 0,
+            last_in: None,
+            last_out: None,
+            stats: ReorderStats::default(),
+        }
+    }
+
+    pub fn stats(&self) -> ReorderStats {
+        self.stats
+    }
+
+    /// Adds `records`, with their `sources` if not empty, and returns ones
+    /// pushed out of the window, in key order, with sources of theirs. In
+    /// strict mode fails on a record which should go before an already
+    /// returned one, records of the batch before it stay held.
+    pub fn push(&mut self, records: &[BAMRawRecord], sources: &[u64]) -> io::Result<Released> {
+        let mut released = Released::default();
+        for (i, rec) in records.iter().enumerate() {
+            let key = sort_key(rec);
+            if matches!(self.last_out, Some(last) if key < last) {
+                if self.strict {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!(
+                            "Record {} is out of coordinate order by more than {} records",
+                            self.received, self.size
+                        ),
+                    ));
+                }
+                self.stats.overflowed += 1;
+            }
+            if matches!(self.last_in, Some(last) if key < last) {
+                self.stats.reordered += 1;
+            }
+            self.last_in = Some(key);
+            let source = sources.get(i).copied();
+            self.heap.push(Reverse((key, self.received, rec.0.to_vec(), source)));
+            self.received += 1;
+            if self.heap.len() > self.size {
+                self.pop_into(&mut released);
+            }
+        }
+        Ok(released)
+    }
+
+    /// Returns all held records, in key order.
+    pub fn drain(&mut self) -> Released {
+        let mut released = Released::default();
+        while !self.heap.is_empty() {
+            self.pop_into(&mut released);
+        }
+        released
+    }
+
+    fn pop_into(&mut self, released: &mut Released) {
+        let Reverse((key, _, bytes, source)) = self.heap.pop().unwrap();
+        self.last_out = std::cmp::max(self.last_out, Some(key));
+        released.records.push(BAMRawRecord(Cow::Owned(bytes)));
+        released.sources.extend(source);
+    }
+}
+
+// Unmapped records go last, as in coordinate sorted files.
+fn sort_key(rec: &BAMRawRecord) -> (i32, i32) {
+    let read_i32 = |field| rec.get_bytes(field).read_i32::<LittleEndian>().unwrap();
+    match read_i32(&Fields::RefID) {
+        ref_id if ref_id < 0 => (i32::MAX, i32::MAX),
+        ref_id => (ref_id, read_i32(&Fields::Pos)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::meta::SortOrder;
+    use crate::test_utils::{new_test_writer, open_test_file, TestRecord};
+    use rand::{rngs::StdRng, seq::SliceRandom, SeedableRng};
+    use tempdir::TempDir;
+
+    fn sorted_records() -> Vec<TestRecord> {
+        (0..6_000).map(|i| TestRecord::new(i / 2_000, i, &format!("read{}", i))).collect()
+    }
+
+    #[test]
+    fn test_reorder_window() {
+        let dir = TempDir::new("gbam_reorder").unwrap();
+        let path = dir.path().join("reordered.gbam");
+        let sorted = sorted_records();
+        let mut shuffled = sorted.clone();
+        let mut rng = StdRng::seed_from_u64(7);
+        for chunk in shuffled.chunks_mut(50) {
+            chunk.shuffle(&mut rng);
+        }
+        let mut unmapped = TestRecord::new(-1, -1, "unmapped");
+        unmapped.flag = 0x4;
+        shuffled.insert(100, unmapped.clone());
+
+        let mut writer = new_test_writer(&path, "");
+        writer.set_rows_per_block(1_000);
+        writer.set_reorder_window(50, true);
+        for batch in shuffled.chunks(333) {
+            let raw: Vec<BAMRawRecord> = batch.iter().map(TestRecord::to_raw).collect();
+            writer.push_records(&raw, false).unwrap();
+        }
+        let stats = writer.reorder_stats().unwrap();
+        assert!(stats.reordered > 0);
+        assert_eq!(stats.overflowed, 0);
+        writer.finish_with_summary(false).unwrap();
+
+        let mut reader = open_test_file(&path);
+        assert_eq!(reader.file_meta.get_sort_order(), SortOrder::Coordinate);
+        // Blocks of mapped records don't overlap.
+        let pos_blocks = reader.file_meta.view_blocks(&Fields::Pos);
+        for pair in pos_blocks[..pos_blocks.len() - 1].windows(2) {
+            let (prev, next) = (pair[0].stats.clone().unwrap(), pair[1].stats.clone().unwrap());
+            assert!(prev.min_value < prev.max_value && prev.max_value < next.min_value);
+        }
+        let mut recs = reader.records();
+        for expected in sorted.iter().chain(std::iter::once(&unmapped)) {
+            let rec = recs.next_rec().unwrap().unwrap();
+            assert_eq!((rec.refid, rec.pos), (Some(expected.refid), Some(expected.pos)));
+        }
+        assert!(recs.next_rec().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_reorder_window_overflow() {
+        let dir = TempDir::new("gbam_reorder").unwrap();
+        let path = dir.path().join("reordered.gbam");
+        let mut records = sorted_records();
+        // Displaced by 30 records.
+        let late = records.remove(100);
+        records.insert(130, late);
+
+        let mut writer = new_test_writer(&path, "");
+        writer.set_reorder_window(10, true);
+        let err = records
+            .iter()
+            .map(|rec| writer.push_record(&rec.to_raw(), false))
+            .find_map(Result::err)
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(err.to_string(), "Record 130 is out of coordinate order by more than 10 records");
+
+        let mut writer = new_test_writer(&path, "");
+        writer.set_reorder_window(10, false);
+        for rec in &records {
+            writer.push_record(&rec.to_raw(), false).unwrap();
+        }
+        assert_eq!(writer.reorder_stats(), Some(ReorderStats { reordered: 1, overflowed: 1 }));
+        writer.finish_with_summary(false).unwrap();
+
+        let mut reader = open_test_file(&path);
+        assert_eq!(reader.file_meta.get_sort_order(), SortOrder::Unsorted);
+        let mut recs = reader.records();
+        let mut n = 0;
+        while recs.next_rec().unwrap().is_some() {
+            n += 1;
+        }
+        assert_eq!(n, records.len());
+    }
+}
@@ -0,0 +1,355 @@
+//! Rewriting of selected fields, GBAM to GBAM. Only columns with transforms
+//! are decompressed, blocks of other columns are copied as is.
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
+use std::io::{self, Seek, SeekFrom, Write};
+
+use bam_tools::record::fields::{
+    field_type, is_data_field, var_size_field_to_index, FieldType, Fields, FIELDS_NUM,
+};
+use byteorder::{ByteOrder, LittleEndian};
+
+use crate::compressor::{Compressor, CompressorPool, OrderingKey};
+use crate::layout::{index_entry, to_usize, INDEX_ENTRY_SIZE};
+use crate::meta::{BlockMeta, FileInfo, FileMeta, SeqEncoding, SortOrder, Stat};
+use crate::reader::column::decompress_block;
+use crate::reader::reader::Reader;
+use crate::source_index;
+use crate::stats::stat_value;
+use crate::writer::{write_data_and_update_meta, write_meta_and_file_info, BlockInfo, SyncOutput};
+use crate::GBAM_VERSION;
+use crate::provenance::PROVENANCE_EXTENSION;
+
+/// Maps raw bytes of a field value, as stored in its column, to new bytes.
+pub type Transform = Box<dyn FnMut(&[u8]) -> Cow<[u8]>>;
+
+/// Writes copy of file opened by `reader` into `out`, with every value of
+/// fields in `transforms` replaced by output of its closure, called in
+/// record order. Block boundaries and item counts are preserved. Values of
+/// fixed sized fields must keep their size, variable sized ones may change
+/// length, their index columns are regenerated then. Stats and bloom filters
+/// of rewritten blocks are rebuilt, linear index is dropped if RefID, Pos or
+/// RawCigar change, write time analytics are not carried over. Sort order is
+/// kept as is, it's up to the caller not to break it. Only data fields can
+/// be transformed, they must not be encrypted nor 2-bit packed. Returns
+/// total amount of bytes written.
+pub fn rewrite<W: Write + Seek + SyncOutput>(
+    reader: &Reader,
+    mut out: W,
+    mut transforms: HashMap<Fields, Transform>,
+) -> io::Result<u64> {
+    let old_meta = &reader.file_meta;
+    for field in transforms.keys() {
+        check_rewritable(old_meta, field)?;
+    }
+    let mut file_meta = (**old_meta).clone();
+    file_meta.clear_analytics();
+    file_meta.remove_extension(PROVENANCE_EXTENSION);
+    if [Fields::RefID, Fields::Pos, Fields::RawCigar]
+        .iter()
+        .any(|field| transforms.contains_key(field))
+    {
+        file_meta.set_linear_index(None);
+    }
+    let is_sorted = old_meta.get_sort_order() == SortOrder::Coordinate;
+    let mut file_info = FileInfo::new(GBAM_VERSION, 0, 0, String::from("rewrite"), is_sorted);
+    out.seek(SeekFrom::Start(0))?;
+    out.write_all(&file_info.to_padded_bytes()?)?;
+
+    let ciphers = vec![None; FIELDS_NUM];
+    let mut compressor = Compressor::new(CompressorPool::new(1));
+    let mut write_block = |out: &mut W, file_meta: &mut FileMeta, key: usize, block_info: BlockInfo, data: Vec<u8>| {
+        compressor.compress_block(OrderingKey::Key(key as u64), block_info, data);
+        let mut task = compressor.get_compr_block();
+        if let OrderingKey::Key(key) = task.ordering_key {
+            write_data_and_update_meta(out, file_meta, &ciphers, key, &mut task)?;
+        }
+        io::Result::Ok(())
+    };
+    // End offsets of rewritten variable sized fields, by index field.
+    let mut new_ends: HashMap<Fields, Vec<u32>> = HashMap::new();
+    for field in Fields::iterator() {
+        file_meta.get_blocks(field).clear();
+        let codec = *old_meta.get_field_codec(field);
+        let block_info = |block: &BlockMeta, data: &[u8]| BlockInfo {
+            numitems: block.numitems,
+            uncompr_size: data.len(),
+            field: *field,
+            stats: None,
+            bloom: None,
+            transform: None,
+            extra_stats: BTreeMap::new(),
+            codec,
+            stripes: Vec::new(),
+        };
+
+        // Index fields follow their data fields.
+        if let Some(ends) = new_ends.remove(field) {
+            let mut first_rec = 0;
+            for (block_num, block) in old_meta.view_blocks(field).iter().enumerate() {
+                let numitems = block.numitems as usize;
+                let mut data = vec![0; INDEX_ENTRY_SIZE * numitems];
+                LittleEndian::write_u32_into(&ends[first_rec..first_rec + numitems], &mut data);
+                first_rec += numitems;
+                write_block(&mut out, &mut file_meta, block_num, block_info(block, &data), data)?;
+            }
+            continue;
+        }
+
+        let transform = match transforms.get_mut(field) {
+            Some(transform) => transform,
+            None => {
+                for block in old_meta.view_blocks(field) {
+                    let data = reader.block_data(block)?;
+                    let mut block = block.clone();
+                    block.seekpos = out.stream_position()?;
+                    out.write_all(&data)?;
+                    file_meta.get_blocks(field).push(block);
+                }
+                continue;
+            }
+        };
+
+        match field_type(field) {
+            FieldType::FixedSized => {
+                let size = old_meta.get_field_size(field).unwrap() as usize;
+                for (block_num, block) in old_meta.view_blocks(field).iter().enumerate() {
+                    let mut data = decompress(reader, field, block)?;
+                    for value in data.chunks_exact_mut(size) {
+                        let new_value = transform(&*value);
+                        if new_value.len() != size {
+                            return Err(io::Error::new(
+                                io::ErrorKind::InvalidInput,
+                                format!(
+                                    "Transform of fixed sized field {} returned {} bytes instead of {}",
+                                    field,
+                                    new_value.len(),
+                                    size
+                                ),
+                            ));
+                        }
+                        let new_value = new_value.into_owned();
+                        value.copy_from_slice(&new_value);
+                    }
+                    let mut info = block_info(block, &data);
+                    if block.stats.is_some() {
+                        let mut stat = Stat::default();
+                        data.chunks_exact(size)
+                            .for_each(|value| stat.update(stat_value(field, value)));
+                        info.stats = Some(stat);
+                    }
+                    write_block(&mut out, &mut file_meta, block_num, info, data)?;
+                }
+            }
+            FieldType::VariableSized => {
+                let index = var_size_field_to_index(field);
+                let mut old_ends = Vec::with_capacity(reader.amount);
+                for block in old_meta.view_blocks(&index) {
+                    let data = decompress(reader, &index, block)?;
+                    old_ends.extend(data.chunks_exact(INDEX_ENTRY_SIZE).map(index_entry));
+                }
+                let mut ends = Vec::with_capacity(old_ends.len());
+                let mut rec_num = 0;
+                for (block_num, block) in old_meta.view_blocks(field).iter().enumerate() {
+                    let old_data = decompress(reader, field, block)?;
+                    let mut data = Vec::with_capacity(old_data.len());
+                    let mut begin = 0;
+                    for &end in &old_ends[rec_num..rec_num + block.numitems as usize] {
+                        data.extend_from_slice(&transform(&old_data[begin..end]));
+                        ends.push(u32::try_from(data.len()).map_err(|_| {
+                            io::Error::new(
+                                io::ErrorKind::InvalidInput,
+                                format!("Rewritten block of field {} exceeds 4 GiB", field),
+                            )
+                        })?);
+                        begin = end;
+                    }
+                    rec_num += block.numitems as usize;
+                    let mut info = block_info(block, &data);
+                    info.bloom = block.bloom.as_ref().map(|old| {
+                        let mut bloom = old.cleared();
+                        for name in data.split(|&b| b == 0).take(block.numitems as usize) {
+                            bloom.insert(name);
+                        }
+                        bloom
+                    });
+                    write_block(&mut out, &mut file_meta, block_num, info, data)?;
+                }
+                new_ends.insert(index, ends);
+            }
+        }
+    }
+    for mut task in compressor.finish() {
+        if let OrderingKey::Key(key) = task.ordering_key {
+            write_data_and_update_meta(&mut out, &mut file_meta, &ciphers, key, &mut task)?;
+        }
+    }
+    source_index::copy_blocks(std::slice::from_ref(reader), &mut out, &mut file_meta)?;
+    write_meta_and_file_info(&mut out, &mut file_meta, &mut file_info)
+}
+
+fn check_rewritable(meta: &FileMeta, field: &Fields) -> io::Result<()> {
+    if !is_data_field(field) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Field {} is an index field, it's regenerated with its data field", field),
+        ));
+    }
+    if *field == Fields::RawSequence && meta.get_seq_encoding() != SeqEncoding::Nibble {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "RawSequence is not plainly encoded, it can't be rewritten",
+        ));
+    }
+    let seq_fields = [Fields::RawSequence, Fields::RawQual];
+    if meta.is_index_derived(&Fields::RawSeqLen) && seq_fields.contains(field) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Field {} shares its index with another field, it can't be rewritten", field),
+        ));
+    }
+    let mut fields = vec![*field];
+    if matches!(field_type(field), FieldType::VariableSized) {
+        fields.push(var_size_field_to_index(field));
+    }
+    for field in fields {
+        if let Some(encryption) = meta.get_field_encryption(&field) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Field {} is encrypted with key '{}', it can't be rewritten",
+                    field, encryption.key_id
+                ),
+            ));
+        }
+        meta.get_field_codec(&field).check_available(field)?;
+    }
+    Ok(())
+}
+
+fn decompress(reader: &Reader, field: &Fields, block: &BlockMeta) -> io::Result<Vec<u8>> {
+    let mut data = vec![0; to_usize(block.uncompressed_size)?];
+    if block.uncompressed_size > 0 {
+        let codec = block.codec(*reader.file_meta.get_field_codec(field));
+        decompress_block(&reader.block_data(block)?, &mut data, &codec)?;
+    }
+    if let Some(transform) = block.transform {
+        data = transform.invert(&data)?;
+    }
+    block.stripe_of(field, data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{open_test_file, write_test_file, TestRecord};
+    use std::collections::hash_map::DefaultHasher;
+    use std::fs::File;
+    use std::hash::{Hash, Hasher};
+    use tempdir::TempDir;
+
+    fn block_hashes(reader: &Reader, field: &Fields) -> Vec<u64> {
+        reader
+            .file_meta
+            .view_blocks(field)
+            .iter()
+            .map(|block| {
+                let mut hasher = DefaultHasher::new();
+                reader.block_data(block).unwrap().hash(&mut hasher);
+                hasher.finish()
+            })
+            .collect()
+    }
+
+    fn transform<F: FnMut(&[u8]) -> Cow<[u8]> + 'static>(f: F) -> Transform {
+        Box::new(f)
+    }
+
+    fn test_records() -> Vec<TestRecord> {
+        (0..200_000)
+            .map(|i| {
+                let mut rec = TestRecord::new(i % 3, i, &format!("run1:{}{}", "n".repeat(i as usize % 40), i));
+                rec.mapq = (i % 256) as u8;
+                rec
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_rewrite_mapq() {
+        let dir = TempDir::new("gbam_rewrite").unwrap();
+        let src = dir.path().join("in.gbam");
+        let dst = dir.path().join("capped.gbam");
+        let records = test_records();
+        write_test_file(&src, "@HD\tVN:1.6\tSO:coordinate\n", &records);
+
+        let reader = open_test_file(&src);
+        let mut transforms: HashMap<Fields, Transform> = HashMap::new();
+        transforms.insert(
+            Fields::Mapq,
+            transform(|value| {
+                if value[0] > 60 {
+                    Cow::Owned(vec![60])
+                } else {
+                    Cow::Borrowed(value)
+                }
+            }),
+        );
+        rewrite(&reader, File::create(&dst).unwrap(), transforms).unwrap();
+
+        let mut capped = open_test_file(&dst);
+        for field in Fields::iterator() {
+            if *field == Fields::Mapq {
+                continue;
+            }
+            assert_eq!(block_hashes(&reader, field), block_hashes(&capped, field));
+        }
+        let mut fetched = capped.records();
+        for rec in &records {
+            let capped_rec = fetched.next_rec().unwrap().unwrap();
+            assert_eq!(capped_rec.mapq, Some(std::cmp::min(rec.mapq, 60)));
+            assert_eq!(capped_rec.pos, Some(rec.pos));
+        }
+        assert!(fetched.next_rec().unwrap().is_none());
+
+        let mut transforms: HashMap<Fields, Transform> = HashMap::new();
+        transforms.insert(Fields::Mapq, transform(|_| Cow::Owned(vec![0, 0])));
+        assert!(rewrite(&reader, File::create(&dst).unwrap(), transforms).is_err());
+        let mut transforms: HashMap<Fields, Transform> = HashMap::new();
+        transforms.insert(Fields::LName, transform(|item| Cow::Borrowed(item)));
+        assert!(rewrite(&reader, File::create(&dst).unwrap(), transforms).is_err());
+    }
+
+    #[test]
+    fn test_rewrite_variable_length() {
+        let dir = TempDir::new("gbam_rewrite").unwrap();
+        let src = dir.path().join("in.gbam");
+        let dst = dir.path().join("renamed.gbam");
+        let records = test_records();
+        write_test_file(&src, "@HD\tVN:1.6\tSO:coordinate\n", &records);
+
+        let reader = open_test_file(&src);
+        let mut transforms: HashMap<Fields, Transform> = HashMap::new();
+        transforms.insert(
+            Fields::ReadName,
+            transform(|value| {
+                match value.strip_prefix(b"run1:") {
+                    Some(name) => Cow::Owned(name.to_vec()),
+                    None => Cow::Borrowed(value),
+                }
+            }),
+        );
+        rewrite(&reader, File::create(&dst).unwrap(), transforms).unwrap();
+
+        let mut renamed = open_test_file(&dst);
+        assert_eq!(block_hashes(&reader, &Fields::RawQual), block_hashes(&renamed, &Fields::RawQual));
+        let mut fetched = renamed.records();
+        for rec in &records {
+            let renamed_rec = fetched.next_rec().unwrap().unwrap();
+            let name = format!("{}\0", rec.name.strip_prefix("run1:").unwrap());
+            assert_eq!(renamed_rec.read_name.as_deref(), Some(name.as_bytes()));
+            assert_eq!(renamed_rec.mapq, Some(rec.mapq));
+        }
+    }
+}
@@ -0,0 +1,308 @@
+//! SAM text output, formatted the way `samtools view` does.
+use std::fmt::Write as FmtWrite;
+use std::io::{self, Write};
+use std::sync::Arc;
+
+use byteorder::{LittleEndian, ReadBytesExt};
+
+use crate::meta::{sam_header_text, FileMeta};
+use crate::reader::record::GbamRecord;
+
+/// Header text stored in the file. @SQ lines are generated from reference
+/// sequences if the text has none, like samtools does.
+pub fn sam_header(meta: &FileMeta) -> String {
+    let mut header = String::from_utf8_lossy(sam_header_text(meta.get_sam_header()))
+        .trim_end_matches('\0')
+        .to_owned();
+    if !header.is_empty() && !header.ends_with('\n') {
+        header.push('\n');
+    }
+    if !header.lines().any(|line| line.starts_with("@SQ\t")) {
+        for (name, len) in meta.get_ref_seqs() {
+            writeln!(header, "@SQ\tSN:{}\tLN:{}", name, len).unwrap();
+        }
+    }
+    header
+}
+
+/// Formats a fully parsed record as SAM line, without trailing newline.
+/// Fails on malformed tags or reference ids missing from `meta`.
+pub fn to_sam_string(rec: &GbamRecord, meta: &FileMeta) -> io::Result<String> {
+    let mut line = String::new();
+    write_sam_line(rec, meta, &mut line)?;
+    Ok(line)
+}
+
+fn write_sam_line(rec: &GbamRecord, meta: &FileMeta, out: &mut String) -> io::Result<()> {
+    let ref_seqs = meta.get_ref_seqs();
+    let ref_name = |ref_id: i32| -> io::Result<&str> {
+        if ref_id < 0 {
+            return Ok("*");
+        }
+        ref_seqs
+            .get(ref_id as usize)
+            .map(|(name, _)| name.as_str())
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Reference id {} is out of range", ref_id),
+                )
+            })
+    };
+    let ref_id = rec.refid.unwrap();
+    let next_ref_id = rec.next_ref_id.unwrap();
+
+    let name = rec.read_name.as_ref().unwrap();
+    let name = name.strip_suffix(&[0]).unwrap_or(name);
+    out.push_str(&String::from_utf8_lossy(name));
+    write!(out, "\t{}\t{}", rec.flag.unwrap(), ref_name(ref_id)?).unwrap();
+    write!(out, "\t{}\t{}\t", rec.pos.unwrap() + 1, rec.mapq.unwrap()).unwrap();
+    let cigar = rec.cigar.as_ref().unwrap();
+    if cigar.0.is_empty() {
+        out.push('*');
+    } else {
+        write!(out, "{}", cigar).unwrap();
+    }
+    let next_ref_name = if next_ref_id >= 0 && next_ref_id == ref_id {
+        "="
+    } else {
+        ref_name(next_ref_id)?
+    };
+    write!(
+        out,
+        "\t{}\t{}\t{}\t",
+        next_ref_name,
+        rec.next_pos.unwrap() + 1,
+        rec.tlen.unwrap()
+    )
+    .unwrap();
+    let seq = rec.seq.as_ref().unwrap();
+    let qual = rec.qual.as_ref().unwrap();
+    // Qualities are l_seq long, decoded sequence may lack trailing '=' or
+    // carry padding base.
+    if qual.is_empty() {
+        out.push('*');
+    } else {
+        out.push_str(&seq[..seq.len().min(qual.len())]);
+        for _ in seq.len()..qual.len() {
+            out.push('=');
+        }
+    }
+    out.push('\t');
+    // Missing qualities are stored as 0xFF.
+    if qual.is_empty() || qual[0] == 0xFF {
+        out.push('*');
+    } else {
+        out.extend(qual.iter().map(|q| q.wrapping_add(33) as char));
+    }
+    write_tags(rec.tags.as_ref().unwrap(), out)
+}
+
+fn write_tags(mut data: &[u8], out: &mut String) -> io::Result<()> {
+    let truncated = || io::Error::new(io::ErrorKind::InvalidData, "Truncated tag");
+    while !data.is_empty() {
+        if data.len() < 3 {
+            return Err(truncated());
+        }
+        let tag_type = data[2];
+        write!(out, "\t{}{}:", data[0] as char, data[1] as char).unwrap();
+        data = &data[3..];
+        match tag_type {
+            b'A' => write!(out, "A:{}", data.read_u8()? as char).unwrap(),
+            b'Z' | b'H' => {
+                let end = data.iter().position(|&b| b == 0).ok_or_else(truncated)?;
+                write!(out, "{}:{}", tag_type as char, String::from_utf8_lossy(&data[..end]))
+                    .unwrap();
+                data = &data[end + 1..];
+            }
+            b'B' => {
+                let item_type = data.read_u8()?;
+                let len = data.read_u32::<LittleEndian>()?;
+                write!(out, "B:{}", item_type as char).unwrap();
+                for _ in 0..len {
+                    out.push(',');
+                    write_tag_scalar(item_type, &mut data, out)?;
+                }
+            }
+            b'f' => {
+                out.push_str("f:");
+                write_tag_scalar(tag_type, &mut data, out)?;
+            }
+            _ => {
+                out.push_str("i:");
+                write_tag_scalar(tag_type, &mut data, out)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn write_tag_scalar(tag_type: u8, data: &mut &[u8], out: &mut String) -> io::Result<()> {
+    match tag_type {
+        b'c' => write!(out, "{}", data.read_i8()?),
+        b'C' => write!(out, "{}", data.read_u8()?),
+        b's' => write!(out, "{}", data.read_i16::<LittleEndian>()?),
+        b'S' => write!(out, "{}", data.read_u16::<LittleEndian>()?),
+        b'i' => write!(out, "{}", data.read_i32::<LittleEndian>()?),
+        b'I' => write!(out, "{}", data.read_u32::<LittleEndian>()?),
+        b'f' => write!(out, "{}", format_g(data.read_f32::<LittleEndian>()? as f64)),
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Unknown tag type '{}'", tag_type as char),
+            ))
+        }
+    }
+    .unwrap();
+    Ok(())
+}
+
+/// Formats like C printf "%g", which samtools uses for floats.
+fn format_g(val: f64) -> String {
+    const PRECISION: i32 = 6;
+    if val == 0.0 {
+        return String::from(if val.is_sign_negative() { "-0" } else { "0" });
+    }
+    if !val.is_finite() {
+        return String::from(if val.is_nan() {
+            "nan"
+        } else if val > 0.0 {
+            "inf"
+        } else {
+            "-inf"
+        });
+    }
+    // Exponent after rounding to the precision.
+    let sci = format!("{:.*e}", PRECISION as usize - 1, val);
+    let (mantissa, exp) = sci.split_once('e').unwrap();
+    let exp: i32 = exp.parse().unwrap();
+    let strip = |s: &str| -> String {
+        match s.contains('.') {
+            true => s.trim_end_matches('0').trim_end_matches('.').to_owned(),
+            false => s.to_owned(),
+        }
+    };
+    if !(-4..PRECISION).contains(&exp) {
+        let sign = if exp < 0 { '-' } else { '+' };
+        format!("{}e{}{:02}", strip(mantissa), sign, exp.abs())
+    } else {
+        strip(&format!("{:.*}", (PRECISION - 1 - exp) as usize, val))
+    }
+}
+
+/// Streams SAM text into `inner`.
+pub struct SamWriter<W: Write> {
+    inner: W,
+    meta: Arc<FileMeta>,
+    line: String,
+}
+
+impl<W: Write> SamWriter<W> {
+    pub fn new(inner: W, meta: Arc<FileMeta>) -> Self {
+        Self {
+            inner,
+            meta,
+            line: String::new(),
+        }
+    }
+
+    pub fn write_header(&mut self) -> io::Result<()> {
+        self.inner.write_all(sam_header(&self.meta).as_bytes())
+    }
+
+    /// Writes fully parsed record.
+    pub fn write_record(&mut self, rec: &GbamRecord) -> io::Result<()> {
+        self.line.clear();
+        write_sam_line(rec, &self.meta, &mut self.line)?;
+        self.line.push('\n');
+        self.inner.write_all(self.line.as_bytes())
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{open_test_file, write_test_file, TestRecord};
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_format_g() {
+        for (val, expected) in [
+            (1.0, "1"),
+            (0.5, "0.5"),
+            (std::f64::consts::PI, "3.14159"),
+            (123456.0, "123456"),
+            (1234567.0, "1.23457e+06"),
+            (0.0001, "0.0001"),
+            (0.00001234, "1.234e-05"),
+            (-2.5, "-2.5"),
+            (999999.5, "1e+06"),
+        ] {
+            assert_eq!(format_g(val), expected);
+        }
+    }
+
+    #[test]
+    fn test_sam_export() {
+        let dir = TempDir::new("gbam_sam").unwrap();
+        let path = dir.path().join("sam.gbam");
+
+        let mut tags = Vec::new();
+        tags.extend_from_slice(b"XAAx");
+        tags.extend_from_slice(b"Xcc\xfe");
+        tags.extend_from_slice(b"XCC\xc8");
+        tags.extend_from_slice(b"Xss\x18\xfc");
+        tags.extend_from_slice(b"XSS\xe8\x03");
+        tags.extend_from_slice(b"Xii\x00\x00\x00\x80");
+        tags.extend_from_slice(b"XII\xff\xff\xff\xff");
+        tags.extend_from_slice(b"Xff");
+        tags.extend_from_slice(&0.1f32.to_le_bytes());
+        tags.extend_from_slice(b"XZZsome text\0");
+        tags.extend_from_slice(b"XHH1AE301\0");
+        tags.extend_from_slice(b"XBBs\x02\x00\x00\x00\xff\xff\x02\x00");
+        tags.extend_from_slice(b"XbBf\x01\x00\x00\x00");
+        tags.extend_from_slice(&1.5f32.to_le_bytes());
+
+        let mut mapped = TestRecord::new(1, 99, "pair");
+        mapped.flag = 99;
+        mapped.next_refid = 1;
+        mapped.next_pos = 199;
+        mapped.tlen = 104;
+        mapped.cigar = vec![2 << 4, (1 << 4) | 1, 1 << 4];
+        mapped.qual = vec![0, 10, 20, 40];
+        mapped.tags = tags;
+        let mut other_ref = TestRecord::new(0, 5, "other");
+        other_ref.next_refid = 2;
+        other_ref.next_pos = 7;
+        let mut unmapped = TestRecord::new(-1, -1, "unmapped");
+        unmapped.flag = 4;
+        unmapped.mapq = 0;
+        unmapped.cigar.clear();
+        unmapped.seq.clear();
+        unmapped.qual.clear();
+        write_test_file(&path, "@HD\tVN:1.6\n", &[mapped, other_ref, unmapped]);
+
+        let mut reader = open_test_file(&path);
+        let mut writer = SamWriter::new(Vec::new(), reader.file_meta.clone());
+        writer.write_header().unwrap();
+        let mut records = reader.records();
+        while let Some(rec) = records.next_rec().unwrap() {
+            writer.write_record(rec).unwrap();
+        }
+        let expected = "@HD\tVN:1.6\n\
+            @SQ\tSN:chr1\tLN:1000000\n\
+            @SQ\tSN:chr2\tLN:1000000\n\
+            @SQ\tSN:chr3\tLN:1000000\n\
+            pair\t99\tchr2\t100\t60\t2M1I1M\t=\t200\t104\tACGT\t!+5I\
+            \tXA:A:x\tXc:i:-2\tXC:i:200\tXs:i:-1000\tXS:i:1000\tXi:i:-2147483648\
+            \tXI:i:4294967295\tXf:f:0.1\tXZ:Z:some text\tXH:H:1AE301\tXB:B:s,-1,2\
+            \tXb:B:f,1.5\n\
+            other\t0\tchr1\t6\t60\t4M\tchr3\t8\t0\tACGT\t????\n\
+            unmapped\t4\t*\t0\t0\t*\t*\t0\t0\t*\t*\n";
+        assert_eq!(String::from_utf8(writer.into_inner()).unwrap(), expected);
+    }
+}
@@ -0,0 +1,255 @@
+//! 2-bit packing of RawSequence blocks.
+//!
+//! Sequences containing only A, C, G and T are repacked from BAM 4-bit codes
+//! to 2 bits per base, other sequences are kept as is. Packed block layout:
+//!
+//! | n_records: u32 | flags: u8 * n_records | l_seq: u32 * n_records | payload |
+//!
+//! Bit 0 of flags marks records stored at 2 bits per base. For odd length
+//! packed records the upper 4 bits keep the padding nibble, since its value is
+//! not mandated by the spec. Block is unpacked into plain BAM encoding on read,
+//! so column index offsets stay valid.
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::io;
+
+const U32_SIZE: usize = std::mem::size_of::<u32>();
+
+/// BAM 4-bit code to 2-bit code, `None` for ambiguity codes.
+fn nibble_to_2bit(nibble: u8) -> Option<u8> {
+    match nibble {
+        1 => Some(0), // A
+        2 => Some(1), // C
+        4 => Some(2), // G
+        8 => Some(3), // T
+        _ => None,
+    }
+}
+
+const TWO_BIT_TO_NIBBLE: [u8; 4] = [1, 2, 4, 8];
+const PACKED: u8 = 1;
+
+fn get_nibble(seq: &[u8], i: usize) -> u8 {
+    (seq[i / 2] >> (4 * (1 - i % 2))) & 0xf
+}
+
+/// Returns 2-bit packed sequence or None if it contains ambiguity codes.
+fn pack_seq(seq: &[u8], l_seq: usize) -> Option<Vec<u8>> {
+    let mut packed = vec![0; l_seq.div_ceil(4)];
+    for i in 0..l_seq {
+        let code = nibble_to_2bit(get_nibble(seq, i))?;
+        packed[i / 4] |= code << (2 * (3 - i % 4));
+    }
+    Some(packed)
+}
+
+fn unpack_seq(packed: &[u8], l_seq: usize, padding: u8, dest: &mut Vec<u8>) {
+    for pair in (0..l_seq).step_by(2) {
+        // Overwritten unless this is the last base of odd length sequence.
+        let mut byte = padding;
+        for i in pair..std::cmp::min(pair + 2, l_seq) {
+            let code = (packed[i / 4] >> (2 * (3 - i % 4))) & 0b11;
+            byte &= !(0xf << (4 * (1 - i % 2)));
+            byte |= TWO_BIT_TO_NIBBLE[code as usize] << (4 * (1 - i % 2));
+        }
+        dest.push(byte);
+    }
+}
+
+/// Packs block of concatenated BAM sequences. `seq_lens` holds l_seq of each
+/// record in the block.
+pub(crate) fn pack_block(data: &[u8], seq_lens: &[u32]) -> Vec<u8> {
+    let n = seq_lens.len();
+    let mut flags = vec![0u8; n];
+    let mut payload = Vec::with_capacity(data.len() / 2);
+    let mut offset = 0;
+    for (i, &l_seq) in seq_lens.iter().enumerate() {
+        let l_seq = l_seq as usize;
+        let seq = &data[offset..offset + l_seq.div_ceil(2)];
+        offset += seq.len();
+        match pack_seq(seq, l_seq) {
+            Some(packed) => {
+                flags[i] = PACKED;
+                if l_seq % 2 == 1 {
+                    flags[i] |= get_nibble(seq, l_seq) << 4;
+                }
+                payload.extend_from_slice(&packed);
+            }
+            None => payload.extend_from_slice(seq),
+        }
+    }
+    debug_assert_eq!(offset, data.len());
+
+    let mut res = Vec::with_capacity(U32_SIZE * (n + 1) + flags.len() + payload.len());
+    res.write_u32::<LittleEndian>(n as u32).unwrap();
+    res.extend_from_slice(&flags);
+    for &l_seq in seq_lens {
+        res.write_u32::<LittleEndian>(l_seq).unwrap();
+    }
+    res.extend_from_slice(&payload);
+    res
+}
+
+/// l_seq of each record in block produced by `pack_block`.
+pub(crate) fn packed_seq_lens(data: &[u8]) -> io::Result<Vec<u32>> {
+    let mut cursor = data;
+    let n = cursor.read_u32::<LittleEndian>()? as usize;
+    if cursor.len() < n * (1 + U32_SIZE) {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Packed sequence block is truncated"));
+    }
+    let mut cursor = &cursor[n..];
+    (0..n).map(|_| cursor.read_u32::<LittleEndian>()).collect()
+}
+
+/// Restores BAM encoding of block produced by `pack_block`.
+pub(crate) fn unpack_block(data: &[u8]) -> io::Result<Vec<u8>> {
+    let truncated = || io::Error::new(io::ErrorKind::InvalidData, "Packed sequence block is truncated");
+    let mut cursor = data;
+    let n = cursor.read_u32::<LittleEndian>()? as usize;
+    if cursor.len() < n * (1 + U32_SIZE) {
+        return Err(truncated());
+    }
+    let (flags, mut cursor) = cursor.split_at(n);
+    let mut seq_lens = Vec::with_capacity(n);
+    for _ in 0..n {
+        seq_lens.push(cursor.read_u32::<LittleEndian>()? as usize);
+    }
+
+    let mut res = Vec::with_capacity(seq_lens.iter().map(|l| l.div_ceil(2)).sum());
+    for (i, l_seq) in seq_lens.into_iter().enumerate() {
+        let is_packed = flags[i] & PACKED != 0;
+        let size = if is_packed {
+            l_seq.div_ceil(4)
+        } else {
+            l_seq.div_ceil(2)
+        };
+        if cursor.len() < size {
+            return Err(truncated());
+        }
+        let (seq, rest) = cursor.split_at(size);
+        if is_packed {
+            unpack_seq(seq, l_seq, flags[i] >> 4, &mut res);
+        } else {
+            res.extend_from_slice(seq);
+        }
+        cursor = rest;
+    }
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::meta::SeqEncoding;
+    use crate::test_utils::{new_test_writer, open_test_file, TestRecord};
+    use bam_tools::record::bamrawrecord::put_sequence;
+    use tempdir::TempDir;
+
+    fn nibble_packed(seqs: &[&str]) -> (Vec<u8>, Vec<u32>) {
+        let mut data = Vec::new();
+        for seq in seqs {
+            let mut bytes = vec![0; seq.len().div_ceil(2)];
+            put_sequence(&mut bytes[..], seq.len(), &seq.to_string()).unwrap();
+            data.extend_from_slice(&bytes);
+        }
+        (data, seqs.iter().map(|s| s.len() as u32).collect())
+    }
+
+    #[test]
+    fn test_pack_block_round_trip() {
+        let seqs = ["ACGTACGT", "ACGTA", "", "ACNGT", "T", "GGCCAATTG"];
+        let (data, lens) = nibble_packed(&seqs);
+        let packed = pack_block(&data, &lens);
+        // Only "ACNGT" isn't packed, odd length ones keep 'N' padding.
+        assert_eq!(&packed[4..10], &[1, 0xf1, 1, 0, 0xf1, 0xf1]);
+        assert_eq!(unpack_block(&packed).unwrap(), data);
+        assert!(unpack_block(&packed[..packed.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn test_packed_seq_column() {
+        let tmp_dir = TempDir::new("gbam_seq_packing").unwrap();
+        let path = tmp_dir.path().join("packed.gbam");
+        let seqs = ["ACGTACGTAC", "ACGTA", "", "NNNNACGT", "T"];
+        let mut writer = new_test_writer(&path, "");
+        writer.set_seq_packing(true);
+        for (i, seq) in seqs.iter().cycle().take(100_000).enumerate() {
+            let rec = TestRecord {
+                name: format!("r{}", i),
+                cigar: Vec::new(),
+                seq: seq.to_string(),
+                qual: vec![30; seq.len()],
+                ..Default::default()
+            };
+            writer.push_record(&rec.to_raw(), false).unwrap();
+        }
+        writer.finish_with_summary(false).unwrap();
+
+        let mut reader = open_test_file(&path);
+        assert_eq!(reader.file_meta.get_seq_encoding(), SeqEncoding::TwoBit);
+        let mut records = reader.records();
+        let mut i = 0;
+        while let Some(rec) = records.next_rec().unwrap() {
+            let seq = seqs[i % seqs.len()];
+            let decoded = rec.seq.as_ref().unwrap();
+            // Odd length sequences are decoded with padding base.
+            assert_eq!(&decoded[..seq.len()], seq);
+            i += 1;
+        }
+        assert_eq!(i, 100_000);
+    }
+
+    #[cfg(feature = "zstd")]
+    use {
+        crate::compressor::compress,
+        crate::meta::Codecs,
+        rand::{rngs::StdRng, Rng, SeedableRng},
+    };
+
+    /// Compression ratios of zstd on nibble-packed and 2-bit packed `seqs`.
+    #[cfg(feature = "zstd")]
+    fn zstd_ratios(seqs: &[String]) -> (f64, f64) {
+        let seqs: Vec<&str> = seqs.iter().map(|s| s.as_str()).collect();
+        let (data, lens) = nibble_packed(&seqs);
+        let nibble_size = compress(&data, Vec::new(), Codecs::Zstd).unwrap().len();
+        let two_bit_size = compress(&pack_block(&data, &lens), Vec::new(), Codecs::Zstd).unwrap().len();
+        (
+            data.len() as f64 / nibble_size as f64,
+            data.len() as f64 / two_bit_size as f64,
+        )
+    }
+
+    #[cfg(feature = "zstd")]
+    fn random_seq(rng: &mut StdRng, len: usize) -> String {
+        (0..len)
+            .map(|_| ['A', 'C', 'G', 'T'][rng.gen_range(0..4)])
+            .collect()
+    }
+
+    #[test]
+    #[cfg(feature = "zstd")]
+    fn test_compression_ratio() {
+        let mut rng = StdRng::seed_from_u64(42);
+
+        // Unrelated reads: packing removes redundancy zstd can't see.
+        let seqs: Vec<String> = (0..20_000).map(|_| random_seq(&mut rng, 151)).collect();
+        let (nibble_ratio, two_bit_ratio) = zstd_ratios(&seqs);
+        assert!(nibble_ratio > 1.8 && nibble_ratio < 2.0, "nibble ratio {:.2}", nibble_ratio);
+        assert!(two_bit_ratio > 1.95, "2-bit ratio {:.2}", two_bit_ratio);
+        assert!(0.95 * two_bit_ratio > nibble_ratio);
+
+        // 30x coverage of a small genome. Zstd finds fewer repeats in 2-bit
+        // data (a base has 4 possible phases in a byte instead of 2), so here
+        // packing loses.
+        let genome = random_seq(&mut rng, 100_000);
+        let seqs: Vec<String> = (0..20_000)
+            .map(|_| {
+                let start = rng.gen_range(0..genome.len() - 151);
+                genome[start..start + 151].to_owned()
+            })
+            .collect();
+        let (nibble_ratio, two_bit_ratio) = zstd_ratios(&seqs);
+        assert!(nibble_ratio > 8.5, "nibble ratio {:.2}", nibble_ratio);
+        assert!(two_bit_ratio > 6.5, "2-bit ratio {:.2}", two_bit_ratio);
+        assert!(two_bit_ratio < 0.9 * nibble_ratio);
+    }
+}
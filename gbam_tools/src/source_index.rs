@@ -0,0 +1,434 @@
+//! Optional column of ingest ordinals of records, see
+//! `Writer::set_source_index()`. Values are u64, stored in blocks outside of
+//! the columns of `Fields` and described by meta extension
+//! `SOURCE_INDEX_EXTENSION`. Tools which reorder, merge or subset records
+//! carry the values of their inputs over, so records can be traced back to
+//! the order they were first written in.
+use std::hash::Hasher;
+use std::io::{self, Seek, Write};
+use std::sync::Arc;
+
+use byteorder::{ByteOrder, LittleEndian};
+use serde::{Deserialize, Serialize};
+use twox_hash::XxHash64;
+
+use crate::compressor::compress;
+use crate::layout::to_usize;
+use crate::meta::{BlockMeta, Codecs, FileMeta};
+use crate::reader::column::decompress_block;
+use crate::reader::reader::{FileBytes, Reader};
+use crate::SIZE_LIMIT;
+
+/// Name of meta extension describing the column, see
+/// `FileMeta::get_extension()`.
+pub const SOURCE_INDEX_EXTENSION: &str = "source_index";
+
+const ITEM_SIZE: usize = std::mem::size_of::<u64>();
+
+/// Blocks of the column, stored in meta extension.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SourceIndexMeta {
+    pub codec: Codecs,
+    pub blocks: Vec<BlockMeta>,
+}
+
+impl SourceIndexMeta {
+    /// Meta of the column of file with `meta`, None if it has no column.
+    pub fn from_file_meta(meta: &FileMeta) -> io::Result<Option<Self>> {
+        let value = match meta.get_extension(SOURCE_INDEX_EXTENSION) {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+        let column: Self = serde_json::from_value(value.clone()).map_err(|err| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Meta extension {} is damaged: {}", SOURCE_INDEX_EXTENSION, err),
+            )
+        })?;
+        // Buffers are sized by the claims, see `FileMeta::check_sizes()`.
+        for (block_num, block) in column.blocks.iter().enumerate() {
+            let limit = block.max_uncompressed_size(column.codec, SIZE_LIMIT);
+            if block.uncompressed_size > limit {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "Source index block {} claims {} decompressed bytes, more than its codec yields",
+                        block_num, block.uncompressed_size
+                    ),
+                ));
+            }
+        }
+        Ok(Some(column))
+    }
+
+    pub fn numitems(&self) -> u64 {
+        self.blocks.iter().map(|block| u64::from(block.numitems)).sum()
+    }
+
+    /// Stores this meta in `meta`.
+    pub fn set_in(&self, meta: &mut FileMeta) {
+        meta.set_extension(SOURCE_INDEX_EXTENSION, serde_json::to_value(self).unwrap());
+    }
+}
+
+/// Values of the column being written, flushed into blocks of `SIZE_LIMIT`
+/// bytes, or of `rows` values if given.
+pub(crate) struct SourceIndexWriter {
+    codec: Codecs,
+    buffer: Vec<u8>,
+    blocks: Vec<BlockMeta>,
+    // Records ingested so far, the ordinal of the next one.
+    ingested: u64,
+}
+
+impl SourceIndexWriter {
+    pub fn new(codec: Codecs) -> Self {
+        Self {
+            codec,
+            buffer: Vec::new(),
+            blocks: Vec::new(),
+            ingested: 0,
+        }
+    }
+
+    /// Values of the next `n` records ingested, `sources` if given,
+    /// otherwise their ordinals. See `ingested()`.
+    pub fn values_of(&self, n: usize, sources: Option<&[u64]>) -> Vec<u64> {
+        match sources {
+            Some(sources) => sources.to_vec(),
+            None => (self.ingested..self.ingested + n as u64).collect(),
+        }
+    }
+
+    /// Counts `n` records as ingested.
+    pub fn ingested(&mut self, n: usize) {
+        self.ingested += n as u64;
+    }
+
+    /// Buffers `values` of records written, flushing full blocks into `out`.
+    /// Returns bytes written.
+    pub fn push<W: Write + Seek>(
+        &mut self,
+        values: &[u64],
+        rows: Option<u32>,
+        out: &mut W,
+        digest: &mut Option<XxHash64>,
+    ) -> io::Result<u64> {
+        let mut written = 0;
+        for &value in values {
+            let mut bytes = [0; ITEM_SIZE];
+            LittleEndian::write_u64(&mut bytes, value);
+            self.buffer.extend_from_slice(&bytes);
+            let numitems = self.buffer.len() / ITEM_SIZE;
+            if self.buffer.len() >= SIZE_LIMIT || rows == Some(numitems as u32) {
+                written += self.flush(out, digest)?;
+            }
+        }
+        Ok(written)
+    }
+
+    /// Writes buffered values as a block, if any. Returns bytes written.
+    pub fn flush<W: Write + Seek>(&mut self, out: &mut W, digest: &mut Option<XxHash64>) -> io::Result<u64> {
+        if self.buffer.is_empty() {
+            return Ok(0);
+        }
+        let (block, bytes) = write_block(out, self.codec, &self.buffer)?;
+        if let Some(digest) = digest.as_mut() {
+            digest.write(&bytes);
+        }
+        self.blocks.push(block);
+        self.buffer.clear();
+        Ok(bytes.len() as u64)
+    }
+
+    pub fn finish(self) -> SourceIndexMeta {
+        debug_assert!(self.buffer.is_empty());
+        SourceIndexMeta {
+            codec: self.codec,
+            blocks: self.blocks,
+        }
+    }
+}
+
+/// Writes `data` compressed with `codec` at the current position of `out`,
+/// or as is if compression makes it larger. Returns meta of the block and
+/// bytes written.
+fn write_block<W: Write + Seek>(out: &mut W, codec: Codecs, data: &[u8]) -> io::Result<(BlockMeta, Vec<u8>)> {
+    let mut compressed = compress(data, Vec::new(), codec)?;
+    let stored = compressed.len() > data.len();
+    if stored {
+        compressed = data.to_vec();
+    }
+    let block = BlockMeta {
+        seekpos: out.stream_position()?,
+        numitems: (data.len() / ITEM_SIZE) as u32,
+        block_size: compressed.len() as u32,
+        uncompressed_size: data.len() as u64,
+        stored,
+        ..Default::default()
+    };
+    out.write_all(&compressed)?;
+    Ok((block, compressed))
+}
+
+/// Values of the column of an open file, decoded a block at a time.
+pub(crate) struct SourceIndexColumn {
+    meta: Arc<SourceIndexMeta>,
+    bytes: Arc<FileBytes>,
+    // First record of each block, then the number of records.
+    starts: Vec<usize>,
+    cur_block: Option<usize>,
+    buffer: Vec<u8>,
+}
+
+impl SourceIndexColumn {
+    pub fn new(meta: Arc<SourceIndexMeta>, bytes: Arc<FileBytes>) -> Self {
+        let mut starts = vec![0];
+        for block in &meta.blocks {
+            starts.push(starts.last().unwrap() + block.numitems as usize);
+        }
+        Self {
+            meta,
+            bytes,
+            starts,
+            cur_block: None,
+            buffer: Vec::new(),
+        }
+    }
+
+    /// Column of file opened by `reader`, None if it has none.
+    pub fn of_reader(reader: &Reader) -> io::Result<Option<Self>> {
+        Ok(SourceIndexMeta::from_file_meta(&reader.file_meta)?
+            .map(|meta| Self::new(Arc::new(meta), reader.mmap.clone())))
+    }
+
+    /// Value of record `rec_num`, in file order.
+    pub fn get(&mut self, rec_num: usize) -> io::Result<u64> {
+        let block_num = self.starts.partition_point(|&start| start <= rec_num) - 1;
+        if self.cur_block != Some(block_num) {
+            self.cur_block = None;
+            let block = self.meta.blocks.get(block_num).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Record {} has no source index", rec_num),
+                )
+            })?;
+            let data = self.bytes.read_at(block.seekpos, block.block_size as usize)?;
+            self.buffer.resize(to_usize(block.uncompressed_size)?, 0);
+            decompress_block(&data, &mut self.buffer, &block.codec(self.meta.codec))?;
+            if self.buffer.len() != block.numitems as usize * ITEM_SIZE {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Source index block {} has {} bytes", block_num, self.buffer.len()),
+                ));
+            }
+            self.cur_block = Some(block_num);
+        }
+        let offset = (rec_num - self.starts[block_num]) * ITEM_SIZE;
+        Ok(LittleEndian::read_u64(&self.buffer[offset..offset + ITEM_SIZE]))
+    }
+
+    pub fn meta(&self) -> Arc<SourceIndexMeta> {
+        self.meta.clone()
+    }
+
+    /// Drops the decoded block, it's read again when needed.
+    pub fn release(&mut self) {
+        self.buffer = Vec::new();
+        self.cur_block = None;
+    }
+}
+
+/// Copies blocks of the columns of files opened by `readers` into `out`, as
+/// they are and in order, and records them in `meta`. The column is removed
+/// from `meta` unless every file has it, with the same codec.
+pub(crate) fn copy_blocks<W: Write + Seek>(
+    readers: &[Reader],
+    out: &mut W,
+    meta: &mut FileMeta,
+) -> io::Result<()> {
+    let mut columns: Vec<(&Reader, SourceIndexMeta)> = Vec::with_capacity(readers.len());
+    for reader in readers {
+        match SourceIndexMeta::from_file_meta(&reader.file_meta)? {
+            Some(column) if columns.is_empty() || columns[0].1.codec == column.codec => {
+                columns.push((reader, column))
+            }
+            _ => {
+                meta.remove_extension(SOURCE_INDEX_EXTENSION);
+                return Ok(());
+            }
+        }
+    }
+    let mut copied = match columns.first() {
+        Some((_, column)) => SourceIndexMeta {
+            codec: column.codec,
+            blocks: Vec::new(),
+        },
+        None => return Ok(()),
+    };
+    for (reader, column) in columns {
+        for mut block in column.blocks {
+            let data = reader.block_data(&block)?;
+            block.seekpos = out.stream_position()?;
+            out.write_all(&data)?;
+            copied.blocks.push(block);
+        }
+    }
+    copied.set_in(meta);
+    Ok(())
+}
+
+/// Writes values of records `first..last` of the column of file opened by
+/// `reader` into `out`, and records them in `meta`. The column is removed
+/// from `meta` for files without it.
+pub(crate) fn write_range<W: Write + Seek>(
+    reader: &Reader,
+    first: usize,
+    last: usize,
+    out: &mut W,
+    meta: &mut FileMeta,
+) -> io::Result<()> {
+    let mut column = match SourceIndexColumn::of_reader(reader)? {
+        Some(column) => column,
+        None => {
+            meta.remove_extension(SOURCE_INDEX_EXTENSION);
+            return Ok(());
+        }
+    };
+    let values = (first..last).map(|rec_num| column.get(rec_num)).collect::<io::Result<Vec<u64>>>()?;
+    let mut writer = SourceIndexWriter::new(column.meta.codec);
+    writer.push(&values, None, out, &mut None)?;
+    writer.flush(out, &mut None)?;
+    writer.finish().set_in(meta);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cat::cat;
+    use crate::merge::{merge, MergeOptions};
+    use crate::reader::record::GbamRecord;
+    use crate::split::split;
+    use crate::test_utils::{new_test_writer, open_test_file, TestRecord};
+    use bam_tools::record::bamrawrecord::BAMRawRecord;
+    use rand::{rngs::StdRng, seq::SliceRandom, SeedableRng};
+    use std::borrow::Cow;
+    use std::fs::File;
+    use std::path::Path;
+    use tempdir::TempDir;
+
+    fn read_all(path: &Path) -> Vec<GbamRecord> {
+        let mut reader = open_test_file(path);
+        let mut records = reader.records();
+        let mut all = Vec::new();
+        while let Some(rec) = records.next_rec().unwrap() {
+            all.push(rec.clone());
+        }
+        all
+    }
+
+    fn key(rec: &GbamRecord) -> (Option<i32>, Option<i32>, Option<Vec<u8>>) {
+        (rec.refid, rec.pos, rec.read_name.clone())
+    }
+
+    #[test]
+    fn test_source_index_survives_sort() {
+        let dir = TempDir::new("gbam_source_index").unwrap();
+        let shuffled_path = dir.path().join("shuffled.gbam");
+        let sorted_path = dir.path().join("sorted.gbam");
+        let mut records: Vec<TestRecord> =
+            (0..3_000).map(|i| TestRecord::new(i % 3, i * 7, &format!("read{}", i))).collect();
+        records.shuffle(&mut StdRng::seed_from_u64(377));
+
+        let mut writer = new_test_writer(&shuffled_path, "");
+        writer.set_rows_per_block(500);
+        writer.set_source_index(true);
+        for batch in records.chunks(256) {
+            let raw: Vec<_> = batch.iter().map(TestRecord::to_raw).collect();
+            writer.push_records(&raw, false).unwrap();
+        }
+        writer.finish_with_summary(false).unwrap();
+        let shuffled = read_all(&shuffled_path);
+        assert!(open_test_file(&shuffled_path).file_meta.has_source_index());
+        for (i, rec) in shuffled.iter().enumerate() {
+            assert_eq!(rec.source_index(), Some(i as u64));
+        }
+
+        // Sorted by the reordering window, values come from the records.
+        let mut writer = new_test_writer(&sorted_path, "");
+        writer.set_rows_per_block(700);
+        writer.set_source_index(true);
+        writer.set_reorder_window(shuffled.len(), true);
+        let mut bytes = Vec::new();
+        for rec in &shuffled {
+            rec.convert_to_bytes(&mut bytes);
+            let raw = BAMRawRecord(Cow::Borrowed(&bytes[4..]));
+            writer.push_record_with_source_index(&raw, rec.source_index(), false).unwrap();
+        }
+        writer.finish_with_summary(false).unwrap();
+        let sorted = read_all(&sorted_path);
+        assert_eq!(sorted.len(), shuffled.len());
+        assert!(sorted.windows(2).all(|pair| (pair[0].refid, pair[0].pos) <= (pair[1].refid, pair[1].pos)));
+        for rec in &sorted {
+            let original = &shuffled[rec.source_index().unwrap() as usize];
+            assert_eq!(key(rec), key(original));
+        }
+
+        // Files without the column have no values.
+        let plain_path = dir.path().join("plain.gbam");
+        let mut writer = new_test_writer(&plain_path, "");
+        writer.push_record(&records[0].to_raw(), false).unwrap();
+        writer.finish_with_summary(false).unwrap();
+        assert!(!open_test_file(&plain_path).file_meta.has_source_index());
+        assert_eq!(read_all(&plain_path)[0].source_index(), None);
+    }
+
+    #[test]
+    fn test_source_index_kept_by_tools() {
+        let dir = TempDir::new("gbam_source_index").unwrap();
+        let paths: Vec<_> = (0..2).map(|i| dir.path().join(format!("in{}.gbam", i))).collect();
+        for (i, path) in paths.iter().enumerate() {
+            let mut writer = new_test_writer(path, "@HD\tVN:1.6\tSO:coordinate\n");
+            writer.set_source_index(true);
+            for j in 0..1_000 {
+                let rec = TestRecord::new(0, j * 2 + i as i32, &format!("in{}_{}", i, j));
+                writer.push_record(&rec.to_raw(), false).unwrap();
+            }
+            writer.finish_with_summary(false).unwrap();
+        }
+        let check = |path: &Path| {
+            let records = read_all(path);
+            for rec in &records {
+                let name = String::from_utf8_lossy(rec.read_name.as_ref().unwrap()).into_owned();
+                let ordinal: u64 = name.trim_end_matches('\0').rsplit('_').next().unwrap().parse().unwrap();
+                assert_eq!(rec.source_index(), Some(ordinal), "{}", name);
+            }
+            records.len()
+        };
+
+        let merged = dir.path().join("merged.gbam");
+        let mut inputs: Vec<Reader> = paths.iter().map(|path| open_test_file(path)).collect();
+        merge(&mut inputs, &merged, &MergeOptions::default()).unwrap();
+        assert_eq!(check(&merged), 2_000);
+
+        let catted = dir.path().join("catted.gbam");
+        cat(&inputs, File::create(&catted).unwrap()).unwrap();
+        assert_eq!(check(&catted), 2_000);
+
+        let shards: Vec<_> = (0..3).map(|i| dir.path().join(format!("shard{}.gbam", i))).collect();
+        let outputs = shards.iter().map(|path| File::create(path).unwrap()).collect();
+        split(&open_test_file(&merged), outputs).unwrap();
+        assert_eq!(shards.iter().map(|path| check(path)).sum::<usize>(), 2_000);
+
+        // Inputs without the column drop it.
+        let plain = dir.path().join("plain.gbam");
+        let mut writer = new_test_writer(&plain, "@HD\tVN:1.6\tSO:coordinate\n");
+        writer.push_record(&TestRecord::new(0, 5, "plain").to_raw(), false).unwrap();
+        writer.finish_with_summary(false).unwrap();
+        inputs.push(open_test_file(&plain));
+        cat(&inputs, File::create(&catted).unwrap()).unwrap();
+        assert!(!open_test_file(&catted).file_meta.has_source_index());
+        assert_eq!(read_all(&catted)[0].source_index(), None);
+    }
+}
@@ -0,0 +1,362 @@
+//! Splitting of GBAM files into shards of consecutive records, the inverse of
+//! `cat`.
+use std::collections::BTreeMap;
+use std::io::{self, Seek, SeekFrom, Write};
+
+use bam_tools::record::fields::{field_item_size, var_size_field_to_index, Fields};
+use byteorder::{ByteOrder, LittleEndian};
+
+use crate::bloom::BloomFilter;
+use crate::compressor::compress;
+use crate::layout::{encode_index_entry, index_entry, to_usize, INDEX_ENTRY_SIZE};
+use crate::meta::{BlockMeta, FileInfo, FileMeta, SeqEncoding, SortOrder, Stat};
+use crate::reader::column::decompress_block;
+use crate::reader::reader::Reader;
+use crate::ref_compression::slice_block;
+use crate::seq_packing::{pack_block, packed_seq_lens, unpack_block};
+use crate::source_index;
+use crate::writer::{write_meta_and_file_info, SyncOutput};
+use crate::GBAM_VERSION;
+use crate::provenance::PROVENANCE_EXTENSION;
+
+/// Block counts of `split()`.
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub struct SplitStats {
+    /// Blocks copied without decompression.
+    pub blocks_copied: u64,
+    /// Blocks cut at shard boundaries or with rebased offsets.
+    pub blocks_rewritten: u64,
+}
+
+/// Writes records of file opened by `reader` into `outputs`, shard i getting
+/// records [i * n / k, (i + 1) * n / k) of n records and k outputs. Blocks
+/// inside a shard are copied verbatim, only blocks crossing shard boundaries
+/// are decompressed and cut, as are index blocks whose offsets have to be
+/// rebased to the cut. Stats of cut blocks are recomputed, bloom filters are
+/// kept as is. Encrypted files and files with derived RawSequence index
+/// can't be split. Write time analytics and
+/// linear index are not carried over.
+pub fn split<W: Write + Seek + SyncOutput>(reader: &Reader, outputs: Vec<W>) -> io::Result<SplitStats> {
+    if outputs.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "No outputs to split into"));
+    }
+    let old_meta = &reader.file_meta;
+    if old_meta.is_index_derived(&Fields::RawSeqLen) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Index of RawSequence is derived from sequence lengths, file can't be split",
+        ));
+    }
+    for field in Fields::iterator() {
+        // Blocks are encrypted with their block number as nonce.
+        if old_meta.get_field_encryption(field).is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Field {} is encrypted, file can't be split", field),
+            ));
+        }
+    }
+
+    let mut columns: Vec<Blocks> = Fields::iterator().map(|field| Blocks::new(reader, *field)).collect();
+    let mut stats = SplitStats::default();
+    let shards = outputs.len();
+    for (shard, mut out) in outputs.into_iter().enumerate() {
+        let first = shard * reader.amount / shards;
+        let last = (shard + 1) * reader.amount / shards;
+
+        let mut file_meta = (**old_meta).clone();
+        file_meta.clear_analytics();
+        file_meta.remove_extension(PROVENANCE_EXTENSION);
+        file_meta.set_linear_index(None);
+        let tail_start = old_meta.get_unmapped_tail_start().map(|start| (start as usize).clamp(first, last));
+        file_meta.set_unmapped_tail_start(tail_start.map(|start| (start - first) as u64));
+        if let Some(rows) = old_meta.get_rows_per_block() {
+            if !first.is_multiple_of(rows as usize) {
+                file_meta.set_rows_per_block(None);
+            }
+        }
+        let is_sorted = old_meta.get_sort_order() == SortOrder::Coordinate;
+        let mut file_info = FileInfo::new(GBAM_VERSION, 0, 0, String::from("split"), is_sorted);
+        out.seek(SeekFrom::Start(0))?;
+        out.write_all(&file_info.to_padded_bytes()?)?;
+
+        for field in Fields::iterator() {
+            file_meta.get_blocks(field).clear();
+            if first == last {
+                // Files without records still have an empty block per field.
+                write_block(&mut out, &mut file_meta, *field, &[], 0, None, None)?;
+                continue;
+            }
+            write_field(&mut columns, &mut out, &mut file_meta, *field, first, last, &mut stats)?;
+        }
+        source_index::write_range(reader, first, last, &mut out, &mut file_meta)?;
+
+        write_meta_and_file_info(&mut out, &mut file_meta, &mut file_info)?;
+    }
+    Ok(stats)
+}
+
+// Decompressed blocks of one field, the last one is cached.
+struct Blocks<'a> {
+    reader: &'a Reader,
+    field: Fields,
+    // First record of each block, followed by total number of records.
+    starts: Vec<usize>,
+    cached: Option<(usize, Vec<u8>)>,
+}
+
+impl<'a> Blocks<'a> {
+    fn new(reader: &'a Reader, field: Fields) -> Self {
+        let mut starts = vec![0];
+        for block in reader.file_meta.view_blocks(&field) {
+            starts.push(starts.last().unwrap() + block.numitems as usize);
+        }
+        Self {
+            reader,
+            field,
+            starts,
+            cached: None,
+        }
+    }
+
+    fn block_of(&self, rec_num: usize) -> usize {
+        self.starts.partition_point(|&start| start <= rec_num) - 1
+    }
+
+    /// Block as it was before compression, so sequences stay packed. Flags
+    /// and Mapq transforms are undone, shards are written without them.
+    fn raw(&mut self, block_num: usize) -> io::Result<&[u8]> {
+        if self.cached.as_ref().map(|(num, _)| *num) != Some(block_num) {
+            let meta = &self.reader.file_meta;
+            let block = &meta.view_blocks(&self.field)[block_num];
+            let data = self.reader.block_data(block)?;
+            let mut uncompressed = vec![0; to_usize(block.uncompressed_size)?];
+            if block.uncompressed_size > 0 {
+                let codec = block.codec(*meta.get_field_codec(&self.field));
+                decompress_block(&data, &mut uncompressed, &codec)?;
+            }
+            if let Some(transform) = block.transform {
+                uncompressed = transform.invert(&uncompressed)?;
+            }
+            uncompressed = block.stripe_of(&self.field, uncompressed)?;
+            self.cached = Some((block_num, uncompressed));
+        }
+        Ok(&self.cached.as_ref().unwrap().1)
+    }
+
+    /// Value of index field: end offset of the record in its data block.
+    fn end_offset(&mut self, rec_num: usize) -> io::Result<usize> {
+        let block_num = self.block_of(rec_num);
+        let item = rec_num - self.starts[block_num];
+        let data = self.raw(block_num)?;
+        Ok(index_entry(&data[item * INDEX_ENTRY_SIZE..]))
+    }
+}
+
+fn write_block<W: Write + Seek>(
+    out: &mut W,
+    file_meta: &mut FileMeta,
+    field: Fields,
+    data: &[u8],
+    numitems: usize,
+    stats: Option<Stat>,
+    bloom: Option<BloomFilter>,
+) -> io::Result<()> {
+    let mut compressed = compress(data, Vec::new(), *file_meta.get_field_codec(&field))?;
+    let stored = compressed.len() > data.len();
+    if stored {
+        compressed = data.to_vec();
+    }
+    let block = BlockMeta {
+        seekpos: out.stream_position()?,
+        numitems: numitems as u32,
+        block_size: compressed.len() as u32,
+        uncompressed_size: data.len() as u64,
+        stats,
+        bloom,
+        transform: None,
+        extra_stats: BTreeMap::new(),
+        stripe: None,
+        stored,
+    };
+    out.write_all(&compressed)?;
+    file_meta.get_blocks(&field).push(block);
+    Ok(())
+}
+
+fn column_pos(field: Fields) -> usize {
+    Fields::iterator().position(|f| *f == field).unwrap()
+}
+
+// Data field of index field.
+fn indexed_field(index: Fields) -> Option<Fields> {
+    Fields::iterator()
+        .copied()
+        .find(|field| field_item_size(field).is_none() && var_size_field_to_index(field) == index)
+}
+
+fn write_field<W: Write + Seek>(
+    columns: &mut [Blocks],
+    out: &mut W,
+    file_meta: &mut FileMeta,
+    field: Fields,
+    first: usize,
+    last: usize,
+    stats: &mut SplitStats,
+) -> io::Result<()> {
+    let item_size = field_item_size(&field);
+    // Index of variable sized field: values are end offsets in data blocks,
+    // so offsets of records sharing data block with the first record are
+    // rebased unless it starts the block.
+    let mut rebase = (0, 0..0);
+    if let Some(data_field) = indexed_field(field) {
+        let data = &columns[column_pos(data_field)];
+        let data_block = data.block_of(first);
+        let rebased = first..data.starts[data_block + 1];
+        if first != data.starts[data_block] {
+            let offset = columns[column_pos(field)].end_offset(first - 1)?;
+            rebase = (offset, rebased);
+        }
+    }
+    let index_pos = if item_size.is_none() {
+        Some(column_pos(var_size_field_to_index(&field)))
+    } else {
+        None
+    };
+    let col_pos = column_pos(field);
+
+    let first_block = columns[col_pos].block_of(first);
+    let last_block = columns[col_pos].block_of(last - 1);
+    for block_num in first_block..=last_block {
+        let old_block = file_meta_block(&columns[col_pos], block_num);
+        let (start, end) = (columns[col_pos].starts[block_num], columns[col_pos].starts[block_num + 1]);
+        if start == end {
+            continue;
+        }
+        let (lo, hi) = (std::cmp::max(first, start), std::cmp::min(last, end));
+        let needs_rebase = rebase.0 > 0 && rebase.1.start < hi && lo < rebase.1.end;
+        if lo == start && hi == end && !needs_rebase {
+            let data = columns[col_pos].reader.block_data(&old_block)?;
+            let mut block = old_block.clone();
+            block.seekpos = out.stream_position()?;
+            out.write_all(&data)?;
+            file_meta.get_blocks(&field).push(block);
+            stats.blocks_copied += 1;
+            continue;
+        }
+
+        let new_data = match (item_size, index_pos) {
+            (Some(size), _) => {
+                let raw = columns[col_pos].raw(block_num)?;
+                let mut new_data = raw[(lo - start) * size..(hi - start) * size].to_vec();
+                if needs_rebase {
+                    for rec_num in std::cmp::max(lo, rebase.1.start)..std::cmp::min(hi, rebase.1.end) {
+                        let item = &mut new_data[(rec_num - lo) * size..][..size];
+                        item.copy_from_slice(&encode_index_entry(index_entry(item) - rebase.0));
+                    }
+                }
+                new_data
+            }
+            (None, Some(index_pos)) => {
+                let index = &mut columns[index_pos];
+                let byte_lo = if lo == start { 0 } else { index.end_offset(lo - 1)? };
+                let byte_hi = index.end_offset(hi - 1)?;
+                let raw = columns[col_pos].raw(block_num)?;
+                match file_meta.get_seq_encoding() {
+                    SeqEncoding::TwoBit if field == Fields::RawSequence => {
+                        let seq_lens = packed_seq_lens(raw)?;
+                        let unpacked = unpack_block(raw)?;
+                        pack_block(&unpacked[byte_lo..byte_hi], &seq_lens[lo - start..hi - start])
+                    }
+                    SeqEncoding::Reference if field == Fields::RawSequence => {
+                        slice_block(raw, lo - start, hi - start)?
+                    }
+                    _ => raw[byte_lo..byte_hi].to_vec(),
+                }
+            }
+            (None, None) => unreachable!(),
+        };
+        let new_stats = match (&old_block.stats, item_size) {
+            (Some(_), Some(4)) => {
+                let mut stat = Stat::default();
+                new_data.chunks_exact(4).for_each(|val| stat.update(LittleEndian::read_i32(val)));
+                Some(stat)
+            }
+            _ => None,
+        };
+        write_block(out, file_meta, field, &new_data, hi - lo, new_stats, old_block.bloom.clone())?;
+        stats.blocks_rewritten += 1;
+    }
+    Ok(())
+}
+
+fn file_meta_block(blocks: &Blocks, block_num: usize) -> BlockMeta {
+    blocks.reader.file_meta.view_blocks(&blocks.field)[block_num].clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{new_test_writer, open_test_file, TestRecord};
+    use std::fs::File;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_split() {
+        let dir = TempDir::new("gbam_split").unwrap();
+        let src = dir.path().join("in.gbam");
+        let records: Vec<TestRecord> = (0..20_000)
+            .map(|i| {
+                let mut rec = TestRecord::new(i / 8000, i, &format!("{}{}", "r".repeat(i as usize % 30), i));
+                let len = i as usize % 9;
+                rec.seq = if i % 7 == 0 { "ACGTN" } else { "ACGTACGTA" }[..len % 5].to_owned();
+                rec.qual = vec![30; rec.seq.len()];
+                rec.tags = if i % 2 == 0 { b"NMC\x01".to_vec() } else { Vec::new() };
+                rec
+            })
+            .collect();
+        let mut writer = new_test_writer(&src, "@HD\tVN:1.6\tSO:coordinate\n");
+        writer.set_rows_per_block(997);
+        writer.set_seq_packing(true);
+        for rec in &records {
+            writer.push_record(&rec.to_raw(), false).unwrap();
+        }
+        writer.finish_with_summary(false).unwrap();
+
+        let mut original = open_test_file(&src);
+        let paths: Vec<_> = (0..3).map(|i| dir.path().join(format!("shard{}.gbam", i))).collect();
+        let outputs = paths.iter().map(|path| File::create(path).unwrap()).collect();
+        let stats = split(&original, outputs).unwrap();
+        assert!(stats.blocks_copied > 4 * stats.blocks_rewritten);
+
+        let mut expected = original.records();
+        for (shard, path) in paths.iter().enumerate() {
+            let mut reader = open_test_file(path);
+            assert_eq!(reader.num_records(), [6666, 6667, 6667][shard]);
+            let pos_stats = reader.file_meta.view_blocks(&Fields::Pos)[0].stats.clone().unwrap();
+            assert_eq!(pos_stats.min_value, [0, 6666, 13333][shard]);
+            let mut records = reader.records();
+            while let Some(rec) = records.next_rec().unwrap() {
+                assert_eq!(
+                    serde_json::to_string(rec).unwrap(),
+                    serde_json::to_string(expected.next_rec().unwrap().unwrap()).unwrap()
+                );
+            }
+        }
+        assert!(expected.next_rec().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_split_into_empty_shards() {
+        let dir = TempDir::new("gbam_split").unwrap();
+        let src = dir.path().join("in.gbam");
+        let records = vec![TestRecord::new(0, 1, "a"), TestRecord::new(0, 2, "b")];
+        crate::test_utils::write_test_file(&src, "@HD\tVN:1.6\n", &records);
+        let reader = open_test_file(&src);
+        let paths: Vec<_> = (0..3).map(|i| dir.path().join(format!("shard{}.gbam", i))).collect();
+        let outputs = paths.iter().map(|path| File::create(path).unwrap()).collect();
+        split(&reader, outputs).unwrap();
+        let counts: Vec<usize> = paths.iter().map(|path| open_test_file(path).num_records()).collect();
+        assert_eq!(counts, [0, 1, 1]);
+    }
+}
@@ -1,43 +1,326 @@
-// pub type StatsComparator = fn(&[u8], &[u8]) -> Ordering;
-
-// pub struct StatsCollector {
-//     pub max_value: Vec<Stat>,
-//     pub min_value: Vec<Stat>,
-//     comparator: StatsComparator,
-// }
-
-// impl StatsCollector {
-//     pub fn new(field: Fields, comparator: StatsComparator) -> Self {
-//         Self {
-//             field,
-//             max_value: None,
-//             min_value: None,
-//             comparator,
-//         }
-//     }
-
-//     pub fn update(&mut self, value: &[u8]) {
-//         if self.max_value.is_none()
-//             || (self.comparator)(value, &self.max_value.as_ref().unwrap()) == Ordering::Greater
-//         {
-//             self.max_value = Some(value.to_vec());
-//         }
-//         if self.min_value.is_none()
-//             || (self.comparator)(value, &self.min_value.as_ref().unwrap()) == Ordering::Less
-//         {
-//             self.min_value = Some(value.to_vec());
-//         }
-//     }
-
-//     pub fn reset(&mut self) {
-//         self.max_value = None;
-//         self.min_value = None;
-//     }
-// }
-
-// pub fn refid_comparator(mut left: &[u8], mut right: &[u8]) -> Ordering {
-//     let left_val = left.read_i32::<LittleEndian>().unwrap();
-//     let right_val = right.read_i32::<LittleEndian>().unwrap();
-
-//     left_val.cmp(&right_val)
-// }
+//! Comparators of raw field values, matching min/max block stats, and
+//! collectors of other per block stats.
+//!
+//! Stats of a block hold minimum and maximum of its values decoded into i32,
+//! so ordering of raw values follows their numeric value in BAM encoding.
+//! Unplaced records have RefID and Pos of -1. RefID -1 is stored as
+//! `UNPLACED_STAT`, so it sorts after all references like in coordinate
+//! sorted files, and a block with unplaced reads doesn't seem to span all
+//! references. Pos -1 sorts before all mapped positions.
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+use bam_tools::record::fields::Fields;
+use byteorder::{ByteOrder, LittleEndian};
+
+use crate::bloom;
+
+/// Name of stat counting sentinel values, see `SentinelCount`.
+pub const NULLS: &str = "nulls";
+/// Name of stat estimating distinct values, see `DistinctEstimate`.
+pub const DISTINCT: &str = "distinct";
+
+/// Collects a stat of field values of each block, stored in
+/// `BlockMeta::extra_stats` under `name()`. Set with
+/// `Writer::add_stats_collector()`.
+pub trait StatsCollector {
+    fn name(&self) -> &str;
+
+    /// Called with raw bytes of each value of the block.
+    fn update(&mut self, data: &[u8]);
+
+    /// Returns stat of the block and resets for the next one.
+    fn finish_block(&mut self) -> u64;
+}
+
+/// Counts values equal to a sentinel, like RefID -1 of unmapped reads or
+/// Mapq 255, for which mapping quality is not available.
+pub struct SentinelCount {
+    sentinel: Vec<u8>,
+    count: u64,
+}
+
+impl SentinelCount {
+    pub fn new(sentinel: &[u8]) -> Self {
+        Self {
+            sentinel: sentinel.to_vec(),
+            count: 0,
+        }
+    }
+}
+
+impl StatsCollector for SentinelCount {
+    fn name(&self) -> &str {
+        NULLS
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        if data == self.sentinel.as_slice() {
+            self.count += 1;
+        }
+    }
+
+    fn finish_block(&mut self) -> u64 {
+        std::mem::take(&mut self.count)
+    }
+}
+
+/// Registers of `DistinctEstimate` are indexed by this many hash bits.
+const HLL_BITS: u32 = 10;
+
+/// Approximate count of distinct values, HyperLogLog with 1024 registers.
+/// Standard error is about 3%, small counts are nearly exact.
+pub struct DistinctEstimate {
+    registers: Vec<u8>,
+}
+
+impl DistinctEstimate {
+    pub fn new() -> Self {
+        Self {
+            registers: vec![0; 1 << HLL_BITS],
+        }
+    }
+
+    fn estimate(&self) -> u64 {
+        let m = self.registers.len() as f64;
+        let sum: f64 = self.registers.iter().map(|&rank| 0.5f64.powi(rank as i32)).sum();
+        let raw = 0.7213 / (1.0 + 1.079 / m) * m * m / sum;
+        let zeros = self.registers.iter().filter(|&&rank| rank == 0).count();
+        // Linear counting is more precise for small counts.
+        if raw <= 2.5 * m && zeros > 0 {
+            (m * (m / zeros as f64).ln()).round() as u64
+        } else {
+            raw.round() as u64
+        }
+    }
+}
+
+impl Default for DistinctEstimate {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl StatsCollector for DistinctEstimate {
+    fn name(&self) -> &str {
+        DISTINCT
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        let (hash, _) = bloom::hash(data);
+        let register = (hash >> (64 - HLL_BITS)) as usize;
+        // Bit below the remaining ones caps the rank.
+        let rank = ((hash << HLL_BITS) | (1 << (HLL_BITS - 1))).leading_zeros() as u8 + 1;
+        self.registers[register] = self.registers[register].max(rank);
+    }
+
+    fn finish_block(&mut self) -> u64 {
+        let estimate = self.estimate();
+        self.registers.iter_mut().for_each(|rank| *rank = 0);
+        estimate
+    }
+}
+
+/// Collectors of sentinel counts of RefID, Pos (-1 if unmapped) and Mapq
+/// (255), and distinct estimates of RefID and ReadName. `Reader::fetch()`
+/// skips blocks of unmapped reads by RefID counts.
+pub fn default_collectors(field: Fields) -> Vec<Box<dyn StatsCollector>> {
+    let unmapped = (-1i32).to_le_bytes();
+    match field {
+        Fields::RefID => vec![Box::new(SentinelCount::new(&unmapped)), Box::new(DistinctEstimate::new())],
+        Fields::Pos => vec![Box::new(SentinelCount::new(&unmapped))],
+        Fields::Mapq => vec![Box::new(SentinelCount::new(&[255]))],
+        Fields::ReadName => vec![Box::new(DistinctEstimate::new())],
+        _ => Vec::new(),
+    }
+}
+
+/// RefID -1 of unplaced reads as stored in block stats.
+pub const UNPLACED_STAT: i32 = i32::MAX;
+
+/// RefID of a RefID stat value. Files written before `UNPLACED_STAT` store
+/// -1 as is.
+pub fn stat_ref_id(stat: i32) -> i32 {
+    if stat == UNPLACED_STAT {
+        -1
+    } else {
+        stat
+    }
+}
+
+/// Orders raw bytes of a field value.
+pub type StatsComparator = fn(&[u8], &[u8]) -> Ordering;
+
+/// Orders RefIDs as in coordinate sorted files, unplaced (-1) last.
+pub fn cmp_ref_id(left: &[u8], right: &[u8]) -> Ordering {
+    stat_value(&Fields::RefID, left).cmp(&stat_value(&Fields::RefID, right))
+}
+
+pub fn cmp_le_i32(left: &[u8], right: &[u8]) -> Ordering {
+    LittleEndian::read_i32(left).cmp(&LittleEndian::read_i32(right))
+}
+
+pub fn cmp_le_u16(left: &[u8], right: &[u8]) -> Ordering {
+    LittleEndian::read_u16(left).cmp(&LittleEndian::read_u16(right))
+}
+
+pub fn cmp_u8(left: &[u8], right: &[u8]) -> Ordering {
+    left[0].cmp(&right[0])
+}
+
+/// Comparators of fields stats are usually collected for, by their BAM
+/// encoding.
+pub fn default_comparators() -> HashMap<Fields, StatsComparator> {
+    let mut comparators: HashMap<Fields, StatsComparator> = HashMap::new();
+    comparators.insert(Fields::RefID, cmp_ref_id);
+    for field in [Fields::Pos, Fields::NextPos, Fields::TemplateLength] {
+        comparators.insert(field, cmp_le_i32);
+    }
+    comparators.insert(Fields::Mapq, cmp_u8);
+    comparators.insert(Fields::Flags, cmp_le_u16);
+    comparators
+}
+
+/// Value of fixed sized field as stored in block stats. Fields of 1 and 2
+/// bytes are unsigned, 4 byte ones signed, RefID -1 is `UNPLACED_STAT`.
+pub(crate) fn stat_value(field: &Fields, data: &[u8]) -> i32 {
+    match data.len() {
+        1 => data[0] as i32,
+        2 => LittleEndian::read_u16(data) as i32,
+        _ => match LittleEndian::read_i32(data) {
+            -1 if *field == Fields::RefID => UNPLACED_STAT,
+            value => value,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{new_test_writer, open_test_file, sam_header_bytes, test_ref_seqs, TestRecord};
+    use crate::writer::Writer;
+    use crate::Codecs;
+    use bam_tools::record::fields::FIELDS_NUM;
+    use std::collections::HashSet;
+    use std::fs::File;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_unmapped_sorts_first() {
+        let comparators = default_comparators();
+        let pos = comparators[&Fields::Pos];
+        let unmapped = (-1i32).to_le_bytes();
+        for mapped in [0i32, 1, 1 << 20, i32::MAX] {
+            assert_eq!(pos(&unmapped, &mapped.to_le_bytes()), Ordering::Less);
+            let stat = |data: &[u8]| stat_value(&Fields::Pos, data);
+            assert_eq!(stat(&unmapped).cmp(&stat(&mapped.to_le_bytes())), Ordering::Less);
+            // Unplaced reads are at the end of sorted files, after every
+            // reference id below `UNPLACED_STAT`, which they compare as.
+            if mapped < UNPLACED_STAT {
+                assert_eq!(comparators[&Fields::RefID](&unmapped, &mapped.to_le_bytes()), Ordering::Greater);
+            }
+        }
+        assert_eq!(stat_ref_id(stat_value(&Fields::RefID, &unmapped)), -1);
+        // Byte order is little endian, not lexicographic.
+        assert_eq!(pos(&256i32.to_le_bytes(), &1i32.to_le_bytes()), Ordering::Greater);
+        let flags = comparators[&Fields::Flags];
+        assert_eq!(flags(&0x400u16.to_le_bytes(), &0x10u16.to_le_bytes()), Ordering::Greater);
+        assert_eq!(comparators[&Fields::Mapq](&[255], &[0]), Ordering::Greater);
+        assert_eq!(comparators.len(), 6);
+    }
+
+    #[test]
+    fn test_position_stats() {
+        let dir = TempDir::new("gbam_stats").unwrap();
+        let path = dir.path().join("stats.gbam");
+        let ref_seqs = test_ref_seqs();
+        let mut writer = Writer::new_with_position_stats(
+            File::create(&path).unwrap(),
+            vec![Codecs::Lz4; FIELDS_NUM],
+            1,
+            ref_seqs.clone(),
+            sam_header_bytes("", &ref_seqs),
+            String::from("test"),
+            false,
+        )
+        .unwrap();
+        let records = [
+            TestRecord::new(0, 100, "a"),
+            TestRecord::new(-1, -1, "b"),
+            TestRecord::new(1, 5, "c"),
+        ];
+        for rec in &records {
+            writer.push_record(&rec.to_raw(), false).unwrap();
+        }
+        writer.finish_with_summary(false).unwrap();
+
+        let reader = open_test_file(&path);
+        let stat = |field| reader.file_meta.view_blocks(field)[0].stats.clone().unwrap();
+        assert_eq!((stat(&Fields::RefID).min_value, stat(&Fields::RefID).max_value), (0, UNPLACED_STAT));
+        assert_eq!((stat(&Fields::Pos).min_value, stat(&Fields::Pos).max_value), (-1, 100));
+        assert!(reader.file_meta.view_blocks(&Fields::Mapq)[0].stats.is_none());
+    }
+
+    #[test]
+    fn test_distinct_estimate() {
+        let mut distinct = DistinctEstimate::new();
+        assert_eq!(distinct.finish_block(), 0);
+        for n in [1u32, 10, 100, 10_000, 200_000] {
+            for i in 0..n {
+                // Repeats don't count.
+                distinct.update(&i.to_le_bytes());
+                distinct.update(&i.to_le_bytes());
+            }
+            let estimate = distinct.finish_block() as f64;
+            assert!((estimate - n as f64).abs() <= n as f64 * 0.05 + 1.0, "{} for {}", estimate, n);
+        }
+    }
+
+    #[test]
+    fn test_null_and_distinct_stats() {
+        let dir = TempDir::new("gbam_stats").unwrap();
+        let path = dir.path().join("extra_stats.gbam");
+        let records: Vec<_> = (0..1_000)
+            .map(|i| {
+                let mut rec = if i % 7 == 0 {
+                    TestRecord::new(-1, -1, &format!("r{}", i % 300))
+                } else {
+                    TestRecord::new(i % 3, i, &format!("r{}", i % 300))
+                };
+                if i % 5 == 0 {
+                    rec.mapq = 255;
+                }
+                rec
+            })
+            .collect();
+        let mut writer = new_test_writer(&path, "");
+        writer.set_rows_per_block(250);
+        writer.add_default_stats_collectors();
+        for rec in &records {
+            writer.push_record(&rec.to_raw(), false).unwrap();
+        }
+        writer.finish_with_summary(false).unwrap();
+
+        let reader = open_test_file(&path);
+        let nulls = |field| reader.block_stats(&field, NULLS).collect::<Vec<_>>();
+        let distinct = |field| reader.block_stats(&field, DISTINCT).collect::<Vec<_>>();
+        let blocks: Vec<_> = records.chunks(250).collect();
+        let count = |pred: &dyn Fn(&TestRecord) -> bool| {
+            blocks
+                .iter()
+                .map(|block| Some(block.iter().filter(|rec| pred(rec)).count() as u64))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(nulls(Fields::RefID), count(&|rec| rec.refid == -1));
+        assert_eq!(nulls(Fields::Pos), count(&|rec| rec.pos == -1));
+        assert_eq!(nulls(Fields::Mapq), count(&|rec| rec.mapq == 255));
+        assert_eq!(distinct(Fields::RefID), vec![Some(4); 4]);
+        for (estimate, block) in distinct(Fields::ReadName).into_iter().zip(&blocks) {
+            let exact = block.iter().map(|rec| &rec.name).collect::<HashSet<_>>().len() as f64;
+            let estimate = estimate.unwrap() as f64;
+            assert!((estimate - exact).abs() <= exact * 0.05 + 1.0, "{} for {}", estimate, exact);
+        }
+        assert_eq!(nulls(Fields::ReadName), vec![None; 4]);
+        assert!(reader.file_meta.view_blocks(&Fields::Flags)[0].extra_stats.is_empty());
+    }
+}
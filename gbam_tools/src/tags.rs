@@ -0,0 +1,402 @@
+//! Parsing of BAM auxiliary data and filtering of records by tag values.
+//!
+//! Tags are decoded lazily: values borrow from the RawTags bytes, and lookup
+//! of a tag stops at the first match instead of parsing the whole stream.
+use std::io;
+
+use bam_tools::record::fields::Fields;
+use byteorder::{LittleEndian, ReadBytesExt};
+
+use crate::error::{Result, TemplateError};
+use crate::reader::reader::Reader;
+use crate::reader::record::GbamRecord;
+
+/// Replacing and removing tags of tag streams
+pub mod rewrite;
+
+/// Value of a single tag. Strings and arrays borrow from the tag stream.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TagValue<'a> {
+    /// A
+    Char(u8),
+    /// c
+    Int8(i8),
+    /// C
+    UInt8(u8),
+    /// s
+    Int16(i16),
+    /// S
+    UInt16(u16),
+    /// i
+    Int32(i32),
+    /// I
+    UInt32(u32),
+    /// f
+    Float(f32),
+    /// Z, without terminating NUL
+    String(&'a [u8]),
+    /// H, without terminating NUL
+    Hex(&'a [u8]),
+    /// B
+    Array(TagArray<'a>),
+}
+
+impl<'a> TagValue<'a> {
+    /// Value of integer types, None for other types.
+    pub fn as_int(&self) -> Option<i64> {
+        match *self {
+            TagValue::Int8(v) => Some(v as i64),
+            TagValue::UInt8(v) => Some(v as i64),
+            TagValue::Int16(v) => Some(v as i64),
+            TagValue::UInt16(v) => Some(v as i64),
+            TagValue::Int32(v) => Some(v as i64),
+            TagValue::UInt32(v) => Some(v as i64),
+            _ => None,
+        }
+    }
+
+    /// Value of float and integer types, None for other types.
+    pub fn as_float(&self) -> Option<f64> {
+        match *self {
+            TagValue::Float(v) => Some(v as f64),
+            _ => self.as_int().map(|v| v as f64),
+        }
+    }
+
+    /// Bytes of Z and H types, None for other types.
+    pub fn as_bytes(&self) -> Option<&'a [u8]> {
+        match *self {
+            TagValue::String(v) | TagValue::Hex(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<TagArray<'a>> {
+        match *self {
+            TagValue::Array(v) => Some(v),
+            _ => None,
+        }
+    }
+}
+
+/// Numeric array of B type tag, items are parsed on access.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TagArray<'a> {
+    item_type: u8,
+    data: &'a [u8],
+}
+
+impl<'a> TagArray<'a> {
+    /// Type code of items, one of cCsSiIf.
+    pub fn item_type(&self) -> u8 {
+        self.item_type
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len() / scalar_size(self.item_type).unwrap()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    pub fn get(&self, idx: usize) -> Option<TagValue<'a>> {
+        let size = scalar_size(self.item_type).unwrap();
+        let mut item = self.data.get(idx * size..(idx + 1) * size)?;
+        Some(read_scalar(self.item_type, &mut item).unwrap())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = TagValue<'a>> + '_ {
+        (0..self.len()).map(move |idx| self.get(idx).unwrap())
+    }
+}
+
+/// Iterates over (tag, value) pairs of BAM auxiliary data. Yields an error
+/// and stops if the stream is malformed.
+pub struct Tags<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Tags<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+}
+
+impl<'a> Iterator for Tags<'a> {
+    type Item = io::Result<([u8; 2], TagValue<'a>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.data.is_empty() {
+            return None;
+        }
+        let item = read_tag(&mut self.data);
+        if item.is_err() {
+            self.data = &[];
+        }
+        Some(item)
+    }
+}
+
+/// Value of `tag` in auxiliary data, parsing only tags preceding it.
+pub fn find_tag<'a>(data: &'a [u8], tag: &[u8; 2]) -> io::Result<Option<TagValue<'a>>> {
+    for item in Tags::new(data) {
+        let (name, value) = item?;
+        if name == *tag {
+            return Ok(Some(value));
+        }
+    }
+    Ok(None)
+}
+
+fn read_tag<'a>(data: &mut &'a [u8]) -> io::Result<([u8; 2], TagValue<'a>)> {
+    if data.len() < 3 {
+        return Err(invalid("Truncated tag".to_owned()));
+    }
+    let name = [data[0], data[1]];
+    let tag_type = data[2];
+    *data = &data[3..];
+    let value = match tag_type {
+        b'A' => TagValue::Char(data.read_u8()?),
+        b'Z' | b'H' => {
+            let end = data.iter().position(|&b| b == 0).ok_or_else(|| {
+                invalid(format!(
+                    "Tag {} is not terminated",
+                    String::from_utf8_lossy(&name)
+                ))
+            })?;
+            let value = &data[..end];
+            *data = &data[end + 1..];
+            if tag_type == b'Z' {
+                TagValue::String(value)
+            } else {
+                TagValue::Hex(value)
+            }
+        }
+        b'B' => {
+            let item_type = data.read_u8()?;
+            let size = scalar_size(item_type).ok_or_else(|| unknown_type(item_type))?;
+            let len = data.read_u32::<LittleEndian>()? as usize;
+            let len_in_bytes = len
+                .checked_mul(size)
+                .filter(|&len| len <= data.len())
+                .ok_or_else(|| {
+                    invalid(format!(
+                        "Array of tag {} is truncated",
+                        String::from_utf8_lossy(&name)
+                    ))
+                })?;
+            let (items, rest) = data.split_at(len_in_bytes);
+            *data = rest;
+            TagValue::Array(TagArray {
+                item_type,
+                data: items,
+            })
+        }
+        _ => read_scalar(tag_type, data)?,
+    };
+    Ok((name, value))
+}
+
+fn scalar_size(tag_type: u8) -> Option<usize> {
+    match tag_type {
+        b'c' | b'C' => Some(1),
+        b's' | b'S' => Some(2),
+        b'i' | b'I' | b'f' => Some(4),
+        _ => None,
+    }
+}
+
+fn read_scalar<'a>(tag_type: u8, data: &mut &[u8]) -> io::Result<TagValue<'a>> {
+    Ok(match tag_type {
+        b'c' => TagValue::Int8(data.read_i8()?),
+        b'C' => TagValue::UInt8(data.read_u8()?),
+        b's' => TagValue::Int16(data.read_i16::<LittleEndian>()?),
+        b'S' => TagValue::UInt16(data.read_u16::<LittleEndian>()?),
+        b'i' => TagValue::Int32(data.read_i32::<LittleEndian>()?),
+        b'I' => TagValue::UInt32(data.read_u32::<LittleEndian>()?),
+        b'f' => TagValue::Float(data.read_f32::<LittleEndian>()?),
+        _ => return Err(unknown_type(tag_type)),
+    })
+}
+
+fn unknown_type(tag_type: u8) -> io::Error {
+    invalid(format!("Unknown tag type '{}'", tag_type as char))
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+impl GbamRecord {
+    /// Value of `tag`, None if the record has no such tag or tags were not
+    /// parsed.
+    pub fn get_tag(&self, tag: &[u8; 2]) -> io::Result<Option<TagValue<'_>>> {
+        match &self.tags {
+            Some(tags) => find_tag(tags, tag),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Iterates over records whose tag matches a predicate. Created by
+/// [`Reader::records_filtered_by_tag`].
+pub struct TagFilter<'a, P> {
+    reader: &'a mut Reader,
+    cur_rec: usize,
+    buf: GbamRecord,
+    tag: [u8; 2],
+    predicate: P,
+    other_fields: Vec<Fields>,
+}
+
+impl<'a, P: FnMut(&TagValue) -> bool> TagFilter<'a, P> {
+    /// Next matching record, None past the last one. Fails like
+    /// `Reader::fill_record()`, or if tags of a record are malformed, the
+    /// error names the record. Iteration may go on after errors.
+    pub fn next_rec(&mut self) -> Result<Option<&GbamRecord>> {
+        while self.cur_rec < self.reader.amount {
+            let rec_num = self.cur_rec;
+            self.cur_rec += 1;
+            if self.read_if_matches(rec_num)? {
+                return Ok(Some(&self.buf));
+            }
+        }
+        Ok(None)
+    }
+
+    // Reads tags of record `rec_num`, and other fields if they match.
+    fn read_if_matches(&mut self, rec_num: usize) -> Result<bool> {
+        let physical = self.reader.physical_rec_num(rec_num)?;
+        self.reader
+            .get_column(&Fields::RawTags)?
+            .fill_record_field(physical, &mut self.buf)?;
+        let predicate = &mut self.predicate;
+        let value = self
+            .buf
+            .get_tag(&self.tag)
+            .map_err(|err| io::Error::new(err.kind(), format!("Record {}: {}", rec_num, err)))?;
+        if !value.is_some_and(|value| predicate(&value)) {
+            return Ok(false);
+        }
+        // Other fields are only read for matching records.
+        for field in &self.other_fields {
+            self.reader
+                .get_column(field)?
+                .fill_record_field(physical, &mut self.buf)?;
+        }
+        Ok(true)
+    }
+}
+
+impl Reader {
+    /// Get iterator over records whose `tag` is present and satisfies
+    /// `predicate`, like `reader.records_filtered_by_tag(*b"NM", |v|
+    /// v.as_int().is_some_and(|nm| nm <= 2))`. RawTags must be enabled in
+    /// parsing template, records are parsed according to it.
+    pub fn records_filtered_by_tag<P>(&mut self, tag: [u8; 2], predicate: P) -> Result<TagFilter<'_, P>>
+    where
+        P: FnMut(&TagValue) -> bool,
+    {
+        if !self.parsing_template.check_if_active(&[Fields::RawTags]) {
+            return Err(TemplateError::FieldNotOpened { field: Fields::RawTags }.into());
+        }
+        let other_fields = self
+            .parsing_template
+            .get_active_data_fields_iter()
+            .filter(|&&field| field != Fields::RawTags)
+            .copied()
+            .collect();
+        Ok(TagFilter {
+            reader: self,
+            cur_rec: 0,
+            buf: GbamRecord::default(),
+            tag,
+            predicate,
+            other_fields,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{open_test_file, write_test_file, TestRecord};
+    use tempdir::TempDir;
+
+    fn record_with_tags(pos: i32, tags: &[u8]) -> TestRecord {
+        let mut rec = TestRecord::new(0, pos, &format!("r{}", pos));
+        rec.tags = tags.to_vec();
+        rec
+    }
+
+    #[test]
+    fn test_find_tag() {
+        let mut data = Vec::new();
+        data.extend_from_slice(b"NMC\x02");
+        data.extend_from_slice(b"RGZgrp1\0");
+        data.extend_from_slice(b"XAA+");
+        // B:s,-1,300
+        data.extend_from_slice(b"ZBBs\x02\0\0\0\xff\xff\x2c\x01");
+        data.extend_from_slice(b"AFf");
+        data.extend_from_slice(&0.5f32.to_le_bytes());
+
+        assert_eq!(find_tag(&data, b"NM").unwrap(), Some(TagValue::UInt8(2)));
+        assert_eq!(find_tag(&data, b"NM").unwrap().unwrap().as_int(), Some(2));
+        assert_eq!(find_tag(&data, b"RG").unwrap().unwrap().as_bytes(), Some(&b"grp1"[..]));
+        assert_eq!(find_tag(&data, b"XA").unwrap(), Some(TagValue::Char(b'+')));
+        let array = find_tag(&data, b"ZB").unwrap().unwrap().as_array().unwrap();
+        assert_eq!(array.item_type(), b's');
+        assert_eq!(array.iter().map(|v| v.as_int().unwrap()).collect::<Vec<_>>(), vec![-1, 300]);
+        assert_eq!(array.get(2), None);
+        assert_eq!(find_tag(&data, b"AF").unwrap().unwrap().as_float(), Some(0.5));
+        assert_eq!(find_tag(&data, b"MD").unwrap(), None);
+        assert_eq!(Tags::new(&data).count(), 5);
+
+        // Lookup stops at the tag, malformed tail is not reached.
+        let mut malformed = data.clone();
+        malformed.extend_from_slice(b"XYq");
+        assert!(find_tag(&malformed, b"AF").unwrap().is_some());
+        assert!(find_tag(&malformed, b"MD").is_err());
+        // Array longer than the stream.
+        assert!(find_tag(b"ZBBi\xff\0\0\0\x01\0\0\0", b"ZB").is_err());
+    }
+
+    #[test]
+    fn test_filter_by_tag() {
+        let dir = TempDir::new("gbam_tags").unwrap();
+        let path = dir.path().join("tags.gbam");
+        let records = vec![
+            record_with_tags(0, b"NMC\x01"),
+            record_with_tags(1, b"RGZa\0NMi\x05\0\0\0"),
+            // No NM tag.
+            record_with_tags(2, b"RGZa\0"),
+            record_with_tags(3, b"ZBBC\x03\0\0\0\x01\x02\x03NMs\x02\0"),
+            // Unterminated string before NM.
+            record_with_tags(4, b"RGZa"),
+            record_with_tags(5, b"NMc\0"),
+        ];
+        write_test_file(&path, "", &records);
+
+        let mut reader = open_test_file(&path);
+        let mut filter = reader
+            .records_filtered_by_tag(*b"NM", |v| v.as_int().is_some_and(|nm| nm <= 2))
+            .unwrap();
+        let mut matched = Vec::new();
+        let mut errors = Vec::new();
+        loop {
+            match filter.next_rec() {
+                Ok(Some(rec)) => {
+                    matched.push(rec.pos.unwrap());
+                    let array = rec.get_tag(b"ZB").unwrap().map(|v| v.as_array().unwrap().len());
+                    assert_eq!(array, if rec.pos == Some(3) { Some(3) } else { None });
+                }
+                Ok(None) => break,
+                Err(err) => errors.push(err.to_string()),
+            }
+        }
+        assert_eq!(matched, vec![0, 3, 5]);
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].starts_with("Record 4:"), "{}", errors[0]);
+    }
+}
@@ -0,0 +1,176 @@
+//! Editing of BAM auxiliary data. Tags are replaced where they are, so
+//! their order is kept, and values may change length. Only tags preceding
+//! the edited one are parsed.
+use std::convert::TryFrom;
+use std::io;
+use std::ops::Range;
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use super::{invalid, read_tag};
+use crate::U32_SIZE;
+
+/// Byte range of `tag` in `data`, from its name to the end of its value.
+pub fn tag_range(data: &[u8], tag: &[u8; 2]) -> io::Result<Option<Range<usize>>> {
+    let mut rest = data;
+    while !rest.is_empty() {
+        let start = data.len() - rest.len();
+        let (name, _) = read_tag(&mut rest)?;
+        if name == *tag {
+            return Ok(Some(start..data.len() - rest.len()));
+        }
+    }
+    Ok(None)
+}
+
+/// Sets `tag` to Z type `value`, replacing the tag of any type in place or
+/// appending it. Fails if `value` contains NUL.
+pub fn set_string_tag(data: &mut Vec<u8>, tag: &[u8; 2], value: &[u8]) -> io::Result<()> {
+    if value.contains(&0) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Value of tag {} contains NUL", String::from_utf8_lossy(tag)),
+        ));
+    }
+    let range = tag_range(data, tag)?.unwrap_or(data.len()..data.len());
+    let entry = tag.iter().chain(b"Z").chain(value).chain(&[0]).copied();
+    data.splice(range, entry);
+    Ok(())
+}
+
+/// Removes `tag`, returns whether it was present.
+pub fn remove_tag(data: &mut Vec<u8>, tag: &[u8; 2]) -> io::Result<bool> {
+    Ok(match tag_range(data, tag)? {
+        Some(range) => {
+            data.drain(range);
+            true
+        }
+        None => false,
+    })
+}
+
+/// Replaces the last `old_len` bytes of BAM `record`, its auxiliary data,
+/// with `tags` and updates block_size. `record` starts with block_size, as
+/// written by `GbamRecord::convert_to_bytes()`.
+pub fn replace_record_tags(record: &mut Vec<u8>, old_len: usize, tags: &[u8]) -> io::Result<()> {
+    let block_size = record.get(..U32_SIZE).map(LittleEndian::read_u32);
+    let tags_start = record.len().checked_sub(old_len).filter(|&start| start >= U32_SIZE);
+    let tags_start = match (block_size, tags_start) {
+        (Some(block_size), Some(start)) if block_size as usize == record.len() - U32_SIZE => start,
+        _ => return Err(invalid(String::from("Record is shorter than its block_size or tags"))),
+    };
+    record.truncate(tags_start);
+    record.extend_from_slice(tags);
+    let block_size = u32::try_from(record.len() - U32_SIZE)
+        .map_err(|_| invalid(String::from("Record doesn't fit into BAM block_size")))?;
+    LittleEndian::write_u32(&mut record[..U32_SIZE], block_size);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tags::{find_tag, TagValue, Tags};
+    use crate::test_utils::TestRecord;
+
+    fn stream() -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend_from_slice(b"NMC\x02");
+        data.extend_from_slice(b"RGZgrp1\0");
+        data.extend_from_slice(b"ZBBs\x02\0\0\0\xff\xff\x2c\x01");
+        data.extend_from_slice(b"XAA+");
+        data
+    }
+
+    fn names(data: &[u8]) -> Vec<[u8; 2]> {
+        Tags::new(data).map(|item| item.unwrap().0).collect()
+    }
+
+    #[test]
+    fn test_tag_range() {
+        let data = stream();
+        assert_eq!(tag_range(&data, b"NM").unwrap(), Some(0..4));
+        assert_eq!(tag_range(&data, b"RG").unwrap(), Some(4..12));
+        assert_eq!(tag_range(&data, b"ZB").unwrap(), Some(12..24));
+        assert_eq!(tag_range(&data, b"XA").unwrap(), Some(24..28));
+        assert_eq!(tag_range(&data, b"MD").unwrap(), None);
+        assert_eq!(tag_range(&[], b"MD").unwrap(), None);
+        let mut malformed = data.clone();
+        malformed.extend_from_slice(b"XYq");
+        assert_eq!(tag_range(&malformed, b"XA").unwrap(), Some(24..28));
+        assert!(tag_range(&malformed, b"MD").is_err());
+    }
+
+    #[test]
+    fn test_set_string_tag() {
+        let original = stream();
+        let values: [&[u8]; 4] = [b"", b"g", b"grp1", b"a_much_longer_read_group"];
+        for value in values {
+            let mut data = original.clone();
+            set_string_tag(&mut data, b"RG", value).unwrap();
+            assert_eq!(find_tag(&data, b"RG").unwrap(), Some(TagValue::String(value)));
+            assert_eq!(names(&data), names(&original));
+            assert_eq!(data.len(), original.len() - 4 + value.len());
+            assert_eq!(find_tag(&data, b"XA").unwrap(), Some(TagValue::Char(b'+')));
+            assert_eq!(find_tag(&data, b"ZB").unwrap().unwrap().as_array().unwrap().len(), 2);
+        }
+
+        // Other types are replaced in place, missing tags are appended.
+        let mut data = original.clone();
+        set_string_tag(&mut data, b"NM", b"x").unwrap();
+        set_string_tag(&mut data, b"BC", b"ACGT").unwrap();
+        assert_eq!(&data[..5], b"NMZx\0");
+        assert!(data.ends_with(b"XAA+BCZACGT\0"));
+        assert_eq!(names(&data), [*b"NM", *b"RG", *b"ZB", *b"XA", *b"BC"]);
+
+        let mut empty = Vec::new();
+        set_string_tag(&mut empty, b"RG", b"grp2").unwrap();
+        assert_eq!(empty, b"RGZgrp2\0");
+
+        let err = set_string_tag(&mut data, b"RG", b"a\0b").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        let mut malformed = b"RGZgrp1".to_vec();
+        assert!(set_string_tag(&mut malformed, b"RG", b"grp2").is_err());
+        assert_eq!(malformed, b"RGZgrp1");
+    }
+
+    #[test]
+    fn test_remove_tag() {
+        let mut data = stream();
+        assert!(remove_tag(&mut data, b"RG").unwrap());
+        assert!(!remove_tag(&mut data, b"RG").unwrap());
+        assert_eq!(names(&data), [*b"NM", *b"ZB", *b"XA"]);
+        assert!(remove_tag(&mut data, b"XA").unwrap());
+        assert!(remove_tag(&mut data, b"NM").unwrap());
+        assert!(remove_tag(&mut data, b"ZB").unwrap());
+        assert!(data.is_empty());
+    }
+
+    #[test]
+    fn test_replace_record_tags() {
+        let rec = TestRecord {
+            tags: stream(),
+            ..TestRecord::new(0, 10, "read")
+        };
+        let with_block_size = |bytes: Vec<u8>| {
+            let mut record = (bytes.len() as u32).to_le_bytes().to_vec();
+            record.extend_from_slice(&bytes);
+            record
+        };
+        let mut record = with_block_size(rec.to_bytes());
+        let mut tags = rec.tags.clone();
+        set_string_tag(&mut tags, b"RG", b"sample_a.lane_1").unwrap();
+        replace_record_tags(&mut record, rec.tags.len(), &tags).unwrap();
+        let tags_len = tags.len();
+        assert_eq!(record, with_block_size(TestRecord { tags, ..rec.clone() }.to_bytes()));
+
+        replace_record_tags(&mut record, tags_len, &[]).unwrap();
+        assert_eq!(record, with_block_size(TestRecord { tags: Vec::new(), ..rec }.to_bytes()));
+
+        let len = record.len();
+        assert!(replace_record_tags(&mut record, len, &[]).is_err());
+        let mut stale = record.clone();
+        stale.push(0);
+        assert!(replace_record_tags(&mut stale, 0, &[]).is_err());
+    }
+}
@@ -0,0 +1,164 @@
+//! Helpers shared by unit tests: synthetic BAM records and small GBAM files.
+use crate::reader::{parse_tmplt::ParsingTemplate, reader::Reader};
+use crate::writer::{Writer, WriterSettings};
+use bam_tools::record::bamrawrecord::BAMRawRecord;
+use bam_tools::record::fields::Fields;
+use byteorder::{LittleEndian, WriteBytesExt};
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+
+/// Description of a synthetic record. Fields not set explicitly get sensible
+/// values for a mapped read.
+#[derive(Clone, Debug)]
+pub(crate) struct TestRecord {
+    pub refid: i32,
+    pub pos: i32,
+    pub mapq: u8,
+    pub bin: u16,
+    pub flag: u16,
+    pub next_refid: i32,
+    pub next_pos: i32,
+    pub tlen: i32,
+    pub name: String,
+    /// Raw BAM cigar ops (`len << 4 | op`).
+    pub cigar: Vec<u32>,
+    pub seq: String,
+    pub qual: Vec<u8>,
+    pub tags: Vec<u8>,
+}
+
+impl Default for TestRecord {
+    fn default() -> Self {
+        Self {
+            refid: 0,
+            pos: 0,
+            mapq: 60,
+            bin: 4680,
+            flag: 0,
+            next_refid: -1,
+            next_pos: -1,
+            tlen: 0,
+            name: String::from("read"),
+            cigar: vec![4 << 4],
+            seq: String::from("ACGT"),
+            qual: vec![30; 4],
+            tags: Vec::new(),
+        }
+    }
+}
+
+impl TestRecord {
+    pub fn new(refid: i32, pos: i32, name: &str) -> Self {
+        Self {
+            refid,
+            pos,
+            name: name.to_owned(),
+            ..Default::default()
+        }
+    }
+
+    /// Serializes into BAM record layout (without the leading block_size).
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.write_i32::<LittleEndian>(self.refid).unwrap();
+        bytes.write_i32::<LittleEndian>(self.pos).unwrap();
+        bytes.write_u8(self.name.len() as u8 + 1).unwrap();
+        bytes.write_u8(self.mapq).unwrap();
+        bytes.write_u16::<LittleEndian>(self.bin).unwrap();
+        bytes
+            .write_u16::<LittleEndian>(self.cigar.len() as u16)
+            .unwrap();
+        bytes.write_u16::<LittleEndian>(self.flag).unwrap();
+        bytes
+            .write_u32::<LittleEndian>(self.seq.len() as u32)
+            .unwrap();
+        bytes.write_i32::<LittleEndian>(self.next_refid).unwrap();
+        bytes.write_i32::<LittleEndian>(self.next_pos).unwrap();
+        bytes.write_i32::<LittleEndian>(self.tlen).unwrap();
+        bytes.extend_from_slice(self.name.as_bytes());
+        bytes.push(0);
+        for op in &self.cigar {
+            bytes.write_u32::<LittleEndian>(*op).unwrap();
+        }
+        let mut seq = vec![0; self.seq.len().div_ceil(2)];
+        crate::utils::seq::encode_bases(self.seq.as_bytes(), &mut seq);
+        bytes.extend_from_slice(&seq);
+        bytes.extend_from_slice(&self.qual);
+        bytes.extend_from_slice(&self.tags);
+        bytes
+    }
+
+    pub fn to_raw(&self) -> BAMRawRecord<'static> {
+        BAMRawRecord::from(self.to_bytes())
+    }
+}
+
+/// Builds SAM header bytes in the layout produced by `bam_tools::Reader::read_header`.
+pub(crate) fn sam_header_bytes(text: &str, ref_seqs: &[(String, u32)]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    bytes
+        .write_u32::<LittleEndian>(text.len() as u32)
+        .unwrap();
+    bytes.extend_from_slice(text.as_bytes());
+    bytes
+        .write_u32::<LittleEndian>(ref_seqs.len() as u32)
+        .unwrap();
+    for (name, len) in ref_seqs {
+        bytes
+            .write_u32::<LittleEndian>(name.len() as u32 + 1)
+            .unwrap();
+        bytes.extend_from_slice(name.as_bytes());
+        bytes.push(0);
+        bytes.write_u32::<LittleEndian>(*len).unwrap();
+    }
+    bytes
+}
+
+pub(crate) fn test_ref_seqs() -> Vec<(String, u32)> {
+    vec![
+        (String::from("chr1"), 1_000_000),
+        (String::from("chr2"), 1_000_000),
+        (String::from("chr3"), 1_000_000),
+    ]
+}
+
+pub(crate) fn new_test_writer(path: &Path, header_text: &str) -> Writer<BufWriter<File>> {
+    let ref_seqs = test_ref_seqs();
+    let settings = WriterSettings {
+        sam_header: sam_header_bytes(header_text, &ref_seqs),
+        ref_seqs,
+        full_command: String::from("test"),
+        ..Default::default()
+    };
+    Writer::create(path, settings).unwrap()
+}
+
+/// Writes records into a new GBAM file at `path`.
+pub(crate) fn write_test_file(path: &Path, header_text: &str, records: &[TestRecord]) {
+    write_test_file_with_limit(path, header_text, records, &[]);
+}
+
+/// Writes records into a new GBAM file at `path`, cutting blocks of
+/// `fields` at `SIZE_LIMIT`, so tests spanning blocks need fewer records.
+pub(crate) fn write_test_file_with_limit(
+    path: &Path,
+    header_text: &str,
+    records: &[TestRecord],
+    fields: &[Fields],
+) {
+    let mut writer = new_test_writer(path, header_text);
+    for field in fields {
+        writer.set_block_size_limit(*field, crate::SIZE_LIMIT).unwrap();
+    }
+    for rec in records {
+        writer.push_record(&rec.to_raw(), false).unwrap();
+    }
+    writer.finish_with_summary(false).unwrap();
+}
+
+pub(crate) fn open_test_file(path: &Path) -> Reader {
+    let mut tmplt = ParsingTemplate::new();
+    tmplt.set_all();
+    Reader::from_path(path, tmplt).unwrap()
+}
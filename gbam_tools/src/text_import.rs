@@ -0,0 +1,529 @@
+//! SAM text and FASTQ input, for pipelines which don't produce BAM. Records
+//! are encoded with `GbamRecordBuilder`, so they are checked like built
+//! records. Malformed lines fail the conversion with
+//! `GbamError::TextInput`, which carries their number.
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufRead, BufWriter};
+use std::path::Path;
+
+use bam_tools::record::fields::{Fields, FIELDS_NUM};
+use byteorder::{LittleEndian, WriteBytesExt};
+
+use crate::error::GbamError;
+use crate::meta::SortOrder;
+use crate::record_builder::{fits_int_type, is_hex, BuiltRecord, GbamRecordBuilder, TagsBuilder};
+use crate::writer::{WriteSummary, WriterSettings};
+use crate::{Codecs, Writer};
+
+const BAM_FPAIRED: u16 = 0x1;
+const BAM_FUNMAP: u16 = 0x4;
+const BAM_FMUNMAP: u16 = 0x8;
+const BAM_FREAD1: u16 = 0x40;
+const BAM_FREAD2: u16 = 0x80;
+
+/// Options of `sam_to_gbam()` and `fastq_to_gbam()`.
+#[derive(Clone, Debug)]
+pub struct TextImportOptions {
+    codec: Codecs,
+    threads: usize,
+    rows_per_block: Option<u32>,
+    command: String,
+}
+
+impl Default for TextImportOptions {
+    /// Lz4 for every field, 4 threads, blocks cut by size only.
+    fn default() -> Self {
+        Self {
+            codec: Codecs::Lz4,
+            threads: 4,
+            rows_per_block: None,
+            command: String::new(),
+        }
+    }
+}
+
+impl TextImportOptions {
+    /// Codec of every field.
+    pub fn with_codec(mut self, codec: Codecs) -> Self {
+        self.codec = codec;
+        self
+    }
+
+    /// Threads compressing GBAM blocks.
+    pub fn with_threads(mut self, threads: usize) -> Self {
+        self.threads = threads.max(1);
+        self
+    }
+
+    /// Records per block, see `Writer::set_rows_per_block()`.
+    pub fn with_block_size(mut self, rows: u32) -> Self {
+        self.rows_per_block = Some(rows);
+        self
+    }
+
+    /// Command recorded in file info.
+    pub fn with_command(mut self, command: String) -> Self {
+        self.command = command;
+        self
+    }
+}
+
+/// Converts SAM text read from `input` into GBAM file at `dst`. Header text
+/// is stored as it is, references are taken from its @SQ lines. The output
+/// is marked coordinate sorted if the @HD line says so. On errors output is
+/// left unfinished.
+pub fn sam_to_gbam<R: BufRead>(
+    input: R,
+    dst: &Path,
+    options: &TextImportOptions,
+) -> io::Result<WriteSummary> {
+    let mut lines = TextLines::new(input);
+    let mut header = String::new();
+    let mut ref_seqs = Vec::new();
+    let mut ref_ids = HashMap::new();
+    let mut writer = None;
+    while lines.next_line()? {
+        let line = lines.line();
+        if line.is_empty() {
+            continue;
+        }
+        if line.starts_with('@') {
+            if writer.is_some() {
+                return Err(lines.error("Header line after records"));
+            }
+            if let Some(ref_seq) = parse_sq_line(line).map_err(|msg| lines.error(&msg))? {
+                if ref_ids.insert(ref_seq.0.clone(), ref_seqs.len() as i32).is_some() {
+                    return Err(lines.error(&format!("Reference {} is defined twice", ref_seq.0)));
+                }
+                ref_seqs.push(ref_seq);
+            }
+            header.push_str(line);
+            header.push('\n');
+            continue;
+        }
+        if writer.is_none() {
+            writer = Some(create_writer(dst, &header, &ref_seqs, options)?);
+        }
+        let rec = parse_sam_record(line, &ref_ids).map_err(|msg| lines.error(&msg))?;
+        writer.as_mut().unwrap().push_built_record(&rec, false)?;
+    }
+    match writer {
+        Some(mut writer) => writer.finish_with_summary(false),
+        None => create_writer(dst, &header, &ref_seqs, options)?.finish_with_summary(false),
+    }
+}
+
+/// Converts unaligned reads of FASTQ read from `input` into GBAM file at
+/// `dst`, with empty header. Reads are named by the first word of their
+/// title line. Names ending in "/1" or "/2" are stored without it, as
+/// READ1 and READ2 of an unmapped pair, like `samtools import` does. Other
+/// reads are unmapped. Columns other than names, flags, sequences and
+/// qualities hold values of unmapped reads: no reference, position -1, mapq
+/// 0, no cigar and no tags. Sequences and qualities take one line each.
+pub fn fastq_to_gbam<R: BufRead>(
+    input: R,
+    dst: &Path,
+    options: &TextImportOptions,
+) -> io::Result<WriteSummary> {
+    let mut lines = TextLines::new(input);
+    let mut writer = create_writer(dst, "", &[], options)?;
+    while lines.next_line()? {
+        if lines.line().is_empty() {
+            continue;
+        }
+        let title = lines
+            .line()
+            .strip_prefix('@')
+            .ok_or_else(|| lines.error("FASTQ record doesn't start with '@'"))?;
+        let name = title.split_whitespace().next().unwrap_or_default();
+        let (name, flags) = match (name.strip_suffix("/1"), name.strip_suffix("/2")) {
+            (Some(name), _) => (name, BAM_FPAIRED | BAM_FUNMAP | BAM_FMUNMAP | BAM_FREAD1),
+            (_, Some(name)) => (name, BAM_FPAIRED | BAM_FUNMAP | BAM_FMUNMAP | BAM_FREAD2),
+            _ => (name, BAM_FUNMAP),
+        };
+        let builder = GbamRecordBuilder::new().read_name(name.as_bytes()).flags(flags).mapq(0);
+
+        let seq = lines.next_fastq_line("sequence")?;
+        let builder = builder.seq(&seq);
+        let separator = lines.next_fastq_line("separator")?;
+        if !separator.starts_with('+') {
+            return Err(lines.error("FASTQ separator line doesn't start with '+'"));
+        }
+        let qual = lines.next_fastq_line("quality")?;
+        let qual = parse_qual(&qual).map_err(|msg| lines.error(&msg))?;
+        let rec = builder.qual(&qual).build().map_err(|err| lines.error(&err.to_string()))?;
+        writer.push_built_record(&rec, false)?;
+    }
+    writer.finish_with_summary(false)
+}
+
+/// Lines of input, without line endings, counted from 1.
+struct TextLines<R> {
+    input: R,
+    line: String,
+    number: u64,
+}
+
+impl<R: BufRead> TextLines<R> {
+    fn new(input: R) -> Self {
+        Self {
+            input,
+            line: String::new(),
+            number: 0,
+        }
+    }
+
+    /// Reads the next line. False at the end of input.
+    fn next_line(&mut self) -> io::Result<bool> {
+        self.line.clear();
+        self.number += 1;
+        let read = self
+            .input
+            .read_line(&mut self.line)
+            .map_err(|err| self.error(&err.to_string()))?;
+        let len = self.line.trim_end_matches(['\n', '\r']).len();
+        self.line.truncate(len);
+        Ok(read > 0)
+    }
+
+    fn line(&self) -> &str {
+        &self.line
+    }
+
+    /// Next line of a FASTQ record, named `what` when it's missing.
+    fn next_fastq_line(&mut self, what: &str) -> io::Result<String> {
+        if !self.next_line()? {
+            return Err(self.error(&format!("FASTQ record is truncated, its {} line is missing", what)));
+        }
+        Ok(self.line.clone())
+    }
+
+    /// Error at the current line.
+    fn error(&self, message: &str) -> io::Error {
+        GbamError::TextInput {
+            line: self.number,
+            message: message.to_owned(),
+        }
+        .into()
+    }
+}
+
+fn create_writer(
+    dst: &Path,
+    header: &str,
+    ref_seqs: &[(String, u32)],
+    options: &TextImportOptions,
+) -> io::Result<Writer<BufWriter<File>>> {
+    let settings = WriterSettings {
+        codecs: vec![options.codec; FIELDS_NUM],
+        thread_num: options.threads,
+        collect_stats_for: vec![Fields::RefID],
+        ref_seqs: ref_seqs.to_vec(),
+        sam_header: header_bytes(header, ref_seqs),
+        full_command: options.command.clone(),
+        is_sorted: SortOrder::from_sam_header(header.as_bytes()) == SortOrder::Coordinate,
+        ..Default::default()
+    };
+    let mut writer = Writer::create(dst, settings)?;
+    if let Some(rows) = options.rows_per_block {
+        writer.set_rows_per_block(rows);
+    }
+    Ok(writer)
+}
+
+/// Header bytes (`l_text`, text, `n_ref` and references, as in BAM).
+fn header_bytes(text: &str, ref_seqs: &[(String, u32)]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    bytes.write_u32::<LittleEndian>(text.len() as u32).unwrap();
+    bytes.extend_from_slice(text.as_bytes());
+    bytes.write_u32::<LittleEndian>(ref_seqs.len() as u32).unwrap();
+    for (name, len) in ref_seqs {
+        bytes.write_u32::<LittleEndian>(name.len() as u32 + 1).unwrap();
+        bytes.extend_from_slice(name.as_bytes());
+        bytes.push(0);
+        bytes.write_u32::<LittleEndian>(*len).unwrap();
+    }
+    bytes
+}
+
+/// Reference of @SQ line, None for other header lines.
+fn parse_sq_line(line: &str) -> Result<Option<(String, u32)>, String> {
+    let tags = match line.strip_prefix("@SQ\t") {
+        Some(tags) => tags,
+        None => return Ok(None),
+    };
+    let tag = |name: &str| tags.split('\t').find_map(|tag| tag.strip_prefix(name));
+    let name = tag("SN:").ok_or("@SQ line has no SN tag")?;
+    let len = tag("LN:")
+        .and_then(|len| len.parse().ok())
+        .ok_or("@SQ line has no valid LN tag")?;
+    Ok(Some((name.to_owned(), len)))
+}
+
+fn parse_sam_record(line: &str, ref_ids: &HashMap<String, i32>) -> Result<BuiltRecord, String> {
+    let columns: Vec<&str> = line.split('\t').collect();
+    if columns.len() < 11 {
+        return Err(format!("SAM record has {} columns, 11 are mandatory", columns.len()));
+    }
+    let ref_id = |name: &str| match name {
+        "*" => Ok(-1),
+        _ => ref_ids
+            .get(name)
+            .copied()
+            .ok_or_else(|| format!("Reference {} is not in header", name)),
+    };
+    let number = |column: usize, name: &str| {
+        columns[column]
+            .parse::<i64>()
+            .map_err(|_| format!("Invalid {} {:?}", name, columns[column]))
+    };
+    let in_range = |value: i64, range: std::ops::RangeInclusive<i64>, name: &str| {
+        if range.contains(&value) {
+            Ok(value)
+        } else {
+            Err(format!("{} {} is out of range", name, value))
+        }
+    };
+    let rec_ref_id = ref_id(columns[2])?;
+    let next_ref_id = match columns[6] {
+        "=" => rec_ref_id,
+        name => ref_id(name)?,
+    };
+    let flags = in_range(number(1, "FLAG")?, 0..=i64::from(u16::MAX), "FLAG")?;
+    let pos = in_range(number(3, "POS")?, 0..=i64::from(i32::MAX), "POS")?;
+    let mapq = in_range(number(4, "MAPQ")?, 0..=i64::from(u8::MAX), "MAPQ")?;
+    let next_pos = in_range(number(7, "PNEXT")?, 0..=i64::from(i32::MAX), "PNEXT")?;
+    let tlen = in_range(number(8, "TLEN")?, i64::from(i32::MIN)..=i64::from(i32::MAX), "TLEN")?;
+
+    let mut builder = GbamRecordBuilder::new()
+        .read_name(columns[0].as_bytes())
+        .flags(flags as u16)
+        .ref_id(rec_ref_id)
+        .pos(pos as i32 - 1)
+        .mapq(mapq as u8)
+        .next_ref_id(next_ref_id)
+        .next_pos(next_pos as i32 - 1)
+        .tlen(tlen as i32)
+        .cigar(&parse_cigar(columns[5])?);
+    if columns[9] != "*" {
+        builder = builder.seq(columns[9]);
+    }
+    if columns[10] != "*" {
+        builder = builder.qual(&parse_qual(columns[10])?);
+    }
+    let mut tags = TagsBuilder::new();
+    for tag in &columns[11..] {
+        tags = parse_tag(tags, tag)?;
+    }
+    builder.tags(tags).build().map_err(|err| err.to_string())
+}
+
+fn parse_cigar(cigar: &str) -> Result<Vec<(u32, char)>, String> {
+    if cigar == "*" {
+        return Ok(Vec::new());
+    }
+    let invalid = || format!("Invalid CIGAR {:?}", cigar);
+    let mut ops = Vec::new();
+    let mut len_start = 0;
+    for (pos, op) in cigar.char_indices() {
+        if op.is_ascii_digit() {
+            continue;
+        }
+        let len = cigar[len_start..pos].parse().map_err(|_| invalid())?;
+        ops.push((len, op));
+        len_start = pos + op.len_utf8();
+    }
+    if len_start != cigar.len() {
+        return Err(invalid());
+    }
+    Ok(ops)
+}
+
+/// Phred qualities of "!" to "~" characters.
+fn parse_qual(qual: &str) -> Result<Vec<u8>, String> {
+    match qual.bytes().find(|c| !(b'!'..=b'~').contains(c)) {
+        Some(c) => Err(format!("Invalid quality {:?}", char::from(c))),
+        None => Ok(qual.bytes().map(|c| c - 33).collect()),
+    }
+}
+
+/// Adds tag of TAG:TYPE:VALUE text to `tags`.
+fn parse_tag(tags: TagsBuilder, text: &str) -> Result<TagsBuilder, String> {
+    let invalid = || format!("Invalid tag {:?}", text);
+    let mut parts = text.splitn(3, ':');
+    let (name, tag_type, value) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(name), Some(tag_type), Some(value)) => (name.as_bytes(), tag_type, value),
+        _ => return Err(invalid()),
+    };
+    if name.len() != 2 || !name[0].is_ascii_alphabetic() || !name[1].is_ascii_alphanumeric() {
+        return Err(invalid());
+    }
+    let name = [name[0], name[1]];
+    let tags = match tag_type {
+        "A" if value.len() == 1 && value.as_bytes()[0].is_ascii_graphic() => {
+            tags.char(name, value.as_bytes()[0])
+        }
+        "i" => {
+            let value: i64 = value.parse().map_err(|_| invalid())?;
+            if !fits_int_type(b'i', value) && !fits_int_type(b'I', value) {
+                return Err(invalid());
+            }
+            tags.int(name, value)
+        }
+        "f" => tags.float(name, value.parse().map_err(|_| invalid())?),
+        "Z" if !value.contains('\0') => tags.string(name, value),
+        "H" if is_hex(value) => tags.hex(name, value),
+        "B" => {
+            let mut items = value.split(',');
+            let item_type = items.next().unwrap_or_default();
+            let items: Vec<&str> = items.collect();
+            match *item_type.as_bytes() {
+                [b'f'] => {
+                    let values: Result<Vec<f32>, _> = items.iter().map(|item| item.parse()).collect();
+                    tags.float_array(name, &values.map_err(|_| invalid())?)
+                }
+                [item_type] => {
+                    let values: Result<Vec<i64>, _> = items.iter().map(|item| item.parse()).collect();
+                    let values = values.map_err(|_| invalid())?;
+                    if !values.iter().all(|&value| fits_int_type(item_type, value)) {
+                        return Err(invalid());
+                    }
+                    tags.typed_int_array(name, item_type, &values)
+                }
+                _ => return Err(invalid()),
+            }
+        }
+        _ => return Err(invalid()),
+    };
+    Ok(tags)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::gbam_error;
+    use crate::fastq_export::{fastq_export_interleaved, FastqOptions};
+    use crate::sam_export::SamWriter;
+    use crate::test_utils::open_test_file;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_sam_round_trip() {
+        let dir = TempDir::new("gbam_text_import").unwrap();
+        let path = dir.path().join("sam.gbam");
+        let sam = "@HD\tVN:1.6\tSO:unsorted\n\
+            @SQ\tSN:chr1\tLN:1000000\n\
+            @SQ\tSN:chr2\tLN:1000000\n\
+            @SQ\tSN:chr3\tLN:1000000\n\
+            @RG\tID:grp1\tSM:sample\n\
+            pair\t99\tchr2\t100\t60\t2M1I1M\t=\t200\t104\tACGT\t!+5I\
+            \tXA:A:x\tXc:i:-2\tXC:i:200\tXs:i:-1000\tXS:i:1000\tXi:i:-2147483648\
+            \tXI:i:4294967295\tXf:f:0.1\tXZ:Z:some text\tXH:H:1AE301\tXB:B:s,-1,2\
+            \tXb:B:f,1.5\tXe:B:C\n\
+            other\t0\tchr1\t6\t60\t4M\tchr3\t8\t0\tACGT\t????\tRG:Z:grp1\n\
+            noqual\t16\tchr3\t1000000\t255\t3S1M\t*\t0\t0\tNACG\t*\n\
+            unmapped\t4\t*\t0\t0\t*\t*\t0\t0\t*\t*\n";
+        let summary = sam_to_gbam(sam.as_bytes(), &path, &TextImportOptions::default()).unwrap();
+        assert_eq!(summary.records_written, 4);
+
+        let mut reader = open_test_file(&path);
+        let mut writer = SamWriter::new(Vec::new(), reader.file_meta.clone());
+        writer.write_header().unwrap();
+        let mut records = reader.records();
+        while let Some(rec) = records.next_rec().unwrap() {
+            writer.write_record(rec).unwrap();
+        }
+        assert_eq!(String::from_utf8(writer.into_inner()).unwrap(), sam);
+    }
+
+    #[test]
+    fn test_sam_errors() {
+        let dir = TempDir::new("gbam_text_import").unwrap();
+        let path = dir.path().join("sam.gbam");
+        let header = "@SQ\tSN:chr1\tLN:1000\n";
+        let record = "r\t0\tchr1\t1\t60\t4M\t*\t0\t0\tACGT\t????";
+        for (line, expected) in [
+            ("r\t0\tchr1\t1\t60\t4M\t*\t0\t0\tACGT", "SAM record has 10 columns"),
+            ("r\t0\tchr9\t1\t60\t4M\t*\t0\t0\tACGT\t????", "Reference chr9 is not in header"),
+            ("r\tx\tchr1\t1\t60\t4M\t*\t0\t0\tACGT\t????", "Invalid FLAG"),
+            ("r\t0\tchr1\t1\t256\t4M\t*\t0\t0\tACGT\t????", "MAPQ 256 is out of range"),
+            ("r\t0\tchr1\t1\t60\t4Q\t*\t0\t0\tACGT\t????", "Invalid cigar operation 4Q"),
+            ("r\t0\tchr1\t1\t60\t4\t*\t0\t0\tACGT\t????", "Invalid CIGAR"),
+            ("r\t0\tchr1\t1\t60\t4M\t*\t0\t0\tACGT\t???", "3 qualities for 4 bases"),
+            ("r\t0\tchr1\t1\t60\t4M\t*\t0\t0\tACGT\t????\tXB:B:c,200", "Invalid tag"),
+            ("r\t0\tchr1\t1\t60\t4M\t*\t0\t0\tACGT\t????\tXH:H:ABC", "Invalid tag"),
+            ("@CO\tlate", "Header line after records"),
+        ] {
+            let sam = format!("{}{}\n\n{}\n", header, record, line);
+            let err = sam_to_gbam(sam.as_bytes(), &path, &TextImportOptions::default()).err().unwrap();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            match gbam_error(&err) {
+                Some(GbamError::TextInput { line, message }) => {
+                    assert_eq!(*line, 4);
+                    assert!(message.starts_with(expected), "{}", message);
+                }
+                other => panic!("Unexpected error {:?}", other),
+            }
+        }
+        let options = TextImportOptions::default();
+        let err = sam_to_gbam("@SQ\tSN:chr1\n".as_bytes(), &path, &options).err().unwrap();
+        assert_eq!(err.to_string(), "Line 1: @SQ line has no valid LN tag");
+
+        // Header only input gives a file without records.
+        let summary = sam_to_gbam(header.as_bytes(), &path, &TextImportOptions::default()).unwrap();
+        assert_eq!(summary.records_written, 0);
+        assert_eq!(open_test_file(&path).file_meta.get_ref_seqs(), &vec![(String::from("chr1"), 1000)]);
+    }
+
+    #[test]
+    fn test_fastq_round_trip() {
+        let dir = TempDir::new("gbam_text_import").unwrap();
+        let path = dir.path().join("fastq.gbam");
+        let fastq = "@pair1/1\nACGTN\n+\n!+5I~\n\
+            @pair1/2\nTTGA\n+\n####\n\
+            @single\nGATTACA\n+\nIIIIIII\n\
+            @pair2/1\nA\n+\n5\n\
+            @pair2/2\nC\n+\n5\n";
+        // Comments of title lines and repeated names after "+" are dropped.
+        let input = fastq
+            .replace("@single\n", "@single comment\n")
+            .replacen("\n+\n", "\n+pair1/1\n", 1);
+        let options = TextImportOptions::default().with_block_size(2);
+        let summary = fastq_to_gbam(input.as_bytes(), &path, &options).unwrap();
+        assert_eq!(summary.records_written, 5);
+
+        let mut reader = open_test_file(&path);
+        let mut out = Vec::new();
+        let report = fastq_export_interleaved(&mut reader, &mut out, &FastqOptions::default()).unwrap();
+        assert_eq!((report.pairs, report.singletons), (2, 1));
+        assert_eq!(String::from_utf8(out).unwrap(), fastq);
+
+        let mut records = reader.records();
+        let rec = records.next_rec().unwrap().unwrap();
+        assert_eq!((rec.flag, rec.refid, rec.pos, rec.mapq), (Some(77), Some(-1), Some(-1), Some(0)));
+        assert!(rec.cigar.as_ref().unwrap().0.is_empty());
+    }
+
+    #[test]
+    fn test_fastq_errors() {
+        let dir = TempDir::new("gbam_text_import").unwrap();
+        let path = dir.path().join("fastq.gbam");
+        for (fastq, line, expected) in [
+            ("@r\nACGT\n+\nIIII\nr2\n", 5, "FASTQ record doesn't start with '@'"),
+            ("@r\nACGT\n-\nIIII\n", 3, "FASTQ separator line doesn't start with '+'"),
+            ("@r\nACGT\n+\n", 4, "FASTQ record is truncated, its quality line is missing"),
+            ("@r\nACGT\n+\nII I\n", 4, "Invalid quality ' '"),
+            ("@r\nACGT\n+\nIII\n", 4, "3 qualities for 4 bases"),
+            ("@r\nAC-T\n+\nIIII\n", 4, "Invalid base '-'"),
+        ] {
+            let err = fastq_to_gbam(fastq.as_bytes(), &path, &TextImportOptions::default()).err().unwrap();
+            match gbam_error(&err) {
+                Some(GbamError::TextInput { line: at, message }) => {
+                    assert_eq!((*at, message.as_str()), (line, expected));
+                }
+                other => panic!("{:?}", other),
+            }
+        }
+    }
+}
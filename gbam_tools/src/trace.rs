@@ -0,0 +1,408 @@
+//! Tracing of writer and reader hot paths, with the `tracing` feature. Spans of
+//! target "gbam" are entered around block flush, compression and writing, meta
+//! serialization, and block fetch and decompression. They carry field name,
+//! block number and sizes, subscribers time them. Writers and readers also sum
+//! blocks, bytes and time spent, and emit the totals as a "summary" event on
+//! `Writer::finish_with_summary()` and when a `Reader` is dropped.
+//!
+//! Without the feature the macros expand to nothing and counters are zero
+//! sized, so nothing is measured.
+#[cfg(feature = "tracing")]
+use std::collections::HashMap;
+#[cfg(feature = "tracing")]
+use std::convert::TryFrom;
+#[cfg(feature = "tracing")]
+use std::fmt;
+#[cfg(feature = "tracing")]
+use std::io::Write;
+#[cfg(feature = "tracing")]
+use std::sync::atomic::{AtomicU64, Ordering};
+#[cfg(feature = "tracing")]
+use std::sync::{Arc, Mutex};
+#[cfg(feature = "tracing")]
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "tracing")]
+use tracing::field::{Field, Visit};
+#[cfg(feature = "tracing")]
+use tracing::span::{Attributes, Id, Record};
+#[cfg(feature = "tracing")]
+use tracing::{Event, Metadata, Subscriber};
+
+/// Enters a span of target "gbam" until the end of scope, e.g.
+/// `let _span = trace_span!("fetch_block", field = %field, block = num);`.
+#[cfg(feature = "tracing")]
+macro_rules! trace_span {
+    ($name:literal $(, $($fields:tt)+)?) => {
+        tracing::debug_span!(target: "gbam", $name $(, $($fields)+)?).entered()
+    };
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! trace_span {
+    ($($args:tt)*) => {
+        $crate::trace::NoSpan
+    };
+}
+
+/// Records `value` in a field of `span` declared as `tracing::field::Empty`.
+#[cfg(feature = "tracing")]
+macro_rules! trace_record {
+    ($span:expr, $name:literal, $value:expr) => {
+        {
+            $span.record($name, $value);
+        }
+    };
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! trace_record {
+    ($span:expr, $name:literal, $value:expr) => {
+        {
+            let _ = (&$span, &$value);
+        }
+    };
+}
+
+pub(crate) use trace_record;
+pub(crate) use trace_span;
+
+/// Stands for a span without the `tracing` feature.
+#[cfg(not(feature = "tracing"))]
+pub(crate) struct NoSpan;
+
+/// Start of a measured operation.
+pub(crate) struct Timer {
+    #[cfg(feature = "tracing")]
+    start: Instant,
+}
+
+impl Timer {
+    #[inline]
+    pub fn start() -> Self {
+        Self {
+            #[cfg(feature = "tracing")]
+            start: Instant::now(),
+        }
+    }
+}
+
+#[cfg(feature = "tracing")]
+struct Totals {
+    kind: &'static str,
+    blocks: AtomicU64,
+    uncompressed_bytes: AtomicU64,
+    compressed_bytes: AtomicU64,
+    // Compression by writers, decompression by readers.
+    codec_nanos: AtomicU64,
+    // Flushing and writing blocks by writers, reading them by readers.
+    io_nanos: AtomicU64,
+}
+
+/// Totals of a writer or a reader, shared by their threads.
+#[derive(Clone)]
+pub(crate) struct Counters {
+    #[cfg(feature = "tracing")]
+    totals: Arc<Totals>,
+}
+
+impl Counters {
+    /// `kind` names the summary, "writer" or "reader".
+    pub fn new(_kind: &'static str) -> Self {
+        Self {
+            #[cfg(feature = "tracing")]
+            totals: Arc::new(Totals {
+                kind: _kind,
+                blocks: AtomicU64::new(0),
+                uncompressed_bytes: AtomicU64::new(0),
+                compressed_bytes: AtomicU64::new(0),
+                codec_nanos: AtomicU64::new(0),
+                io_nanos: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    #[inline]
+    pub fn add_block(&self, _uncompressed: u64, _compressed: u64) {
+        #[cfg(feature = "tracing")]
+        {
+            let totals = &self.totals;
+            totals.blocks.fetch_add(1, Ordering::Relaxed);
+            totals.uncompressed_bytes.fetch_add(_uncompressed, Ordering::Relaxed);
+            totals.compressed_bytes.fetch_add(_compressed, Ordering::Relaxed);
+        }
+    }
+
+    #[inline]
+    pub fn add_codec_time(&self, _timer: &Timer) {
+        #[cfg(feature = "tracing")]
+        add_elapsed(&self.totals.codec_nanos, _timer);
+    }
+
+    #[inline]
+    pub fn add_io_time(&self, _timer: &Timer) {
+        #[cfg(feature = "tracing")]
+        add_elapsed(&self.totals.io_nanos, _timer);
+    }
+
+    /// Emits totals as an info event with message "summary".
+    pub fn emit_summary(&self) {
+        #[cfg(feature = "tracing")]
+        {
+            let totals = &self.totals;
+            let millis = |nanos: &AtomicU64| nanos.load(Ordering::Relaxed) / 1_000_000;
+            tracing::info!(
+                target: "gbam",
+                kind = totals.kind,
+                blocks = totals.blocks.load(Ordering::Relaxed),
+                uncompressed_bytes = totals.uncompressed_bytes.load(Ordering::Relaxed),
+                compressed_bytes = totals.compressed_bytes.load(Ordering::Relaxed),
+                codec_ms = millis(&totals.codec_nanos),
+                io_ms = millis(&totals.io_nanos),
+                "summary"
+            );
+        }
+    }
+}
+
+#[cfg(feature = "tracing")]
+fn add_elapsed(nanos: &AtomicU64, timer: &Timer) {
+    let elapsed = u64::try_from(timer.start.elapsed().as_nanos()).unwrap_or(u64::MAX);
+    nanos.fetch_add(elapsed, Ordering::Relaxed);
+}
+
+/// Emits summary of counters when dropped.
+pub(crate) struct SummaryOnDrop {
+    #[cfg(feature = "tracing")]
+    counters: Counters,
+}
+
+impl SummaryOnDrop {
+    pub fn new(_counters: &Counters) -> Self {
+        Self {
+            #[cfg(feature = "tracing")]
+            counters: _counters.clone(),
+        }
+    }
+}
+
+#[cfg(feature = "tracing")]
+impl Drop for SummaryOnDrop {
+    fn drop(&mut self) {
+        self.counters.emit_summary();
+    }
+}
+
+/// Subscriber of the calling thread, so spans of work handed to other
+/// threads reach it.
+#[derive(Clone)]
+pub(crate) struct Dispatch {
+    #[cfg(feature = "tracing")]
+    inner: tracing::Dispatch,
+}
+
+/// Keeps a `Dispatch` the default of the thread until dropped.
+pub(crate) struct DispatchGuard {
+    #[cfg(feature = "tracing")]
+    _inner: tracing::dispatcher::DefaultGuard,
+}
+
+impl Dispatch {
+    #[inline]
+    pub fn current() -> Self {
+        Self {
+            #[cfg(feature = "tracing")]
+            inner: tracing::dispatcher::get_default(|dispatch| dispatch.clone()),
+        }
+    }
+
+    #[inline]
+    pub fn enter(&self) -> DispatchGuard {
+        DispatchGuard {
+            #[cfg(feature = "tracing")]
+            _inner: tracing::dispatcher::set_default(&self.inner),
+        }
+    }
+}
+
+/// Subscriber printing closed spans of target "gbam", with their fields and
+/// time spent inside, and events, a line each. Meant for diagnosing slow
+/// conversions without other tracing dependencies, e.g.
+/// `tracing::subscriber::set_global_default(LogSubscriber::stderr())`.
+#[cfg(feature = "tracing")]
+pub struct LogSubscriber {
+    output: Mutex<Box<dyn Write + Send>>,
+    next_id: AtomicU64,
+    spans: Mutex<HashMap<u64, OpenSpan>>,
+}
+
+#[cfg(feature = "tracing")]
+struct OpenSpan {
+    name: &'static str,
+    fields: String,
+    refs: usize,
+    entered: Option<Instant>,
+    busy: Duration,
+}
+
+#[cfg(feature = "tracing")]
+struct FieldWriter<'a>(&'a mut String);
+
+#[cfg(feature = "tracing")]
+impl Visit for FieldWriter<'_> {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        use std::fmt::Write;
+        let _ = write!(self.0, " {}={:?}", field.name(), value);
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        use std::fmt::Write;
+        let _ = write!(self.0, " {}={}", field.name(), value);
+    }
+}
+
+#[cfg(feature = "tracing")]
+impl LogSubscriber {
+    pub fn new<W: Write + Send + 'static>(output: W) -> Self {
+        Self {
+            output: Mutex::new(Box::new(output)),
+            next_id: AtomicU64::new(1),
+            spans: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn stderr() -> Self {
+        Self::new(std::io::stderr())
+    }
+
+    fn print(&self, line: &str) {
+        let _ = writeln!(self.output.lock().unwrap(), "{}", line);
+    }
+}
+
+#[cfg(feature = "tracing")]
+impl Subscriber for LogSubscriber {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        metadata.target() == "gbam"
+    }
+
+    fn new_span(&self, span: &Attributes<'_>) -> Id {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let mut fields = String::new();
+        span.record(&mut FieldWriter(&mut fields));
+        let open = OpenSpan {
+            name: span.metadata().name(),
+            fields,
+            refs: 1,
+            entered: None,
+            busy: Duration::ZERO,
+        };
+        self.spans.lock().unwrap().insert(id, open);
+        Id::from_u64(id)
+    }
+
+    fn record(&self, span: &Id, values: &Record<'_>) {
+        if let Some(open) = self.spans.lock().unwrap().get_mut(&span.into_u64()) {
+            values.record(&mut FieldWriter(&mut open.fields));
+        }
+    }
+
+    fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+
+    fn event(&self, event: &Event<'_>) {
+        let mut fields = String::new();
+        event.record(&mut FieldWriter(&mut fields));
+        self.print(&format!("gbam event{}", fields));
+    }
+
+    fn enter(&self, span: &Id) {
+        if let Some(open) = self.spans.lock().unwrap().get_mut(&span.into_u64()) {
+            open.entered = Some(Instant::now());
+        }
+    }
+
+    fn exit(&self, span: &Id) {
+        if let Some(open) = self.spans.lock().unwrap().get_mut(&span.into_u64()) {
+            if let Some(entered) = open.entered.take() {
+                open.busy += entered.elapsed();
+            }
+        }
+    }
+
+    fn clone_span(&self, span: &Id) -> Id {
+        if let Some(open) = self.spans.lock().unwrap().get_mut(&span.into_u64()) {
+            open.refs += 1;
+        }
+        span.clone()
+    }
+
+    fn try_close(&self, span: Id) -> bool {
+        let mut spans = self.spans.lock().unwrap();
+        let closed = match spans.get_mut(&span.into_u64()) {
+            Some(open) => {
+                open.refs -= 1;
+                open.refs == 0
+            }
+            None => false,
+        };
+        if closed {
+            let open = spans.remove(&span.into_u64()).unwrap();
+            drop(spans);
+            self.print(&format!("gbam {}{} busy={:?}", open.name, open.fields, open.busy));
+        }
+        closed
+    }
+}
+
+#[cfg(all(test, feature = "tracing"))]
+mod tests {
+    use super::*;
+    use crate::test_utils::{open_test_file, write_test_file, TestRecord};
+    use tempdir::TempDir;
+
+    #[derive(Clone, Default)]
+    struct Captured(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_conversion_spans() {
+        let dir = TempDir::new("gbam_trace").unwrap();
+        let path = dir.path().join("traced.gbam");
+        let records: Vec<TestRecord> = (0..1_000).map(|i| TestRecord::new(0, i, &format!("r{}", i))).collect();
+        let captured = Captured::default();
+        tracing::subscriber::with_default(LogSubscriber::new(captured.clone()), || {
+            write_test_file(&path, "", &records);
+            let mut reader = open_test_file(&path);
+            let mut recs = reader.records();
+            while recs.next_rec().unwrap().is_some() {}
+        });
+
+        let output = String::from_utf8(captured.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        let spans = |name: &str| {
+            let prefix = format!("gbam {} ", name);
+            lines.iter().filter(|line| line.starts_with(&prefix)).count()
+        };
+        // Fields of both columns and indices have a block each.
+        for name in ["flush_block", "compress_block", "write_block", "fetch_block", "decompress_block"] {
+            assert!(spans(name) >= 10, "{} in {}", name, output);
+        }
+        assert_eq!(spans("write_meta"), 1);
+        assert!(output.contains("gbam compress_block field=ReadName items=1000"), "{}", output);
+        assert!(output.contains("gbam fetch_block field=Pos block=0"), "{}", output);
+        for kind in ["writer", "reader"] {
+            let summary = format!("gbam event message=summary kind={} blocks=", kind);
+            assert_eq!(lines.iter().filter(|line| line.starts_with(&summary)).count(), 1, "{}", output);
+        }
+    }
+}
@@ -0,0 +1,171 @@
+//! Recompression of existing GBAM files with different codecs.
+use std::collections::HashMap;
+use std::io::{self, Seek, SeekFrom, Write};
+
+use bam_tools::record::fields::{Fields, FIELDS_NUM};
+
+use crate::compressor::{Compressor, CompressorPool, OrderingKey};
+use crate::layout::to_usize;
+use crate::meta::{Codecs, FileInfo, SortOrder};
+use crate::reader::column::decompress_block;
+use crate::reader::reader::Reader;
+use crate::source_index;
+use crate::writer::{write_data_and_update_meta, write_meta_and_file_info, BlockInfo, SyncOutput};
+use crate::GBAM_VERSION;
+use crate::provenance::PROVENANCE_EXTENSION;
+
+/// Copies file opened by `reader` into `out`, recompressing fields listed in
+/// `new_codecs`. Blocks are copied one to one, so block boundaries, item
+/// counts and stats are preserved, records are never reconstructed. Other
+/// fields are copied without decompression. Encrypted fields can't change
+/// codec. Returns total amount of bytes written.
+pub fn transcode<W: Write + Seek + SyncOutput>(
+    reader: &Reader,
+    mut out: W,
+    new_codecs: &HashMap<Fields, Codecs>,
+    thread_num: usize,
+) -> io::Result<u64> {
+    let old_meta = &reader.file_meta;
+    let mut file_meta = (**old_meta).clone();
+    file_meta.remove_extension(PROVENANCE_EXTENSION);
+    let is_sorted = old_meta.get_sort_order() == SortOrder::Coordinate;
+    let mut file_info = FileInfo::new(GBAM_VERSION, 0, 0, String::from("transcode"), is_sorted);
+    out.seek(SeekFrom::Start(0))?;
+    out.write_all(&file_info.to_padded_bytes()?)?;
+
+    // Encrypted blocks are copied as is, so nothing is encrypted here.
+    let ciphers = vec![None; FIELDS_NUM];
+    let mut compressor = Compressor::new(CompressorPool::new(thread_num));
+    for field in Fields::iterator() {
+        let old_codec = *old_meta.get_field_codec(field);
+        let codec = *new_codecs.get(field).unwrap_or(&old_codec);
+        codec.check_available(*field)?;
+        if codec != old_codec {
+            if let Some(encryption) = old_meta.get_field_encryption(field) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "Field {} is encrypted with key '{}', its codec can't be changed",
+                        field, encryption.key_id
+                    ),
+                ));
+            }
+        }
+        file_meta.set_field_codec(field, codec);
+        file_meta.get_blocks(field).clear();
+
+        for (block_num, block) in old_meta.view_blocks(field).iter().enumerate() {
+            let data = reader.block_data(block)?;
+            if codec == old_codec {
+                let mut block = block.clone();
+                block.seekpos = out.stream_position()?;
+                out.write_all(&data)?;
+                file_meta.get_blocks(field).push(block);
+                continue;
+            }
+
+            let mut uncompressed = vec![0; to_usize(block.uncompressed_size)?];
+            if block.uncompressed_size > 0 {
+                decompress_block(&data, &mut uncompressed, &block.codec(old_codec))?;
+            }
+            // Values of grouped fields are recompressed on their own.
+            uncompressed = block.stripe_of(field, uncompressed)?;
+            let block_info = BlockInfo {
+                numitems: block.numitems,
+                uncompr_size: uncompressed.len(),
+                field: *field,
+                stats: block.stats.clone(),
+                bloom: block.bloom.clone(),
+                transform: block.transform,
+                extra_stats: block.extra_stats.clone(),
+                codec,
+                stripes: Vec::new(),
+            };
+            compressor.compress_block(OrderingKey::Key(block_num as u64), block_info, uncompressed);
+            let mut task = compressor.get_compr_block();
+            if let OrderingKey::Key(key) = task.ordering_key {
+                write_data_and_update_meta(&mut out, &mut file_meta, &ciphers, key, &mut task)?;
+            }
+        }
+    }
+    for mut task in compressor.finish() {
+        if let OrderingKey::Key(key) = task.ordering_key {
+            write_data_and_update_meta(&mut out, &mut file_meta, &ciphers, key, &mut task)?;
+        }
+    }
+    source_index::copy_blocks(std::slice::from_ref(reader), &mut out, &mut file_meta)?;
+
+    write_meta_and_file_info(&mut out, &mut file_meta, &mut file_info)
+}
+
+// Tests recompress into Zstd.
+#[cfg(all(test, feature = "zstd"))]
+mod tests {
+    use super::*;
+    use crate::test_utils::{open_test_file, write_test_file_with_limit, TestRecord};
+    use std::fs::File;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_transcode() {
+        let dir = TempDir::new("gbam_transcode").unwrap();
+        let src = dir.path().join("lz4.gbam");
+        let dst = dir.path().join("zstd.gbam");
+        let records: Vec<TestRecord> = (0..300_000)
+            .map(|i| {
+                let mut rec =
+                    TestRecord::new(i % 3, i, &format!("{}{}", "n".repeat(i as usize % 50), i));
+                rec.tags = b"NMC\x01".to_vec();
+                rec
+            })
+            .collect();
+        write_test_file_with_limit(&src, "@HD\tVN:1.6\tSO:coordinate\n", &records, &[Fields::ReadName]);
+
+        let reader = open_test_file(&src);
+        let mut codecs = HashMap::new();
+        for field in [
+            Fields::ReadName,
+            Fields::LName,
+            Fields::Pos,
+            Fields::RawTags,
+        ] {
+            codecs.insert(field, Codecs::Zstd);
+        }
+        codecs.insert(Fields::RawQual, Codecs::Gzip);
+        transcode(&reader, File::create(&dst).unwrap(), &codecs, 4).unwrap();
+
+        let mut transcoded = open_test_file(&dst);
+        let (old_meta, new_meta) = (&reader.file_meta, transcoded.file_meta.clone());
+        for field in Fields::iterator() {
+            let codec = codecs.get(field).unwrap_or(&Codecs::Lz4);
+            assert_eq!(new_meta.get_field_codec(field), codec);
+            let (old_blocks, new_blocks) =
+                (old_meta.view_blocks(field), new_meta.view_blocks(field));
+            assert_eq!(old_blocks.len(), new_blocks.len());
+            for (old, new) in old_blocks.iter().zip(new_blocks) {
+                assert_eq!(old.numitems, new.numitems);
+                assert_eq!(old.uncompressed_size, new.uncompressed_size);
+                assert_eq!(
+                    old.stats.as_ref().map(|s| (s.min_value, s.max_value)),
+                    new.stats.as_ref().map(|s| (s.min_value, s.max_value))
+                );
+            }
+        }
+        assert!(new_meta.view_blocks(&Fields::ReadName).len() > 1);
+
+        let mut original = open_test_file(&src);
+        let mut expected = original.records();
+        let mut actual = transcoded.records();
+        let mut n = 0;
+        while let Some(rec) = actual.next_rec().unwrap() {
+            let exp = expected.next_rec().unwrap().unwrap();
+            assert_eq!(
+                serde_json::to_string(rec).unwrap(),
+                serde_json::to_string(exp).unwrap()
+            );
+            n += 1;
+        }
+        assert!(expected.next_rec().unwrap().is_none());
+        assert_eq!(n, records.len());
+    }
+}
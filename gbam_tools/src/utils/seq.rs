@@ -0,0 +1,204 @@
+//! Conversion between ASCII bases and 4-bit codes of BAM sequences, two
+//! bases per byte. Bytes are decoded with a table holding both of their
+//! bases. With `simd` feature, 16 bytes at a time are decoded with SSSE3
+//! (x86_64, detected at runtime) or NEON (aarch64).
+
+/// Bases by their 4-bit code.
+const BASES: &[u8; 16] = b"=ACMGRSVTWYHKDBN";
+
+/// Both bases of each packed byte.
+static DECODE: [[u8; 2]; 256] = decode_table();
+
+/// Codes of ASCII bases, case insensitive. Other characters are N.
+static ENCODE: [u8; 256] = encode_table();
+
+const fn decode_table() -> [[u8; 2]; 256] {
+    let mut table = [[0; 2]; 256];
+    let mut byte = 0;
+    while byte < 256 {
+        table[byte] = [BASES[byte >> 4], BASES[byte & 0xf]];
+        byte += 1;
+    }
+    table
+}
+
+const fn encode_table() -> [u8; 256] {
+    let mut table = [15; 256];
+    let mut code = 0;
+    while code < 16 {
+        table[BASES[code] as usize] = code as u8;
+        table[BASES[code].to_ascii_lowercase() as usize] = code as u8;
+        code += 1;
+    }
+    table
+}
+
+/// Appends the first `len` bases of `packed` to `out`. `len` can't exceed
+/// twice the length of `packed`.
+pub fn decode_bases(packed: &[u8], len: usize, out: &mut Vec<u8>) {
+    assert!(len <= 2 * packed.len(), "{} bases don't fit in {} bytes", len, packed.len());
+    let packed = &packed[..len.div_ceil(2)];
+    let start = out.len();
+    out.reserve(2 * packed.len());
+    let decoded = simd::decode(packed, out);
+    for &byte in &packed[decoded..] {
+        out.extend_from_slice(&DECODE[byte as usize]);
+    }
+    out.truncate(start + len);
+}
+
+/// Decodes `packed` into `res`, like `bam_tools` `decode_seq()`. Without
+/// l_seq zero bottom 4 bits of the last byte are taken as padding.
+pub fn decode_seq(packed: &[u8], res: &mut String) {
+    let padded = matches!(packed.last(), Some(&byte) if byte & 0xf == 0);
+    let mut bases = std::mem::take(res).into_bytes();
+    bases.clear();
+    decode_bases(packed, 2 * packed.len() - padded as usize, &mut bases);
+    // Bases are ASCII.
+    *res = String::from_utf8(bases).unwrap();
+}
+
+/// Packs `bases` into the first half of their length, rounded up, of
+/// `packed`. Bottom 4 bits of the last byte are zero for odd lengths, as
+/// htslib writes them.
+pub fn encode_bases(bases: &[u8], packed: &mut [u8]) {
+    let packed = &mut packed[..bases.len().div_ceil(2)];
+    let pairs = bases.chunks_exact(2);
+    let last = pairs.remainder().first();
+    for (byte, pair) in packed.iter_mut().zip(pairs) {
+        *byte = (ENCODE[pair[0] as usize] << 4) | ENCODE[pair[1] as usize];
+    }
+    if let Some(&base) = last {
+        packed[packed.len() - 1] = ENCODE[base as usize] << 4;
+    }
+}
+
+#[cfg(all(feature = "simd", target_arch = "x86_64"))]
+mod simd {
+    use super::BASES;
+    use std::arch::x86_64::*;
+
+    /// Decodes whole 16 byte chunks of `packed` into `out`, returns the
+    /// number of bytes decoded.
+    pub fn decode(packed: &[u8], out: &mut Vec<u8>) -> usize {
+        if !is_x86_feature_detected!("ssse3") {
+            return 0;
+        }
+        // SSSE3 is available.
+        unsafe { decode_ssse3(packed, out) }
+    }
+
+    #[target_feature(enable = "ssse3")]
+    unsafe fn decode_ssse3(packed: &[u8], out: &mut Vec<u8>) -> usize {
+        let chunks = packed.len() / 16;
+        out.reserve(32 * chunks);
+        let table = _mm_loadu_si128(BASES.as_ptr() as *const __m128i);
+        let mask = _mm_set1_epi8(0xf);
+        let mut dst = out.as_mut_ptr().add(out.len());
+        for chunk in packed.chunks_exact(16) {
+            let bytes = _mm_loadu_si128(chunk.as_ptr() as *const __m128i);
+            let first = _mm_shuffle_epi8(table, _mm_and_si128(_mm_srli_epi16(bytes, 4), mask));
+            let second = _mm_shuffle_epi8(table, _mm_and_si128(bytes, mask));
+            _mm_storeu_si128(dst as *mut __m128i, _mm_unpacklo_epi8(first, second));
+            _mm_storeu_si128(dst.add(16) as *mut __m128i, _mm_unpackhi_epi8(first, second));
+            dst = dst.add(32);
+        }
+        out.set_len(out.len() + 32 * chunks);
+        16 * chunks
+    }
+}
+
+#[cfg(all(feature = "simd", target_arch = "aarch64"))]
+mod simd {
+    use super::BASES;
+    use std::arch::aarch64::*;
+
+    /// Decodes whole 16 byte chunks of `packed` into `out`, returns the
+    /// number of bytes decoded.
+    pub fn decode(packed: &[u8], out: &mut Vec<u8>) -> usize {
+        let chunks = packed.len() / 16;
+        out.reserve(32 * chunks);
+        // NEON is always available on aarch64, `out` has room for the chunks.
+        unsafe {
+            let table = vld1q_u8(BASES.as_ptr());
+            let mask = vdupq_n_u8(0xf);
+            let mut dst = out.as_mut_ptr().add(out.len());
+            for chunk in packed.chunks_exact(16) {
+                let bytes = vld1q_u8(chunk.as_ptr());
+                let first = vqtbl1q_u8(table, vshrq_n_u8::<4>(bytes));
+                let second = vqtbl1q_u8(table, vandq_u8(bytes, mask));
+                vst1q_u8(dst, vzip1q_u8(first, second));
+                vst1q_u8(dst.add(16), vzip2q_u8(first, second));
+                dst = dst.add(32);
+            }
+            out.set_len(out.len() + 32 * chunks);
+        }
+        16 * chunks
+    }
+}
+
+#[cfg(not(all(feature = "simd", any(target_arch = "x86_64", target_arch = "aarch64"))))]
+mod simd {
+    pub fn decode(_packed: &[u8], _out: &mut Vec<u8>) -> usize {
+        0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bam_tools::record::bamrawrecord::put_sequence;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    #[test]
+    fn test_decode_random_bytes() {
+        let mut rng = StdRng::seed_from_u64(338);
+        for len in 0..300usize {
+            let packed: Vec<u8> = (0..len).map(|_| rng.gen()).collect();
+            let mut expected = String::new();
+            bam_tools::record::bamrawrecord::decode_seq(&packed, &mut expected);
+            let mut decoded = String::from("stale");
+            decode_seq(&packed, &mut decoded);
+            assert_eq!(decoded, expected);
+
+            // Odd and even lengths, appended to what `out` holds.
+            for bases in [2 * len, (2 * len).saturating_sub(1)] {
+                let mut out = b"prefix".to_vec();
+                decode_bases(&packed, bases, &mut out);
+                let naive = (0..bases).map(|i| BASES[(packed[i / 2] >> (4 - 4 * (i % 2)) & 0xf) as usize]);
+                assert!(out[6..].iter().copied().eq(naive), "{} bases", bases);
+            }
+        }
+    }
+
+    #[test]
+    fn test_encode_round_trip() {
+        let mut rng = StdRng::seed_from_u64(338);
+        for len in 0..300usize {
+            let bases: String = (0..len).map(|_| BASES[rng.gen_range(0..16)] as char).collect();
+            let mut expected = vec![0; len.div_ceil(2)];
+            put_sequence(&mut expected, len, &bases).unwrap();
+            if len % 2 == 1 {
+                expected[len / 2] &= 0xf0;
+            }
+            let mut packed = vec![0xff; len.div_ceil(2) + 1];
+            encode_bases(bases.as_bytes(), &mut packed);
+            assert_eq!(packed[..len.div_ceil(2)], expected[..]);
+            // Bytes past the sequence are left as they are.
+            assert_eq!(packed.last(), Some(&0xff));
+
+            let mut decoded = Vec::new();
+            decode_bases(&packed, len, &mut decoded);
+            assert_eq!(decoded, bases.as_bytes());
+        }
+    }
+
+    #[test]
+    fn test_encode_other_characters() {
+        let mut packed = [0; 3];
+        encode_bases(b"acgt.", &mut packed);
+        let mut decoded = Vec::new();
+        decode_bases(&packed, 5, &mut decoded);
+        assert_eq!(decoded, b"ACGTN");
+    }
+}
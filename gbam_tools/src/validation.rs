@@ -0,0 +1,289 @@
+use bam_tools::record::bamrawrecord::BAMRawRecord;
+use bam_tools::record::fields::Fields;
+use byteorder::{LittleEndian, ReadBytesExt};
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// Size of fixed part of BAM record (without block_size).
+const FIXED_PART_SIZE: usize = 32;
+const BAM_FUNMAP: u16 = 4;
+
+/// How the writer treats records violating BAM invariants.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum ValidationMode {
+    /// Records are written as is.
+    #[default]
+    Off,
+    /// Invalid records are written, but counted in
+    /// `Writer::validation_report()`, and logged by
+    /// `Writer::finish_with_summary()` with the `tracing` feature.
+    Warn,
+    /// First invalid record makes `push_record()` return an error.
+    Strict,
+}
+
+/// BAM record invariant which doesn't hold.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Violation {
+    /// Record is shorter than fields it declares.
+    Truncated { expected: usize, actual: usize },
+    /// l_read_name is zero or read name isn't NUL terminated.
+    ReadName,
+    /// l_seq doesn't fit into int32.
+    NegativeSeqLen(i32),
+    RefIdOutOfRange { ref_id: i32, n_refs: usize },
+    NextRefIdOutOfRange { ref_id: i32, n_refs: usize },
+    NegativePos(i32),
+    /// Query length consumed by CIGAR differs from l_seq.
+    CigarSeqLenMismatch { cigar_len: u64, seq_len: u32 },
+    /// Position is set while reference is not.
+    PosWithoutRef(i32),
+    /// Read isn't flagged unmapped, but has no reference or position.
+    MappedWithoutPos,
+}
+
+impl Violation {
+    /// Short name of violated invariant, used for counting.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Violation::Truncated { .. } => "truncated record",
+            Violation::ReadName => "malformed read name",
+            Violation::NegativeSeqLen(_) => "negative l_seq",
+            Violation::RefIdOutOfRange { .. } => "refID out of range",
+            Violation::NextRefIdOutOfRange { .. } => "next refID out of range",
+            Violation::NegativePos(_) => "negative pos",
+            Violation::CigarSeqLenMismatch { .. } => "cigar/seq length mismatch",
+            Violation::PosWithoutRef(_) => "pos without refID",
+            Violation::MappedWithoutPos => "mapped read without position",
+        }
+    }
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Violation::Truncated { expected, actual } => write!(
+                f,
+                "{}: fields require {} bytes, record has {}",
+                self.name(),
+                expected,
+                actual
+            ),
+            Violation::NegativeSeqLen(l_seq) => write!(f, "{}: {}", self.name(), l_seq),
+            Violation::RefIdOutOfRange { ref_id, n_refs }
+            | Violation::NextRefIdOutOfRange { ref_id, n_refs } => write!(
+                f,
+                "{}: {} with {} reference sequences",
+                self.name(),
+                ref_id,
+                n_refs
+            ),
+            Violation::NegativePos(pos) | Violation::PosWithoutRef(pos) => {
+                write!(f, "{}: {}", self.name(), pos)
+            }
+            Violation::CigarSeqLenMismatch {
+                cigar_len,
+                seq_len,
+            } => write!(
+                f,
+                "{}: cigar consumes {} query bases, l_seq is {}",
+                self.name(),
+                cigar_len,
+                seq_len
+            ),
+            Violation::ReadName | Violation::MappedWithoutPos => write!(f, "{}", self.name()),
+        }
+    }
+}
+
+fn read_i32(rec: &BAMRawRecord, field: &Fields) -> i32 {
+    rec.get_bytes(field).read_i32::<LittleEndian>().unwrap()
+}
+
+/// Checks record against BAM invariants. Length consistency is checked first,
+/// so other checks never read past the end of the record.
+pub fn validate_record(rec: &BAMRawRecord, n_refs: usize) -> Result<(), Violation> {
+    if rec.len() < FIXED_PART_SIZE {
+        return Err(Violation::Truncated {
+            expected: FIXED_PART_SIZE,
+            actual: rec.len(),
+        });
+    }
+
+    let l_seq = rec.get_len_val(&Fields::SequenceLength);
+    if l_seq > i32::MAX as usize {
+        return Err(Violation::NegativeSeqLen(l_seq as u32 as i32));
+    }
+    let l_read_name = rec.get_len_val(&Fields::LName);
+    let n_cigar = rec.get_len_val(&Fields::NCigar);
+    let expected = FIXED_PART_SIZE + l_read_name + 4 * n_cigar + l_seq.div_ceil(2) + l_seq;
+    if rec.len() < expected {
+        return Err(Violation::Truncated {
+            expected,
+            actual: rec.len(),
+        });
+    }
+
+    let name = &rec[FIXED_PART_SIZE..FIXED_PART_SIZE + l_read_name];
+    if name.last() != Some(&0) {
+        return Err(Violation::ReadName);
+    }
+
+    let ref_id = read_i32(rec, &Fields::RefID);
+    if ref_id < -1 || ref_id >= n_refs as i32 {
+        return Err(Violation::RefIdOutOfRange { ref_id, n_refs });
+    }
+    let next_ref_id = read_i32(rec, &Fields::NextRefID);
+    if next_ref_id < -1 || next_ref_id >= n_refs as i32 {
+        return Err(Violation::NextRefIdOutOfRange {
+            ref_id: next_ref_id,
+            n_refs,
+        });
+    }
+
+    let pos = read_i32(rec, &Fields::Pos);
+    if pos < -1 {
+        return Err(Violation::NegativePos(pos));
+    }
+    let next_pos = read_i32(rec, &Fields::NextPos);
+    if next_pos < -1 {
+        return Err(Violation::NegativePos(next_pos));
+    }
+
+    let flags = rec.get_bytes(&Fields::Flags).read_u16::<LittleEndian>().unwrap();
+    if ref_id == -1 && pos != -1 {
+        return Err(Violation::PosWithoutRef(pos));
+    }
+    if flags & BAM_FUNMAP == 0 && (ref_id == -1 || pos == -1) {
+        return Err(Violation::MappedWithoutPos);
+    }
+
+    // Cigar is read directly: get_bytes may substitute it with CG tag.
+    let cigar_start = FIXED_PART_SIZE + l_read_name;
+    let mut cigar = &rec[cigar_start..cigar_start + 4 * n_cigar];
+    let mut cigar_len = 0u64;
+    while !cigar.is_empty() {
+        let op = cigar.read_u32::<LittleEndian>().unwrap();
+        // M, I, S, =, X consume query.
+        if matches!(op & 0xf, 0 | 1 | 4 | 7 | 8) {
+            cigar_len += (op >> 4) as u64;
+        }
+    }
+    // Sequence may be omitted ('*'), then there is nothing to compare with.
+    if n_cigar > 0 && l_seq > 0 && cigar_len != l_seq as u64 {
+        return Err(Violation::CigarSeqLenMismatch {
+            cigar_len,
+            seq_len: l_seq as u32,
+        });
+    }
+
+    Ok(())
+}
+
+/// Counts violations found in `ValidationMode::Warn`.
+#[derive(Default, Debug)]
+pub struct ValidationReport {
+    pub invalid_records: u64,
+    pub violations: BTreeMap<&'static str, u64>,
+}
+
+impl ValidationReport {
+    pub fn add(&mut self, violation: &Violation) {
+        self.invalid_records += 1;
+        *self.violations.entry(violation.name()).or_default() += 1;
+    }
+}
+
+impl fmt::Display for ValidationReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} invalid records written", self.invalid_records)?;
+        for (name, count) in &self.violations {
+            write!(f, "\n  {}: {}", name, count)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{new_test_writer, TestRecord};
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_validate_record() {
+        assert_eq!(validate_record(&TestRecord::default().to_raw(), 3), Ok(()));
+
+        let rec = TestRecord::new(5, 100, "r");
+        assert_eq!(
+            validate_record(&rec.to_raw(), 3),
+            Err(Violation::RefIdOutOfRange {
+                ref_id: 5,
+                n_refs: 3
+            })
+        );
+
+        let rec = TestRecord {
+            cigar: vec![3 << 4],
+            ..Default::default()
+        };
+        assert_eq!(
+            validate_record(&rec.to_raw(), 3),
+            Err(Violation::CigarSeqLenMismatch {
+                cigar_len: 3,
+                seq_len: 4
+            })
+        );
+
+        let mut rec = TestRecord::new(-1, 100, "r");
+        rec.flag = BAM_FUNMAP;
+        rec.cigar.clear();
+        assert_eq!(
+            validate_record(&rec.to_raw(), 3),
+            Err(Violation::PosWithoutRef(100))
+        );
+
+        // l_seq of -1 claims far more bytes than record has.
+        let mut bytes = TestRecord::default().to_bytes();
+        bytes[16..20].copy_from_slice(&(-1i32).to_le_bytes());
+        assert_eq!(
+            validate_record(&BAMRawRecord::from(bytes), 3),
+            Err(Violation::NegativeSeqLen(-1))
+        );
+
+        let mut bytes = TestRecord::default().to_bytes();
+        bytes.truncate(40);
+        assert!(matches!(
+            validate_record(&BAMRawRecord::from(bytes), 3),
+            Err(Violation::Truncated { actual: 40, .. })
+        ));
+    }
+
+    #[test]
+    fn test_writer_validation_modes() {
+        let tmp_dir = TempDir::new("gbam_validation").unwrap();
+        let bad = TestRecord::new(7, 10, "bad").to_raw();
+        let good = TestRecord::default().to_raw();
+
+        let mut writer = new_test_writer(&tmp_dir.path().join("strict.gbam"), "");
+        writer.set_validation_mode(ValidationMode::Strict);
+        writer.push_record(&good, false).unwrap();
+        writer.push_record(&good, false).unwrap();
+        let err = writer.push_record(&bad, false).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert_eq!(
+            err.to_string(),
+            "Record 2 is invalid: refID out of range: 7 with 3 reference sequences"
+        );
+
+        let mut writer = new_test_writer(&tmp_dir.path().join("warn.gbam"), "");
+        writer.set_validation_mode(ValidationMode::Warn);
+        writer.push_record(&good, false).unwrap();
+        writer.push_record(&bad, false).unwrap();
+        writer.push_record(&bad, false).unwrap();
+        writer.finish_with_summary(false).unwrap();
+        let report = writer.validation_report();
+        assert_eq!(report.invalid_records, 2);
+        assert_eq!(report.violations["refID out of range"], 2);
+    }
+}
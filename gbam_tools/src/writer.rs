@@ -1,22 +1,56 @@
-use super::meta::{BlockMeta, Codecs, FileInfo, FileMeta, Stat, FILE_INFO_SIZE};
-use crate::compressor::{CompressTask, Compressor, OrderingKey};
-use crate::{SIZE_LIMIT, U32_SIZE};
+use super::meta::{
+    BlockMeta, Codecs, FileInfo, FileMeta, SeqEncoding, SortOrder, Stat, FILE_INFO_SIZE, META_PREFIX_SIZE,
+};
+use std::fs::File;
+use std::io::{BufWriter, Cursor};
+use crate::analytics::RecordObserver;
+use crate::bloom::BloomFilter;
+use crate::buffer_pool::{BufferPool, BufferPoolStats};
+use crate::column_transform::{transform_block, ColumnTransform};
+use crate::encryption::{BlockCipher, EncryptionKey, FieldEncryption};
+use crate::error::{gbam_error, with_path, CodecError, GbamError};
+use crate::layout::{encode_index_entry, MetaPrefix, INDEX_ENTRY_SIZE};
+use crate::linear_index::LinearIndexBuilder;
+use crate::seq_packing::pack_block;
+use crate::stats::{default_collectors, stat_value, StatsCollector};
+use crate::compressor::{CompressTask, Compressor, CompressorPool, OrderingKey};
+use crate::reader::region::UNPLACED_REF_ID;
+use crate::reader::reader::MAX_META_SIZE;
+use crate::record_builder::BuiltRecord;
+use crate::progress::{cancelled_error, CancellationToken, Progress, ProgressEvent};
+use crate::provenance::{new_digest, Provenance, PROVENANCE_EXTENSION};
+use crate::trace::{trace_record, trace_span, Timer};
+use crate::level_tuning::{level_range, AdaptiveLevels, LEVELS_EXTENSION};
+use crate::ref_compression::{RefEncoder, RefSeqMap, Reference};
+use crate::ref_map::RefMap;
+use crate::ref_subset::{subset_sam_header, RefSubset, RefSubsetReport};
+use crate::reorder::{Released, ReorderStats, ReorderWindow};
+use crate::source_index::{SourceIndexMeta, SourceIndexWriter};
+use crate::validation::{validate_record, ValidationMode, ValidationReport};
+use crate::{
+    GBAM_VERSION, MAX_BLOCK_SIZE_LIMIT, MAX_RECORD_SIZE, MIN_BLOCK_SIZE_LIMIT, SIZE_LIMIT, U32_SIZE,
+    VAR_SIZE_LIMIT,
+};
 use bam_tools::record::bamrawrecord::BAMRawRecord;
 use bam_tools::record::fields::{
-    field_type, is_data_field, var_size_field_to_index, FieldType, Fields, FIELDS_NUM,
+    field_item_size, field_type, is_data_field, var_size_field_to_index, FieldType, Fields, FIELDS_NUM,
 };
-use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use byteorder::{LittleEndian, ReadBytesExt};
 use crc32fast::Hasher;
+use std::hash::Hasher as _;
+use twox_hash::XxHash64;
 use std::borrow::Cow;
-use std::convert::TryFrom;
 use std::convert::TryInto;
 use std::io::{Seek, SeekFrom, Write};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use once_cell::sync::Lazy;
 use std::fs;
 use std::path::Path;
+use std::sync::Arc;
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use std::str::FromStr;
+use std::time::{Duration, Instant};
 
 pub(crate) struct BlockInfo {
     pub numitems: u32,
@@ -24,7 +58,13 @@ pub(crate) struct BlockInfo {
     pub field: Fields,
     // Interpretation is up to the reader.
     pub stats: Option<Stat>,
+    pub bloom: Option<BloomFilter>,
+    pub transform: Option<ColumnTransform>,
+    pub extra_stats: BTreeMap<String, u64>,
     pub codec: Codecs,
+    /// Blocks of grouped fields combined into this one, in order, see
+    /// `Writer::set_field_group()`.
+    pub stripes: Vec<BlockInfo>,
 }
 
 impl Default for BlockInfo {
@@ -34,7 +74,11 @@ impl Default for BlockInfo {
             uncompr_size: 0,
             field: Fields::RefID,
             stats: None,
+            bloom: None,
+            transform: None,
+            extra_stats: BTreeMap::new(),
             codec: Codecs::Brotli,
+            stripes: Vec::new(),
         }
     }
 }
@@ -62,6 +106,147 @@ pub static FIELD_CODEC_MAP: Lazy<HashMap<Fields, Codecs>> = Lazy::new(|| {
     map
 });
 
+/// Output which can be durably synced to storage.
+/// `Writer::finish_with_summary()` syncs meta before overwriting file info, so
+/// a crash can't leave file info pointing to unwritten meta.
+pub trait SyncOutput {
+    fn sync_output(&mut self) -> std::io::Result<()>;
+}
+
+impl SyncOutput for File {
+    fn sync_output(&mut self) -> std::io::Result<()> {
+        self.sync_data()
+    }
+}
+
+impl<W: Write + SyncOutput> SyncOutput for BufWriter<W> {
+    fn sync_output(&mut self) -> std::io::Result<()> {
+        self.flush()?;
+        self.get_mut().sync_output()
+    }
+}
+
+impl<T> SyncOutput for Cursor<T> {
+    fn sync_output(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Output buffer size of `Writer::create()`. Blocks are written whole, the
+/// buffer mostly batches writes of meta and small blocks.
+pub const DEFAULT_BUFFER_SIZE: usize = 1 << 20;
+
+/// Settings of `Writer::create()`, arguments of `Writer::new()` plus output
+/// options. Reference sequences and SAM header have to be set together.
+#[derive(Clone, Debug)]
+pub struct WriterSettings {
+    pub codecs: Vec<Codecs>,
+    pub thread_num: usize,
+    pub collect_stats_for: Vec<Fields>,
+    pub ref_seqs: Vec<(String, u32)>,
+    /// Header bytes as in BAM, see `bam_tools::Reader::read_header()`.
+    pub sam_header: Vec<u8>,
+    pub full_command: String,
+    pub is_sorted: bool,
+    pub codec_map_required: bool,
+    /// Capacity of output buffer.
+    pub buffer_size: usize,
+    /// Create missing parent directories of output.
+    pub create_dirs: bool,
+}
+
+impl Default for WriterSettings {
+    /// Lz4 for every field, 4 threads, RefID and Pos stats, no references.
+    fn default() -> Self {
+        Self {
+            codecs: vec![Codecs::Lz4; FIELDS_NUM],
+            thread_num: 4,
+            collect_stats_for: vec![Fields::RefID, Fields::Pos],
+            ref_seqs: Vec::new(),
+            // Empty text and no references.
+            sam_header: vec![0; 2 * U32_SIZE],
+            full_command: String::new(),
+            is_sorted: false,
+            codec_map_required: false,
+            buffer_size: DEFAULT_BUFFER_SIZE,
+            create_dirs: false,
+        }
+    }
+}
+
+/// What `Writer::finish_with_summary()` wrote. Maps are keyed by field names
+/// and hold fields with blocks. The file takes `FILE_INFO_SIZE`, `meta_bytes`,
+/// `source_index_bytes` and compressed bytes of fields.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct WriteSummary {
+    pub total_bytes: u64,
+    pub records_written: u64,
+    /// Blocks of grouped fields are counted for every member.
+    pub blocks_per_field: BTreeMap<String, u64>,
+    /// Blocks of grouped fields are counted once, for the member first in
+    /// order of `Fields`.
+    pub compressed_bytes_per_field: BTreeMap<String, u64>,
+    /// Like `compressed_bytes_per_field`.
+    pub uncompressed_bytes_per_field: BTreeMap<String, u64>,
+    /// Meta with its prefix, or the space reserved for it, see
+    /// `Writer::reserve_meta_bytes()`.
+    pub meta_bytes: u64,
+    /// Blocks of the source index, see `Writer::set_source_index()`.
+    pub source_index_bytes: u64,
+    /// Since the writer was created.
+    pub wall_time: Duration,
+    pub codec_settings: BTreeMap<String, CodecSetting>,
+}
+
+/// Compression of a field, see `WriteSummary`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CodecSetting {
+    pub codec: Codecs,
+    /// Pinned or picked by adaptive levels, None for the default level.
+    pub level: Option<i32>,
+}
+
+/// Fields persisted by a writer, the write side of `ParsingTemplate`. Index
+/// fields follow their variable sized fields. RefID is always written, since
+/// readers count records by it. Skipped fields get a single empty block, so
+/// files written with a template must be read with a subset of its fields.
+#[derive(Clone, Debug)]
+pub struct WriteTemplate {
+    // Indexed by field.
+    inner: Vec<bool>,
+}
+
+impl WriteTemplate {
+    /// Template with every field.
+    pub fn all() -> Self {
+        Self {
+            inner: vec![true; FIELDS_NUM],
+        }
+    }
+
+    /// Template with `fields` and RefID.
+    pub fn new_with(fields: &[Fields]) -> Self {
+        let mut inner = vec![false; FIELDS_NUM];
+        for field in fields.iter().chain(&[Fields::RefID]) {
+            inner[*field as usize] = true;
+            if matches!(field_type(field), FieldType::VariableSized) {
+                inner[var_size_field_to_index(field) as usize] = true;
+            }
+        }
+        Self { inner }
+    }
+
+    pub fn contains(&self, field: &Fields) -> bool {
+        self.inner[*field as usize]
+    }
+}
+
+impl Default for WriteTemplate {
+    fn default() -> Self {
+        Self::all()
+    }
+}
+
 /// The data is held in blocks.
 ///
 /// Fixed sized fields are written as fixed size blocks into file. All blocks
@@ -73,22 +258,93 @@ pub static FIELD_CODEC_MAP: Lazy<HashMap<Fields, Codecs>> = Lazy::new(|| {
 /// out to file.
 pub struct Writer<WS>
 where
-    WS: Write + Seek,
+    WS: Write + Seek + SyncOutput,
 {
     file_info: FileInfo,
     file_meta: FileMeta,
-    columns: Vec<Box<dyn Column>>,
+    columns: Vec<ColumnKind>,
     compressor: Compressor,
     inner: WS,
+    validation_mode: ValidationMode,
+    validation_report: ValidationReport,
+    records_pushed: u64,
+    // Records given to push functions, including ones dropped by reference
+    // subsetting. Errors refer to input records by it.
+    records_ingested: u64,
+    // Indexed by field, None for plain fields.
+    ciphers: Vec<Option<BlockCipher>>,
+    collectors: Vec<(Fields, Box<dyn RecordObserver>)>,
+    ref_subset: Option<RefSubset>,
+    ref_map: RefMap,
+    linear_index: Option<LinearIndexBuilder>,
+    reorder: Option<ReorderWindow>,
+    // Set with `set_source_index()`.
+    source_index: Option<SourceIndexWriter>,
+    // Requested with `set_derived_seq_index()`, applies if the write template allows.
+    derived_seq_index: bool,
+    deterministic: bool,
+    write_template: WriteTemplate,
+    // Tail of a record split between calls of `Write::write()`.
+    partial_record: Vec<u8>,
+    progress: Progress,
+    cancel: Option<CancellationToken>,
+    // Fields with blocks that failed to be written, the file can't be finished.
+    failed_fields: Vec<Fields>,
+    // Requested with `set_field_group()`, members apply if the write template allows.
+    field_groups: Vec<FieldGroup>,
+    // Running digest of bytes written after file info, see `provenance`.
+    digest: Option<XxHash64>,
+    // Set if RefID stats are collected, holding the start of the current
+    // run of unplaced records, see `FileMeta::get_unmapped_tail_start()`.
+    unmapped_tail: Option<Option<u64>>,
+    // Set with `set_memory_budget()`.
+    memory_budget: Option<usize>,
+    // See `WriteSummary::wall_time`.
+    created: Instant,
+    // Called with `memory_usage()` after the budget is enforced.
+    #[cfg(test)]
+    memory_probe: Option<Box<dyn FnMut(usize)>>,
+}
+
+impl Writer<BufWriter<File>> {
+    /// Creates file at `path`, truncating existing one. Errors carry the
+    /// path, see `GbamError::AtPath`, as does `CodecError::Unavailable`
+    /// if a codec is compiled out.
+    pub fn create<P: AsRef<Path>>(path: P, settings: WriterSettings) -> std::io::Result<Self> {
+        let path = path.as_ref();
+        let create = || {
+            if settings.create_dirs {
+                if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+                    fs::create_dir_all(dir)?;
+                }
+            }
+            File::create(path)
+        };
+        let file = create().map_err(|err| with_path(err, path))?;
+        Self::new(
+            BufWriter::with_capacity(settings.buffer_size, file),
+            settings.codecs,
+            settings.thread_num,
+            settings.collect_stats_for,
+            settings.ref_seqs,
+            settings.sam_header,
+            settings.full_command,
+            settings.is_sorted,
+            settings.codec_map_required,
+        )
+        .map_err(|err| with_path(err, path).into())
+    }
 }
 
 impl<WS> Writer<WS>
 where
-    WS: Write + Seek,
+    WS: Write + Seek + SyncOutput,
 {
+    /// Fails with `CodecError::Unavailable` if a codec is compiled out,
+    /// see `Codecs::is_available()`.
     #[allow(clippy::too_many_arguments)]
     pub fn new(
-        mut inner: WS,
+        inner: WS,
         codecs: Vec<Codecs>,
         thread_num: usize,
         collect_stats_for: Vec<Fields>,
@@ -97,11 +353,51 @@ where
         full_command: String,
         is_sorted: bool,
         codec_map_required: bool
-    ) -> Self {
-        inner
-            .seek(SeekFrom::Start((FILE_INFO_SIZE) as u64))
-            .unwrap();
+    ) -> std::io::Result<Self> {
+        Self::with_compressor_pool(
+            inner,
+            codecs,
+            CompressorPool::new(thread_num),
+            collect_stats_for,
+            ref_seqs,
+            sam_header,
+            full_command,
+            is_sorted,
+            codec_map_required,
+        )
+    }
 
+    /// Like `new()`, but compresses blocks on threads of `compr_pool`, which
+    /// outlive the writer. Writers sharing the pool may run concurrently.
+    #[allow(clippy::too_many_arguments)]
+    pub fn with_compressor_pool(
+        mut inner: WS,
+        codecs: Vec<Codecs>,
+        compr_pool: CompressorPool,
+        collect_stats_for: Vec<Fields>,
+        ref_seqs: Vec<(String, u32)>,
+        sam_header: Vec<u8>,
+        full_command: String,
+        is_sorted: bool,
+        codec_map_required: bool
+    ) -> std::io::Result<Self> {
+        // TODO: Codecs (currently only one is supported).
+        let mut file_meta = FileMeta::new(codecs[0], ref_seqs, sam_header, codec_map_required);
+        if is_sorted {
+            file_meta.set_sort_order(SortOrder::Coordinate);
+        }
+        // Nothing is written with a codec which is compiled out.
+        for field in Fields::iterator() {
+            file_meta.get_field_codec(field).check_available(*field)?;
+            file_meta.set_block_size_limit(field, default_block_size_limit(field));
+        }
+        // Placeholder with valid magic, but without meta pointer. It stays if
+        // finish() is never completed, so readers can suggest recovery.
+        let file_info = FileInfo::new(GBAM_VERSION, 0, 0, full_command, is_sorted);
+        inner.seek(SeekFrom::Start(0))?;
+        inner.write_all(&file_info.to_padded_bytes()?)?;
+
+        let compressor = Compressor::new(compr_pool);
         let mut columns = Vec::new();
 
         let mut count = 0;
@@ -112,12 +408,12 @@ where
                 .and(Some(Stat::default()));
             let col = match field_type(field) {
                 FieldType::FixedSized => {
-                    Box::new(FixedColumn::new(*field, stat_collector)) as Box<dyn Column>
+                    ColumnKind::Fixed(FixedColumn::new(*field, stat_collector, compressor.buffer_pool()))
                 }
                 FieldType::VariableSized => {
                     // Index column +1.
                     count += 1;
-                    Box::new(VariableColumn::new(*field, stat_collector)) as Box<dyn Column>
+                    ColumnKind::Variable(VariableColumn::new(*field, stat_collector, compressor.buffer_pool()))
                 }
             };
             columns.push(col);
@@ -125,14 +421,44 @@ where
         }
         debug_assert!(count == FIELDS_NUM);
 
-        Self {
-            // TODO: Codecs (currently only one is supported).
-            file_meta: FileMeta::new(codecs[0], ref_seqs, sam_header,  codec_map_required),
+        let unmapped_tail = if collect_stats_for.contains(&Fields::RefID) {
+            Some(None)
+        } else {
+            None
+        };
+
+        Ok(Self {
+            file_meta,
             inner,
-            compressor: Compressor::new(thread_num),
+            compressor,
             columns,
-            file_info: FileInfo::new([1, 0], 0, 0, full_command, is_sorted),
-        }
+            file_info,
+            validation_mode: ValidationMode::Off,
+            validation_report: ValidationReport::default(),
+            records_pushed: 0,
+            records_ingested: 0,
+            ciphers: vec![None; FIELDS_NUM],
+            collectors: Vec::new(),
+            ref_subset: None,
+            ref_map: RefMap::default(),
+            linear_index: None,
+            reorder: None,
+            source_index: None,
+            derived_seq_index: false,
+            deterministic: false,
+            write_template: WriteTemplate::all(),
+            partial_record: Vec::new(),
+            progress: Progress::default(),
+            cancel: None,
+            failed_fields: Vec::new(),
+            field_groups: Vec::new(),
+            digest: None,
+            unmapped_tail,
+            memory_budget: None,
+            created: Instant::now(),
+            #[cfg(test)]
+            memory_probe: None,
+        })
     }
 
     pub fn new_no_stats(
@@ -143,7 +469,7 @@ where
         sam_header: Vec<u8>,
         full_command: String,
         is_sorted: bool,
-    ) -> Self {
+    ) -> std::io::Result<Self> {
         Self::new(
             inner,
             codecs,
@@ -157,251 +483,1683 @@ where
         )
     }
 
-    /// Push BAM record into this writer
-    pub fn push_record(&mut self, record: &BAMRawRecord, codec_map_required: bool) {
-        // Index fields are not written on their own. They hold index data for variable sized fields.
+    /// Like `new()`, with block stats of RefID and Pos collected, as region
+    /// queries need.
+    pub fn new_with_position_stats(
+        inner: WS,
+        codecs: Vec<Codecs>,
+        thread_num: usize,
+        ref_seqs: Vec<(String, u32)>,
+        sam_header: Vec<u8>,
+        full_command: String,
+        is_sorted: bool,
+    ) -> std::io::Result<Self> {
+        Self::new(
+            inner,
+            codecs,
+            thread_num,
+            vec![Fields::RefID, Fields::Pos],
+            ref_seqs,
+            sam_header,
+            full_command,
+            is_sorted,
+            false
+        )
+    }
+
+    /// Overrides sort order detected from the SAM header. Should be set when
+    /// the order of pushed records is known to differ from the header claim.
+    pub fn set_sort_order(&mut self, sort_order: SortOrder) {
+        self.file_meta.set_sort_order(sort_order);
+    }
+
+    /// Enables 2-bit packing of ACGT-only sequences in RawSequence column.
+    /// Must be set before pushing records. Shrinks low redundancy data, but on
+    /// high coverage blocks zstd and brotli may do better on plain encoding.
+    pub fn set_seq_packing(&mut self, enabled: bool) {
+        assert_eq!(self.records_pushed, 0, "Records were already pushed.");
+        let encoding = if enabled {
+            SeqEncoding::TwoBit
+        } else {
+            SeqEncoding::Nibble
+        };
+        self.file_meta.set_seq_encoding(encoding);
+        self.file_meta.set_reference_md5s(Vec::new());
         for col in self.columns.iter_mut() {
-            // Attempt to write data in this column. If the column is full it
-            // will return bytes for flushing. While loop is here because
-            // variable sized columns also have index columns (fixed size)
-            // inside and they might also come full and request flushing
-            // simultaneously with containing variable sized field column.
-            while let WriteStatus::Full(inner) = col.write_record_field(record) {
-                flush_field_buffer(
-                    &mut self.inner,
-                    &mut self.file_meta,
-                    &mut self.compressor,
-                    inner,
-                    codec_map_required
-                );
+            let (inner, _) = col.get_inners();
+            if inner.field == Fields::RawSequence {
+                inner.seq_lens = if enabled { Some(Vec::new()) } else { None };
+                inner.ref_encoder = None;
             }
         }
     }
 
-    /// Terminates the writer. Always call after writting all the data. Returns
-    /// total amount of bytes written.
-    pub fn finish(&mut self, codec_map_required: bool) -> std::io::Result<u64> {
-        // Flush leftovers
-        let mut columns: Vec<Box<dyn Column>> = self.columns.drain(..).collect();
-        for (inner, idx) in columns.iter_mut().map(|col| col.get_inners()) {
-            let writer = &mut self.inner;
-            let meta = &mut self.file_meta;
-            let compress = &mut self.compressor;
-
-            flush_field_buffer(writer, meta, compress, inner, codec_map_required);
-            if let Some(idx_inner) = idx {
-                flush_field_buffer(writer, meta, compress, idx_inner, codec_map_required);
+    /// Stores sequences of mapped reads as differences from `reference`,
+    /// see `ref_compression`. Reference sequences are matched by name and
+    /// length, MD5 of matched ones is kept in meta and readers need the same
+    /// sequences to decode the file. Replaces 2-bit packing. Must be set
+    /// before pushing records, after references are subset.
+    pub fn set_reference(&mut self, reference: Arc<Reference>) {
+        assert_eq!(self.records_pushed, 0, "Records were already pushed.");
+        let refs = RefSeqMap::new(reference, self.file_meta.get_ref_seqs());
+        self.file_meta.set_seq_encoding(SeqEncoding::Reference);
+        self.file_meta.set_reference_md5s(refs.md5s());
+        for col in self.columns.iter_mut() {
+            let (inner, _) = col.get_inners();
+            if inner.field == Fields::RawSequence {
+                inner.seq_lens = None;
+                inner.ref_encoder = Some(RefEncoder::new(refs.clone()));
             }
         }
+    }
 
-        for mut task in self.compressor.finish() {
-            if let OrderingKey::Key(key) = task.ordering_key {
-                write_data_and_update_meta(&mut self.inner, &mut self.file_meta, key, &mut task);
+    /// Flushes every column after exactly `rows` records, so block k of each
+    /// fixed sized column (including indices of variable sized ones) covers
+    /// records [k * rows, (k + 1) * rows). Variable sized columns flush after
+    /// `rows` records too, but may flush earlier on block size limit, so they
+    /// are not guaranteed to be aligned. Blocks of `rows` fixed sized items
+    /// must fit block size limits of their fields. Must be set before
+    /// pushing records.
+    pub fn set_rows_per_block(&mut self, rows: u32) {
+        assert_eq!(self.records_pushed, 0, "Records were already pushed.");
+        assert!(rows > 0, "Rows per block must be positive.");
+        self.file_meta.set_rows_per_block(Some(rows));
+        for col in self.columns.iter_mut() {
+            let (inner, idx) = col.get_inners();
+            for inner in std::iter::once(inner).chain(idx) {
+                inner.rows_per_block = Some(rows);
+                inner.check_rows_fit();
             }
         }
-
-        let meta_start_pos = self.inner.stream_position()?;
-        // Write meta
-        let main_meta = serde_json::to_string(&self.file_meta).unwrap();
-        let main_meta_bytes = main_meta.as_bytes();
-        let crc32 = calc_crc_for_meta_bytes(main_meta_bytes);
-        self.inner.write_all(main_meta_bytes)?;
-
-        let total_bytes_written = self.inner.stream_position()?;
-        // Revert back to the beginning of the file
-        self.inner.seek(SeekFrom::Start(0)).unwrap();
-        self.inner.write_all(&[0; FILE_INFO_SIZE]).unwrap();
-        self.inner.seek(SeekFrom::Start(0)).unwrap();
-        let file_info = &mut self.file_info;
-        file_info.seekpos = meta_start_pos;
-        file_info.crc32 = crc32;
-        let file_info_bytes = serde_json::to_string(&file_info).unwrap();
-        self.inner.write_all(file_info_bytes.as_bytes())?;
-        Ok(total_bytes_written)
     }
-}
-
-fn flush_field_buffer<WS: Write + Seek>(
-    writer: &mut WS,
-    file_meta: &mut FileMeta,
-    compressor: &mut Compressor,
-    inner: &mut Inner,
-    codec_map_required: bool
-) {
-    // Use an empty buffer to start the flushing process
-    // Don't worry, Vec::new() is temporary, it won't need to fully allocate the Vec as it replaces the reference with the &mut from the reused Buffer
-    let data = std::mem::take(&mut inner.buffer);
-
-    let field = &inner.field;
-    let codec = *file_meta.get_field_codec(field);
-
-    compressor.compress_block(
-        OrderingKey::Key(inner.block_num),
-        inner.generate_block_info(codec_map_required, codec),
-        data,
-    );
 
-    let mut completed_task = compressor.get_compr_block();
-
-    if let OrderingKey::Key(key) = completed_task.ordering_key {
-        write_data_and_update_meta(writer, file_meta, key, &mut completed_task);
+    /// Cuts blocks of `field` at `bytes` instead of the default, 8 MB for
+    /// fixed sized fields and 16 MB for variable sized ones. Indices of
+    /// variable sized fields are fields of their own. Smaller blocks let
+    /// readers fetch less around each record, larger ones compress better.
+    /// The limit is recorded in meta, see `FileMeta::get_block_size_limit()`.
+    /// Fails with `InvalidInput` unless it is in
+    /// `MIN_BLOCK_SIZE_LIMIT..=MAX_BLOCK_SIZE_LIMIT` and fits rows set with
+    /// `set_rows_per_block()`, the limit is kept then. Must be set before
+    /// pushing records.
+    pub fn set_block_size_limit(&mut self, field: Fields, bytes: usize) -> std::io::Result<()> {
+        assert_eq!(self.records_pushed, 0, "Records were already pushed.");
+        if !(MIN_BLOCK_SIZE_LIMIT..=MAX_BLOCK_SIZE_LIMIT).contains(&bytes) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "Block size limit {} of field {} is not in {}..={}",
+                    bytes, field, MIN_BLOCK_SIZE_LIMIT, MAX_BLOCK_SIZE_LIMIT
+                ),
+            ));
+        }
+        if let (Some(rows), Some(item_size)) = (self.file_meta.get_rows_per_block(), field_item_size(&field)) {
+            if rows as usize * item_size > bytes {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!(
+                        "Rows per block of field {} take more than block size limit of {} bytes",
+                        field, bytes
+                    ),
+                ));
+            }
+        }
+        self.file_meta.set_block_size_limit(&field, bytes);
+        let budget = self.memory_budget;
+        for col in self.columns.iter_mut() {
+            let (inner, idx) = col.get_inners();
+            for inner in std::iter::once(inner).chain(idx).filter(|inner| inner.field == field) {
+                inner.size_limit = bytes;
+                inner.reserve = budget.map_or(bytes, |budget| column_reserve(budget).min(bytes));
+            }
+        }
+        self.apply_field_groups();
+        Ok(())
     }
 
-    // We need to reuse the same buffer for the next task, as it is always the same size so we can avoid re-allocating the same buffer for each processed block
-    inner.buffer = completed_task.buf;
-
-    inner.reset_for_new_block();
-}
-
-fn write_data_and_update_meta<WS: Write + Seek>(
-    writer: &mut WS,
-    file_meta: &mut FileMeta,
-    key: u64,
-    task: &mut CompressTask,
-) {
-    let compressed_size = task.buf.len();
-    let meta = generate_meta(
-        writer,
-        &mut task.block_info,
-        compressed_size.try_into().unwrap(),
-    );
+    /// Stores bloom filter of read names in meta of each ReadName block, so
+    /// `Reader::find_by_name()` scans only blocks which may hold the name.
+    /// About 1% of blocks are scanned needlessly at 10 bits per key. Must be
+    /// set before pushing records.
+    pub fn set_name_bloom_filter(&mut self, bits_per_key: u32) {
+        assert_eq!(self.records_pushed, 0, "Records were already pushed.");
+        assert!(bits_per_key > 0, "Bits per key must be positive.");
+        for col in self.columns.iter_mut() {
+            let (inner, _) = col.get_inners();
+            if inner.field == Fields::ReadName {
+                inner.bloom_bits_per_key = Some(bits_per_key);
+            }
+        }
+    }
 
-    writer.write_all(&task.buf).unwrap();
+    /// Builds linear index of records, which lets `Reader::fetch()` start at
+    /// the region without searching. It is stored only if pushed records turn
+    /// out to be coordinate sorted. Must be set before pushing records.
+    pub fn set_linear_index(&mut self, enabled: bool) {
+        assert_eq!(self.records_pushed, 0, "Records were already pushed.");
+        self.linear_index = if enabled {
+            Some(LinearIndexBuilder::new())
+        } else {
+            None
+        };
+    }
 
-    let field_meta = file_meta.get_blocks(&task.block_info.field);
-    if field_meta.len() <= key as usize {
-        field_meta.resize(key as usize + 1, BlockMeta::default());
+    /// Routes pushed records through a window of `size` records kept sorted by
+    /// (RefID, Pos), which writes the smallest one when full, see `reorder`.
+    /// Input displaced by at most `size` records is written coordinate sorted,
+    /// and the file is marked so if no record overflowed the window. Others are
+    /// written out of order, or fail the push in `strict` mode. Held records
+    /// are written only by later pushes and `finish_with_summary()`, not by
+    /// `flush_all_columns()`. Must be set before pushing records.
+    pub fn set_reorder_window(&mut self, size: usize, strict: bool) {
+        assert_eq!(self.records_pushed, 0, "Records were already pushed.");
+        self.reorder = Some(ReorderWindow::new(size, strict));
     }
 
-    // Order as came in
-    field_meta[key as usize] = meta;
-}
+    /// Keeps the ordinal of each pushed record, counting ones dropped by
+    /// `set_ref_subset()` or failing validation, in a column of u64 values
+    /// outside of `Fields`, compressed with the codec of RefID. Ordinals
+    /// stay with their records through the reordering window, see
+    /// `GbamRecord::source_index()`. Records pushed with
+    /// `push_records_with_source_index()` keep the given values instead.
+    /// Must be set before pushing records.
+    pub fn set_source_index(&mut self, enabled: bool) {
+        assert_eq!(self.records_pushed, 0, "Records were already pushed.");
+        let codec = *self.file_meta.get_field_codec(&Fields::RefID);
+        self.source_index = if enabled { Some(SourceIndexWriter::new(codec)) } else { None };
+    }
 
-fn generate_meta<S: Seek>(
-    writer: &mut S,
-    block_info: &mut BlockInfo,
-    block_size: u32,
-) -> BlockMeta {
-    let seekpos = writer.stream_position().unwrap();
-    BlockMeta {
-        seekpos,
-        numitems: block_info.numitems,
-        block_size,
-        uncompressed_size: block_info.uncompr_size as u64,
-        stats: block_info.stats.take(),
+    /// Counts of reordering window so far, if it is set.
+    pub fn reorder_stats(&self) -> Option<ReorderStats> {
+        self.reorder.as_ref().map(ReorderWindow::stats)
     }
-}
 
-enum WriteStatus<'a> {
-    Written,
-    // Column or its index is at capacity. Flush it.
-    Full(&'a mut Inner),
-}
+    /// Run-length encodes Flags blocks and bit-packs Mapq blocks, if they
+    /// hold few distinct values, see `column_transform`. Transform is chosen
+    /// per block and undone on read. Must be set before pushing records.
+    pub fn set_column_transforms(&mut self, enabled: bool) {
+        assert_eq!(self.records_pushed, 0, "Records were already pushed.");
+        for col in self.columns.iter_mut() {
+            let (inner, _) = col.get_inners();
+            if matches!(inner.field, Fields::Flags | Fields::Mapq) {
+                inner.column_transforms = enabled;
+            }
+        }
+    }
 
-struct Inner {
-    stats_collector: Option<Stat>,
-    buffer: Vec<u8>,
-    offset: usize,
-    field: Fields,
-    rec_count: u32,
-    block_num: u64,
-}
+    /// Stores offsets in blocks of index fields as differences packed at the
+    /// width of the largest one, see `column_transform`. Blocks which don't
+    /// shrink are left as is. Readers of versions before 1.7 reject files
+    /// with them, failing to parse the transform in block meta, see
+    /// `GBAM_VERSION`. Must be set before pushing records.
+    pub fn set_offset_deltas(&mut self, enabled: bool) {
+        assert_eq!(self.records_pushed, 0, "Records were already pushed.");
+        for col in self.columns.iter_mut() {
+            if let (_, Some(index)) = col.get_inners() {
+                index.column_transforms = enabled;
+            }
+        }
+    }
 
-impl Inner {
-    pub fn new(field: Fields, stats_collector: Option<Stat>) -> Self {
-        Self {
-            stats_collector,
-            buffer: Vec::new(),
-            offset: 0,
-            field,
-            rec_count: 0,
-            block_num: 0,
+    /// Takes block buffers from `pool` and hands them back there, instead of
+    /// a pool of its own, so writers created one after another reuse
+    /// buffers. Must be set before pushing records.
+    pub fn set_buffer_pool(&mut self, pool: BufferPool) {
+        assert_eq!(self.records_pushed, 0, "Records were already pushed.");
+        for col in self.columns.iter_mut() {
+            let (inner, idx) = col.get_inners();
+            for inner in std::iter::once(inner).chain(idx) {
+                inner.buffers = pool.clone();
+            }
         }
+        self.compressor.set_buffer_pool(pool);
     }
-    pub fn write_data(&mut self, data: &[u8]) -> WriteStatus {
-        // At this point everything should be flushed.
-        debug_assert!(!self.flush_required(data));
 
-        let limit = std::cmp::max(data.len(), SIZE_LIMIT);
-        if self.buffer.len() < limit {
-            self.buffer.resize(limit, 0);
+    /// Keeps `memory_usage()` under `bytes` after each push, by flushing the
+    /// largest columns before their blocks are full and, if blocks queued
+    /// for compression take the rest, by waiting for them. Column buffers
+    /// reserve a share of the budget instead of a full block, and the buffer
+    /// pool retains only what the budget leaves. Files get more, smaller
+    /// blocks, and are no longer aligned by `set_rows_per_block()` once a
+    /// column is flushed early. A single batch of records may exceed the
+    /// budget while it's pushed. Must be set before pushing records.
+    pub fn set_memory_budget(&mut self, bytes: usize) {
+        assert_eq!(self.records_pushed, 0, "Records were already pushed.");
+        assert!(bytes > 0, "Memory budget must be positive.");
+        self.memory_budget = Some(bytes);
+        for col in self.columns.iter_mut() {
+            let (inner, idx) = col.get_inners();
+            for inner in std::iter::once(inner).chain(idx) {
+                inner.reserve = column_reserve(bytes).min(inner.size_limit);
+            }
         }
+    }
 
-        self.buffer[self.offset..self.offset + data.len()].clone_from_slice(data);
-        self.offset += data.len();
+    /// Bytes buffered in columns, see `bytes_buffered()`, and of blocks
+    /// queued for compression and not written yet.
+    pub fn memory_usage(&self) -> usize {
+        self.bytes_buffered() + self.compressor.queued_bytes()
+    }
 
-        self.rec_count += 1;
+    #[cfg(test)]
+    pub(crate) fn set_memory_probe(&mut self, probe: Box<dyn FnMut(usize)>) {
+        self.memory_probe = Some(probe);
+    }
 
-        WriteStatus::Written
+    /// Allocations of block buffers avoided by reusing them, see
+    /// `set_buffer_pool()`.
+    pub fn buffer_pool_stats(&self) -> BufferPoolStats {
+        self.compressor.buffer_pool().stats()
     }
 
-    pub fn flush_required(&self, data: &[u8]) -> bool {
-        // At least one record will be written in even if it exceeds SIZE_LIMIT.
-        self.offset > 0 && self.offset + data.len() > SIZE_LIMIT
+    /// Stores stat of `collector` in extra stats of each block of `field`,
+    /// see `Reader::block_stats()`. Several collectors may be added per
+    /// field, names have to differ. Must be set before pushing records.
+    pub fn add_stats_collector(&mut self, field: Fields, collector: Box<dyn StatsCollector>) {
+        assert_eq!(self.records_pushed, 0, "Records were already pushed.");
+        let inner = self
+            .columns
+            .iter_mut()
+            .map(|col| col.get_inners().0)
+            .find(|inner| inner.field == field)
+            .expect("Stats are collected for data fields only.");
+        assert!(
+            inner.collectors.iter().all(|added| added.name() != collector.name()),
+            "Stats collector {} was already added for {}.",
+            collector.name(),
+            field
+        );
+        inner.collectors.push(collector);
     }
 
-    pub fn reset_for_new_block(&mut self) {
-        self.offset = 0;
-        self.rec_count = 0;
-        self.block_num += 1;
+    /// Adds `stats::default_collectors()` of all fields: unmapped counts of
+    /// RefID and Pos, unavailable Mapq counts and distinct estimates of RefID
+    /// and ReadName. Must be set before pushing records.
+    pub fn add_default_stats_collectors(&mut self) {
+        for field in Fields::iterator() {
+            for collector in default_collectors(*field) {
+                self.add_stats_collector(*field, collector);
+            }
+        }
     }
 
-    pub fn generate_block_info(&mut self, codec_map_required: bool, mut codec: Codecs) -> BlockInfo {
-        let stat = if self.stats_collector.is_some() {
-            self.stats_collector.replace(Stat::default())
-        } else {
-            None
-        };
-        if codec_map_required {
-            codec = *FIELD_CODEC_MAP.get(&self.field).expect("Missing codec mapping");
+    /// Encrypts blocks of `field` (and its index, for variable sized fields)
+    /// with AES-256-GCM. Only `key_id` is stored in the file, readers get the
+    /// key from a key provider. Must be set before pushing records.
+    pub fn set_field_encryption(&mut self, field: Fields, key_id: &str, key: &EncryptionKey) {
+        assert_eq!(self.records_pushed, 0, "Records were already pushed.");
+        assert!(
+            !self.deterministic,
+            "Encryption uses random nonce salts, output can't be deterministic."
+        );
+        assert!(
+            !self.field_groups.iter().any(|group| group.fields.contains(&field)),
+            "Field {} is grouped, it can't be encrypted.",
+            field
+        );
+        let mut fields = vec![field];
+        if matches!(field_type(&field), FieldType::VariableSized) {
+            fields.push(var_size_field_to_index(&field));
         }
-        BlockInfo {
-            numitems: self.rec_count,
-            uncompr_size: self.offset,
-            field: self.field,
-            stats: stat,
-            codec: codec,
+        for field in fields {
+            let (params, cipher) = FieldEncryption::new(field, key_id, key);
+            self.file_meta.set_field_encryption(&field, Some(params));
+            self.ciphers[field as usize] = Some(cipher);
         }
     }
-}
 
-trait Column {
-    // Extracts and writes data from corresponding BAMRawRecord record.
-    fn write_record_field(&mut self, rec: &BAMRawRecord) -> WriteStatus;
+    /// Compresses values of fixed sized `fields` of each block of records
+    /// together, one field after another, so a block is written and read
+    /// once for all of them, which favours projections like (Flags, Mapq,
+    /// Pos). Reading any one of them decompresses the whole block though.
+    /// Blocks hold as many records as blocks of the widest member, fields are
+    /// compressed with codec and level of the first one, and blocks aren't
+    /// transformed, see `set_column_transforms()`. Only fields persisted by
+    /// the write template are grouped. Grouped fields can't be encrypted,
+    /// and files with them are misread by earlier versions of this crate.
+    /// Must be set before pushing records.
+    pub fn set_field_group(&mut self, fields: &[Fields]) {
+        assert_eq!(self.records_pushed, 0, "Records were already pushed.");
+        assert!(fields.len() > 1, "Field group needs at least two fields.");
+        for (i, field) in fields.iter().enumerate() {
+            assert!(
+                is_data_field(field) && matches!(field_type(field), FieldType::FixedSized),
+                "Only fixed sized data fields can be grouped, {} can't.",
+                field
+            );
+            assert!(self.ciphers[*field as usize].is_none(), "Field {} is encrypted, it can't be grouped.", field);
+            assert!(
+                !fields[..i].contains(field) && !self.field_groups.iter().any(|group| group.fields.contains(field)),
+                "Field {} is already grouped.",
+                field
+            );
+        }
+        let codec = *self.file_meta.get_field_codec(&fields[0]);
+        for field in &fields[1..] {
+            self.file_meta.set_field_codec(field, codec);
+        }
+        self.field_groups.push(FieldGroup::new(fields.to_vec()));
+        self.apply_field_groups();
+    }
 
-    fn get_inners(&mut self) -> (&mut Inner, Option<&mut Inner>);
-}
+    fn apply_field_groups(&mut self) {
+        let template = &self.write_template;
+        let columns = &mut self.columns;
+        for col in columns.iter_mut() {
+            let (inner, _) = col.get_inners();
+            inner.group = None;
+        }
+        for (group_num, group) in self.field_groups.iter_mut().enumerate() {
+            group.members = group.fields.iter().copied().filter(|field| template.contains(field)).collect();
+            if group.members.len() < 2 {
+                group.members.clear();
+            }
+            group.pending = group.members.iter().map(|_| VecDeque::new()).collect();
+            // Stripes of the group never reach block size limits of their fields.
+            let rows = columns
+                .iter_mut()
+                .map(|col| col.get_inners().0)
+                .filter(|inner| group.members.contains(&inner.field))
+                .map(|inner| inner.size_limit / field_item_size(&inner.field).unwrap_or(1))
+                .min()
+                .unwrap_or(SIZE_LIMIT);
+            for col in columns.iter_mut() {
+                let (inner, _) = col.get_inners();
+                if group.members.contains(&inner.field) {
+                    inner.group = Some((group_num, rows as u32));
+                }
+            }
+        }
+    }
 
-/// Column containing fixed sized fields.
-struct FixedColumn(Inner);
+    /// Guarantees that the same records pushed with the same settings give a
+    /// byte-identical file, with any number of compression threads. Block
+    /// payload depends only on column data and codec, blocks are written in
+    /// submission order and meta is serialized with fields and analytics in
+    /// fixed order. Calls of `flush_all_columns()` change block boundaries,
+    /// so they have to happen at the same records. Can't be combined with
+    /// encryption. Must be set before pushing records.
+    pub fn deterministic(&mut self, enabled: bool) {
+        assert_eq!(self.records_pushed, 0, "Records were already pushed.");
+        assert!(
+            !enabled || self.ciphers.iter().all(Option::is_none),
+            "Encryption uses random nonce salts, output can't be deterministic."
+        );
+        assert!(
+            !enabled || !self.compressor.levels().is_adaptive(),
+            "Adaptive levels depend on timing, output can't be deterministic."
+        );
+        assert!(
+            !enabled || self.digest.is_none(),
+            "Provenance holds a random UUID and creation time, output can't be deterministic."
+        );
+        self.deterministic = enabled;
+    }
 
-impl FixedColumn {
-    pub fn new(field: Fields, comparator: Option<Stat>) -> Self {
-        if comparator.is_some() && field != Fields::RefID && field != Fields::Pos {
-            panic!("Stats collection is only supported for RefID and POS fields.");
-        }
-        Self(Inner::new(field, comparator))
+    /// Keeps a digest of data region as it's written and records it in meta on
+    /// `finish_with_summary()`, with a random UUID, creation time and version
+    /// of gbam_tools, see `provenance`. Can't be combined with deterministic
+    /// output. Must be set before pushing records.
+    pub fn set_content_digest(&mut self, enabled: bool) {
+        assert_eq!(self.records_pushed, 0, "Records were already pushed.");
+        assert!(
+            !enabled || !self.deterministic,
+            "Provenance holds a random UUID and creation time, output can't be deterministic."
+        );
+        self.digest = if enabled { Some(new_digest()) } else { None };
     }
-}
 
-impl Column for FixedColumn {
-    fn write_record_field(&mut self, rec: &BAMRawRecord) -> WriteStatus {
-        let inner = &mut self.0;
-        let data = rec.get_bytes(&inner.field);
+    /// Reserves `bytes` after file info, where `finish_with_summary()` writes
+    /// meta if it fits, so readers get everything they need from the file head,
+    /// see `Reader::from_stream()`. Otherwise meta goes to the end of file as
+    /// usual and the space stays empty. Must be set before pushing records, up
+    /// to `MAX_META_SIZE` bytes. Fails if the placeholder can't be written.
+    pub fn reserve_meta_bytes(&mut self, bytes: usize) -> std::io::Result<()> {
+        assert_eq!(self.records_pushed, 0, "Records were already pushed.");
+        assert!(bytes as u64 <= MAX_META_SIZE, "Readers take up to {} bytes of meta.", MAX_META_SIZE);
+        self.file_info.reserved_meta = bytes as u64;
+        // Placeholder file info tells recovery where blocks start.
+        self.inner.seek(SeekFrom::Start(0))?;
+        self.inner.write_all(&self.file_info.to_padded_bytes()?)?;
+        self.inner.write_all(&vec![0; bytes])
+    }
 
-        if inner.flush_required(data) {
-            return WriteStatus::Full(inner);
-        }
+    /// Compresses blocks of `field` at `level` of its codec, see
+    /// `level_tuning::level_range()`, instead of the default level. Overrides
+    /// adaptive levels. The level is recorded in meta. Must be set before
+    /// pushing records.
+    pub fn set_compression_level(&mut self, field: Fields, level: i32) {
+        assert_eq!(self.records_pushed, 0, "Records were already pushed.");
+        let codec = *self.file_meta.get_field_codec(&field);
+        assert!(
+            level_range(codec).is_some_and(|range| range.contains(&level)),
+            "Codec {:?} of field {} has no level {}",
+            codec,
+            field,
+            level
+        );
+        self.compressor.levels_mut().pin(field, level);
+    }
 
-        if let Some(ref mut stats) = inner.stats_collector {
-            stats.update((&data[..]).read_i32::<LittleEndian>().unwrap());
+    /// Picks compression level of each field by compressing its first blocks
+    /// at two levels, see `level_tuning`. Levels are recorded in meta. Picks
+    /// depend on timing, so output can't be deterministic. Must be set before
+    /// pushing records.
+    pub fn set_adaptive_levels(&mut self, adaptive: Option<AdaptiveLevels>) {
+        assert_eq!(self.records_pushed, 0, "Records were already pushed.");
+        assert!(
+            adaptive.is_none() || !self.deterministic,
+            "Adaptive levels depend on timing, output can't be deterministic."
+        );
+        if let Some(adaptive) = adaptive {
+            assert!(adaptive.sample_blocks > 0, "Adaptive levels need at least one sampled block.");
         }
+        self.compressor.levels_mut().set_adaptive(adaptive);
+    }
 
-        inner.write_data(data)
+    /// Persists only fields of `template`, other fields of pushed records are
+    /// dropped. Must be set before pushing records.
+    pub fn set_write_template(&mut self, template: WriteTemplate) {
+        assert_eq!(self.records_pushed, 0, "Records were already pushed.");
+        self.write_template = template;
+        self.apply_derived_seq_index();
+        self.apply_field_groups();
     }
 
-    fn get_inners(&mut self) -> (&mut Inner, Option<&mut Inner>) {
-        (&mut self.0, None)
+    /// Leaves out RawSeqLen, the index of RawSequence column. Readers derive
+    /// it from SequenceLength, the index of RawQual, since qualities are as
+    /// long as sequences and bases take half a byte each. Saves 4 bytes per
+    /// record before compression. Applies only if both RawSequence and
+    /// RawQual are persisted, see `set_write_template()`. Files written so
+    /// can't be split, nor rewritten in those fields, and are not read by
+    /// earlier versions of this crate. Must be set before pushing records.
+    pub fn set_derived_seq_index(&mut self, enabled: bool) {
+        assert_eq!(self.records_pushed, 0, "Records were already pushed.");
+        self.derived_seq_index = enabled;
+        self.apply_derived_seq_index();
+    }
+
+    fn apply_derived_seq_index(&mut self) {
+        let derived = self.derived_seq_index
+            && self.write_template.contains(&Fields::RawSequence)
+            && self.write_template.contains(&Fields::RawQual);
+        self.file_meta.set_index_derived(Fields::RawSeqLen, derived);
+        for col in self.columns.iter_mut() {
+            let (inner, _) = col.get_inners();
+            if inner.field == Fields::RawSequence {
+                inner.derived_index = derived;
+            }
+        }
+    }
+
+    /// Calls `callback` with progress of writing, at most a few times per
+    /// second, and once more when `finish_with_summary()` succeeds.
+    pub fn set_progress_callback(&mut self, callback: Box<dyn FnMut(ProgressEvent)>) {
+        self.progress.set_callback(callback);
+    }
+
+    /// Aborts writing once `token` is cancelled: pushes fail, blocks queued for
+    /// compression are skipped and `finish_with_summary()` fails without
+    /// writing meta, leaving the file unfinalized.
+    pub fn set_cancellation_token(&mut self, token: CancellationToken) {
+        self.compressor.set_cancellation_token(token.clone());
+        self.cancel = Some(token);
+    }
+
+    /// Registers observer called with `field` bytes of every pushed record. Its
+    /// summary is stored in file meta analytics on `finish_with_summary()`.
+    pub fn register_collector(&mut self, field: Fields, collector: Box<dyn RecordObserver>) {
+        assert_eq!(self.records_pushed, 0, "Records were already pushed.");
+        self.collectors.push((field, collector));
+    }
+
+    /// Writes only records on references `ref_ids`. Kept references are
+    /// renumbered in their original order, and the header lists only them.
+    /// Mates on dropped references are marked unmapped. Must be set before
+    /// pushing records.
+    pub fn set_kept_refs(&mut self, ref_ids: &[i32]) {
+        assert_eq!(self.records_pushed, 0, "Records were already pushed.");
+        assert!(self.ref_subset.is_none(), "References were already subset.");
+        let ref_seqs = self.file_meta.get_ref_seqs();
+        let mut kept = ref_ids.to_vec();
+        kept.sort_unstable();
+        kept.dedup();
+        assert!(
+            kept.iter().all(|&id| id >= 0 && (id as usize) < ref_seqs.len()),
+            "Reference ids must be in 0..{}",
+            ref_seqs.len()
+        );
+        let sam_header = subset_sam_header(self.file_meta.get_sam_header(), ref_seqs, &kept);
+        let kept_seqs = kept.iter().map(|&id| ref_seqs[id as usize].clone()).collect();
+        self.ref_subset = Some(RefSubset::new(&kept, ref_seqs.len()));
+        self.file_meta.set_ref_seqs(kept_seqs, sam_header);
+    }
+
+    /// Same as `set_kept_refs()`, with references given by name. Fails on
+    /// unknown names.
+    pub fn set_kept_ref_names(&mut self, names: &[&str]) -> std::io::Result<()> {
+        let ref_seqs = self.file_meta.get_ref_seqs();
+        let ref_ids = names
+            .iter()
+            .map(|name| {
+                ref_seqs
+                    .iter()
+                    .position(|(ref_name, _)| ref_name == name)
+                    .map(|id| id as i32)
+                    .ok_or_else(|| {
+                        std::io::Error::new(
+                            std::io::ErrorKind::InvalidInput,
+                            format!("Unknown reference sequence '{}'", name),
+                        )
+                    })
+            })
+            .collect::<std::io::Result<Vec<i32>>>()?;
+        self.set_kept_refs(&ref_ids);
+        Ok(())
+    }
+
+    /// Translates RefID and NextRefID of pushed records with `ref_map`,
+    /// indexed by reference id of the input, see `build_ref_map()`. Ids
+    /// refer to reference sequences passed to `new()`, even if they are
+    /// subset. May be changed between inputs, None turns translation off.
+    pub fn set_ref_map(&mut self, ref_map: Option<Vec<i32>>) {
+        self.ref_map.new_ids = ref_map;
+    }
+
+    /// Rejects batches with a RefID or NextRefID out of range of the ref map,
+    /// or of reference sequences without one. Such ids are written as is by
+    /// default.
+    pub fn set_strict_ref_ids(&mut self, strict: bool) {
+        self.ref_map.strict = strict;
+    }
+
+    /// Records dropped and changed so far, if references are subset.
+    pub fn ref_subset_report(&self) -> Option<&RefSubsetReport> {
+        self.ref_subset.as_ref().map(|subset| &subset.report)
+    }
+
+    /// Sets how records are checked in `push_record()`. Validation is off by default.
+    pub fn set_validation_mode(&mut self, mode: ValidationMode) {
+        self.validation_mode = mode;
+    }
+
+    /// Violations counted so far in `ValidationMode::Warn`.
+    pub fn validation_report(&self) -> &ValidationReport {
+        &self.validation_report
+    }
+
+    /// Push BAM record into this writer. Fails without writing the record if
+    /// it is larger than `MAX_RECORD_SIZE`, the writer was cancelled, see
+    /// `set_cancellation_token()`, the record is invalid in
+    /// `ValidationMode::Strict`, its reference ids are out of range with
+    /// `set_strict_ref_ids()`, or it is out of order by more than a strict
+    /// `set_reorder_window()`. Also fails if blocks it completes, or blocks
+    /// flushed to stay within `set_memory_budget()`, can't be compressed or
+    /// written, after which the file can't be finished.
+    pub fn push_record(
+        &mut self,
+        record: &BAMRawRecord,
+        codec_map_required: bool,
+    ) -> std::io::Result<()> {
+        self.push_records(std::slice::from_ref(record), codec_map_required)
+    }
+
+    /// Push record built with `GbamRecordBuilder`, the same way as
+    /// `push_record()`.
+    pub fn push_built_record(
+        &mut self,
+        record: &BuiltRecord,
+        codec_map_required: bool,
+    ) -> std::io::Result<()> {
+        self.push_record(record.raw(), codec_map_required)
+    }
+
+    /// Push batch of BAM records. Each column is filled with the whole batch
+    /// at once, which is cheaper than pushing records one by one. In
+    /// `ValidationMode::Strict` the batch is checked first, and none of its
+    /// records are written if any is invalid.
+    pub fn push_records(
+        &mut self,
+        records: &[BAMRawRecord],
+        codec_map_required: bool,
+    ) -> std::io::Result<()> {
+        self.push_records_from(records, None, codec_map_required)
+    }
+
+    /// Push batch of records like `push_records()`, each with its value of
+    /// source index, e.g. read from the file it comes from, which is kept
+    /// instead of its ordinal. Values are ignored unless the writer keeps
+    /// source index, see `set_source_index()`.
+    pub fn push_records_with_source_index(
+        &mut self,
+        records: &[BAMRawRecord],
+        source_indices: &[u64],
+        codec_map_required: bool,
+    ) -> std::io::Result<()> {
+        if records.len() != source_indices.len() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "{} records were pushed with {} source indices",
+                    records.len(),
+                    source_indices.len()
+                ),
+            ));
+        }
+        self.push_records_from(records, Some(source_indices), codec_map_required)
+    }
+
+    /// Push record like `push_record()`, with its value of source index if
+    /// given, see `push_records_with_source_index()`.
+    pub fn push_record_with_source_index(
+        &mut self,
+        record: &BAMRawRecord,
+        source_index: Option<u64>,
+        codec_map_required: bool,
+    ) -> std::io::Result<()> {
+        let sources = source_index.as_ref().map(std::slice::from_ref);
+        self.push_records_from(std::slice::from_ref(record), sources, codec_map_required)
+    }
+
+    fn push_records_from(
+        &mut self,
+        records: &[BAMRawRecord],
+        sources: Option<&[u64]>,
+        codec_map_required: bool,
+    ) -> std::io::Result<()> {
+        self.check_not_cancelled()?;
+        // Readers bound what blocks decompress into by it, see
+        // `BlockMeta::max_uncompressed_size()`.
+        if let Some(rec) = records.iter().find(|rec| rec.len() > MAX_RECORD_SIZE) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Record of {} bytes is larger than {} allowed", rec.len(), MAX_RECORD_SIZE),
+            ));
+        }
+        let ingested = records.len();
+        let mut sources = match &self.source_index {
+            Some(source_index) => source_index.values_of(ingested, sources),
+            None => Vec::new(),
+        };
+        let mapped;
+        let records = if self.ref_map.is_active() {
+            let n_refs = match &self.ref_subset {
+                Some(ref_subset) => ref_subset.n_refs(),
+                None => self.file_meta.get_ref_seqs().len(),
+            };
+            mapped = self.ref_map.apply(records, n_refs, self.records_ingested)?;
+            &mapped[..]
+        } else {
+            records
+        };
+        let kept;
+        // Positions of kept records in the input, if any were dropped.
+        let mut input_indices = Vec::new();
+        let records = match self.ref_subset.as_mut() {
+            Some(ref_subset) => {
+                let mut kept_sources = Vec::new();
+                kept = records
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(i, rec)| {
+                        let rec = ref_subset.apply(rec)?;
+                        kept_sources.extend(sources.get(i));
+                        input_indices.push(i);
+                        Some(rec)
+                    })
+                    .collect::<Vec<_>>();
+                sources = kept_sources;
+                &kept[..]
+            }
+            None => records,
+        };
+        if self.validation_mode != ValidationMode::Off {
+            let n_refs = self.file_meta.get_ref_seqs().len();
+            for (i, record) in records.iter().enumerate() {
+                if let Err(violation) = validate_record(record, n_refs) {
+                    if self.validation_mode == ValidationMode::Strict {
+                        let input_index = input_indices.get(i).copied().unwrap_or(i);
+                        return Err(std::io::Error::new(
+                            std::io::ErrorKind::InvalidData,
+                            format!(
+                                "Record {} is invalid: {}",
+                                self.records_ingested + input_index as u64,
+                                violation
+                            ),
+                        ));
+                    }
+                    self.validation_report.add(&violation);
+                }
+            }
+        }
+        self.records_ingested += ingested as u64;
+        if let Some(source_index) = self.source_index.as_mut() {
+            source_index.ingested(ingested);
+        }
+        match self.reorder.as_mut() {
+            Some(window) => {
+                let Released { records, sources } = window.push(records, &sources)?;
+                self.write_records(&records, &sources, codec_map_required)?;
+            }
+            None => self.write_records(records, &sources, codec_map_required)?,
+        }
+        self.enforce_memory_budget(codec_map_required)
+    }
+
+    // Flushes the largest columns, with the other members of their groups,
+    // until columns hold at most half of the memory budget. Waits for blocks
+    // queued for compression if they take more than the rest.
+    fn enforce_memory_budget(&mut self, codec_map_required: bool) -> std::io::Result<()> {
+        let budget = match self.memory_budget {
+            Some(budget) => budget,
+            None => return Ok(()),
+        };
+        let mut errors = Vec::new();
+        if self.memory_usage() > budget {
+            while self.bytes_buffered() > budget / 2 {
+                let (field, group) = self
+                    .columns
+                    .iter_mut()
+                    .flat_map(|col| {
+                        let (inner, idx) = col.get_inners();
+                        std::iter::once(inner).chain(idx)
+                    })
+                    .max_by_key(|inner| inner.offset)
+                    .map(|inner| (inner.field, inner.group.map(|(group, _)| group)))
+                    .unwrap();
+                errors.extend(self.flush_columns(codec_map_required, |inner| {
+                    inner.rec_count > 0
+                        && (inner.field == field || (group.is_some() && inner.group.map(|(g, _)| g) == group))
+                }));
+                self.file_meta.set_rows_per_block(None);
+            }
+            if self.memory_usage() > budget {
+                errors.extend(self.write_queued_blocks(false));
+            }
+            self.compressor.buffer_pool().trim(budget.saturating_sub(self.memory_usage()));
+        }
+        self.check_no_failed_fields(errors)?;
+        #[cfg(test)]
+        {
+            let usage = self.memory_usage();
+            if let Some(probe) = self.memory_probe.as_mut() {
+                probe(usage);
+            }
+        }
+        Ok(())
+    }
+
+    // Writes records into columns, in order, and their `sources` into source
+    // index if it's kept.
+    fn write_records(
+        &mut self,
+        records: &[BAMRawRecord],
+        sources: &[u64],
+        codec_map_required: bool,
+    ) -> std::io::Result<()> {
+        if let Some(source_index) = self.source_index.as_mut() {
+            let rows = self.file_meta.get_rows_per_block();
+            let bytes = source_index.push(sources, rows, &mut self.inner, &mut self.digest)?;
+            if bytes > 0 {
+                self.progress.block_written(bytes);
+            }
+        }
+        if let Some(linear_index) = self.linear_index.as_mut() {
+            for (i, record) in records.iter().enumerate() {
+                linear_index.observe(self.records_pushed + i as u64, record);
+            }
+        }
+        if let Some(tail_start) = self.unmapped_tail.as_mut() {
+            for (i, record) in records.iter().enumerate() {
+                let ref_id = record.get_bytes(&Fields::RefID).read_i32::<LittleEndian>().unwrap();
+                match (ref_id == UNPLACED_REF_ID, *tail_start) {
+                    (true, None) => *tail_start = Some(self.records_pushed + i as u64),
+                    (false, Some(_)) => *tail_start = None,
+                    _ => {}
+                }
+            }
+        }
+        self.records_pushed += records.len() as u64;
+        for (field, collector) in self.collectors.iter_mut() {
+            for record in records {
+                collector.observe(record.get_bytes(field));
+            }
+        }
+
+        let out = &mut self.inner;
+        let file_meta = &mut self.file_meta;
+        let compressor = &mut self.compressor;
+        let ciphers = &self.ciphers;
+        let field_groups = &mut self.field_groups;
+        let digest = &mut self.digest;
+        let progress = &mut self.progress;
+        let failed_fields = &mut self.failed_fields;
+        // Called by columns whenever they or their indices are full.
+        let mut flush = |inner: &mut Inner| {
+            let written = flush_field_buffer(
+                out,
+                file_meta,
+                compressor,
+                ciphers,
+                field_groups,
+                digest,
+                inner,
+                codec_map_required,
+            );
+            match written {
+                Ok(Some(bytes)) => progress.block_written(bytes),
+                Ok(None) => {}
+                Err((field, err)) => {
+                    add_failed_field(failed_fields, field);
+                    return Err(err);
+                }
+            }
+            Ok(())
+        };
+        // Index fields are not written on their own. They hold index data for variable sized fields.
+        for col in self.columns.iter_mut() {
+            if self.write_template.contains(&col.field()) {
+                col.write_records_field(records, &mut flush)?;
+            }
+        }
+        self.report_progress(false);
+        Ok(())
+    }
+
+    /// Bytes of records buffered in columns and not yet handed to compressor.
+    /// Callers may use it to decide when to call `flush_all_columns()`.
+    pub fn bytes_buffered(&self) -> usize {
+        self.columns.iter().map(|col| col.bytes_buffered()).sum()
+    }
+
+    /// Compresses and writes out records buffered in every column, so they
+    /// don't wait in memory for blocks to fill up. Meant for slow streams of
+    /// records, called on a timer. With `write_meta_snapshot`, meta is written
+    /// after the blocks too, so records pushed so far can be restored with
+    /// `recover::recover_meta()` if `finish_with_summary()` is never called.
+    /// Partially filled blocks break record alignment of
+    /// `set_rows_per_block()`.
+    pub fn flush_all_columns(
+        &mut self,
+        write_meta_snapshot: bool,
+        codec_map_required: bool,
+    ) -> std::io::Result<()> {
+        self.check_not_cancelled()?;
+        let mut errors = self.flush_columns(codec_map_required, |inner| inner.rec_count > 0);
+        if let Some(rows) = self.file_meta.get_rows_per_block() {
+            if !self.records_pushed.is_multiple_of(rows as u64) {
+                self.file_meta.set_rows_per_block(None);
+            }
+        }
+        errors.extend(self.write_queued_blocks(false));
+        self.check_no_failed_fields(errors)?;
+        if write_meta_snapshot {
+            let (bytes, _) = prefixed_meta_bytes(&self.file_meta);
+            self.inner.write_all(&bytes)?;
+            // Snapshots lie among blocks, in the data region.
+            if let Some(digest) = self.digest.as_mut() {
+                digest.write(&bytes);
+            }
+        }
+        self.report_progress(false);
+        self.inner.flush()
+    }
+
+    // Waits for blocks queued for compression and writes them, keeping the
+    // number of blocks in flight. Once `finished`, buffers are only kept in
+    // the pool, for writers sharing it. Errors are returned.
+    fn write_queued_blocks(&mut self, finished: bool) -> Vec<std::io::Error> {
+        let mut errors = Vec::new();
+        for mut task in self.compressor.finish() {
+            if let OrderingKey::Key(key) = task.ordering_key {
+                match write_data_and_update_meta(
+                    &mut self.inner,
+                    &mut self.file_meta,
+                    &self.ciphers,
+                    key,
+                    &mut task,
+                ) {
+                    Ok(bytes) => {
+                        if let Some(digest) = self.digest.as_mut() {
+                            digest.write(&task.buf);
+                        }
+                        self.progress.block_written(bytes)
+                    }
+                    Err(err) => {
+                        add_failed_field(&mut self.failed_fields, task.block_info.field);
+                        errors.push(err);
+                    }
+                }
+            }
+            if finished {
+                self.compressor.buffer_pool().put(task.buf);
+            } else {
+                self.compressor.recycle_buffer(task.buf);
+            }
+        }
+        errors
+    }
+
+    // Flushes buffers of columns and their indices `flush` returns true for.
+    // Columns are flushed even if blocks of previous ones failed to be
+    // written, errors are returned.
+    fn flush_columns<F: Fn(&Inner) -> bool>(
+        &mut self,
+        codec_map_required: bool,
+        flush: F,
+    ) -> Vec<std::io::Error> {
+        let mut errors = Vec::new();
+        for col in self.columns.iter_mut() {
+            let (inner, idx) = col.get_inners();
+            for inner in std::iter::once(inner).chain(idx) {
+                if flush(inner) {
+                    match flush_field_buffer(
+                        &mut self.inner,
+                        &mut self.file_meta,
+                        &mut self.compressor,
+                        &self.ciphers,
+                        &mut self.field_groups,
+                        &mut self.digest,
+                        inner,
+                        codec_map_required,
+                    ) {
+                        Ok(Some(bytes)) => self.progress.block_written(bytes),
+                        Ok(None) => {}
+                        Err((field, err)) => {
+                            add_failed_field(&mut self.failed_fields, field);
+                            errors.push(err);
+                        }
+                    }
+                }
+            }
+        }
+        errors
+    }
+
+    // Fails if blocks of any field failed to be written, now or by earlier
+    // calls, naming the fields. Error kind is the one of the first of
+    // `errors`, failure of compression is passed on as is.
+    fn check_no_failed_fields(&self, errors: Vec<std::io::Error>) -> std::io::Result<()> {
+        if self.failed_fields.is_empty() {
+            return Ok(());
+        }
+        if let Some(err) = errors.first() {
+            if let Some(GbamError::Codec(failed @ CodecError::CompressionFailed { .. })) = gbam_error(err) {
+                return Err(failed.clone().into());
+            }
+        }
+        let fields: Vec<String> = self.failed_fields.iter().map(|field| field.to_string()).collect();
+        let mut msg = format!("Blocks of fields {} were not written", fields.join(", "));
+        let mut kind = std::io::ErrorKind::Other;
+        if let Some(err) = errors.first() {
+            msg = format!("{}: {}", msg, err);
+            kind = err.kind();
+        }
+        Err(std::io::Error::new(kind, msg))
+    }
+
+    /// Terminates the writer, returning total amount of bytes written.
+    #[deprecated(note = "Use finish_with_summary()")]
+    pub fn finish(&mut self, codec_map_required: bool) -> std::io::Result<u64> {
+        self.finish_with_summary(codec_map_required).map(|summary| summary.total_bytes)
+    }
+
+    /// Terminates the writer. Always call after writting all the data. Returns
+    /// what was written.
+    pub fn finish_with_summary(&mut self, codec_map_required: bool) -> std::io::Result<WriteSummary> {
+        if self.is_cancelled() {
+            // Compressor skips queued blocks, waiting for it is quick.
+            self.compressor.finish();
+            return Err(cancelled_error());
+        }
+        self.check_no_partial_record()?;
+        if let Some(held) = self.reorder.as_mut().map(ReorderWindow::drain) {
+            self.write_records(&held.records, &held.sources, codec_map_required)?;
+        }
+        if let Some(stats) = self.reorder_stats() {
+            let sorted = stats.overflowed == 0;
+            self.file_meta.set_sort_order(if sorted { SortOrder::Coordinate } else { SortOrder::Unsorted });
+            self.file_info.is_sorted = sorted;
+        }
+        #[cfg(feature = "tracing")]
+        if self.validation_report.invalid_records > 0 {
+            tracing::warn!(target: "gbam", "{}", self.validation_report);
+        }
+        #[cfg(feature = "tracing")]
+        if let Some(ref_subset) = &self.ref_subset {
+            tracing::info!(target: "gbam", "{}", ref_subset.report);
+        }
+
+        // Flush leftovers. Empty buffers are flushed only if nothing was
+        // flushed before, so every field has at least one block, even if no
+        // records were pushed. All blocks are attempted before failures are
+        // reported.
+        let mut errors =
+            self.flush_columns(codec_map_required, |inner| inner.rec_count > 0 || inner.block_num == 0);
+        errors.extend(self.write_queued_blocks(true));
+        if self.is_cancelled() {
+            return Err(cancelled_error());
+        }
+        // Meta and file info aren't written, readers reject the file as
+        // unfinished instead of reading blocks missing from it.
+        self.check_no_failed_fields(errors)?;
+        if let Some(mut source_index) = self.source_index.take() {
+            let bytes = source_index.flush(&mut self.inner, &mut self.digest)?;
+            if bytes > 0 {
+                self.progress.block_written(bytes);
+            }
+            source_index.finish().set_in(&mut self.file_meta);
+        }
+        self.report_progress(true);
+
+        for (_, collector) in &self.collectors {
+            self.file_meta
+                .set_analytics(collector.name(), collector.summary());
+        }
+        if let Some(linear_index) = self.linear_index.take() {
+            self.file_meta.set_linear_index(linear_index.finish());
+        }
+        if let Some(tail_start) = self.unmapped_tail {
+            self.file_meta.set_unmapped_tail_start(Some(tail_start.unwrap_or(self.records_pushed)));
+        }
+        let file_meta = &self.file_meta;
+        let levels = self.compressor.levels().decisions(|field| *file_meta.get_field_codec(&field));
+        let level_of: HashMap<String, i32> =
+            levels.iter().map(|(field, decision)| (field.clone(), decision.level)).collect();
+        if !levels.is_empty() {
+            let levels = serde_json::to_value(levels).unwrap();
+            self.file_meta.set_extension(LEVELS_EXTENSION, levels);
+        }
+        self.file_meta.check_no_missing_blocks()?;
+        for field in Fields::iterator() {
+            let persisted = self.write_template.contains(field) && !self.file_meta.is_index_derived(field);
+            let expected = if persisted {
+                self.records_pushed
+            } else {
+                0
+            };
+            let items = self.file_meta.count_items(field);
+            if items != expected {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!(
+                        "Field {} has {} items, but {} records were pushed",
+                        field, items, expected
+                    ),
+                ));
+            }
+        }
+        if let Some(digest) = self.digest.take() {
+            let data_start = self.file_info.data_range().start;
+            let provenance = Provenance::new(digest.finish(), data_start, self.inner.stream_position()?);
+            self.file_meta
+                .set_extension(PROVENANCE_EXTENSION, serde_json::to_value(provenance).unwrap());
+        }
+        let data_end = self.inner.stream_position()?;
+        let total_bytes =
+            write_meta_and_file_info(&mut self.inner, &mut self.file_meta, &mut self.file_info)?;
+        self.compressor.counters().emit_summary();
+        let meta_bytes = self.file_info.reserved_meta + (total_bytes - data_end);
+        self.summary(total_bytes, meta_bytes, &level_of)
+    }
+
+    // Summary of finished file, with levels by field name.
+    fn summary(
+        &self,
+        total_bytes: u64,
+        meta_bytes: u64,
+        level_of: &HashMap<String, i32>,
+    ) -> std::io::Result<WriteSummary> {
+        let mut summary = WriteSummary {
+            total_bytes,
+            records_written: self.records_pushed,
+            meta_bytes,
+            wall_time: self.created.elapsed(),
+            ..Default::default()
+        };
+        // Members of a group share blocks, told apart by position and size.
+        let mut counted = HashSet::new();
+        for field in Fields::iterator() {
+            let blocks = self.file_meta.view_blocks(field);
+            if blocks.is_empty() {
+                continue;
+            }
+            let name = field.to_string();
+            let (mut compressed, mut uncompressed) = (0, 0);
+            for block in blocks {
+                if counted.insert((block.seekpos, block.block_size)) {
+                    compressed += u64::from(block.block_size);
+                    uncompressed += block.uncompressed_size;
+                }
+            }
+            summary.blocks_per_field.insert(name.clone(), blocks.len() as u64);
+            summary.compressed_bytes_per_field.insert(name.clone(), compressed);
+            summary.uncompressed_bytes_per_field.insert(name.clone(), uncompressed);
+            let setting = CodecSetting {
+                codec: *self.file_meta.get_field_codec(field),
+                level: level_of.get(&name).copied(),
+            };
+            summary.codec_settings.insert(name, setting);
+        }
+        if let Some(column) = SourceIndexMeta::from_file_meta(&self.file_meta)? {
+            summary.source_index_bytes = column.blocks.iter().map(|block| u64::from(block.block_size)).sum();
+        }
+        Ok(summary)
+    }
+}
+
+/// Checks that no blocks are missing from meta and records item totals of
+/// fields in it, then writes it prefixed with its crc32 and length for
+/// recovery, and points file info to it. Meta goes to the space reserved
+/// after file info if it fits there, otherwise to the current position.
+/// Returns total amount of bytes written.
+pub(crate) fn write_meta_and_file_info<WS: Write + Seek + SyncOutput>(
+    inner: &mut WS,
+    file_meta: &mut FileMeta,
+    file_info: &mut FileInfo,
+) -> std::io::Result<u64> {
+    file_meta.check_no_missing_blocks()?;
+    file_meta.record_item_totals();
+    file_meta.record_index_spans();
+    let span = trace_span!("write_meta", size = tracing::field::Empty);
+    let (bytes, crc32) = prefixed_meta_bytes(file_meta);
+    trace_record!(span, "size", bytes.len() - META_PREFIX_SIZE);
+    let data_end = inner.stream_position()?;
+    let prefix_pos = if bytes.len() as u64 <= file_info.reserved_meta {
+        inner.seek(SeekFrom::Start(FILE_INFO_SIZE as u64))?;
+        inner.write_all(&bytes)?;
+        inner.seek(SeekFrom::Start(data_end))?;
+        FILE_INFO_SIZE as u64
+    } else {
+        inner.write_all(&bytes)?;
+        data_end
+    };
+    let meta_start_pos = prefix_pos + META_PREFIX_SIZE as u64;
+
+    let total_bytes_written = inner.stream_position()?;
+    // File info is overwritten only when meta is on disk.
+    inner.sync_output()?;
+    file_info.seekpos = meta_start_pos;
+    file_info.crc32 = crc32;
+    let file_info_bytes = file_info.to_padded_bytes()?;
+    inner.seek(SeekFrom::Start(0))?;
+    inner.write_all(&file_info_bytes)?;
+    inner.sync_output()?;
+    Ok(total_bytes_written)
+}
+
+/// Writes meta at current position, prefixed with its crc32 and length.
+/// Returns position of meta and its crc32.
+pub(crate) fn write_prefixed_meta<WS: Write + Seek>(
+    inner: &mut WS,
+    file_meta: &FileMeta,
+) -> std::io::Result<(u64, u32)> {
+    let span = trace_span!("write_meta", size = tracing::field::Empty);
+    let (bytes, crc32) = prefixed_meta_bytes(file_meta);
+    trace_record!(span, "size", bytes.len() - META_PREFIX_SIZE);
+    let meta_start_pos = inner.stream_position()? + META_PREFIX_SIZE as u64;
+    inner.write_all(&bytes)?;
+    Ok((meta_start_pos, crc32))
+}
+
+/// Meta prefixed with its crc32 and length, and the crc32.
+fn prefixed_meta_bytes(file_meta: &FileMeta) -> (Vec<u8>, u32) {
+    let main_meta = serde_json::to_vec(file_meta).unwrap();
+    let crc32 = calc_crc_for_meta_bytes(&main_meta);
+    let mut bytes = Vec::with_capacity(META_PREFIX_SIZE + main_meta.len());
+    let prefix = MetaPrefix {
+        crc32,
+        len: main_meta.len() as u64,
+    };
+    bytes.extend_from_slice(&prefix.encode());
+    bytes.extend_from_slice(&main_meta);
+    (bytes, crc32)
+}
+
+#[allow(clippy::too_many_arguments)]
+fn flush_field_buffer<WS: Write + Seek>(
+    writer: &mut WS,
+    file_meta: &mut FileMeta,
+    compressor: &mut Compressor,
+    ciphers: &[Option<BlockCipher>],
+    groups: &mut [FieldGroup],
+    digest: &mut Option<XxHash64>,
+    inner: &mut Inner,
+    codec_map_required: bool
+) -> Result<Option<u64>, (Fields, std::io::Error)> {
+    let _span = trace_span!(
+        "flush_block",
+        field = %inner.field,
+        block = inner.block_num,
+        items = inner.rec_count
+    );
+    let timer = Timer::start();
+    // Column takes a buffer from the pool when its next block starts. Only
+    // the first `offset` bytes belong to the block.
+    let mut data = std::mem::take(&mut inner.buffer);
+    data.truncate(inner.offset);
+
+    let field = &inner.field;
+    let codec = *file_meta.get_field_codec(field);
+    let mut block_info = inner.generate_block_info(codec_map_required, codec);
+
+    if let Some(bits_per_key) = inner.bloom_bits_per_key {
+        let names = &data[..inner.offset];
+        block_info.bloom = Some(BloomFilter::from_names(names, inner.rec_count, bits_per_key));
+    }
+    if let Some(seq_lens) = inner.seq_lens.as_mut() {
+        let packed = pack_block(&data[..inner.offset], seq_lens);
+        inner.buffers.put(std::mem::replace(&mut data, packed));
+        block_info.uncompr_size = data.len();
+        seq_lens.clear();
+    }
+    if let Some(encoder) = inner.ref_encoder.as_mut() {
+        let encoded = encoder.encode_block(&data);
+        inner.buffers.put(std::mem::replace(&mut data, encoded));
+        block_info.uncompr_size = data.len();
+    }
+    if inner.column_transforms && inner.group.is_none() {
+        if let Some((transform, transformed)) = transform_block(inner.field, &data) {
+            inner.buffers.put(std::mem::replace(&mut data, transformed));
+            block_info.uncompr_size = data.len();
+            block_info.transform = Some(transform);
+        }
+    }
+
+    // Grouped fields flush after the same records, so the block of the last
+    // member to flush completes blocks of the same number of the others.
+    if let Some((group, _)) = inner.group {
+        match groups[group].add_block(block_info, data, &inner.buffers) {
+            Some((combined_info, combined)) => {
+                block_info = combined_info;
+                data = combined;
+            }
+            None => {
+                inner.reset_for_new_block();
+                compressor.counters().add_io_time(&timer);
+                return Ok(None);
+            }
+        }
+    }
+
+    // Block numbers count blocks of `inner.field` only, index fields of
+    // variable sized columns have inners of their own.
+    compressor.compress_block(OrderingKey::Key(inner.block_num), block_info, data);
+
+    let mut completed_task = compressor.get_compr_block();
+
+    // Compressor may hand back a block of another field.
+    let bytes_written = match completed_task.ordering_key {
+        OrderingKey::Key(key) => {
+            let written = write_data_and_update_meta(writer, file_meta, ciphers, key, &mut completed_task);
+            if let (Ok(_), Some(digest)) = (&written, digest.as_mut()) {
+                digest.write(&completed_task.buf);
+            }
+            written.map(Some).map_err(|err| (completed_task.block_info.field, err))
+        }
+        OrderingKey::UnusedBlock => Ok(None),
+    };
+
+    compressor.buffer_pool().put(completed_task.buf);
+    inner.reset_for_new_block();
+    compressor.counters().add_io_time(&timer);
+    bytes_written
+}
+
+/// Records `field` as failed, once.
+fn add_failed_field(failed_fields: &mut Vec<Fields>, field: Fields) {
+    if !failed_fields.contains(&field) {
+        failed_fields.push(field);
+    }
+}
+
+/// Writes compressed block of `task` and records it in meta. Returns number
+/// of bytes written. Block isn't recorded if it fails to be written.
+pub(crate) fn write_data_and_update_meta<WS: Write + Seek>(
+    writer: &mut WS,
+    file_meta: &mut FileMeta,
+    ciphers: &[Option<BlockCipher>],
+    key: u64,
+    task: &mut CompressTask,
+) -> std::io::Result<u64> {
+    let _span = trace_span!("write_block", field = %task.block_info.field, block = key, size = task.buf.len());
+    task.result.clone()?;
+    if let Some(cipher) = &ciphers[task.block_info.field as usize] {
+        task.buf = cipher.encrypt(key, std::mem::take(&mut task.buf));
+    }
+    let compressed_size = task.buf.len();
+    let block_size = compressed_size.try_into().unwrap();
+    let seekpos = writer.stream_position()?;
+
+    writer.write_all(&task.buf)?;
+
+    let block_info = &mut task.block_info;
+    let uncompressed_size = block_info.uncompr_size as u64;
+    if block_info.stripes.is_empty() {
+        let mut meta = generate_meta(block_info, seekpos, block_size, uncompressed_size);
+        meta.stored = task.stored;
+        set_block_meta(file_meta, block_info.field, key, meta);
+    } else {
+        // Every member records the whole block and where its values start.
+        let mut offset = 0;
+        for stripe in block_info.stripes.iter_mut() {
+            let mut meta = generate_meta(stripe, seekpos, block_size, uncompressed_size);
+            meta.stripe = Some(offset);
+            meta.stored = task.stored;
+            offset += stripe.uncompr_size as u64;
+            set_block_meta(file_meta, stripe.field, key, meta);
+        }
+    }
+    Ok(compressed_size as u64)
+}
+
+fn generate_meta(
+    block_info: &mut BlockInfo,
+    seekpos: u64,
+    block_size: u32,
+    uncompressed_size: u64,
+) -> BlockMeta {
+    BlockMeta {
+        seekpos,
+        numitems: block_info.numitems,
+        block_size,
+        uncompressed_size,
+        stats: block_info.stats.take(),
+        bloom: block_info.bloom.take(),
+        transform: block_info.transform,
+        extra_stats: std::mem::take(&mut block_info.extra_stats),
+        stripe: None,
+        stored: false,
+    }
+}
+
+// Blocks may be written out of order, meta is kept in order of their numbers.
+fn set_block_meta(file_meta: &mut FileMeta, field: Fields, key: u64, meta: BlockMeta) {
+    let field_meta = file_meta.get_blocks(&field);
+    if field_meta.len() <= key as usize {
+        field_meta.resize(key as usize + 1, BlockMeta::default());
+    }
+    field_meta[key as usize] = meta;
+}
+
+/// Fixed sized fields compressed in shared blocks, see
+/// `Writer::set_field_group()`.
+struct FieldGroup {
+    // As requested, and persisted ones, which are grouped.
+    fields: Vec<Fields>,
+    members: Vec<Fields>,
+    // Flushed blocks of each member waiting for blocks of the others.
+    pending: Vec<VecDeque<(BlockInfo, Vec<u8>)>>,
+}
+
+impl FieldGroup {
+    fn new(fields: Vec<Fields>) -> Self {
+        Self {
+            fields,
+            members: Vec::new(),
+            pending: Vec::new(),
+        }
+    }
+
+    /// Queues flushed block of a member. Once every member has a block
+    /// queued, returns the first ones combined, with their infos as stripes.
+    fn add_block(
+        &mut self,
+        block_info: BlockInfo,
+        data: Vec<u8>,
+        buffers: &BufferPool,
+    ) -> Option<(BlockInfo, Vec<u8>)> {
+        let member = self.members.iter().position(|field| *field == block_info.field).unwrap();
+        self.pending[member].push_back((block_info, data));
+        if self.pending.iter().any(VecDeque::is_empty) {
+            return None;
+        }
+        let mut stripes = Vec::new();
+        let mut combined = Vec::new();
+        for queue in self.pending.iter_mut() {
+            let (block_info, data) = queue.pop_front().unwrap();
+            if stripes.is_empty() {
+                combined = data;
+            } else {
+                combined.extend_from_slice(&data);
+                buffers.put(data);
+            }
+            stripes.push(block_info);
+        }
+        let block_info = BlockInfo {
+            numitems: stripes[0].numitems,
+            uncompr_size: combined.len(),
+            field: stripes[0].field,
+            codec: stripes[0].codec,
+            stripes,
+            ..Default::default()
+        };
+        Some((block_info, combined))
+    }
+}
+
+/// Block size limit of `field` unless set with `Writer::set_block_size_limit()`.
+fn default_block_size_limit(field: &Fields) -> usize {
+    match field_type(field) {
+        FieldType::FixedSized => SIZE_LIMIT,
+        FieldType::VariableSized => VAR_SIZE_LIMIT,
+    }
+}
+
+/// Capacity each column buffer reserves under memory budget of `bytes`:
+/// columns get at most half of the budget, shared by their buffers.
+fn column_reserve(bytes: usize) -> usize {
+    bytes / 2 / FIELDS_NUM
+}
+
+struct Inner {
+    stats_collector: Option<Stat>,
+    // Stored in extra stats of blocks.
+    collectors: Vec<Box<dyn StatsCollector>>,
+    buffer: Vec<u8>,
+    // Buffer is taken from it when the block starts.
+    buffers: BufferPool,
+    // Capacity taken for a block, lowered by `Writer::set_memory_budget()`.
+    reserve: usize,
+    // Bytes at which blocks are cut, see `Writer::set_block_size_limit()`.
+    size_limit: usize,
+    offset: usize,
+    field: Fields,
+    rec_count: u32,
+    block_num: u64,
+    // Sequence lengths of records in current block, kept only if sequences are 2-bit packed.
+    seq_lens: Option<Vec<u32>>,
+    // Set if sequences are encoded against a reference.
+    ref_encoder: Option<RefEncoder>,
+    // Set if blocks are flushed after fixed amount of records.
+    rows_per_block: Option<u32>,
+    // Set if bloom filters of read names are built, ReadName only.
+    bloom_bits_per_key: Option<u32>,
+    // Set if blocks may be transformed, see `column_transform`.
+    column_transforms: bool,
+    // Set if index of the column isn't written, RawSequence only.
+    derived_index: bool,
+    // Group of the field in `Writer::field_groups` and records per block of
+    // the group, set if the field is grouped.
+    group: Option<(usize, u32)>,
+}
+
+impl Inner {
+    pub fn new(field: Fields, stats_collector: Option<Stat>, buffers: BufferPool) -> Self {
+        let size_limit = default_block_size_limit(&field);
+        Self {
+            stats_collector,
+            collectors: Vec::new(),
+            buffer: Vec::new(),
+            buffers,
+            reserve: size_limit,
+            size_limit,
+            offset: 0,
+            field,
+            rec_count: 0,
+            block_num: 0,
+            seq_lens: None,
+            ref_encoder: None,
+            rows_per_block: None,
+            bloom_bits_per_key: None,
+            column_transforms: false,
+            derived_index: false,
+            group: None,
+        }
+    }
+    pub fn write_data(&mut self, data: &[u8]) {
+        // At this point everything should be flushed.
+        debug_assert!(!self.flush_required(data));
+
+        // Buffers of compressed blocks usually keep capacity of a full block.
+        // A record larger than that gets a block of its own. Nothing is zero
+        // filled.
+        let block_size = self.reserve.max(data.len());
+        if self.offset == 0 && self.buffer.capacity() < block_size {
+            let small = std::mem::replace(&mut self.buffer, self.buffers.get(block_size));
+            self.buffers.put(small);
+            self.buffer.reserve(block_size);
+        }
+        self.buffer.extend_from_slice(data);
+        self.offset += data.len();
+        debug_assert_eq!(self.offset, self.buffer.len());
+
+        self.rec_count += 1;
+    }
+
+    pub fn flush_required(&self, data: &[u8]) -> bool {
+        // At least one record will be written in even if it exceeds the limit.
+        (self.offset > 0 && self.offset + data.len() > self.size_limit)
+            || Some(self.rec_count) == self.rows_per_block
+            || self.group.is_some_and(|(_, rows)| self.rec_count == rows)
+    }
+
+    // Blocks of fixed sized fields cut after rows per block must never
+    // reach the size limit, or they would not be aligned.
+    fn check_rows_fit(&self) {
+        if let (Some(rows), Some(item_size)) = (self.rows_per_block, field_item_size(&self.field)) {
+            assert!(
+                rows as usize * item_size <= self.size_limit,
+                "Rows per block of field {} take more than its block size limit of {} bytes",
+                self.field,
+                self.size_limit
+            );
+        }
+    }
+
+    pub fn reset_for_new_block(&mut self) {
+        self.buffer.clear();
+        self.offset = 0;
+        self.rec_count = 0;
+        self.block_num += 1;
+    }
+
+    pub fn generate_block_info(&mut self, codec_map_required: bool, mut codec: Codecs) -> BlockInfo {
+        let stat = if self.stats_collector.is_some() {
+            self.stats_collector.replace(Stat::default())
+        } else {
+            None
+        };
+        if codec_map_required {
+            codec = *FIELD_CODEC_MAP.get(&self.field).expect("Missing codec mapping");
+        }
+        BlockInfo {
+            numitems: self.rec_count,
+            uncompr_size: self.offset,
+            field: self.field,
+            stats: stat,
+            bloom: None,
+            transform: None,
+            extra_stats: self
+                .collectors
+                .iter_mut()
+                .map(|collector| (collector.name().to_owned(), collector.finish_block()))
+                .collect(),
+            codec: codec,
+            stripes: Vec::new(),
+        }
+    }
+}
+
+/// Column of a data field. Dispatched by match rather than through trait
+/// objects, since it's called for every field of every batch of records.
+/// Columns live as long as the writer, one per field, so variants aren't
+/// boxed to even out their sizes.
+#[allow(clippy::large_enum_variant)]
+enum ColumnKind {
+    Fixed(FixedColumn),
+    Variable(VariableColumn),
+}
+
+impl ColumnKind {
+    fn field(&self) -> Fields {
+        match self {
+            ColumnKind::Fixed(col) => col.0.field,
+            ColumnKind::Variable(col) => col.inner.field,
+        }
+    }
+
+    // Extracts and writes data of `recs`. Calls `flush` with the column or
+    // its index when it's full, before writing the next record.
+    fn write_records_field<F>(&mut self, recs: &[BAMRawRecord], flush: &mut F) -> std::io::Result<()>
+    where
+        F: FnMut(&mut Inner) -> std::io::Result<()>,
+    {
+        match self {
+            ColumnKind::Fixed(col) => col.write_records_field(recs, flush),
+            ColumnKind::Variable(col) => col.write_records_field(recs, flush),
+        }
+    }
+
+    fn get_inners(&mut self) -> (&mut Inner, Option<&mut Inner>) {
+        match self {
+            ColumnKind::Fixed(col) => (&mut col.0, None),
+            ColumnKind::Variable(col) => (&mut col.inner, Some(&mut col.index.0)),
+        }
+    }
+
+    // Buffered bytes of column and its index.
+    fn bytes_buffered(&self) -> usize {
+        match self {
+            ColumnKind::Fixed(col) => col.0.offset,
+            ColumnKind::Variable(col) => col.inner.offset + col.index.0.offset,
+        }
+    }
+}
+
+/// Column containing fixed sized fields.
+struct FixedColumn(Inner);
+
+impl FixedColumn {
+    pub fn new(field: Fields, comparator: Option<Stat>, buffers: &BufferPool) -> Self {
+        if comparator.is_some() && field != Fields::RefID && field != Fields::Pos {
+            panic!("Stats collection is only supported for RefID and POS fields.");
+        }
+        Self(Inner::new(field, comparator, buffers.clone()))
+    }
+
+    fn write_records_field<F>(&mut self, recs: &[BAMRawRecord], flush: &mut F) -> std::io::Result<()>
+    where
+        F: FnMut(&mut Inner) -> std::io::Result<()>,
+    {
+        let inner = &mut self.0;
+        for rec in recs {
+            let data = rec.get_bytes(&inner.field);
+
+            if inner.flush_required(data) {
+                flush(inner)?;
+            }
+
+            if let Some(ref mut stats) = inner.stats_collector {
+                stats.update(stat_value(&inner.field, data));
+            }
+            for collector in inner.collectors.iter_mut() {
+                collector.update(data);
+            }
+
+            inner.write_data(data);
+        }
+        Ok(())
     }
 }
 
@@ -411,64 +2169,144 @@ struct VariableColumn {
 }
 
 impl VariableColumn {
-    pub fn new(field: Fields, comparator: Option<Stat>) -> Self {
+    pub fn new(field: Fields, comparator: Option<Stat>, buffers: &BufferPool) -> Self {
         if comparator.is_some() {
             panic!("Stats collection is not supported for variable length fields.");
         }
         Self {
-            inner: Inner::new(field, comparator),
-            index: FixedColumn::new(var_size_field_to_index(&field), None),
+            inner: Inner::new(field, comparator, buffers.clone()),
+            index: FixedColumn::new(var_size_field_to_index(&field), None, buffers),
         }
     }
-}
 
-impl Column for VariableColumn {
-    fn write_record_field(&mut self, rec: &BAMRawRecord) -> WriteStatus {
+    fn write_records_field<F>(&mut self, recs: &[BAMRawRecord], flush: &mut F) -> std::io::Result<()>
+    where
+        F: FnMut(&mut Inner) -> std::io::Result<()>,
+    {
         let inner = &mut self.inner;
         let index_inner = &mut self.index.0;
+        assert!(inner.stats_collector.is_none());
+        let write_index = !inner.derived_index;
 
-        let data = rec.get_bytes(&inner.field);
-        let mut idx_buf: [u8; U32_SIZE] = [0; U32_SIZE];
-
-        if index_inner.flush_required(&idx_buf) {
-            return WriteStatus::Full(index_inner);
-        }
-
-        if inner.flush_required(data) {
-            return WriteStatus::Full(inner);
-        }
+        for rec in recs {
+            let data = rec.get_bytes(&inner.field);
 
-        assert!(inner.stats_collector.is_none());
+            if write_index && index_inner.flush_required(&[0; INDEX_ENTRY_SIZE]) {
+                flush(index_inner)?;
+            }
 
-        inner.write_data(data);
-        (&mut idx_buf[..])
-            .write_u32::<LittleEndian>(u32::try_from(inner.offset).unwrap())
-            .unwrap();
-        index_inner.write_data(&idx_buf)
-    }
+            if inner.flush_required(data) {
+                flush(inner)?;
+            }
 
-    fn get_inners(&mut self) -> (&mut Inner, Option<&mut Inner>) {
-        (&mut self.inner, Some(&mut self.index.0))
+            for collector in inner.collectors.iter_mut() {
+                collector.update(data);
+            }
+            inner.write_data(data);
+            if let Some(seq_lens) = inner.seq_lens.as_mut() {
+                seq_lens.push(rec.get_len_val(&Fields::SequenceLength) as u32);
+            }
+            if let Some(encoder) = inner.ref_encoder.as_mut() {
+                encoder.observe(rec);
+            }
+            if write_index {
+                index_inner.write_data(&encode_index_entry(inner.offset));
+            }
+        }
+        Ok(())
     }
 }
 
 impl<W> Write for Writer<W>
 where
-    W: Write + Seek,
+    W: Write + Seek + SyncOutput,
 {
-    /// WARNING: ENSURE THAT BUF CONTAINS A ONE FULL RECORD.
-    /// TODO: Implement a proper trait and use it instead of Write in bam_sorting.
-    /// Write trait implementation is made to allow passing Write trait objects to sort function in BAM parallel.
+    /// Takes records as in BAM, each prefixed with its block_size, so the
+    /// writer can be the sink of
+    /// `bam_tools::sorting::sort::sort_bam_block_size_prefixed()`. `buf` may
+    /// hold any number of records, a record split between calls is completed
+    /// by the next one. The whole `buf` is always consumed.
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
-        assert!(!buf.is_empty());
-        let wrapper = BAMRawRecord(Cow::Borrowed(buf));
-        self.push_record(&wrapper, false);
-        Ok(buf.len())
+        let mut pending = std::mem::take(&mut self.partial_record);
+        let data = if pending.is_empty() {
+            buf
+        } else {
+            pending.extend_from_slice(buf);
+            &pending[..]
+        };
+        let mut records = Vec::new();
+        let mut rest = data;
+        while rest.len() >= U32_SIZE {
+            let block_size = (&rest[..]).read_u32::<LittleEndian>().unwrap() as usize;
+            if block_size < FIXED_FIELDS_SIZE {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("Record block_size {} is shorter than fixed fields", block_size),
+                ));
+            }
+            if rest.len() < U32_SIZE + block_size {
+                break;
+            }
+            records.push(BAMRawRecord(Cow::Borrowed(&rest[U32_SIZE..U32_SIZE + block_size])));
+            rest = &rest[U32_SIZE + block_size..];
+        }
+        let result = self.push_records(&records, false);
+        self.partial_record = rest.to_vec();
+        result.map(|_| buf.len())
     }
 
+    /// Flushes the underlying sink. Records buffered in columns wait for
+    /// their blocks to fill up or for `finish_with_summary()`, use
+    /// `flush_all_columns()` to write them out earlier.
     fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Size of fixed part of BAM record, without block_size.
+const FIXED_FIELDS_SIZE: usize = 32;
+
+impl<W> Writer<W>
+where
+    W: Write + Seek + SyncOutput,
+{
+    fn is_cancelled(&self) -> bool {
+        self.cancel.as_ref().is_some_and(CancellationToken::is_cancelled)
+    }
+
+    fn check_not_cancelled(&self) -> std::io::Result<()> {
+        if self.is_cancelled() {
+            return Err(cancelled_error());
+        }
         Ok(())
     }
+
+    fn report_progress(&mut self, finished: bool) {
+        if !self.progress.is_due(finished) {
+            return;
+        }
+        let event = ProgressEvent {
+            records_pushed: self.records_pushed,
+            bytes_buffered: self.bytes_buffered() as u64,
+            blocks_compressed: self.progress.blocks_compressed,
+            bytes_written: self.progress.bytes_written,
+            finished,
+        };
+        self.progress.report(event);
+    }
+
+    fn check_no_partial_record(&self) -> std::io::Result<()> {
+        if self.partial_record.is_empty() {
+            return Ok(());
+        }
+        Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!(
+                "Incomplete record of {} bytes was written",
+                self.partial_record.len()
+            ),
+        ))
+    }
 }
 
 // TODO: Currently end user should manually call finish. Probably can be done
@@ -527,3 +2365,1000 @@ pub(crate) fn calc_crc_for_meta_bytes(bytes: &[u8]) -> u32 {
 //         // }
 //     }
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use byteorder::WriteBytesExt;
+    use crate::test_utils::{
+        new_test_writer, open_test_file, sam_header_bytes, test_ref_seqs, write_test_file, TestRecord,
+    };
+    use crate::reader::{parse_tmplt::ParsingTemplate, reader::Reader, record::GbamRecord};
+    use crate::reader::column::decompress_into;
+    use crate::MEGA_BYTE_SIZE;
+    use tempdir::TempDir;
+
+    fn cursor_writer() -> Writer<Cursor<Vec<u8>>> {
+        let ref_seqs = test_ref_seqs();
+        Writer::new(
+            Cursor::new(Vec::new()),
+            vec![Codecs::Lz4; FIELDS_NUM],
+            1,
+            vec![Fields::RefID, Fields::Pos],
+            ref_seqs.clone(),
+            sam_header_bytes("", &ref_seqs),
+            String::from("test"),
+            false,
+            false,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_push_records_same_output() {
+        // Long names make variable sized columns flush in the middle of batches.
+        let records: Vec<BAMRawRecord> = (0..150_000)
+            .map(|i| {
+                let name = format!("{}{}", "r".repeat(i % 200), i);
+                TestRecord::new(i as i32 % 3, i as i32, &name).to_raw()
+            })
+            .collect();
+
+        let new_writer = || {
+            let mut writer = cursor_writer();
+            writer.set_block_size_limit(Fields::ReadName, MEGA_BYTE_SIZE).unwrap();
+            writer
+        };
+        let mut writer = new_writer();
+        for rec in &records {
+            writer.push_record(rec, false).unwrap();
+        }
+        writer.finish_with_summary(false).unwrap();
+        let expected = writer.inner.into_inner();
+
+        for batch_size in [1, 7, 4096, records.len()] {
+            let mut writer = new_writer();
+            for batch in records.chunks(batch_size) {
+                writer.push_records(batch, false).unwrap();
+            }
+            writer.finish_with_summary(false).unwrap();
+            assert!(writer.inner.into_inner() == expected, "batch size {}", batch_size);
+        }
+    }
+
+    /// Hash of the blocks of GBAM file `bytes`, apart from where they are
+    /// and whether they are stored compressed: record counts, transforms and
+    /// decompressed payloads of blocks of every field, in order.
+    fn blocks_md5(bytes: Vec<u8>) -> md5::Digest {
+        let reader = Reader::from_bytes(bytes, ParsingTemplate::new_with(&[])).unwrap();
+        let mut context = md5::Context::new();
+        for field in Fields::iterator() {
+            let field_codec = *reader.file_meta.get_field_codec(field);
+            for block in reader.file_meta.view_blocks(field) {
+                let mut payload = Vec::new();
+                if block.uncompressed_size > 0 {
+                    let data = reader.block_data(block).unwrap();
+                    let (codec, size) = (block.codec(field_codec), block.uncompressed_size as usize);
+                    decompress_into(&data, &mut payload, &codec, size).unwrap();
+                }
+                context.consume(block.numitems.to_le_bytes());
+                context.consume(format!("{:?}", block.transform));
+                context.consume(&payload);
+            }
+        }
+        context.compute()
+    }
+
+    /// Columns flush mid-batch on row counts, group rows and size limits of
+    /// data and index blocks alike, whichever way records come in.
+    #[test]
+    fn test_ingest_paths_same_hash() {
+        let records: Vec<TestRecord> = (0..60_000)
+            .map(|i| {
+                let name = format!("{}{}", "r".repeat(i as usize % 200), i);
+                let mut rec = TestRecord::new(i / 20_000, i, &name);
+                rec.flag = (i % 4) as u16;
+                rec.mapq = (i % 61) as u8;
+                rec
+            })
+            .collect();
+        let write = |how: usize| {
+            let mut writer = cursor_writer();
+            writer.deterministic(true);
+            writer.set_rows_per_block(7_000);
+            writer.set_column_transforms(true);
+            writer.set_field_group(&[Fields::Flags, Fields::Mapq, Fields::Bin]);
+            match how {
+                0 => {
+                    for rec in &records {
+                        writer.push_record(&rec.to_raw(), false).unwrap();
+                    }
+                }
+                1 => {
+                    let raw: Vec<BAMRawRecord> = records.iter().map(|rec| rec.to_raw()).collect();
+                    for batch in raw.chunks(999) {
+                        writer.push_records(batch, false).unwrap();
+                    }
+                }
+                _ => {
+                    for rec in &records {
+                        let bytes = rec.to_bytes();
+                        writer.write_all(&(bytes.len() as u32).to_le_bytes()).unwrap();
+                        writer.write_all(&bytes).unwrap();
+                    }
+                }
+            }
+            writer.finish_with_summary(false).unwrap();
+            writer.inner.into_inner()
+        };
+
+        let bytes = write(0);
+        let expected = md5::compute(&bytes);
+        assert_eq!(md5::compute(write(1)), expected);
+        assert_eq!(md5::compute(write(2)), expected);
+        // Blocks written by pushing records one by one before columns were
+        // dispatched through `ColumnKind` instead of `Box<dyn Column>`.
+        assert_eq!(format!("{:x}", blocks_md5(bytes)), "1e222913ff851f2831069dc76aefb67f");
+    }
+
+    #[test]
+    fn test_lost_block() {
+        let records: Vec<BAMRawRecord> = (0..2_500)
+            .map(|i| TestRecord::new(0, i, "r").to_raw())
+            .collect();
+        let mut writer = cursor_writer();
+        writer.set_rows_per_block(1000);
+        writer.push_records(&records, false).unwrap();
+        // Compressor loses the block in flight, the next block of its field
+        // is written after it.
+        writer.compressor.finish();
+        let err = writer.finish_with_summary(false).err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert!(err.to_string().starts_with("Block 1 of field"), "{}", err);
+        // File info still has no meta pointer.
+        let file_info = crate::reader::reader::parse_file_info(writer.inner.get_ref()).unwrap();
+        assert_eq!(file_info.seekpos, 0);
+    }
+
+    /// Output running out of space once `limit` bytes are written.
+    struct FullDisk {
+        cursor: Cursor<Vec<u8>>,
+        limit: u64,
+    }
+
+    impl Write for FullDisk {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            if self.cursor.position() + buf.len() as u64 > self.limit {
+                return Err(std::io::Error::from_raw_os_error(libc::ENOSPC));
+            }
+            self.cursor.write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Seek for FullDisk {
+        fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+            self.cursor.seek(pos)
+        }
+    }
+
+    impl SyncOutput for FullDisk {
+        fn sync_output(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_failed_blocks_leave_file_unfinished() {
+        let dir = TempDir::new("gbam_full_disk").unwrap();
+        // Columns stay under a block, all blocks are written by
+        // `finish_with_summary()`.
+        let records: Vec<BAMRawRecord> = (0..5_000)
+            .map(|i| TestRecord::new(i % 3, i, &format!("read{}", i)).to_raw())
+            .collect();
+        let mut writer = cursor_writer();
+        writer.push_records(&records, false).unwrap();
+        writer.finish_with_summary(false).unwrap();
+        let full_size = writer.inner.into_inner().len() as u64;
+
+        // Space runs out in the middle of blocks and in meta.
+        for limit in [full_size / 2, full_size - 10] {
+            let ref_seqs = test_ref_seqs();
+            let mut writer = Writer::new(
+                FullDisk {
+                    cursor: Cursor::new(Vec::new()),
+                    limit,
+                },
+                vec![Codecs::Lz4; FIELDS_NUM],
+                1,
+                vec![Fields::RefID, Fields::Pos],
+                ref_seqs.clone(),
+                sam_header_bytes("", &ref_seqs),
+                String::from("test"),
+                false,
+                false,
+            )
+            .unwrap();
+            writer.push_records(&records, false).unwrap();
+            let err = writer.finish_with_summary(false).err().unwrap();
+            assert!(err.to_string().contains("os error 28"), "{}", err);
+            if limit == full_size / 2 {
+                assert!(err.to_string().starts_with("Blocks of fields "), "{}", err);
+                assert!(!writer.failed_fields.is_empty());
+            }
+
+            let bytes = writer.inner.cursor.into_inner();
+            let file_info = crate::reader::reader::parse_file_info(&bytes).unwrap();
+            assert_eq!(file_info.seekpos, 0);
+            let path = dir.path().join("full.gbam");
+            std::fs::write(&path, &bytes).unwrap();
+            let template = ParsingTemplate::new_with(&[Fields::RefID, Fields::Pos]);
+            assert!(Reader::new(File::open(&path).unwrap(), template).is_err(), "limit {}", limit);
+        }
+    }
+
+    #[test]
+    fn test_compression_failure() {
+        use crate::compressor::CodecHook;
+
+        let dir = TempDir::new("gbam_codec_failure").unwrap();
+        let records: Vec<BAMRawRecord> = (0..1_000)
+            .map(|i| TestRecord::new(0, i, &format!("read{}", i)).to_raw())
+            .collect();
+        let failing = |field: Fields, block: u64| field == Fields::ReadName && block == 3;
+        let hooks: [CodecHook; 2] = [
+            Arc::new(move |field, block| {
+                if failing(field, block) {
+                    return Err(std::io::Error::other("broken codec"));
+                }
+                Ok(())
+            }),
+            Arc::new(move |field, block| {
+                assert!(!failing(field, block), "broken codec");
+                Ok(())
+            }),
+        ];
+        for hook in hooks {
+            let mut writer = cursor_writer();
+            writer.set_rows_per_block(100);
+            writer.compressor.set_codec_hook(hook);
+            // Failure comes out of whichever call gets the block back.
+            let mut first_err = None;
+            for batch in records.chunks(100) {
+                if let Err(err) = writer.push_records(batch, false) {
+                    first_err = Some(err);
+                    break;
+                }
+            }
+            let err = writer.finish_with_summary(false).err().unwrap();
+            assert!(err.to_string().contains("ReadName"), "{}", err);
+            let first_err = first_err.unwrap_or(err);
+            assert!(first_err.to_string().contains("broken codec"), "{}", first_err);
+            assert!(matches!(
+                gbam_error(&first_err),
+                Some(GbamError::Codec(CodecError::CompressionFailed { field: Fields::ReadName, block: 3, .. }))
+            ));
+
+            let path = dir.path().join("failed.gbam");
+            std::fs::write(&path, writer.inner.get_ref()).unwrap();
+            assert!(Reader::from_path(&path, ParsingTemplate::new()).is_err());
+        }
+    }
+
+    #[test]
+    fn test_rows_per_block() {
+        let dir = tempdir::TempDir::new("gbam_rows_per_block").unwrap();
+        let path = dir.path().join("aligned.gbam");
+        let mut writer = new_test_writer(&path, "");
+        writer.set_rows_per_block(1000);
+        let records: Vec<BAMRawRecord> = (0..4_500)
+            .map(|i| TestRecord::new(i % 3, i, &format!("read{}", i)).to_raw())
+            .collect();
+        // Batches crossing block boundaries.
+        for batch in records.chunks(777) {
+            writer.push_records(batch, false).unwrap();
+        }
+        writer.finish_with_summary(false).unwrap();
+
+        let mut reader = crate::test_utils::open_test_file(&path);
+        assert_eq!(reader.file_meta.get_rows_per_block(), Some(1000));
+        for field in [Fields::RefID, Fields::Pos, Fields::Flags, Fields::LName, Fields::ReadName] {
+            let numitems: Vec<u32> = reader
+                .file_meta
+                .view_blocks(&field)
+                .iter()
+                .map(|b| b.numitems)
+                .collect();
+            assert_eq!(numitems, vec![1000, 1000, 1000, 1000, 500], "{}", field);
+        }
+        assert_eq!(reader.blocks_for_records(1_500..2_500), Some(1..3));
+        assert_eq!(reader.blocks_for_records(4_000..4_500), Some(4..5));
+
+        let mut recs = reader.records();
+        let mut n = 0;
+        while let Some(rec) = recs.next_rec().unwrap() {
+            assert_eq!(rec.pos, Some(n));
+            n += 1;
+        }
+        assert_eq!(n, 4_500);
+    }
+
+    #[test]
+    fn test_field_groups() {
+        let dir = TempDir::new("gbam_field_groups").unwrap();
+        let path = dir.path().join("grouped.gbam");
+        let mut writer = new_test_writer(&path, "");
+        writer.set_rows_per_block(1000);
+        writer.set_column_transforms(true);
+        writer.set_field_group(&[Fields::Flags, Fields::Mapq, Fields::Pos]);
+        let records: Vec<TestRecord> = (0..4_500)
+            .map(|i| {
+                let mut rec = TestRecord::new(i % 3, i, &format!("read{}", i));
+                rec.flag = (i % 7) as u16;
+                rec.mapq = (i % 60) as u8;
+                rec
+            })
+            .collect();
+        for batch in records.chunks(777) {
+            let batch: Vec<BAMRawRecord> = batch.iter().map(TestRecord::to_raw).collect();
+            writer.push_records(&batch, false).unwrap();
+        }
+        writer.finish_with_summary(false).unwrap();
+
+        let meta = open_test_file(&path).file_meta.clone();
+        let flags = meta.view_blocks(&Fields::Flags);
+        let mapq = meta.view_blocks(&Fields::Mapq);
+        let pos = meta.view_blocks(&Fields::Pos);
+        assert_eq!(flags.len(), 5);
+        for block_num in 0..5 {
+            let blocks = [&flags[block_num], &mapq[block_num], &pos[block_num]];
+            let numitems = blocks[0].numitems as u64;
+            for block in blocks {
+                assert_eq!(block.seekpos, blocks[0].seekpos);
+                assert_eq!(block.block_size, blocks[0].block_size);
+                assert!(block.transform.is_none());
+            }
+            let stripes: Vec<Option<u64>> = blocks.iter().map(|block| block.stripe).collect();
+            assert_eq!(stripes, [Some(0), Some(2 * numitems), Some(3 * numitems)]);
+        }
+        assert_eq!(pos[1].stats.as_ref().map(|stat| stat.min_value), Some(1000));
+        assert!(meta.view_blocks(&Fields::RefID)[0].stripe.is_none());
+        assert!(crate::inspect::inspect(&path).unwrap().failed_checks.is_empty());
+
+        // Any single member can be projected.
+        for field in [Fields::Flags, Fields::Mapq, Fields::Pos] {
+            let mut reader = Reader::from_path(&path, ParsingTemplate::new_with(&[field])).unwrap();
+            let mut recs = reader.records();
+            let mut n = 0;
+            while let Some(rec) = recs.next_rec().unwrap() {
+                match field {
+                    Fields::Flags => assert_eq!(rec.flag, Some(records[n].flag)),
+                    Fields::Mapq => assert_eq!(rec.mapq, Some(records[n].mapq)),
+                    _ => assert_eq!(rec.pos, Some(records[n].pos)),
+                }
+                n += 1;
+            }
+            assert_eq!(n, records.len(), "{}", field);
+        }
+    }
+
+    #[test]
+    fn test_output_independent_of_thread_num() {
+        // Long sequences make their blocks compress much slower than blocks of
+        // other fields, so blocks complete out of submission order.
+        let records: Vec<BAMRawRecord> = (0..2_000)
+            .map(|i| {
+                let mut rec = TestRecord::new(i % 3, i, &format!("{}{}", "r".repeat(i as usize % 50), i));
+                rec.tlen = i;
+                rec.seq = (0..2000).map(|j| ["A", "C", "G", "T"][(j * j + i as usize) % 4]).collect();
+                rec.qual = (0..2000).map(|j| (j % 41) as u8).collect();
+                rec.cigar = vec![2000 << 4];
+                rec.to_raw()
+            })
+            .collect();
+        let write = |thread_num| {
+            let ref_seqs = test_ref_seqs();
+            let mut writer = Writer::new(
+                Cursor::new(Vec::new()),
+                vec![Codecs::Gzip; FIELDS_NUM],
+                thread_num,
+                vec![Fields::RefID, Fields::Pos],
+                ref_seqs.clone(),
+                sam_header_bytes("", &ref_seqs),
+                String::from("test"),
+                false,
+                false,
+            )
+            .unwrap();
+            writer.set_rows_per_block(250);
+            for batch in records.chunks(300) {
+                writer.push_records(batch, false).unwrap();
+            }
+            writer.finish_with_summary(false).unwrap();
+            let meta = serde_json::to_string(&writer.file_meta).unwrap();
+            (meta, writer.inner.into_inner())
+        };
+
+        let (expected_meta, expected) = write(1);
+        for thread_num in [8, 8] {
+            let (meta, bytes) = write(thread_num);
+            assert_eq!(meta, expected_meta);
+            assert!(bytes == expected);
+        }
+    }
+
+    #[test]
+    fn test_deterministic_files() {
+        let dir = TempDir::new("gbam_deterministic").unwrap();
+        let records: Vec<BAMRawRecord> = (0..60_000)
+            .map(|i| {
+                let mut rec = TestRecord::new(i / 20_000, i, &format!("{}{}", "r".repeat(i as usize % 30), i));
+                rec.tlen = i % 700;
+                rec.seq = (0..100).map(|j| ["A", "C", "G", "T"][(j * j + i as usize) % 4]).collect();
+                rec.qual = vec![30; 100];
+                rec.cigar = vec![100 << 4];
+                rec.to_raw()
+            })
+            .collect();
+        let write = |thread_num| {
+            let path = dir.path().join(format!("{}.gbam", thread_num));
+            let ref_seqs = test_ref_seqs();
+            let mut writer = Writer::new(
+                File::create(&path).unwrap(),
+                vec![Codecs::Gzip; FIELDS_NUM],
+                thread_num,
+                vec![Fields::RefID, Fields::Pos],
+                ref_seqs.clone(),
+                sam_header_bytes("@HD\tVN:1.6\tSO:coordinate\n", &ref_seqs),
+                String::from("test"),
+                true,
+                false,
+            )
+            .unwrap();
+            writer.deterministic(true);
+            writer.set_seq_packing(true);
+            writer.set_name_bloom_filter(10);
+            writer.set_linear_index(true);
+            writer.register_collector(
+                Fields::TemplateLength,
+                Box::new(crate::analytics::InsertSizeHistogram::new(500)),
+            );
+            for batch in records.chunks(1_000) {
+                writer.push_records(batch, false).unwrap();
+            }
+            writer.finish_with_summary(false).unwrap();
+            std::fs::read(path).unwrap()
+        };
+
+        let expected = write(1);
+        assert!(write(8) == expected);
+        assert!(write(8) == expected);
+    }
+
+    #[test]
+    fn test_last_block_sizes() {
+        let dir = TempDir::new("gbam_writer").unwrap();
+        let path = dir.path().join("partial.gbam");
+        let records: Vec<TestRecord> = (0..1_234)
+            .map(|i| TestRecord::new(0, i, &"r".repeat(1 + i as usize % 50)))
+            .collect();
+        let mut writer = new_test_writer(&path, "");
+        for rec in &records {
+            writer.push_record(&rec.to_raw(), false).unwrap();
+        }
+        writer.finish_with_summary(false).unwrap();
+
+        let reader = open_test_file(&path);
+        for field in [Fields::Pos, Fields::ReadName, Fields::LName, Fields::RawQual] {
+            let expected: Vec<u8> = match field {
+                Fields::LName => {
+                    let mut end = 0;
+                    records
+                        .iter()
+                        .flat_map(|rec| {
+                            end += rec.name.len() as u32 + 1;
+                            end.to_le_bytes()
+                        })
+                        .collect()
+                }
+                _ => records
+                    .iter()
+                    .flat_map(|rec| rec.to_raw().get_bytes(&field).to_vec())
+                    .collect(),
+            };
+            let blocks = reader.file_meta.view_blocks(&field);
+            assert_eq!(blocks.len(), 1);
+            let block = &blocks[0];
+            assert_eq!(block.uncompressed_size, expected.len() as u64);
+            let data = reader.block_data(block).unwrap();
+            let mut uncompressed = vec![0; block.uncompressed_size as usize];
+            let codec = block.codec(Codecs::Lz4);
+            crate::reader::column::decompress_block(&data, &mut uncompressed, &codec).unwrap();
+            // No zero padding after the last record.
+            assert_eq!(uncompressed, expected);
+        }
+    }
+
+    #[test]
+    fn test_finish_summary() {
+        for config in 0..3 {
+            let mut writer = cursor_writer();
+            writer.set_rows_per_block(5_000);
+            match config {
+                0 => {}
+                1 => {
+                    writer.set_field_group(&[Fields::Flags, Fields::Mapq, Fields::Bin]);
+                    writer.set_source_index(true);
+                }
+                _ => writer.reserve_meta_bytes(256 * 1024).unwrap(),
+            }
+            for i in 0..20_000 {
+                writer.push_record(&TestRecord::new(0, i, &format!("r{}", i)).to_raw(), false).unwrap();
+            }
+            let summary = writer.finish_with_summary(false).unwrap();
+            assert_eq!(summary.total_bytes, writer.inner.into_inner().len() as u64);
+            let data_bytes: u64 = summary.compressed_bytes_per_field.values().sum();
+            assert_eq!(
+                FILE_INFO_SIZE as u64 + summary.meta_bytes + summary.source_index_bytes + data_bytes,
+                summary.total_bytes,
+                "config {}",
+                config
+            );
+            assert_eq!(summary.records_written, 20_000);
+            assert_eq!(summary.blocks_per_field["Pos"], 4);
+            assert_eq!(summary.uncompressed_bytes_per_field["Pos"], 20_000 * 4);
+            assert_eq!(summary.codec_settings["Pos"].codec, Codecs::Lz4);
+            assert_eq!(summary.blocks_per_field["Mapq"], summary.blocks_per_field["Flags"]);
+            // Mapq comes first of the group.
+            assert_eq!(summary.compressed_bytes_per_field["Flags"] == 0, config == 1);
+            assert_eq!(summary.source_index_bytes > 0, config == 1);
+            if config == 2 {
+                assert_eq!(summary.meta_bytes, 256 * 1024);
+            }
+            let json = serde_json::to_string(&summary).unwrap();
+            assert_eq!(serde_json::from_str::<WriteSummary>(&json).unwrap(), summary);
+        }
+    }
+
+    #[test]
+    fn test_block_size_limits() {
+        let dir = TempDir::new("gbam_writer").unwrap();
+        let path = dir.path().join("limits.gbam");
+        let mut writer = new_test_writer(&path, "");
+        writer.set_block_size_limit(Fields::Mapq, 64 * 1024).unwrap();
+        writer.set_block_size_limit(Fields::RawSequence, 8 * MEGA_BYTE_SIZE).unwrap();
+        // 1 byte of Mapq, 50 of packed sequence, 100 of qualities per record.
+        let records: Vec<BAMRawRecord> = (0..200_000)
+            .map(|i| {
+                let mut rec = TestRecord::new(0, i, &format!("r{}", i));
+                rec.seq = "ACGT".repeat(25);
+                rec.qual = vec![30; 100];
+                rec.to_raw()
+            })
+            .collect();
+        writer.push_records(&records, false).unwrap();
+        writer.finish_with_summary(false).unwrap();
+
+        let reader = open_test_file(&path);
+        let meta = &reader.file_meta;
+        let expected = [
+            (Fields::Mapq, 64 * 1024, 4),
+            (Fields::RawSequence, 8 * MEGA_BYTE_SIZE, 2),
+            // Defaults, 16 MB of variable sized fields hold 167772 qualities.
+            (Fields::RawQual, VAR_SIZE_LIMIT, 2),
+            (Fields::Pos, SIZE_LIMIT, 1),
+        ];
+        for (field, limit, blocks) in expected {
+            assert_eq!(meta.get_block_size_limit(&field), limit, "{}", field);
+            assert_eq!(meta.view_blocks(&field).len(), blocks, "{}", field);
+        }
+        let mapq: Vec<u32> = meta.view_blocks(&Fields::Mapq).iter().map(|block| block.numitems).collect();
+        assert_eq!(mapq, [65_536, 65_536, 65_536, 3_392]);
+        assert_eq!(meta.view_blocks(&Fields::RawSequence)[0].numitems, 167_772);
+    }
+
+    #[test]
+    fn test_block_size_limit_bounds() {
+        let mut writer = cursor_writer();
+        let err = writer.set_block_size_limit(Fields::Mapq, 1024).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        assert_eq!(err.to_string(), "Block size limit 1024 of field Mapq is not in 4096..=67108864");
+        assert_eq!(writer.file_meta.get_block_size_limit(&Fields::Mapq), SIZE_LIMIT);
+    }
+
+    #[test]
+    fn test_block_size_limit_rows() {
+        let mut writer = cursor_writer();
+        writer.set_rows_per_block(5_000);
+        let err = writer.set_block_size_limit(Fields::Mapq, 4096).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        assert!(err.to_string().starts_with("Rows per block of field Mapq take more"), "{}", err);
+        writer.set_block_size_limit(Fields::Mapq, 5_000).unwrap();
+    }
+
+    #[test]
+    fn test_records_larger_than_block() {
+        let dir = TempDir::new("gbam_writer").unwrap();
+        for size in [1, VAR_SIZE_LIMIT, VAR_SIZE_LIMIT + 1, 4 * VAR_SIZE_LIMIT] {
+            for field in [Fields::RawSequence, Fields::RawQual, Fields::RawTags] {
+                let mut large = TestRecord::new(0, 1, "large");
+                match field {
+                    Fields::RawSequence => {
+                        large.seq = "AC".repeat(size);
+                        large.qual = vec![30; 2 * size];
+                    }
+                    Fields::RawQual => {
+                        large.seq = "A".repeat(size);
+                        large.qual = vec![30; size];
+                    }
+                    // Tags are not parsed by the writer.
+                    _ => large.tags = vec![b'A'; size],
+                }
+                let small = |pos| TestRecord {
+                    tags: b"NMC\x00".to_vec(),
+                    ..TestRecord::new(0, pos, "small")
+                };
+                let records = [small(0), large, small(2), small(3)];
+                let path = dir.path().join(format!("large_{}_{}.gbam", field, size));
+                write_test_file(&path, "", &records);
+
+                // Data blocks end before and after the large record, index
+                // entries stay in one block.
+                let mut reader = open_test_file(&path);
+                let blocks = reader.file_meta.view_blocks(&field).len();
+                assert_eq!(blocks, if size >= VAR_SIZE_LIMIT { 3 } else { 1 }, "{} of {}", field, size);
+                assert_eq!(reader.file_meta.view_blocks(&var_size_field_to_index(&field)).len(), 1);
+
+                let mut fetched = reader.records();
+                for rec in &records {
+                    let got = fetched.next_rec().unwrap().unwrap();
+                    assert_eq!(got.pos, Some(rec.pos));
+                    assert!(got.seq.as_ref() == Some(&rec.seq), "{} of {}", field, size);
+                    assert!(got.qual.as_ref() == Some(&rec.qual), "{} of {}", field, size);
+                    assert!(got.tags.as_ref() == Some(&rec.tags), "{} of {}", field, size);
+                }
+                assert!(fetched.next_rec().unwrap().is_none());
+            }
+        }
+    }
+
+    #[test]
+    fn test_derived_seq_index() {
+        let dir = TempDir::new("gbam_writer").unwrap();
+        // Odd, empty ('*') and long sequences, so RawSequence and RawQual
+        // blocks end at different records.
+        let records: Vec<TestRecord> = (0..3_000)
+            .map(|i| {
+                let mut rec = TestRecord::new(0, i, &format!("r{}", i));
+                let len = match i % 7 {
+                    0 => 0,
+                    1 => 40_001,
+                    _ => (i as usize * 31) % 301,
+                };
+                rec.seq = (0..len).map(|j| ["A", "C", "G", "T", "N"][(j + i as usize) % 5]).collect();
+                // Missing qualities are 0xff bytes as long as the sequence.
+                rec.qual = (0..len).map(|j| if i % 5 == 0 { 0xff } else { (j % 41) as u8 }).collect();
+                rec.cigar = if len == 0 { Vec::new() } else { vec![(len as u32) << 4] };
+                rec
+            })
+            .collect();
+        let write = |name: &str, derived: bool, packed: bool| {
+            let path = dir.path().join(name);
+            let mut writer = new_test_writer(&path, "");
+            writer.set_seq_packing(packed);
+            writer.set_derived_seq_index(derived);
+            writer.set_block_size_limit(Fields::RawSequence, SIZE_LIMIT).unwrap();
+            writer.set_block_size_limit(Fields::RawQual, SIZE_LIMIT).unwrap();
+            for rec in &records {
+                writer.push_record(&rec.to_raw(), false).unwrap();
+            }
+            writer.finish_with_summary(false).unwrap();
+            path
+        };
+
+        let stored = write("stored.gbam", false, false);
+        for (name, packed) in [("derived.gbam", false), ("packed.gbam", true)] {
+            let path = write(name, true, packed);
+            let mut reader = open_test_file(&path);
+            assert!(reader.file_meta.is_index_derived(&Fields::RawSeqLen));
+            assert_eq!(reader.file_meta.count_items(&Fields::RawSeqLen), 0);
+            let blocks = |field| reader.file_meta.view_blocks(&field).len();
+            assert_eq!((blocks(Fields::RawSequence), blocks(Fields::RawQual)), (2, 3));
+            let mut recs = reader.records();
+            for rec in &records {
+                let got = recs.next_rec().unwrap().unwrap();
+                assert!(got.seq.as_ref() == Some(&rec.seq), "{} at {}", name, rec.pos);
+                assert!(got.qual.as_ref() == Some(&rec.qual), "{} at {}", name, rec.pos);
+            }
+            assert!(recs.next_rec().unwrap().is_none());
+
+            // SequenceLength is read even if only RawSequence is parsed.
+            let template = ParsingTemplate::new_with(&[Fields::RawSequence]);
+            let mut reader = Reader::from_path(&path, template).unwrap();
+            let mut got = GbamRecord::default();
+            for rec in records.iter().rev().step_by(97) {
+                reader.fill_record(rec.pos as usize, &mut got).unwrap();
+                assert!(got.seq.as_ref() == Some(&rec.seq), "{} at {}", name, rec.pos);
+            }
+        }
+
+        let derived = dir.path().join("derived.gbam");
+        let index_size = |path: &Path| -> u64 {
+            let reader = open_test_file(path);
+            reader.file_meta.view_blocks(&Fields::RawSeqLen).iter().map(|b| b.uncompressed_size).sum()
+        };
+        assert_eq!(index_size(&derived), 0);
+        assert_eq!(index_size(&stored), 4 * records.len() as u64);
+        let file_size = |path: &Path| std::fs::metadata(path).unwrap().len();
+        assert!(file_size(&derived) < file_size(&stored));
+    }
+
+    #[test]
+    fn test_no_bytes_of_other_blocks() {
+        let dir = TempDir::new("gbam_writer").unwrap();
+        let path = dir.path().join("patterns.gbam");
+        // Block sizes vary, so buffers of long blocks are reused for short
+        // ones, of this and other fields.
+        let records: Vec<TestRecord> = (0..2_000)
+            .map(|i| {
+                let mut rec = TestRecord::new(0, i, &"x".repeat(i as usize % 97 + 1));
+                rec.seq = "A".repeat(i as usize % 61);
+                rec.qual = vec![7; rec.seq.len()];
+                rec
+            })
+            .collect();
+        let mut writer = new_test_writer(&path, "");
+        writer.set_rows_per_block(37);
+        for rec in &records {
+            writer.push_record(&rec.to_raw(), false).unwrap();
+        }
+        writer.finish_with_summary(false).unwrap();
+
+        let reader = open_test_file(&path);
+        for (field, allowed) in [(Fields::ReadName, &b"x\0"[..]), (Fields::RawQual, &[7][..])] {
+            let blocks = reader.file_meta.view_blocks(&field);
+            assert!(blocks.len() > 50);
+            for block in blocks {
+                let data = reader.block_data(block).unwrap();
+                let mut uncompressed = vec![0; block.uncompressed_size as usize];
+                let codec = block.codec(*reader.file_meta.get_field_codec(&field));
+                crate::reader::column::decompress_block(&data, &mut uncompressed, &codec).unwrap();
+                assert!(uncompressed.iter().all(|byte| allowed.contains(byte)), "{}", field);
+            }
+        }
+    }
+
+    #[test]
+    fn test_write_concatenated_records() {
+        let dir = TempDir::new("gbam_writer").unwrap();
+        let path = dir.path().join("piped.gbam");
+        let records: Vec<TestRecord> = (0..4).map(|i| TestRecord::new(0, i, &format!("read{}", i))).collect();
+        let mut bytes = Vec::new();
+        for rec in &records {
+            let rec = rec.to_bytes();
+            bytes.write_u32::<LittleEndian>(rec.len() as u32).unwrap();
+            bytes.extend_from_slice(&rec);
+        }
+        let third_end = bytes.len() - records[3].to_bytes().len() - 4;
+
+        let mut writer = new_test_writer(&path, "");
+        writer.set_write_template(WriteTemplate::new_with(&[Fields::Pos, Fields::ReadName]));
+        // Three records at once, then the last one in two parts.
+        assert_eq!(writer.write(&bytes[..third_end]).unwrap(), third_end);
+        writer.write_all(&bytes[third_end..third_end + 10]).unwrap();
+        // Flushing leaves records in their columns.
+        writer.flush().unwrap();
+        assert!(writer.bytes_buffered() > 0);
+        writer.write_all(&bytes[third_end + 10..]).unwrap();
+        writer.finish_with_summary(false).unwrap();
+
+        let template = ParsingTemplate::new_with(&[Fields::RefID, Fields::Pos, Fields::ReadName]);
+        let mut reader = Reader::new(File::open(&path).unwrap(), template).unwrap();
+        assert_eq!(reader.amount, 4);
+        for field in [Fields::Mapq, Fields::RawQual, Fields::SequenceLength] {
+            let blocks = reader.file_meta.view_blocks(&field);
+            assert!(blocks.iter().all(|block| block.numitems == 0), "{}", field);
+        }
+        let mut fetched = reader.records();
+        for rec in &records {
+            let got = fetched.next_rec().unwrap().unwrap();
+            assert_eq!(got.pos, Some(rec.pos));
+            assert_eq!(got.read_name.as_deref(), Some(format!("{}\0", rec.name).as_bytes()));
+        }
+        assert!(fetched.next_rec().unwrap().is_none());
+
+        let mut writer = new_test_writer(&dir.path().join("bad.gbam"), "");
+        assert!(writer.write(&[4, 0, 0, 0, 1, 2, 3, 4]).is_err());
+
+        let mut writer = new_test_writer(&dir.path().join("truncated.gbam"), "");
+        writer.write_all(&bytes[..third_end + 10]).unwrap();
+        assert!(writer.finish_with_summary(false).is_err());
+    }
+
+    #[test]
+    fn test_bytes_buffered() {
+        let mut writer = cursor_writer();
+        assert_eq!(writer.bytes_buffered(), 0);
+        let rec = TestRecord::new(0, 1, "read");
+        writer.push_record(&rec.to_raw(), false).unwrap();
+        writer.push_record(&rec.to_raw(), false).unwrap();
+        // BAM record without l_read_name, n_cigar_op and l_seq, plus five u32
+        // indices.
+        let expected = 2 * (rec.to_bytes().len() - 7 + 5 * 4);
+        assert_eq!(writer.bytes_buffered(), expected);
+        writer.flush_all_columns(false, false).unwrap();
+        assert_eq!(writer.bytes_buffered(), 0);
+    }
+
+    #[test]
+    fn test_memory_budget() {
+        use std::cell::Cell;
+        use std::rc::Rc;
+
+        let dir = TempDir::new("gbam_writer").unwrap();
+        // About 32 MB of records, mostly sequences and qualities.
+        let records: Vec<TestRecord> = (0..20_000)
+            .map(|i| TestRecord {
+                cigar: vec![1_000 << 4],
+                seq: "ACGT".repeat(250),
+                qual: vec![(i % 40) as u8; 1_000],
+                ..TestRecord::new(i % 3, i, &format!("r{}", i))
+            })
+            .collect();
+        let write = |name: &str, budget: Option<usize>| {
+            let path = dir.path().join(name);
+            let peak = Rc::new(Cell::new(0));
+            let mut writer = new_test_writer(&path, "");
+            if let Some(budget) = budget {
+                writer.set_memory_budget(budget);
+                let peak = peak.clone();
+                writer.set_memory_probe(Box::new(move |usage| peak.set(peak.get().max(usage))));
+            }
+            for batch in records.chunks(100) {
+                let batch: Vec<_> = batch.iter().map(TestRecord::to_raw).collect();
+                writer.push_records(&batch, false).unwrap();
+            }
+            writer.finish_with_summary(false).unwrap();
+            (open_test_file(&path), peak.get())
+        };
+
+        let (plain, _) = write("plain.gbam", None);
+        let plain_blocks = plain.file_meta.view_blocks(&Fields::RawSequence).len();
+        for budget in [16 << 20, 1 << 20] {
+            let (mut reader, peak) = write(&format!("budget_{}.gbam", budget), Some(budget));
+            assert!(peak > budget / 2 && peak <= budget, "peak {} of {}", peak, budget);
+            let blocks = reader.file_meta.view_blocks(&Fields::RawSequence).len();
+            assert!(blocks >= plain_blocks, "{} blocks of {}", blocks, budget);
+            if budget == 1 << 20 {
+                assert!(blocks > plain_blocks, "{} blocks of {}", blocks, budget);
+            }
+            let mut fetched = reader.records();
+            for rec in &records {
+                let got = fetched.next_rec().unwrap().unwrap();
+                assert_eq!((got.refid, got.pos), (Some(rec.refid), Some(rec.pos)));
+                assert!(got.read_name.as_deref() == Some(format!("{}\0", rec.name).as_bytes()));
+                assert!(got.seq.as_ref() == Some(&rec.seq) && got.qual.as_ref() == Some(&rec.qual));
+            }
+            assert!(fetched.next_rec().unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn test_shared_compressor_pool() {
+        let dir = TempDir::new("gbam_pool").unwrap();
+        let pool = CompressorPool::new(4);
+        let ref_seqs = test_ref_seqs();
+        let new_writer = |name: &str| {
+            let mut writer = Writer::with_compressor_pool(
+                File::create(dir.path().join(name)).unwrap(),
+                vec![Codecs::Lz4; FIELDS_NUM],
+                pool.clone(),
+                vec![Fields::RefID, Fields::Pos],
+                ref_seqs.clone(),
+                sam_header_bytes("", &ref_seqs),
+                String::from("test"),
+                false,
+                false,
+            )
+            .unwrap();
+            writer.set_rows_per_block(100);
+            writer
+        };
+        let records = |file: i32| -> Vec<TestRecord> {
+            (0..250 * (file + 1))
+                .map(|i| TestRecord::new(file, i, &format!("file{}_read{}", file, i)))
+                .collect()
+        };
+
+        // Sequential writers, then two concurrent ones.
+        for file in 0..3 {
+            let mut writer = new_writer(&format!("{}.gbam", file));
+            for rec in records(file) {
+                writer.push_record(&rec.to_raw(), false).unwrap();
+            }
+            writer.finish_with_summary(false).unwrap();
+        }
+        let mut first = new_writer("concurrent0.gbam");
+        let mut second = new_writer("concurrent1.gbam");
+        for (a, b) in records(0).iter().zip(records(1).iter()) {
+            first.push_record(&a.to_raw(), false).unwrap();
+            second.push_record(&b.to_raw(), false).unwrap();
+        }
+        first.finish_with_summary(false).unwrap();
+        for b in &records(1)[records(0).len()..] {
+            second.push_record(&b.to_raw(), false).unwrap();
+        }
+        second.finish_with_summary(false).unwrap();
+        assert_eq!(pool.threads_started(), 4);
+
+        let files = ["0", "1", "2", "concurrent0", "concurrent1"];
+        for (name, file) in files.iter().zip([0, 1, 2, 0, 1]) {
+            let expected = records(file);
+            let mut reader = open_test_file(&dir.path().join(format!("{}.gbam", name)));
+            assert_eq!(reader.num_records(), expected.len());
+            let mut recs = reader.records();
+            for rec in &expected {
+                let fetched = recs.next_rec().unwrap().unwrap();
+                assert_eq!(fetched.refid, Some(rec.refid));
+                assert_eq!(fetched.pos, Some(rec.pos));
+                assert_eq!(
+                    fetched.read_name.as_deref(),
+                    Some(format!("{}\0", rec.name).as_bytes())
+                );
+            }
+            assert!(recs.next_rec().unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn test_incompressible_blocks_stored() {
+        use rand::{rngs::StdRng, Rng, SeedableRng};
+
+        let dir = TempDir::new("gbam_writer").unwrap();
+        let mut rng = StdRng::seed_from_u64(374);
+        let records: Vec<TestRecord> = (0..5_000)
+            .map(|i| {
+                let mut rec = TestRecord::new(0, i, "read");
+                rec.seq = "A".repeat(150);
+                rec.qual = (0..150).map(|_| rng.gen()).collect();
+                rec
+            })
+            .collect();
+        for codec in [Codecs::Gzip, Codecs::Lz4] {
+            let path = dir.path().join(format!("{:?}.gbam", codec));
+            let ref_seqs = test_ref_seqs();
+            let settings = WriterSettings {
+                codecs: vec![codec; FIELDS_NUM],
+                sam_header: sam_header_bytes("", &ref_seqs),
+                ref_seqs,
+                full_command: String::from("test"),
+                ..Default::default()
+            };
+            let mut writer = Writer::create(&path, settings).unwrap();
+            for rec in &records {
+                writer.push_record(&rec.to_raw(), false).unwrap();
+            }
+            writer.finish_with_summary(false).unwrap();
+
+            let mut reader = open_test_file(&path);
+            let blocks = reader.file_meta.view_blocks(&Fields::RawQual);
+            assert!(!blocks.is_empty());
+            for block in blocks {
+                assert!(block.stored, "{:?}", codec);
+                assert!(u64::from(block.block_size) <= block.uncompressed_size + 16);
+            }
+            // RefID is the same for all reads and compresses with either
+            // codec, unlike sequential positions with LZ4.
+            assert!(reader.file_meta.view_blocks(&Fields::RefID).iter().all(|block| !block.stored));
+
+            let mut recs = reader.records();
+            for rec in &records {
+                assert_eq!(recs.next_rec().unwrap().unwrap().qual.as_ref(), Some(&rec.qual));
+            }
+            assert!(recs.next_rec().unwrap().is_none());
+        }
+    }
+}
@@ -82,6 +82,25 @@ def test_flagstat():
     assert(len(view_of_original) > 0)
     assert(view_of_original == view_of_result)
 
+def test_view_sam():
+    view_of_original = subprocess.check_output(["samtools", "view", "-h", "--no-PG", str(bam_file_path)], stderr=subprocess.STDOUT)
+    view_of_result = subprocess.check_output([binary_path, "--view-sam", gbam_file.name], stderr=subprocess.STDOUT)
+
+    assert(len(view_of_original) > 0)
+    assert(view_of_original == view_of_result)
+
+def test_region_depth():
+    subprocess.run(["samtools", "sort", bam_file_path, "-o", bam_file_sorted_path.name], check=True)
+    subprocess.check_call(["samtools", "index", bam_file_sorted_path.name])
+    ref_name = subprocess.check_output(["samtools", "view", "-H", bam_file_sorted_path.name], text=True).split("SN:")[1].split("\t")[0]
+    region = f"{ref_name}:1-200000"
+    for mapq in ["0", "20"]:
+        view_of_original = subprocess.check_output(["samtools", "depth", "-a", "-Q", mapq, "-r", region, bam_file_sorted_path.name])
+        view_of_result = subprocess.check_output([binary_path, gbam_file_sorted.name, "--region-depth", "-q", region, "--mapq", mapq, "--index-file", gbam_file_sorted.name + ".gbai"])
+
+        assert(len(view_of_original) > 0)
+        assert(view_of_original == view_of_result)
+
 def test_sort(request):
     gbam_sorted_results = NamedTemporaryFile(suffix=".bam")
     samtools_sorted_results = bam_file_sorted_path
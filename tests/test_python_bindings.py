@@ -0,0 +1,80 @@
+"""Python bindings tests. Require `maturin develop` in the repository root and pysam."""
+from pathlib import Path
+from tempfile import TemporaryDirectory
+import subprocess
+
+import pytest
+
+gbam_tools = pytest.importorskip("gbam_tools")
+pysam = pytest.importorskip("pysam")
+
+cur_file_path = Path(__file__).parent.absolute()
+binary_path = cur_file_path.parent/"target"/"release"/"gbam_binary"
+bam_file_path = cur_file_path.parent/"test_data"/"little.bam"
+
+
+@pytest.fixture(scope='module')
+def sorted_files():
+    with TemporaryDirectory() as tmp_dir:
+        bam_path = Path(tmp_dir)/"sorted.bam"
+        gbam_path = Path(tmp_dir)/"sorted.gbam"
+        pysam.sort("-o", str(bam_path), str(bam_file_path))
+        pysam.index(str(bam_path))
+        subprocess.run([binary_path, bam_path, "-c", "-o", gbam_path], check=True)
+        yield bam_path, gbam_path
+
+
+def tags_as_lists(read):
+    return {tag: list(val) if hasattr(val, "__len__") and not isinstance(val, str) else val
+            for tag, val in read.get_tags()}
+
+
+def assert_same_record(rec, read):
+    assert rec["read_name"] == read.query_name
+    assert rec["ref_id"] == read.reference_id
+    assert rec["pos"] == read.reference_start
+    assert rec["mapq"] == read.mapping_quality
+    assert rec["flags"] == read.flag
+    assert rec["next_ref_id"] == read.next_reference_id
+    assert rec["next_pos"] == read.next_reference_start
+    assert rec["tlen"] == read.template_length
+    assert rec["cigar"] == read.cigarstring
+    assert rec["seq"] == (read.query_sequence or "")
+    qual = read.query_qualities
+    if qual is not None:
+        assert list(rec["qual"]) == list(qual)
+    assert rec["tags"] == tags_as_lists(read)
+
+
+def test_records(sorted_files):
+    bam_path, gbam_path = sorted_files
+    reader = gbam_tools.PyReader.open(str(gbam_path))
+    with pysam.AlignmentFile(str(bam_path)) as bam:
+        reads = list(bam.fetch(until_eof=True))
+    assert reader.num_records() == len(reads)
+    n = 0
+    for rec, read in zip(reader, reads):
+        assert_same_record(rec, read)
+        n += 1
+    assert n == len(reads)
+
+
+def test_selected_fields(sorted_files):
+    _, gbam_path = sorted_files
+    reader = gbam_tools.PyReader.open(str(gbam_path), fields=["pos", "flags"])
+    assert set(next(iter(reader)).keys()) == {"pos", "flags"}
+    with pytest.raises(KeyError):
+        gbam_tools.PyReader.open(str(gbam_path), fields=["no_such_field"])
+
+
+def test_fetch(sorted_files):
+    bam_path, gbam_path = sorted_files
+    reader = gbam_tools.PyReader.open(str(gbam_path))
+    with pysam.AlignmentFile(str(bam_path)) as bam:
+        ref_name = bam.references[0]
+        start, end = 10_000, 200_000
+        reads = list(bam.fetch(ref_name, start, end))
+    fetched = reader.fetch(ref_name, start, end)
+    assert len(fetched) == len(reads)
+    for rec, read in zip(fetched, reads):
+        assert_same_record(rec, read)